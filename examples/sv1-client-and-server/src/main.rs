@@ -39,6 +39,7 @@ struct Server {
     authorized_names: Vec<String>,
     extranonce1: HexBytes,
     extranonce2_size: usize,
+    extranonce_subscribed: bool,
     version_rolling_mask: Option<HexU32Be>,
     version_rolling_min_bit: Option<HexU32Be>,
     receiver_incoming: Receiver<String>,
@@ -85,6 +86,7 @@ impl Server {
             authorized_names: vec![],
             extranonce1: "00000000".try_into().unwrap(),
             extranonce2_size: 2,
+            extranonce_subscribed: false,
             version_rolling_mask: None,
             version_rolling_min_bit: None,
             receiver_incoming,
@@ -189,7 +191,14 @@ impl IsServer for Server {
     }
 
     /// Indicates to the server that the client supports the mining.set_extranonce method.
-    fn handle_extranonce_subscribe(&self) {}
+    fn handle_extranonce_subscribe(&mut self) -> bool {
+        self.extranonce_subscribed = true;
+        true
+    }
+
+    fn is_extranonce_subscribed(&self) -> bool {
+        self.extranonce_subscribed
+    }
 
     fn is_authorized(&self, _name: &str) -> bool {
         true
@@ -99,9 +99,9 @@ pub trait IsServer {
                 let (version_rolling, min_diff) = self.handle_configure(&configure);
                 Ok(Some(configure.respond(version_rolling, min_diff)))
             }
-            methods::Client2Server::ExtranonceSubscribe(_) => {
-                self.handle_extranonce_subscribe();
-                Ok(None)
+            methods::Client2Server::ExtranonceSubscribe(subscribe) => {
+                let subscribed = self.handle_extranonce_subscribe();
+                Ok(Some(subscribe.respond(subscribed)))
             }
             methods::Client2Server::Submit(submit) => {
                 let has_valid_version_bits = match &submit.version_bits {
@@ -180,8 +180,13 @@ pub trait IsServer {
     ///
     fn handle_submit(&self, request: &client_to_server::Submit) -> bool;
 
-    /// Indicates to the server that the client supports the mining.set_extranonce method.
-    fn handle_extranonce_subscribe(&self);
+    /// Indicates to the server that the client supports the mining.set_extranonce method. Return
+    /// true if the server will notify the extranonce changes, `is_extranonce_subscribed` must then
+    /// return true.
+    fn handle_extranonce_subscribe(&mut self) -> bool;
+
+    /// True if the client sent mining.extranonce.subscribe and the server accepted it
+    fn is_extranonce_subscribed(&self) -> bool;
 
     fn is_authorized(&self, name: &str) -> bool;
 
@@ -217,6 +222,25 @@ pub trait IsServer {
         .try_into()
         .map_err(|_| ())
     }
+
+    /// The extranonce of the connection changed, eg a translator got a new extranonce prefix for
+    /// the upstream sv2 channel. If the client subscribed to the extranonce changes return the
+    /// mining.set_extranonce to send, otherwise return None: the client can not learn the new
+    /// extranonce and must be disconnected so that it subscribe again.
+    fn on_extranonce_change(
+        &mut self,
+        extra_nonce1: HexBytes,
+        extra_nonce2_size: usize,
+    ) -> Result<Option<json_rpc::Message>, ()> {
+        if self.is_extranonce_subscribed() {
+            self.update_extranonce(extra_nonce1, extra_nonce2_size)
+                .map(Some)
+        } else {
+            self.set_extranonce1(Some(extra_nonce1));
+            self.set_extranonce2_size(Some(extra_nonce2_size));
+            Ok(None)
+        }
+    }
     // {"params":["00003000"], "id":null, "method": "mining.set_version_mask"}
     // fn update_version_rolling_mask
 
@@ -291,7 +315,12 @@ pub trait IsClient {
                 Ok(None)
             }
             methods::Server2Client::SetDifficulty(_set_diff) => todo!(),
-            methods::Server2Client::SetExtranonce(_set_extra_nonce) => todo!(),
+            methods::Server2Client::SetExtranonce(set_extra_nonce) => {
+                // The new extranonce is used from the next mining.notify
+                self.set_extranonce1(set_extra_nonce.extra_nonce1);
+                self.set_extranonce2_size(set_extra_nonce.extra_nonce2_size);
+                Ok(None)
+            }
             methods::Server2Client::SetVersionMask(_set_version_mask) => todo!(),
         }
     }
@@ -401,6 +430,16 @@ pub trait IsClient {
         }
     }
 
+    /// Ask the server to send mining.set_extranonce when the extranonce change, a proxy that
+    /// serve sv1 clients with the extranonce of its upstream must send it in order to follow the
+    /// extranonce changes of the upstream
+    fn extranonce_subscribe(&mut self, id: String) -> Result<json_rpc::Message, ()> {
+        match self.status() {
+            ClientStatus::Init => Err(()),
+            _ => Ok(client_to_server::ExtranonceSubscribe { id }.into()),
+        }
+    }
+
     fn authorize(
         &mut self,
         id: String,
@@ -96,8 +96,47 @@ fn from_to_json_rpc(auth: Authorize) -> bool {
 /// _mining.extranonce.subscribe()_
 /// Indicates to the server that the client supports the mining.set_extranonce method.
 /// https://en.bitcoin.it/wiki/BIP_0310
-#[derive(Debug)]
-pub struct ExtranonceSubscribe();
+///
+/// The result is true if the server will send mining.set_extranonce when the extranonce of the
+/// connection change (some pools answer with an error instead of false).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtranonceSubscribe {
+    pub id: String,
+}
+
+impl ExtranonceSubscribe {
+    pub fn respond(self, is_ok: bool) -> Response {
+        // infallible
+        let result = serde_json::to_value(is_ok).unwrap();
+        Response {
+            id: self.id,
+            result,
+            error: None,
+        }
+    }
+}
+
+impl From<ExtranonceSubscribe> for Message {
+    fn from(subscribe: ExtranonceSubscribe) -> Self {
+        Message::StandardRequest(StandardRequest {
+            id: subscribe.id,
+            method: "mining.extranonce.subscribe".into(),
+            parameters: JArrary(vec![]),
+        })
+    }
+}
+
+#[cfg(test)]
+#[quickcheck_macros::quickcheck]
+fn extranonce_subscribe_from_to_json_rpc(id: String) -> bool {
+    use crate::methods::{Client2Server, Method};
+    let subscribe = ExtranonceSubscribe { id };
+    let message = Into::<Message>::into(subscribe.clone());
+    match TryInto::<Method>::try_into(message) {
+        Ok(Method::Client2Server(Client2Server::ExtranonceSubscribe(s))) => s == subscribe,
+        _ => false,
+    }
+}
 
 // mining.get_transactions
 
@@ -213,7 +213,9 @@ impl TryFrom<Message> for Method {
                     Ok(Method::Client2Server(Client2Server::Authorize(method)))
                 }
                 "mining.extranonce.subscribe" => Ok(Method::Client2Server(
-                    Client2Server::ExtranonceSubscribe(client_to_server::ExtranonceSubscribe()),
+                    Client2Server::ExtranonceSubscribe(client_to_server::ExtranonceSubscribe {
+                        id: request.id.clone(),
+                    }),
                 )),
                 "mining.submit" => {
                     let method = request
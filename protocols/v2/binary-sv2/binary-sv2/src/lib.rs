@@ -2,6 +2,9 @@
 //
 use core::convert::TryInto;
 
+#[cfg(all(feature = "with_serde", feature = "with_json"))]
+compile_error!("with_json can not be used with with_serde: the serde impls would conflict");
+
 #[cfg(feature = "with_serde")]
 pub use serde::{self, Deserialize, Serialize};
 #[cfg(feature = "with_serde")]
@@ -62,6 +65,21 @@ mod test {
 
             assert_eq!(deserialized, expected);
         }
+
+        #[cfg(not(feature = "with_serde"))]
+        #[test]
+        fn test_truncated_struct() {
+            let expected = Test {
+                a: 456,
+                b: 9,
+                c: 67_u32.try_into().unwrap(),
+            };
+            let mut bytes = to_bytes(expected).unwrap();
+
+            let deserialized: Result<Test, _> = from_bytes(&mut bytes[..2]);
+
+            assert!(matches!(deserialized, Err(Error::OutOfBound)));
+        }
     }
 
     mod test_f32 {
@@ -755,4 +773,49 @@ mod test {
             assert_eq!(deserialized, expected);
         }
     }
+
+    #[cfg(feature = "with_json")]
+    mod test_json {
+        use super::*;
+        use core::convert::TryInto;
+
+        #[derive(
+            Deserialize, Serialize, PartialEq, Debug, Clone, serde::Serialize, serde::Deserialize,
+        )]
+        struct Test<'decoder> {
+            a: U24,
+            b: U256<'decoder>,
+            c: Str032<'decoder>,
+            d: Seq0255<'decoder, B0255<'decoder>>,
+        }
+
+        #[test]
+        fn test_json_round_trip() {
+            let mut b = [1_u8; 32];
+            let mut c = b"error-code".to_vec();
+            let expected = Test {
+                a: 67_u32.try_into().unwrap(),
+                b: (&mut b[..]).try_into().unwrap(),
+                c: (&mut c[..]).try_into().unwrap(),
+                d: Seq0255::new(vec![vec![255_u8, 0].try_into().unwrap()]).unwrap(),
+            };
+
+            let json = serde_json::to_string(&expected).unwrap();
+            assert_eq!(
+                json,
+                format!(
+                    r#"{{"a":67,"b":"{}","c":"6572726f722d636f6465","d":["ff00"]}}"#,
+                    "01".repeat(32)
+                )
+            );
+            let deserialized: Test = serde_json::from_str(&json).unwrap();
+            assert_eq!(deserialized, expected);
+        }
+
+        #[test]
+        fn test_json_invalid_len() {
+            let json = r#"{"a":67,"b":"0101","c":"","d":[]}"#;
+            assert!(serde_json::from_str::<Test>(json).is_err());
+        }
+    }
 }
@@ -22,6 +22,10 @@ pub trait Decodable<'a>: Sized {
 
         for field in structure {
             let field_size = field.size_hint_(tail, 0)?;
+            // A truncated message must be an error not a panic
+            if field_size > tail.len() {
+                return Err(Error::OutOfBound);
+            }
             let (head, t) = tail.split_at_mut(field_size);
             tail = t;
             fields.push(field.decode(head)?);
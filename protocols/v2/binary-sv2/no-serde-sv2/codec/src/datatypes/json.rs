@@ -0,0 +1,140 @@
+//! Serde implementations used to encode the Sv2 data types in human readable formats like json.
+//!
+//! They are not used to encode Sv2 messages on the wire (that is done by `Encodable` and
+//! `Decodable`) but by tooling that need to dump, store or replay messages:
+//! * bytes types (`U256`, `PubKey`, `Signature`, `B0xx`, `Str0xx`, ..) are hex strings
+//! * `U24` is a number
+//! * `Seq0255` and `Seq064K` are arrays
+//!
+//! Deserialized values always own their data so they can be built from any deserializer.
+use super::{Inner, Seq0255, Seq064K, U24};
+use core::{convert::TryFrom, fmt};
+use serde::{
+    de::{Error as DeError, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+const HEX_CHARS: &[u8; 16] = b"0123456789abcdef";
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        hex.push(HEX_CHARS[(b >> 4) as usize] as char);
+        hex.push(HEX_CHARS[(b & 0x0f) as usize] as char);
+    }
+    hex
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    fn nibble(c: u8) -> Option<u8> {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            b'A'..=b'F' => Some(c - b'A' + 10),
+            _ => None,
+        }
+    }
+    let chunks = hex.as_bytes().chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    chunks
+        .map(|pair| Some((nibble(pair[0])? << 4) | nibble(pair[1])?))
+        .collect()
+}
+
+impl<'a, const ISFIXED: bool, const SIZE: usize, const HEADERSIZE: usize, const MAXSIZE: usize>
+    Serialize for Inner<'a, ISFIXED, SIZE, HEADERSIZE, MAXSIZE>
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&to_hex(self.as_ref()))
+    }
+}
+
+struct HexVisitor<
+    const ISFIXED: bool,
+    const SIZE: usize,
+    const HEADERSIZE: usize,
+    const MAXSIZE: usize,
+>;
+
+impl<
+        'de,
+        const ISFIXED: bool,
+        const SIZE: usize,
+        const HEADERSIZE: usize,
+        const MAXSIZE: usize,
+    > Visitor<'de> for HexVisitor<ISFIXED, SIZE, HEADERSIZE, MAXSIZE>
+{
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        if ISFIXED {
+            write!(formatter, "an hex string of {} bytes", SIZE)
+        } else {
+            write!(formatter, "an hex string of at most {} bytes", MAXSIZE)
+        }
+    }
+
+    fn visit_str<E: DeError>(self, value: &str) -> Result<Self::Value, E> {
+        from_hex(value).ok_or_else(|| E::custom("invalid hex string"))
+    }
+}
+
+impl<
+        'de,
+        'a,
+        const ISFIXED: bool,
+        const SIZE: usize,
+        const HEADERSIZE: usize,
+        const MAXSIZE: usize,
+    > Deserialize<'de> for Inner<'a, ISFIXED, SIZE, HEADERSIZE, MAXSIZE>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes =
+            deserializer.deserialize_str(HexVisitor::<ISFIXED, SIZE, HEADERSIZE, MAXSIZE>)?;
+        let len = bytes.len();
+        Self::try_from(bytes).map_err(|_| D::Error::custom(format!("invalid length {}", len)))
+    }
+}
+
+impl Serialize for U24 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for U24 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = u32::deserialize(deserializer)?;
+        Self::try_from(value).map_err(|_| D::Error::custom(format!("{} do not fit in U24", value)))
+    }
+}
+
+impl<'a, T: Serialize> Serialize for Seq0255<'a, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, 'a, T: Deserialize<'de>> Deserialize<'de> for Seq0255<'a, T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let inner = Vec::<T>::deserialize(deserializer)?;
+        let len = inner.len();
+        Self::new(inner).map_err(|_| D::Error::custom(format!("sequence too long {}", len)))
+    }
+}
+
+impl<'a, T: Serialize> Serialize for Seq064K<'a, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, 'a, T: Deserialize<'de>> Deserialize<'de> for Seq064K<'a, T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let inner = Vec::<T>::deserialize(deserializer)?;
+        let len = inner.len();
+        Self::new(inner).map_err(|_| D::Error::custom(format!("sequence too long {}", len)))
+    }
+}
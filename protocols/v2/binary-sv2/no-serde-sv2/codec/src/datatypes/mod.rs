@@ -5,6 +5,8 @@ use crate::{
 mod non_copy_data_types;
 
 mod copy_data_types;
+#[cfg(feature = "with_json")]
+mod json;
 use crate::codec::decodable::FieldMarker;
 pub use copy_data_types::U24;
 pub use non_copy_data_types::{
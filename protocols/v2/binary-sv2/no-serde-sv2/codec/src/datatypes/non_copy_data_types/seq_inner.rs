@@ -31,6 +31,28 @@ impl<'a, const SIZE: usize> Seq0255<'a, super::inner::Inner<'a, true, SIZE, 0, 0
     }
 }
 
+// TODO add test for that and implement it also with serde!!!!
+impl<'a, const SIZE: usize, const HEADERSIZE: usize, const MAXSIZE: usize>
+    Seq064K<'a, super::inner::Inner<'a, false, SIZE, HEADERSIZE, MAXSIZE>>
+{
+    pub fn to_vec(&self) -> Vec<Vec<u8>> {
+        self.0.iter().map(|x| x.to_vec()).collect()
+    }
+    pub fn inner_as_ref(&self) -> Vec<&[u8]> {
+        self.0.iter().map(|x| x.inner_as_ref()).collect()
+    }
+}
+
+// TODO add test for that and implement it also with serde!!!!
+impl<'a, const SIZE: usize> Seq064K<'a, super::inner::Inner<'a, true, SIZE, 0, 0>> {
+    pub fn to_vec(&self) -> Vec<Vec<u8>> {
+        self.0.iter().map(|x| x.to_vec()).collect()
+    }
+    pub fn inner_as_ref(&self) -> Vec<&[u8]> {
+        self.0.iter().map(|x| x.inner_as_ref()).collect()
+    }
+}
+
 #[cfg(not(feature = "no_std"))]
 use std::io::Read;
 
@@ -1,5 +1,77 @@
 use alloc::vec::Vec;
 
+/// Frames up to this size (most mining frames, eg shares and targets) are copied inline in a
+/// `FrameBytes`, without allocating, and the buffer keep its memory for the next frame. The longer
+/// frames take the memory of the buffer instead of being copied, and the buffer allocate again for
+/// the next frame
+pub const SMALL_FRAME_MAX_LEN: usize = 64;
+
+/// Bytes of a decoded frame, the frames up to `SMALL_FRAME_MAX_LEN` bytes are stored inline
+#[derive(Debug, Clone)]
+pub enum FrameBytes {
+    /// The first `len` bytes are the frame
+    Inline([u8; SMALL_FRAME_MAX_LEN], usize),
+    Heap(Vec<u8>),
+}
+
+impl FrameBytes {
+    /// Copy `bytes` inline if they fit, in a new `Vec` otherwise
+    pub fn copy_from_slice(bytes: &[u8]) -> Self {
+        if bytes.len() <= SMALL_FRAME_MAX_LEN {
+            let mut inline = [0; SMALL_FRAME_MAX_LEN];
+            inline[..bytes.len()].copy_from_slice(bytes);
+            Self::Inline(inline, bytes.len())
+        } else {
+            Self::Heap(bytes.to_vec())
+        }
+    }
+
+    pub fn is_inline(&self) -> bool {
+        matches!(self, Self::Inline(_, _))
+    }
+}
+
+impl AsRef<[u8]> for FrameBytes {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Self::Inline(bytes, len) => &bytes[..*len],
+            Self::Heap(bytes) => &bytes[..],
+        }
+    }
+}
+
+impl AsMut<[u8]> for FrameBytes {
+    fn as_mut(&mut self) -> &mut [u8] {
+        match self {
+            Self::Inline(bytes, len) => &mut bytes[..*len],
+            Self::Heap(bytes) => &mut bytes[..],
+        }
+    }
+}
+
+impl From<Vec<u8>> for FrameBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Heap(bytes)
+    }
+}
+
+impl From<FrameBytes> for Vec<u8> {
+    fn from(bytes: FrameBytes) -> Self {
+        match bytes {
+            FrameBytes::Inline(bytes, len) => bytes[..len].to_vec(),
+            FrameBytes::Heap(bytes) => bytes,
+        }
+    }
+}
+
+impl PartialEq for FrameBytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_ref() == other.as_ref()
+    }
+}
+
+impl Eq for FrameBytes {}
+
 pub trait Buffer {
     type Slice: AsMut<[u8]> + AsRef<[u8]>;
 
@@ -10,6 +82,12 @@ pub trait Buffer {
     fn get_data_by_ref(&mut self, header_size: usize) -> &mut [u8];
 
     fn len(&self) -> usize;
+
+    /// Discard the written data, the memory of the buffer is kept
+    fn clear(&mut self);
+
+    /// Discard the data written after the first `len` bytes
+    fn truncate(&mut self, len: usize);
 }
 
 #[derive(Debug)]
@@ -34,7 +112,7 @@ impl Default for SlowAndCorrect {
 }
 
 impl Buffer for SlowAndCorrect {
-    type Slice = Vec<u8>;
+    type Slice = FrameBytes;
 
     #[inline]
     fn get_writable(&mut self, len: usize) -> &mut [u8] {
@@ -51,12 +129,17 @@ impl Buffer for SlowAndCorrect {
     }
 
     #[inline]
-    fn get_data_owned(&mut self) -> Vec<u8> {
+    fn get_data_owned(&mut self) -> FrameBytes {
+        if self.cursor <= SMALL_FRAME_MAX_LEN {
+            let head = FrameBytes::copy_from_slice(&self.inner[..self.cursor]);
+            self.cursor = 0;
+            return head;
+        }
         let mut tail = self.inner.split_off(self.cursor);
         core::mem::swap(&mut tail, &mut self.inner);
         let head = tail;
         self.cursor = 0;
-        head
+        FrameBytes::Heap(head)
     }
 
     #[inline]
@@ -68,4 +151,84 @@ impl Buffer for SlowAndCorrect {
     fn len(&self) -> usize {
         self.cursor
     }
+
+    #[inline]
+    fn clear(&mut self) {
+        self.cursor = 0;
+    }
+
+    #[inline]
+    fn truncate(&mut self, len: usize) {
+        self.cursor = self.cursor.min(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn write(buffer: &mut SlowAndCorrect, bytes: &[u8]) {
+        buffer.get_writable(bytes.len()).copy_from_slice(bytes);
+    }
+
+    #[test]
+    fn small_frames_are_inline_and_the_buffer_keep_its_memory() {
+        let mut buffer = SlowAndCorrect::new();
+        write(&mut buffer, &[1; SMALL_FRAME_MAX_LEN]);
+        let memory = buffer.inner.as_ptr();
+        let frame = buffer.get_data_owned();
+        assert!(frame.is_inline());
+        assert_eq!(frame.as_ref(), &[1; SMALL_FRAME_MAX_LEN][..]);
+        assert_eq!(buffer.len(), 0);
+
+        write(&mut buffer, &[2; 8]);
+        assert_eq!(buffer.inner.as_ptr(), memory);
+        let frame = buffer.get_data_owned();
+        assert!(frame.is_inline());
+        assert_eq!(frame.as_ref(), &[2; 8]);
+    }
+
+    #[test]
+    fn big_frames_take_the_memory_of_the_buffer() {
+        let mut buffer = SlowAndCorrect::new();
+        write(&mut buffer, &[1; SMALL_FRAME_MAX_LEN + 1]);
+        let memory = buffer.inner.as_ptr();
+        let frame = buffer.get_data_owned();
+        assert!(!frame.is_inline());
+        assert_eq!(frame.as_ref(), &[1; SMALL_FRAME_MAX_LEN + 1][..]);
+        assert_eq!(frame.as_ref().as_ptr(), memory);
+        assert_eq!(buffer.len(), 0);
+
+        write(&mut buffer, &[2; SMALL_FRAME_MAX_LEN + 1]);
+        assert_eq!(
+            buffer.get_data_owned().as_ref(),
+            &[2; SMALL_FRAME_MAX_LEN + 1][..]
+        );
+    }
+
+    #[test]
+    fn frame_bytes_convert_from_and_to_vec() {
+        let inline = FrameBytes::copy_from_slice(&[3; 4]);
+        assert!(inline.is_inline());
+        assert_eq!(Vec::from(inline.clone()), vec![3; 4]);
+        let heap = FrameBytes::from(vec![3; 4]);
+        assert!(!heap.is_inline());
+        assert_eq!(heap, inline);
+        assert!(!FrameBytes::copy_from_slice(&[3; SMALL_FRAME_MAX_LEN + 1]).is_inline());
+    }
+
+    #[test]
+    fn truncate_and_clear_discard_the_written_data() {
+        let mut buffer = SlowAndCorrect::new();
+        write(&mut buffer, &[1, 2, 3, 4]);
+        buffer.truncate(8);
+        assert_eq!(buffer.len(), 4);
+        buffer.truncate(2);
+        assert_eq!(buffer.get_data_by_ref(8), &[1, 2]);
+        buffer.clear();
+        assert_eq!(buffer.len(), 0);
+        write(&mut buffer, &[5]);
+        assert_eq!(buffer.get_data_owned().as_ref(), &[5]);
+    }
 }
@@ -3,6 +3,9 @@ use binary_sv2::Deserialize;
 #[cfg(feature = "noise_sv2")]
 use binary_sv2::GetSize;
 use binary_sv2::Serialize;
+use const_sv2::SV2_FRAME_MAX_LEN;
+#[cfg(feature = "noise_sv2")]
+use core::cmp::Ordering;
 use core::marker::PhantomData;
 #[cfg(feature = "noise_sv2")]
 use framing_sv2::framing2::{HandShakeFrame, NoiseFrame};
@@ -18,7 +21,7 @@ use crate::{
     error::{Error, Result},
 };
 #[cfg(feature = "noise_sv2")]
-use crate::{State, TransportMode};
+use crate::{padding::is_padding, State, TransportMode};
 
 #[cfg(feature = "noise_sv2")]
 pub type StandardNoiseDecoder<T> = WithNoise<SlowAndCorrect, T>;
@@ -29,99 +32,105 @@ pub type StandardDecoder<T> = WithoutNoise<SlowAndCorrect, T>;
 #[cfg(feature = "noise_sv2")]
 pub struct WithNoise<B: Buffer, T: Serialize + binary_sv2::GetSize> {
     frame: PhantomData<T>,
-    missing_noise_b: usize,
     noise_buffer: B,
+    /// Decrypted payloads of the noise frames, an sv2 frame longer than a noise frame is split in
+    /// more noise frames
     sv2_buffer: B,
-    sv2_frame_size: usize,
+    max_frame_len: usize,
 }
 
 #[cfg(feature = "noise_sv2")]
 impl<'a, T: Serialize + GetSize + Deserialize<'a>, B: Buffer> WithNoise<B, T> {
     #[inline]
     pub fn next_frame(&mut self, state: &mut State) -> Result<EitherFrame<T, B::Slice>> {
-        let len = self.noise_buffer.len();
-        let src = self.noise_buffer.get_data_by_ref(len);
-        let hint = NoiseFrame::size_hint(src) as usize;
-
-        match hint {
-            0 => {
-                self.missing_noise_b = NoiseHeader::SIZE;
-                self.decode_noise_frame(state)
-            }
-            _ => {
-                self.missing_noise_b = hint;
-                Err(Error::MissingBytes(hint))
-            }
+        match self.missing_bytes() {
+            0 => self.decode_noise_frame(state),
+            missing => Err(Error::MissingBytes(missing)),
         }
     }
 
+    /// Bytes missing to complete the noise header or the noise frame being read
+    #[inline]
+    fn missing_bytes(&mut self) -> usize {
+        let len = self.noise_buffer.len();
+        let hint = NoiseFrame::size_hint(self.noise_buffer.get_data_by_ref(len));
+        hint.max(0) as usize
+    }
+
     #[inline]
     fn decode_noise_frame(&mut self, state: &mut State) -> Result<EitherFrame<T, B::Slice>> {
         match state {
             State::Transport(transport_mode) => {
-                // STRIP THE HEADER FROM THE FRAME AND GET THE ENCRYPTED PAYLOAD
+                // STRIP THE HEADER FROM THE FRAME AND GET THE ENCRYPTED PAYLOAD, THE PAYLOAD IS
+                // DECRYPTED IN THE SV2 BUFFER AND THE NOISE BUFFER IS CLEARED SO THAT ITS MEMORY IS
+                // REUSED FOR THE NEXT FRAME
                 // everything here can not fail as the size has been already checked
-                let src = self.noise_buffer.get_data_owned().as_mut().to_vec();
-                let mut noise_frame = NoiseFrame::from_bytes_unchecked(src);
-                let src = noise_frame.payload();
+                let len = self.noise_buffer.len();
+                let src = &self.noise_buffer.get_data_by_ref(len)[NoiseHeader::SIZE..];
 
                 // DECRYPT THE ENCRYPTED PAYLOAD
-                let len = TransportMode::size_hint_decrypt(src.len()).ok_or(())?;
-                let decrypted = self.sv2_buffer.get_writable(len);
-                transport_mode.read(src, decrypted).map_err(|_| ())?;
-
-                // IF THE DECODER IS RECEIVING A FRAGMENTED FRAME ADD THE DECRYPTED DATA TO THE
-                // PARTIAL FRAME AND CHECK IF READY
-                if self.sv2_frame_size > 0 {
-                    return Ok(self.handle_fragmented().ok_or(())?);
+                let decrypted_len = TransportMode::size_hint_decrypt(src.len());
+                let res = match decrypted_len {
+                    Some(decrypted_len) => {
+                        let decrypted = self.sv2_buffer.get_writable(decrypted_len);
+                        transport_mode.read(src, decrypted).map_err(|_| ())
+                    }
+                    None => Err(()),
                 };
-
-                let len = self.sv2_buffer.len();
-                let src = self.sv2_buffer.get_data_by_ref(len);
-                let hint = Sv2Frame::<T, B::Slice>::size_hint(src);
-
-                // IF HINT IS 0 A COMPLETE SV2 FRAME IS AVAIABLE THIS IS THE HOT PATH AS USUALLY
-                // THE SIZE OF AN SV2 MESSAGE IS SMALLER THE THE MAX SIZE OF A NOISE FRAME
-                if hint == 0 {
-                    let src = self.sv2_buffer.get_data_owned();
-                    let frame = Sv2Frame::<T, B::Slice>::from_bytes_unchecked(src);
-                    return Ok(frame.into());
+                self.noise_buffer.clear();
+                if res.is_err() {
+                    // THE SV2 BUFFER WOULD CONTAIN GARBAGE, A FAILED DECRYPTION IS FATAL FOR THE
+                    // SESSION
+                    self.sv2_buffer.clear();
+                    return Err(Error::TagMismatch);
                 }
-
-                // IF HINT IS NOT 0 AND MISSING BYTES IS 0 IT MEAN THAT THE FIRST FRAGMENT OF AN
-                // SV2 HAS BEEN RECEIVED
-                self.handle_fragmented().ok_or(())?;
-                Err(Error::MissingBytes(self.missing_noise_b))
+                self.decode_sv2_frame()
             }
             State::HandShake(_) => Ok(self.while_handshaking()),
             State::NotInitialized => Ok(self.while_handshaking()),
         }
     }
 
-    #[inline(never)]
-    fn handle_fragmented(&mut self) -> Option<EitherFrame<T, B::Slice>> {
-        // IF IS NOT THE FIRST FRAGMENT CHECK IF A COMPLETE FRAME IS AVAIBLE IF YES RETURN THE
-        // FRAME IF NOT SET MISSING NOISE BYTES TO NOISE HEADER SIZE SO THE DECODER CAN START TO
-        // DECODE THE NEXT NOISE FRAME
+    /// Return the sv2 frame if the decrypted payloads make a complete frame, if not the decoder
+    /// wait for the next noise frame
+    #[inline]
+    fn decode_sv2_frame(&mut self) -> Result<EitherFrame<T, B::Slice>> {
         let len = self.sv2_buffer.len();
-        let src = self.sv2_buffer.get_data_by_ref(len);
-        let hint = Sv2Frame::<T, B::Slice>::size_hint(src);
-        if self.sv2_frame_size != 0 {
-            if hint == 0 {
+        let header = match Header::from_bytes(self.sv2_buffer.get_data_by_ref(len)) {
+            Ok(header) => header,
+            // THE FIRST FRAGMENT IS SHORTER THAN THE SV2 HEADER
+            Err(_) => return Err(Error::MissingBytes(NoiseHeader::SIZE)),
+        };
+        if header.len() > self.max_frame_len {
+            self.sv2_buffer.clear();
+            return Err(Error::FrameTooLarge {
+                len: header.len(),
+                max: self.max_frame_len,
+            });
+        }
+        match (len - Header::SIZE).cmp(&header.len()) {
+            // THIS IS THE HOT PATH AS USUALLY THE SIZE OF AN SV2 MESSAGE IS SMALLER THAN THE MAX
+            // SIZE OF A NOISE FRAME
+            Ordering::Equal => {
                 let src = self.sv2_buffer.get_data_owned();
                 let frame = Sv2Frame::<T, B::Slice>::from_bytes_unchecked(src);
-                Some(frame.into())
-            } else {
-                self.missing_noise_b = NoiseHeader::SIZE;
-                None
+                Ok(frame.into())
+            }
+            Ordering::Less => Err(Error::MissingBytes(NoiseHeader::SIZE)),
+            // A NOISE FRAME NEVER CARRY BYTES OF TWO SV2 FRAMES, THE ONLY BYTES THAT CAN FOLLOW THE
+            // SV2 FRAME ARE A PADDING FRAME
+            Ordering::Greater => {
+                let frame_len = Header::SIZE + header.len();
+                if is_padding(&self.sv2_buffer.get_data_by_ref(len)[frame_len..]) {
+                    self.sv2_buffer.truncate(frame_len);
+                    let src = self.sv2_buffer.get_data_owned();
+                    let frame = Sv2Frame::<T, B::Slice>::from_bytes_unchecked(src);
+                    Ok(frame.into())
+                } else {
+                    self.sv2_buffer.clear();
+                    Err(Error::InvalidFragment)
+                }
             }
-
-        // IF IS THE FIRST FRAGMETN JUST SET THE MISSING SV2 AND NOISE BYTES
-        } else {
-            self.sv2_frame_size = hint as usize;
-            self.missing_noise_b = NoiseHeader::SIZE;
-
-            None
         }
     }
 
@@ -134,9 +143,26 @@ impl<'a, T: Serialize + GetSize + Deserialize<'a>, B: Buffer> WithNoise<B, T> {
         frame.into()
     }
 
+    /// Buffer where the missing bytes of the current noise frame must be written, see
+    /// `WithoutNoise::writable`
     #[inline]
     pub fn writable(&mut self) -> &mut [u8] {
-        self.noise_buffer.get_writable(self.missing_noise_b)
+        let missing = self.missing_bytes();
+        self.noise_buffer.get_writable(missing)
+    }
+
+    /// See `WithoutNoise::push_bytes`
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> usize {
+        let len = self.missing_bytes().min(bytes.len());
+        self.noise_buffer
+            .get_writable(len)
+            .copy_from_slice(&bytes[..len]);
+        len
+    }
+
+    /// See `WithoutNoise::set_max_frame_len`
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len.min(SV2_FRAME_MAX_LEN);
     }
 }
 
@@ -145,10 +171,9 @@ impl<T: Serialize + binary_sv2::GetSize> WithNoise<SlowAndCorrect, T> {
     pub fn new() -> Self {
         Self {
             frame: PhantomData,
-            missing_noise_b: 0,
             noise_buffer: SlowAndCorrect::new(),
             sv2_buffer: SlowAndCorrect::new(),
-            sv2_frame_size: 0,
+            max_frame_len: SV2_FRAME_MAX_LEN,
         }
     }
 }
@@ -160,36 +185,84 @@ impl<T: Serialize + binary_sv2::GetSize> Default for WithNoise<SlowAndCorrect, T
     }
 }
 
+/// Part of the sv2 frame that the decoder is reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadState {
+    Header,
+    /// The header has been read, the body has this length
+    Body(usize),
+}
+
 #[derive(Debug)]
 pub struct WithoutNoise<B: Buffer, T: Serialize + binary_sv2::GetSize> {
     frame: PhantomData<T>,
-    missing_b: usize,
+    state: ReadState,
     buffer: B,
+    max_frame_len: usize,
 }
 
 impl<T: Serialize + binary_sv2::GetSize, B: Buffer> WithoutNoise<B, T> {
+    /// Return the frame if the header and the whole body have been written, if not the missing
+    /// bytes of the header or of the body. A frame longer than the max frame len is rejected with
+    /// `Error::FrameTooLarge`, after that the stream can not be decoded anymore.
     #[inline]
     pub fn next_frame(&mut self) -> Result<Sv2Frame<T, B::Slice>> {
-        let len = self.buffer.len();
-        let src = self.buffer.get_data_by_ref(len);
-        let hint = Sv2Frame::<T, B::Slice>::size_hint(src) as usize;
-
-        match hint {
+        if self.state == ReadState::Header {
+            match self.missing_bytes() {
+                0 => (),
+                missing => return Err(Error::MissingBytes(missing)),
+            }
+            let header =
+                Header::from_bytes(self.buffer.get_data_by_ref(Header::SIZE)).map_err(|_| ())?;
+            if header.len() > self.max_frame_len {
+                self.buffer.clear();
+                return Err(Error::FrameTooLarge {
+                    len: header.len(),
+                    max: self.max_frame_len,
+                });
+            }
+            self.state = ReadState::Body(header.len());
+        }
+        match self.missing_bytes() {
             0 => {
-                self.missing_b = Header::SIZE;
+                self.state = ReadState::Header;
                 let src = self.buffer.get_data_owned();
-                let frame = Sv2Frame::<T, B::Slice>::from_bytes_unchecked(src);
-                Ok(frame)
-            }
-            _ => {
-                self.missing_b = hint;
-                Err(Error::MissingBytes(self.missing_b))
+                Ok(Sv2Frame::<T, B::Slice>::from_bytes_unchecked(src))
             }
+            missing => Err(Error::MissingBytes(missing)),
         }
     }
 
+    /// Bytes missing to complete the header or the body being read
+    #[inline]
+    fn missing_bytes(&self) -> usize {
+        let expected = match self.state {
+            ReadState::Header => Header::SIZE,
+            ReadState::Body(len) => Header::SIZE + len,
+        };
+        expected.saturating_sub(self.buffer.len())
+    }
+
+    /// Buffer where the missing bytes of the header or of the body must be written, once it is
+    /// filled `next_frame` must be called
     pub fn writable(&mut self) -> &mut [u8] {
-        self.buffer.get_writable(self.missing_b)
+        let missing = self.missing_bytes();
+        self.buffer.get_writable(missing)
+    }
+
+    /// Copy from `bytes` at most the missing bytes of the header or of the body and return how many
+    /// bytes have been copied, for the readers that get chunks of any size (eg WebSocket messages)
+    /// instead of reading exactly `writable`. `next_frame` must be called after every push.
+    pub fn push_bytes(&mut self, bytes: &[u8]) -> usize {
+        let len = self.missing_bytes().min(bytes.len());
+        self.buffer.get_writable(len).copy_from_slice(&bytes[..len]);
+        len
+    }
+
+    /// Frames longer than `max_frame_len` (at most and by default `SV2_FRAME_MAX_LEN`) are
+    /// rejected, so that a peer can not make the decoder allocate 16MB for every frame
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len.min(SV2_FRAME_MAX_LEN);
     }
 }
 
@@ -197,8 +270,9 @@ impl<T: Serialize + binary_sv2::GetSize> WithoutNoise<SlowAndCorrect, T> {
     pub fn new() -> Self {
         Self {
             frame: PhantomData,
-            missing_b: Header::SIZE,
+            state: ReadState::Header,
             buffer: SlowAndCorrect::new(),
+            max_frame_len: SV2_FRAME_MAX_LEN,
         }
     }
 }
@@ -208,3 +282,289 @@ impl<T: Serialize + binary_sv2::GetSize> Default for WithoutNoise<SlowAndCorrect
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoder::Encoder, FrameBytes, SMALL_FRAME_MAX_LEN};
+    #[cfg(feature = "noise_sv2")]
+    use crate::{encoder::NoiseEncoder, HandshakeRole, Initiator, PaddingPolicy, Responder};
+    use alloc::{vec, vec::Vec};
+    #[cfg(feature = "noise_sv2")]
+    use core::{convert::TryInto, time::Duration};
+
+    /// Length of the chunks in which the bytes are pushed, like the reads of a fragmented stream
+    const SPLITS: [usize; 7] = [1, 7, 2, 13, 5, 64, 3];
+
+    /// Serialized sv2 frame with a payload of `len` bytes
+    fn raw_frame(len: usize) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&0_u16.to_le_bytes());
+        frame.push(1);
+        frame.extend_from_slice(&(len as u32).to_le_bytes()[..3]);
+        frame.extend((0..len).map(|i| i as u8));
+        frame
+    }
+
+    fn serialized(frame: StandardSv2Frame<u32>) -> Vec<u8> {
+        frame_bytes(frame).into()
+    }
+
+    fn frame_bytes(frame: StandardSv2Frame<u32>) -> FrameBytes {
+        let mut bytes = FrameBytes::from(Vec::new());
+        frame.serialize(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Push `bytes` until a frame is decoded, return the frame and the bytes not pushed
+    fn decode_one<'a>(
+        decoder: &mut StandardDecoder<u32>,
+        mut bytes: &'a [u8],
+    ) -> (StandardSv2Frame<u32>, &'a [u8]) {
+        loop {
+            bytes = &bytes[decoder.push_bytes(bytes)..];
+            match decoder.next_frame() {
+                Ok(frame) => return (frame, bytes),
+                Err(Error::MissingBytes(_)) => assert!(!bytes.is_empty()),
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+    }
+
+    /// Push `bytes` in chunks of the lengths in `splits` (repeated) and return the decoded frames
+    fn decode_plain(
+        decoder: &mut StandardDecoder<u32>,
+        bytes: &[u8],
+        splits: &[usize],
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        for split in splits.iter().cycle() {
+            if offset == bytes.len() {
+                break;
+            }
+            let end = (offset + split).min(bytes.len());
+            while offset < end {
+                offset += decoder.push_bytes(&bytes[offset..end]);
+                match decoder.next_frame() {
+                    Ok(frame) => frames.push(serialized(frame)),
+                    Err(Error::MissingBytes(_)) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(frames)
+    }
+
+    fn frames() -> Vec<Vec<u8>> {
+        [10, 200, 0, 20, 1000]
+            .iter()
+            .map(|len| raw_frame(*len))
+            .collect()
+    }
+
+    #[test]
+    fn decode_frames_pushed_one_byte_at_a_time() {
+        let frames = frames();
+        let bytes = frames.concat();
+        let mut decoder = StandardDecoder::<u32>::new();
+        assert_eq!(decode_plain(&mut decoder, &bytes, &[1]).unwrap(), frames);
+    }
+
+    #[test]
+    fn decode_frames_pushed_in_arbitrary_splits() {
+        let frames = frames();
+        let bytes = frames.concat();
+        let mut decoder = StandardDecoder::<u32>::new();
+        assert_eq!(decode_plain(&mut decoder, &bytes, &SPLITS).unwrap(), frames);
+        let mut decoder = StandardDecoder::<u32>::new();
+        assert_eq!(
+            decode_plain(&mut decoder, &bytes, &[bytes.len()]).unwrap(),
+            frames
+        );
+    }
+
+    #[test]
+    fn frames_up_to_the_small_frame_max_len_are_inline() {
+        let payload_len = SMALL_FRAME_MAX_LEN - Header::SIZE;
+        let frames = [raw_frame(payload_len), raw_frame(payload_len + 1)];
+        let bytes = frames.concat();
+        let mut decoder = StandardDecoder::<u32>::new();
+
+        let (small, rest) = decode_one(&mut decoder, &bytes);
+        let small = frame_bytes(small);
+        assert!(small.is_inline());
+        assert_eq!(small.as_ref(), &frames[0][..]);
+        let big = frame_bytes(decode_one(&mut decoder, rest).0);
+        assert!(!big.is_inline());
+        assert_eq!(big.as_ref(), &frames[1][..]);
+    }
+
+    #[test]
+    fn small_frames_are_encoded_again_without_a_vec() {
+        let frame = raw_frame(SMALL_FRAME_MAX_LEN - Header::SIZE);
+        let mut decoder = StandardDecoder::<u32>::new();
+        let (decoded, _) = decode_one(&mut decoder, &frame);
+        let mut encoder = Encoder::<u32>::new();
+        assert_eq!(encoder.encode(decoded).unwrap(), &frame[..]);
+    }
+
+    #[test]
+    fn reject_frames_longer_than_the_max_frame_len() {
+        let mut decoder = StandardDecoder::<u32>::new();
+        decoder.set_max_frame_len(16);
+        assert_eq!(
+            decode_plain(&mut decoder, &raw_frame(16), &SPLITS).unwrap(),
+            vec![raw_frame(16)]
+        );
+        // The frame is rejected once the header is read, before the body is buffered
+        let header = &raw_frame(17)[..Header::SIZE];
+        assert!(matches!(
+            decode_plain(&mut decoder, header, &[1]),
+            Err(Error::FrameTooLarge { len: 17, max: 16 })
+        ));
+    }
+
+    #[cfg(feature = "noise_sv2")]
+    /// Initiator and Responder states in transport mode
+    fn transport_pair() -> (State, State) {
+        let (public_key, private_key) = noise_sv2::random_keypair();
+        let initiator = Initiator::from_raw_k(public_key).unwrap();
+        let responder =
+            Responder::from_authority_kp(&public_key, &private_key, Duration::from_secs(60))
+                .unwrap();
+        let mut initiator = State::initialize(HandshakeRole::Initiator(initiator));
+        let mut responder = State::initialize(HandshakeRole::Responder(responder));
+        let first_message = initiator.step(None).unwrap().payload().to_vec();
+        let second_message = responder
+            .step(Some(first_message))
+            .unwrap()
+            .payload()
+            .to_vec();
+        initiator.step(Some(second_message)).unwrap();
+        (
+            initiator.into_transport_mode().unwrap(),
+            responder.into_transport_mode().unwrap(),
+        )
+    }
+
+    #[cfg(feature = "noise_sv2")]
+    fn encode(encoder: &mut NoiseEncoder<u32>, state: &mut State, frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for raw in frames {
+            let frame = Sv2Frame::<u32, Vec<u8>>::from_bytes(raw.clone()).unwrap();
+            bytes.extend_from_slice(encoder.encode(frame.into(), state).unwrap());
+        }
+        bytes
+    }
+
+    #[cfg(feature = "noise_sv2")]
+    /// Like `decode_plain` for the noise decoder
+    fn decode_noise(
+        decoder: &mut StandardNoiseDecoder<u32>,
+        state: &mut State,
+        bytes: &[u8],
+        splits: &[usize],
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        for split in splits.iter().cycle() {
+            if offset == bytes.len() {
+                break;
+            }
+            let end = (offset + split).min(bytes.len());
+            while offset < end {
+                offset += decoder.push_bytes(&bytes[offset..end]);
+                match decoder.next_frame(state) {
+                    Ok(frame) => frames.push(serialized(frame.try_into().unwrap())),
+                    Err(Error::MissingBytes(_)) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(frames)
+    }
+
+    #[cfg(feature = "noise_sv2")]
+    #[test]
+    fn decode_consecutive_small_and_big_noise_frames() {
+        let (mut initiator, mut responder) = transport_pair();
+        let mut encoder = NoiseEncoder::<u32>::new();
+        let mut decoder = StandardNoiseDecoder::<u32>::new();
+        let frames = frames();
+        let bytes = encode(&mut encoder, &mut initiator, &frames);
+        let decoded = decode_noise(&mut decoder, &mut responder, &bytes, &[bytes.len()]);
+        assert_eq!(decoded.unwrap(), frames);
+    }
+
+    #[cfg(feature = "noise_sv2")]
+    #[test]
+    fn decode_noise_frames_pushed_one_byte_at_a_time_and_in_arbitrary_splits() {
+        let (mut initiator, mut responder) = transport_pair();
+        let mut encoder = NoiseEncoder::<u32>::new();
+        let mut decoder = StandardNoiseDecoder::<u32>::new();
+        let frames = frames();
+        for splits in [&[1][..], &SPLITS[..]].iter() {
+            let bytes = encode(&mut encoder, &mut initiator, &frames);
+            let decoded = decode_noise(&mut decoder, &mut responder, &bytes, splits);
+            assert_eq!(decoded.unwrap(), frames);
+        }
+    }
+
+    #[cfg(feature = "noise_sv2")]
+    #[test]
+    fn reject_noise_frames_longer_than_the_max_frame_len() {
+        let (mut initiator, mut responder) = transport_pair();
+        let mut encoder = NoiseEncoder::<u32>::new();
+        let mut decoder = StandardNoiseDecoder::<u32>::new();
+        decoder.set_max_frame_len(16);
+        let bytes = encode(
+            &mut encoder,
+            &mut initiator,
+            &[raw_frame(16), raw_frame(17)],
+        );
+        assert!(matches!(
+            decode_noise(&mut decoder, &mut responder, &bytes, &SPLITS),
+            Err(Error::FrameTooLarge { len: 17, max: 16 })
+        ));
+    }
+
+    #[cfg(feature = "noise_sv2")]
+    #[test]
+    fn padding_frames_are_stripped_by_the_decoder() {
+        let (mut initiator, mut responder) = transport_pair();
+        let mut encoder = NoiseEncoder::<u32>::new();
+        encoder.set_padding(PaddingPolicy::Bucket(64));
+        let mut decoder = StandardNoiseDecoder::<u32>::new();
+
+        // Every frame is padded to a multiple of the bucket before the encryption
+        for len in [0, 10, 58, 59, 200].iter() {
+            let frame = raw_frame(*len);
+            let bytes = encode(&mut encoder, &mut initiator, core::slice::from_ref(&frame));
+            let encrypted_len = bytes.len() - NoiseHeader::SIZE - const_sv2::SNOW_TAGLEN;
+            assert_eq!(encrypted_len % 64, 0);
+            assert!(encrypted_len >= frame.len());
+            let decoded = decode_noise(&mut decoder, &mut responder, &bytes, &SPLITS);
+            assert_eq!(decoded.unwrap(), vec![frame]);
+        }
+    }
+
+    #[cfg(feature = "noise_sv2")]
+    #[test]
+    fn reject_a_noise_frame_with_a_trailer_that_is_not_padding() {
+        let (mut initiator, mut responder) = transport_pair();
+        let mut encoder = NoiseEncoder::<u32>::new();
+        let mut decoder = StandardNoiseDecoder::<u32>::new();
+        // Two sv2 frames in the same noise frame, the second one is not a padding frame
+        let raw = [raw_frame(10), raw_frame(0)].concat();
+        let frame = Sv2Frame::<u32, Vec<u8>>::from_bytes_unchecked(raw);
+        let bytes = encoder
+            .encode(frame.into(), &mut initiator)
+            .unwrap()
+            .to_vec();
+        assert!(matches!(
+            decode_noise(&mut decoder, &mut responder, &bytes, &SPLITS),
+            Err(Error::InvalidFragment)
+        ));
+    }
+}
@@ -12,7 +12,10 @@ use framing_sv2::framing2::{Frame as F_, Sv2Frame};
 use framing_sv2::header::NoiseHeader;
 
 #[cfg(feature = "noise_sv2")]
-use crate::{State, TransportMode};
+use crate::{
+    padding::{pad, PaddingPolicy},
+    State, TransportMode,
+};
 
 #[cfg(feature = "noise_sv2")]
 const TAGLEN: usize = const_sv2::SNOW_TAGLEN;
@@ -21,32 +24,62 @@ const MAX_M_L: usize = const_sv2::NOISE_FRAME_MAX_SIZE;
 #[cfg(feature = "noise_sv2")]
 const M: usize = MAX_M_L - TAGLEN;
 
+/// Serialize `frame` in `buffer`, `B` is the bytes of the frame eg `Vec<u8>` or `FrameBytes` for the
+/// decoded frames. `buffer` must be already resized to the encoded length of the frame. The frames
+/// already serialized are copied so that `buffer` keep its memory
+#[inline]
+fn serialize_in<T: Serialize + GetSize, B>(
+    frame: Sv2Frame<T, B>,
+    buffer: &mut Vec<u8>,
+) -> Result<(), ()>
+where
+    B: AsMut<[u8]> + AsRef<[u8]> + From<Vec<u8>> + Into<Vec<u8>>,
+{
+    if frame.message().is_some() {
+        let mut dst = B::from(core::mem::take(buffer));
+        let result = frame.serialize(&mut dst).map_err(|_| ());
+        *buffer = dst.into();
+        result
+    } else {
+        let mut serialized = B::from(Vec::new());
+        frame.serialize(&mut serialized).map_err(|_| ())?;
+        buffer.clear();
+        buffer.extend_from_slice(serialized.as_ref());
+        Ok(())
+    }
+}
+
 #[cfg(feature = "noise_sv2")]
 pub struct NoiseEncoder<T: Serialize + binary_sv2::GetSize> {
     noise_buffer: Vec<u8>,
     sv2_buffer: Vec<u8>,
+    padding: PaddingPolicy,
     frame: PhantomData<T>,
 }
 
 #[cfg(feature = "noise_sv2")]
 impl<T: Serialize + GetSize> NoiseEncoder<T> {
     #[inline]
-    pub fn encode(
+    pub fn encode<B>(
         &mut self,
-        item: EitherFrame<T, Vec<u8>>,
+        item: EitherFrame<T, B>,
         state: &mut State,
-    ) -> Result<&[u8], crate::Error> {
+    ) -> Result<&[u8], crate::Error>
+    where
+        B: AsMut<[u8]> + AsRef<[u8]> + From<Vec<u8>> + Into<Vec<u8>>,
+    {
         match state {
             State::Transport(transport_mode) => {
                 let len = item.encoded_length();
                 self.sv2_buffer.resize(len, 0);
 
                 // ENCODE THE SV2 FRAME
-                let i: Sv2Frame<T, Vec<u8>> = item.try_into().map_err(|_| ())?;
-                i.serialize(&mut self.sv2_buffer).map_err(|_| ())?;
+                let i: Sv2Frame<T, B> = item.try_into().map_err(|_| ())?;
+                serialize_in(i, &mut self.sv2_buffer)?;
 
                 // IF THE MESSAGE FIT INTO A NOISE FRAME ENCODE IT HOT PATH
                 if len <= M {
+                    pad(&mut self.sv2_buffer, self.padding.padded_len(len, M));
                     self.encode_single_frame(transport_mode)?;
 
                 // IF LEN IS BIGGER THAN NOISE PAYLOAD MAX SIZE MESSAGE IS ENCODED AS SEVERAL NOISE
@@ -133,7 +166,7 @@ impl<T: Serialize + GetSize> NoiseEncoder<T> {
     }
 
     #[inline(never)]
-    fn while_handshaking(&mut self, item: EitherFrame<T, Vec<u8>>) -> Result<(), ()> {
+    fn while_handshaking<B>(&mut self, item: EitherFrame<T, B>) -> Result<(), ()> {
         // ENCODE THE SV2 FRAME
         let i: HandShakeFrame = item.try_into().map_err(|_| ())?;
         i.serialize(&mut self.sv2_buffer).map_err(|_| ())?;
@@ -151,9 +184,15 @@ impl<T: Serialize + binary_sv2::GetSize> NoiseEncoder<T> {
         Self {
             sv2_buffer: Vec::with_capacity(512),
             noise_buffer: Vec::with_capacity(512),
+            padding: PaddingPolicy::None,
             frame: core::marker::PhantomData,
         }
     }
+
+    /// Pad the frames encoded in transport mode, see `PaddingPolicy`
+    pub fn set_padding(&mut self, padding: PaddingPolicy) {
+        self.padding = padding;
+    }
 }
 
 #[cfg(feature = "noise_sv2")]
@@ -170,12 +209,15 @@ pub struct Encoder<T> {
 }
 
 impl<T: Serialize + GetSize> Encoder<T> {
-    pub fn encode(&mut self, item: Sv2Frame<T, Vec<u8>>) -> Result<&[u8], crate::Error> {
+    pub fn encode<B>(&mut self, item: Sv2Frame<T, B>) -> Result<&[u8], crate::Error>
+    where
+        B: AsMut<[u8]> + AsRef<[u8]> + From<Vec<u8>> + Into<Vec<u8>>,
+    {
         let len = item.encoded_length();
 
         self.buffer.resize(len, 0);
 
-        item.serialize(&mut self.buffer).map_err(|_| ())?;
+        serialize_in(item, &mut self.buffer)?;
 
         Ok(&self.buffer[..])
     }
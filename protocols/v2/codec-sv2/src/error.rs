@@ -1,6 +1,16 @@
 #[derive(Debug)]
 pub enum Error {
     MissingBytes(usize),
+    /// The length in the header of a frame is greater than the max frame len of the decoder
+    FrameTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// A noise frame carry more bytes than the sv2 frame that it complete and its padding frame
+    InvalidFragment,
+    /// A noise frame can not be decrypted (the AEAD tag does not match), the session can not be
+    /// used anymore
+    TagMismatch,
     Todo,
 }
 
@@ -9,9 +9,13 @@ mod buffer;
 mod decoder;
 mod encoder;
 mod error;
+#[cfg(feature = "noise_sv2")]
+mod padding;
 
 pub use error::Error;
 
+pub use buffer::{FrameBytes, SMALL_FRAME_MAX_LEN};
+
 pub use decoder::{StandardEitherFrame, StandardSv2Frame};
 
 pub use decoder::StandardDecoder;
@@ -21,6 +25,8 @@ pub use decoder::StandardNoiseDecoder;
 pub use encoder::Encoder;
 #[cfg(feature = "noise_sv2")]
 pub use encoder::NoiseEncoder;
+#[cfg(feature = "noise_sv2")]
+pub use padding::PaddingPolicy;
 
 pub use framing_sv2::framing2::{Frame, Sv2Frame};
 #[cfg(feature = "noise_sv2")]
@@ -66,21 +72,8 @@ impl HandshakeRole {
 
     pub fn into_transport(self) -> Result<TransportMode, crate::Error> {
         match self {
-            Self::Initiator(stepper) => {
-                let tp = stepper
-                    .into_handshake_state()
-                    .into_transport_mode()
-                    .map_err(|_| ())?;
-                Ok(TransportMode::new(tp))
-            }
-
-            Self::Responder(stepper) => {
-                let tp = stepper
-                    .into_handshake_state()
-                    .into_transport_mode()
-                    .map_err(|_| ())?;
-                Ok(TransportMode::new(tp))
-            }
+            Self::Initiator(stepper) => Ok(stepper.into_transport_mode().map_err(|_| ())?),
+            Self::Responder(stepper) => Ok(stepper.into_transport_mode().map_err(|_| ())?),
         }
     }
 }
@@ -104,6 +97,14 @@ impl State {
             Self::Transport(_) => false,
         }
     }
+
+    /// The transport mode, None until the handshake is completed
+    pub fn transport_mode(&self) -> Option<&TransportMode> {
+        match self {
+            Self::Transport(transport_mode) => Some(transport_mode),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(feature = "noise_sv2")]
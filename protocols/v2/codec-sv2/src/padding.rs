@@ -0,0 +1,122 @@
+//! Padding of the encrypted frames.
+//!
+//! The length of a noise frame is visible on the wire, so a passive observer can tell a
+//! SubmitShares from a NewMiningJob and measure the share cadence of a farm. With a
+//! `PaddingPolicy::Bucket` the `NoiseEncoder` append a padding frame (an sv2 frame with
+//! `EXTENSION_TYPE_PADDING` and a zeroed payload) after the sv2 frame, inside the same encrypted
+//! payload, so that every noise frame is a multiple of the bucket. The `StandardNoiseDecoder`
+//! always discard the padding frames, but a decoder older than the padding reject them with
+//! `Error::InvalidFragment`: the padding must be enabled only toward peers that support it.
+use alloc::vec::Vec;
+use const_sv2::{EXTENSION_TYPE_PADDING, MESSAGE_TYPE_PADDING};
+use framing_sv2::header::Header;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    /// Frames are not padded
+    None,
+    /// The decrypted payload of a noise frame is padded up to a multiple of this len. The sv2
+    /// frames longer than a noise frame are not padded
+    Bucket(usize),
+}
+
+impl PaddingPolicy {
+    /// Length of the sv2 frame of `len` bytes plus its padding frame, `len` if the frame is not
+    /// padded. The padding frame can not be shorter than an sv2 header, if the bucket has less room
+    /// left the frame is padded up to the next bucket
+    pub fn padded_len(&self, len: usize, max_len: usize) -> usize {
+        match *self {
+            Self::Bucket(0) | Self::None => len,
+            Self::Bucket(bucket) => match len % bucket {
+                0 => len,
+                _ => {
+                    let min_len = len + Header::SIZE;
+                    let padded = min_len + (bucket - min_len % bucket) % bucket;
+                    if padded <= max_len {
+                        padded
+                    } else {
+                        len
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Append to `buffer` a padding frame that make it `padded_len` long, `padded_len` must be either
+/// the len of `buffer` or at least an sv2 header longer
+pub(crate) fn pad(buffer: &mut Vec<u8>, padded_len: usize) {
+    let len = buffer.len();
+    if padded_len == len {
+        return;
+    }
+    let payload_len = (padded_len - len - Header::SIZE) as u32;
+    buffer.extend_from_slice(&EXTENSION_TYPE_PADDING.to_le_bytes());
+    buffer.push(MESSAGE_TYPE_PADDING);
+    buffer.extend_from_slice(&payload_len.to_le_bytes()[..3]);
+    buffer.resize(padded_len, 0);
+}
+
+/// True if `bytes` is exactly one padding frame
+pub(crate) fn is_padding(bytes: &[u8]) -> bool {
+    match Header::from_bytes(bytes) {
+        Ok(header) => {
+            header.extension_type() == EXTENSION_TYPE_PADDING
+                && header.len() == bytes.len() - Header::SIZE
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn padded_len_edge_cases() {
+        let policy = PaddingPolicy::Bucket(16);
+        // Already a multiple of the bucket
+        assert_eq!(policy.padded_len(32, 1024), 32);
+        // Exactly an sv2 header left in the bucket
+        assert_eq!(policy.padded_len(10, 1024), 16);
+        // Less than an sv2 header left in the bucket, padded up to the next bucket
+        assert_eq!(policy.padded_len(12, 1024), 32);
+        assert_eq!(policy.padded_len(15, 1024), 32);
+        // The padded frame must fit in max_len
+        assert_eq!(policy.padded_len(10, 16), 16);
+        assert_eq!(policy.padded_len(12, 31), 12);
+        assert_eq!(PaddingPolicy::Bucket(0).padded_len(12, 1024), 12);
+        assert_eq!(PaddingPolicy::None.padded_len(12, 1024), 12);
+    }
+
+    #[test]
+    fn pad_append_a_padding_frame() {
+        for &(len, padded_len) in [(10, 16), (12, 32), (0, Header::SIZE)].iter() {
+            let mut buffer = vec![1; len];
+            pad(&mut buffer, padded_len);
+            assert_eq!(buffer.len(), padded_len);
+            assert!(is_padding(&buffer[len..]));
+        }
+        let mut buffer = vec![1; 16];
+        pad(&mut buffer, 16);
+        assert_eq!(buffer, vec![1; 16]);
+    }
+
+    #[test]
+    fn is_padding_only_accept_a_whole_padding_frame() {
+        let mut padding = Vec::new();
+        pad(&mut padding, Header::SIZE + 4);
+        assert!(is_padding(&padding));
+        // The padding frame must end the buffer
+        assert!(!is_padding(&padding[..Header::SIZE + 3]));
+        assert!(!is_padding(&[&padding[..], &[0]].concat()));
+        // Shorter than an sv2 header
+        assert!(!is_padding(&padding[..Header::SIZE - 1]));
+        assert!(!is_padding(&[]));
+        // An sv2 frame of another extension
+        let mut not_padding = padding.clone();
+        not_padding[0] = 0;
+        assert!(!is_padding(&not_padding));
+    }
+}
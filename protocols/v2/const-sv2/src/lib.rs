@@ -2,10 +2,20 @@
 #![no_std]
 
 pub const EXTENSION_TYPE_NO_EXTENSION: u16 = 0;
+/// Extensions negotiation, the bit 0 of the extension type is the channel_msg bit so extension
+/// types are even
+pub const EXTENSION_TYPE_EXTENSIONS_NEGOTIATION: u16 = 0x0002;
+/// Device telemetry, see `roles_logic_sv2::telemetry`
+pub const EXTENSION_TYPE_TELEMETRY: u16 = 0x0004;
+/// Padding of the encrypted frames, a padding frame is discarded by the noise decoder and never
+/// reach the application
+pub const EXTENSION_TYPE_PADDING: u16 = 0x0006;
 
 pub const SV2_FRAME_HEADER_SIZE: usize = 6;
 pub const SV2_FRAME_HEADER_LEN_OFFSET: usize = 3;
 pub const SV2_FRAME_HEADER_LEN_END: usize = 3;
+/// Max length of the payload of an sv2 frame, the length in the header is an u24
+pub const SV2_FRAME_MAX_LEN: usize = 16_777_215;
 
 pub const NOISE_FRAME_HEADER_SIZE: usize = 2;
 pub const NOISE_FRAME_HEADER_LEN_OFFSET: usize = 0;
@@ -13,9 +23,17 @@ pub const NOISE_FRAME_HEADER_LEN_END: usize = 2;
 pub const NOISE_FRAME_MAX_SIZE: usize = u16::MAX as usize;
 
 pub const NOISE_PARAMS: &str = "Noise_NX_25519_ChaChaPoly_BLAKE2s";
+/// Handshake pattern of the Responders that accept only some Initiators, the Initiator send its
+/// static key in the first message
+pub const NOISE_PARAMS_INITIATOR_AUTH: &str = "Noise_IX_25519_ChaChaPoly_BLAKE2s";
 pub const SNOW_PSKLEN: usize = 32;
 pub const SNOW_TAGLEN: usize = 16;
 
+/// Min and max Sv2 protocol version supported by this implementation, used in SetupConnection
+/// version negotiation
+pub const SV2_MIN_PROTOCOL_VERSION: u16 = 2;
+pub const SV2_MAX_PROTOCOL_VERSION: u16 = 2;
+
 pub const SV2_MINING_PROTOCOL_DISCRIMINANT: u8 = 0;
 pub const SV2_JOB_NEG_PROTOCOL_DISCRIMINANT: u8 = 1;
 pub const SV2_TEMPLATE_DISTR_PROTOCOL_DISCRIMINANT: u8 = 2;
@@ -47,6 +65,18 @@ pub const MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTION_SUCCESS: u8 = 0x56;
 pub const MESSAGE_TYPE_COMMIT_MINING_JOB: u8 = 0x57;
 pub const MESSAGE_TYPE_COMMIT_MINING_JOB_SUCCESS: u8 = 0x58;
 pub const MESSAGE_TYPE_COMMIT_MINING_JOB_ERROR: u8 = 0x59;
+
+// EXTENSIONS NEGOTIATION MESSAGES TYPES
+pub const MESSAGE_TYPE_REQUEST_EXTENSIONS: u8 = 0x00;
+pub const MESSAGE_TYPE_REQUEST_EXTENSIONS_SUCCESS: u8 = 0x01;
+pub const MESSAGE_TYPE_REQUEST_EXTENSIONS_ERROR: u8 = 0x02;
+
+// TELEMETRY MESSAGES TYPES
+pub const MESSAGE_TYPE_SUBMIT_TELEMETRY: u8 = 0x00;
+
+// PADDING MESSAGES TYPES
+pub const MESSAGE_TYPE_PADDING: u8 = 0x00;
+
 // MINING PROTOCOL MESSAGES TYPES
 pub const MESSAGE_TYPE_CLOSE_CHANNEL: u8 = 0x18;
 pub const MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB: u8 = 0x1f;
@@ -126,3 +156,5 @@ pub const CHANNEL_BIT_SUBMIT_SHARES_STANDARD: bool = true;
 pub const CHANNEL_BIT_SUBMIT_SHARES_SUCCESS: bool = true;
 pub const CHANNEL_BIT_UPDATE_CHANNEL: bool = true;
 pub const CHANNEL_BIT_UPDATE_CHANNEL_ERROR: bool = true;
+// TELEMETRY MESSAGES CHANNEL BIT
+pub const CHANNEL_BIT_SUBMIT_TELEMETRY: bool = true;
@@ -16,6 +16,25 @@ impl<A, B> Sv2Frame<A, B> {
             serialized,
         }
     }
+
+    /// Like `map` but `fun` can fail. `fun` is called only if the frame has not been serialized
+    pub fn try_map<C, E>(self, fun: fn(A) -> Result<C, E>) -> Result<Sv2Frame<C, B>, E> {
+        let payload = match self.payload {
+            Some(payload) => Some(fun(payload)?),
+            None => None,
+        };
+        Ok(Sv2Frame {
+            header: self.header,
+            payload,
+            serialized: self.serialized,
+        })
+    }
+
+    /// The message of a frame built with `from_message`, None if the frame has been built from
+    /// bytes (use `payload` for it)
+    pub fn message(&self) -> Option<&A> {
+        self.payload.as_ref()
+    }
 }
 
 pub trait Frame<'a, T: Serialize + GetSize>: Sized {
@@ -313,6 +332,14 @@ impl<T: Serialize + GetSize, B: AsMut<[u8]> + AsRef<[u8]>> EitherFrame<T, B> {
             Self::Sv2(frame) => frame.encoded_length(),
         }
     }
+
+    /// Header of the Sv2 frames, None for the handshake frames
+    pub fn get_header(&self) -> Option<Header> {
+        match &self {
+            Self::HandShake(_) => None,
+            Self::Sv2(frame) => frame.get_header(),
+        }
+    }
 }
 
 impl<T, B> TryFrom<EitherFrame<T, B>> for HandShakeFrame {
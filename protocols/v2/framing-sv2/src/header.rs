@@ -68,9 +68,20 @@ impl Header {
         self.msg_type
     }
 
+    pub fn extension_type(&self) -> u16 {
+        self.extension_type
+    }
+
+    /// True if the channel_msg bit is set, the bit is set by `Sv2Frame::from_message`
     pub fn channel_msg(&self) -> bool {
         let mask = 0b0000_0000_0000_0001;
-        self.extension_type & mask == self.extension_type
+        self.extension_type & mask == mask
+    }
+
+    /// Extension type with the channel_msg bit cleared
+    pub fn ext_type_without_channel_msg(&self) -> u16 {
+        let mask = 0b1111_1111_1111_1110;
+        self.extension_type & mask
     }
 }
 
@@ -0,0 +1,87 @@
+//! Handshake and transport benchmarks, run with `cargo bench -p noise_sv2`
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use noise_sv2::{handshake::Step, random_keypair, Initiator, Responder, TransportMode};
+use std::time::Duration;
+
+/// Payload sizes of typical frames: SubmitSharesStandard, NewExtendedMiningJob with a short merkle
+/// path, a big coinbase, the max noise message
+const FRAME_SIZES: [usize; 4] = [32, 256, 2048, 65519];
+
+fn handshake(
+    authority_public_key: [u8; 32],
+    responder: Responder,
+) -> (TransportMode, TransportMode) {
+    let mut initiator = Initiator::from_raw_k(authority_public_key).unwrap();
+    let mut responder = responder;
+    let first_message = initiator.step(None).unwrap().inner();
+    let second_message = responder.step(Some(first_message)).unwrap().inner();
+    initiator.step(Some(second_message)).unwrap();
+    (
+        initiator.into_transport_mode().unwrap(),
+        responder.into_transport_mode().unwrap(),
+    )
+}
+
+fn new_responder(authority_keypair: &([u8; 32], [u8; 32])) -> Responder {
+    Responder::from_authority_kp(
+        &authority_keypair.0,
+        &authority_keypair.1,
+        Duration::from_secs(3600),
+    )
+    .unwrap()
+}
+
+fn bench_handshake(c: &mut Criterion) {
+    let authority_keypair = random_keypair();
+    // The static key and the certificate of the Responder are created once, as a Responder do
+    c.bench_function("handshake", |b| {
+        b.iter_batched(
+            || new_responder(&authority_keypair),
+            |responder| handshake(authority_keypair.0, responder),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn bench_transport(c: &mut Criterion) {
+    let authority_keypair = random_keypair();
+    let (mut initiator, _) = handshake(authority_keypair.0, new_responder(&authority_keypair));
+
+    let mut group = c.benchmark_group("transport_write");
+    for size in FRAME_SIZES.iter() {
+        let plain = vec![0xab; *size];
+        let mut encrypted = vec![0; TransportMode::size_hint_encrypt(*size)];
+        group.throughput(Throughput::Bytes(*size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &plain, |b, plain| {
+            b.iter(|| initiator.write(plain, &mut encrypted).unwrap())
+        });
+    }
+    group.finish();
+
+    // A fresh session so that the nonces of the two sides match
+    let (mut initiator, mut responder) =
+        handshake(authority_keypair.0, new_responder(&authority_keypair));
+    let mut group = c.benchmark_group("transport_read");
+    for size in FRAME_SIZES.iter() {
+        let plain = vec![0xab; *size];
+        let mut decrypted = vec![0; *size];
+        group.throughput(Throughput::Bytes(*size as u64));
+        // The messages are encrypted in the setup and decrypted in the same order, as the nonces
+        // require
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter_batched(
+                || {
+                    let mut encrypted = vec![0; TransportMode::size_hint_encrypt(plain.len())];
+                    initiator.write(&plain, &mut encrypted).unwrap();
+                    encrypted
+                },
+                |encrypted| responder.read(&encrypted, &mut decrypted).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_handshake, bench_transport);
+criterion_main!(benches);
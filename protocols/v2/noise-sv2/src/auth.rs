@@ -4,7 +4,7 @@ use core::{
     time::Duration,
 };
 use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
+use std::{sync::Arc, time::SystemTime};
 
 use crate::{Error, Result, StaticPublicKey};
 
@@ -25,7 +25,15 @@ pub struct SignedPartHeader {
 }
 
 impl SignedPartHeader {
-    const VERSION: u16 = 0;
+    /// Certificate signed with ed25519
+    pub const VERSION: u16 = 0;
+    /// Certificate signed with BIP340 Schnorr on secp256k1
+    pub const VERSION_SCHNORR: u16 = 1;
+    /// Certificate signed with an intermediate key, followed by the `IntermediateCertificate` of
+    /// the key
+    pub const VERSION_CHAINED: u16 = 2;
+    /// Certificate of an intermediate key, signed by the root authority
+    pub const VERSION_INTERMEDIATE: u16 = 3;
 
     pub fn serialize_to_writer<T: Write>(&self, writer: &mut T) -> Result<()> {
         let version = self.version.to_le_bytes();
@@ -59,6 +67,15 @@ impl SignedPartHeader {
         })
     }
 
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub(crate) fn with_version(mut self, version: u16) -> Self {
+        self.version = version;
+        self
+    }
+
     pub fn valid_from(&self) -> SystemTime {
         Self::unix_time_u32_to_system_time(self.valid_from)
             .expect("BUG: cannot provide 'valid_from' time")
@@ -70,8 +87,15 @@ impl SignedPartHeader {
     }
 
     pub fn verify_expiration(&self, now: SystemTime) -> Result<()> {
-        let now_timestamp = Self::system_time_to_unix_time_u32(&now)?;
-        if now_timestamp < self.valid_from {
+        self.verify_expiration_with_skew(now, Duration::from_secs(0))
+    }
+
+    /// Like `verify_expiration` but `now` can be off by `max_skew` in both directions, the
+    /// validity period is extended by `max_skew` on both ends
+    pub fn verify_expiration_with_skew(&self, now: SystemTime, max_skew: Duration) -> Result<()> {
+        let now_timestamp = Self::system_time_to_unix_time_u32(&now)? as u64;
+        let max_skew = max_skew.as_secs();
+        if now_timestamp + max_skew < self.valid_from as u64 {
             //return Err(ErrorKind::Noise(format!(
             //    "Certificate not yet valid, valid from: {:?}, now: {:?}",
             //    self.valid_from, now
@@ -79,7 +103,7 @@ impl SignedPartHeader {
             //.into());
             return Err(Error {});
         }
-        if now_timestamp > self.not_valid_after {
+        if now_timestamp > self.not_valid_after as u64 + max_skew {
             //return Err(ErrorKind::Noise(format!(
             //    "Certificate expired, not valid after: {:?}, now: {:?}",
             //    self.valid_from, now
@@ -119,6 +143,97 @@ impl SignedPartHeader {
     }
 }
 
+/// Bytes signed by the authority: the header, the static key of the Responder and the key of the
+/// authority (both 32 bytes)
+fn serialize_signed_part(
+    header: &SignedPartHeader,
+    pub_k: &[u8],
+    auth_pub_k: &[u8],
+) -> Result<BytesMut> {
+    let mut signed_part_writer = BytesMut::new().writer();
+    let version = &header.version.to_le_bytes()[..];
+    let valid_from = &header.valid_from.to_le_bytes()[..];
+    let not_valid_after = &header.not_valid_after.to_le_bytes()[..];
+    let pub_k_len = [32, 0];
+    signed_part_writer
+        .write_all(
+            &[
+                version,
+                valid_from,
+                not_valid_after,
+                &pub_k_len,
+                pub_k,
+                &pub_k_len,
+                auth_pub_k,
+            ]
+            .concat()[..],
+        )
+        .map_err(|_| Error {})?;
+    Ok(signed_part_writer.into_inner())
+}
+
+/// Source of the current time for the validation of the certificates
+pub trait TimeProvider: core::fmt::Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The time of the system clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemTimeProvider;
+
+impl TimeProvider for SystemTimeProvider {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Always the same instant, for the tests
+#[derive(Debug, Clone, Copy)]
+pub struct FixedTime(pub SystemTime);
+
+impl TimeProvider for FixedTime {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+/// Time against which the validity period of the certificates is checked: the time of a
+/// `TimeProvider` that can be off by `max_skew`. The default is the system clock without
+/// tolerance. A tolerance of a few minutes avoids spurious handshake failures on the devices with
+/// a drifting clock, the certificates are valid that much longer.
+#[derive(Debug, Clone)]
+pub struct CertificateClock {
+    time_provider: Arc<dyn TimeProvider>,
+    max_skew: Duration,
+}
+
+impl CertificateClock {
+    pub fn new(time_provider: Arc<dyn TimeProvider>, max_skew: Duration) -> Self {
+        Self {
+            time_provider,
+            max_skew,
+        }
+    }
+
+    pub fn now(&self) -> SystemTime {
+        self.time_provider.now()
+    }
+
+    pub fn max_skew(&self) -> Duration {
+        self.max_skew
+    }
+
+    pub fn verify_expiration(&self, header: &SignedPartHeader) -> Result<()> {
+        header.verify_expiration_with_skew(self.now(), self.max_skew)
+    }
+}
+
+impl Default for CertificateClock {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemTimeProvider), Duration::from_secs(0))
+    }
+}
+
 /// Helper struct for performing the actual signature of the relevant parts of the certificate
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct SignedPart {
@@ -141,28 +256,11 @@ impl SignedPart {
     }
 
     fn serialize_to_buf(&self) -> Result<BytesMut> {
-        let mut signed_part_writer = BytesMut::new().writer();
-        let version = &self.header.version.to_le_bytes()[..];
-        let valid_from = &self.header.valid_from.to_le_bytes()[..];
-        let not_valid_after = &self.header.not_valid_after.to_le_bytes()[..];
-        let pub_k_len = [32, 0];
-        let pub_k = &self.pubkey[..];
-        let auth_pub_k = &self.authority_public_key.as_bytes()[..];
-        signed_part_writer
-            .write_all(
-                &[
-                    version,
-                    valid_from,
-                    not_valid_after,
-                    &pub_k_len,
-                    pub_k,
-                    &pub_k_len,
-                    auth_pub_k,
-                ]
-                .concat()[..],
-            )
-            .map_err(|_| Error {})?;
-        Ok(signed_part_writer.into_inner())
+        serialize_signed_part(
+            &self.header,
+            &self.pubkey[..],
+            &self.authority_public_key.as_bytes()[..],
+        )
     }
 
     /// Generates the actual ed25519_dalek::Signature that is ready to be embedded into the certificate
@@ -189,8 +287,8 @@ impl SignedPart {
         Ok(())
     }
 
-    pub(crate) fn verify_expiration(&self, now: SystemTime) -> Result<()> {
-        self.header.verify_expiration(now)
+    pub(crate) fn verify_expiration(&self, clock: &CertificateClock) -> Result<()> {
+        clock.verify_expiration(&self.header)
     }
 }
 
@@ -200,6 +298,8 @@ impl SignedPart {
 pub struct SignatureNoiseMessage {
     pub(crate) header: SignedPartHeader,
     pub(crate) signature: ed25519_dalek::Signature,
+    /// Certificate of the key that signed, when it is not the root authority
+    pub(crate) intermediate: Option<IntermediateCertificate>,
 }
 
 impl SignatureNoiseMessage {
@@ -212,6 +312,9 @@ impl SignatureNoiseMessage {
         writer
             .write_all(&self.signature.to_bytes()[..])
             .map_err(|_| Error {})?;
+        if let Some(intermediate) = &self.intermediate {
+            intermediate.serialize_to_writer(writer)?;
+        }
         Ok(())
     }
 
@@ -226,22 +329,39 @@ impl SignatureNoiseMessage {
         Ok(serialized_signature_noise_message)
     }
 
+    /// When the certificate expires, the intermediate certificate can expire earlier
+    pub fn not_valid_after(&self) -> SystemTime {
+        let not_valid_after = self.header.not_valid_after();
+        match &self.intermediate {
+            Some(intermediate) => {
+                let intermediate = intermediate.signed_part().0.header.not_valid_after();
+                not_valid_after.min(intermediate)
+            }
+            None => not_valid_after,
+        }
+    }
+
     pub fn with_duration(pub_k: &[u8], priv_k: &[u8], duration: core::time::Duration) -> Self {
         let to_be_signed_keypair =
             crate::generate_keypair().expect("BUG: cannot generate noise static keypair");
-        let authority_keypair = ed25519_dalek::Keypair::from_bytes(&[priv_k, pub_k].concat())
+        let authority_keys = zeroize::Zeroizing::new([priv_k, pub_k].concat());
+        let authority_keypair = ed25519_dalek::Keypair::from_bytes(&authority_keys)
             .expect("BUG: cannot generate noise authority keypair");
         let header = SignedPartHeader::with_duration(duration)
             .expect("BUG: cannot prepare certificate header");
         let signed_part = SignedPart::new(
             header.clone(),
-            to_be_signed_keypair.public,
+            to_be_signed_keypair.public.clone(),
             authority_keypair.public,
         );
         let signature = signed_part
             .sign_with(&authority_keypair)
             .expect("BUG: cannot sign");
-        Self { header, signature }
+        Self {
+            header,
+            signature,
+            intermediate: None,
+        }
     }
 }
 
@@ -250,12 +370,230 @@ impl TryFrom<&[u8]> for SignatureNoiseMessage {
     type Error = Error;
 
     fn try_from(data: &[u8]) -> Result<Self> {
-        debug_assert!(data.len() == 76);
+        if data.len() < crate::SIGNATURE_MESSAGE_LEN {
+            return Err(Error {});
+        }
+        let (data, intermediate) = data.split_at(crate::SIGNATURE_MESSAGE_LEN);
         let header = &data[0..10];
         let siganture = &data[12..76];
         let header = SignedPartHeader::from_bytes(header);
         let signature = ed25519_dalek::Signature::new(siganture.try_into().map_err(|_| Error {})?);
-        Ok(SignatureNoiseMessage { header, signature })
+        let intermediate = match header.version {
+            SignedPartHeader::VERSION_CHAINED => {
+                Some(IntermediateCertificate::from_bytes(intermediate)?)
+            }
+            _ if intermediate.is_empty() => None,
+            _ => return Err(Error {}),
+        };
+        Ok(SignatureNoiseMessage {
+            header,
+            signature,
+            intermediate,
+        })
+    }
+}
+
+/// Certificates signed with BIP340 Schnorr, so that the authority key can be a secp256k1 key.
+///
+/// The signature noise message has the layout of the ed25519 one, the version of the header is
+/// `SignedPartHeader::VERSION_SCHNORR`. The authority signs the SHA256 of the same signed part,
+/// with the x-only public key of the authority in place of the ed25519 key. An Initiator only
+/// accepts the version of the key of its authority, so the scheme is chosen by the authority key
+/// configured on both sides.
+#[cfg(feature = "schnorr")]
+pub mod schnorr {
+    use super::*;
+    use secp256k1::{hashes::sha256, schnorr::Signature, Message, Secp256k1};
+    pub use secp256k1::{KeyPair, XOnlyPublicKey};
+
+    fn message(
+        header: &SignedPartHeader,
+        pub_k: &[u8],
+        authority_public_key: &XOnlyPublicKey,
+    ) -> Result<Message> {
+        let signed_part = serialize_signed_part(header, pub_k, &authority_public_key.serialize())?;
+        Ok(Message::from_hashed_data::<sha256::Hash>(&signed_part[..]))
+    }
+
+    /// Authority keypair of the 32 bytes secret key `priv_k`
+    pub fn keypair_from_raw(priv_k: &[u8]) -> Result<KeyPair> {
+        KeyPair::from_seckey_slice(&Secp256k1::signing_only(), priv_k).map_err(|_| Error {})
+    }
+
+    /// Serialized signature noise message that certify `pub_k` until now + duration
+    pub fn new_noise_message(
+        authority: &KeyPair,
+        pub_k: &[u8],
+        duration: Duration,
+    ) -> Result<BytesMut> {
+        let mut header = SignedPartHeader::with_duration(duration)?;
+        header.version = SignedPartHeader::VERSION_SCHNORR;
+        let message = message(&header, pub_k, &authority.public_key())?;
+        let mut aux_rand = [0_u8; 32];
+        rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut aux_rand);
+        let signature =
+            Secp256k1::signing_only().sign_schnorr_with_aux_rand(&message, authority, &aux_rand);
+
+        let mut writer = BytesMut::new().writer();
+        header.serialize_to_writer(&mut writer)?;
+        writer.write_all(&[74, 0]).map_err(|_| Error {})?;
+        writer.write_all(&signature[..]).map_err(|_| Error {})?;
+        Ok(writer.into_inner())
+    }
+
+    /// Verify the serialized signature noise message `data` received with `pub_k`, return the
+    /// expiration of the certificate
+    pub fn verify_noise_message(
+        data: &[u8],
+        pub_k: &[u8],
+        authority_public_key: &XOnlyPublicKey,
+        clock: &CertificateClock,
+    ) -> Result<SystemTime> {
+        if data.len() != crate::SIGNATURE_MESSAGE_LEN {
+            return Err(Error {});
+        }
+        let header = SignedPartHeader::from_bytes(&data[0..10]);
+        if header.version != SignedPartHeader::VERSION_SCHNORR {
+            return Err(Error {});
+        }
+        let signature = Signature::from_slice(&data[12..76]).map_err(|_| Error {})?;
+        let message = message(&header, pub_k, authority_public_key)?;
+        Secp256k1::verification_only()
+            .verify_schnorr(&signature, &message, authority_public_key)
+            .map_err(|_| Error {})?;
+        clock.verify_expiration(&header)?;
+        Ok(header.not_valid_after())
+    }
+}
+
+#[cfg(feature = "batch_verify")]
+pub use batch::BatchVerifier;
+
+/// Certificate verifications batched with `ed25519_dalek::verify_batch`.
+///
+/// Verifying a batch of n signatures costs about half of n single verifications, which matters
+/// when many handshakes are done at the same time (e.g. a reconnect storm). The certificates are
+/// queued and a dedicated thread verifies them in batches of at most `max_batch` certificates; a
+/// batch is closed after `max_wait` even when it is not full. Every certificate gets its own
+/// result: when a batch fails, its certificates are verified one by one so that only the bad ones
+/// are rejected.
+///
+/// The batch equation is less strict than `verify_strict`: it accepts some malleated signatures of
+/// a valid signature, but never a signature forged without the authority secret key.
+#[cfg(feature = "batch_verify")]
+mod batch {
+    use super::*;
+    use futures::channel::oneshot;
+    use std::{
+        sync::{
+            mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+            Arc, Mutex,
+        },
+        time::Instant,
+    };
+
+    struct Pending {
+        message: BytesMut,
+        signature: ed25519_dalek::Signature,
+        authority_public_key: ed25519_dalek::PublicKey,
+        result: oneshot::Sender<Result<()>>,
+    }
+
+    /// Handle to the verification queue, it can be cloned and shared by all the handshakes. The
+    /// verification thread stops when every handle has been dropped
+    #[derive(Debug, Clone)]
+    pub struct BatchVerifier {
+        queue: Arc<Mutex<Sender<Pending>>>,
+    }
+
+    impl BatchVerifier {
+        /// Start the verification thread
+        pub fn spawn(max_batch: usize, max_wait: Duration) -> Self {
+            let (queue, pending) = channel();
+            std::thread::Builder::new()
+                .name("certificate-verifier".into())
+                .spawn(move || run(pending, max_batch.max(1), max_wait))
+                .expect("BUG: cannot spawn the certificate verifier");
+            Self {
+                queue: Arc::new(Mutex::new(queue)),
+            }
+        }
+
+        /// Verify the signature and the expiration of `certificate`, like
+        /// `Certificate::validate_with_clock`. The expiration is checked immediately, the
+        /// signature with the next batch
+        pub async fn verify(
+            &self,
+            certificate: &Certificate,
+            clock: &CertificateClock,
+        ) -> Result<()> {
+            // The signature of the intermediate certificate, if any, is verified as well
+            for result in self.submit(certificate, clock)? {
+                result.await.map_err(|_| Error {})??;
+            }
+            Ok(())
+        }
+
+        fn submit(
+            &self,
+            certificate: &Certificate,
+            clock: &CertificateClock,
+        ) -> Result<Vec<oneshot::Receiver<Result<()>>>> {
+            let queue = self.queue.lock().map_err(|_| Error {})?;
+            certificate
+                .signed_parts()?
+                .into_iter()
+                .map(|(signed_part, signature)| {
+                    signed_part.verify_expiration(clock)?;
+                    let (sender, result) = oneshot::channel();
+                    let pending = Pending {
+                        message: signed_part.serialize_to_buf()?,
+                        signature,
+                        authority_public_key: signed_part.authority_public_key,
+                        result: sender,
+                    };
+                    queue.send(pending).map_err(|_| Error {})?;
+                    Ok(result)
+                })
+                .collect()
+        }
+    }
+
+    fn run(pending: Receiver<Pending>, max_batch: usize, max_wait: Duration) {
+        while let Ok(first) = pending.recv() {
+            let deadline = Instant::now() + max_wait;
+            let mut batch = vec![first];
+            while batch.len() < max_batch {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match pending.recv_timeout(timeout) {
+                    Ok(next) => batch.push(next),
+                    Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            verify_batch(batch);
+        }
+    }
+
+    fn verify_batch(batch: Vec<Pending>) {
+        // A single signature is cheaper to verify alone
+        let batch_is_valid = batch.len() > 1 && {
+            let messages: Vec<&[u8]> = batch.iter().map(|p| &p.message[..]).collect();
+            let signatures: Vec<_> = batch.iter().map(|p| p.signature).collect();
+            let public_keys: Vec<_> = batch.iter().map(|p| p.authority_public_key).collect();
+            ed25519_dalek::verify_batch(&messages, &signatures, &public_keys).is_ok()
+        };
+        for pending in batch {
+            let result = if batch_is_valid {
+                Ok(())
+            } else {
+                pending
+                    .authority_public_key
+                    .verify_strict(&pending.message[..], &pending.signature)
+                    .map_err(|_| Error {})
+            };
+            // The handshake may have been dropped in the meantime
+            let _ = pending.result.send(result);
+        }
     }
 }
 
@@ -334,6 +672,26 @@ pub(crate) mod test {
         );
     }
 
+    #[test]
+    fn header_time_validity_with_skew() {
+        let header = SignedPartHeader::with_duration(TEST_CERT_VALIDITY)
+            .expect("BUG: cannot build certificate header");
+        let skew = Duration::from_secs(60);
+        let early = header.valid_from() - Duration::from_secs(30);
+        let late = header.not_valid_after() + Duration::from_secs(30);
+        assert!(header.verify_expiration(early).is_err());
+        assert!(header.verify_expiration(late).is_err());
+        header.verify_expiration_with_skew(early, skew).unwrap();
+        header.verify_expiration_with_skew(late, skew).unwrap();
+        assert!(header
+            .verify_expiration_with_skew(early - skew, skew)
+            .is_err());
+
+        let clock = CertificateClock::new(Arc::new(FixedTime(late)), skew);
+        assert_eq!(clock.now(), late);
+        clock.verify_expiration(&header).unwrap();
+    }
+
     #[test]
     fn signature_noise_message_serialization() {
         let (signed_part, authority_keypair, _static_keypair, _signature) =
@@ -344,6 +702,7 @@ pub(crate) mod test {
             signature: signed_part
                 .sign_with(&authority_keypair)
                 .expect("BUG: cannot sign"),
+            intermediate: None,
         };
 
         let mut serialized_noise_message_writer = BytesMut::new().writer();
@@ -361,4 +720,72 @@ pub(crate) mod test {
             "Signature noise messages don't match each other after serialization cycle"
         )
     }
+
+    #[cfg(feature = "batch_verify")]
+    #[async_std::test]
+    async fn batch_verifier_rejects_only_bad_certificates() {
+        let verifier = BatchVerifier::spawn(8, Duration::from_millis(50));
+        let clock = CertificateClock::default();
+        let mut certificates: Vec<Certificate> = (0..4)
+            .map(|_| {
+                let (signed_part, _authority_keypair, _static_keypair, signature) =
+                    build_test_signed_part_and_auth();
+                Certificate::new(signed_part, signature)
+            })
+            .collect();
+        // Signed by another authority
+        let (signed_part, ..) = build_test_signed_part_and_auth();
+        let (_, _, _, signature) = build_test_signed_part_and_auth();
+        certificates.push(Certificate::new(signed_part, signature));
+
+        let results =
+            futures::future::join_all(certificates.iter().map(|c| verifier.verify(c, &clock)))
+                .await;
+        assert!(results[..4].iter().all(|result| result.is_ok()));
+        assert!(results[4].is_err());
+
+        // A batch of one certificate
+        assert!(verifier.verify(&certificates[0], &clock).await.is_ok());
+    }
+
+    #[cfg(feature = "schnorr")]
+    #[test]
+    fn schnorr_noise_message() {
+        let authority = schnorr::keypair_from_raw(&[7; 32]).unwrap();
+        let other_authority = schnorr::keypair_from_raw(&[8; 32]).unwrap();
+        let static_keypair = generate_keypair().unwrap();
+        let clock = CertificateClock::default();
+        let data =
+            schnorr::new_noise_message(&authority, &static_keypair.public, TEST_CERT_VALIDITY)
+                .unwrap();
+        assert_eq!(data.len(), crate::SIGNATURE_MESSAGE_LEN);
+        assert_eq!(
+            SignedPartHeader::from_bytes(&data[..10]).version(),
+            SignedPartHeader::VERSION_SCHNORR
+        );
+
+        let not_valid_after = schnorr::verify_noise_message(
+            &data,
+            &static_keypair.public,
+            &authority.public_key(),
+            &clock,
+        )
+        .unwrap();
+        assert!(not_valid_after > SystemTime::now());
+        // Another authority, another static key
+        assert!(schnorr::verify_noise_message(
+            &data,
+            &static_keypair.public,
+            &other_authority.public_key(),
+            &clock
+        )
+        .is_err());
+        assert!(schnorr::verify_noise_message(
+            &data,
+            &generate_keypair().unwrap().public,
+            &authority.public_key(),
+            &clock
+        )
+        .is_err());
+    }
 }
@@ -0,0 +1,242 @@
+//! Noise backend.
+//!
+//! `Initiator`, `Responder` and `TransportMode` do not use a noise implementation directly but the
+//! `HandshakeBackend` and `CipherState` traits. The backend is selected at compile time with a
+//! feature, `HandshakeState` and `TransportState` are aliases for the types of the selected
+//! backend so that the callers of this crate do not change when the backend change.
+//!
+//! Available backends:
+//! * `snow` (default): the [snow](https://github.com/mcginty/snow) crate
+//!
+//! A new backend implement the two traits for its handshake and transport states, and add a
+//! feature that select them below. Backends own the ephemeral and static keys used during the
+//! handshake and the transport keys, they are responsible for wiping them when dropped.
+//! (snow 0.8 does not wipe its internal state)
+use crate::error::Result;
+use alloc::vec::Vec;
+use zeroize::Zeroize;
+
+/// Static keypair (aka 's' and 'rs') from the noise handshake patterns. This has to be used by
+/// users of this noise when Building the responder. The private key is wiped from memory when the
+/// keypair is dropped.
+#[derive(Clone, PartialEq, Eq)]
+pub struct StaticKeypair {
+    pub private: Vec<u8>,
+    pub public: Vec<u8>,
+}
+
+impl Drop for StaticKeypair {
+    fn drop(&mut self) {
+        self.private.zeroize();
+    }
+}
+
+/// Do not leak the private key in logs
+impl core::fmt::Debug for StaticKeypair {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("StaticKeypair")
+            .field("public", &self.public)
+            .finish()
+    }
+}
+
+/// State of a Noise_NX handshake (as specified by `const_sv2::NOISE_PARAMS`)
+pub trait HandshakeBackend: Sized + core::fmt::Debug {
+    type Transport: CipherState;
+
+    /// Generate a static keypair for the noise params
+    fn generate_keypair() -> Result<StaticKeypair>;
+
+    /// Build the keypair of `private_key`
+    fn keypair_from_private(private_key: &[u8]) -> Result<StaticKeypair>;
+
+    fn build_initiator() -> Result<Self>;
+
+    fn build_responder(local_private_key: &[u8]) -> Result<Self>;
+
+    /// Build an initiator that send its static key `local_private_key` to the responder
+    /// (`const_sv2::NOISE_PARAMS_INITIATOR_AUTH`)
+    #[cfg(feature = "initiator_auth")]
+    fn build_authenticated_initiator(local_private_key: &[u8]) -> Result<Self>;
+
+    /// Build a responder that receive the static key of the initiator
+    /// (`const_sv2::NOISE_PARAMS_INITIATOR_AUTH`)
+    #[cfg(feature = "initiator_auth")]
+    fn build_authenticating_responder(local_private_key: &[u8]) -> Result<Self>;
+
+    /// Build an initiator that use `ephemeral_private_key` instead of a random ephemeral key.
+    /// Only for reproducible tests
+    #[cfg(any(test, feature = "deterministic"))]
+    fn build_initiator_with_ephemeral(ephemeral_private_key: &[u8]) -> Result<Self>;
+
+    /// Build a responder that use `ephemeral_private_key` instead of a random ephemeral key.
+    /// Only for reproducible tests
+    #[cfg(any(test, feature = "deterministic"))]
+    fn build_responder_with_ephemeral(
+        local_private_key: &[u8],
+        ephemeral_private_key: &[u8],
+    ) -> Result<Self>;
+
+    /// Write the next handshake message with `payload` in `message`, return the written len
+    fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize>;
+
+    /// Read the next handshake message in `payload`, return the len of the payload
+    fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize>;
+
+    /// Remote static public key, available once it has been received
+    fn get_remote_static(&self) -> Option<&[u8]>;
+
+    /// Hash of the handshake transcript, the same for both the peers once the handshake is
+    /// complete
+    fn get_handshake_hash(&self) -> &[u8];
+
+    /// Called when the handshake is complete
+    fn into_transport_mode(self) -> Result<Self::Transport>;
+}
+
+/// Transport state, encrypt and decrypt messages after the handshake
+pub trait CipherState: core::fmt::Debug {
+    /// Encrypt `payload` in `message`, return the written len
+    fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize>;
+
+    /// Decrypt `message` in `payload`, return the len of the payload
+    fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize>;
+}
+
+#[cfg(not(feature = "snow"))]
+compile_error!("noise_sv2 needs a backend, enable the `snow` feature");
+
+#[cfg(feature = "snow")]
+pub type HandshakeState = snow::HandshakeState;
+#[cfg(feature = "snow")]
+pub type TransportState = snow::TransportState;
+
+#[cfg(feature = "snow")]
+mod snow_backend {
+    use super::*;
+    use crate::error::Error;
+    use snow::{
+        params::NoiseParams,
+        resolvers::{CryptoResolver, DefaultResolver},
+        Builder,
+    };
+
+    fn params() -> NoiseParams {
+        crate::PARAMS
+            .parse()
+            .expect("BUG: cannot parse noise parameters")
+    }
+
+    fn builder() -> Builder<'static> {
+        Builder::new(params())
+    }
+
+    #[cfg(feature = "initiator_auth")]
+    fn initiator_auth_builder() -> Builder<'static> {
+        let params = crate::PARAMS_INITIATOR_AUTH
+            .parse()
+            .expect("BUG: cannot parse noise parameters");
+        Builder::new(params)
+    }
+
+    impl HandshakeBackend for snow::HandshakeState {
+        type Transport = snow::TransportState;
+
+        fn generate_keypair() -> Result<StaticKeypair> {
+            let keypair = builder().generate_keypair().map_err(|_| Error {})?;
+            Ok(StaticKeypair {
+                private: keypair.private,
+                public: keypair.public,
+            })
+        }
+
+        fn keypair_from_private(private_key: &[u8]) -> Result<StaticKeypair> {
+            let mut dh = DefaultResolver.resolve_dh(&params().dh).ok_or(Error {})?;
+            if private_key.len() != dh.priv_len() {
+                return Err(Error {});
+            }
+            dh.set(private_key);
+            Ok(StaticKeypair {
+                private: private_key.to_vec(),
+                public: dh.pubkey().to_vec(),
+            })
+        }
+
+        fn build_initiator() -> Result<Self> {
+            builder().build_initiator().map_err(|_| Error {})
+        }
+
+        fn build_responder(local_private_key: &[u8]) -> Result<Self> {
+            builder()
+                .local_private_key(local_private_key)
+                .build_responder()
+                .map_err(|_| Error {})
+        }
+
+        #[cfg(feature = "initiator_auth")]
+        fn build_authenticated_initiator(local_private_key: &[u8]) -> Result<Self> {
+            initiator_auth_builder()
+                .local_private_key(local_private_key)
+                .build_initiator()
+                .map_err(|_| Error {})
+        }
+
+        #[cfg(feature = "initiator_auth")]
+        fn build_authenticating_responder(local_private_key: &[u8]) -> Result<Self> {
+            initiator_auth_builder()
+                .local_private_key(local_private_key)
+                .build_responder()
+                .map_err(|_| Error {})
+        }
+
+        #[cfg(any(test, feature = "deterministic"))]
+        fn build_initiator_with_ephemeral(ephemeral_private_key: &[u8]) -> Result<Self> {
+            builder()
+                .fixed_ephemeral_key_for_testing_only(ephemeral_private_key)
+                .build_initiator()
+                .map_err(|_| Error {})
+        }
+
+        #[cfg(any(test, feature = "deterministic"))]
+        fn build_responder_with_ephemeral(
+            local_private_key: &[u8],
+            ephemeral_private_key: &[u8],
+        ) -> Result<Self> {
+            builder()
+                .local_private_key(local_private_key)
+                .fixed_ephemeral_key_for_testing_only(ephemeral_private_key)
+                .build_responder()
+                .map_err(|_| Error {})
+        }
+
+        fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize> {
+            snow::HandshakeState::write_message(self, payload, message).map_err(|_| Error {})
+        }
+
+        fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize> {
+            snow::HandshakeState::read_message(self, message, payload).map_err(|_| Error {})
+        }
+
+        fn get_remote_static(&self) -> Option<&[u8]> {
+            snow::HandshakeState::get_remote_static(self)
+        }
+
+        fn get_handshake_hash(&self) -> &[u8] {
+            snow::HandshakeState::get_handshake_hash(self)
+        }
+
+        fn into_transport_mode(self) -> Result<Self::Transport> {
+            snow::HandshakeState::into_transport_mode(self).map_err(|_| Error {})
+        }
+    }
+
+    impl CipherState for snow::TransportState {
+        fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize> {
+            snow::TransportState::write_message(self, payload, message).map_err(|_| Error {})
+        }
+
+        fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize> {
+            snow::TransportState::read_message(self, message, payload).map_err(|_| Error {})
+        }
+    }
+}
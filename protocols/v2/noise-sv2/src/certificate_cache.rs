@@ -0,0 +1,115 @@
+//! Certificates of the Responders remembered by the Initiators.
+//!
+//! Verifying the certificate of the Responder (one ed25519 signature, two for a certificate
+//! signed by an intermediate key) is the most expensive part of the handshake for the Initiator.
+//! An Initiator built with `Initiator::with_certificate_cache` does not verify again a certificate
+//! already verified in a previous session: when the Responder has the same static key and send the
+//! same signature noise message the handshake only check the validity period of the cached
+//! certificate. A certificate that is expired, a new static key of the Responder or a new
+//! certificate fall back to the full verification.
+//!
+//! The static key of the Responder is still authenticated by the noise handshake, the cache only
+//! skip the signatures of the authority over a key and a certificate that have not changed.
+use crate::auth::{CertificateClock, SignatureNoiseMessage, SignedPartHeader};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::convert::TryFrom;
+use std::{sync::Mutex, time::SystemTime};
+
+/// Authority public key and static key of the Responder
+type CacheKey = (Vec<u8>, Vec<u8>);
+
+#[derive(Debug, Clone)]
+struct CachedCertificate {
+    signature_noise_message: Vec<u8>,
+    /// Header of the certificate followed by the header of the intermediate certificate if any
+    headers: Vec<SignedPartHeader>,
+}
+
+impl CachedCertificate {
+    fn is_valid(&self, clock: &CertificateClock) -> bool {
+        self.headers
+            .iter()
+            .all(|header| clock.verify_expiration(header).is_ok())
+    }
+}
+
+/// Certificates verified by the Initiators, by authority and static key of the Responder. Cloning
+/// it is cheap, the same cache can be used by the Initiators of every connection
+#[derive(Debug, Clone, Default)]
+pub struct CertificateCache {
+    certificates: Arc<Mutex<BTreeMap<CacheKey, CachedCertificate>>>,
+}
+
+impl CertificateCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expiration of the certificate if `signature_noise_message` has already been verified for
+    /// the Responder with `static_key` and the authority `authority_public_key`, and it is still
+    /// valid for `clock`. A cached certificate that is no more valid is forgotten
+    pub(crate) fn lookup(
+        &self,
+        authority_public_key: &[u8],
+        static_key: &[u8],
+        signature_noise_message: &[u8],
+        clock: &CertificateClock,
+    ) -> Option<SystemTime> {
+        let key = (authority_public_key.to_vec(), static_key.to_vec());
+        // Is fine to unwrap, the lock is never held while panicking
+        let mut certificates = self.certificates.lock().unwrap();
+        let cached = certificates.get(&key)?;
+        if cached.signature_noise_message != signature_noise_message {
+            return None;
+        }
+        if !cached.is_valid(clock) {
+            certificates.remove(&key);
+            return None;
+        }
+        cached
+            .headers
+            .first()
+            .map(|header| header.not_valid_after())
+    }
+
+    /// Remember a certificate verified in full, it replace the certificate previously cached for
+    /// the same Responder. The certificates that are no more valid for `clock` are dropped
+    pub(crate) fn insert(
+        &self,
+        authority_public_key: &[u8],
+        static_key: &[u8],
+        signature_noise_message: &[u8],
+        clock: &CertificateClock,
+    ) {
+        let message = match SignatureNoiseMessage::try_from(signature_noise_message) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        let mut headers = vec![message.header];
+        if let Some(intermediate) = message.intermediate {
+            headers.push(intermediate.signed_part().0.header);
+        }
+        let certificate = CachedCertificate {
+            signature_noise_message: signature_noise_message.to_vec(),
+            headers,
+        };
+        let key = (authority_public_key.to_vec(), static_key.to_vec());
+        let mut certificates = self.certificates.lock().unwrap();
+        certificates.retain(|_, cached| cached.is_valid(clock));
+        certificates.insert(key, certificate);
+    }
+
+    /// Number of cached certificates
+    pub fn len(&self) -> usize {
+        self.certificates.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget every certificate, the next handshakes verify the certificates in full
+    pub fn clear(&self) {
+        self.certificates.lock().unwrap().clear();
+    }
+}
@@ -1,10 +1,9 @@
 use alloc::string::String;
 use core::{convert::TryFrom, fmt};
 use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
 
 use crate::{
-    auth::{SignatureNoiseMessage, SignedPart, SignedPartHeader},
+    auth::{CertificateClock, SignatureNoiseMessage, SignedPart, SignedPartHeader},
     error::{Error, Result},
     StaticPublicKey, StaticSecretKey,
 };
@@ -119,13 +118,15 @@ macro_rules! generate_noise_keypair_structs {
                     .with_check(None)
                     .into_vec()
                     .map_err(|_| Error {})?;
-                Ok(Self::new(bytes))
+                Ok(Self::new(bytes.into()))
             }
         }
 
         impl From<$encoded_struct_type> for String {
             fn from(value: $encoded_struct_type) -> Self {
-                bs58::encode(&value.into_inner()).with_check().into_string()
+                bs58::encode(&value.into_inner()[..])
+                    .with_check()
+                    .into_string()
             }
         }
     };
@@ -199,8 +200,12 @@ generate_noise_keypair_structs!(
 pub struct Certificate {
     signed_part_header: SignedPartHeader,
     pub public_key: StaticPublicKeyFormat,
+    /// Root authority, the key that signed the certificate when there is no intermediate
     authority_public_key: Ed25519PublicKeyFormat,
     signature: Ed25519SignatureFormat,
+    /// Certificate of the intermediate key that signed the certificate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    intermediate: Option<IntermediateCertificate>,
 }
 
 impl Certificate {
@@ -210,35 +215,180 @@ impl Certificate {
             public_key: StaticPublicKeyFormat::new(signed_part.pubkey),
             authority_public_key: Ed25519PublicKeyFormat::new(signed_part.authority_public_key),
             signature: Ed25519SignatureFormat::new(signature),
+            intermediate: None,
         }
     }
 
+    /// Certificate signed by the intermediate key of `intermediate`, `signed_part` is signed by
+    /// the intermediate key
+    pub fn new_chained(
+        signed_part: SignedPart,
+        signature: ed25519_dalek::Signature,
+        intermediate: IntermediateCertificate,
+    ) -> Self {
+        Self {
+            authority_public_key: intermediate.authority_public_key.clone(),
+            intermediate: Some(intermediate),
+            ..Self::new(signed_part, signature)
+        }
+    }
+
+    /// Check the signatures and the expiration of the certificate and of its intermediate
+    /// certificate if any
     pub fn validate(&self) -> Result<()> {
+        self.validate_with_clock(&CertificateClock::default())
+    }
+
+    /// Like `validate` but the expiration is checked with `clock`
+    pub fn validate_with_clock(&self, clock: &CertificateClock) -> Result<()> {
+        for (signed_part, signature) in self.signed_parts()? {
+            signed_part.verify(&signature)?;
+            signed_part.verify_expiration(clock)?;
+        }
+        Ok(())
+    }
+
+    /// Parts of the certificate signed by an authority with their signature: the certificate and
+    /// the intermediate certificate if any. Fail if the chain is malformed
+    pub(crate) fn signed_parts(&self) -> Result<Vec<(SignedPart, ed25519_dalek::Signature)>> {
+        let root = self.authority_public_key.clone().into_inner();
+        let version = self.signed_part_header.version();
+        let (signer, intermediate) = match &self.intermediate {
+            Some(intermediate) if version == SignedPartHeader::VERSION_CHAINED => {
+                let signed_part = intermediate.signed_part();
+                // The root of the chain is the authority trusted by the Initiator
+                if signed_part.0.authority_public_key != root
+                    || signed_part.0.header.version() != SignedPartHeader::VERSION_INTERMEDIATE
+                {
+                    return Err(Error {});
+                }
+                (
+                    intermediate.public_key.clone().into_inner(),
+                    Some(signed_part),
+                )
+            }
+            None if version != SignedPartHeader::VERSION_CHAINED => (root, None),
+            _ => return Err(Error {}),
+        };
         let signed_part = SignedPart::new(
             self.signed_part_header.clone(),
             self.public_key.clone().into_inner(),
-            self.authority_public_key.clone().into_inner(),
+            signer,
         );
-        signed_part.verify(&self.signature.clone().into_inner())?;
-        signed_part.verify_expiration(SystemTime::now())
+        let mut signed_parts = vec![(signed_part, self.signature.clone().into_inner())];
+        signed_parts.extend(intermediate);
+        Ok(signed_parts)
     }
 
+    /// `authority_public_key` is the root authority, the certificate can be signed by an
+    /// intermediate key certified by the root authority
     pub fn from_noise_message(
         signature_noise_message: SignatureNoiseMessage,
         pubkey: StaticPublicKey,
         authority_public_key: ed25519_dalek::PublicKey,
     ) -> Self {
-        Self::new(
+        let mut certificate = Self::new(
             SignedPart::new(signature_noise_message.header, pubkey, authority_public_key),
             signature_noise_message.signature,
-        )
+        );
+        // The root authority is not part of the noise message
+        certificate.intermediate = signature_noise_message
+            .intermediate
+            .map(|mut intermediate| {
+                intermediate.authority_public_key =
+                    Ed25519PublicKeyFormat::new(authority_public_key);
+                intermediate
+            });
+        certificate
     }
 
     pub fn build_noise_message(&self) -> SignatureNoiseMessage {
         SignatureNoiseMessage {
             header: self.signed_part_header.clone(),
             signature: self.signature.clone().into_inner(),
+            intermediate: self.intermediate.clone(),
+        }
+    }
+}
+
+/// Certificate of an intermediate signing key, signed by the root authority. The intermediate key
+/// signs the certificates of the Responders, so that the root key can be kept offline. It is
+/// serialized to a file like `Certificate`, and appended to the signature noise message of the
+/// certificates signed by the intermediate key.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct IntermediateCertificate {
+    signed_part_header: SignedPartHeader,
+    pub public_key: Ed25519PublicKeyFormat,
+    authority_public_key: Ed25519PublicKeyFormat,
+    signature: Ed25519SignatureFormat,
+}
+
+impl IntermediateCertificate {
+    /// header (10 bytes) + intermediate public key (32 bytes) + signature (64 bytes)
+    pub const SERIALIZED_LEN: usize = 106;
+
+    pub(crate) fn new(
+        signed_part_header: SignedPartHeader,
+        public_key: ed25519_dalek::PublicKey,
+        authority_public_key: ed25519_dalek::PublicKey,
+        signature: ed25519_dalek::Signature,
+    ) -> Self {
+        Self {
+            signed_part_header,
+            public_key: Ed25519PublicKeyFormat::new(public_key),
+            authority_public_key: Ed25519PublicKeyFormat::new(authority_public_key),
+            signature: Ed25519SignatureFormat::new(signature),
+        }
+    }
+
+    /// Check the signature of the root authority and the expiration
+    pub fn validate(&self) -> Result<()> {
+        self.validate_with_clock(&CertificateClock::default())
+    }
+
+    /// Like `validate` but the expiration is checked with `clock`
+    pub fn validate_with_clock(&self, clock: &CertificateClock) -> Result<()> {
+        let (signed_part, signature) = self.signed_part();
+        signed_part.verify(&signature)?;
+        signed_part.verify_expiration(clock)
+    }
+
+    pub(crate) fn signed_part(&self) -> (SignedPart, ed25519_dalek::Signature) {
+        let signed_part = SignedPart::new(
+            self.signed_part_header.clone(),
+            self.public_key.clone().into_inner().as_bytes().to_vec(),
+            self.authority_public_key.clone().into_inner(),
+        );
+        (signed_part, self.signature.clone().into_inner())
+    }
+
+    pub(crate) fn serialize_to_writer<T: std::io::Write>(&self, writer: &mut T) -> Result<()> {
+        self.signed_part_header.serialize_to_writer(writer)?;
+        writer
+            .write_all(self.public_key.clone().into_inner().as_bytes())
+            .map_err(|_| Error {})?;
+        writer
+            .write_all(&self.signature.clone().into_inner().to_bytes()[..])
+            .map_err(|_| Error {})
+    }
+
+    /// Deserialize the certificate appended to a signature noise message. The root authority is
+    /// not serialized, it is set by `Certificate::from_noise_message`
+    pub(crate) fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() != Self::SERIALIZED_LEN {
+            return Err(Error {});
         }
+        let header = SignedPartHeader::from_bytes(&data[0..10]);
+        let public_key =
+            ed25519_dalek::PublicKey::from_bytes(&data[10..42]).map_err(|_| Error {})?;
+        let signature =
+            ed25519_dalek::Signature::from_bytes(&data[42..106]).map_err(|_| Error {})?;
+        Ok(Self {
+            signed_part_header: header,
+            public_key: Ed25519PublicKeyFormat::new(public_key),
+            authority_public_key: Ed25519PublicKeyFormat::new(public_key),
+            signature: Ed25519SignatureFormat::new(signature),
+        })
     }
 }
 
@@ -261,6 +411,9 @@ impl TryFrom<Certificate> for String {
 pub mod test {
     use super::*;
     use crate::auth::test::build_test_signed_part_and_auth;
+    use core::time::Duration;
+
+    const TEST_CERT_VALIDITY: Duration = Duration::from_secs(3600);
 
     #[test]
     fn certificate_validate() {
@@ -284,4 +437,74 @@ pub mod test {
 
         assert_eq!(certificate, deserialized_cert, "Certificates don't match!");
     }
+
+    #[test]
+    fn certificate_chain_validate() {
+        let mut csprng = rand::rngs::OsRng {};
+        let root_keypair = ed25519_dalek::Keypair::generate(&mut csprng);
+        let root_public_key = root_keypair.public;
+        let root = crate::Authority::new(root_keypair);
+        let intermediate_keypair = ed25519_dalek::Keypair::generate(&mut csprng);
+        let intermediate_certificate = root
+            .new_intermediate_cert(intermediate_keypair.public, TEST_CERT_VALIDITY)
+            .expect("BUG: cannot issue intermediate certificate");
+        intermediate_certificate
+            .validate()
+            .expect("BUG: Intermediate certificate not valid!");
+        let intermediate =
+            crate::Authority::intermediate(intermediate_keypair, intermediate_certificate)
+                .expect("BUG: cannot build intermediate authority");
+        assert!(intermediate
+            .new_intermediate_cert(
+                ed25519_dalek::Keypair::generate(&mut csprng).public,
+                TEST_CERT_VALIDITY
+            )
+            .is_err());
+
+        let static_keypair = crate::generate_keypair().expect("BUG: cannot generate keypair");
+        let noise_message = intermediate
+            .new_cert(static_keypair.public.clone(), TEST_CERT_VALIDITY)
+            .expect("BUG: cannot issue certificate");
+        let bytes = noise_message
+            .serialize_to_bytes_mut()
+            .expect("BUG: cannot serialize signature noise message");
+        assert_eq!(bytes.len(), crate::CHAINED_SIGNATURE_MESSAGE_LEN);
+        let noise_message = SignatureNoiseMessage::try_from(&bytes[..])
+            .expect("BUG: cannot deserialize signature noise message");
+
+        let certificate = Certificate::from_noise_message(
+            noise_message.clone(),
+            static_keypair.public.clone(),
+            root_public_key,
+        );
+        certificate
+            .validate()
+            .expect("BUG: Certificate chain not valid!");
+        let serialized_cert =
+            serde_json::to_string(&certificate).expect("BUG: cannot serialize certificate");
+        let deserialized_cert: Certificate = serde_json::from_str(serialized_cert.as_str())
+            .expect("BUG: cannot deserialized certificate");
+        assert_eq!(certificate, deserialized_cert, "Certificates don't match!");
+
+        // Chained to another root
+        let other_root = ed25519_dalek::Keypair::generate(&mut csprng).public;
+        let certificate = Certificate::from_noise_message(
+            noise_message,
+            static_keypair.public.clone(),
+            other_root,
+        );
+        assert!(certificate.validate().is_err());
+    }
+
+    #[test]
+    fn static_secret_key_encoding() {
+        let keypair = crate::generate_keypair().expect("BUG: cannot generate keypair");
+        let secret_key = EncodedStaticSecretKey::new(keypair.private.clone().into());
+
+        let encoded: String = secret_key.clone().into();
+        let decoded = EncodedStaticSecretKey::try_from(encoded).expect("BUG: cannot decode key");
+
+        assert!(decoded == secret_key, "Secret keys don't match!");
+        assert_eq!(&decoded.into_inner()[..], &keypair.private[..]);
+    }
 }
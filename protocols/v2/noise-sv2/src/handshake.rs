@@ -1,21 +1,25 @@
 //use bytes::BytesMut;
+use crate::{backend::HandshakeState, error::Result, TransportMode};
 use alloc::vec::Vec;
-use snow::HandshakeState;
-
-use crate::error::Result;
+use std::time::SystemTime;
 
 /// Handshake message
 pub type Message = Vec<u8>;
 
+/// Size of the largest handshake message, an output buffer of this size is always big enough for
+/// `Step::step_with_buffer`
+pub const HANDSHAKE_MESSAGE_MAX_LEN: usize = crate::BUFFER_LEN;
+
 /// Describes the step result what the relevant party should do after sending out the
-/// provided message (if any)
+/// provided message (if any). `M` is the message for `Step::step` and the number of bytes written
+/// in the output buffer for `Step::step_with_buffer`
 #[derive(Debug, PartialEq)]
-pub enum StepResult {
+pub enum StepResult<M = Message> {
     /// variant and expect a reply
-    ExpectReply(Message),
+    ExpectReply(M),
     /// This message is yet to be sent to the counter party and we are allowed to switch to
     /// transport mode
-    NoMoreReply(Message),
+    NoMoreReply(M),
     /// The handshake is complete, no more messages are expected and nothing is to be sent. The
     /// protocol can be switched to transport mode now.
     Done,
@@ -31,15 +35,345 @@ impl StepResult {
     }
 }
 
+impl StepResult<usize> {
+    /// Number of bytes written in the output buffer
+    pub fn len(&self) -> usize {
+        match self {
+            Self::ExpectReply(len) => *len,
+            Self::NoMoreReply(len) => *len,
+            Self::Done => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Replace the written size with the message
+    fn with_message(self, out: &[u8]) -> StepResult {
+        match self {
+            Self::ExpectReply(len) => StepResult::ExpectReply(out[..len].to_vec()),
+            Self::NoMoreReply(len) => StepResult::NoMoreReply(out[..len].to_vec()),
+            Self::Done => StepResult::Done,
+        }
+    }
+}
+
+/// Event of a handshake, recorded in a `Transcript`
+#[derive(Debug, Clone, PartialEq)]
+pub enum TranscriptEvent {
+    /// A handshake message of `len` bytes has been sent
+    Sent {
+        len: usize,
+    },
+    /// A handshake message of `len` bytes has been received
+    Received {
+        len: usize,
+    },
+    /// Signature message of the Responder, decrypted by the Initiator
+    SignatureMessage {
+        version: u16,
+        valid_from: SystemTime,
+        not_valid_after: SystemTime,
+        remote_static_key: Vec<u8>,
+    },
+    /// The certificate of the Responder has been accepted, `at` is the time of the Initiator
+    Accepted {
+        at: SystemTime,
+    },
+    Failed {
+        reason: &'static str,
+    },
+}
+
+/// What happened during a handshake, recorded when enabled with `Initiator::record_transcript`
+/// or `Responder::record_transcript`. It contains no secret so it can be logged when a handshake
+/// fails, e.g. to diagnose a miner that can not connect without a packet capture.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Transcript {
+    events: Vec<TranscriptEvent>,
+}
+
+impl Transcript {
+    pub fn events(&self) -> &[TranscriptEvent] {
+        &self.events[..]
+    }
+
+    pub(crate) fn push(&mut self, event: TranscriptEvent) {
+        self.events.push(event);
+    }
+}
+
+fn unix_time(time: &SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl core::fmt::Display for Transcript {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for event in &self.events {
+            match event {
+                TranscriptEvent::Sent { len } => writeln!(f, "-> {} bytes", len)?,
+                TranscriptEvent::Received { len } => writeln!(f, "<- {} bytes", len)?,
+                TranscriptEvent::SignatureMessage {
+                    version,
+                    valid_from,
+                    not_valid_after,
+                    remote_static_key,
+                } => writeln!(
+                    f,
+                    "certificate version {} valid from {} to {} for {}",
+                    version,
+                    unix_time(valid_from),
+                    unix_time(not_valid_after),
+                    bs58::encode(remote_static_key).into_string()
+                )?,
+                TranscriptEvent::Accepted { at } => {
+                    writeln!(f, "certificate accepted at {}", unix_time(at))?
+                }
+                TranscriptEvent::Failed { reason } => writeln!(f, "failed: {}", reason)?,
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Objects that can perform 1 handshake step implement this trait
 pub trait Step {
+    /// Proceeds with the handshake and processes an optional incoming message - `in_msg` and
+    /// writes the handshake message to be sent out in `out`. It never allocates, the returned
+    /// `StepResult` contains the number of bytes written in `out`
+    ///
+    /// `in_msg` - optional input message to be processed
+    /// `out` - buffer for the output message, `HANDSHAKE_MESSAGE_MAX_LEN` bytes are always enough
+    fn step_with_buffer(
+        &mut self,
+        in_msg: Option<&[u8]>,
+        out: &mut [u8],
+    ) -> Result<StepResult<usize>>;
+
     /// Proceeds with the handshake and processes an optional incoming message - `in_msg` and
     /// generates a new handshake message to be sent out
     ///
     /// `in_msg` - optional input message to be processed
     /// this buffer and returned as appropriate `StepResult`
-    fn step(&mut self, in_msg: Option<Message>) -> Result<StepResult>;
+    fn step(&mut self, in_msg: Option<Message>) -> Result<StepResult> {
+        let mut out = [0_u8; HANDSHAKE_MESSAGE_MAX_LEN];
+        self.step_with_buffer(in_msg.as_deref(), &mut out)
+            .map(|result| result.with_message(&out))
+    }
 
     /// Transforms step into the handshake state
     fn into_handshake_state(self) -> HandshakeState;
+
+    /// Called when the handshake is complete, transforms step into the transport mode
+    fn into_transport_mode(self) -> Result<TransportMode>
+    where
+        Self: Sized,
+    {
+        TransportMode::from_handshake_state(self.into_handshake_state())
+    }
+}
+
+/// Write a handshake message prefixed by its length (u16 little endian), as a noise handshake frame
+#[cfg(feature = "async_io")]
+async fn write_message<IO>(io: &mut IO, message: &[u8]) -> Result<()>
+where
+    IO: futures::io::AsyncWrite + Unpin,
+{
+    use futures::io::AsyncWriteExt;
+    if message.len() > crate::MAX_MESSAGE_SIZE {
+        return Err(crate::error::Error {});
+    }
+    let header = (message.len() as u16).to_le_bytes();
+    io.write_all(&header)
+        .await
+        .map_err(|_| crate::error::Error {})?;
+    io.write_all(message)
+        .await
+        .map_err(|_| crate::error::Error {})?;
+    io.flush().await.map_err(|_| crate::error::Error {})
+}
+
+/// Read a handshake message prefixed by its length (u16 little endian) in `buffer`, return the
+/// length of the message
+#[cfg(feature = "async_io")]
+async fn read_message<IO>(io: &mut IO, buffer: &mut [u8]) -> Result<usize>
+where
+    IO: futures::io::AsyncRead + Unpin,
+{
+    use futures::io::AsyncReadExt;
+    let mut header = [0_u8; crate::HEADER_SIZE];
+    io.read_exact(&mut header)
+        .await
+        .map_err(|_| crate::error::Error {})?;
+    let len = u16::from_le_bytes(header) as usize;
+    if len > buffer.len() {
+        return Err(crate::error::Error {});
+    }
+    io.read_exact(&mut buffer[..len])
+        .await
+        .map_err(|_| crate::error::Error {})?;
+    Ok(len)
+}
+
+/// Drive `state` until the handshake is complete, `in_buffer[..in_len]` is the message already
+/// received from the counter party (if any)
+#[cfg(feature = "async_io")]
+async fn run<IO, S>(
+    io: &mut IO,
+    mut state: S,
+    in_buffer: &mut [u8; HANDSHAKE_MESSAGE_MAX_LEN],
+    in_len: Option<usize>,
+) -> Result<TransportMode>
+where
+    IO: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin,
+    S: Step,
+{
+    run_steps(io, &mut state, in_buffer, in_len).await?;
+    state.into_transport_mode()
+}
+
+/// Exchange the handshake messages of `state`, without switching to transport mode
+#[cfg(feature = "async_io")]
+async fn run_steps<IO, S>(
+    io: &mut IO,
+    state: &mut S,
+    in_buffer: &mut [u8; HANDSHAKE_MESSAGE_MAX_LEN],
+    mut in_len: Option<usize>,
+) -> Result<()>
+where
+    IO: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin,
+    S: Step,
+{
+    let mut out = [0_u8; HANDSHAKE_MESSAGE_MAX_LEN];
+    loop {
+        let in_msg = in_len.take().map(|len| &in_buffer[..len]);
+        match state.step_with_buffer(in_msg, &mut out)? {
+            StepResult::ExpectReply(len) => {
+                write_message(io, &out[..len]).await?;
+                in_len = Some(read_message(io, &mut in_buffer[..]).await?);
+            }
+            StepResult::NoMoreReply(len) => {
+                write_message(io, &out[..len]).await?;
+                break;
+            }
+            StepResult::Done => break,
+        }
+    }
+    Ok(())
+}
+
+/// Perform the whole handshake as initiator (downstream) over `io`. The handshake messages are
+/// sent as noise handshake frames (2 bytes length + message)
+#[cfg(feature = "async_io")]
+pub async fn run_initiator<IO>(io: &mut IO, initiator: crate::Initiator) -> Result<TransportMode>
+where
+    IO: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin,
+{
+    let mut in_buffer = [0_u8; HANDSHAKE_MESSAGE_MAX_LEN];
+    run(io, initiator, &mut in_buffer, None).await
+}
+
+/// Like `run_initiator` but the certificate of the Responder is verified by `verifier`, in a
+/// batch with the certificates of the other handshakes in progress
+#[cfg(all(feature = "async_io", feature = "batch_verify"))]
+pub async fn run_initiator_with_verifier<IO>(
+    io: &mut IO,
+    mut initiator: crate::Initiator,
+    verifier: &crate::BatchVerifier,
+) -> Result<TransportMode>
+where
+    IO: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin,
+{
+    let mut in_buffer = [0_u8; HANDSHAKE_MESSAGE_MAX_LEN];
+    initiator.defer_certificate_verification();
+    run_steps(io, &mut initiator, &mut in_buffer, None).await?;
+    initiator.verify_deferred_certificate(verifier).await?;
+    initiator.into_transport_mode()
+}
+
+/// Perform the whole handshake as responder (upstream) over `io`, wait for the first message of
+/// the initiator. The handshake messages are sent as noise handshake frames (2 bytes length +
+/// message)
+#[cfg(feature = "async_io")]
+pub async fn run_responder<IO>(io: &mut IO, responder: crate::Responder) -> Result<TransportMode>
+where
+    IO: futures::io::AsyncRead + futures::io::AsyncWrite + Unpin,
+{
+    let mut in_buffer = [0_u8; HANDSHAKE_MESSAGE_MAX_LEN];
+    let first_len = read_message(io, &mut in_buffer[..]).await?;
+    run(io, responder, &mut in_buffer, Some(first_len)).await
+}
+
+#[cfg(all(test, feature = "async_io"))]
+mod test {
+    use super::*;
+    use async_std::net::{TcpListener, TcpStream};
+
+    #[async_std::test]
+    async fn run_handshake() {
+        let (public_key, private_key) = crate::random_keypair();
+        let responder = crate::Responder::from_authority_kp(
+            &public_key,
+            &private_key,
+            core::time::Duration::from_secs(3600),
+        )
+        .unwrap();
+        let initiator = crate::Initiator::from_raw_k(public_key).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let upstream = async_std::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            run_responder(&mut stream, responder).await.unwrap()
+        });
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let mut initiator = run_initiator(&mut stream, initiator).await.unwrap();
+        let mut responder = upstream.await;
+
+        let message = b"test message";
+        let mut encrypted = alloc::vec![0; TransportMode::size_hint_encrypt(message.len())];
+        initiator.write(message, &mut encrypted).unwrap();
+        let mut decrypted = alloc::vec![0; message.len()];
+        responder.read(&encrypted, &mut decrypted).unwrap();
+        assert_eq!(&decrypted[..], &message[..]);
+    }
+
+    #[cfg(feature = "batch_verify")]
+    #[async_std::test]
+    async fn run_handshake_with_verifier() {
+        let verifier = crate::BatchVerifier::spawn(16, core::time::Duration::from_millis(10));
+        let (public_key, private_key) = crate::random_keypair();
+        let (other_public_key, _) = crate::random_keypair();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let responder = crate::Responder::from_authority_kp(
+                    &public_key,
+                    &private_key,
+                    core::time::Duration::from_secs(3600),
+                )
+                .unwrap();
+                async_std::task::spawn(async move {
+                    let _ = run_responder(&mut stream, responder).await;
+                });
+            }
+        });
+
+        let handshake = |authority_public_key| {
+            let verifier = verifier.clone();
+            async move {
+                let mut stream = TcpStream::connect(address).await.unwrap();
+                let initiator = crate::Initiator::from_raw_k(authority_public_key).unwrap();
+                run_initiator_with_verifier(&mut stream, initiator, &verifier).await
+            }
+        };
+        let (valid, invalid) =
+            futures::future::join(handshake(public_key), handshake(other_public_key)).await;
+        assert!(valid.unwrap().remote_certificate_expiry().is_some());
+        assert!(invalid.is_err());
+    }
 }
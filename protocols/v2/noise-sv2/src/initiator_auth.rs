@@ -0,0 +1,67 @@
+//! Responders that accept only some Initiators.
+//!
+//! With `Noise_NX` the Initiator is anonymous, any miner that know the address of a pool can
+//! connect. A private pool can instead build its Responders with
+//! `Responder::with_allowed_initiators`: the handshake use `Noise_IX`, the Initiator send its
+//! static key in the first message and the Responder reject the handshake if the key is not in
+//! the `AllowedInitiators`. The Initiators must be built with `Initiator::with_static_keypair`.
+//!
+//! The static key of the Initiator is sent before any key is agreed, a passive observer can see
+//! which Initiator is connecting.
+use crate::error::{Error, Result};
+use alloc::{collections::BTreeSet, sync::Arc};
+use core::convert::TryFrom;
+
+/// Static public keys of the Initiators accepted by a Responder. Cloning it is cheap, the same
+/// list can be used by the Responders of every connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllowedInitiators {
+    keys: Arc<BTreeSet<[u8; 32]>>,
+}
+
+impl AllowedInitiators {
+    pub fn new<I: IntoIterator<Item = [u8; 32]>>(keys: I) -> Self {
+        Self {
+            keys: Arc::new(keys.into_iter().collect()),
+        }
+    }
+
+    /// Parse a list of base58check encoded static public keys, one for line. The empty lines and
+    /// the lines that start with `#` are ignored
+    pub fn from_bs58_lines(lines: &str) -> Result<Self> {
+        let mut keys = BTreeSet::new();
+        for line in lines.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bytes = bs58::decode(line)
+                .with_check(None)
+                .into_vec()
+                .map_err(|_| Error {})?;
+            let mut key = [0_u8; 32];
+            if bytes.len() != key.len() {
+                return Err(Error {});
+            }
+            key.copy_from_slice(&bytes);
+            keys.insert(key);
+        }
+        Ok(Self {
+            keys: Arc::new(keys),
+        })
+    }
+
+    pub fn is_allowed(&self, static_public_key: &[u8]) -> bool {
+        match <[u8; 32]>::try_from(static_public_key) {
+            Ok(key) => self.keys.contains(&key),
+            Err(_) => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
@@ -1,28 +1,48 @@
 extern crate alloc;
 
 mod auth;
+pub mod backend;
+mod certificate_cache;
 mod error;
 mod formats;
 pub mod handshake;
+#[cfg(feature = "initiator_auth")]
+mod initiator_auth;
+mod session_stats;
 
 use alloc::vec::Vec;
 use bytes::Bytes;
 use core::{convert::TryFrom, time::Duration};
 use error::{Error, Result};
-use snow::{params::NoiseParams, Builder, HandshakeState, TransportState};
+use std::time::{Instant, SystemTime};
+
+pub use backend::{CipherState, HandshakeBackend, HandshakeState, StaticKeypair, TransportState};
+
+use zeroize::Zeroizing;
+
+#[cfg(feature = "schnorr")]
+pub use auth::schnorr;
+#[cfg(feature = "batch_verify")]
+pub use auth::BatchVerifier;
+pub use auth::{
+    CertificateClock, FixedTime, SignatureNoiseMessage, SignedPartHeader, SystemTimeProvider,
+    TimeProvider,
+};
+pub use certificate_cache::CertificateCache;
+pub use formats::{Certificate, IntermediateCertificate};
+#[cfg(feature = "initiator_auth")]
+pub use initiator_auth::AllowedInitiators;
+pub use session_stats::SessionStats;
 
-pub use auth::{SignatureNoiseMessage, SignedPartHeader};
-pub use formats::Certificate;
-
-/// Static keypair (aka 's' and 'rs') from the noise handshake patterns. This has to be used by
-/// users of this noise when Building the responder
-pub use snow::Keypair as StaticKeypair;
 /// Snow doesn't have a dedicated public key type, we will need it for authentication
 pub type StaticPublicKey = Vec<u8>;
-/// Snow doesn't have a dedicated secret key type, we will need it for authentication
-pub type StaticSecretKey = Vec<u8>;
+/// Snow doesn't have a dedicated secret key type, we will need it for authentication. The key is
+/// wiped from memory when dropped
+pub type StaticSecretKey = Zeroizing<Vec<u8>>;
 
 const PARAMS: &str = const_sv2::NOISE_PARAMS;
+#[cfg(feature = "initiator_auth")]
+const PARAMS_INITIATOR_AUTH: &str = const_sv2::NOISE_PARAMS_INITIATOR_AUTH;
 
 /// version: u16
 /// valid_from: u32
@@ -30,6 +50,10 @@ const PARAMS: &str = const_sv2::NOISE_PARAMS;
 /// siganture len: u16 (64 little endian)
 /// siganture: 64 bytes
 pub const SIGNATURE_MESSAGE_LEN: usize = 76;
+/// Signature noise message of a certificate signed by an intermediate key, followed by the
+/// certificate of the intermediate key
+pub const CHAINED_SIGNATURE_MESSAGE_LEN: usize =
+    SIGNATURE_MESSAGE_LEN + IntermediateCertificate::SERIALIZED_LEN;
 
 /// Private snow constants redefined here
 pub const MAX_MESSAGE_SIZE: usize = const_sv2::NOISE_FRAME_MAX_SIZE;
@@ -38,13 +62,27 @@ pub const SNOW_TAGLEN: usize = const_sv2::SNOW_TAGLEN;
 pub const HEADER_SIZE: usize = const_sv2::NOISE_FRAME_HEADER_SIZE;
 
 const BUFFER_LEN: usize =
-    SNOW_PSKLEN + SNOW_PSKLEN + SNOW_TAGLEN + SNOW_TAGLEN + SIGNATURE_MESSAGE_LEN;
+    SNOW_PSKLEN + SNOW_PSKLEN + SNOW_TAGLEN + SNOW_TAGLEN + CHAINED_SIGNATURE_MESSAGE_LEN;
 
 /// Generates noise specific static keypair specific for the current params
 pub fn generate_keypair() -> Result<StaticKeypair> {
-    let params: NoiseParams = PARAMS.parse().expect("BUG: cannot parse noise parameters");
-    let builder: Builder<'_> = Builder::new(params);
-    builder.generate_keypair().map_err(|_| Error {})
+    HandshakeState::generate_keypair()
+}
+
+/// Build the noise static keypair of `private_key`
+pub fn keypair_from_private(private_key: &[u8]) -> Result<StaticKeypair> {
+    HandshakeState::keypair_from_private(private_key)
+}
+
+/// Generates a noise static keypair drawing the private key from `rng`. With a seeded rng the
+/// keypair is reproducible, it can be used for the static key and for the ephemeral keys injected
+/// in `Initiator::with_ephemeral_key` and `Responder::with_ephemeral_key`
+pub fn generate_keypair_from_rng<R: rand::RngCore + rand::CryptoRng>(
+    rng: &mut R,
+) -> Result<StaticKeypair> {
+    let mut private_key = Zeroizing::new([0_u8; 32]);
+    rng.fill_bytes(&mut private_key[..]);
+    keypair_from_private(&private_key[..])
 }
 
 /// Generate a random ed25519 dalek keypair
@@ -55,26 +93,48 @@ pub fn random_keypair() -> ([u8; 32], [u8; 32]) {
     (kp.public.to_bytes(), kp.secret.to_bytes())
 }
 
+/// Key of the authority, the certificate of the Responder must be signed with the same scheme
+#[derive(Debug, Clone, Copy)]
+enum AuthorityPublicKey {
+    Ed25519(ed25519_dalek::PublicKey),
+    #[cfg(feature = "schnorr")]
+    Schnorr(auth::schnorr::XOnlyPublicKey),
+}
+
 #[derive(Debug)]
 pub struct Initiator {
     stage: usize,
     handshake_state: HandshakeState,
     /// Authority public key use to sign the certificate that prove the identity of the Responder
     /// (upstream node) to the Initiator (downstream node)
-    authority_public_key: ed25519_dalek::PublicKey,
+    authority_public_key: AuthorityPublicKey,
+    /// Expiration of the certificate of the Responder, set when the certificate is verified
+    remote_certificate_expiry: Option<SystemTime>,
+    /// The certificate of the Responder is verified by a `BatchVerifier` after the handshake
+    /// step, no transport mode until then
+    defer_verification: bool,
+    unverified_certificate: Option<auth::Certificate>,
+    /// Time against which the validity period of the certificate of the Responder is checked
+    clock: CertificateClock,
+    transcript: Option<handshake::Transcript>,
+    /// Certificates of the Responders verified in previous sessions, see `with_certificate_cache`
+    certificate_cache: Option<CertificateCache>,
 }
 
 impl Initiator {
     pub fn new(authority_public_key: ed25519_dalek::PublicKey) -> Result<Self> {
-        let params: NoiseParams = PARAMS.parse().expect("BUG: cannot parse noise parameters");
-
-        let builder: Builder<'_> = Builder::new(params);
-        let handshake_state = builder.build_initiator().map_err(|_| Error {})?;
+        let handshake_state = HandshakeState::build_initiator()?;
 
         Ok(Self {
             stage: 0,
             handshake_state,
-            authority_public_key,
+            authority_public_key: AuthorityPublicKey::Ed25519(authority_public_key),
+            remote_certificate_expiry: None,
+            defer_verification: false,
+            unverified_certificate: None,
+            clock: CertificateClock::default(),
+            transcript: None,
+            certificate_cache: None,
         })
     }
 
@@ -84,28 +144,292 @@ impl Initiator {
         Self::new(authority_public_key)
     }
 
+    /// Initiator that accept only the certificates signed with BIP340 Schnorr by
+    /// `authority_public_key`
+    #[cfg(feature = "schnorr")]
+    pub fn with_schnorr_authority(
+        authority_public_key: auth::schnorr::XOnlyPublicKey,
+    ) -> Result<Self> {
+        let handshake_state = HandshakeState::build_initiator()?;
+
+        Ok(Self {
+            stage: 0,
+            handshake_state,
+            authority_public_key: AuthorityPublicKey::Schnorr(authority_public_key),
+            remote_certificate_expiry: None,
+            defer_verification: false,
+            unverified_certificate: None,
+            clock: CertificateClock::default(),
+            transcript: None,
+            certificate_cache: None,
+        })
+    }
+
+    /// Initiator that send the public key of `static_keypair` to the Responder, for the
+    /// Responders that accept only some Initiators, see `AllowedInitiators`. It can not connect
+    /// to the other Responders
+    #[cfg(feature = "initiator_auth")]
+    pub fn with_static_keypair(
+        authority_public_key: ed25519_dalek::PublicKey,
+        static_keypair: &StaticKeypair,
+    ) -> Result<Self> {
+        let handshake_state =
+            HandshakeState::build_authenticated_initiator(&static_keypair.private)?;
+
+        Ok(Self {
+            stage: 0,
+            handshake_state,
+            authority_public_key: AuthorityPublicKey::Ed25519(authority_public_key),
+            remote_certificate_expiry: None,
+            defer_verification: false,
+            unverified_certificate: None,
+            clock: CertificateClock::default(),
+            transcript: None,
+            certificate_cache: None,
+        })
+    }
+
+    /// Initiator that use `ephemeral_private_key` as ephemeral key, the handshake messages are
+    /// reproducible. Only for tests and test vectors, a fixed ephemeral key is not secure
+    #[cfg(any(test, feature = "deterministic"))]
+    pub fn with_ephemeral_key(
+        authority_public_key: ed25519_dalek::PublicKey,
+        ephemeral_private_key: &[u8],
+    ) -> Result<Self> {
+        let handshake_state =
+            HandshakeState::build_initiator_with_ephemeral(ephemeral_private_key)?;
+
+        Ok(Self {
+            stage: 0,
+            handshake_state,
+            authority_public_key: AuthorityPublicKey::Ed25519(authority_public_key),
+            remote_certificate_expiry: None,
+            defer_verification: false,
+            unverified_certificate: None,
+            clock: CertificateClock::default(),
+            transcript: None,
+            certificate_cache: None,
+        })
+    }
+
+    /// Check the validity period of the certificate of the Responder with `clock` instead of the
+    /// system clock without tolerance
+    pub fn with_clock(mut self, clock: CertificateClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Skip the verification of the certificate of the Responder if the same certificate has been
+    /// verified for the same static key in a previous session and it is still valid, see
+    /// `CertificateCache`. Only the ed25519 certificates are cached
+    pub fn with_certificate_cache(mut self, cache: CertificateCache) -> Self {
+        self.certificate_cache = Some(cache);
+        self
+    }
+
+    /// Record the transcript of the handshake, see `Initiator::transcript`
+    pub fn record_transcript(&mut self) {
+        self.transcript = Some(handshake::Transcript::default());
+    }
+
+    /// Transcript of the handshake so far, if enabled with `record_transcript`. It is still
+    /// available after a failed step
+    pub fn transcript(&self) -> Option<&handshake::Transcript> {
+        self.transcript.as_ref()
+    }
+
+    fn record(&mut self, event: handshake::TranscriptEvent) {
+        if let Some(transcript) = &mut self.transcript {
+            transcript.push(event);
+        }
+    }
+
+    /// Record the signature message of the Responder and the outcome of its verification
+    fn record_verification(&mut self, signature_noise_message: &[u8], result: &Result<()>) {
+        if self.transcript.is_none() {
+            return;
+        }
+        if signature_noise_message.len() < SIGNATURE_MESSAGE_LEN {
+            self.record(handshake::TranscriptEvent::Failed {
+                reason: "signature message too short",
+            });
+            return;
+        }
+        let header = SignedPartHeader::from_bytes(&signature_noise_message[..10]);
+        let remote_static_key = HandshakeBackend::get_remote_static(&self.handshake_state)
+            .map(|key| key.to_vec())
+            .unwrap_or_default();
+        self.record(handshake::TranscriptEvent::SignatureMessage {
+            version: header.version(),
+            valid_from: header.valid_from(),
+            not_valid_after: header.not_valid_after(),
+            remote_static_key,
+        });
+        let scheme_matches = match self.authority_public_key {
+            AuthorityPublicKey::Ed25519(_) => {
+                header.version() == SignedPartHeader::VERSION
+                    || header.version() == SignedPartHeader::VERSION_CHAINED
+            }
+            #[cfg(feature = "schnorr")]
+            AuthorityPublicKey::Schnorr(_) => header.version() == SignedPartHeader::VERSION_SCHNORR,
+        };
+        let event = match result {
+            // Recorded by `verify_deferred_certificate`
+            Ok(()) if self.unverified_certificate.is_some() => return,
+            Ok(()) => handshake::TranscriptEvent::Accepted {
+                at: self.clock.now(),
+            },
+            Err(_) if !scheme_matches => handshake::TranscriptEvent::Failed {
+                reason: "certificate scheme does not match the authority key",
+            },
+            Err(_) if self.clock.verify_expiration(&header).is_err() => {
+                handshake::TranscriptEvent::Failed {
+                    reason: "certificate expired or not yet valid",
+                }
+            }
+            Err(_) => handshake::TranscriptEvent::Failed {
+                reason: "invalid certificate",
+            },
+        };
+        self.record(event);
+    }
+
     /// Verify the signature of the remote static key
-    fn verify_remote_static_key_signature(
-        &mut self,
-        signature_noise_message: Vec<u8>,
-    ) -> Result<()> {
-        let remote_static_key = self.handshake_state.get_remote_static().ok_or(Error {})?;
+    fn verify_remote_static_key_signature(&mut self, signature_noise_message: &[u8]) -> Result<()> {
+        let remote_static_key =
+            HandshakeBackend::get_remote_static(&self.handshake_state).ok_or(Error {})?;
         let remote_static_key = StaticPublicKey::from(remote_static_key);
 
+        if signature_noise_message.len() < SIGNATURE_MESSAGE_LEN {
+            return Err(Error {});
+        }
+        // The version of the certificate tell the signature scheme
+        let version = SignedPartHeader::from_bytes(&signature_noise_message[..10]).version();
+        let authority_public_key = match self.authority_public_key {
+            AuthorityPublicKey::Ed25519(key)
+                if version == SignedPartHeader::VERSION
+                    || version == SignedPartHeader::VERSION_CHAINED =>
+            {
+                key
+            }
+            #[cfg(feature = "schnorr")]
+            AuthorityPublicKey::Schnorr(key) if version == SignedPartHeader::VERSION_SCHNORR => {
+                // Schnorr certificates are never batched, they are verified here
+                let not_valid_after = auth::schnorr::verify_noise_message(
+                    signature_noise_message,
+                    &remote_static_key,
+                    &key,
+                    &self.clock,
+                )?;
+                self.defer_verification = false;
+                self.remote_certificate_expiry = Some(not_valid_after);
+                return Ok(());
+            }
+            _ => return Err(Error {}),
+        };
+
+        if let Some(cache) = &self.certificate_cache {
+            if let Some(not_valid_after) = cache.lookup(
+                authority_public_key.as_bytes(),
+                &remote_static_key,
+                signature_noise_message,
+                &self.clock,
+            ) {
+                // Nothing left to verify, also if the verification was deferred
+                self.defer_verification = false;
+                self.remote_certificate_expiry = Some(not_valid_after);
+                return Ok(());
+            }
+        }
+        let raw_signature_noise_message = signature_noise_message;
         let signature_noise_message =
-            auth::SignatureNoiseMessage::try_from(&signature_noise_message[..])
-                .map_err(|_| Error {})?;
+            auth::SignatureNoiseMessage::try_from(signature_noise_message).map_err(|_| Error {})?;
 
+        let not_valid_after = signature_noise_message.header.not_valid_after();
         let certificate = auth::Certificate::from_noise_message(
             signature_noise_message,
-            remote_static_key,
-            self.authority_public_key,
+            remote_static_key.clone(),
+            authority_public_key,
         );
 
-        certificate.validate().map_err(|_| Error {})?;
+        if self.defer_verification {
+            self.unverified_certificate = Some(certificate);
+        } else {
+            certificate
+                .validate_with_clock(&self.clock)
+                .map_err(|_| Error {})?;
+            if let Some(cache) = &self.certificate_cache {
+                cache.insert(
+                    authority_public_key.as_bytes(),
+                    &remote_static_key,
+                    raw_signature_noise_message,
+                    &self.clock,
+                );
+            }
+        }
+        self.remote_certificate_expiry = Some(not_valid_after);
 
         Ok(())
     }
+
+    /// The handshake step keeps the certificate of the Responder instead of verifying it, the
+    /// certificate must then be verified with `verify_deferred_certificate` before switching to
+    /// transport mode
+    #[cfg(feature = "batch_verify")]
+    pub fn defer_certificate_verification(&mut self) {
+        self.defer_verification = true;
+    }
+
+    /// Verify the certificate received in the handshake step with `verifier`, together with the
+    /// certificates of the other handshakes in progress
+    #[cfg(feature = "batch_verify")]
+    pub async fn verify_deferred_certificate(&mut self, verifier: &BatchVerifier) -> Result<()> {
+        match self.unverified_certificate.take() {
+            Some(certificate) => {
+                let result = verifier.verify(&certificate, &self.clock).await;
+                self.record(match result {
+                    Ok(()) => handshake::TranscriptEvent::Accepted {
+                        at: self.clock.now(),
+                    },
+                    Err(_) => handshake::TranscriptEvent::Failed {
+                        reason: "certificate rejected by the batch verifier",
+                    },
+                });
+                result?;
+                self.cache_deferred_certificate(&certificate);
+            }
+            // Already verified in the handshake step (Schnorr or cached certificate)
+            None if !self.defer_verification => (),
+            None => return Err(Error {}),
+        }
+        self.defer_verification = false;
+        Ok(())
+    }
+
+    /// Cache the certificate verified by `verify_deferred_certificate`
+    #[cfg(feature = "batch_verify")]
+    fn cache_deferred_certificate(&self, certificate: &auth::Certificate) {
+        let signature_noise_message =
+            match certificate.build_noise_message().serialize_to_bytes_mut() {
+                Ok(signature_noise_message) => signature_noise_message,
+                Err(_) => return,
+            };
+        let authority_public_key = match self.authority_public_key {
+            AuthorityPublicKey::Ed25519(key) => key,
+            #[cfg(feature = "schnorr")]
+            AuthorityPublicKey::Schnorr(_) => return,
+        };
+        let remote_static_key = HandshakeBackend::get_remote_static(&self.handshake_state);
+        if let (Some(cache), Some(remote_static_key)) = (&self.certificate_cache, remote_static_key)
+        {
+            cache.insert(
+                authority_public_key.as_bytes(),
+                remote_static_key,
+                &signature_noise_message,
+                &self.clock,
+            );
+        }
+    }
 }
 
 impl handshake::Step for Initiator {
@@ -113,42 +437,59 @@ impl handshake::Step for Initiator {
         self.handshake_state
     }
 
-    fn step(&mut self, in_msg: Option<handshake::Message>) -> Result<handshake::StepResult> {
-        let mut noise_bytes = Vec::new();
+    fn into_transport_mode(self) -> Result<TransportMode> {
+        if self.defer_verification {
+            return Err(Error {});
+        }
+        let remote_certificate_expiry = self.remote_certificate_expiry;
+        let mut transport_mode = TransportMode::from_handshake_state(self.handshake_state)?;
+        transport_mode.remote_certificate_expiry = remote_certificate_expiry;
+        Ok(transport_mode)
+    }
 
+    fn step_with_buffer(
+        &mut self,
+        in_msg: Option<&[u8]>,
+        out: &mut [u8],
+    ) -> Result<handshake::StepResult<usize>> {
         let result = match self.stage {
             0 => {
                 // Create first message (initiator ephemeral public key)
                 // -> e
+                // (-> e, s for the Responders that accept only some Initiators)
                 //
-                let buffer_len = SNOW_PSKLEN + SNOW_TAGLEN;
-                noise_bytes.resize(buffer_len, 0);
-
                 let len_written = self
                     .handshake_state
-                    .write_message(&[], &mut noise_bytes)
+                    .write_message(&[], out)
                     .map_err(|_| Error {})?;
+                self.record(handshake::TranscriptEvent::Sent { len: len_written });
 
-                noise_bytes.truncate(len_written);
-
-                handshake::StepResult::ExpectReply(noise_bytes)
+                handshake::StepResult::ExpectReply(len_written)
             }
             1 => {
                 // Receive responder message
                 // <- e, ee, s, es, SIGNATURE_NOISE_MESSAGE
                 //
                 let in_msg = in_msg.ok_or(Error {})?;
+                self.record(handshake::TranscriptEvent::Received { len: in_msg.len() });
+
+                let mut noise_bytes = [0_u8; BUFFER_LEN];
+
+                let signature_len =
+                    match self.handshake_state.read_message(in_msg, &mut noise_bytes) {
+                        Ok(len) => len,
+                        Err(_) => {
+                            self.record(handshake::TranscriptEvent::Failed {
+                                reason: "can not decrypt the message of the Responder",
+                            });
+                            return Err(Error {});
+                        }
+                    };
 
-                noise_bytes.resize(BUFFER_LEN, 0);
-
-                let signature_len = self
-                    .handshake_state
-                    .read_message(&in_msg[..], &mut noise_bytes)
-                    .map_err(|_| Error {})?;
-
-                debug_assert!(SIGNATURE_MESSAGE_LEN == signature_len);
-
-                self.verify_remote_static_key_signature(noise_bytes[..signature_len].to_vec())?;
+                let signature_noise_message = &noise_bytes[..signature_len];
+                let result = self.verify_remote_static_key_signature(signature_noise_message);
+                self.record_verification(signature_noise_message, &result);
+                result?;
 
                 handshake::StepResult::Done
             }
@@ -167,21 +508,77 @@ pub struct Responder {
     handshake_state: HandshakeState,
     /// Serialized signature noise message
     signature_noise_message: Bytes,
+    transcript: Option<handshake::Transcript>,
+    /// If set the static key of the Initiator must be in the list
+    #[cfg(feature = "initiator_auth")]
+    allowed_initiators: Option<AllowedInitiators>,
 }
 
+/// The secret key of `ed25519_dalek::Keypair` is wiped from memory when dropped
 pub struct Authority {
     kp: ed25519_dalek::Keypair,
+    /// Set when `kp` is an intermediate key, certificate of `kp` signed by the root authority
+    intermediate: Option<IntermediateCertificate>,
 }
 
 impl Authority {
     pub fn new(kp: ed25519_dalek::Keypair) -> Self {
-        Self { kp }
+        Self {
+            kp,
+            intermediate: None,
+        }
     }
 
     /// Create an Authority from pub_k and priv_k (32 bytes keys)
     pub fn from_raw_k(pub_k: &[u8], priv_k: &[u8]) -> Option<Self> {
-        let kp = ed25519_dalek::Keypair::from_bytes(&[priv_k, pub_k].concat()).ok()?;
-        Some(Self { kp })
+        // The concatenated keys contain the secret key and must be wiped
+        let keys = Zeroizing::new([priv_k, pub_k].concat());
+        let kp = ed25519_dalek::Keypair::from_bytes(&keys).ok()?;
+        Some(Self::new(kp))
+    }
+
+    /// Authority that sign with the intermediate key `kp`, `certificate` is the certificate of
+    /// `kp` issued by the root authority with `Authority::new_intermediate_cert`. The
+    /// certificates issued by this authority are valid for the Initiators of the root authority
+    pub fn intermediate(
+        kp: ed25519_dalek::Keypair,
+        certificate: IntermediateCertificate,
+    ) -> Result<Self> {
+        if certificate.public_key.clone().into_inner() != kp.public {
+            return Err(Error {});
+        }
+        certificate.validate()?;
+        Ok(Self {
+            kp,
+            intermediate: Some(certificate),
+        })
+    }
+
+    /// Create the certificate of the intermediate key `intermediate_public_key` valid until now +
+    /// duration, the root authority can then be kept offline until the certificate expires
+    pub fn new_intermediate_cert(
+        &self,
+        intermediate_public_key: ed25519_dalek::PublicKey,
+        duration: Duration,
+    ) -> Result<IntermediateCertificate> {
+        // An intermediate key can not issue other intermediate keys
+        if self.intermediate.is_some() {
+            return Err(Error {});
+        }
+        let header = SignedPartHeader::with_duration(duration)?
+            .with_version(SignedPartHeader::VERSION_INTERMEDIATE);
+        let signed_part = auth::SignedPart::new(
+            header.clone(),
+            intermediate_public_key.as_bytes().to_vec(),
+            self.kp.public,
+        );
+        let signature = signed_part.sign_with(&self.kp)?;
+        Ok(IntermediateCertificate::new(
+            header,
+            intermediate_public_key,
+            self.kp.public,
+            signature,
+        ))
     }
 
     /// Create a Certificate valid until now + duration for pub_k
@@ -190,13 +587,21 @@ impl Authority {
         pub_k: &[u8],
         duration: Duration,
     ) -> Result<auth::SignatureNoiseMessage> {
-        let header = SignedPartHeader::with_duration(duration).map_err(|_| Error {})?;
+        let mut header = SignedPartHeader::with_duration(duration).map_err(|_| Error {})?;
+        if self.intermediate.is_some() {
+            header = header.with_version(SignedPartHeader::VERSION_CHAINED);
+        }
 
         let signed_part = auth::SignedPart::new(header, pub_k.into(), self.kp.public);
 
         let signature = signed_part.sign_with(&self.kp).map_err(|_| Error {})?;
 
-        let certificate = auth::Certificate::new(signed_part, signature);
+        let certificate = match &self.intermediate {
+            Some(intermediate) => {
+                auth::Certificate::new_chained(signed_part, signature, intermediate.clone())
+            }
+            None => auth::Certificate::new(signed_part, signature),
+        };
 
         Ok(certificate.build_noise_message())
     }
@@ -213,27 +618,74 @@ impl Authority {
 
 impl Responder {
     pub fn new(static_keypair: &StaticKeypair, signature_noise_message: Bytes) -> Result<Self> {
-        let params: NoiseParams = PARAMS.parse().map_err(|_| Error {})?;
+        let handshake_state = HandshakeState::build_responder(&static_keypair.private)
+            .expect("BUG: cannot build responder");
 
-        let builder: Builder<'_> = Builder::new(params);
+        Ok(Self {
+            stage: 0,
+            handshake_state,
+            signature_noise_message,
+            transcript: None,
+            #[cfg(feature = "initiator_auth")]
+            allowed_initiators: None,
+        })
+    }
 
-        let handshake_state = builder
-            .local_private_key(&static_keypair.private)
-            .build_responder()
-            .expect("BUG: cannot build responder");
+    /// Responder that use `ephemeral_private_key` as ephemeral key, the handshake messages are
+    /// reproducible. Only for tests and test vectors, a fixed ephemeral key is not secure
+    #[cfg(any(test, feature = "deterministic"))]
+    pub fn with_ephemeral_key(
+        static_keypair: &StaticKeypair,
+        signature_noise_message: Bytes,
+        ephemeral_private_key: &[u8],
+    ) -> Result<Self> {
+        let handshake_state = HandshakeState::build_responder_with_ephemeral(
+            &static_keypair.private,
+            ephemeral_private_key,
+        )?;
 
         Ok(Self {
             stage: 0,
             handshake_state,
             signature_noise_message,
+            transcript: None,
+            #[cfg(feature = "initiator_auth")]
+            allowed_initiators: None,
         })
     }
 
+    /// Record the transcript of the handshake, see `Responder::transcript`
+    pub fn record_transcript(&mut self) {
+        self.transcript = Some(handshake::Transcript::default());
+    }
+
+    /// Transcript of the handshake so far, if enabled with `record_transcript`
+    pub fn transcript(&self) -> Option<&handshake::Transcript> {
+        self.transcript.as_ref()
+    }
+
+    fn record(&mut self, event: handshake::TranscriptEvent) {
+        if let Some(transcript) = &mut self.transcript {
+            transcript.push(event);
+        }
+    }
+
     pub fn with_random_static_kp(signature_noise_message: Bytes) -> Result<Self> {
         let static_keypair = generate_keypair().map_err(|_| Error {})?;
         Self::new(&static_keypair, signature_noise_message)
     }
 
+    /// Create a Responder with a certificate signed with BIP340 Schnorr by the secp256k1 authority
+    /// key priv_k (32 bytes)
+    #[cfg(feature = "schnorr")]
+    pub fn from_schnorr_authority_k(priv_k: &[u8], duration: core::time::Duration) -> Result<Self> {
+        let authority = auth::schnorr::keypair_from_raw(priv_k)?;
+        let static_keypair = generate_keypair().map_err(|_| Error {})?;
+        let signature_noise_message =
+            auth::schnorr::new_noise_message(&authority, &static_keypair.public, duration)?;
+        Self::new(&static_keypair, signature_noise_message.freeze())
+    }
+
     /// Create a Responder from authority pub_k and priv_k (32 bytes keys)
     /// Usefull if there is no central pool authority and the Responder can certify itself
     pub fn from_authority_kp(
@@ -241,16 +693,80 @@ impl Responder {
         priv_k: &[u8],
         duration: core::time::Duration,
     ) -> Result<Self> {
-        let authority = Authority::from_raw_k(pub_k, priv_k);
+        let authority = Authority::from_raw_k(pub_k, priv_k).ok_or(Error {})?;
+        Self::from_authority(&authority, duration)
+    }
+
+    /// Create a Responder with a new static key certified by `authority`, that can be an
+    /// intermediate authority
+    pub fn from_authority(authority: &Authority, duration: core::time::Duration) -> Result<Self> {
+        let (static_keypair, signature_noise_message) =
+            Self::certified_keypair(authority, duration)?;
+        Self::new(&static_keypair, signature_noise_message)
+    }
 
+    /// New static keypair and its serialized certificate issued by `authority`
+    fn certified_keypair(
+        authority: &Authority,
+        duration: core::time::Duration,
+    ) -> Result<(StaticKeypair, Bytes)> {
         let static_keypair = generate_keypair().map_err(|_| Error {})?;
 
         let signature_noise_message = authority
-            .ok_or(Error {})?
             .new_cert(static_keypair.public.clone(), duration)?
             .serialize_to_bytes_mut()?;
 
-        Self::new(&static_keypair, signature_noise_message.into())
+        Ok((static_keypair, signature_noise_message.into()))
+    }
+
+    /// Responder that accept only the Initiators built with `Initiator::with_static_keypair` and
+    /// a key in `allowed_initiators`, see `AllowedInitiators`
+    #[cfg(feature = "initiator_auth")]
+    pub fn with_allowed_initiators(
+        static_keypair: &StaticKeypair,
+        signature_noise_message: Bytes,
+        allowed_initiators: AllowedInitiators,
+    ) -> Result<Self> {
+        let handshake_state =
+            HandshakeState::build_authenticating_responder(&static_keypair.private)?;
+
+        Ok(Self {
+            stage: 0,
+            handshake_state,
+            signature_noise_message,
+            transcript: None,
+            allowed_initiators: Some(allowed_initiators),
+        })
+    }
+
+    /// Like `Responder::from_authority` but accept only the Initiators in `allowed_initiators`
+    #[cfg(feature = "initiator_auth")]
+    pub fn from_authority_with_allowed_initiators(
+        authority: &Authority,
+        duration: core::time::Duration,
+        allowed_initiators: AllowedInitiators,
+    ) -> Result<Self> {
+        let (static_keypair, signature_noise_message) =
+            Self::certified_keypair(authority, duration)?;
+        Self::with_allowed_initiators(&static_keypair, signature_noise_message, allowed_initiators)
+    }
+
+    /// Reject the Initiator if its static key is not allowed
+    #[cfg(feature = "initiator_auth")]
+    fn check_initiator(&mut self) -> Result<()> {
+        let allowed_initiators = match &self.allowed_initiators {
+            Some(allowed_initiators) => allowed_initiators,
+            None => return Ok(()),
+        };
+        match HandshakeBackend::get_remote_static(&self.handshake_state) {
+            Some(key) if allowed_initiators.is_allowed(key) => Ok(()),
+            _ => {
+                self.record(handshake::TranscriptEvent::Failed {
+                    reason: "the Initiator static key is not allowed",
+                });
+                Err(Error {})
+            }
+        }
     }
 }
 
@@ -259,34 +775,44 @@ impl handshake::Step for Responder {
         self.handshake_state
     }
 
-    fn step(&mut self, in_msg: Option<handshake::Message>) -> Result<handshake::StepResult> {
-        let mut noise_bytes = Vec::new();
-
+    fn step_with_buffer(
+        &mut self,
+        in_msg: Option<&[u8]>,
+        out: &mut [u8],
+    ) -> Result<handshake::StepResult<usize>> {
         let result = match self.stage {
             0 => {
                 // Receive Initiator ephemeral public key
                 // <- e
+                // (<- e, s for the Responders that accept only some Initiators)
                 //
                 let in_msg = in_msg.ok_or(Error {})?;
+                self.record(handshake::TranscriptEvent::Received { len: in_msg.len() });
 
-                let buffer_len = BUFFER_LEN;
-
-                noise_bytes.resize(buffer_len, 0);
+                if out.len() < BUFFER_LEN {
+                    return Err(Error {});
+                }
 
-                self.handshake_state
-                    .read_message(&in_msg, &mut noise_bytes)
-                    .map_err(|_| Error {})?;
+                if self.handshake_state.read_message(in_msg, out).is_err() {
+                    self.record(handshake::TranscriptEvent::Failed {
+                        reason: "can not decrypt the message of the Initiator",
+                    });
+                    return Err(Error {});
+                }
+                #[cfg(feature = "initiator_auth")]
+                self.check_initiator()?;
 
                 // Create response message
                 // -> e, ee, s, es, SIGNATURE_NOISE_MESSAGE
                 //
                 let len_written = self
                     .handshake_state
-                    .write_message(&self.signature_noise_message, &mut noise_bytes)
+                    .write_message(&self.signature_noise_message, out)
                     .map_err(|_| Error {})?;
 
-                debug_assert!(buffer_len == len_written);
-                handshake::StepResult::NoMoreReply(noise_bytes)
+                debug_assert!(len_written <= BUFFER_LEN);
+                self.record(handshake::TranscriptEvent::Sent { len: len_written });
+                handshake::StepResult::NoMoreReply(len_written)
             }
             1 => handshake::StepResult::Done,
             _ => return Err(Error {}),
@@ -301,22 +827,80 @@ impl handshake::Step for Responder {
 #[derive(Debug)]
 pub struct TransportMode {
     inner: TransportState,
+    remote_certificate_expiry: Option<SystemTime>,
+    handshake_hash: Option<Vec<u8>>,
+    remote_static_key: Option<Vec<u8>>,
+    stats: SessionStats,
 }
 
 impl TransportMode {
     pub fn new(inner: TransportState) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            remote_certificate_expiry: None,
+            handshake_hash: None,
+            remote_static_key: None,
+            stats: SessionStats::new(Instant::now()),
+        }
+    }
+
+    /// Called when the handshake is complete, the handshake hash is kept as the transport state
+    /// do not have it
+    pub(crate) fn from_handshake_state(handshake_state: HandshakeState) -> Result<Self> {
+        let handshake_hash = handshake_state.get_handshake_hash().to_vec();
+        let remote_static_key =
+            HandshakeBackend::get_remote_static(&handshake_state).map(|key| key.to_vec());
+        let mut transport_mode =
+            HandshakeBackend::into_transport_mode(handshake_state).map(Self::new)?;
+        transport_mode.handshake_hash = Some(handshake_hash);
+        transport_mode.remote_static_key = remote_static_key;
+        Ok(transport_mode)
+    }
+
+    /// Hash of the noise handshake (channel binding value). It is the same for the Initiator and
+    /// the Responder of a session and different for every session, so an authentication token
+    /// signed together with it can not be replayed on another session. None if the transport mode
+    /// has been built with `TransportMode::new`
+    pub fn handshake_hash(&self) -> Option<&[u8]> {
+        self.handshake_hash.as_deref()
+    }
+
+    /// Static key of the counter party: the key of the Responder for the Initiator, the key of the
+    /// Initiator for a Responder built with `Responder::with_allowed_initiators`. None if the
+    /// transport mode has been built with `TransportMode::new`
+    pub fn remote_static_key(&self) -> Option<&[u8]> {
+        self.remote_static_key.as_deref()
+    }
+
+    /// When the certificate of the Responder expires, only for the Initiator side of the session
+    pub fn remote_certificate_expiry(&self) -> Option<SystemTime> {
+        self.remote_certificate_expiry
+    }
+
+    /// Frames and bytes exchanged in the session so far
+    pub fn stats(&self) -> &SessionStats {
+        &self.stats
     }
 
     /// Decrypt and verify message from `in_buf` and append the result to `decrypted_message`
+    ///
+    /// A message that can not be decrypted (the tag does not match) is fatal for the session: the
+    /// stream has been tampered or the counter party is out of sync, every following read fail
     #[inline(always)]
     pub fn read(&mut self, encrypted_msg: &[u8], decrypted_msg: &mut [u8]) -> Result<()> {
-        let _msg_len = self
-            .inner
-            .read_message(encrypted_msg, decrypted_msg)
-            .map_err(|_| Error {})?;
-
-        Ok(())
+        if self.stats.decrypt_failures > 0 {
+            return Err(Error {});
+        }
+        match self.inner.read_message(encrypted_msg, decrypted_msg) {
+            Ok(_) => {
+                self.stats.on_received(encrypted_msg.len(), Instant::now());
+                Ok(())
+            }
+            Err(_) => {
+                self.stats.on_decrypt_failure();
+                Err(Error {})
+            }
+        }
     }
 
     /// Return the size that decrypt_msg in Self::read should have in order to decrypt the
@@ -344,10 +928,11 @@ impl TransportMode {
         //encrypted_msg[0] = len.to_le_bytes()[0];
         //encrypted_msg[1] = len.to_be_bytes()[1];
 
-        let _msg_len = self
+        let msg_len = self
             .inner
             .write_message(plain_msg, encrypted_msg)
             .map_err(|_| Error {})?;
+        self.stats.on_sent(msg_len, Instant::now());
 
         Ok(())
     }
@@ -358,6 +943,7 @@ pub(crate) mod test {
     use super::*;
     use bytes::BytesMut;
     use handshake::Step as _;
+    use std::sync::Arc;
 
     /// Helper that builds:
     /// - serialized signature noise message
@@ -490,6 +1076,443 @@ pub(crate) mod test {
         );
     }
 
+    #[test]
+    fn initiator_transport_mode_has_remote_certificate_expiry() {
+        let (signature_noise_message, authority_keypair, static_keypair) =
+            build_serialized_signature_noise_message_and_keypairs();
+
+        let mut initiator = Initiator::new(authority_keypair.public).unwrap();
+        let mut responder = Responder::new(&static_keypair, signature_noise_message).unwrap();
+        let first_message = match initiator.step(None).unwrap() {
+            handshake::StepResult::ExpectReply(msg) => msg,
+            _ => panic!(),
+        };
+        let second_message = match responder.step(Some(first_message)).unwrap() {
+            handshake::StepResult::NoMoreReply(msg) => msg,
+            _ => panic!(),
+        };
+        initiator.step(Some(second_message)).unwrap();
+
+        let expiry = initiator
+            .into_transport_mode()
+            .unwrap()
+            .remote_certificate_expiry()
+            .unwrap();
+        assert!(expiry > SystemTime::now());
+        let responder = responder.into_transport_mode().unwrap();
+        assert_eq!(responder.remote_certificate_expiry(), None);
+    }
+
+    #[test]
+    fn transport_modes_have_the_same_handshake_hash() {
+        let handshake = || {
+            let (signature_noise_message, authority_keypair, static_keypair) =
+                build_serialized_signature_noise_message_and_keypairs();
+            let mut initiator = Initiator::new(authority_keypair.public).unwrap();
+            let mut responder = Responder::new(&static_keypair, signature_noise_message).unwrap();
+            let first_message = initiator.step(None).unwrap().inner();
+            let second_message = responder.step(Some(first_message)).unwrap().inner();
+            initiator.step(Some(second_message)).unwrap();
+            (
+                initiator.into_transport_mode().unwrap(),
+                responder.into_transport_mode().unwrap(),
+            )
+        };
+        let (initiator, responder) = handshake();
+        let hash = initiator.handshake_hash().unwrap();
+        assert_eq!(hash.len(), 32);
+        assert_eq!(Some(hash), responder.handshake_hash());
+
+        let (other_session, _) = handshake();
+        assert_ne!(Some(hash), other_session.handshake_hash());
+
+        let (initiator, _) = perform_handshake();
+        assert_eq!(initiator.handshake_hash(), None);
+    }
+
+    #[cfg(feature = "initiator_auth")]
+    #[test]
+    fn responder_accept_only_the_allowed_initiators() {
+        let (signature_noise_message, authority_keypair, static_keypair) =
+            build_serialized_signature_noise_message_and_keypairs();
+        let allowed = generate_keypair().unwrap();
+        let unknown = generate_keypair().unwrap();
+        let allowed_key = <[u8; 32]>::try_from(&allowed.public[..]).unwrap();
+        let allowed_initiators = AllowedInitiators::new(vec![allowed_key]);
+
+        let handshake = |mut initiator: Initiator| -> Result<()> {
+            let mut responder = Responder::with_allowed_initiators(
+                &static_keypair,
+                signature_noise_message.clone(),
+                allowed_initiators.clone(),
+            )
+            .unwrap();
+            let first_message = initiator.step(None)?.inner();
+            let second_message = responder.step(Some(first_message))?.inner();
+            initiator.step(Some(second_message))?;
+            initiator.into_transport_mode()?;
+            responder.into_transport_mode()?;
+            Ok(())
+        };
+        let authority_public_key = authority_keypair.public;
+        assert!(
+            handshake(Initiator::with_static_keypair(authority_public_key, &allowed).unwrap())
+                .is_ok()
+        );
+        assert!(
+            handshake(Initiator::with_static_keypair(authority_public_key, &unknown).unwrap())
+                .is_err()
+        );
+        // An anonymous Initiator use an handshake pattern that the Responder can not read
+        assert!(handshake(Initiator::new(authority_public_key).unwrap()).is_err());
+
+        let encoded = bs58::encode(&allowed.public[..]).with_check().into_string();
+        let lines = format!("# farm 1\n\n{}\n", encoded);
+        assert_eq!(
+            AllowedInitiators::from_bs58_lines(&lines).unwrap(),
+            allowed_initiators
+        );
+        assert!(AllowedInitiators::from_bs58_lines("not a key").is_err());
+    }
+
+    #[cfg(feature = "schnorr")]
+    #[test]
+    fn schnorr_certificate_handshake() {
+        let authority = schnorr::keypair_from_raw(&[7; 32]).unwrap();
+        let (ed25519_public_key, _) = random_keypair();
+        let handshake = |mut initiator: Initiator| {
+            let mut responder =
+                Responder::from_schnorr_authority_k(&[7; 32], Duration::from_secs(3600)).unwrap();
+            let first_message = initiator.step(None).unwrap().inner();
+            let second_message = responder.step(Some(first_message)).unwrap().inner();
+            initiator.step(Some(second_message))?;
+            initiator.into_transport_mode()
+        };
+
+        let initiator = Initiator::with_schnorr_authority(authority.public_key()).unwrap();
+        let transport_mode = handshake(initiator).unwrap();
+        assert!(transport_mode.remote_certificate_expiry().is_some());
+        // An ed25519 authority never accept a Schnorr certificate
+        let initiator = Initiator::from_raw_k(ed25519_public_key).unwrap();
+        assert!(handshake(initiator).is_err());
+    }
+
+    #[test]
+    fn chained_certificate_handshake() {
+        let mut csprng = rand::rngs::OsRng {};
+        let root_keypair = ed25519_dalek::Keypair::generate(&mut csprng);
+        let root_public_key = root_keypair.public;
+        let intermediate_keypair = ed25519_dalek::Keypair::generate(&mut csprng);
+        let certificate = Authority::new(root_keypair)
+            .new_intermediate_cert(intermediate_keypair.public, Duration::from_secs(3600))
+            .unwrap();
+        let intermediate = Authority::intermediate(intermediate_keypair, certificate).unwrap();
+
+        let mut initiator = Initiator::new(root_public_key).unwrap();
+        let mut responder =
+            Responder::from_authority(&intermediate, Duration::from_secs(3600)).unwrap();
+        let first_message = initiator.step(None).unwrap().inner();
+        let second_message = responder.step(Some(first_message)).unwrap().inner();
+        initiator.step(Some(second_message)).unwrap();
+        assert!(initiator
+            .into_transport_mode()
+            .unwrap()
+            .remote_certificate_expiry()
+            .is_some());
+    }
+
+    #[test]
+    fn certificate_cache_skip_the_verified_certificates() {
+        let (signature_noise_message, authority_keypair, static_keypair) =
+            build_serialized_signature_noise_message_and_keypairs();
+        let authority_public_key = authority_keypair.public;
+        let handshake = |initiator: Initiator, static_keypair: &StaticKeypair, message: Bytes| {
+            let mut initiator = initiator;
+            let mut responder = Responder::new(static_keypair, message).unwrap();
+            let first_message = initiator.step(None).unwrap().inner();
+            let second_message = responder.step(Some(first_message)).unwrap().inner();
+            initiator.step(Some(second_message)).map(|_| ())
+        };
+        let cache = CertificateCache::new();
+        let initiator = || {
+            Initiator::new(authority_public_key)
+                .unwrap()
+                .with_certificate_cache(cache.clone())
+        };
+
+        assert!(handshake(
+            initiator(),
+            &static_keypair,
+            signature_noise_message.clone()
+        )
+        .is_ok());
+        assert_eq!(cache.len(), 1);
+
+        // A certificate cached for the key is not verified again, the tampered signature is only
+        // rejected without the cache
+        let mut tampered = signature_noise_message.to_vec();
+        tampered[20] ^= 0xff;
+        cache.insert(
+            authority_public_key.as_bytes(),
+            &static_keypair.public,
+            &tampered,
+            &CertificateClock::default(),
+        );
+        assert!(handshake(initiator(), &static_keypair, tampered.clone().into()).is_ok());
+        let uncached = Initiator::new(authority_public_key).unwrap();
+        assert!(handshake(uncached, &static_keypair, tampered.clone().into()).is_err());
+
+        // Another certificate for the same key is verified in full
+        let (other_message, _, _) = build_serialized_signature_noise_message_and_keypairs();
+        assert!(handshake(initiator(), &static_keypair, other_message).is_err());
+
+        // The cached certificate is expired, it is forgotten and verified in full
+        let later = SystemTime::now() + Duration::from_secs(365 * 24 * 3600);
+        let clock = CertificateClock::new(Arc::new(FixedTime(later)), Duration::from_secs(0));
+        let initiator_later = initiator().with_clock(clock);
+        assert!(handshake(initiator_later, &static_keypair, tampered.into()).is_err());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn certificate_validity_checked_with_the_clock() {
+        let (public_key, private_key) = random_keypair();
+        let handshake = |clock: CertificateClock| {
+            let mut initiator = Initiator::from_raw_k(public_key).unwrap().with_clock(clock);
+            let mut responder =
+                Responder::from_authority_kp(&public_key, &private_key, Duration::from_secs(60))
+                    .unwrap();
+            let first_message = initiator.step(None).unwrap().inner();
+            let second_message = responder.step(Some(first_message)).unwrap().inner();
+            initiator.step(Some(second_message)).map(|_| ())
+        };
+        let at = |offset: i64| {
+            let now = SystemTime::now();
+            let instant = if offset < 0 {
+                now - Duration::from_secs(offset.unsigned_abs())
+            } else {
+                now + Duration::from_secs(offset as u64)
+            };
+            Arc::new(FixedTime(instant))
+        };
+
+        assert!(handshake(CertificateClock::new(at(30), Duration::from_secs(0))).is_ok());
+        // The clock of the Initiator is 2 minutes late or early
+        assert!(handshake(CertificateClock::new(at(-120), Duration::from_secs(0))).is_err());
+        assert!(handshake(CertificateClock::new(at(180), Duration::from_secs(0))).is_err());
+        assert!(handshake(CertificateClock::new(at(-120), Duration::from_secs(300))).is_ok());
+        assert!(handshake(CertificateClock::new(at(180), Duration::from_secs(300))).is_ok());
+    }
+
+    #[test]
+    fn transcript_records_the_handshake() {
+        let (public_key, private_key) = random_keypair();
+        let (other_public_key, _) = random_keypair();
+        let handshake = |authority_public_key| {
+            let mut initiator = Initiator::from_raw_k(authority_public_key).unwrap();
+            initiator.record_transcript();
+            let mut responder =
+                Responder::from_authority_kp(&public_key, &private_key, Duration::from_secs(60))
+                    .unwrap();
+            responder.record_transcript();
+            let first_message = initiator.step(None).unwrap().inner();
+            let second_message = responder.step(Some(first_message)).unwrap().inner();
+            let _ = initiator.step(Some(second_message));
+            (
+                initiator.transcript().unwrap().clone(),
+                responder.transcript().unwrap().clone(),
+            )
+        };
+
+        let (initiator, responder) = handshake(public_key);
+        assert_eq!(
+            responder.events(),
+            &[
+                handshake::TranscriptEvent::Received { len: 32 },
+                handshake::TranscriptEvent::Sent { len: 172 },
+            ]
+        );
+        let events = initiator.events();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0], handshake::TranscriptEvent::Sent { len: 32 });
+        assert_eq!(events[1], handshake::TranscriptEvent::Received { len: 172 });
+        assert!(matches!(
+            events[2],
+            handshake::TranscriptEvent::SignatureMessage { version: 0, .. }
+        ));
+        assert!(matches!(
+            events[3],
+            handshake::TranscriptEvent::Accepted { .. }
+        ));
+
+        // Signed by another authority
+        let (initiator, _) = handshake(other_public_key);
+        assert_eq!(
+            initiator.events().last(),
+            Some(&handshake::TranscriptEvent::Failed {
+                reason: "invalid certificate"
+            })
+        );
+        assert!(initiator
+            .to_string()
+            .ends_with("failed: invalid certificate\n"));
+    }
+
+    #[test]
+    fn test_handshake_with_buffer() {
+        let (signature_noise_message, authority_keypair, static_keypair) =
+            build_serialized_signature_noise_message_and_keypairs();
+
+        let mut initiator = Initiator::new(authority_keypair.public).unwrap();
+        let mut responder = Responder::new(&static_keypair, signature_noise_message).unwrap();
+
+        let mut first_message = [0_u8; handshake::HANDSHAKE_MESSAGE_MAX_LEN];
+        let first_len = match initiator
+            .step_with_buffer(None, &mut first_message)
+            .unwrap()
+        {
+            handshake::StepResult::ExpectReply(len) => len,
+            _ => panic!(),
+        };
+        // The output buffer must be big enough for the response
+        let mut small = [0_u8; SNOW_PSKLEN];
+        assert!(responder
+            .step_with_buffer(Some(&first_message[..first_len]), &mut small)
+            .is_err());
+
+        let mut second_message = [0_u8; handshake::HANDSHAKE_MESSAGE_MAX_LEN];
+        let second_len = match responder
+            .step_with_buffer(Some(&first_message[..first_len]), &mut second_message)
+            .unwrap()
+        {
+            handshake::StepResult::NoMoreReply(len) => len,
+            _ => panic!(),
+        };
+        let mut out = [0_u8; 0];
+        assert_eq!(
+            initiator
+                .step_with_buffer(Some(&second_message[..second_len]), &mut out)
+                .unwrap(),
+            handshake::StepResult::Done
+        );
+
+        let mut initiator = initiator.into_transport_mode().unwrap();
+        let mut responder = responder.into_transport_mode().unwrap();
+        let message = b"test message";
+        let mut encrypted = vec![0; TransportMode::size_hint_encrypt(message.len())];
+        initiator.write(message, &mut encrypted).unwrap();
+        let mut decrypted = vec![0; message.len()];
+        responder.read(&encrypted, &mut decrypted).unwrap();
+        assert_eq!(&decrypted[..], &message[..]);
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn from_hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).expect("BUG: invalid hex"))
+            .collect()
+    }
+
+    /// Golden handshake transcript, see `test-vectors/README.md`
+    #[derive(serde::Deserialize, serde::Serialize, Debug, PartialEq)]
+    struct HandshakeVector {
+        authority_secret_key: String,
+        responder_static_secret_key: String,
+        initiator_ephemeral_secret_key: String,
+        responder_ephemeral_secret_key: String,
+        signature_noise_message: String,
+        message_1: String,
+        message_2: String,
+        plaintext: String,
+        ciphertext: String,
+    }
+
+    /// Performs the handshake with the keys of `vector` and fill the transcript fields
+    fn handshake_transcript(vector: &HandshakeVector) -> HandshakeVector {
+        let authority_secret =
+            ed25519_dalek::SecretKey::from_bytes(&from_hex(&vector.authority_secret_key)).unwrap();
+        let authority_public = ed25519_dalek::PublicKey::from(&authority_secret);
+        let authority = Authority::new(ed25519_dalek::Keypair {
+            secret: authority_secret,
+            public: authority_public,
+        });
+        let static_keypair =
+            keypair_from_private(&from_hex(&vector.responder_static_secret_key)).unwrap();
+
+        // Valid forever so that the vector do not expire
+        let header = SignedPartHeader::from_bytes(&[0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        let signed_part =
+            auth::SignedPart::new(header, static_keypair.public.clone(), authority_public);
+        let signature = signed_part.sign_with(&authority.kp).unwrap();
+        let signature_noise_message = auth::Certificate::new(signed_part, signature)
+            .build_noise_message()
+            .serialize_to_bytes_mut()
+            .unwrap()
+            .freeze();
+
+        let mut initiator = Initiator::with_ephemeral_key(
+            authority_public,
+            &from_hex(&vector.initiator_ephemeral_secret_key),
+        )
+        .unwrap();
+        let mut responder = Responder::with_ephemeral_key(
+            &static_keypair,
+            signature_noise_message.clone(),
+            &from_hex(&vector.responder_ephemeral_secret_key),
+        )
+        .unwrap();
+        let message_1 = match initiator.step(None).unwrap() {
+            handshake::StepResult::ExpectReply(msg) => msg,
+            _ => panic!(),
+        };
+        let message_2 = match responder.step(Some(message_1.clone())).unwrap() {
+            handshake::StepResult::NoMoreReply(msg) => msg,
+            _ => panic!(),
+        };
+        initiator.step(Some(message_2.clone())).unwrap();
+
+        let mut initiator = initiator.into_transport_mode().unwrap();
+        let plaintext = from_hex(&vector.plaintext);
+        let mut ciphertext = vec![0; TransportMode::size_hint_encrypt(plaintext.len())];
+        initiator.write(&plaintext, &mut ciphertext).unwrap();
+
+        HandshakeVector {
+            authority_secret_key: vector.authority_secret_key.clone(),
+            responder_static_secret_key: vector.responder_static_secret_key.clone(),
+            initiator_ephemeral_secret_key: vector.initiator_ephemeral_secret_key.clone(),
+            responder_ephemeral_secret_key: vector.responder_ephemeral_secret_key.clone(),
+            signature_noise_message: to_hex(&signature_noise_message),
+            message_1: to_hex(&message_1),
+            message_2: to_hex(&message_2),
+            plaintext: vector.plaintext.clone(),
+            ciphertext: to_hex(&ciphertext),
+        }
+    }
+
+    #[test]
+    fn test_handshake_vectors() {
+        let vectors: Vec<HandshakeVector> =
+            serde_json::from_str(include_str!("../test-vectors/handshake.json")).unwrap();
+        assert!(!vectors.is_empty());
+        for vector in vectors {
+            assert_eq!(handshake_transcript(&vector), vector);
+        }
+    }
+
+    #[test]
+    fn test_keypair_from_rng_is_reproducible() {
+        use rand::SeedableRng;
+        let mut rng_1 = rand::rngs::StdRng::seed_from_u64(42);
+        let mut rng_2 = rand::rngs::StdRng::seed_from_u64(42);
+        let keypair = generate_keypair_from_rng(&mut rng_1).unwrap();
+        assert_eq!(keypair, generate_keypair_from_rng(&mut rng_2).unwrap());
+        assert_eq!(keypair, keypair_from_private(&keypair.private).unwrap());
+    }
+
     /// Verifies that initiator and responder can successfully send/receive message after
     /// handshake;
     #[test]
@@ -516,4 +1539,47 @@ pub(crate) mod test {
 
         assert_eq!(&message[..], &decrypted_msg[..], "Messages don't match");
     }
+
+    #[test]
+    fn transport_mode_count_the_frames_of_the_session() {
+        let (mut initiator, mut responder) = perform_handshake();
+        let message = b"test message";
+        let mut encrypted = vec![0; TransportMode::size_hint_encrypt(message.len())];
+        let mut decrypted = vec![0; message.len()];
+
+        for _ in 0..3 {
+            initiator.write(&message[..], &mut encrypted).unwrap();
+            responder.read(&encrypted, &mut decrypted).unwrap();
+        }
+        assert!(responder.read(&encrypted, &mut decrypted).is_err());
+
+        let sent = initiator.stats();
+        assert_eq!(sent.frames_sent, 3);
+        assert_eq!(sent.bytes_sent, 3 * encrypted.len() as u64);
+        assert_eq!(sent.frames_received, 0);
+        assert!(sent.last_sent.is_some() && sent.last_received.is_none());
+        let received = responder.stats();
+        assert_eq!(received.frames_received, 3);
+        assert_eq!(received.bytes_received, 3 * encrypted.len() as u64);
+        assert_eq!(received.decrypt_failures, 1);
+        let now = received.last_received.unwrap();
+        assert_eq!(received.idle(now), Duration::from_secs(0));
+        assert!(received.uptime(now) >= received.idle(now));
+    }
+
+    #[test]
+    fn decrypt_failure_is_fatal_for_the_session() {
+        let (mut initiator, mut responder) = perform_handshake();
+        let message = b"test message";
+        let mut encrypted = vec![0; TransportMode::size_hint_encrypt(message.len())];
+        let mut decrypted = vec![0; message.len()];
+
+        initiator.write(&message[..], &mut encrypted).unwrap();
+        let mut tampered = encrypted.clone();
+        tampered[0] ^= 1;
+        assert!(responder.read(&tampered, &mut decrypted).is_err());
+        // The untampered message is rejected as well
+        assert!(responder.read(&encrypted, &mut decrypted).is_err());
+        assert_eq!(responder.stats().frames_received, 0);
+    }
 }
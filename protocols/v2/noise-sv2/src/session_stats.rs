@@ -0,0 +1,69 @@
+//! Statistics of a noise session.
+//!
+//! `TransportMode` count the frames that it encrypt and decrypt, so that a connection can be
+//! monitored (throughput, idle connections, peers that send garbage) without wrapping every call
+//! to `TransportMode::read` and `TransportMode::write`. The bytes are the bytes of the encrypted
+//! frames, tag included.
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionStats {
+    /// When the transport mode has been built (eg the end of the handshake)
+    pub established_at: Instant,
+    pub frames_sent: u64,
+    pub bytes_sent: u64,
+    pub frames_received: u64,
+    pub bytes_received: u64,
+    /// Frames that can not be decrypted, they are not counted in `frames_received`
+    pub decrypt_failures: u64,
+    pub last_sent: Option<Instant>,
+    pub last_received: Option<Instant>,
+}
+
+impl SessionStats {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            established_at: now,
+            frames_sent: 0,
+            bytes_sent: 0,
+            frames_received: 0,
+            bytes_received: 0,
+            decrypt_failures: 0,
+            last_sent: None,
+            last_received: None,
+        }
+    }
+
+    pub(crate) fn on_sent(&mut self, len: usize, now: Instant) {
+        self.frames_sent += 1;
+        self.bytes_sent += len as u64;
+        self.last_sent = Some(now);
+    }
+
+    pub(crate) fn on_received(&mut self, len: usize, now: Instant) {
+        self.frames_received += 1;
+        self.bytes_received += len as u64;
+        self.last_received = Some(now);
+    }
+
+    pub(crate) fn on_decrypt_failure(&mut self) {
+        self.decrypt_failures += 1;
+    }
+
+    /// How long the session has been up at `now`
+    pub fn uptime(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.established_at)
+    }
+
+    /// Time since the last frame sent or received at `now`, since the start of the session if no
+    /// frame has been exchanged
+    pub fn idle(&self, now: Instant) -> Duration {
+        let last_activity = [self.last_sent, self.last_received]
+            .iter()
+            .flatten()
+            .max()
+            .copied()
+            .unwrap_or(self.established_at);
+        now.saturating_duration_since(last_activity)
+    }
+}
@@ -0,0 +1,231 @@
+//! Parsing and handler dispatch benchmarks, run with `cargo bench -p roles_logic_sv2`
+use binary_sv2::{Seq0255, U256};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use framing_sv2::{
+    framing2::{Frame, Sv2Frame},
+    header::Header,
+};
+use roles_logic_sv2::{
+    common_properties::{CommonDownstreamData, IsDownstream, IsMiningDownstream},
+    errors::Error,
+    handlers::mining::{ParseDownstreamMiningMessages, SendTo, SupportedChannelTypes},
+    mining_sv2::{
+        NewExtendedMiningJob, NewMiningJob, OpenExtendedMiningChannel, OpenStandardMiningChannel,
+        SetCustomMiningJob, SetNewPrevHash, SubmitSharesExtended, SubmitSharesStandard,
+        UpdateChannel,
+    },
+    parsers::{fast_shares, Mining, PoolMessages},
+    routing_logic::{MiningRoutingLogic, NoRouting},
+    selectors::NullDownstreamMiningSelector,
+    share_accounting::ShareAccounting,
+    utils::Mutex,
+};
+use std::{convert::TryInto, sync::Arc};
+
+/// Header and payload of the frame of `message`
+fn encode(message: Mining<'static>) -> (Header, Vec<u8>) {
+    let frame: Sv2Frame<PoolMessages<'static>, Vec<u8>> =
+        PoolMessages::Mining(message).try_into().unwrap();
+    let mut bytes = vec![0; frame.encoded_length()];
+    frame.serialize(&mut bytes).unwrap();
+    let payload = bytes.split_off(const_sv2::SV2_FRAME_HEADER_SIZE);
+    (Header::from_bytes(&bytes).unwrap(), payload)
+}
+
+fn u256(byte: u8) -> U256<'static> {
+    vec![byte; 32].try_into().unwrap()
+}
+
+fn submit_shares_standard() -> Mining<'static> {
+    Mining::SubmitSharesStandard(SubmitSharesStandard {
+        channel_id: 1,
+        sequence_number: 7,
+        job_id: 3,
+        nonce: 0xdead_beef,
+        ntime: 1_650_000_000,
+        version: 0x2000_0000,
+    })
+}
+
+fn messages() -> Vec<(&'static str, Mining<'static>)> {
+    vec![
+        ("submit_shares_standard", submit_shares_standard()),
+        (
+            "new_mining_job",
+            Mining::NewMiningJob(NewMiningJob {
+                channel_id: 1,
+                job_id: 3,
+                future_job: false,
+                version: 0x2000_0000,
+                merkle_root: vec![0; 32].try_into().unwrap(),
+            }),
+        ),
+        (
+            "set_new_prev_hash",
+            Mining::SetNewPrevHash(SetNewPrevHash {
+                channel_id: 1,
+                job_id: 3,
+                prev_hash: u256(0xaa),
+                min_ntime: 1_650_000_000,
+                nbits: 0x1703_4219,
+            }),
+        ),
+        (
+            "new_extended_mining_job",
+            Mining::NewExtendedMiningJob(NewExtendedMiningJob {
+                channel_id: 1,
+                job_id: 3,
+                future_job: false,
+                version: 0x2000_0000,
+                version_rolling_allowed: true,
+                // A block with about 4000 transactions
+                merkle_path: Seq0255::new((0..12).map(u256).collect()).unwrap(),
+                coinbase_tx_prefix: vec![1; 64].try_into().unwrap(),
+                coinbase_tx_suffix: vec![2; 128].try_into().unwrap(),
+            }),
+        ),
+    ]
+}
+
+fn bench_parsing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, message) in messages() {
+        let (header, payload) = encode(message);
+        group.bench_function(name, |b| {
+            b.iter_batched_ref(
+                || payload.clone(),
+                |payload| {
+                    let message: Mining = (header.msg_type(), payload.as_mut_slice())
+                        .try_into()
+                        .unwrap();
+                    black_box(message);
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// General parser against `fast_shares::parse_share` for the shares
+fn bench_share_parsing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_share");
+    let (header, payload) = encode(submit_shares_standard());
+    group.bench_function("general", |b| {
+        b.iter_batched_ref(
+            || payload.clone(),
+            |payload| {
+                let message: Mining = (header.msg_type(), payload.as_mut_slice())
+                    .try_into()
+                    .unwrap();
+                black_box(message);
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("fast", |b| {
+        b.iter(|| {
+            let share = fast_shares::parse_share(header.msg_type(), black_box(&payload)).unwrap();
+            black_box(share);
+        })
+    });
+    group.finish();
+}
+
+/// Upstream side of a connection that accept every standard share, as a pool would do
+#[derive(Debug)]
+struct Downstream {
+    share_accounting: ShareAccounting,
+}
+
+impl IsDownstream for Downstream {
+    fn get_downstream_mining_data(&self) -> CommonDownstreamData {
+        CommonDownstreamData {
+            header_only: false,
+            work_selection: false,
+            version_rolling: false,
+            flags: 0,
+            device: Default::default(),
+            remote_address: None,
+        }
+    }
+}
+
+impl IsMiningDownstream for Downstream {}
+
+impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting> for Downstream {
+    fn get_channel_type(&self) -> SupportedChannelTypes {
+        SupportedChannelTypes::Group
+    }
+
+    fn is_work_selection_enabled(&self) -> bool {
+        false
+    }
+
+    fn handle_open_standard_mining_channel(
+        &mut self,
+        _: OpenStandardMiningChannel,
+        _: Option<Arc<Mutex<()>>>,
+    ) -> Result<SendTo<()>, Error> {
+        unreachable!()
+    }
+
+    fn handle_open_extended_mining_channel(
+        &mut self,
+        _: OpenExtendedMiningChannel,
+    ) -> Result<SendTo<()>, Error> {
+        unreachable!()
+    }
+
+    fn handle_update_channel(&mut self, _: UpdateChannel) -> Result<SendTo<()>, Error> {
+        unreachable!()
+    }
+
+    fn handle_submit_shares_standard(
+        &mut self,
+        m: SubmitSharesStandard,
+    ) -> Result<SendTo<()>, Error> {
+        // Every share is accepted, so a success is sent for each share
+        let success = self
+            .share_accounting
+            .on_accepted(m.channel_id, m.sequence_number, 1)
+            .unwrap();
+        Ok(SendTo::Respond(Mining::SubmitSharesSuccess(success)))
+    }
+
+    fn handle_submit_shares_extended(
+        &mut self,
+        _: SubmitSharesExtended,
+    ) -> Result<SendTo<()>, Error> {
+        unreachable!()
+    }
+
+    fn handle_set_custom_mining_job(&mut self, _: SetCustomMiningJob) -> Result<SendTo<()>, Error> {
+        unreachable!()
+    }
+}
+
+fn bench_dispatch(c: &mut Criterion) {
+    let downstream = Arc::new(Mutex::new(Downstream {
+        share_accounting: ShareAccounting::default(),
+    }));
+    let (header, payload) = encode(submit_shares_standard());
+    c.bench_function("handle_message_mining/submit_shares_standard", |b| {
+        b.iter_batched_ref(
+            || payload.clone(),
+            |payload| {
+                ParseDownstreamMiningMessages::handle_message_mining(
+                    downstream.clone(),
+                    header,
+                    payload,
+                    MiningRoutingLogic::None,
+                )
+                .unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_parsing, bench_share_parsing, bench_dispatch);
+criterion_main!(benches);
@@ -0,0 +1,125 @@
+//! Downstream selectors benchmarks for 100k channels, run with
+//! `cargo bench -p roles_logic_sv2 --bench selectors`
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use roles_logic_sv2::{
+    selectors::{
+        ConsistentHashDownstreamMiningSelector, DownstreamMiningSelector,
+        ProxyDownstreamMiningSelector, DEFAULT_VIRTUAL_NODES,
+    },
+    utils::Mutex,
+};
+use std::sync::Arc;
+
+const CHANNELS: u32 = 100_000;
+const DOWNSTREAMS: u32 = 1_000;
+
+fn downstreams() -> Vec<Arc<Mutex<()>>> {
+    (0..DOWNSTREAMS).map(|_| Arc::new(Mutex::new(()))).collect()
+}
+
+/// Open `CHANNELS` channels in groups of 100, every downstream open the same number of channels
+fn open_channels<Sel: DownstreamMiningSelector<()>>(
+    selector: &mut Sel,
+    downstreams: &[Arc<Mutex<()>>],
+) {
+    for channel_id in 0..CHANNELS {
+        let downstream = downstreams[(channel_id % DOWNSTREAMS) as usize].clone();
+        selector
+            .on_open_standard_channel_request(channel_id, downstream)
+            .unwrap();
+        selector
+            .on_open_standard_channel_success(channel_id, channel_id / 100, channel_id)
+            .unwrap();
+    }
+}
+
+fn proxy_selector(downstreams: &[Arc<Mutex<()>>]) -> ProxyDownstreamMiningSelector<()> {
+    let mut selector = ProxyDownstreamMiningSelector::new();
+    open_channels(&mut selector, downstreams);
+    selector
+}
+
+fn consistent_hash_selector(
+    downstreams: &[Arc<Mutex<()>>],
+) -> ConsistentHashDownstreamMiningSelector<()> {
+    let mut selector = ConsistentHashDownstreamMiningSelector::new(DEFAULT_VIRTUAL_NODES);
+    open_channels(&mut selector, downstreams);
+    selector
+}
+
+fn bench_open_channels(c: &mut Criterion) {
+    let downstreams = downstreams();
+    let mut group = c.benchmark_group("open_100k_channels");
+    group.sample_size(10);
+    group.bench_function("proxy", |b| {
+        b.iter(|| black_box(proxy_selector(&downstreams)))
+    });
+    group.bench_function("consistent_hash", |b| {
+        b.iter(|| black_box(consistent_hash_selector(&downstreams)))
+    });
+    group.finish();
+}
+
+fn bench_lookups(c: &mut Criterion) {
+    let downstreams = downstreams();
+    let proxy = proxy_selector(&downstreams);
+    let consistent_hash = consistent_hash_selector(&downstreams);
+    let mut group = c.benchmark_group("lookup_100k_channels");
+    group.bench_function("proxy", |b| {
+        b.iter(|| {
+            for channel_id in 0..CHANNELS {
+                black_box(proxy.downstream_from_channel_id(channel_id));
+            }
+        })
+    });
+    group.bench_function("consistent_hash", |b| {
+        b.iter(|| {
+            for channel_id in 0..CHANNELS {
+                black_box(consistent_hash.downstream_from_channel_id(channel_id));
+            }
+        })
+    });
+    // Channels without an owner are looked up on the ring
+    group.bench_function("consistent_hash_ring", |b| {
+        b.iter(|| {
+            for channel_id in CHANNELS..2 * CHANNELS {
+                black_box(consistent_hash.downstream_from_channel_id(channel_id));
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_disconnect(c: &mut Criterion) {
+    let downstreams = downstreams();
+    let mut group = c.benchmark_group("disconnect_downstream_100k_channels");
+    group.sample_size(10);
+    group.bench_function("proxy", |b| {
+        b.iter_batched(
+            || proxy_selector(&downstreams),
+            // The proxy selector can only remove the channels one by one
+            |mut selector| {
+                for channel_id in (0..CHANNELS).step_by(DOWNSTREAMS as usize) {
+                    black_box(selector.remove_channel(channel_id));
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("consistent_hash", |b| {
+        b.iter_batched(
+            || consistent_hash_selector(&downstreams),
+            |mut selector| black_box(selector.remove_downstream(&downstreams[0])),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_open_channels,
+    bench_lookups,
+    bench_disconnect
+);
+criterion_main!(benches);
@@ -0,0 +1,366 @@
+//! Full blocks rebuilt from the solutions found by the downstreams.
+//!
+//! A solution for a job built on a template is published by the Template Provider, that only need
+//! the SubmitSolution. A custom job (SetCustomMiningJob) has been built by the downstream on its
+//! own transactions, so the pool has to rebuild and publish the block itself.
+//!
+//! `BlockAssembler` keep the transactions of the last templates, requested with
+//! RequestTransactionData, and of the custom jobs declared with `declare_custom_job`, whose
+//! transactions must match the merkle path of the job (and should have been accepted by a
+//! `crate::job_policy::JobPolicy`). When a solution arrive the block is rebuilt with the coinbase
+//! of the solution followed by the transactions of the job.
+use crate::{
+    errors::Error,
+    job_creator::is_empty_block_template,
+    utils::{build_coinbase, new_header},
+};
+use bitcoin::{
+    consensus::deserialize,
+    hashes::{sha256d::Hash as DHash, Hash, HashEngine},
+    Block, Transaction,
+};
+use mining_sv2::{SetCustomMiningJob, SubmitSharesExtended};
+use std::collections::{BTreeMap, HashMap};
+use template_distribution_sv2::{RequestTransactionDataSuccess, SetNewPrevHash, SubmitSolution};
+
+/// Templates whose transactions are kept, the oldest are dropped first
+pub const MAX_TEMPLATES: usize = 16;
+
+/// Merkle path of the coinbase of a block with the transactions `txids` (coinbase excluded),
+/// ordered from the deepest like `NewTemplate::merkle_path`
+pub fn merkle_path(txids: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    // The first node is the coinbase, its value never end in the path
+    let mut level: Vec<[u8; 32]> = std::iter::once([0; 32]).chain(txids.to_vec()).collect();
+    let mut path = Vec::new();
+    while level.len() > 1 {
+        path.push(level[1]);
+        level = level
+            .chunks(2)
+            .map(|pair| {
+                let mut engine = DHash::engine();
+                engine.input(&pair[0]);
+                engine.input(pair.get(1).unwrap_or(&pair[0]));
+                DHash::from_engine(engine).into_inner()
+            })
+            .collect();
+    }
+    path
+}
+
+fn txid(transaction: &Transaction) -> [u8; 32] {
+    transaction.txid().as_hash().into_inner()
+}
+
+/// Block made of `coinbase` followed by `transactions`
+fn assemble(
+    version: u32,
+    prev_hash: &[u8],
+    time: u32,
+    nbits: u32,
+    nonce: u32,
+    coinbase: Transaction,
+    transactions: &[Transaction],
+) -> Result<Block, Error> {
+    let txids: Vec<[u8; 32]> = transactions.iter().map(txid).collect();
+    let mut merkle_root = txid(&coinbase);
+    for hash in merkle_path(&txids) {
+        let mut engine = DHash::engine();
+        engine.input(&merkle_root);
+        engine.input(&hash);
+        merkle_root = DHash::from_engine(engine).into_inner();
+    }
+    let header = new_header(version as i32, prev_hash, &merkle_root, time, nbits, nonce)?;
+    let mut txdata = vec![coinbase];
+    txdata.extend_from_slice(transactions);
+    Ok(Block { header, txdata })
+}
+
+#[derive(Debug, Clone)]
+struct CustomJob {
+    prev_hash: Vec<u8>,
+    nbits: u32,
+    coinbase_tx_prefix: Vec<u8>,
+    coinbase_tx_suffix: Vec<u8>,
+    transactions: Vec<Transaction>,
+}
+
+#[derive(Debug, Default)]
+pub struct BlockAssembler {
+    /// template_id -> transactions (coinbase excluded)
+    templates: BTreeMap<u64, Vec<Transaction>>,
+    /// Prev hash and nbits of the last SetNewPrevHash
+    prev_hash: Option<(Vec<u8>, u32)>,
+    /// (channel_id, job_id) -> custom job
+    custom_jobs: HashMap<(u32, u32), CustomJob>,
+}
+
+impl BlockAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the transactions of a template, `transaction_list` is the list of the serialized
+    /// transactions
+    pub fn on_transaction_data(&mut self, m: &RequestTransactionDataSuccess) -> Result<(), Error> {
+        let transactions = m
+            .transaction_list
+            .inner_as_ref()
+            .into_iter()
+            .map(deserialize)
+            .collect::<Result<Vec<Transaction>, _>>()
+            .map_err(|_| Error::InvalidTransactionData(m.template_id))?;
+        self.templates.insert(m.template_id, transactions);
+        while self.templates.len() > MAX_TEMPLATES {
+            let oldest = *self.templates.keys().next().unwrap();
+            self.templates.remove(&oldest);
+        }
+        Ok(())
+    }
+
+    /// The templates not built on the new prev hash are dropped
+    pub fn on_new_prev_hash(&mut self, m: &SetNewPrevHash) {
+        let template_id = m.template_id;
+        self.templates.retain(|id, _| *id >= template_id);
+        self.prev_hash = Some((m.prev_hash.to_vec(), m.n_bits));
+        // The custom jobs on the old prev hash can not be solved anymore
+        let prev_hash = m.prev_hash.to_vec();
+        self.custom_jobs.retain(|_, job| job.prev_hash == prev_hash);
+    }
+
+    /// Block of a solution found on a template of the Template Provider, or on an empty block
+    /// template (see `crate::job_creator::empty_block_template`) that has the coinbase only
+    pub fn assemble_solution(&self, solution: &SubmitSolution) -> Result<Block, Error> {
+        let transactions = if is_empty_block_template(solution.template_id) {
+            &[]
+        } else {
+            self.templates
+                .get(&solution.template_id)
+                .ok_or(Error::NoTransactionData(solution.template_id))?
+                .as_slice()
+        };
+        let (prev_hash, nbits) = self
+            .prev_hash
+            .as_ref()
+            .ok_or(Error::NoTransactionData(solution.template_id))?;
+        let coinbase = deserialize(solution.coinbase_tx.inner_as_ref())
+            .map_err(|_| Error::InvalidTransactionData(solution.template_id))?;
+        assemble(
+            solution.version,
+            prev_hash,
+            solution.header_timestamp,
+            *nbits,
+            solution.header_nonce,
+            coinbase,
+            transactions,
+        )
+    }
+
+    /// Transactions of the last template, the ones that the Template Provider would mine. Used by
+    /// the `crate::job_policy::JobPolicy` of the custom jobs.
+    pub fn template_transactions(&self) -> Option<&[Transaction]> {
+        self.templates.values().next_back().map(|t| &t[..])
+    }
+
+    /// Keep a custom job accepted as `job_id`, `coinbase_tx_prefix` and `coinbase_tx_suffix` are
+    /// the ones of the SetCustomMiningJobSuccess. Fail if `transactions` do not match the merkle
+    /// path of the job.
+    pub fn declare_custom_job(
+        &mut self,
+        job_id: u32,
+        job: &SetCustomMiningJob,
+        coinbase_tx_prefix: &[u8],
+        coinbase_tx_suffix: &[u8],
+        transactions: Vec<Transaction>,
+    ) -> Result<(), Error> {
+        let txids: Vec<[u8; 32]> = transactions.iter().map(txid).collect();
+        let path = merkle_path(&txids);
+        if job.merkle_path.inner_as_ref() != path.iter().map(|h| &h[..]).collect::<Vec<_>>() {
+            return Err(Error::TransactionsMismatch(job_id));
+        }
+        let custom_job = CustomJob {
+            prev_hash: job.prev_hash.to_vec(),
+            nbits: job.nbits,
+            coinbase_tx_prefix: coinbase_tx_prefix.to_vec(),
+            coinbase_tx_suffix: coinbase_tx_suffix.to_vec(),
+            transactions,
+        };
+        self.custom_jobs
+            .insert((job.channel_id, job_id), custom_job);
+        Ok(())
+    }
+
+    /// Block of a share that solve the custom job `share.job_id`, `extranonce_prefix` is the
+    /// prefix of the channel
+    pub fn assemble_custom_job(
+        &self,
+        share: &SubmitSharesExtended,
+        extranonce_prefix: &[u8],
+    ) -> Result<Block, Error> {
+        let job = self
+            .custom_jobs
+            .get(&(share.channel_id, share.job_id))
+            .ok_or(Error::UnknownCustomJob(share.job_id))?;
+        let mut extranonce = extranonce_prefix.to_vec();
+        extranonce.extend_from_slice(share.extranonce.inner_as_ref());
+        let coinbase = build_coinbase(
+            &job.coinbase_tx_prefix,
+            &extranonce,
+            &job.coinbase_tx_suffix,
+        );
+        let coinbase =
+            deserialize(&coinbase).map_err(|_| Error::TransactionsMismatch(share.job_id))?;
+        assemble(
+            share.version,
+            &job.prev_hash,
+            share.ntime,
+            job.nbits,
+            share.nonce,
+            coinbase,
+            &job.transactions,
+        )
+    }
+
+    /// Forget the custom jobs of a closed channel
+    pub fn remove_channel(&mut self, channel_id: u32) {
+        self.custom_jobs
+            .retain(|(channel, _), _| *channel != channel_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{
+        blockdata::transaction::{OutPoint, TxIn, TxOut},
+        consensus::serialize,
+        Script,
+    };
+    use std::convert::TryInto;
+
+    fn transaction(lock_time: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::from(vec![1, 2, 3, 4]),
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 50,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn assembles_template_solutions() {
+        let transactions: Vec<Transaction> = (1..4).map(transaction).collect();
+        let transaction_list: Vec<_> = transactions
+            .iter()
+            .map(|t| serialize(t).try_into().unwrap())
+            .collect();
+        let mut assembler = BlockAssembler::new();
+        assembler
+            .on_transaction_data(&RequestTransactionDataSuccess {
+                template_id: 5,
+                excess_data: vec![].try_into().unwrap(),
+                transaction_list: transaction_list.into(),
+            })
+            .unwrap();
+        let solution = SubmitSolution {
+            template_id: 5,
+            version: 0x2000_0000,
+            header_timestamp: 1_600_000_000,
+            header_nonce: 7,
+            coinbase_tx: serialize(&transaction(0)).try_into().unwrap(),
+        };
+        assert!(matches!(
+            assembler.assemble_solution(&solution),
+            Err(Error::NoTransactionData(5))
+        ));
+
+        assembler.on_new_prev_hash(&SetNewPrevHash {
+            template_id: 5,
+            prev_hash: [3; 32].into(),
+            header_timestamp: 1_600_000_000,
+            n_bits: 0x1d00ffff,
+            target: [0xff; 32].into(),
+        });
+        let block = assembler.assemble_solution(&solution).unwrap();
+        assert_eq!(block.txdata.len(), 4);
+        assert_eq!(block.txdata[1..], transactions[..]);
+        assert!(block.check_merkle_root());
+        assert_eq!(block.header.nonce, 7);
+        assert_eq!(block.header.prev_blockhash.as_hash().into_inner(), [3; 32]);
+
+        let empty = SubmitSolution {
+            template_id: crate::job_creator::empty_block_template_id(5),
+            ..solution
+        };
+        let block = assembler.assemble_solution(&empty).unwrap();
+        assert_eq!(block.txdata.len(), 1);
+        assert!(block.check_merkle_root());
+    }
+
+    #[test]
+    fn assembles_custom_jobs() {
+        let transactions: Vec<Transaction> = (1..6).map(transaction).collect();
+        let txids: Vec<[u8; 32]> = transactions.iter().map(txid).collect();
+        let path: Vec<_> = merkle_path(&txids)
+            .iter()
+            .map(|hash| hash.to_vec().try_into().unwrap())
+            .collect();
+        let job = SetCustomMiningJob {
+            channel_id: 2,
+            request_id: 1,
+            mining_job_token: vec![].try_into().unwrap(),
+            version: 0x2000_0000,
+            prev_hash: [3; 32].into(),
+            min_ntime: 0,
+            nbits: 0x1d00ffff,
+            coinbase_tx_version: 2,
+            coinbase_prefix: 0,
+            coinbase_tx_input_n_sequence: u32::MAX,
+            coinbase_tx_value_remaining: 50,
+            coinbase_tx_outputs: vec![].into(),
+            coinbase_tx_locktime: 0,
+            merkle_path: path.into(),
+            extranonce_size: 4,
+            future_job: false,
+        };
+        // The script of the coinbase is the extranonce (prefix + share extranonce)
+        let coinbase = serialize(&transaction(0));
+        let (prefix, suffix) = (&coinbase[..42], &coinbase[46..]);
+        let mut assembler = BlockAssembler::new();
+        assert!(matches!(
+            assembler.declare_custom_job(9, &job, prefix, suffix, transactions[1..].to_vec()),
+            Err(Error::TransactionsMismatch(9))
+        ));
+        assembler
+            .declare_custom_job(9, &job, prefix, suffix, transactions.clone())
+            .unwrap();
+
+        let share = SubmitSharesExtended {
+            channel_id: 2,
+            sequence_number: 0,
+            job_id: 9,
+            nonce: 11,
+            ntime: 1_600_000_000,
+            version: 0x2000_4000,
+            extranonce: vec![3, 4].try_into().unwrap(),
+        };
+        let block = assembler.assemble_custom_job(&share, &[1, 2]).unwrap();
+        assert_eq!(block.txdata[0], transaction(0));
+        assert_eq!(block.txdata.len(), 6);
+        assert!(block.check_merkle_root());
+        assert_eq!(block.header.version, 0x2000_4000);
+
+        assembler.remove_channel(2);
+        assert!(matches!(
+            assembler.assemble_custom_job(&share, &[1, 2]),
+            Err(Error::UnknownCustomJob(9))
+        ));
+    }
+}
@@ -0,0 +1,817 @@
+//! Standard channels aggregated in one upstream extended channel.
+//!
+//! A proxy opens one extended channel with the upstream and serves many standard channels to its
+//! downstreams, so that the upstream sees a single channel and a single job stream. Every standard
+//! channel gets a slice of the extranonce space of the extended channel: the extranonce of the
+//! standard channel is the extranonce prefix of the extended channel followed by
+//! `extranonce_size` bytes that identify the standard channel.
+//!
+//! Standard channels do not roll the extranonce, so:
+//! * every `NewExtendedMiningJob` becomes a `NewMiningJob` for each standard channel, with the
+//!   merkle root computed from the channel extranonce
+//! * every `SubmitSharesStandard` becomes a `SubmitSharesExtended` with the channel extranonce and
+//!   the extended job id, the upstream response is translated back to the downstream channel
+use crate::{
+    error_codes::{share_error, MiningErrorCode},
+    errors::Error,
+    job_dispatcher::{JobHistory, StaleJob, StaleJobs},
+    parsers::Mining,
+    utils::{
+        is_valid_rolled_version, merkle_root_from_path, version_rolling_mask, ChannelIdFactory, Id,
+        Mutex,
+    },
+};
+use mining_sv2::{
+    NewExtendedMiningJob, NewMiningJob, OpenExtendedMiningChannelSuccess,
+    OpenStandardMiningChannelSuccess, SetExtranoncePrefix, SetNewPrevHash, SetTarget,
+    SubmitSharesError, SubmitSharesExtended, SubmitSharesStandard, SubmitSharesSuccess,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+struct StandardJob {
+    extended_job_id: u32,
+    version: u32,
+    // Bits of version that the downstream is allowed to roll
+    version_rolling_mask: u32,
+}
+
+/// Messages for a new standard channel or for the standard channels on a new extended job/prev
+/// hash
+#[derive(Debug)]
+pub enum AggregatedMessage {
+    NewMiningJob(NewMiningJob<'static>),
+    SetNewPrevHash(SetNewPrevHash<'static>),
+    /// The standard channel is served by a new extended channel, see `ChannelAggregator::resume`
+    SetExtranoncePrefix(SetExtranoncePrefix<'static>),
+    SetTarget(SetTarget<'static>),
+}
+
+impl AggregatedMessage {
+    /// Standard channel of the message
+    pub fn channel_id(&self) -> u32 {
+        match self {
+            Self::NewMiningJob(m) => m.channel_id,
+            Self::SetNewPrevHash(m) => m.channel_id,
+            Self::SetExtranoncePrefix(m) => m.channel_id,
+            Self::SetTarget(m) => m.channel_id,
+        }
+    }
+}
+
+impl From<AggregatedMessage> for Mining<'static> {
+    fn from(message: AggregatedMessage) -> Self {
+        match message {
+            AggregatedMessage::NewMiningJob(m) => Mining::NewMiningJob(m),
+            AggregatedMessage::SetNewPrevHash(m) => Mining::SetNewPrevHash(m),
+            AggregatedMessage::SetExtranoncePrefix(m) => Mining::SetExtranoncePrefix(m),
+            AggregatedMessage::SetTarget(m) => Mining::SetTarget(m),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ChannelAggregator {
+    /// Upstream extended channel
+    channel_id: u32,
+    target: mining_sv2::Target,
+    extranonce_prefix: Vec<u8>,
+    extranonce_size: usize,
+    /// Last assigned extranonce slice
+    last_slice: u128,
+    channel_ids: ChannelIdFactory,
+    // standard channel_id -> standard channel extranonce (prefix + slice)
+    channels: HashMap<u32, Vec<u8>>,
+    job_ids: Arc<Mutex<Id>>,
+    // standard job_id -> job
+    jobs: JobHistory<StandardJob>,
+    // standard job_id -> job, for the jobs replaced by the last prev hash
+    stale_jobs: StaleJobs<StandardJob>,
+    // extended job_id -> channel_id -> standard job_id
+    extended_to_standard: HashMap<u32, HashMap<u32, u32>>,
+    // Extended jobs received since the last prev hash, sent to the new channels
+    last_jobs: Vec<NewExtendedMiningJob<'static>>,
+    last_prev_hash: Option<SetNewPrevHash<'static>>,
+    next_sequence_number: u32,
+    // upstream sequence_number -> (channel_id, downstream sequence_number)
+    pending_shares: BTreeMap<u32, (u32, u32)>,
+}
+
+impl ChannelAggregator {
+    /// Build the aggregator for the extended channel opened by the upstream, `job_ids` generate
+    /// the ids of the standard jobs
+    pub fn new(
+        success: &OpenExtendedMiningChannelSuccess,
+        job_ids: Arc<Mutex<Id>>,
+    ) -> Result<Self, Error> {
+        let extranonce_prefix = success.extranonce_prefix.to_vec();
+        let extranonce_size = success.extranonce_size as usize;
+        if extranonce_size == 0 || extranonce_prefix.len() + extranonce_size > 32 {
+            return Err(Error::InvalidExtranonceSize(
+                extranonce_prefix.len(),
+                extranonce_size,
+            ));
+        }
+        // The standard channels are in the group of the extended channel, their ids must differ
+        let mut channel_ids = ChannelIdFactory::new();
+        channel_ids.reserve(success.channel_id);
+        Ok(Self {
+            channel_id: success.channel_id,
+            target: success.target.clone().into(),
+            extranonce_prefix,
+            extranonce_size,
+            last_slice: 0,
+            channel_ids,
+            channels: HashMap::new(),
+            job_ids,
+            jobs: JobHistory::default(),
+            stale_jobs: StaleJobs::default(),
+            extended_to_standard: HashMap::new(),
+            last_jobs: Vec::new(),
+            last_prev_hash: None,
+            next_sequence_number: 0,
+            pending_shares: BTreeMap::new(),
+        })
+    }
+
+    /// Id of the upstream extended channel
+    /// Accept the shares for the jobs replaced by a prev hash for `grace_period` after the prev
+    /// hash, see `StaleJobs`
+    pub fn set_stale_grace_period(&mut self, grace_period: Duration) {
+        self.stale_jobs.set_grace_period(grace_period);
+    }
+
+    /// Keep only the last `capacity` jobs, see `JobHistory`
+    pub fn set_job_history_capacity(&mut self, capacity: usize) {
+        for (_, job) in self.jobs.set_capacity(capacity) {
+            self.forget_extended_job(job.extended_job_id);
+        }
+    }
+
+    /// Number of jobs evicted because the job history was full
+    pub fn evicted_jobs(&self) -> u64 {
+        self.jobs.evicted()
+    }
+
+    // Drop the state of an evicted job. The job of the last prev hash is kept in `last_jobs` as
+    // the new channels need it to start mining.
+    fn forget_extended_job(&mut self, extended_job_id: u32) {
+        let is_active = self
+            .last_prev_hash
+            .as_ref()
+            .map(|prev_hash| prev_hash.job_id == extended_job_id)
+            .unwrap_or(false);
+        if !is_active {
+            self.extended_to_standard.remove(&extended_job_id);
+            self.last_jobs.retain(|job| job.job_id != extended_job_id);
+        }
+    }
+
+    pub fn channel_id(&self) -> u32 {
+        self.channel_id
+    }
+
+    /// True if `channel_id` is a standard channel served by the aggregator
+    pub fn has_channel(&self, channel_id: u32) -> bool {
+        self.channels.contains_key(&channel_id)
+    }
+
+    /// Max number of standard channels that can be served at the same time
+    pub fn capacity(&self) -> u128 {
+        match self.extranonce_size {
+            size if size >= 16 => u128::MAX,
+            size => (1_u128 << (size * 8)) - 1,
+        }
+    }
+
+    /// Open a standard channel, return the success message and the jobs (and prev hash) that the
+    /// channel need to start mining
+    pub fn open_standard_channel(
+        &mut self,
+        request_id: u32,
+    ) -> Result<
+        (
+            OpenStandardMiningChannelSuccess<'static>,
+            Vec<AggregatedMessage>,
+        ),
+        Error,
+    > {
+        if self.channels.len() as u128 >= self.capacity() {
+            return Err(Error::ExtranonceSpaceExhausted);
+        }
+        let slice = self.next_slice();
+        let mut extranonce = self.extranonce_prefix.clone();
+        // Slices are big endian and fill the extranonce_size bytes of the extended channel
+        let slice = slice.to_be_bytes();
+        if self.extranonce_size > slice.len() {
+            extranonce.resize(extranonce.len() + self.extranonce_size - slice.len(), 0);
+            extranonce.extend_from_slice(&slice);
+        } else {
+            extranonce.extend_from_slice(&slice[slice.len() - self.extranonce_size..]);
+        }
+        let channel_id = self
+            .channel_ids
+            .allocate()
+            .ok_or(Error::ChannelIdsExhausted)?;
+        self.channels.insert(channel_id, extranonce.clone());
+
+        let success = OpenStandardMiningChannelSuccess {
+            request_id: request_id.into(),
+            channel_id,
+            target: self.target.clone().into(),
+            // Is fine to unwrap, the extranonce is at most 32 bytes
+            extranonce_prefix: extranonce.try_into().unwrap(),
+            group_channel_id: self.channel_id,
+        };
+
+        let mut messages = Vec::new();
+        let last_jobs = std::mem::take(&mut self.last_jobs);
+        for job in last_jobs.iter().filter(|j| j.future_job) {
+            if let Some(job) = self.new_standard_job(job, channel_id) {
+                messages.push(AggregatedMessage::NewMiningJob(job));
+            }
+        }
+        if let Some(prev_hash) = &self.last_prev_hash {
+            if let Some(job_id) = self
+                .extended_to_standard
+                .get(&prev_hash.job_id)
+                .and_then(|jobs| jobs.get(&channel_id))
+            {
+                messages.push(AggregatedMessage::SetNewPrevHash(SetNewPrevHash {
+                    channel_id,
+                    job_id: *job_id,
+                    prev_hash: prev_hash.prev_hash.clone(),
+                    min_ntime: prev_hash.min_ntime,
+                    nbits: prev_hash.nbits,
+                }));
+            }
+        }
+        for job in last_jobs.iter().filter(|j| !j.future_job) {
+            if let Some(job) = self.new_standard_job(job, channel_id) {
+                messages.push(AggregatedMessage::NewMiningJob(job));
+            }
+        }
+        self.last_jobs = last_jobs;
+        Ok((success, messages))
+    }
+
+    /// Serve the standard channels from the extended channel opened on a new connection with the
+    /// upstream, so that the downstreams keep their channels when the upstream reconnects. The
+    /// standard channels keep their id and their extranonce slice, the jobs of the old extended
+    /// channel are dropped and the downstreams get the jobs of the new one as they arrive.
+    ///
+    /// Return a SetExtranoncePrefix for every standard channel if the extranonce prefix changed
+    /// and a SetTarget if the new target is harder. If the new extended channel has a different
+    /// extranonce size the slices can not be kept, an error is returned and the aggregator is not
+    /// changed.
+    pub fn resume(
+        &mut self,
+        success: &OpenExtendedMiningChannelSuccess,
+    ) -> Result<Vec<AggregatedMessage>, Error> {
+        let extranonce_prefix = success.extranonce_prefix.to_vec();
+        let extranonce_size = success.extranonce_size as usize;
+        if extranonce_size != self.extranonce_size || extranonce_prefix.len() + extranonce_size > 32
+        {
+            return Err(Error::InvalidExtranonceSize(
+                extranonce_prefix.len(),
+                extranonce_size,
+            ));
+        }
+        let mut channel_ids: Vec<u32> = self.channels.keys().copied().collect();
+        channel_ids.sort_unstable();
+        let mut messages = Vec::new();
+        if extranonce_prefix != self.extranonce_prefix {
+            for channel_id in &channel_ids {
+                // Is fine to unwrap, channel_ids are the keys of channels
+                let extranonce = self.channels.get_mut(channel_id).unwrap();
+                let slice = extranonce.split_off(self.extranonce_prefix.len());
+                *extranonce = extranonce_prefix.clone();
+                extranonce.extend_from_slice(&slice);
+                messages.push(AggregatedMessage::SetExtranoncePrefix(
+                    SetExtranoncePrefix {
+                        channel_id: *channel_id,
+                        // Is fine to unwrap, the extranonce is at most 32 bytes
+                        extranonce_prefix: extranonce.clone().try_into().unwrap(),
+                    },
+                ));
+            }
+        }
+        let target: mining_sv2::Target = success.target.clone().into();
+        if target < self.target {
+            for channel_id in &channel_ids {
+                messages.push(AggregatedMessage::SetTarget(SetTarget {
+                    channel_id: *channel_id,
+                    maximum_target: target.clone().into(),
+                }));
+            }
+        }
+        // The upstream choose the new id, it can not be checked against the standard channels
+        self.channel_ids.free(self.channel_id);
+        self.channel_ids.reserve(success.channel_id);
+        self.channel_id = success.channel_id;
+        self.target = target;
+        self.extranonce_prefix = extranonce_prefix;
+        self.jobs.clear();
+        self.stale_jobs = StaleJobs::new(self.stale_jobs.grace_period());
+        self.extended_to_standard.clear();
+        self.last_jobs.clear();
+        self.last_prev_hash = None;
+        // The shares sent on the old connection will never be answered
+        self.pending_shares.clear();
+        Ok(messages)
+    }
+
+    /// Forget a closed standard channel
+    pub fn close_standard_channel(&mut self, channel_id: u32) {
+        if self.channels.remove(&channel_id).is_some() {
+            self.channel_ids.free(channel_id);
+        }
+        for jobs in self.extended_to_standard.values_mut() {
+            jobs.remove(&channel_id);
+        }
+    }
+
+    fn next_slice(&mut self) -> u128 {
+        // Slice 0 is never used so that a standard channel never get the extranonce of the
+        // extended channel itself
+        loop {
+            self.last_slice = if self.last_slice >= self.capacity() {
+                1
+            } else {
+                self.last_slice + 1
+            };
+            let slice = self.last_slice;
+            let taken = self.channels.values().any(|extranonce| {
+                let bytes = &extranonce[extranonce.len() - self.extranonce_size.min(16)..];
+                bytes
+                    .iter()
+                    .fold(0_u128, |acc, byte| (acc << 8) | *byte as u128)
+                    == slice
+            });
+            if !taken {
+                return slice;
+            }
+        }
+    }
+
+    fn new_standard_job(
+        &mut self,
+        extended: &NewExtendedMiningJob,
+        channel_id: u32,
+    ) -> Option<NewMiningJob<'static>> {
+        let merkle_root = merkle_root_from_path(
+            extended.coinbase_tx_prefix.inner_as_ref(),
+            extended.coinbase_tx_suffix.inner_as_ref(),
+            self.channels.get(&channel_id)?,
+            &extended.merkle_path.inner_as_ref(),
+        )?;
+        // Every standard job of an extended job has the same id, so that the prev hash is the same
+        // message for every channel
+        let shared_id = self
+            .extended_to_standard
+            .get(&extended.job_id)
+            .and_then(|jobs| jobs.values().next().copied());
+        let job_id = match shared_id {
+            Some(job_id) => job_id,
+            None => {
+                // Is fine to unwrap on safe_lock
+                let job_id = self.job_ids.safe_lock(|ids| ids.next()).unwrap();
+                let evicted = self.jobs.insert(
+                    job_id,
+                    StandardJob {
+                        extended_job_id: extended.job_id,
+                        version: extended.version,
+                        version_rolling_mask: version_rolling_mask(
+                            extended.version_rolling_allowed,
+                        ),
+                    },
+                );
+                if let Some((_, job)) = evicted {
+                    self.forget_extended_job(job.extended_job_id);
+                }
+                job_id
+            }
+        };
+        self.extended_to_standard
+            .entry(extended.job_id)
+            .or_default()
+            .insert(channel_id, job_id);
+        Some(NewMiningJob {
+            channel_id,
+            job_id,
+            future_job: extended.future_job,
+            version: extended.version,
+            merkle_root: merkle_root.try_into().ok()?,
+        })
+    }
+
+    /// Translate a job of the extended channel into a job for every standard channel
+    pub fn on_new_extended_mining_job(
+        &mut self,
+        extended: &NewExtendedMiningJob,
+    ) -> Vec<NewMiningJob<'static>> {
+        let channel_ids: Vec<u32> = self.channels.keys().copied().collect();
+        let jobs = channel_ids
+            .into_iter()
+            .filter_map(|channel_id| self.new_standard_job(extended, channel_id))
+            .collect();
+        self.last_jobs.push(extended.as_static());
+        jobs
+    }
+
+    /// Translate a prev hash of the extended channel into a prev hash for every standard channel,
+    /// the jobs that do not build on the new prev hash are dropped
+    pub fn on_set_new_prev_hash(
+        &mut self,
+        prev_hash: &SetNewPrevHash,
+    ) -> Result<Vec<SetNewPrevHash<'static>>, Error> {
+        let standard_jobs = match self.extended_to_standard.remove(&prev_hash.job_id) {
+            Some(standard_jobs) => standard_jobs,
+            // No standard channel is open yet, the channels opened later get the job from
+            // `last_jobs`
+            None if self
+                .last_jobs
+                .iter()
+                .any(|job| job.job_id == prev_hash.job_id) =>
+            {
+                HashMap::new()
+            }
+            None => return Err(Error::PrevHashRequireNonExistentJobId(prev_hash.job_id)),
+        };
+        let replaced = self
+            .jobs
+            .remove_if(|job| job.extended_job_id != prev_hash.job_id);
+        self.stale_jobs.on_new_prev_hash(replaced, Instant::now());
+        self.extended_to_standard.clear();
+        self.extended_to_standard
+            .insert(prev_hash.job_id, standard_jobs.clone());
+        self.last_jobs.retain(|job| job.job_id == prev_hash.job_id);
+        self.last_prev_hash = Some(prev_hash.as_static());
+        Ok(standard_jobs
+            .into_iter()
+            .map(|(channel_id, job_id)| SetNewPrevHash {
+                channel_id,
+                job_id,
+                prev_hash: prev_hash.prev_hash.clone().into_static(),
+                min_ntime: prev_hash.min_ntime,
+                nbits: prev_hash.nbits,
+            })
+            .collect())
+    }
+
+    /// Translate a share of a standard channel into a share of the extended channel, if the share
+    /// is not valid return the error for the downstream
+    pub fn on_submit_shares_standard(
+        &mut self,
+        share: &SubmitSharesStandard,
+    ) -> Result<SubmitSharesExtended<'static>, SubmitSharesError<'static>> {
+        let error = |code| share_error(share, code);
+        let extranonce = self
+            .channels
+            .get(&share.channel_id)
+            .ok_or_else(|| error(MiningErrorCode::InvalidChannelId))?;
+        let job = match self.jobs.get(share.job_id) {
+            Some(job) => job,
+            None => match self.stale_jobs.get_mut(share.job_id, Instant::now()) {
+                StaleJob::InGracePeriod(job) => &*job,
+                StaleJob::Expired => return Err(error(MiningErrorCode::StaleShare)),
+                StaleJob::Unknown => return Err(error(MiningErrorCode::InvalidJobId)),
+            },
+        };
+        if !is_valid_rolled_version(job.version, share.version, job.version_rolling_mask) {
+            return Err(error(MiningErrorCode::InvalidVersion));
+        }
+        let sequence_number = self.next_sequence_number;
+        self.next_sequence_number = self.next_sequence_number.wrapping_add(1);
+        self.pending_shares
+            .insert(sequence_number, (share.channel_id, share.sequence_number));
+        Ok(SubmitSharesExtended {
+            channel_id: self.channel_id,
+            sequence_number,
+            job_id: job.extended_job_id,
+            nonce: share.nonce,
+            ntime: share.ntime,
+            version: share.version,
+            // Is fine to unwrap, the extranonce is at most 32 bytes
+            extranonce: extranonce[self.extranonce_prefix.len()..]
+                .to_vec()
+                .try_into()
+                .unwrap(),
+        })
+    }
+
+    /// Translate the upstream acknowledgement of the shares up to `last_sequence_number` into an
+    /// acknowledgement for every standard channel that submitted some of them
+    pub fn on_submit_shares_success(
+        &mut self,
+        success: &SubmitSharesSuccess,
+    ) -> Vec<SubmitSharesSuccess> {
+        let not_acknowledged = self
+            .pending_shares
+            .split_off(&success.last_sequence_number.saturating_add(1));
+        let acknowledged = std::mem::replace(&mut self.pending_shares, not_acknowledged);
+        let mut per_channel: BTreeMap<u32, SubmitSharesSuccess> = BTreeMap::new();
+        for (channel_id, sequence_number) in acknowledged.values() {
+            let response = per_channel
+                .entry(*channel_id)
+                .or_insert(SubmitSharesSuccess {
+                    channel_id: *channel_id,
+                    last_sequence_number: *sequence_number,
+                    new_submits_accepted_count: 0,
+                    new_shares_sum: 0,
+                });
+            response.last_sequence_number = *sequence_number;
+            response.new_submits_accepted_count += 1;
+            response.new_shares_sum += 1;
+        }
+        per_channel.into_values().collect()
+    }
+
+    /// Translate an upstream share rejection for the standard channel that submitted the share
+    pub fn on_submit_shares_error(
+        &mut self,
+        error: &SubmitSharesError,
+    ) -> Option<SubmitSharesError<'static>> {
+        let (channel_id, sequence_number) = self.pending_shares.remove(&error.sequence_number)?;
+        Some(SubmitSharesError {
+            channel_id,
+            sequence_number,
+            error_code: error.error_code.clone().into_static(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{
+        blockdata::{script::Script, transaction::OutPoint},
+        consensus::encode::serialize,
+        Transaction, TxIn, TxOut,
+    };
+
+    const EXTENDED_CHANNEL_ID: u32 = 10;
+
+    fn aggregator(extranonce_size: u16) -> ChannelAggregator {
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id: 1,
+            channel_id: EXTENDED_CHANNEL_ID,
+            target: [0xff; 32].to_vec().try_into().unwrap(),
+            extranonce_size,
+            extranonce_prefix: vec![7; 24].try_into().unwrap(),
+        };
+        ChannelAggregator::new(&success, Arc::new(Mutex::new(Id::new()))).unwrap()
+    }
+
+    // Coinbase with a 32 bytes script_sig that is the full extranonce
+    fn extended_job(job_id: u32, future_job: bool) -> NewExtendedMiningJob<'static> {
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::from(vec![0; 32]),
+                sequence: u32::MAX,
+                witness: Default::default(),
+            }],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let coinbase = serialize(&coinbase);
+        // version + inputs count + outpoint + script_sig len
+        let script_start = 4 + 1 + 36 + 1;
+        NewExtendedMiningJob {
+            channel_id: EXTENDED_CHANNEL_ID,
+            job_id,
+            future_job,
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: Vec::new().into(),
+            coinbase_tx_prefix: coinbase[..script_start].to_vec().try_into().unwrap(),
+            coinbase_tx_suffix: coinbase[script_start + 32..].to_vec().try_into().unwrap(),
+        }
+    }
+
+    fn prev_hash(job_id: u32) -> SetNewPrevHash<'static> {
+        SetNewPrevHash {
+            channel_id: EXTENDED_CHANNEL_ID,
+            job_id,
+            prev_hash: [1; 32].to_vec().try_into().unwrap(),
+            min_ntime: 0,
+            nbits: 0x1d00_ffff,
+        }
+    }
+
+    #[test]
+    fn slices_the_extranonce_space() {
+        let mut aggregator = aggregator(1);
+        assert_eq!(aggregator.capacity(), 255);
+        let mut extranonces = Vec::new();
+        for request_id in 0..255 {
+            let (success, _) = aggregator.open_standard_channel(request_id).unwrap();
+            assert_eq!(success.group_channel_id, EXTENDED_CHANNEL_ID);
+            let extranonce = success.extranonce_prefix.to_vec();
+            assert_eq!(&extranonce[..24], &[7; 24]);
+            assert_eq!(extranonce.len(), 25);
+            extranonces.push(extranonce);
+        }
+        extranonces.sort();
+        extranonces.dedup();
+        assert_eq!(extranonces.len(), 255);
+        assert!(matches!(
+            aggregator.open_standard_channel(0),
+            Err(Error::ExtranonceSpaceExhausted)
+        ));
+        // A closed channel release its slice
+        aggregator.close_standard_channel(3);
+        assert!(aggregator.open_standard_channel(0).is_ok());
+    }
+
+    #[test]
+    fn translates_jobs_and_shares() {
+        let mut aggregator = aggregator(8);
+        let (first, messages) = aggregator.open_standard_channel(1).unwrap();
+        assert!(messages.is_empty());
+
+        let jobs = aggregator.on_new_extended_mining_job(&extended_job(5, true));
+        assert_eq!(jobs.len(), 1);
+        let prev_hashes = aggregator.on_set_new_prev_hash(&prev_hash(5)).unwrap();
+        assert_eq!(prev_hashes[0].job_id, jobs[0].job_id);
+
+        // A channel opened later get the current job and prev hash
+        let (second, messages) = aggregator.open_standard_channel(2).unwrap();
+        assert!(matches!(
+            &messages[..],
+            [
+                AggregatedMessage::NewMiningJob(_),
+                AggregatedMessage::SetNewPrevHash(_)
+            ]
+        ));
+        let second_job_id = match &messages[0] {
+            AggregatedMessage::NewMiningJob(job) => {
+                // Different extranonce so different merkle root
+                assert_ne!(job.merkle_root, jobs[0].merkle_root);
+                job.job_id
+            }
+            _ => unreachable!(),
+        };
+        // Same extended job so same job id
+        assert_eq!(second_job_id, jobs[0].job_id);
+
+        let share = |channel_id, sequence_number, job_id| SubmitSharesStandard {
+            channel_id,
+            sequence_number,
+            job_id,
+            nonce: 1,
+            ntime: 2,
+            version: 0x2000_0000,
+        };
+        let extended = aggregator
+            .on_submit_shares_standard(&share(first.channel_id, 40, jobs[0].job_id))
+            .unwrap();
+        assert_eq!(extended.channel_id, EXTENDED_CHANNEL_ID);
+        assert_eq!(extended.job_id, 5);
+        assert_eq!(
+            extended.extranonce.to_vec(),
+            first.extranonce_prefix.to_vec()[24..].to_vec()
+        );
+        aggregator
+            .on_submit_shares_standard(&share(second.channel_id, 7, second_job_id))
+            .unwrap();
+        aggregator
+            .on_submit_shares_standard(&share(first.channel_id, 41, jobs[0].job_id))
+            .unwrap();
+        assert!(aggregator
+            .on_submit_shares_standard(&share(first.channel_id, 42, 1000))
+            .is_err());
+
+        let error = SubmitSharesError {
+            channel_id: EXTENDED_CHANNEL_ID,
+            sequence_number: 2,
+            error_code: MiningErrorCode::StaleShare.into(),
+        };
+        let error = aggregator.on_submit_shares_error(&error).unwrap();
+        assert_eq!(
+            (error.channel_id, error.sequence_number),
+            (first.channel_id, 41)
+        );
+
+        let success = SubmitSharesSuccess {
+            channel_id: EXTENDED_CHANNEL_ID,
+            last_sequence_number: 1,
+            new_submits_accepted_count: 2,
+            new_shares_sum: 2,
+        };
+        let acks = aggregator.on_submit_shares_success(&success);
+        assert_eq!(acks.len(), 2);
+        assert!(acks
+            .iter()
+            .any(|a| a.channel_id == first.channel_id && a.last_sequence_number == 40));
+        assert!(acks
+            .iter()
+            .any(|a| a.channel_id == second.channel_id && a.last_sequence_number == 7));
+    }
+
+    #[test]
+    fn prev_hash_before_the_first_channel() {
+        let mut aggregator = aggregator(8);
+        assert!(aggregator
+            .on_new_extended_mining_job(&extended_job(5, true))
+            .is_empty());
+        assert!(aggregator
+            .on_set_new_prev_hash(&prev_hash(5))
+            .unwrap()
+            .is_empty());
+        assert!(aggregator.on_set_new_prev_hash(&prev_hash(6)).is_err());
+
+        let (_, messages) = aggregator.open_standard_channel(1).unwrap();
+        match &messages[..] {
+            [AggregatedMessage::NewMiningJob(job), AggregatedMessage::SetNewPrevHash(prev_hash)] => {
+                assert_eq!(prev_hash.job_id, job.job_id)
+            }
+            _ => panic!("Expected a job and a prev hash"),
+        };
+    }
+
+    #[test]
+    fn accepts_stale_shares_in_grace_period() {
+        let mut aggregator = aggregator(8);
+        let (channel, _) = aggregator.open_standard_channel(1).unwrap();
+        let old_job = aggregator.on_new_extended_mining_job(&extended_job(5, true))[0].job_id;
+        aggregator.on_set_new_prev_hash(&prev_hash(5)).unwrap();
+        aggregator.on_new_extended_mining_job(&extended_job(6, true));
+        aggregator.on_set_new_prev_hash(&prev_hash(6)).unwrap();
+
+        let share = SubmitSharesStandard {
+            channel_id: channel.channel_id,
+            sequence_number: 1,
+            job_id: old_job,
+            nonce: 1,
+            ntime: 2,
+            version: 0x2000_0000,
+        };
+        let extended = aggregator.on_submit_shares_standard(&share).unwrap();
+        assert_eq!(extended.job_id, 5);
+
+        aggregator.set_stale_grace_period(Duration::from_secs(0));
+        aggregator.on_new_extended_mining_job(&extended_job(7, true));
+        aggregator.on_set_new_prev_hash(&prev_hash(7)).unwrap();
+        let error = aggregator.on_submit_shares_standard(&share).unwrap_err();
+        assert_eq!(error.error_code.to_vec(), b"invalid-job-id".to_vec());
+    }
+
+    #[test]
+    fn resumes_channels_on_new_extended_channel() {
+        let mut aggregator = aggregator(8);
+        let (channel, _) = aggregator.open_standard_channel(1).unwrap();
+        let old_job = aggregator.on_new_extended_mining_job(&extended_job(5, true))[0].job_id;
+        aggregator.on_set_new_prev_hash(&prev_hash(5)).unwrap();
+
+        let mut success = OpenExtendedMiningChannelSuccess {
+            request_id: 2,
+            channel_id: EXTENDED_CHANNEL_ID + 1,
+            target: [0x0f; 32].to_vec().try_into().unwrap(),
+            extranonce_size: 4,
+            extranonce_prefix: vec![9; 24].try_into().unwrap(),
+        };
+        assert!(aggregator.resume(&success).is_err());
+        assert_eq!(aggregator.channel_id(), EXTENDED_CHANNEL_ID);
+
+        success.extranonce_size = 8;
+        let messages = aggregator.resume(&success).unwrap();
+        assert_eq!(messages.len(), 2);
+        match &messages[0] {
+            AggregatedMessage::SetExtranoncePrefix(m) => {
+                let extranonce = m.extranonce_prefix.to_vec();
+                assert_eq!(&extranonce[..24], &[9; 24]);
+                // Same slice
+                assert_eq!(&extranonce[24..], &channel.extranonce_prefix.to_vec()[24..]);
+            }
+            message => panic!("unexpected {:?}", message),
+        }
+        assert!(matches!(messages[1], AggregatedMessage::SetTarget(_)));
+        assert_eq!(aggregator.channel_id(), EXTENDED_CHANNEL_ID + 1);
+        assert!(aggregator.has_channel(channel.channel_id));
+
+        // The jobs of the old extended channel are no more valid
+        let share = SubmitSharesStandard {
+            channel_id: channel.channel_id,
+            sequence_number: 1,
+            job_id: old_job,
+            nonce: 1,
+            ntime: 2,
+            version: 0x2000_0000,
+        };
+        assert!(aggregator.on_submit_shares_standard(&share).is_err());
+        let mut job = extended_job(1, false);
+        job.channel_id = EXTENDED_CHANNEL_ID + 1;
+        assert_eq!(aggregator.on_new_extended_mining_job(&job).len(), 1);
+    }
+}
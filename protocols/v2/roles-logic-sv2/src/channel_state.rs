@@ -0,0 +1,178 @@
+//! Job state of a channel.
+//!
+//! A channel can only mine a job that has a prev hash. Jobs sent before the prev hash that they
+//! build on are future jobs, a SetNewPrevHash activate one of them and discard the others:
+//! * `AwaitingFirstJob`: no job received yet
+//! * `FutureJobPending`: only future jobs, waiting for the SetNewPrevHash that activate one
+//! * `ActiveJob`: the job that is mined and the prev hash that it build on, new non future jobs
+//!   replace the active job
+//!
+//! Out of order messages are errors: a non future job before any prev hash, a prev hash that do
+//! not activate a future job and a share before the first active job (or for another job).
+//! `J` is whatever the role need to keep for each job.
+use crate::errors::Error;
+use mining_sv2::SetNewPrevHash;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub enum ChannelState<J> {
+    AwaitingFirstJob,
+    FutureJobPending {
+        future_jobs: HashMap<u32, J>,
+    },
+    ActiveJob {
+        job_id: u32,
+        job: J,
+        prev_hash: SetNewPrevHash<'static>,
+        future_jobs: HashMap<u32, J>,
+    },
+}
+
+impl<J> Default for ChannelState<J> {
+    fn default() -> Self {
+        Self::AwaitingFirstJob
+    }
+}
+
+impl<J> ChannelState<J> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a job, a non future job replace the active job and the id of the replaced job is
+    /// returned
+    pub fn on_new_job(
+        &mut self,
+        job_id: u32,
+        future_job: bool,
+        job: J,
+    ) -> Result<Option<u32>, Error> {
+        match (self, future_job) {
+            (state @ Self::AwaitingFirstJob, true) => {
+                let mut future_jobs = HashMap::new();
+                future_jobs.insert(job_id, job);
+                *state = Self::FutureJobPending { future_jobs };
+                Ok(None)
+            }
+            (Self::FutureJobPending { future_jobs }, true)
+            | (Self::ActiveJob { future_jobs, .. }, true) => {
+                future_jobs.insert(job_id, job);
+                Ok(None)
+            }
+            (
+                Self::ActiveJob {
+                    job_id: active_id,
+                    job: active_job,
+                    ..
+                },
+                false,
+            ) => {
+                let replaced = std::mem::replace(active_id, job_id);
+                *active_job = job;
+                Ok(Some(replaced))
+            }
+            (_, false) => Err(Error::JobWithoutPrevHash(job_id)),
+        }
+    }
+
+    /// Activate the future job referenced by the prev hash, the other future jobs are dropped
+    pub fn on_set_new_prev_hash(
+        &mut self,
+        prev_hash: SetNewPrevHash<'static>,
+    ) -> Result<(), Error> {
+        let job = match self {
+            Self::AwaitingFirstJob => None,
+            Self::FutureJobPending { future_jobs } | Self::ActiveJob { future_jobs, .. } => {
+                future_jobs.remove(&prev_hash.job_id)
+            }
+        };
+        let job = job.ok_or(Error::PrevHashRequireNonExistentJobId(prev_hash.job_id))?;
+        *self = Self::ActiveJob {
+            job_id: prev_hash.job_id,
+            job,
+            prev_hash,
+            future_jobs: HashMap::new(),
+        };
+        Ok(())
+    }
+
+    /// Job of a share, the share must be for the active job
+    pub fn on_share(&self, job_id: u32) -> Result<&J, Error> {
+        match self {
+            Self::ActiveJob {
+                job_id: active_id,
+                job,
+                ..
+            } if *active_id == job_id => Ok(job),
+            Self::ActiveJob { .. } => Err(Error::ShareForInactiveJob(job_id)),
+            _ => Err(Error::ShareBeforeFirstJob),
+        }
+    }
+
+    /// (job_id, job) of the active job
+    pub fn active_job(&self) -> Option<(u32, &J)> {
+        match self {
+            Self::ActiveJob { job_id, job, .. } => Some((*job_id, job)),
+            _ => None,
+        }
+    }
+
+    /// Prev hash of the active job
+    pub fn prev_hash(&self) -> Option<&SetNewPrevHash<'static>> {
+        match self {
+            Self::ActiveJob { prev_hash, .. } => Some(prev_hash),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn prev_hash(job_id: u32) -> SetNewPrevHash<'static> {
+        SetNewPrevHash {
+            channel_id: 1,
+            job_id,
+            prev_hash: [0; 32].to_vec().try_into().unwrap(),
+            min_ntime: 0,
+            nbits: 0,
+        }
+    }
+
+    #[test]
+    fn enforces_message_ordering() {
+        let mut state = ChannelState::new();
+        assert!(matches!(state.on_share(1), Err(Error::ShareBeforeFirstJob)));
+        assert!(matches!(
+            state.on_new_job(1, false, "a"),
+            Err(Error::JobWithoutPrevHash(1))
+        ));
+        assert!(matches!(
+            state.on_set_new_prev_hash(prev_hash(1)),
+            Err(Error::PrevHashRequireNonExistentJobId(1))
+        ));
+
+        state.on_new_job(1, true, "a").unwrap();
+        state.on_new_job(2, true, "b").unwrap();
+        assert!(matches!(state, ChannelState::FutureJobPending { .. }));
+        assert!(matches!(state.on_share(1), Err(Error::ShareBeforeFirstJob)));
+
+        state.on_set_new_prev_hash(prev_hash(2)).unwrap();
+        assert_eq!(state.active_job(), Some((2, &"b")));
+        assert_eq!(state.prev_hash().unwrap().job_id, 2);
+        assert_eq!(state.on_share(2).unwrap(), &"b");
+
+        // A non future job replace the active job
+        assert_eq!(state.on_new_job(3, false, "c").unwrap(), Some(2));
+        assert!(matches!(
+            state.on_share(2),
+            Err(Error::ShareForInactiveJob(2))
+        ));
+        assert_eq!(state.on_share(3).unwrap(), &"c");
+
+        // The future job "a" has been dropped by the prev hash
+        assert!(state.on_set_new_prev_hash(prev_hash(1)).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+//! Coinbase outputs added by a pool to the jobs built from templates.
+//!
+//! The value of a template (`NewTemplate::coinbase_tx_value_remaining`) is paid to the outputs
+//! described by `CoinbaseOutputs`: some fee splits, that get a fixed part of the value, and the
+//! pool output, that get what is left. OP_RETURN commitments (eg merged mining) are outputs
+//! without value. The outputs are written in config files as a comma separated list:
+//!
+//! ```text
+//! bc1qpool..., bc1qfee...:1.5%, op_return:aa21a9ed
+//! ```
+//!
+//! an address alone is the pool output, `address:N%` is a split of N percent of the value and
+//! `op_return:HEX` is a commitment. The Template Provider must leave room for the outputs in the
+//! block, the pool declare their size with the `CoinbaseOutputDataSize` message and `build` reject
+//! the outputs that do not fit in the declared `coinbase_output_max_additional_size`.
+use bitcoin::{
+    blockdata::{opcodes::all::OP_RETURN, script::Builder, transaction::TxOut},
+    consensus::serialize,
+    Address, Network, Script,
+};
+use std::{fmt, str::FromStr};
+
+/// Max bytes of data of a standard OP_RETURN output
+pub const MAX_OP_RETURN_DATA: usize = 80;
+/// A fee split is expressed in basis points of the value of the template
+pub const BASIS_POINTS: u32 = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidCoinbaseOutputs {
+    /// Entry of the config that can not be parsed
+    Syntax(String),
+    /// Address for an other network
+    WrongNetwork(String),
+    /// No output get the value left by the splits
+    MissingPoolOutput,
+    /// More than one output get the value left by the splits
+    MultiplePoolOutputs,
+    /// The splits add up to more than the whole value (basis points)
+    SplitsTooLarge(u32),
+    /// OP_RETURN with more than `MAX_OP_RETURN_DATA` bytes
+    OpReturnTooLarge(usize),
+    /// The serialized outputs (bytes) do not fit in coinbase_output_max_additional_size
+    TooLarge(u32, u32),
+}
+
+impl fmt::Display for InvalidCoinbaseOutputs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax(entry) => write!(f, "Invalid coinbase output {:?}", entry),
+            Self::WrongNetwork(address) => write!(f, "Address {} of an other network", address),
+            Self::MissingPoolOutput => write!(f, "No pool output"),
+            Self::MultiplePoolOutputs => write!(f, "More than one pool output"),
+            Self::SplitsTooLarge(basis_points) => {
+                write!(f, "Fee splits of {} basis points", basis_points)
+            }
+            Self::OpReturnTooLarge(len) => write!(f, "OP_RETURN of {} bytes", len),
+            Self::TooLarge(size, max) => write!(
+                f,
+                "Coinbase outputs of {} bytes, the template leave room for {}",
+                size, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvalidCoinbaseOutputs {}
+
+/// An entry of the coinbase outputs config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoinbaseOutputSpec {
+    /// Get the value left by the splits
+    Pool(Script),
+    /// Get `basis_points` / `BASIS_POINTS` of the value
+    Split { script: Script, basis_points: u32 },
+    /// OP_RETURN output with this data
+    OpReturn(Vec<u8>),
+}
+
+impl CoinbaseOutputSpec {
+    /// Parse an entry of the config, the addresses must be of `network`
+    pub fn parse(entry: &str, network: Network) -> Result<Self, InvalidCoinbaseOutputs> {
+        let entry = entry.trim();
+        let syntax = || InvalidCoinbaseOutputs::Syntax(entry.to_string());
+        if let Some(data) = entry.strip_prefix("op_return:") {
+            return decode_hex(data).map(Self::OpReturn).ok_or_else(syntax);
+        }
+        let (address, split) = match entry.split_once(':') {
+            Some((address, split)) => (address, Some(split)),
+            None => (entry, None),
+        };
+        let address = Address::from_str(address).map_err(|_| syntax())?;
+        // Testnet, signet and regtest share the address formats
+        if (address.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+            return Err(InvalidCoinbaseOutputs::WrongNetwork(address.to_string()));
+        }
+        let script = address.script_pubkey();
+        match split {
+            None => Ok(Self::Pool(script)),
+            Some(split) => {
+                let percent: f64 = split
+                    .strip_suffix('%')
+                    .and_then(|percent| percent.parse().ok())
+                    .filter(|percent: &f64| *percent >= 0.0 && *percent <= 100.0)
+                    .ok_or_else(syntax)?;
+                let basis_points = (percent * 100.0).round() as u32;
+                Ok(Self::Split {
+                    script,
+                    basis_points,
+                })
+            }
+        }
+    }
+}
+
+/// Build `CoinbaseOutputs` from code or from the config, see `CoinbaseOutputs::parse`
+#[derive(Debug, Clone, Default)]
+pub struct CoinbaseOutputsBuilder {
+    specs: Vec<CoinbaseOutputSpec>,
+}
+
+impl CoinbaseOutputsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The output that get the value left by the splits
+    pub fn pool(mut self, script: Script) -> Self {
+        self.specs.push(CoinbaseOutputSpec::Pool(script));
+        self
+    }
+
+    /// An output that get `basis_points` / `BASIS_POINTS` of the value
+    pub fn split(mut self, script: Script, basis_points: u32) -> Self {
+        self.specs.push(CoinbaseOutputSpec::Split {
+            script,
+            basis_points,
+        });
+        self
+    }
+
+    pub fn op_return(mut self, data: Vec<u8>) -> Self {
+        self.specs.push(CoinbaseOutputSpec::OpReturn(data));
+        self
+    }
+
+    pub fn spec(mut self, spec: CoinbaseOutputSpec) -> Self {
+        self.specs.push(spec);
+        self
+    }
+
+    /// Validate the outputs, they must fit in `coinbase_output_max_additional_size` bytes
+    pub fn build(
+        self,
+        coinbase_output_max_additional_size: u32,
+    ) -> Result<CoinbaseOutputs, InvalidCoinbaseOutputs> {
+        let mut pool = None;
+        let mut splits = Vec::new();
+        let mut commitments = Vec::new();
+        for spec in self.specs {
+            match spec {
+                CoinbaseOutputSpec::Pool(script) if pool.is_none() => pool = Some(script),
+                CoinbaseOutputSpec::Pool(_) => {
+                    return Err(InvalidCoinbaseOutputs::MultiplePoolOutputs)
+                }
+                CoinbaseOutputSpec::Split {
+                    script,
+                    basis_points,
+                } => splits.push((script, basis_points)),
+                CoinbaseOutputSpec::OpReturn(data) if data.len() > MAX_OP_RETURN_DATA => {
+                    return Err(InvalidCoinbaseOutputs::OpReturnTooLarge(data.len()))
+                }
+                CoinbaseOutputSpec::OpReturn(data) => commitments.push(
+                    Builder::new()
+                        .push_opcode(OP_RETURN)
+                        .push_slice(&data)
+                        .into_script(),
+                ),
+            }
+        }
+        let pool = pool.ok_or(InvalidCoinbaseOutputs::MissingPoolOutput)?;
+        let basis_points = splits.iter().map(|(_, basis_points)| basis_points).sum();
+        if basis_points > BASIS_POINTS {
+            return Err(InvalidCoinbaseOutputs::SplitsTooLarge(basis_points));
+        }
+        let outputs = CoinbaseOutputs {
+            pool,
+            splits,
+            commitments,
+        };
+        let size = outputs.serialized_size();
+        if size > coinbase_output_max_additional_size {
+            return Err(InvalidCoinbaseOutputs::TooLarge(
+                size,
+                coinbase_output_max_additional_size,
+            ));
+        }
+        Ok(outputs)
+    }
+}
+
+/// Validated coinbase outputs of a pool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinbaseOutputs {
+    pool: Script,
+    splits: Vec<(Script, u32)>,
+    commitments: Vec<Script>,
+}
+
+impl CoinbaseOutputs {
+    /// Parse the comma separated config, the addresses must be of `network`
+    pub fn parse(
+        config: &str,
+        network: Network,
+        coinbase_output_max_additional_size: u32,
+    ) -> Result<Self, InvalidCoinbaseOutputs> {
+        config
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .try_fold(CoinbaseOutputsBuilder::new(), |builder, entry| {
+                Ok(builder.spec(CoinbaseOutputSpec::parse(entry, network)?))
+            })?
+            .build(coinbase_output_max_additional_size)
+    }
+
+    /// The whole value is paid to `script`
+    pub fn pay_to(script: Script) -> Self {
+        Self {
+            pool: script,
+            splits: Vec::new(),
+            commitments: Vec::new(),
+        }
+    }
+
+    /// Outputs that share `value`: the pool output, the splits and then the commitments. The
+    /// splits are rounded down, the pool output get the rest.
+    pub fn outputs(&self, value: u64) -> Vec<TxOut> {
+        let splits: Vec<TxOut> = self
+            .splits
+            .iter()
+            .map(|(script, basis_points)| TxOut {
+                value: (value as u128 * *basis_points as u128 / BASIS_POINTS as u128) as u64,
+                script_pubkey: script.clone(),
+            })
+            .collect();
+        let split_value: u64 = splits.iter().map(|output| output.value).sum();
+        let mut outputs = vec![TxOut {
+            value: value - split_value,
+            script_pubkey: self.pool.clone(),
+        }];
+        outputs.extend(splits);
+        outputs.extend(self.commitments.iter().map(|script| TxOut {
+            value: 0,
+            script_pubkey: script.clone(),
+        }));
+        outputs
+    }
+
+    /// Bytes of the serialized outputs, to declare with `CoinbaseOutputDataSize`. The value of
+    /// an output is always 8 bytes so the size does not depend on the value.
+    pub fn serialized_size(&self) -> u32 {
+        self.outputs(0)
+            .iter()
+            .map(|output| serialize(output).len() as u32)
+            .sum()
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => u8::from_str_radix(std::str::from_utf8(&[*high, *low]).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POOL: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+    const FEE: &str = "2MzQwSSnBHWHqSAqtTVQ6v47XtaisrJa1Vc";
+
+    #[test]
+    fn splits_the_value_between_the_outputs() {
+        let config = format!("{}, {}:1.5%, op_return:aa21a9ed", POOL, FEE);
+        let outputs = CoinbaseOutputs::parse(&config, Network::Testnet, 200).unwrap();
+        let txs = outputs.outputs(625_000_001);
+        assert_eq!(txs.len(), 3);
+        assert_eq!(txs[1].value, 9_375_000);
+        assert_eq!(txs[0].value, 625_000_001 - 9_375_000);
+        assert_eq!(
+            txs[0].script_pubkey,
+            Address::from_str(POOL).unwrap().script_pubkey()
+        );
+        assert_eq!(txs[2].value, 0);
+        assert!(txs[2].script_pubkey.is_op_return());
+        // 31 bytes for the p2wpkh, 32 for the p2sh and 15 for the OP_RETURN
+        assert_eq!(outputs.serialized_size(), 31 + 32 + 15);
+    }
+
+    #[test]
+    fn rejects_invalid_outputs() {
+        let parse = |config: &str| CoinbaseOutputs::parse(config, Network::Testnet, 100);
+        assert_eq!(
+            parse(&format!("{}:1%", FEE)),
+            Err(InvalidCoinbaseOutputs::MissingPoolOutput)
+        );
+        assert_eq!(
+            parse(&format!("{},{}", POOL, FEE)),
+            Err(InvalidCoinbaseOutputs::MultiplePoolOutputs)
+        );
+        assert_eq!(
+            parse(&format!("{},{}:60%,{}:50%", POOL, FEE, FEE)),
+            Err(InvalidCoinbaseOutputs::SplitsTooLarge(11_000))
+        );
+        assert!(matches!(
+            parse(&format!("{},{}:ten", POOL, FEE)),
+            Err(InvalidCoinbaseOutputs::Syntax(_))
+        ));
+        assert!(matches!(
+            parse(&format!("{},op_return:zz", POOL)),
+            Err(InvalidCoinbaseOutputs::Syntax(_))
+        ));
+        assert!(matches!(
+            CoinbaseOutputs::parse(POOL, Network::Bitcoin, 100),
+            Err(InvalidCoinbaseOutputs::WrongNetwork(_))
+        ));
+        let commitment = format!("{},op_return:{}", POOL, "00".repeat(81));
+        assert_eq!(
+            parse(&commitment),
+            Err(InvalidCoinbaseOutputs::OpReturnTooLarge(81))
+        );
+        assert_eq!(
+            CoinbaseOutputs::parse(POOL, Network::Testnet, 30),
+            Err(InvalidCoinbaseOutputs::TooLarge(31, 30))
+        );
+    }
+}
@@ -2,16 +2,61 @@
 use crate::selectors::{
     DownstreamMiningSelector, DownstreamSelector, NullDownstreamMiningSelector,
 };
-use common_messages_sv2::{has_requires_std_job, Protocol, SetupConnection};
+use common_messages_sv2::{
+    has_requires_std_job, has_version_rolling, has_work_selection, Protocol, SetupConnection,
+};
 use mining_sv2::{Extranonce, Target};
-use std::{collections::HashMap, fmt::Debug as D};
+use std::{collections::HashMap, fmt::Debug as D, net::SocketAddr};
 
 /// What define a mining downstream node at the very basic
-#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct CommonDownstreamData {
     pub header_only: bool,
     pub work_selection: bool,
     pub version_rolling: bool,
+    /// Flags of the SetupConnection sent by the downstream
+    pub flags: u32,
+    /// Device declared by the downstream in SetupConnection
+    pub device: DeviceInfo,
+    /// Address of the downstream, when the node is behind a load balancer that use the PROXY
+    /// protocol is the address of the actual client
+    pub remote_address: Option<SocketAddr>,
+}
+
+impl CommonDownstreamData {
+    /// Data of a downstream that sent `m`
+    pub fn from_setup_connection(m: &SetupConnection, remote_address: Option<SocketAddr>) -> Self {
+        Self {
+            header_only: has_requires_std_job(m.flags),
+            work_selection: has_work_selection(m.flags),
+            version_rolling: has_version_rolling(m.flags),
+            flags: m.flags,
+            device: DeviceInfo::from_setup_connection(m),
+            remote_address,
+        }
+    }
+}
+
+/// Device information of SetupConnection, the strings are not validated: they are what the
+/// downstream sent and can be empty
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Default)]
+pub struct DeviceInfo {
+    pub vendor: String,
+    pub hardware_version: String,
+    pub firmware: String,
+    pub device_id: String,
+}
+
+impl DeviceInfo {
+    pub fn from_setup_connection(m: &SetupConnection) -> Self {
+        let to_string = |s: &[u8]| String::from_utf8_lossy(s).into_owned();
+        Self {
+            vendor: to_string(m.vendor.inner_as_ref()),
+            hardware_version: to_string(m.hardware_version.inner_as_ref()),
+            firmware: to_string(m.firmware.inner_as_ref()),
+            device_id: to_string(m.device_id.inner_as_ref()),
+        }
+    }
 }
 
 /// SetupConnection sugared
@@ -40,6 +85,21 @@ pub trait IsUpstream<Down: IsDownstream, Sel: DownstreamSelector<Down> + ?Sized>
     fn get_id(&self) -> u32;
     fn get_mapper(&mut self) -> Option<&mut RequestIdMapper>;
     fn get_remote_selector(&mut self) -> &mut Sel;
+    /// A quarantined upstream (eg too many rejected shares or connection errors) is never paired
+    /// with new downstreams
+    fn is_quarantined(&self) -> bool {
+        false
+    }
+    /// A draining upstream (eg closed by the operator for maintenance) is never paired with new
+    /// downstreams and is not a failover candidate
+    fn is_draining(&self) -> bool {
+        false
+    }
+    /// Called with the downstreams pairable with the upstream (see `is_pairable`), an upstream can
+    /// refuse some devices (eg by vendor or firmware, or the header only ones)
+    fn accepts_downstream(&self, _downstream: &CommonDownstreamData) -> bool {
+        true
+    }
 }
 
 /// Channel to be opened with the upstream nodes.
@@ -201,10 +261,133 @@ impl RequestIdMapper {
         new_id
     }
 
+    /// Returns the downstream id mapped to `upstream_id` without removing the mapping.
+    pub fn get(&self, upstream_id: u32) -> Option<u32> {
+        self.request_ids_map.get(&upstream_id).copied()
+    }
+
     /// Removes a upstream/downstream mapping from the `RequsetIdMapper`.
     pub fn remove(&mut self, upstream_id: u32) -> Option<u32> {
         self.request_ids_map.remove(&upstream_id)
     }
+
+    /// Returns the upstream/downstream mappings and the next upstream id, used to save the mapper
+    /// state and restore it with `RequestIdMapper::restore`.
+    pub fn state(&self) -> (Vec<(u32, u32)>, u32) {
+        let mappings = self
+            .request_ids_map
+            .iter()
+            .map(|(upstream_id, downstream_id)| (*upstream_id, *downstream_id))
+            .collect();
+        (mappings, self.next_id)
+    }
+
+    /// Builds a `RequestIdMapper` from a state returned by `RequestIdMapper::state`.
+    pub fn restore(mappings: Vec<(u32, u32)>, next_id: u32) -> Self {
+        Self {
+            request_ids_map: mappings.into_iter().collect(),
+            next_id,
+        }
+    }
+}
+
+/// Proxies that relay the jobs of more than one upstream to a downstream connection can not use
+/// the upstream job ids: two upstreams can use the same id. `JobIdMapper` give to every relayed
+/// job an id that is unique on the downstream connection and map back the job id of the shares.
+///
+/// The most significant byte of the ids is the epoch of the mapper. A downstream that reconnects
+/// get a mapper with another epoch, so the shares for the jobs of the previous session are
+/// rejected instead of being mapped to a job of the new session that has the same id.
+#[derive(Debug, Default, PartialEq)]
+pub struct JobIdMapper {
+    /// downstream job id -> (upstream id, upstream job id)
+    upstream_jobs: HashMap<u32, (u32, u32)>,
+    /// (upstream id, upstream job id) -> downstream job id
+    downstream_jobs: HashMap<(u32, u32), u32>,
+    next_id: u32,
+    epoch: u8,
+}
+
+impl JobIdMapper {
+    /// Bits of the job ids that are left to the jobs of an epoch
+    pub const EPOCH_SHIFT: u32 = 24;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mapper that give to the jobs ids of the epoch `epoch`
+    pub fn with_epoch(epoch: u8) -> Self {
+        Self {
+            epoch,
+            ..Self::default()
+        }
+    }
+
+    pub fn epoch(&self) -> u8 {
+        self.epoch
+    }
+
+    /// Epoch of a downstream job id
+    pub fn epoch_of(downstream_job_id: u32) -> u8 {
+        (downstream_job_id >> Self::EPOCH_SHIFT) as u8
+    }
+
+    /// False if the job id has been given by a mapper of another epoch, eg a share for a job of
+    /// a previous session of the downstream
+    pub fn is_current_epoch(&self, downstream_job_id: u32) -> bool {
+        Self::epoch_of(downstream_job_id) == self.epoch
+    }
+
+    fn id_in_epoch(&self, id: u32) -> u32 {
+        ((self.epoch as u32) << Self::EPOCH_SHIFT) | (id & ((1 << Self::EPOCH_SHIFT) - 1))
+    }
+
+    /// Downstream id of the job `job_id` of the upstream `upstream_id`, a new id is allocated the
+    /// first time that a job is relayed. A job relayed on more than one channel of the
+    /// downstream (or a prev hash for the job) keep the same id.
+    pub fn on_new_job(&mut self, upstream_id: u32, job_id: u32) -> u32 {
+        if let Some(id) = self.downstream_jobs.get(&(upstream_id, job_id)) {
+            return *id;
+        }
+        let mut next_id = self.next_id;
+        while self.upstream_jobs.contains_key(&self.id_in_epoch(next_id)) {
+            next_id = next_id.wrapping_add(1);
+        }
+        self.next_id = next_id.wrapping_add(1);
+        let id = self.id_in_epoch(next_id);
+        self.upstream_jobs.insert(id, (upstream_id, job_id));
+        self.downstream_jobs.insert((upstream_id, job_id), id);
+        id
+    }
+
+    /// (upstream id, upstream job id) of a downstream job id, eg of the job of a share
+    pub fn get(&self, downstream_job_id: u32) -> Option<(u32, u32)> {
+        self.upstream_jobs.get(&downstream_job_id).copied()
+    }
+
+    /// Forget a job that is not valid anymore, return (upstream id, upstream job id)
+    pub fn remove(&mut self, downstream_job_id: u32) -> Option<(u32, u32)> {
+        let upstream_job = self.upstream_jobs.remove(&downstream_job_id)?;
+        self.downstream_jobs.remove(&upstream_job);
+        Some(upstream_job)
+    }
+
+    /// Forget every job, eg when the channels are moved to another upstream. The ids of the old
+    /// jobs are not given to the next jobs, so late shares for the old jobs are not valid
+    pub fn clear(&mut self) {
+        self.upstream_jobs.clear();
+        self.downstream_jobs.clear();
+    }
+
+    /// Number of mapped jobs
+    pub fn len(&self) -> usize {
+        self.upstream_jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upstream_jobs.is_empty()
+    }
 }
 
 #[cfg(test)]
@@ -223,6 +406,17 @@ mod tests {
         assert_eq!(expect, actual);
     }
 
+    #[test]
+    fn restores_request_id_mapper() {
+        let mut mapper = RequestIdMapper::new();
+        mapper.on_open_channel(7);
+        mapper.on_open_channel(7);
+        let (mappings, next_id) = mapper.state();
+        let mut restored = RequestIdMapper::restore(mappings, next_id);
+        assert_eq!(restored, mapper);
+        assert_eq!(restored.on_open_channel(3), 2);
+    }
+
     #[test]
     fn updates_request_id_mapper_on_open_channel() {
         let id = 0;
@@ -250,6 +444,75 @@ mod tests {
         assert!(request_id_mapper.request_ids_map.is_empty());
     }
 
+    #[test]
+    fn maps_job_ids_of_many_upstreams() {
+        let mut mapper = JobIdMapper::new();
+        // Two upstreams that use the same job id
+        let first = mapper.on_new_job(1, 10);
+        let second = mapper.on_new_job(2, 10);
+        assert_ne!(first, second);
+        assert_eq!(mapper.on_new_job(1, 10), first);
+        assert_eq!(mapper.get(first), Some((1, 10)));
+        assert_eq!(mapper.get(second), Some((2, 10)));
+
+        assert_eq!(mapper.remove(first), Some((1, 10)));
+        assert_eq!(mapper.get(first), None);
+        let third = mapper.on_new_job(1, 10);
+        assert_ne!(third, second);
+
+        mapper.clear();
+        assert!(mapper.is_empty());
+        let fourth = mapper.on_new_job(2, 10);
+        assert!(![first, second, third].contains(&fourth));
+    }
+
+    #[test]
+    fn tags_job_ids_with_the_epoch() {
+        let mut previous = JobIdMapper::with_epoch(1);
+        let mut mapper = JobIdMapper::with_epoch(2);
+        let old = previous.on_new_job(1, 10);
+        let new = mapper.on_new_job(1, 10);
+        assert_ne!(old, new);
+        assert_eq!(JobIdMapper::epoch_of(new), 2);
+        assert!(mapper.is_current_epoch(new));
+        assert!(!mapper.is_current_epoch(old));
+
+        // The ids wrap inside the epoch
+        mapper.next_id = u32::MAX;
+        let last = mapper.on_new_job(1, 11);
+        let wrapped = mapper.on_new_job(1, 12);
+        assert_eq!(last, 0x02ff_ffff);
+        assert!(mapper.is_current_epoch(wrapped));
+        assert!(![new, last].contains(&wrapped));
+    }
+
+    #[test]
+    fn reads_downstream_data_from_setup_connection() {
+        use common_messages_sv2::MiningFlags;
+        use std::convert::TryInto;
+        let flags =
+            (MiningFlags::REQUIRES_STANDARD_JOBS | MiningFlags::REQUIRES_VERSION_ROLLING).bits();
+        let setup = SetupConnection {
+            protocol: Protocol::MiningProtocol,
+            min_version: 2,
+            max_version: 2,
+            flags,
+            endpoint_host: "".to_string().try_into().unwrap(),
+            endpoint_port: 0,
+            vendor: "Bitmain".to_string().try_into().unwrap(),
+            hardware_version: "S19".to_string().try_into().unwrap(),
+            firmware: "1.0".to_string().try_into().unwrap(),
+            device_id: "".to_string().try_into().unwrap(),
+        };
+        let data = CommonDownstreamData::from_setup_connection(&setup, None);
+        assert!(data.header_only && data.version_rolling && !data.work_selection);
+        assert_eq!(data.flags, flags);
+        assert_eq!(data.device.vendor, "Bitmain");
+        assert_eq!(data.device.hardware_version, "S19");
+        assert_eq!(data.device.firmware, "1.0");
+        assert_eq!(data.device.device_id, "");
+    }
+
     #[test]
     fn downstream_channel_returns_group_id_on_receiving_standard_channel() {
         let expect = 0;
@@ -0,0 +1,384 @@
+//! Protocol conformance guard.
+//!
+//! `ConformanceGuard` follow the messages of a connection (in both directions) and check the
+//! ordering invariants of the sequence diagrams of the spec:
+//! * no message other than SetupConnection is exchanged before SetupConnectionSuccess
+//! * no job (and no prev hash, target or extranonce prefix) is sent on a channel before the
+//!   channel has been opened, a job sent on a group channel is a job for every channel of the group
+//! * no share is submitted on a channel before that a job has been sent on it
+//!
+//! The handlers check the received messages and the responses of the handlers when the node return
+//! a guard with `get_conformance_guard`, the messages that the node send outside the handlers (eg
+//! the jobs of a pool) must be passed to `ConformanceGuard::observe_sent`.
+//!
+//! A violation in a received message is a bug of the remote, the handler return
+//! `Error::ProtocolViolation` and the node decide what to do with the connection. A violation in a
+//! sent message is a bug of the node: in debug builds (so in the tests) the guard panic, in release
+//! builds it is only logged.
+use crate::{
+    errors::Error,
+    handlers::SendTo_,
+    parsers::{CommonMessages, Mining, MiningDeviceMessages},
+    utils::Mutex,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// Message (type) exchanged before SetupConnectionSuccess
+    MessageBeforeSetupConnection(u8),
+    /// Job, prev hash, target or extranonce prefix (message type) for a channel not yet opened
+    JobBeforeChannelSuccess(u8, u32),
+    /// Share submitted on a channel (channel_id) without a job
+    ShareBeforeJob(u32),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Violation::*;
+        match self {
+            MessageBeforeSetupConnection(message_type) => write!(
+                f,
+                "Message {:#x} sent before SetupConnectionSuccess",
+                message_type
+            ),
+            JobBeforeChannelSuccess(message_type, channel_id) => write!(
+                f,
+                "Message {:#x} sent on channel {} before the channel is opened",
+                message_type, channel_id
+            ),
+            ShareBeforeJob(channel_id) => {
+                write!(
+                    f,
+                    "Share submitted on channel {} before any job",
+                    channel_id
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConformanceGuard {
+    setup_done: bool,
+    // channel_id -> group_channel_id
+    channels: HashMap<u32, Option<u32>>,
+    groups: HashSet<u32>,
+    // Channels and groups that got a job
+    with_job: HashSet<u32>,
+}
+
+impl ConformanceGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check a common message exchanged on the connection
+    pub fn observe_common(&mut self, message: &CommonMessages) -> Result<(), Violation> {
+        if let CommonMessages::SetupConnectionSuccess(_) = message {
+            self.setup_done = true;
+        }
+        Ok(())
+    }
+
+    /// Check a mining message exchanged on the connection, the message is recorded also when it
+    /// violate an invariant
+    pub fn observe(&mut self, message: &Mining) -> Result<(), Violation> {
+        let result = self.check(message);
+        self.record(message);
+        result
+    }
+
+    /// Check a message sent by the node outside the handlers and report the violation, see
+    /// `enforce`
+    pub fn observe_sent<M: Observed>(&mut self, message: &M) {
+        if let Err(violation) = message.observe_by(self) {
+            Self::enforce(violation);
+        }
+    }
+
+    /// Report a violation of the node itself: panic in debug builds, log in release builds. The
+    /// violations of the remote are never enforced, see `check_received`
+    pub fn enforce(violation: Violation) {
+        if cfg!(debug_assertions) {
+            panic!("Protocol violation: {}", violation);
+        } else {
+            log::error!("Protocol violation: {}", violation);
+        }
+    }
+
+    /// Register a group channel that the node open without an OpenStandardMiningChannelSuccess,
+    /// eg a pool that send the jobs of the group of the connection before the first channel
+    pub fn open_group(&mut self, group_channel_id: u32) {
+        self.groups.insert(group_channel_id);
+    }
+
+    fn check(&self, message: &Mining) -> Result<(), Violation> {
+        let message_type = crate::parsers::IsSv2Message::message_type(message);
+        if !self.setup_done {
+            return Err(Violation::MessageBeforeSetupConnection(message_type));
+        }
+        match message {
+            Mining::NewMiningJob(m) => self.check_opened(message_type, m.channel_id),
+            Mining::NewExtendedMiningJob(m) => self.check_opened(message_type, m.channel_id),
+            Mining::SetNewPrevHash(m) => self.check_opened(message_type, m.channel_id),
+            Mining::SetTarget(m) => self.check_opened(message_type, m.channel_id),
+            Mining::SetExtranoncePrefix(m) => self.check_opened(message_type, m.channel_id),
+            Mining::SubmitSharesStandard(m) => self.check_has_job(m.channel_id),
+            Mining::SubmitSharesExtended(m) => self.check_has_job(m.channel_id),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_opened(&self, message_type: u8, channel_id: u32) -> Result<(), Violation> {
+        if self.channels.contains_key(&channel_id) || self.groups.contains(&channel_id) {
+            Ok(())
+        } else {
+            Err(Violation::JobBeforeChannelSuccess(message_type, channel_id))
+        }
+    }
+
+    fn check_has_job(&self, channel_id: u32) -> Result<(), Violation> {
+        let group = self.channels.get(&channel_id).copied().flatten();
+        if self.with_job.contains(&channel_id)
+            || matches!(group, Some(g) if self.with_job.contains(&g))
+        {
+            Ok(())
+        } else {
+            Err(Violation::ShareBeforeJob(channel_id))
+        }
+    }
+
+    fn record(&mut self, message: &Mining) {
+        match message {
+            Mining::OpenStandardMiningChannelSuccess(m) => {
+                self.channels.insert(m.channel_id, Some(m.group_channel_id));
+                self.groups.insert(m.group_channel_id);
+            }
+            Mining::OpenExtendedMiningChannelSuccess(m) => {
+                self.channels.insert(m.channel_id, None);
+            }
+            // The channels moved to the new group keep the group of their success message
+            Mining::SetGroupChannel(m) => {
+                self.groups.insert(m.group_channel_id);
+            }
+            Mining::NewMiningJob(m) => {
+                self.with_job.insert(m.channel_id);
+            }
+            Mining::NewExtendedMiningJob(m) => {
+                self.with_job.insert(m.channel_id);
+            }
+            Mining::SetCustomMiningJobSuccess(m) => {
+                self.with_job.insert(m.channel_id);
+            }
+            Mining::CloseChannel(m) => {
+                self.channels.remove(&m.channel_id);
+                self.groups.remove(&m.channel_id);
+                self.with_job.remove(&m.channel_id);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Messages checked by the guard
+pub trait Observed {
+    fn observe_by(&self, guard: &mut ConformanceGuard) -> Result<(), Violation>;
+}
+
+impl<'a> Observed for Mining<'a> {
+    fn observe_by(&self, guard: &mut ConformanceGuard) -> Result<(), Violation> {
+        guard.observe(self)
+    }
+}
+
+impl<'a> Observed for CommonMessages<'a> {
+    fn observe_by(&self, guard: &mut ConformanceGuard) -> Result<(), Violation> {
+        guard.observe_common(self)
+    }
+}
+
+impl<'a> Observed for MiningDeviceMessages<'a> {
+    fn observe_by(&self, guard: &mut ConformanceGuard) -> Result<(), Violation> {
+        match self {
+            MiningDeviceMessages::Common(m) => guard.observe_common(m),
+            MiningDeviceMessages::Mining(m) => guard.observe(m),
+        }
+    }
+}
+
+/// Check a message received by a node that has a guard, the violation is returned as an error
+pub(crate) fn check_received<M: Observed>(
+    guard: &Option<Arc<Mutex<ConformanceGuard>>>,
+    message: &M,
+) -> Result<(), Error> {
+    match guard {
+        // Is fine to unwrap on safe_lock
+        Some(guard) => guard
+            .safe_lock(|g| message.observe_by(g))
+            .unwrap()
+            .map_err(Error::ProtocolViolation),
+        None => Ok(()),
+    }
+}
+
+/// Check a message sent by a node that has a guard, see `ConformanceGuard::enforce`
+fn check_sent<M: Observed>(guard: &Option<Arc<Mutex<ConformanceGuard>>>, message: &M) {
+    if let Some(guard) = guard {
+        // Is fine to unwrap on safe_lock
+        guard.safe_lock(|g| g.observe_sent(message)).unwrap();
+    }
+}
+
+/// Check the messages that a handler respond to the remote of the connection, the relayed messages
+/// belong to other connections and are not checked
+pub(crate) fn check_responses<M: Observed, Remote>(
+    guard: &Option<Arc<Mutex<ConformanceGuard>>>,
+    result: &Result<SendTo_<M, Remote>, Error>,
+) {
+    fn responses<'a, M, Remote>(send_to: &'a SendTo_<M, Remote>, out: &mut Vec<&'a M>) {
+        match send_to {
+            SendTo_::Respond(m) => out.push(m),
+            SendTo_::Multiple(inner) => inner.iter().for_each(|s| responses(s, out)),
+            _ => (),
+        }
+    }
+    if let (Some(_), Ok(send_to)) = (guard, result) {
+        let mut out = vec![];
+        responses(send_to, &mut out);
+        for message in out {
+            check_sent(guard, message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common_messages_sv2::SetupConnectionSuccess;
+    use mining_sv2::{
+        CloseChannel, NewExtendedMiningJob, NewMiningJob, OpenStandardMiningChannelSuccess,
+        SubmitSharesStandard,
+    };
+    use std::convert::TryInto;
+
+    fn setup_done() -> ConformanceGuard {
+        let mut guard = ConformanceGuard::new();
+        let success = CommonMessages::SetupConnectionSuccess(SetupConnectionSuccess {
+            used_version: 2,
+            flags: 0,
+        });
+        guard.observe_common(&success).unwrap();
+        guard
+    }
+
+    fn open(channel_id: u32, group_channel_id: u32) -> Mining<'static> {
+        Mining::OpenStandardMiningChannelSuccess(OpenStandardMiningChannelSuccess {
+            request_id: 1.into(),
+            channel_id,
+            target: [0xff; 32].into(),
+            extranonce_prefix: vec![0; 8].try_into().unwrap(),
+            group_channel_id,
+        })
+    }
+
+    fn job(channel_id: u32) -> Mining<'static> {
+        Mining::NewMiningJob(NewMiningJob {
+            channel_id,
+            job_id: 1,
+            future_job: true,
+            version: 0x2000_0000,
+            merkle_root: vec![0; 32].try_into().unwrap(),
+        })
+    }
+
+    fn extended_job(channel_id: u32) -> Mining<'static> {
+        Mining::NewExtendedMiningJob(NewExtendedMiningJob {
+            channel_id,
+            job_id: 1,
+            future_job: true,
+            version: 0x2000_0000,
+            version_rolling_allowed: false,
+            merkle_path: vec![].into(),
+            coinbase_tx_prefix: vec![0; 4].try_into().unwrap(),
+            coinbase_tx_suffix: vec![0; 4].try_into().unwrap(),
+        })
+    }
+
+    fn share(channel_id: u32) -> Mining<'static> {
+        Mining::SubmitSharesStandard(SubmitSharesStandard {
+            channel_id,
+            sequence_number: 0,
+            job_id: 1,
+            nonce: 0,
+            ntime: 0,
+            version: 0x2000_0000,
+        })
+    }
+
+    #[test]
+    fn rejects_messages_before_setup_connection() {
+        let mut guard = ConformanceGuard::new();
+        assert_eq!(
+            guard.observe(&open(1, 10)),
+            Err(Violation::MessageBeforeSetupConnection(0x11))
+        );
+    }
+
+    #[test]
+    fn rejects_jobs_and_shares_out_of_order() {
+        let mut guard = setup_done();
+        assert!(matches!(
+            guard.observe(&job(3)),
+            Err(Violation::JobBeforeChannelSuccess(_, 3))
+        ));
+        guard.observe(&open(1, 10)).unwrap();
+        guard.observe(&open(2, 10)).unwrap();
+        assert_eq!(guard.observe(&share(1)), Err(Violation::ShareBeforeJob(1)));
+
+        guard.observe(&job(1)).unwrap();
+        guard.observe(&share(1)).unwrap();
+        assert_eq!(guard.observe(&share(2)), Err(Violation::ShareBeforeJob(2)));
+        // A job on the group is a job for every channel of the group
+        guard.observe(&extended_job(10)).unwrap();
+        guard.observe(&share(2)).unwrap();
+
+        guard
+            .observe(&Mining::CloseChannel(CloseChannel {
+                channel_id: 1,
+                reason_code: "".to_string().try_into().unwrap(),
+            }))
+            .unwrap();
+        assert!(guard.observe(&job(1)).is_err());
+    }
+
+    #[test]
+    fn received_violations_are_returned() {
+        let guard = Some(Arc::new(Mutex::new(setup_done())));
+        assert!(matches!(
+            check_received(&guard, &share(1)),
+            Err(Error::ProtocolViolation(Violation::ShareBeforeJob(1)))
+        ));
+        assert!(check_received(&None, &share(1)).is_ok());
+    }
+
+    #[test]
+    fn jobs_on_an_open_group_are_accepted() {
+        let mut guard = setup_done();
+        guard.open_group(10);
+        guard.observe_sent(&MiningDeviceMessages::Mining(extended_job(10)));
+        guard.observe(&open(1, 10)).unwrap();
+        guard.observe(&share(1)).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Protocol violation")]
+    fn panics_in_debug_builds() {
+        let mut guard = setup_done();
+        guard.observe_sent(&share(1));
+    }
+}
@@ -0,0 +1,244 @@
+//! Error codes of the mining protocol error messages.
+//!
+//! The error messages (`OpenMiningChannelError`, `UpdateChannelError`, `SubmitSharesError`, ...)
+//! carry the error code as a string. `MiningErrorCode` is the typed version of the codes defined
+//! by the spec, plus the ones used by the roles of this repo, and the functions below build the
+//! error messages so that the roles do not have to convert strings and copy the ids by hand.
+use crate::errors::{Error, RoutingError};
+use binary_sv2::Str032;
+use common_messages_sv2::SetupConnectionError;
+use mining_sv2::{
+    OpenMiningChannelError, SetCustomMiningJobError, SubmitSharesError, SubmitSharesStandard,
+    UpdateChannelError,
+};
+use std::{convert::TryInto, fmt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MiningErrorCode {
+    /// OpenMiningChannel: the user is not known or not allowed
+    UnknownUser,
+    /// OpenMiningChannel and UpdateChannel: the requested max target can not be served
+    MaxTargetOutOfRange,
+    /// SubmitShares, UpdateChannel and SetCustomMiningJob: the channel is not open
+    InvalidChannelId,
+    /// SubmitShares: the share is for a job that is no more valid
+    StaleShare,
+    /// SubmitShares: the share do not meet the channel target
+    DifficultyTooLow,
+    /// SubmitShares: the job is not known
+    InvalidJobId,
+    /// SetCustomMiningJob: the mining job token is not valid
+    InvalidMiningJobToken,
+    /// Not defined by the spec, the share has already been submitted
+    DuplicateShare,
+    /// Not defined by the spec, the share roll version bits that the job do not allow
+    InvalidVersion,
+    /// Not defined by the spec, a proxy can not open the channel because its upstream is not ready
+    UpstreamNotReady,
+    /// Not defined by the spec, a proxy has no extranonce left for a new channel
+    ExtranonceSpaceExhausted,
+    /// Not defined by the spec, the request id do not match any pending request
+    UnknownRequestId,
+    /// Not defined by the spec, the upstream did not answer the request in time
+    RequestTimeout,
+    /// Not defined by the spec, OpenMiningChannel: the node do not serve this channel type
+    UnsupportedChannelType,
+    /// Not defined by the spec, the channel submit far more shares than its target imply
+    ShareRateExceeded,
+    /// Not defined by the spec, SetCustomMiningJob: the transactions pay too few fees
+    FeeRateTooLow,
+    /// Not defined by the spec, SetCustomMiningJob: the transactions have too many sigops
+    TooManySigops,
+    /// Not defined by the spec, OpenMiningChannel: a request with the same id is already pending
+    DuplicateRequestId,
+}
+
+impl MiningErrorCode {
+    const ALL: [MiningErrorCode; 18] = [
+        Self::UnknownUser,
+        Self::MaxTargetOutOfRange,
+        Self::InvalidChannelId,
+        Self::StaleShare,
+        Self::DifficultyTooLow,
+        Self::InvalidJobId,
+        Self::InvalidMiningJobToken,
+        Self::DuplicateShare,
+        Self::InvalidVersion,
+        Self::UpstreamNotReady,
+        Self::ExtranonceSpaceExhausted,
+        Self::UnknownRequestId,
+        Self::RequestTimeout,
+        Self::UnsupportedChannelType,
+        Self::ShareRateExceeded,
+        Self::FeeRateTooLow,
+        Self::TooManySigops,
+        Self::DuplicateRequestId,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UnknownUser => "unknown-user",
+            Self::MaxTargetOutOfRange => "max-target-out-of-range",
+            Self::InvalidChannelId => "invalid-channel-id",
+            Self::StaleShare => "stale-share",
+            Self::DifficultyTooLow => "difficulty-too-low",
+            Self::InvalidJobId => "invalid-job-id",
+            Self::InvalidMiningJobToken => "invalid-mining-job-token",
+            Self::DuplicateShare => "duplicate-share",
+            Self::InvalidVersion => "invalid-version",
+            Self::UpstreamNotReady => "upstream-not-ready",
+            Self::ExtranonceSpaceExhausted => "extranonce-space-exhausted",
+            Self::UnknownRequestId => "unknown-request-id",
+            Self::RequestTimeout => "request-timeout",
+            Self::UnsupportedChannelType => "unsupported-channel-type",
+            Self::ShareRateExceeded => "share-rate-exceeded",
+            Self::FeeRateTooLow => "fee-rate-too-low",
+            Self::TooManySigops => "too-many-sigops",
+            Self::DuplicateRequestId => "duplicate-request-id",
+        }
+    }
+
+    /// Code of a received error message, None if the code is not known
+    pub fn from_bytes(error_code: &[u8]) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|code| code.as_str().as_bytes() == error_code)
+    }
+}
+
+impl fmt::Display for MiningErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<MiningErrorCode> for Str032<'static> {
+    fn from(code: MiningErrorCode) -> Self {
+        // Is fine to unwrap, every code is shorter than 32 bytes
+        code.as_str().as_bytes().to_vec().try_into().unwrap()
+    }
+}
+
+pub fn open_mining_channel_error(
+    request_id: u32,
+    code: MiningErrorCode,
+) -> OpenMiningChannelError<'static> {
+    OpenMiningChannelError {
+        request_id,
+        error_code: code.into(),
+    }
+}
+
+pub fn update_channel_error(channel_id: u32, code: MiningErrorCode) -> UpdateChannelError<'static> {
+    UpdateChannelError {
+        channel_id,
+        error_code: code.into(),
+    }
+}
+
+pub fn submit_shares_error(
+    channel_id: u32,
+    sequence_number: u32,
+    code: MiningErrorCode,
+) -> SubmitSharesError<'static> {
+    SubmitSharesError {
+        channel_id,
+        sequence_number,
+        error_code: code.into(),
+    }
+}
+
+/// Reject `share`
+pub fn share_error(
+    share: &SubmitSharesStandard,
+    code: MiningErrorCode,
+) -> SubmitSharesError<'static> {
+    submit_shares_error(share.channel_id, share.sequence_number, code)
+}
+
+pub fn set_custom_mining_job_error(
+    channel_id: u32,
+    request_id: u32,
+    code: MiningErrorCode,
+) -> SetCustomMiningJobError<'static> {
+    SetCustomMiningJobError {
+        channel_id,
+        request_id,
+        error_code: code.into(),
+    }
+}
+
+impl From<RoutingError> for MiningErrorCode {
+    /// Code of the OpenMiningChannel.Error sent when the channel can not be routed
+    fn from(error: RoutingError) -> Self {
+        match error {
+            RoutingError::DuplicateRequestId(_) => Self::DuplicateRequestId,
+            RoutingError::UnsupportedProtocol(_) | RoutingError::UnsupportedFlags(_) => {
+                Self::UnsupportedChannelType
+            }
+            RoutingError::NoUpstreamAvailable
+            | RoutingError::DownstreamNotFound
+            | RoutingError::NoRequestIdMapper
+            | RoutingError::InvalidRoutingLogic => Self::UpstreamNotReady,
+        }
+    }
+}
+
+/// SetupConnection.Error for a downstream whose SetupConnection (with `flags`) failed with
+/// `error`
+pub fn setup_connection_error(error: &Error, flags: u32) -> SetupConnectionError<'static> {
+    match error {
+        Error::Routing(RoutingError::UnsupportedProtocol(_)) => {
+            SetupConnectionError::unsupported_protocol()
+        }
+        Error::Routing(RoutingError::UnsupportedFlags(flags)) => {
+            SetupConnectionError::unsupported_feature_flags(*flags)
+        }
+        // No upstream support the version and flags requested
+        _ => SetupConnectionError::unsupported_feature_flags(flags),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_the_codes() {
+        for code in MiningErrorCode::ALL.iter() {
+            assert!(code.as_str().len() <= 32);
+            assert_eq!(
+                MiningErrorCode::from_bytes(code.as_str().as_bytes()),
+                Some(*code)
+            );
+        }
+        assert_eq!(MiningErrorCode::from_bytes(b"not-a-code"), None);
+
+        let error = submit_shares_error(3, 7, MiningErrorCode::StaleShare);
+        assert_eq!((error.channel_id, error.sequence_number), (3, 7));
+        assert_eq!(error.error_code.to_vec(), b"stale-share".to_vec());
+    }
+
+    #[test]
+    fn converts_routing_errors() {
+        let code: MiningErrorCode = RoutingError::DuplicateRequestId(4).into();
+        assert_eq!(code, MiningErrorCode::DuplicateRequestId);
+        let code: MiningErrorCode = RoutingError::NoUpstreamAvailable.into();
+        assert_eq!(code, MiningErrorCode::UpstreamNotReady);
+
+        let protocol = common_messages_sv2::Protocol::TemplateDistributionProtocol;
+        let error = Error::Routing(RoutingError::UnsupportedProtocol(protocol));
+        let message = setup_connection_error(&error, 1);
+        assert_eq!(
+            message.error_code.to_vec(),
+            b"unsupported-protocol".to_vec()
+        );
+        let message = setup_connection_error(&Error::NoPairableUpstream((2, 2, 0)), 1);
+        assert_eq!(message.flags, 1);
+        assert_eq!(
+            message.error_code.to_vec(),
+            b"unsupported-feature-flags".to_vec()
+        );
+    }
+}
@@ -1,4 +1,6 @@
+use crate::{conformance::Violation, message_registry::BadHeader};
 use binary_sv2::Error as BinarySv2Error;
+use common_messages_sv2::Protocol;
 use std::fmt::{self, Display, Formatter};
 
 #[derive(Debug)]
@@ -16,8 +18,86 @@ pub enum Error {
     NoDownstreamsConnected,
     PrevHashRequireNonExistentJobId(u32),
     RequestIdNotMapped(u32),
-    NoUpstreamsConnected,
     UnknownRequestId(u32),
+    /// The frame header do not match a known message, see `message_registry`
+    BadHeader(BadHeader),
+    /// Extensions negotiation refused by the upstream (unsupported extensions, required
+    /// extensions)
+    ExtensionsNegotiationFailed(Vec<u16>, Vec<u16>),
+    /// Extranonce prefix len and extranonce size of an extended channel do not fit in 32 bytes
+    InvalidExtranonceSize(usize, usize),
+    /// Every extranonce slice of an aggregated extended channel is in use
+    ExtranonceSpaceExhausted,
+    /// Every channel id of the connection is in use
+    ChannelIdsExhausted,
+    /// Non future job (job_id) received before any prev hash
+    JobWithoutPrevHash(u32),
+    /// Share received before that the channel has an active job
+    ShareBeforeFirstJob,
+    /// Share for a job (job_id) that is not the active job of the channel
+    ShareForInactiveJob(u32),
+    /// The coinbase_prefix (or the coinbase version) of a template can not start a coinbase
+    InvalidCoinbasePrefix,
+    /// The coinbase_tx_outputs of a template are not coinbase_tx_outputs_count outputs
+    InvalidCoinbaseOutputs,
+    /// The transactions of the template (template_id) have not been received
+    NoTransactionData(u64),
+    /// The transactions (or the coinbase) of the template (template_id) can not be decoded
+    InvalidTransactionData(u64),
+    /// The transactions of a custom job (job_id) do not match its merkle path
+    TransactionsMismatch(u32),
+    /// Share for a custom job (job_id) that has not been declared
+    UnknownCustomJob(u32),
+    /// The routing logic can not route the message, see `RoutingError`
+    Routing(RoutingError),
+    /// The message (message type) can not be relayed to the remote as it is not in its message
+    /// set, see `parsers::RelayFrame`
+    UnrelayableMessage(u8),
+    /// The payload of a share (message type) is not a valid share, see `parsers::fast_shares`
+    MalformedShare(u8),
+    /// The remote sent a message that violate the ordering of the protocol, see `conformance`
+    ProtocolViolation(Violation),
+}
+
+/// Errors of the routing logic of the proxies, the handlers convert them in protocol error
+/// messages (SetupConnection.Error and OpenMiningChannel.Error) for the downstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutingError {
+    /// No upstream can serve the downstream (none connected, all quarantined or draining)
+    NoUpstreamAvailable,
+    /// The downstream has not been paired with an upstream by the router on setup connection
+    DownstreamNotFound,
+    /// An open channel request with the same request id is already pending
+    DuplicateRequestId(u32),
+    /// The upstream do not have a request id mapper
+    NoRequestIdMapper,
+    /// SetupConnection for a protocol that the router do not route
+    UnsupportedProtocol(Protocol),
+    /// SetupConnection with flags (the passed ones) that the router do not support
+    UnsupportedFlags(u32),
+    /// MiningRoutingLogic::_P used as routing logic
+    InvalidRoutingLogic,
+}
+
+impl From<RoutingError> for Error {
+    fn from(v: RoutingError) -> Error {
+        Error::Routing(v)
+    }
+}
+
+impl Display for RoutingError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use RoutingError::*;
+        match self {
+            NoUpstreamAvailable => write!(f, "No upstream available"),
+            DownstreamNotFound => write!(f, "Downstream not paired with any upstream"),
+            DuplicateRequestId(id) => write!(f, "Request id {} already pending", id),
+            NoRequestIdMapper => write!(f, "Upstream without request id mapper"),
+            UnsupportedProtocol(p) => write!(f, "Unsupported protocol {:?}", p),
+            UnsupportedFlags(flags) => write!(f, "Unsupported setup connection flags {:b}", flags),
+            InvalidRoutingLogic => write!(f, "MiningRoutingLogic::_P is not a routing logic"),
+        }
+    }
 }
 
 impl From<BinarySv2Error> for Error {
@@ -51,7 +131,6 @@ impl Display for Error {
                 write!(f, "PrevHashRequireNonExistentJobId {}", id)
             }
             RequestIdNotMapped(id) => write!(f, "RequestIdNotMapped {}", id),
-            NoUpstreamsConnected => write!(f, "There are no upstream connected"),
             UnknownRequestId(id) => write!(
                 f,
                 "Upstream is answering with a wrong request ID {} or
@@ -59,6 +138,40 @@ impl Display for Error {
                 before relaying open channel request to upstream",
                 id
             ),
+            BadHeader(e) => write!(f, "Bad frame header: {:?}", e),
+            ExtensionsNegotiationFailed(unsupported, required) => write!(
+                f,
+                "Extensions negotiation failed, unsupported: {:?} required: {:?}",
+                unsupported, required
+            ),
+            InvalidExtranonceSize(prefix, size) => write!(
+                f,
+                "Invalid extranonce, prefix len: {} extranonce size: {}",
+                prefix, size
+            ),
+            ExtranonceSpaceExhausted => write!(f, "No free extranonce slice"),
+            ChannelIdsExhausted => write!(f, "No free channel id"),
+            JobWithoutPrevHash(id) => {
+                write!(f, "Non future job {} received before a prev hash", id)
+            }
+            ShareBeforeFirstJob => write!(f, "Share received before the first job"),
+            ShareForInactiveJob(id) => write!(f, "Share for job {} that is not active", id),
+            InvalidCoinbasePrefix => write!(f, "Template coinbase prefix without a BIP34 height"),
+            InvalidCoinbaseOutputs => write!(f, "Template coinbase outputs can not be decoded"),
+            NoTransactionData(id) => write!(f, "No transactions for template {}", id),
+            InvalidTransactionData(id) => {
+                write!(f, "Transactions of template {} can not be decoded", id)
+            }
+            TransactionsMismatch(id) => write!(
+                f,
+                "Transactions of custom job {} do not match the merkle path",
+                id
+            ),
+            UnknownCustomJob(id) => write!(f, "Custom job {} not declared", id),
+            Routing(e) => write!(f, "Routing error: {}", e),
+            UnrelayableMessage(m) => write!(f, "Message type {} can not be relayed", m),
+            MalformedShare(m) => write!(f, "Malformed share of message type {}", m),
+            ProtocolViolation(v) => write!(f, "Protocol violation: {}", v),
         }
     }
 }
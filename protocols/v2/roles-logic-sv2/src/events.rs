@@ -0,0 +1,60 @@
+//! Lifecycle events of the downstream connections of a role.
+//!
+//! The roles publish a `ConnectionEvent` on every transition of a downstream connection, so that
+//! monitoring code, dashboards and tests can follow the connections without looking at the
+//! internals of the role. This crate do no I/O, the roles deliver the events over an async channel
+//! and drop them if the channel is full: a slow observer never slow down the role.
+use std::net::SocketAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// A connection has been accepted, `connection_id` identify the connection in the next events.
+    /// `remote_address` is the address of the tcp peer.
+    Accepted {
+        connection_id: u32,
+        remote_address: Option<SocketAddr>,
+    },
+    /// The connection has been dropped before the SetupConnection succeeded (noise handshake,
+    /// PROXY header or SetupConnection failed)
+    HandshakeFailed {
+        connection_id: u32,
+    },
+    /// SetupConnection succeeded, the connection can open channels
+    Paired {
+        connection_id: u32,
+    },
+    ChannelOpened {
+        connection_id: u32,
+        channel_id: u32,
+    },
+    /// A channel has been closed while the connection is still open, the channels of a closed
+    /// connection are implicitly closed by `Disconnected`
+    ChannelClosed {
+        connection_id: u32,
+        channel_id: u32,
+    },
+    /// A share submitted on the channel meet the bitcoin target, it has been relayed ahead of the
+    /// other messages. Meant for alerting, the role does not wait the observers to relay it.
+    BlockFound {
+        connection_id: u32,
+        channel_id: u32,
+    },
+    /// A paired connection has been closed
+    Disconnected {
+        connection_id: u32,
+    },
+}
+
+impl ConnectionEvent {
+    pub fn connection_id(&self) -> u32 {
+        match self {
+            ConnectionEvent::Accepted { connection_id, .. }
+            | ConnectionEvent::HandshakeFailed { connection_id }
+            | ConnectionEvent::Paired { connection_id }
+            | ConnectionEvent::ChannelOpened { connection_id, .. }
+            | ConnectionEvent::ChannelClosed { connection_id, .. }
+            | ConnectionEvent::BlockFound { connection_id, .. }
+            | ConnectionEvent::Disconnected { connection_id } => *connection_id,
+        }
+    }
+}
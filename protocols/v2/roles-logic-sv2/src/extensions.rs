@@ -0,0 +1,352 @@
+//! Sv2 protocol extensions.
+//!
+//! A role declare the extensions that it supports registering an `ExtensionHandler` for each
+//! `extension_type` in `Extensions`. Extensions are negotiated when the connection is set up: right
+//! after `SetupConnectionSuccess` the downstream send `Extensions::request_extensions` and the
+//! upstream answer with RequestExtensionsSuccess (the extensions supported by both) or
+//! RequestExtensionsError (some extensions required by the upstream are not requested).
+//!
+//! The handlers return `SendTo_::Extension` for every frame with an extension type different
+//! from `EXTENSION_TYPE_NO_EXTENSION`: negotiation messages are handled by `Extensions`, messages
+//! of a negotiated extension are passed to the registered `ExtensionHandler`. Frames of not
+//! negotiated extensions are rejected with `Error::BadHeader`.
+//!
+//! Negotiation messages payload:
+//! * RequestExtensions: request_id u16, requested extensions Seq064K<u16>
+//! * RequestExtensionsSuccess: request_id u16, supported extensions Seq064K<u16>
+//! * RequestExtensionsError: request_id u16, unsupported extensions Seq064K<u16>, required
+//!   extensions Seq064K<u16>
+use crate::{errors::Error, message_registry::BadHeader, utils::Mutex};
+use const_sv2::{
+    EXTENSION_TYPE_EXTENSIONS_NEGOTIATION, MESSAGE_TYPE_REQUEST_EXTENSIONS,
+    MESSAGE_TYPE_REQUEST_EXTENSIONS_ERROR, MESSAGE_TYPE_REQUEST_EXTENSIONS_SUCCESS,
+};
+use framing_sv2::header::Header;
+use std::sync::Arc;
+
+/// Message of an extension, the payload is already serialized
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionMessage {
+    /// Extension type without the channel_msg bit
+    pub extension_type: u16,
+    pub msg_type: u8,
+    pub channel_msg: bool,
+    pub payload: Vec<u8>,
+}
+
+impl ExtensionMessage {
+    /// Header of the frame that carry the message, `None` if the payload is too big
+    pub fn header(&self) -> Option<Header> {
+        let extension_type = if self.channel_msg {
+            self.extension_type | 0b0000_0000_0000_0001
+        } else {
+            self.extension_type
+        };
+        Header::from_len(self.payload.len() as u32, self.msg_type, extension_type)
+    }
+
+    /// Serialized (not encrypted) sv2 frame: header + payload
+    pub fn to_frame_bytes(&self) -> Option<Vec<u8>> {
+        let header = self.header()?;
+        let mut bytes = Vec::with_capacity(Header::SIZE + self.payload.len());
+        bytes.extend_from_slice(&header.extension_type().to_le_bytes());
+        bytes.push(header.msg_type());
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes()[..3]);
+        bytes.extend_from_slice(&self.payload);
+        Some(bytes)
+    }
+}
+
+/// Implemented by the role for each extension that it supports
+pub trait ExtensionHandler: Send + std::fmt::Debug {
+    fn extension_type(&self) -> u16;
+
+    /// Handle a message of the extension, return the messages that must be sent to the remote
+    fn handle_message(
+        &mut self,
+        msg_type: u8,
+        channel_msg: bool,
+        payload: &mut [u8],
+    ) -> Result<Vec<ExtensionMessage>, Error>;
+}
+
+/// Extensions supported by a role and extensions negotiated on a connection
+#[derive(Debug, Default)]
+pub struct Extensions {
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    /// Extensions that the downstream must request (used by upstreams)
+    required: Vec<u16>,
+    negotiated: Vec<u16>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register an handler for `handler.extension_type()`, replace the previous one if any
+    pub fn register(&mut self, handler: Box<dyn ExtensionHandler>) {
+        let extension_type = handler.extension_type();
+        self.handlers
+            .retain(|h| h.extension_type() != extension_type);
+        self.handlers.push(handler);
+    }
+
+    /// Upstream only: the downstreams that do not request `extension_type` are refused
+    pub fn require(&mut self, extension_type: u16) {
+        if !self.required.contains(&extension_type) {
+            self.required.push(extension_type);
+        }
+    }
+
+    pub fn supported(&self) -> Vec<u16> {
+        self.handlers.iter().map(|h| h.extension_type()).collect()
+    }
+
+    pub fn negotiated(&self) -> &[u16] {
+        &self.negotiated
+    }
+
+    pub fn is_negotiated(&self, extension_type: u16) -> bool {
+        self.negotiated.contains(&extension_type)
+    }
+
+    /// Downstream only: RequestExtensions with every supported extension
+    pub fn request_extensions(&self, request_id: u16) -> ExtensionMessage {
+        let mut payload = request_id.to_le_bytes().to_vec();
+        encode_seq(&self.supported(), &mut payload);
+        negotiation_message(MESSAGE_TYPE_REQUEST_EXTENSIONS, payload)
+    }
+
+    fn on_request_extensions(&mut self, payload: &[u8]) -> Result<ExtensionMessage, Error> {
+        let (request_id, payload) = decode_request_id(payload)?;
+        let (requested, _) = decode_seq(payload)?;
+        let supported = self.supported();
+        let missing_required = self.required.iter().any(|r| !requested.contains(r));
+        let mut response = request_id.to_le_bytes().to_vec();
+        if missing_required {
+            let unsupported: Vec<u16> = requested
+                .iter()
+                .filter(|r| !supported.contains(r))
+                .copied()
+                .collect();
+            encode_seq(&unsupported, &mut response);
+            encode_seq(&self.required, &mut response);
+            self.negotiated = Vec::new();
+            Ok(negotiation_message(
+                MESSAGE_TYPE_REQUEST_EXTENSIONS_ERROR,
+                response,
+            ))
+        } else {
+            self.negotiated = requested
+                .into_iter()
+                .filter(|r| supported.contains(r))
+                .collect();
+            encode_seq(&self.negotiated, &mut response);
+            Ok(negotiation_message(
+                MESSAGE_TYPE_REQUEST_EXTENSIONS_SUCCESS,
+                response,
+            ))
+        }
+    }
+
+    fn on_request_extensions_success(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let (_, payload) = decode_request_id(payload)?;
+        let (accepted, _) = decode_seq(payload)?;
+        let supported = self.supported();
+        self.negotiated = accepted
+            .into_iter()
+            .filter(|a| supported.contains(a))
+            .collect();
+        Ok(())
+    }
+
+    fn on_request_extensions_error(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let (_, payload) = decode_request_id(payload)?;
+        let (unsupported, payload) = decode_seq(payload)?;
+        let (required, _) = decode_seq(payload)?;
+        self.negotiated = Vec::new();
+        Err(Error::ExtensionsNegotiationFailed(unsupported, required))
+    }
+
+    /// Handle a frame with an extension type different from `EXTENSION_TYPE_NO_EXTENSION`
+    pub fn handle_message(
+        &mut self,
+        header: &Header,
+        payload: &mut [u8],
+    ) -> Result<Vec<ExtensionMessage>, Error> {
+        let extension_type = header.ext_type_without_channel_msg();
+        let msg_type = header.msg_type();
+        if extension_type == EXTENSION_TYPE_EXTENSIONS_NEGOTIATION {
+            return match msg_type {
+                MESSAGE_TYPE_REQUEST_EXTENSIONS => Ok(vec![self.on_request_extensions(payload)?]),
+                MESSAGE_TYPE_REQUEST_EXTENSIONS_SUCCESS => self
+                    .on_request_extensions_success(payload)
+                    .map(|_| Vec::new()),
+                MESSAGE_TYPE_REQUEST_EXTENSIONS_ERROR => self
+                    .on_request_extensions_error(payload)
+                    .map(|_| Vec::new()),
+                _ => Err(Error::BadHeader(BadHeader::UnknownMessageType(
+                    extension_type,
+                    msg_type,
+                ))),
+            };
+        }
+        if !self.is_negotiated(extension_type) {
+            return Err(Error::BadHeader(BadHeader::UnknownExtension(
+                extension_type,
+            )));
+        }
+        match self
+            .handlers
+            .iter_mut()
+            .find(|h| h.extension_type() == extension_type)
+        {
+            Some(handler) => handler.handle_message(msg_type, header.channel_msg(), payload),
+            None => Err(Error::BadHeader(BadHeader::UnknownExtension(
+                extension_type,
+            ))),
+        }
+    }
+}
+
+/// Dispatch hook used by the handlers, roles that do not support extensions pass `None`
+pub fn dispatch(
+    extensions: Option<Arc<Mutex<Extensions>>>,
+    header: &Header,
+    payload: &mut [u8],
+) -> Result<Vec<ExtensionMessage>, Error> {
+    match extensions {
+        // Is fine to unwrap on safe_lock
+        Some(extensions) => extensions
+            .safe_lock(|e| e.handle_message(header, payload))
+            .unwrap(),
+        None => Err(Error::BadHeader(BadHeader::UnknownExtension(
+            header.ext_type_without_channel_msg(),
+        ))),
+    }
+}
+
+fn negotiation_message(msg_type: u8, payload: Vec<u8>) -> ExtensionMessage {
+    ExtensionMessage {
+        extension_type: EXTENSION_TYPE_EXTENSIONS_NEGOTIATION,
+        msg_type,
+        channel_msg: false,
+        payload,
+    }
+}
+
+fn decode_request_id(payload: &[u8]) -> Result<(u16, &[u8]), Error> {
+    if payload.len() < 2 {
+        return Err(Error::BinarySv2Error(binary_sv2::Error::OutOfBound));
+    }
+    Ok((u16::from_le_bytes([payload[0], payload[1]]), &payload[2..]))
+}
+
+fn encode_seq(seq: &[u16], dst: &mut Vec<u8>) {
+    dst.extend_from_slice(&(seq.len() as u16).to_le_bytes());
+    for e in seq {
+        dst.extend_from_slice(&e.to_le_bytes());
+    }
+}
+
+fn decode_seq(payload: &[u8]) -> Result<(Vec<u16>, &[u8]), Error> {
+    let (len, payload) = decode_request_id(payload)?;
+    let len = len as usize * 2;
+    if payload.len() < len {
+        return Err(Error::BinarySv2Error(binary_sv2::Error::OutOfBound));
+    }
+    let seq = payload[..len]
+        .chunks(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    Ok((seq, &payload[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXTENSION_TYPE_TEST: u16 = 0x0010;
+
+    /// Echo the received payload
+    #[derive(Debug)]
+    struct Echo;
+
+    impl ExtensionHandler for Echo {
+        fn extension_type(&self) -> u16 {
+            EXTENSION_TYPE_TEST
+        }
+
+        fn handle_message(
+            &mut self,
+            msg_type: u8,
+            channel_msg: bool,
+            payload: &mut [u8],
+        ) -> Result<Vec<ExtensionMessage>, Error> {
+            Ok(vec![ExtensionMessage {
+                extension_type: EXTENSION_TYPE_TEST,
+                msg_type,
+                channel_msg,
+                payload: payload.to_vec(),
+            }])
+        }
+    }
+
+    fn handle(
+        extensions: &mut Extensions,
+        message: &ExtensionMessage,
+    ) -> Result<Vec<ExtensionMessage>, Error> {
+        extensions.handle_message(&message.header().unwrap(), &mut message.payload.clone())
+    }
+
+    #[test]
+    fn negotiates_and_dispatches() {
+        let mut upstream = Extensions::new();
+        upstream.register(Box::new(Echo));
+        let mut downstream = Extensions::new();
+        downstream.register(Box::new(Echo));
+
+        let message = ExtensionMessage {
+            extension_type: EXTENSION_TYPE_TEST,
+            msg_type: 0x01,
+            channel_msg: true,
+            payload: vec![1, 2, 3],
+        };
+        // Not negotiated yet
+        assert!(matches!(
+            handle(&mut upstream, &message),
+            Err(Error::BadHeader(BadHeader::UnknownExtension(
+                EXTENSION_TYPE_TEST
+            )))
+        ));
+
+        let request = downstream.request_extensions(1);
+        let response = handle(&mut upstream, &request).unwrap();
+        assert_eq!(upstream.negotiated(), &[EXTENSION_TYPE_TEST]);
+        assert_eq!(
+            response[0].msg_type,
+            MESSAGE_TYPE_REQUEST_EXTENSIONS_SUCCESS
+        );
+        handle(&mut downstream, &response[0]).unwrap();
+        assert_eq!(downstream.negotiated(), &[EXTENSION_TYPE_TEST]);
+
+        assert_eq!(handle(&mut upstream, &message).unwrap(), vec![message]);
+    }
+
+    #[test]
+    fn refuses_downstream_without_required_extensions() {
+        let mut upstream = Extensions::new();
+        upstream.register(Box::new(Echo));
+        upstream.require(EXTENSION_TYPE_TEST);
+        let mut downstream = Extensions::new();
+
+        let response = handle(&mut upstream, &downstream.request_extensions(1)).unwrap();
+        assert_eq!(response[0].msg_type, MESSAGE_TYPE_REQUEST_EXTENSIONS_ERROR);
+        assert!(matches!(
+            handle(&mut downstream, &response[0]),
+            Err(Error::ExtensionsNegotiationFailed(_, required)) if required == vec![EXTENSION_TYPE_TEST]
+        ));
+        assert!(downstream.negotiated().is_empty());
+    }
+}
@@ -1,7 +1,10 @@
 use super::SendTo_;
 use crate::{
     common_properties::CommonDownstreamData,
+    conformance::{self, ConformanceGuard},
     errors::Error,
+    extensions::{self, Extensions},
+    message_registry::validate_header,
     parsers::CommonMessages,
     routing_logic::{CommonRouter, CommonRoutingLogic},
     utils::Mutex,
@@ -9,8 +12,10 @@ use crate::{
 use common_messages_sv2::{
     ChannelEndpointChanged, SetupConnection, SetupConnectionError, SetupConnectionSuccess,
 };
+use const_sv2::{EXTENSION_TYPE_NO_EXTENSION, SV2_MAX_PROTOCOL_VERSION, SV2_MIN_PROTOCOL_VERSION};
 use core::convert::TryInto;
-use std::sync::Arc;
+use framing_sv2::header::Header;
+use std::{net::SocketAddr, sync::Arc};
 
 pub type SendTo = SendTo_<CommonMessages<'static>, ()>;
 
@@ -18,14 +23,36 @@ pub trait ParseUpstreamCommonMessages<Router: CommonRouter>
 where
     Self: Sized,
 {
+    /// Extensions supported by the node, frames of an extension are passed to
+    /// `extensions::dispatch`. Nodes that do not support extensions return None
+    fn get_extensions(&self) -> Option<Arc<Mutex<Extensions>>> {
+        None
+    }
+
+    /// Guard that check the ordering of the messages of the connection, see `conformance`. It must
+    /// be the same guard returned by the mining handler of the node
+    fn get_conformance_guard(&self) -> Option<Arc<Mutex<ConformanceGuard>>> {
+        None
+    }
+
     // Is fine to unwrap on safe_lock
     fn handle_message_common(
         self_: Arc<Mutex<Self>>,
-        message_type: u8,
+        header: Header,
         payload: &mut [u8],
         _routing_logic: CommonRoutingLogic<Router>,
     ) -> Result<SendTo, Error> {
-        match (message_type, payload).try_into() {
+        if header.ext_type_without_channel_msg() != EXTENSION_TYPE_NO_EXTENSION {
+            let extensions = self_.safe_lock(|x| x.get_extensions()).unwrap();
+            return extensions::dispatch(extensions, &header, payload).map(SendTo::Extension);
+        }
+        let message_type = validate_header(&header)?;
+        let message: Result<CommonMessages, Error> = (message_type, payload).try_into();
+        let guard = self_.safe_lock(|x| x.get_conformance_guard()).unwrap();
+        if let Ok(message) = &message {
+            conformance::check_received(&guard, message)?;
+        }
+        match message {
             Ok(CommonMessages::SetupConnectionSuccess(m)) => self_
                 .safe_lock(|x| x.handle_setup_connection_success(m))
                 .unwrap(),
@@ -67,34 +94,92 @@ where
         }
     }
 
+    /// Extensions supported by the node, frames of an extension are passed to
+    /// `extensions::dispatch`. Nodes that do not support extensions return None
+    fn get_extensions(&self) -> Option<Arc<Mutex<Extensions>>> {
+        None
+    }
+
+    /// Guard that check the ordering of the messages of the connection, see `conformance`. It must
+    /// be the same guard returned by the mining handler of the node
+    fn get_conformance_guard(&self) -> Option<Arc<Mutex<ConformanceGuard>>> {
+        None
+    }
+
     // Is fine to unwrap on safe_lock
     fn handle_message_common(
         self_: Arc<Mutex<Self>>,
-        message_type: u8,
+        header: Header,
         payload: &mut [u8],
         routing_logic: CommonRoutingLogic<Router>,
     ) -> Result<SendTo, Error> {
-        match (message_type, payload).try_into() {
-            Ok(CommonMessages::SetupConnection(m)) => match routing_logic {
-                CommonRoutingLogic::Proxy(r_logic) => {
-                    let result = r_logic
-                        .safe_lock(|r_logic| r_logic.on_setup_connection(&m))
-                        .unwrap();
-                    self_
-                        .safe_lock(|x| x.handle_setup_connection(m, Some(result)))
-                        .unwrap()
+        if header.ext_type_without_channel_msg() != EXTENSION_TYPE_NO_EXTENSION {
+            let extensions = self_.safe_lock(|x| x.get_extensions()).unwrap();
+            return extensions::dispatch(extensions, &header, payload).map(SendTo::Extension);
+        }
+        let message_type = validate_header(&header)?;
+        let guard = self_.safe_lock(|x| x.get_conformance_guard()).unwrap();
+        let result = match (message_type, payload).try_into() {
+            Ok(CommonMessages::SetupConnection(m)) => {
+                let (min_version, max_version) =
+                    self_.safe_lock(|x| x.get_supported_versions()).unwrap();
+                match m.negotiate_version(min_version, max_version) {
+                    Ok(version) => {
+                        self_
+                            .safe_lock(|x| x.on_version_negotiated(version))
+                            .unwrap();
+                        Self::route_setup_connection(self_, m, routing_logic)
+                    }
+                    Err(e) => Ok(SendTo::Respond(CommonMessages::SetupConnectionError(e))),
                 }
-                CommonRoutingLogic::None => self_
-                    .safe_lock(|x| x.handle_setup_connection(m, None))
-                    .unwrap(),
-            },
+            }
             Ok(CommonMessages::SetupConnectionSuccess(_)) => Err(Error::UnexpectedMessage),
             Ok(CommonMessages::SetupConnectionError(_)) => Err(Error::UnexpectedMessage),
             Ok(CommonMessages::ChannelEndpointChanged(_)) => Err(Error::UnexpectedMessage),
             Err(e) => Err(e),
+        };
+        conformance::check_responses(&guard, &result);
+        result
+    }
+
+    fn route_setup_connection(
+        self_: Arc<Mutex<Self>>,
+        m: SetupConnection,
+        routing_logic: CommonRoutingLogic<Router>,
+    ) -> Result<SendTo, Error> {
+        match routing_logic {
+            CommonRoutingLogic::Proxy(r_logic) => {
+                let remote_address = self_.safe_lock(|x| x.get_remote_address()).unwrap();
+                let result = r_logic
+                    .safe_lock(|r_logic| r_logic.on_setup_connection(&m, remote_address))
+                    .unwrap();
+                self_
+                    .safe_lock(|x| x.handle_setup_connection(m, Some(result)))
+                    .unwrap()
+            }
+            CommonRoutingLogic::None => self_
+                .safe_lock(|x| x.handle_setup_connection(m, None))
+                .unwrap(),
         }
     }
 
+    /// Min and max protocol version supported by the node. When the versions requested by the
+    /// downstream are not supported a SetupConnectionError with error code
+    /// `protocol-version-mismatch` is sent back and `handle_setup_connection` is not called.
+    fn get_supported_versions(&self) -> (u16, u16) {
+        (SV2_MIN_PROTOCOL_VERSION, SV2_MAX_PROTOCOL_VERSION)
+    }
+
+    /// Called with the negotiated version before `handle_setup_connection`, implementors that
+    /// need to gate messages on the protocol version should save it here and use it in
+    /// `SetupConnectionSuccess.used_version`.
+    fn on_version_negotiated(&mut self, _version: u16) {}
+
+    /// Address of the downstream passed to the router, None if unknown
+    fn get_remote_address(&self) -> Option<SocketAddr> {
+        None
+    }
+
     fn handle_setup_connection(
         &mut self,
         m: SetupConnection,
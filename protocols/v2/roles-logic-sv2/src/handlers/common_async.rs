@@ -0,0 +1,105 @@
+//! Async variant of `ParseDownstreamCommonMessages` (feature `async_handlers`), see
+//! `super::mining_async`. A pool can authenticate the downstream in `handle_setup_connection`
+//! without locking the node while it wait for the answer.
+use super::common::SendTo;
+use crate::{
+    common_properties::CommonDownstreamData,
+    conformance::{self, ConformanceGuard},
+    errors::Error,
+    extensions::{self, Extensions},
+    message_registry::validate_header,
+    parsers::CommonMessages,
+    routing_logic::{CommonRouter, CommonRoutingLogic},
+    utils::Mutex,
+};
+use async_trait::async_trait;
+use common_messages_sv2::{SetupConnection, SetupConnectionSuccess};
+use const_sv2::{EXTENSION_TYPE_NO_EXTENSION, SV2_MAX_PROTOCOL_VERSION, SV2_MIN_PROTOCOL_VERSION};
+use core::convert::TryInto;
+use framing_sv2::header::Header;
+use std::{net::SocketAddr, sync::Arc};
+
+#[async_trait]
+pub trait AsyncParseDownstreamCommonMessages<Router: CommonRouter + Send>
+where
+    Self: Sized + Send,
+{
+    /// See `ParseDownstreamCommonMessages::get_extensions`
+    fn get_extensions(&self) -> Option<Arc<Mutex<Extensions>>> {
+        None
+    }
+
+    /// See `ParseDownstreamCommonMessages::get_conformance_guard`
+    fn get_conformance_guard(&self) -> Option<Arc<Mutex<ConformanceGuard>>> {
+        None
+    }
+
+    // Is fine to unwrap on safe_lock
+    async fn handle_message_common(
+        self_: Arc<Mutex<Self>>,
+        header: Header,
+        payload: &mut [u8],
+        routing_logic: CommonRoutingLogic<Router>,
+    ) -> Result<SendTo, Error> {
+        if header.ext_type_without_channel_msg() != EXTENSION_TYPE_NO_EXTENSION {
+            let extensions = self_.safe_lock(|x| x.get_extensions()).unwrap();
+            return extensions::dispatch(extensions, &header, payload).map(SendTo::Extension);
+        }
+        let message_type = validate_header(&header)?;
+        let guard = self_.safe_lock(|x| x.get_conformance_guard()).unwrap();
+        let result = match (message_type, payload).try_into() {
+            Ok(CommonMessages::SetupConnection(m)) => {
+                let (min_version, max_version) =
+                    self_.safe_lock(|x| x.get_supported_versions()).unwrap();
+                match m.negotiate_version(min_version, max_version) {
+                    Ok(version) => {
+                        self_
+                            .safe_lock(|x| x.on_version_negotiated(version))
+                            .unwrap();
+                        let result = match routing_logic {
+                            CommonRoutingLogic::Proxy(r_logic) => {
+                                let remote_address =
+                                    self_.safe_lock(|x| x.get_remote_address()).unwrap();
+                                Some(
+                                    r_logic
+                                        .safe_lock(|r_logic| {
+                                            r_logic.on_setup_connection(&m, remote_address)
+                                        })
+                                        .unwrap(),
+                                )
+                            }
+                            CommonRoutingLogic::None => None,
+                        };
+                        Self::handle_setup_connection(self_, m, result).await
+                    }
+                    Err(e) => Ok(SendTo::Respond(CommonMessages::SetupConnectionError(e))),
+                }
+            }
+            Ok(CommonMessages::SetupConnectionSuccess(_)) => Err(Error::UnexpectedMessage),
+            Ok(CommonMessages::SetupConnectionError(_)) => Err(Error::UnexpectedMessage),
+            Ok(CommonMessages::ChannelEndpointChanged(_)) => Err(Error::UnexpectedMessage),
+            Err(e) => Err(e),
+        };
+        conformance::check_responses(&guard, &result);
+        result
+    }
+
+    /// See `ParseDownstreamCommonMessages::get_supported_versions`
+    fn get_supported_versions(&self) -> (u16, u16) {
+        (SV2_MIN_PROTOCOL_VERSION, SV2_MAX_PROTOCOL_VERSION)
+    }
+
+    /// See `ParseDownstreamCommonMessages::on_version_negotiated`
+    fn on_version_negotiated(&mut self, _version: u16) {}
+
+    /// Address of the downstream passed to the router, None if unknown
+    fn get_remote_address(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn handle_setup_connection(
+        self_: Arc<Mutex<Self>>,
+        m: SetupConnection<'_>,
+        result: Option<Result<(CommonDownstreamData, SetupConnectionSuccess), Error>>,
+    ) -> Result<SendTo, Error>;
+}
@@ -1,4 +1,12 @@
-use crate::{common_properties::RequestIdMapper, errors::Error, parsers::Mining};
+use crate::{
+    common_properties::RequestIdMapper,
+    conformance::{self, ConformanceGuard},
+    error_codes::open_mining_channel_error,
+    errors::{Error, RoutingError},
+    extensions::{self, Extensions},
+    message_registry::validate_header,
+    parsers::Mining,
+};
 use core::convert::TryInto;
 use mining_sv2::{
     CloseChannel, NewExtendedMiningJob, NewMiningJob, OpenExtendedMiningChannel,
@@ -16,12 +24,15 @@ use crate::{
 };
 
 use super::SendTo_;
+use const_sv2::EXTENSION_TYPE_NO_EXTENSION;
+use framing_sv2::header::Header;
 
 use crate::utils::Mutex;
 use std::{fmt::Debug as D, sync::Arc};
 
 pub type SendTo<Remote> = SendTo_<Mining<'static>, Remote>;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SupportedChannelTypes {
     Standard,
     Extended,
@@ -30,6 +41,59 @@ pub enum SupportedChannelTypes {
     GroupAndExtended,
 }
 
+use SupportedChannelTypes::{Extended, Group, GroupAndExtended, Standard};
+
+impl std::str::FromStr for SupportedChannelTypes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standard" => Ok(Standard),
+            "extended" => Ok(Extended),
+            "group" => Ok(Group),
+            "group-and-extended" => Ok(GroupAndExtended),
+            _ => Err(format!("Unknown channel types {}", s)),
+        }
+    }
+}
+
+/// Channel types that can receive a message, see `dispatch_mining!`
+pub(crate) const ANY_CHANNEL: &[SupportedChannelTypes] =
+    &[Standard, Extended, Group, GroupAndExtended];
+pub(crate) const STANDARD_CHANNELS: &[SupportedChannelTypes] = &[Standard, Group, GroupAndExtended];
+pub(crate) const EXTENDED_CHANNELS: &[SupportedChannelTypes] = &[Extended, GroupAndExtended];
+const GROUP_CHANNELS: &[SupportedChannelTypes] = &[Group, GroupAndExtended];
+const STANDARD_JOB_CHANNELS: &[SupportedChannelTypes] = &[Standard];
+pub(crate) const EXTENDED_JOB_CHANNELS: &[SupportedChannelTypes] =
+    &[Extended, Group, GroupAndExtended];
+
+/// Dispatch a parsed mining message to its handler. Every row of the table is:
+///
+/// `Variant(m) => allowed channel types, require work selection, |self_| handler`
+///
+/// The handler is called with the node locked only if the channel type of the node is one of the
+/// allowed ones and, for the rows that require it, if work selection is enabled. Otherwise (and
+/// for the messages that are not in the table) the message is unexpected.
+macro_rules! dispatch_mining {
+    (
+        $self_mutex:expr, $channel_type:expr, $work_selection:expr, $message:expr,
+        { $($variant:ident($m:ident) => $allowed:expr, $require_ws:expr, |$s:ident| $handler:expr;)* }
+    ) => {
+        match $message {
+            $(Ok(Mining::$variant($m)) => {
+                if $allowed.contains(&$channel_type) && ($work_selection || !$require_ws) {
+                    // Is fine to unwrap on safe_lock
+                    $self_mutex.safe_lock(|$s| $handler).unwrap()
+                } else {
+                    Err(Error::UnexpectedMessage)
+                }
+            })*
+            Ok(_) => Err(Error::UnexpectedMessage),
+            Err(e) => Err(e),
+        }
+    };
+}
+
 /// Connection-wide downtream's messages parser implemented by an upstream.
 pub trait ParseDownstreamMiningMessages<
     Up: IsMiningUpstream<Self, Selector> + D,
@@ -40,118 +104,90 @@ pub trait ParseDownstreamMiningMessages<
 {
     fn get_channel_type(&self) -> SupportedChannelTypes;
 
+    /// Extensions supported by the node, frames of an extension are passed to
+    /// `extensions::dispatch`. Nodes that do not support extensions return None
+    fn get_extensions(&self) -> Option<Arc<Mutex<Extensions>>> {
+        None
+    }
+
+    /// Guard that check the ordering of the messages of the connection, see `conformance`. It must
+    /// be the same guard returned by the common handler of the node
+    fn get_conformance_guard(&self) -> Option<Arc<Mutex<ConformanceGuard>>> {
+        None
+    }
+
     fn handle_message_mining(
         self_mutex: Arc<Mutex<Self>>,
-        message_type: u8,
+        header: Header,
         payload: &mut [u8],
         routing_logic: MiningRoutingLogic<Self, Up, Selector, Router>,
     ) -> Result<SendTo<Up>, Error>
     where
         Self: IsMiningDownstream + Sized,
     {
-        let (channel_type, is_work_selection_enabled, downstream_mining_data) = self_mutex
+        let (channel_type, is_work_selection_enabled, downstream_mining_data, guard) = self_mutex
             .safe_lock(|self_| {
                 (
                     self_.get_channel_type(),
                     self_.is_work_selection_enabled(),
                     self_.get_downstream_mining_data(),
+                    self_.get_conformance_guard(),
                 )
             })
             .unwrap();
         // Is fine to unwrap on safe_lock
-        match (message_type, payload).try_into() {
-            Ok(Mining::OpenStandardMiningChannel(mut m)) => {
-                let upstream = match routing_logic {
-                    MiningRoutingLogic::None => None,
-                    MiningRoutingLogic::Proxy(r_logic) => {
-                        let up = r_logic
-                            .safe_lock(|r_logic| {
-                                r_logic.on_open_standard_channel(
-                                    self_mutex.clone(),
-                                    &mut m,
-                                    &downstream_mining_data,
-                                )
-                            })
-                            .unwrap();
-                        Some(up?)
+        if header.ext_type_without_channel_msg() != EXTENSION_TYPE_NO_EXTENSION {
+            let extensions = self_mutex.safe_lock(|x| x.get_extensions()).unwrap();
+            return extensions::dispatch(extensions, &header, payload).map(SendTo::Extension);
+        }
+        let message_type = validate_header(&header)?;
+        let mut message: Result<Mining, Error> = (message_type, payload).try_into();
+        if let Ok(message) = &message {
+            conformance::check_received(&guard, message)?;
+        }
+        // The proxies choose the upstream of a new channel before the channel is opened
+        let upstream = match (&mut message, routing_logic) {
+            (Ok(Mining::OpenStandardMiningChannel(m)), MiningRoutingLogic::Proxy(r_logic)) => {
+                let request_id = m.get_request_id_as_u32();
+                let up = r_logic
+                    .safe_lock(|r_logic| {
+                        r_logic.on_open_standard_channel(
+                            self_mutex.clone(),
+                            m,
+                            &downstream_mining_data,
+                        )
+                    })
+                    .unwrap();
+                match up {
+                    Ok(up) => Some(up),
+                    // The channel can not be routed, the downstream is told why
+                    Err(Error::Routing(e)) => {
+                        let error = open_mining_channel_error(request_id, e.into());
+                        let result = Ok(SendTo::Respond(Mining::OpenMiningChannelError(error)));
+                        conformance::check_responses(&guard, &result);
+                        return result;
                     }
-                    // Variant just used for phantom data is ok to panic
-                    MiningRoutingLogic::_P(_) => panic!(),
-                };
-                match channel_type {
-                    SupportedChannelTypes::Standard => self_mutex
-                        .safe_lock(|self_| self_.handle_open_standard_mining_channel(m, upstream))
-                        .unwrap(),
-                    SupportedChannelTypes::Extended => Err(Error::UnexpectedMessage),
-                    SupportedChannelTypes::Group => self_mutex
-                        .safe_lock(|self_| self_.handle_open_standard_mining_channel(m, upstream))
-                        .unwrap(),
-                    SupportedChannelTypes::GroupAndExtended => self_mutex
-                        .safe_lock(|self_| self_.handle_open_standard_mining_channel(m, upstream))
-                        .unwrap(),
+                    Err(e) => return Err(e),
                 }
             }
-            Ok(Mining::OpenExtendedMiningChannel(m)) => match channel_type {
-                SupportedChannelTypes::Standard => Err(Error::UnexpectedMessage),
-                SupportedChannelTypes::Extended => self_mutex
-                    .safe_lock(|self_| self_.handle_open_extended_mining_channel(m))
-                    .unwrap(),
-                SupportedChannelTypes::Group => Err(Error::UnexpectedMessage),
-                SupportedChannelTypes::GroupAndExtended => self_mutex
-                    .safe_lock(|self_| self_.handle_open_extended_mining_channel(m))
-                    .unwrap(),
-            },
-            Ok(Mining::UpdateChannel(m)) => match channel_type {
-                SupportedChannelTypes::Standard => self_mutex
-                    .safe_lock(|self_| self_.handle_update_channel(m))
-                    .unwrap(),
-                SupportedChannelTypes::Extended => self_mutex
-                    .safe_lock(|self_| self_.handle_update_channel(m))
-                    .unwrap(),
-                SupportedChannelTypes::Group => self_mutex
-                    .safe_lock(|self_| self_.handle_update_channel(m))
-                    .unwrap(),
-                SupportedChannelTypes::GroupAndExtended => self_mutex
-                    .safe_lock(|self_| self_.handle_update_channel(m))
-                    .unwrap(),
-            },
-            Ok(Mining::SubmitSharesStandard(m)) => match channel_type {
-                SupportedChannelTypes::Standard => self_mutex
-                    .safe_lock(|self_| self_.handle_submit_shares_standard(m))
-                    .unwrap(),
-                SupportedChannelTypes::Extended => Err(Error::UnexpectedMessage),
-                SupportedChannelTypes::Group => self_mutex
-                    .safe_lock(|self_| self_.handle_submit_shares_standard(m))
-                    .unwrap(),
-                SupportedChannelTypes::GroupAndExtended => self_mutex
-                    .safe_lock(|self_| self_.handle_submit_shares_standard(m))
-                    .unwrap(),
-            },
-            Ok(Mining::SubmitSharesExtended(m)) => match channel_type {
-                SupportedChannelTypes::Standard => Err(Error::UnexpectedMessage),
-                SupportedChannelTypes::Extended => self_mutex
-                    .safe_lock(|self_| self_.handle_submit_shares_extended(m))
-                    .unwrap(),
-                SupportedChannelTypes::Group => Err(Error::UnexpectedMessage),
-                SupportedChannelTypes::GroupAndExtended => self_mutex
-                    .safe_lock(|self_| self_.handle_submit_shares_extended(m))
-                    .unwrap(),
-            },
-            Ok(Mining::SetCustomMiningJob(m)) => match (channel_type, is_work_selection_enabled) {
-                (SupportedChannelTypes::Extended, true) => self_mutex
-                    .safe_lock(|self_| self_.handle_set_custom_mining_job(m))
-                    .unwrap(),
-                (SupportedChannelTypes::Group, true) => self_mutex
-                    .safe_lock(|self_| self_.handle_set_custom_mining_job(m))
-                    .unwrap(),
-                (SupportedChannelTypes::GroupAndExtended, true) => self_mutex
-                    .safe_lock(|self_| self_.handle_set_custom_mining_job(m))
-                    .unwrap(),
-                _ => Err(Error::UnexpectedMessage),
-            },
-            Ok(_) => Err(Error::UnexpectedMessage),
-            Err(e) => Err(e),
-        }
+            (_, MiningRoutingLogic::_P(_)) => return Err(RoutingError::InvalidRoutingLogic.into()),
+            _ => None,
+        };
+        let result = dispatch_mining!(self_mutex, channel_type, is_work_selection_enabled, message, {
+            OpenStandardMiningChannel(m) => STANDARD_CHANNELS, false,
+                |s| s.handle_open_standard_mining_channel(m, upstream);
+            OpenExtendedMiningChannel(m) => EXTENDED_CHANNELS, false,
+                |s| s.handle_open_extended_mining_channel(m);
+            UpdateChannel(m) => ANY_CHANNEL, false, |s| s.handle_update_channel(m);
+            SubmitSharesStandard(m) => STANDARD_CHANNELS, false,
+                |s| s.handle_submit_shares_standard(m);
+            SubmitSharesExtended(m) => EXTENDED_CHANNELS, false,
+                |s| s.handle_submit_shares_extended(m);
+            SetCustomMiningJob(m) => EXTENDED_JOB_CHANNELS, true,
+                |s| s.handle_set_custom_mining_job(m);
+        });
+        conformance::check_responses(&guard, &result);
+        result
     }
 
     fn is_work_selection_enabled(&self) -> bool;
@@ -195,246 +231,90 @@ pub trait ParseUpstreamMiningMessages<
         None
     }
 
+    /// Extensions supported by the node, frames of an extension are passed to
+    /// `extensions::dispatch`. Nodes that do not support extensions return None
+    fn get_extensions(&self) -> Option<Arc<Mutex<Extensions>>> {
+        None
+    }
+
+    /// Guard that check the ordering of the messages of the connection, see `conformance`. It must
+    /// be the same guard returned by the common handler of the node
+    fn get_conformance_guard(&self) -> Option<Arc<Mutex<ConformanceGuard>>> {
+        None
+    }
+
     /// Proxies likely would want to update a downstream req id to a new one as req id must be
     /// connection-wide unique
     /// The implementor of DownstreamMining need to pass a RequestIdMapper if want to change the req id
     fn handle_message_mining(
         self_mutex: Arc<Mutex<Self>>,
-        message_type: u8,
+        header: Header,
         payload: &mut [u8],
         routing_logic: MiningRoutingLogic<Down, Self, Selector, Router>,
     ) -> Result<SendTo<Down>, Error> {
         // Is fine to unwrap on safe_lock
-        let (channel_type, is_work_selection_enabled) = self_mutex
-            .safe_lock(|s| (s.get_channel_type(), s.is_work_selection_enabled()))
+        let (channel_type, is_work_selection_enabled, guard) = self_mutex
+            .safe_lock(|s| {
+                (
+                    s.get_channel_type(),
+                    s.is_work_selection_enabled(),
+                    s.get_conformance_guard(),
+                )
+            })
             .unwrap();
 
         // Is fine to unwrap on safe_lock
-        match (message_type, payload).try_into() {
-            Ok(Mining::OpenStandardMiningChannelSuccess(mut m)) => {
-                let remote = match routing_logic {
-                    MiningRoutingLogic::None => None,
-                    MiningRoutingLogic::Proxy(r_logic) => {
-                        let up = r_logic
-                            .safe_lock(|r_logic| {
-                                r_logic.on_open_standard_channel_success(self_mutex.clone(), &mut m)
-                            })
-                            .unwrap();
-                        Some(up?)
-                    }
-                    // Variant just used for phantom data is ok to panic
-                    MiningRoutingLogic::_P(_) => panic!(),
-                };
-                match channel_type {
-                    SupportedChannelTypes::Standard => self_mutex
-                        .safe_lock(|s| s.handle_open_standard_mining_channel_success(m, remote))
-                        .unwrap(),
-                    SupportedChannelTypes::Extended => Err(Error::UnexpectedMessage),
-                    SupportedChannelTypes::Group => self_mutex
-                        .safe_lock(|s| s.handle_open_standard_mining_channel_success(m, remote))
-                        .unwrap(),
-                    SupportedChannelTypes::GroupAndExtended => self_mutex
-                        .safe_lock(|s| s.handle_open_standard_mining_channel_success(m, remote))
-                        .unwrap(),
-                }
-            }
-            Ok(Mining::OpenExtendedMiningChannelSuccess(m)) => match channel_type {
-                SupportedChannelTypes::Standard => Err(Error::UnexpectedMessage),
-                SupportedChannelTypes::Extended => self_mutex
-                    .safe_lock(|s| s.handle_open_extended_mining_channel_success(m))
-                    .unwrap(),
-                SupportedChannelTypes::Group => Err(Error::UnexpectedMessage),
-                SupportedChannelTypes::GroupAndExtended => self_mutex
-                    .safe_lock(|s| s.handle_open_extended_mining_channel_success(m))
-                    .unwrap(),
-            },
-            Ok(Mining::OpenMiningChannelError(m)) => match channel_type {
-                SupportedChannelTypes::Standard => self_mutex
-                    .safe_lock(|x| x.handle_open_mining_channel_error(m))
-                    .unwrap(),
-                SupportedChannelTypes::Extended => self_mutex
-                    .safe_lock(|x| x.handle_open_mining_channel_error(m))
-                    .unwrap(),
-                SupportedChannelTypes::Group => self_mutex
-                    .safe_lock(|x| x.handle_open_mining_channel_error(m))
-                    .unwrap(),
-                SupportedChannelTypes::GroupAndExtended => self_mutex
-                    .safe_lock(|x| x.handle_open_mining_channel_error(m))
-                    .unwrap(),
-            },
-            Ok(Mining::UpdateChannelError(m)) => match channel_type {
-                SupportedChannelTypes::Standard => self_mutex
-                    .safe_lock(|x| x.handle_update_channel_error(m))
-                    .unwrap(),
-                SupportedChannelTypes::Extended => self_mutex
-                    .safe_lock(|x| x.handle_update_channel_error(m))
-                    .unwrap(),
-                SupportedChannelTypes::Group => self_mutex
-                    .safe_lock(|x| x.handle_update_channel_error(m))
-                    .unwrap(),
-                SupportedChannelTypes::GroupAndExtended => self_mutex
-                    .safe_lock(|x| x.handle_update_channel_error(m))
-                    .unwrap(),
-            },
-            Ok(Mining::CloseChannel(m)) => match channel_type {
-                SupportedChannelTypes::Standard => {
-                    self_mutex.safe_lock(|x| x.handle_close_channel(m)).unwrap()
-                }
-                SupportedChannelTypes::Extended => {
-                    self_mutex.safe_lock(|x| x.handle_close_channel(m)).unwrap()
-                }
-                SupportedChannelTypes::Group => {
-                    self_mutex.safe_lock(|x| x.handle_close_channel(m)).unwrap()
-                }
-                SupportedChannelTypes::GroupAndExtended => {
-                    self_mutex.safe_lock(|x| x.handle_close_channel(m)).unwrap()
-                }
-            },
-            Ok(Mining::SetExtranoncePrefix(m)) => match channel_type {
-                SupportedChannelTypes::Standard => self_mutex
-                    .safe_lock(|x| x.handle_set_extranonce_prefix(m))
-                    .unwrap(),
-                SupportedChannelTypes::Extended => self_mutex
-                    .safe_lock(|x| x.handle_set_extranonce_prefix(m))
-                    .unwrap(),
-                SupportedChannelTypes::Group => self_mutex
-                    .safe_lock(|x| x.handle_set_extranonce_prefix(m))
-                    .unwrap(),
-                SupportedChannelTypes::GroupAndExtended => self_mutex
-                    .safe_lock(|x| x.handle_set_extranonce_prefix(m))
-                    .unwrap(),
-            },
-            Ok(Mining::SubmitSharesSuccess(m)) => match channel_type {
-                SupportedChannelTypes::Standard => self_mutex
-                    .safe_lock(|x| x.handle_submit_shares_success(m))
-                    .unwrap(),
-                SupportedChannelTypes::Extended => self_mutex
-                    .safe_lock(|x| x.handle_submit_shares_success(m))
-                    .unwrap(),
-                SupportedChannelTypes::Group => self_mutex
-                    .safe_lock(|x| x.handle_submit_shares_success(m))
-                    .unwrap(),
-                SupportedChannelTypes::GroupAndExtended => self_mutex
-                    .safe_lock(|x| x.handle_submit_shares_success(m))
-                    .unwrap(),
-            },
-            Ok(Mining::SubmitSharesError(m)) => match channel_type {
-                SupportedChannelTypes::Standard => self_mutex
-                    .safe_lock(|x| x.handle_submit_shares_error(m))
-                    .unwrap(),
-                SupportedChannelTypes::Extended => self_mutex
-                    .safe_lock(|x| x.handle_submit_shares_error(m))
-                    .unwrap(),
-                SupportedChannelTypes::Group => self_mutex
-                    .safe_lock(|x| x.handle_submit_shares_error(m))
-                    .unwrap(),
-                SupportedChannelTypes::GroupAndExtended => self_mutex
-                    .safe_lock(|x| x.handle_submit_shares_error(m))
-                    .unwrap(),
-            },
-            Ok(Mining::NewMiningJob(m)) => match channel_type {
-                SupportedChannelTypes::Standard => self_mutex
-                    .safe_lock(|x| x.handle_new_mining_job(m))
-                    .unwrap(),
-                SupportedChannelTypes::Extended => Err(Error::UnexpectedMessage),
-                SupportedChannelTypes::Group => Err(Error::UnexpectedMessage),
-                SupportedChannelTypes::GroupAndExtended => Err(Error::UnexpectedMessage),
-            },
-            Ok(Mining::NewExtendedMiningJob(m)) => match channel_type {
-                SupportedChannelTypes::Standard => Err(Error::UnexpectedMessage),
-                SupportedChannelTypes::Extended => self_mutex
-                    .safe_lock(|x| x.handle_new_extended_mining_job(m))
-                    .unwrap(),
-                SupportedChannelTypes::Group => self_mutex
-                    .safe_lock(|x| x.handle_new_extended_mining_job(m))
-                    .unwrap(),
-                SupportedChannelTypes::GroupAndExtended => self_mutex
-                    .safe_lock(|x| x.handle_new_extended_mining_job(m))
-                    .unwrap(),
-            },
-            Ok(Mining::SetNewPrevHash(m)) => match channel_type {
-                SupportedChannelTypes::Standard => self_mutex
-                    .safe_lock(|x| x.handle_set_new_prev_hash(m))
-                    .unwrap(),
-                SupportedChannelTypes::Extended => self_mutex
-                    .safe_lock(|x| x.handle_set_new_prev_hash(m))
-                    .unwrap(),
-                SupportedChannelTypes::Group => self_mutex
-                    .safe_lock(|x| x.handle_set_new_prev_hash(m))
-                    .unwrap(),
-                SupportedChannelTypes::GroupAndExtended => self_mutex
-                    .safe_lock(|x| x.handle_set_new_prev_hash(m))
-                    .unwrap(),
-            },
-            Ok(Mining::SetCustomMiningJobSuccess(m)) => {
-                match (channel_type, is_work_selection_enabled) {
-                    (SupportedChannelTypes::Extended, true) => self_mutex
-                        .safe_lock(|x| x.handle_set_custom_mining_job_success(m))
-                        .unwrap(),
-                    (SupportedChannelTypes::Group, true) => self_mutex
-                        .safe_lock(|x| x.handle_set_custom_mining_job_success(m))
-                        .unwrap(),
-                    (SupportedChannelTypes::GroupAndExtended, true) => self_mutex
-                        .safe_lock(|x| x.handle_set_custom_mining_job_success(m))
-                        .unwrap(),
-                    _ => Err(Error::UnexpectedMessage),
-                }
-            }
-            Ok(Mining::SetCustomMiningJobError(m)) => {
-                match (channel_type, is_work_selection_enabled) {
-                    (SupportedChannelTypes::Extended, true) => self_mutex
-                        .safe_lock(|x| x.handle_set_custom_mining_job_error(m))
-                        .unwrap(),
-                    (SupportedChannelTypes::Group, true) => self_mutex
-                        .safe_lock(|x| x.handle_set_custom_mining_job_error(m))
-                        .unwrap(),
-                    (SupportedChannelTypes::GroupAndExtended, true) => self_mutex
-                        .safe_lock(|x| x.handle_set_custom_mining_job_error(m))
-                        .unwrap(),
-                    _ => Err(Error::UnexpectedMessage),
-                }
-            }
-            Ok(Mining::SetTarget(m)) => match channel_type {
-                SupportedChannelTypes::Standard => {
-                    self_mutex.safe_lock(|x| x.handle_set_target(m)).unwrap()
-                }
-                SupportedChannelTypes::Extended => {
-                    self_mutex.safe_lock(|x| x.handle_set_target(m)).unwrap()
-                }
-                SupportedChannelTypes::Group => {
-                    self_mutex.safe_lock(|x| x.handle_set_target(m)).unwrap()
-                }
-                SupportedChannelTypes::GroupAndExtended => {
-                    self_mutex.safe_lock(|x| x.handle_set_target(m)).unwrap()
-                }
-            },
-            Ok(Mining::Reconnect(m)) => match channel_type {
-                SupportedChannelTypes::Standard => {
-                    self_mutex.safe_lock(|x| x.handle_reconnect(m)).unwrap()
-                }
-                SupportedChannelTypes::Extended => {
-                    self_mutex.safe_lock(|x| x.handle_reconnect(m)).unwrap()
-                }
-                SupportedChannelTypes::Group => {
-                    self_mutex.safe_lock(|x| x.handle_reconnect(m)).unwrap()
-                }
-                SupportedChannelTypes::GroupAndExtended => {
-                    self_mutex.safe_lock(|x| x.handle_reconnect(m)).unwrap()
-                }
-            },
-            Ok(Mining::SetGroupChannel(m)) => match channel_type {
-                SupportedChannelTypes::Standard => Err(Error::UnexpectedMessage),
-                SupportedChannelTypes::Extended => Err(Error::UnexpectedMessage),
-                SupportedChannelTypes::Group => self_mutex
-                    .safe_lock(|x| x.handle_set_group_channel(m))
-                    .unwrap(),
-                SupportedChannelTypes::GroupAndExtended => self_mutex
-                    .safe_lock(|x| x.handle_set_group_channel(m))
-                    .unwrap(),
-            },
-            Ok(_) => Err(Error::UnexpectedMessage),
-            Err(e) => Err(e),
+        if header.ext_type_without_channel_msg() != EXTENSION_TYPE_NO_EXTENSION {
+            let extensions = self_mutex.safe_lock(|x| x.get_extensions()).unwrap();
+            return extensions::dispatch(extensions, &header, payload).map(SendTo::Extension);
+        }
+        let message_type = validate_header(&header)?;
+        let mut message: Result<Mining, Error> = (message_type, payload).try_into();
+        if let Ok(message) = &message {
+            conformance::check_received(&guard, message)?;
         }
+        // The proxies find the downstream that requested the channel
+        let remote = match (&mut message, routing_logic) {
+            (
+                Ok(Mining::OpenStandardMiningChannelSuccess(m)),
+                MiningRoutingLogic::Proxy(r_logic),
+            ) => {
+                let down = r_logic
+                    .safe_lock(|r_logic| {
+                        r_logic.on_open_standard_channel_success(self_mutex.clone(), m)
+                    })
+                    .unwrap();
+                Some(down?)
+            }
+            (_, MiningRoutingLogic::_P(_)) => return Err(RoutingError::InvalidRoutingLogic.into()),
+            _ => None,
+        };
+        let result = dispatch_mining!(self_mutex, channel_type, is_work_selection_enabled, message, {
+            OpenStandardMiningChannelSuccess(m) => STANDARD_CHANNELS, false,
+                |s| s.handle_open_standard_mining_channel_success(m, remote);
+            OpenExtendedMiningChannelSuccess(m) => EXTENDED_CHANNELS, false,
+                |s| s.handle_open_extended_mining_channel_success(m);
+            OpenMiningChannelError(m) => ANY_CHANNEL, false,
+                |s| s.handle_open_mining_channel_error(m);
+            UpdateChannelError(m) => ANY_CHANNEL, false, |s| s.handle_update_channel_error(m);
+            CloseChannel(m) => ANY_CHANNEL, false, |s| s.handle_close_channel(m);
+            SetExtranoncePrefix(m) => ANY_CHANNEL, false, |s| s.handle_set_extranonce_prefix(m);
+            SubmitSharesSuccess(m) => ANY_CHANNEL, false, |s| s.handle_submit_shares_success(m);
+            SubmitSharesError(m) => ANY_CHANNEL, false, |s| s.handle_submit_shares_error(m);
+            NewMiningJob(m) => STANDARD_JOB_CHANNELS, false, |s| s.handle_new_mining_job(m);
+            NewExtendedMiningJob(m) => EXTENDED_JOB_CHANNELS, false,
+                |s| s.handle_new_extended_mining_job(m);
+            SetNewPrevHash(m) => ANY_CHANNEL, false, |s| s.handle_set_new_prev_hash(m);
+            SetCustomMiningJobSuccess(m) => EXTENDED_JOB_CHANNELS, true,
+                |s| s.handle_set_custom_mining_job_success(m);
+            SetCustomMiningJobError(m) => EXTENDED_JOB_CHANNELS, true,
+                |s| s.handle_set_custom_mining_job_error(m);
+            SetTarget(m) => ANY_CHANNEL, false, |s| s.handle_set_target(m);
+            Reconnect(m) => ANY_CHANNEL, false, |s| s.handle_reconnect(m);
+            SetGroupChannel(m) => GROUP_CHANNELS, false, |s| s.handle_set_group_channel(m);
+        });
+        conformance::check_responses(&guard, &result);
+        result
     }
 
     fn is_work_selection_enabled(&self) -> bool;
@@ -0,0 +1,181 @@
+//! Async variant of `ParseDownstreamMiningMessages` (feature `async_handlers`).
+//!
+//! The handlers of `ParseDownstreamMiningMessages` are called with the node locked, so a pool that
+//! check a channel (or record a share) against a database or a webhook has to block while
+//! holding the lock. The handlers of `AsyncParseDownstreamMiningMessages` are async and get the
+//! node mutex instead of `&mut self`: they lock the node only for the state updates and can await
+//! between them. The node must not be locked across an await.
+//!
+//! The message is parsed, checked (channel type, work selection, conformance) and routed exactly
+//! like in the sync handler.
+use super::mining::{
+    SendTo, SupportedChannelTypes, ANY_CHANNEL, EXTENDED_CHANNELS, EXTENDED_JOB_CHANNELS,
+    STANDARD_CHANNELS,
+};
+use crate::{
+    common_properties::{IsMiningDownstream, IsMiningUpstream},
+    conformance::{self, ConformanceGuard},
+    error_codes::open_mining_channel_error,
+    errors::{Error, RoutingError},
+    extensions::{self, Extensions},
+    message_registry::validate_header,
+    parsers::Mining,
+    routing_logic::{MiningRouter, MiningRoutingLogic},
+    selectors::DownstreamMiningSelector,
+    utils::Mutex,
+};
+use async_trait::async_trait;
+use const_sv2::EXTENSION_TYPE_NO_EXTENSION;
+use core::convert::TryInto;
+use framing_sv2::header::Header;
+use mining_sv2::{
+    OpenExtendedMiningChannel, OpenStandardMiningChannel, SetCustomMiningJob, SubmitSharesExtended,
+    SubmitSharesStandard, UpdateChannel,
+};
+use std::{fmt::Debug as D, sync::Arc};
+
+/// Like `dispatch_mining!` but the handlers are awaited without locking the node
+macro_rules! dispatch_mining_async {
+    (
+        $channel_type:expr, $work_selection:expr, $message:expr,
+        { $($variant:ident($m:ident) => $allowed:expr, $require_ws:expr, $handler:expr;)* }
+    ) => {
+        match $message {
+            $(Ok(Mining::$variant($m)) => {
+                if $allowed.contains(&$channel_type) && ($work_selection || !$require_ws) {
+                    $handler.await
+                } else {
+                    Err(Error::UnexpectedMessage)
+                }
+            })*
+            Ok(_) => Err(Error::UnexpectedMessage),
+            Err(e) => Err(e),
+        }
+    };
+}
+
+/// Connection-wide downtream's messages parser implemented by an upstream, with async handlers
+#[async_trait]
+pub trait AsyncParseDownstreamMiningMessages<
+    Up: IsMiningUpstream<Self, Selector> + D + Send + 'static,
+    Selector: DownstreamMiningSelector<Self> + D + Send + 'static,
+    Router: MiningRouter<Self, Up, Selector> + Send,
+> where
+    Self: IsMiningDownstream + Sized + D + Send,
+{
+    fn get_channel_type(&self) -> SupportedChannelTypes;
+
+    /// See `ParseDownstreamMiningMessages::get_extensions`
+    fn get_extensions(&self) -> Option<Arc<Mutex<Extensions>>> {
+        None
+    }
+
+    /// See `ParseDownstreamMiningMessages::get_conformance_guard`
+    fn get_conformance_guard(&self) -> Option<Arc<Mutex<ConformanceGuard>>> {
+        None
+    }
+
+    async fn handle_message_mining(
+        self_mutex: Arc<Mutex<Self>>,
+        header: Header,
+        payload: &mut [u8],
+        routing_logic: MiningRoutingLogic<Self, Up, Selector, Router>,
+    ) -> Result<SendTo<Up>, Error> {
+        // Is fine to unwrap on safe_lock
+        let (channel_type, is_work_selection_enabled, downstream_mining_data, guard) = self_mutex
+            .safe_lock(|self_| {
+                (
+                    self_.get_channel_type(),
+                    self_.is_work_selection_enabled(),
+                    self_.get_downstream_mining_data(),
+                    self_.get_conformance_guard(),
+                )
+            })
+            .unwrap();
+        if header.ext_type_without_channel_msg() != EXTENSION_TYPE_NO_EXTENSION {
+            let extensions = self_mutex.safe_lock(|x| x.get_extensions()).unwrap();
+            return extensions::dispatch(extensions, &header, payload).map(SendTo::Extension);
+        }
+        let message_type = validate_header(&header)?;
+        let mut message: Result<Mining, Error> = (message_type, payload).try_into();
+        if let Ok(message) = &message {
+            conformance::check_received(&guard, message)?;
+        }
+        // The proxies choose the upstream of a new channel before the channel is opened
+        let upstream = match (&mut message, routing_logic) {
+            (Ok(Mining::OpenStandardMiningChannel(m)), MiningRoutingLogic::Proxy(r_logic)) => {
+                let request_id = m.get_request_id_as_u32();
+                let up = r_logic
+                    .safe_lock(|r_logic| {
+                        r_logic.on_open_standard_channel(
+                            self_mutex.clone(),
+                            m,
+                            &downstream_mining_data,
+                        )
+                    })
+                    .unwrap();
+                match up {
+                    Ok(up) => Some(up),
+                    // The channel can not be routed, the downstream is told why
+                    Err(Error::Routing(e)) => {
+                        let error = open_mining_channel_error(request_id, e.into());
+                        let result = Ok(SendTo::Respond(Mining::OpenMiningChannelError(error)));
+                        conformance::check_responses(&guard, &result);
+                        return result;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            (_, MiningRoutingLogic::_P(_)) => return Err(RoutingError::InvalidRoutingLogic.into()),
+            _ => None,
+        };
+        let result = dispatch_mining_async!(channel_type, is_work_selection_enabled, message, {
+            OpenStandardMiningChannel(m) => STANDARD_CHANNELS, false,
+                Self::handle_open_standard_mining_channel(self_mutex, m, upstream);
+            OpenExtendedMiningChannel(m) => EXTENDED_CHANNELS, false,
+                Self::handle_open_extended_mining_channel(self_mutex, m);
+            UpdateChannel(m) => ANY_CHANNEL, false, Self::handle_update_channel(self_mutex, m);
+            SubmitSharesStandard(m) => STANDARD_CHANNELS, false,
+                Self::handle_submit_shares_standard(self_mutex, m);
+            SubmitSharesExtended(m) => EXTENDED_CHANNELS, false,
+                Self::handle_submit_shares_extended(self_mutex, m);
+            SetCustomMiningJob(m) => EXTENDED_JOB_CHANNELS, true,
+                Self::handle_set_custom_mining_job(self_mutex, m);
+        });
+        conformance::check_responses(&guard, &result);
+        result
+    }
+
+    fn is_work_selection_enabled(&self) -> bool;
+
+    async fn handle_open_standard_mining_channel(
+        self_mutex: Arc<Mutex<Self>>,
+        m: OpenStandardMiningChannel<'_>,
+        up: Option<Arc<Mutex<Up>>>,
+    ) -> Result<SendTo<Up>, Error>;
+
+    async fn handle_open_extended_mining_channel(
+        self_mutex: Arc<Mutex<Self>>,
+        m: OpenExtendedMiningChannel<'_>,
+    ) -> Result<SendTo<Up>, Error>;
+
+    async fn handle_update_channel(
+        self_mutex: Arc<Mutex<Self>>,
+        m: UpdateChannel<'_>,
+    ) -> Result<SendTo<Up>, Error>;
+
+    async fn handle_submit_shares_standard(
+        self_mutex: Arc<Mutex<Self>>,
+        m: SubmitSharesStandard,
+    ) -> Result<SendTo<Up>, Error>;
+
+    async fn handle_submit_shares_extended(
+        self_mutex: Arc<Mutex<Self>>,
+        m: SubmitSharesExtended<'_>,
+    ) -> Result<SendTo<Up>, Error>;
+
+    async fn handle_set_custom_mining_job(
+        self_mutex: Arc<Mutex<Self>>,
+        m: SetCustomMiningJob<'_>,
+    ) -> Result<SendTo<Up>, Error>;
+}
@@ -19,10 +19,17 @@
 //! called with the Sv2 message and the remote that must receive the message.
 //!
 //! A Result<SendTo_, Error> is returned and is duty of the implementor to send the message
+//!
+//! With the feature `async_handlers` the downstream handlers have an async variant
+//! (AsyncParseDownstream[(sub)protocol]) for the nodes that do I/O while handling a message.
 pub mod common;
+#[cfg(feature = "async_handlers")]
+pub mod common_async;
 pub mod mining;
+#[cfg(feature = "async_handlers")]
+pub mod mining_async;
 pub mod template_distribution;
-use crate::utils::Mutex;
+use crate::{extensions::ExtensionMessage, utils::Mutex};
 use std::sync::Arc;
 
 /// SubProtocol is the Sv2 (sub)protocol that the implementor is implementing (eg: mining, common,
@@ -40,6 +47,8 @@ pub enum SendTo_<SubProtocol, Remote> {
     Multiple(Vec<SendTo_<SubProtocol, Remote>>),
     /// Used by proxyies and other roles when no messages need to be sent.
     None(Option<SubProtocol>),
+    /// Messages of a protocol extension that must be sent to the remote, see `extensions`
+    Extension(Vec<ExtensionMessage>),
 }
 
 impl<SubProtocol, Remote> SendTo_<SubProtocol, Remote> {
@@ -50,6 +59,7 @@ impl<SubProtocol, Remote> SendTo_<SubProtocol, Remote> {
             Self::Respond(m) => Some(m),
             Self::Multiple(_) => None,
             Self::None(m) => m,
+            Self::Extension(_) => None,
         }
     }
     pub fn into_remote(self) -> Option<Arc<Mutex<Remote>>> {
@@ -59,6 +69,7 @@ impl<SubProtocol, Remote> SendTo_<SubProtocol, Remote> {
             Self::Respond(_) => None,
             Self::Multiple(_) => None,
             Self::None(_) => None,
+            Self::Extension(_) => None,
         }
     }
 }
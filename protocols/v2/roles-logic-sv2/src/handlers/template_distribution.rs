@@ -1,5 +1,13 @@
 use super::SendTo_;
-use crate::{errors::Error, parsers::TemplateDistribution, utils::Mutex};
+use crate::{
+    errors::Error,
+    extensions::{self, Extensions},
+    message_registry::validate_header,
+    parsers::TemplateDistribution,
+    utils::Mutex,
+};
+use const_sv2::EXTENSION_TYPE_NO_EXTENSION;
+use framing_sv2::header::Header;
 use template_distribution_sv2::{
     CoinbaseOutputDataSize, NewTemplate, RequestTransactionData, RequestTransactionDataError,
     RequestTransactionDataSuccess, SetNewPrevHash, SubmitSolution,
@@ -13,12 +21,23 @@ pub trait ParseServerTemplateDistributionMessages
 where
     Self: Sized,
 {
+    /// Extensions supported by the node, frames of an extension are passed to
+    /// `extensions::dispatch`. Nodes that do not support extensions return None
+    fn get_extensions(&self) -> Option<Arc<Mutex<Extensions>>> {
+        None
+    }
+
     fn handle_message_template_distribution(
         self_: Arc<Mutex<Self>>,
-        message_type: u8,
+        header: Header,
         payload: &mut [u8],
     ) -> Result<SendTo, Error> {
         // Is ok to unwrap a safe_lock result
+        if header.ext_type_without_channel_msg() != EXTENSION_TYPE_NO_EXTENSION {
+            let extensions = self_.safe_lock(|x| x.get_extensions()).unwrap();
+            return extensions::dispatch(extensions, &header, payload).map(SendTo::Extension);
+        }
+        let message_type = validate_header(&header)?;
         match (message_type, payload).try_into() {
             Ok(TemplateDistribution::NewTemplate(m)) => {
                 self_.safe_lock(|x| x.handle_new_template(m)).unwrap()
@@ -54,12 +73,23 @@ pub trait ParseClientTemplateDistributionMessages
 where
     Self: Sized,
 {
+    /// Extensions supported by the node, frames of an extension are passed to
+    /// `extensions::dispatch`. Nodes that do not support extensions return None
+    fn get_extensions(&self) -> Option<Arc<Mutex<Extensions>>> {
+        None
+    }
+
     fn handle_message_template_distribution(
         self_: Arc<Mutex<Self>>,
-        message_type: u8,
+        header: Header,
         payload: &mut [u8],
     ) -> Result<SendTo, Error> {
         // Is ok to unwrap a safe_lock result
+        if header.ext_type_without_channel_msg() != EXTENSION_TYPE_NO_EXTENSION {
+            let extensions = self_.safe_lock(|x| x.get_extensions()).unwrap();
+            return extensions::dispatch(extensions, &header, payload).map(SendTo::Extension);
+        }
+        let message_type = validate_header(&header)?;
         match (message_type, payload).try_into() {
             Ok(TemplateDistribution::CoinbaseOutputDataSize(m)) => self_
                 .safe_lock(|x| x.handle_coinbase_out_data_size(m))
@@ -1,23 +1,211 @@
-use crate::{errors::Error, utils::Id};
+//! Jobs of a pool built from the templates of a Template Provider.
+//!
+//! The free functions convert a `NewTemplate` in a `NewExtendedMiningJob` for a channel:
+//! `extended_job_from_template` build the coinbase (the outputs of the pool followed by the
+//! outputs required by the template) and split it around the extranonce, `mining_prev_hash`
+//! build the SetNewPrevHash that activate the (future) job of a template. `JobsCreators` use them
+//! to keep a job for every group channel of the pool.
+//!
+//! When a new block is found the pool can activate an empty block job (see
+//! `empty_block_template`) in place of the job of the template and send the full job right after,
+//! the downstreams start to work on the new prev hash with the smallest jobs possible.
+use crate::{coinbase_outputs::CoinbaseOutputs, errors::Error, utils::Id};
 use binary_sv2::B064K;
 use bitcoin::{
     blockdata::{
         script::Script,
         transaction::{OutPoint, Transaction, TxIn, TxOut},
     },
+    consensus::Decodable,
     util::psbt::serialize::Serialize,
 };
 pub use bitcoin::{
     secp256k1::SecretKey,
     util::ecdsa::{PrivateKey, PublicKey},
 };
-use mining_sv2::NewExtendedMiningJob;
+use mining_sv2::{NewExtendedMiningJob, SetNewPrevHash as MiningPrevHash};
 use std::{collections::HashMap, convert::TryInto};
 use template_distribution_sv2::{NewTemplate, SetNewPrevHash};
 
+/// Serialized coinbase before the input: version (4 bytes)
 const SCRIPT_PREFIX_LEN: usize = 4;
+/// Serialized coinbase before the script_sig: input count (1), prev out (36), script len (1)
 const PREV_OUT_LEN: usize = 38;
-const EXTRANONCE_LEN: usize = 32;
+/// Bytes of the coinbase script reserved for the extranonce
+pub const EXTRANONCE_LEN: usize = 32;
+/// Blocks between two halvings of the subsidy (mainnet, testnet and signet)
+pub const SUBSIDY_HALVING_INTERVAL: u32 = 210_000;
+/// Set in the template id of the empty block jobs. The Template Provider would publish the
+/// solutions of these jobs with the transactions of the template, the pool has to recognize them.
+pub const EMPTY_BLOCK_TEMPLATE: u64 = 1 << 63;
+
+/// BIP34 height (push length and height) that start the coinbase script, read from the
+/// `coinbase_prefix` of a NewTemplate
+pub fn bip34_height_bytes(coinbase_prefix: &[u8]) -> Result<Vec<u8>, Error> {
+    // On a chain at least 16 blocks long the height is pushed with at least 1 byte
+    if coinbase_prefix.len() <= 3 {
+        return Err(Error::InvalidCoinbasePrefix);
+    }
+    let bip34_len = coinbase_prefix[1] as usize;
+    coinbase_prefix
+        .get(1..2 + bip34_len)
+        .map(|bytes| bytes.to_vec())
+        .ok_or(Error::InvalidCoinbasePrefix)
+}
+
+/// Height of the block of a template, read from the BIP34 bytes of its `coinbase_prefix`
+pub fn template_height(coinbase_prefix: &[u8]) -> Result<u32, Error> {
+    let bip34_bytes = bip34_height_bytes(coinbase_prefix)?;
+    // The first byte is the push length, the height is a little endian number
+    if bip34_bytes.len() > 5 {
+        return Err(Error::InvalidCoinbasePrefix);
+    }
+    Ok(bip34_bytes[1..]
+        .iter()
+        .rev()
+        .fold(0, |height, byte| height << 8 | *byte as u32))
+}
+
+/// Subsidy in satoshi of the block at `height`
+pub fn block_subsidy(height: u32) -> u64 {
+    match height / SUBSIDY_HALVING_INTERVAL {
+        halvings if halvings >= 64 => 0,
+        halvings => 5_000_000_000 >> halvings,
+    }
+}
+
+pub fn empty_block_template_id(template_id: u64) -> u64 {
+    template_id | EMPTY_BLOCK_TEMPLATE
+}
+
+pub fn is_empty_block_template(template_id: u64) -> bool {
+    template_id & EMPTY_BLOCK_TEMPLATE != 0
+}
+
+/// Future template of a block without transactions built on the same coinbase of `template`: the
+/// coinbase value is the subsidy only, and the outputs required by the template (that commit to
+/// its transactions, eg the witness commitment) are dropped. The template id is the one of
+/// `template` with the `EMPTY_BLOCK_TEMPLATE` flag.
+pub fn empty_block_template(
+    template: &NewTemplate<'static>,
+) -> Result<NewTemplate<'static>, Error> {
+    let height = template_height(template.coinbase_prefix.inner_as_ref())?;
+    let mut empty = template.clone();
+    empty.template_id = empty_block_template_id(template.template_id);
+    empty.future_template = true;
+    empty.coinbase_tx_value_remaining = block_subsidy(height);
+    empty.coinbase_tx_outputs_count = 0;
+    empty.coinbase_tx_outputs = Vec::new().try_into()?;
+    empty.merkle_path = Vec::new().into();
+    Ok(empty)
+}
+
+/// Outputs that the template require at the end of the coinbase (eg the witness commitment)
+pub fn template_coinbase_outputs(template: &NewTemplate) -> Result<Vec<TxOut>, Error> {
+    let mut outputs = template.coinbase_tx_outputs.inner_as_ref();
+    (0..template.coinbase_tx_outputs_count)
+        .map(|_| TxOut::consensus_decode(&mut outputs).map_err(|_| Error::InvalidCoinbaseOutputs))
+        .collect()
+}
+
+/// Coinbase with the script `bip34_bytes` + `extranonce_len` zeros
+pub fn coinbase(
+    mut bip34_bytes: Vec<u8>,
+    version: i32,
+    lock_time: u32,
+    sequence: u32,
+    coinbase_outputs: &[TxOut],
+    extranonce_len: usize,
+) -> Transaction {
+    bip34_bytes.resize(bip34_bytes.len() + extranonce_len, 0);
+    let tx_in = TxIn {
+        previous_output: OutPoint::null(),
+        script_sig: bip34_bytes.into(),
+        sequence,
+        witness: vec![],
+    };
+    Transaction {
+        version,
+        lock_time,
+        input: vec![tx_in],
+        output: coinbase_outputs.to_vec(),
+    }
+}
+
+/// Split the serialized `coinbase` in the coinbase_tx_prefix and the coinbase_tx_suffix of an
+/// extended job: the prefix end with the first `script_prefix_len` bytes of the coinbase script,
+/// the suffix start after the `extranonce_len` bytes that follow them
+pub fn split_coinbase(
+    coinbase: &Transaction,
+    script_prefix_len: usize,
+    extranonce_len: usize,
+) -> Result<(B064K<'static>, B064K<'static>), Error> {
+    let encoded = coinbase.serialize();
+    let prefix_end = SCRIPT_PREFIX_LEN + PREV_OUT_LEN + script_prefix_len;
+    let suffix_start = prefix_end + extranonce_len;
+    if encoded.len() < suffix_start {
+        return Err(Error::InvalidCoinbasePrefix);
+    }
+    let prefix = encoded[..prefix_end].to_vec().try_into()?;
+    let suffix = encoded[suffix_start..].to_vec().try_into()?;
+    Ok((prefix, suffix))
+}
+
+/// Job `job_id` of the (group or extended) channel `channel_id` built on `template`. The coinbase
+/// pay `pool_outputs` followed by the outputs of the template, its script is the BIP34 height
+/// followed by `EXTRANONCE_LEN` bytes of extranonce. A future template give a future job, that is
+/// activated by the SetNewPrevHash returned by `mining_prev_hash`.
+pub fn extended_job_from_template(
+    template: &NewTemplate,
+    channel_id: u32,
+    job_id: u32,
+    version_rolling_allowed: bool,
+    pool_outputs: &[TxOut],
+) -> Result<NewExtendedMiningJob<'static>, Error> {
+    let bip34_bytes = bip34_height_bytes(template.coinbase_prefix.inner_as_ref())?;
+    let script_prefix_len = bip34_bytes.len();
+    let mut outputs = pool_outputs.to_vec();
+    outputs.extend(template_coinbase_outputs(template)?);
+    let coinbase = coinbase(
+        bip34_bytes,
+        template
+            .coinbase_tx_version
+            .try_into()
+            .map_err(|_| Error::InvalidCoinbasePrefix)?,
+        template.coinbase_tx_locktime,
+        template.coinbase_tx_input_sequence,
+        &outputs,
+        EXTRANONCE_LEN,
+    );
+    let (coinbase_tx_prefix, coinbase_tx_suffix) =
+        split_coinbase(&coinbase, script_prefix_len, EXTRANONCE_LEN)?;
+    Ok(NewExtendedMiningJob {
+        channel_id,
+        job_id,
+        future_job: template.future_template,
+        version: template.version,
+        version_rolling_allowed,
+        merkle_path: template.merkle_path.clone().into_static(),
+        coinbase_tx_prefix,
+        coinbase_tx_suffix,
+    })
+}
+
+/// SetNewPrevHash of the mining protocol that activate the job `job_id` of `channel_id`, built for
+/// the template of `prev_hash`
+pub fn mining_prev_hash(
+    prev_hash: &SetNewPrevHash<'static>,
+    channel_id: u32,
+    job_id: u32,
+) -> MiningPrevHash<'static> {
+    MiningPrevHash {
+        channel_id,
+        job_id,
+        prev_hash: prev_hash.prev_hash.clone(),
+        min_ntime: prev_hash.header_timestamp,
+        nbits: prev_hash.n_bits,
+    }
+}
 
 /// Used by pool one for each group channel
 /// extended and standard channel not supported
@@ -35,40 +223,13 @@ impl JobCreator {
         new_template: &mut NewTemplate,
         coinbase_outputs: &[TxOut],
     ) -> Result<NewExtendedMiningJob<'static>, Error> {
-        assert!(
-            new_template.coinbase_tx_outputs_count == 0,
-            "node provided outputs not supported yet"
-        );
-        let script_prefix = new_template.coinbase_prefix.to_vec();
-        // Is ok to panic here cause condition will be always true when not in a test chain
-        // (regtest ecc ecc)
-        assert!(
-            script_prefix.len() > 3,
-            "Bitcoin blockchain should be at least 16 block long"
-        );
-        let bip34_len = script_prefix[1] as usize;
-        let bip34_bytes = script_prefix[1..2 + bip34_len].to_vec();
-
-        let coinbase = self.coinbase(
-            bip34_bytes,
-            new_template
-                .coinbase_tx_version
-                .try_into()
-                .expect("invalid version"),
-            new_template.coinbase_tx_locktime,
-            new_template.coinbase_tx_input_sequence,
+        let new_extended_mining_job = extended_job_from_template(
+            new_template,
+            self.group_channel_id,
+            self.job_ids.next(),
+            self.version_rolling_allowed,
             coinbase_outputs,
-        );
-        let new_extended_mining_job: NewExtendedMiningJob<'static> = NewExtendedMiningJob {
-            channel_id: self.group_channel_id,
-            job_id: self.job_ids.next(),
-            future_job: new_template.future_template,
-            version: new_template.version,
-            version_rolling_allowed: self.version_rolling_allowed,
-            merkle_path: new_template.merkle_path.clone().into_static(),
-            coinbase_tx_prefix: Self::coinbase_tx_prefix(&coinbase, SCRIPT_PREFIX_LEN)?,
-            coinbase_tx_suffix: Self::coinbase_tx_suffix(&coinbase, SCRIPT_PREFIX_LEN)?,
-        };
+        )?;
         self.template_id_to_job_id
             .insert(new_template.template_id, new_extended_mining_job.job_id);
         Ok(new_extended_mining_job)
@@ -77,56 +238,6 @@ impl JobCreator {
     fn get_job_id(&self, template_id: u64) -> Option<u32> {
         self.template_id_to_job_id.get(&template_id).copied()
     }
-
-    fn coinbase_tx_prefix(
-        coinbase: &Transaction,
-        coinbase_tx_input_script_prefix_byte_len: usize,
-    ) -> Result<B064K<'static>, Error> {
-        let encoded = coinbase.serialize();
-        // add 1 cause the script header (len of script) is 1 byte
-        let r = encoded
-            [0..SCRIPT_PREFIX_LEN + coinbase_tx_input_script_prefix_byte_len + PREV_OUT_LEN]
-            .to_vec();
-        r.try_into().map_err(Error::BinarySv2Error)
-    }
-
-    fn coinbase_tx_suffix(
-        coinbase: &Transaction,
-        coinbase_tx_input_script_prefix_byte_len: usize,
-    ) -> Result<B064K<'static>, Error> {
-        let encoded = coinbase.serialize();
-        let r = encoded[SCRIPT_PREFIX_LEN
-            + coinbase_tx_input_script_prefix_byte_len
-            + PREV_OUT_LEN
-            + EXTRANONCE_LEN..]
-            .to_vec();
-        r.try_into().map_err(Error::BinarySv2Error)
-    }
-
-    /// coinbase_tx_input_script_prefix: extranonce prefix (script lenght + bip34 block height) provided by the node
-    /// It assume that NewTemplate.coinbase_tx_outputs == 0
-    fn coinbase(
-        &self,
-        mut bip34_bytes: Vec<u8>,
-        version: i32,
-        lock_time: u32,
-        sequence: u32,
-        coinbase_outputs: &[TxOut],
-    ) -> Transaction {
-        bip34_bytes.extend_from_slice(&[0; EXTRANONCE_LEN]);
-        let tx_in = TxIn {
-            previous_output: OutPoint::null(),
-            script_sig: bip34_bytes.into(),
-            sequence,
-            witness: vec![],
-        };
-        Transaction {
-            version,
-            lock_time,
-            input: vec![tx_in],
-            output: coinbase_outputs.to_vec(),
-        }
-    }
 }
 
 /// Used by pool add a JobCreator for each group channel
@@ -137,33 +248,35 @@ pub struct JobsCreators {
     /// Computed by the pool
     coinbase_outputs: Vec<TxOut>,
     block_reward_staoshi: u64,
-    pub_key: PublicKey,
+    /// Outputs that share the value of the templates
+    outputs: CoinbaseOutputs,
     lasts_new_template: Vec<NewTemplate<'static>>,
     //last_prev_hash: Pr
 }
 
 impl JobsCreators {
+    /// The whole value of the templates is paid to the p2wpkh of `pub_key`
     pub fn new(block_reward_staoshi: u64, pub_key: PublicKey) -> Option<Self> {
-        Some(Self {
-            jobs_creators: vec![],
-            coinbase_outputs: vec![Self::new_output(block_reward_staoshi, pub_key)?],
+        let script_pubkey = Script::new_v0_wpkh(&pub_key.wpubkey_hash()?);
+        Some(Self::with_outputs(
             block_reward_staoshi,
-            pub_key,
-            lasts_new_template: Vec::new(),
-        })
+            CoinbaseOutputs::pay_to(script_pubkey),
+        ))
     }
 
-    fn new_output(block_reward_staoshi: u64, pub_key: PublicKey) -> Option<TxOut> {
-        let script_pubkey = Script::new_v0_wpkh(&pub_key.wpubkey_hash()?);
-        Some(TxOut {
-            value: block_reward_staoshi,
-            script_pubkey,
-        })
+    /// The value of the templates is shared by `outputs`, see `coinbase_outputs`
+    pub fn with_outputs(block_reward_staoshi: u64, outputs: CoinbaseOutputs) -> Self {
+        Self {
+            jobs_creators: vec![],
+            coinbase_outputs: outputs.outputs(block_reward_staoshi),
+            block_reward_staoshi,
+            outputs,
+            lasts_new_template: Vec::new(),
+        }
     }
 
     pub fn new_outputs(&self, block_reward_staoshi: u64) -> Vec<TxOut> {
-        // safe unwrap cause pub key in self is compressed
-        vec![Self::new_output(block_reward_staoshi, self.pub_key).unwrap()]
+        self.outputs.outputs(block_reward_staoshi)
     }
 
     pub fn on_new_template(
@@ -175,16 +288,63 @@ impl JobsCreators {
             self.coinbase_outputs = self.new_outputs(template.coinbase_tx_value_remaining);
         }
 
+        let coinbase_outputs = self.coinbase_outputs.clone();
+        let new_extended_jobs = self.new_extended_jobs(template, &coinbase_outputs)?;
+        self.lasts_new_template.push(template.as_static());
+
+        Ok(new_extended_jobs)
+    }
+
+    fn new_extended_jobs(
+        &mut self,
+        template: &mut NewTemplate,
+        coinbase_outputs: &[TxOut],
+    ) -> Result<HashMap<u32, NewExtendedMiningJob<'static>>, Error> {
         let mut new_extended_jobs = HashMap::new();
         for creator in &mut self.jobs_creators {
-            let job = creator.new_extended_job(template, &self.coinbase_outputs)?;
+            let job = creator.new_extended_job(template, coinbase_outputs)?;
             new_extended_jobs.insert(job.channel_id, job);
         }
-        self.lasts_new_template.push(template.as_static());
-
         Ok(new_extended_jobs)
     }
 
+    fn last_template(&self, template_id: u64) -> Option<NewTemplate<'static>> {
+        self.lasts_new_template
+            .iter()
+            .find(|t| t.template_id == template_id)
+            .cloned()
+    }
+
+    /// Future empty block jobs (see `empty_block_template`) of the group channels, built on the
+    /// template `template_id`. Empty if the template is unknown.
+    pub fn empty_block_jobs(
+        &mut self,
+        template_id: u64,
+    ) -> Result<HashMap<u32, NewExtendedMiningJob<'static>>, Error> {
+        let template = match self.last_template(template_id) {
+            Some(template) => template,
+            None => return Ok(HashMap::new()),
+        };
+        let mut empty = empty_block_template(&template)?;
+        let coinbase_outputs = self.new_outputs(empty.coinbase_tx_value_remaining);
+        self.new_extended_jobs(&mut empty, &coinbase_outputs)
+    }
+
+    /// Non future jobs of the group channels built again on the template `template_id`, that
+    /// replace the empty block jobs. Empty if the template is unknown.
+    pub fn full_block_jobs(
+        &mut self,
+        template_id: u64,
+    ) -> Result<HashMap<u32, NewExtendedMiningJob<'static>>, Error> {
+        let mut template = match self.last_template(template_id) {
+            Some(template) => template,
+            None => return Ok(HashMap::new()),
+        };
+        template.future_template = false;
+        let coinbase_outputs = self.new_outputs(template.coinbase_tx_value_remaining);
+        self.new_extended_jobs(&mut template, &coinbase_outputs)
+    }
+
     fn reset_new_templates(&mut self, template: Option<NewTemplate<'static>>) {
         match template {
             Some(t) => self.lasts_new_template = vec![t],
@@ -238,3 +398,130 @@ impl JobsCreators {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::consensus::serialize;
+
+    fn template(future_template: bool, node_outputs: &[TxOut]) -> NewTemplate<'static> {
+        let coinbase_tx_outputs: Vec<u8> = node_outputs.iter().flat_map(serialize).collect();
+        NewTemplate {
+            template_id: 7,
+            future_template,
+            version: 0x2000_0000,
+            coinbase_tx_version: 2,
+            coinbase_prefix: vec![104, 3, 94, 49, 22].try_into().unwrap(),
+            coinbase_tx_input_sequence: u32::MAX,
+            coinbase_tx_value_remaining: 625_000_000,
+            coinbase_tx_outputs_count: node_outputs.len() as u32,
+            coinbase_tx_outputs: coinbase_tx_outputs.try_into().unwrap(),
+            coinbase_tx_locktime: 0,
+            merkle_path: vec![].into(),
+        }
+    }
+
+    #[test]
+    fn builds_extended_jobs_from_templates() {
+        let pool_output = TxOut {
+            value: 625_000_000,
+            script_pubkey: Script::from(vec![0x51]),
+        };
+        let witness_commitment = TxOut {
+            value: 0,
+            script_pubkey: Script::from(vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed]),
+        };
+        let outputs = vec![pool_output, witness_commitment];
+        let template = template(true, &outputs[1..]);
+        let job = extended_job_from_template(&template, 3, 9, true, &outputs[..1]).unwrap();
+        assert_eq!((job.channel_id, job.job_id), (3, 9));
+        assert!(job.future_job && job.version_rolling_allowed);
+
+        // The coinbase is the prefix, the extranonce and the suffix
+        let extranonce = [7; EXTRANONCE_LEN];
+        let mut serialized = job.coinbase_tx_prefix.to_vec();
+        serialized.extend_from_slice(&extranonce);
+        serialized.extend_from_slice(job.coinbase_tx_suffix.inner_as_ref());
+        let coinbase: Transaction = bitcoin::consensus::deserialize(&serialized).unwrap();
+        let mut script = vec![3, 94, 49, 22];
+        script.extend_from_slice(&extranonce);
+        assert_eq!(coinbase.input[0].script_sig, Script::from(script));
+        assert_eq!(coinbase.output, outputs);
+        assert_eq!(coinbase.version, 2);
+
+        let mut broken = template.clone();
+        broken.coinbase_tx_outputs_count = 2;
+        assert!(matches!(
+            extended_job_from_template(&broken, 3, 10, true, &[]),
+            Err(Error::InvalidCoinbaseOutputs)
+        ));
+        let mut broken = template;
+        broken.coinbase_prefix = vec![104, 3].try_into().unwrap();
+        assert!(matches!(
+            extended_job_from_template(&broken, 3, 10, true, &[]),
+            Err(Error::InvalidCoinbasePrefix)
+        ));
+    }
+
+    #[test]
+    fn builds_empty_block_jobs() {
+        let witness_commitment = TxOut {
+            value: 0,
+            script_pubkey: Script::from(vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed]),
+        };
+        let mut template = template(false, &[witness_commitment]);
+        template.merkle_path = vec![[5; 32].into()].into();
+        assert_eq!(
+            template_height(template.coinbase_prefix.inner_as_ref()).unwrap(),
+            1_454_430
+        );
+        assert_eq!(block_subsidy(1_454_430), 5_000_000_000 >> 6);
+        assert_eq!(block_subsidy(64 * SUBSIDY_HALVING_INTERVAL), 0);
+
+        let empty = empty_block_template(&template).unwrap();
+        assert!(is_empty_block_template(empty.template_id));
+        assert!(!is_empty_block_template(template.template_id));
+        assert_eq!(empty.template_id & !EMPTY_BLOCK_TEMPLATE, 7);
+        assert!(empty.future_template);
+        assert_eq!(empty.coinbase_tx_value_remaining, 78_125_000);
+        assert_eq!(empty.coinbase_tx_outputs_count, 0);
+        assert!(empty.merkle_path.inner_as_ref().is_empty());
+
+        let mut creators =
+            JobsCreators::with_outputs(625_000_000, CoinbaseOutputs::pay_to(Script::new()));
+        creators.new_group_channel(1, true).unwrap();
+        let mut future = template.clone();
+        future.future_template = true;
+        let future_job = creators
+            .on_new_template(&mut future)
+            .unwrap()
+            .remove(&1)
+            .unwrap();
+        let empty_job = creators.empty_block_jobs(7).unwrap().remove(&1).unwrap();
+        assert!(empty_job.future_job && empty_job.merkle_path.inner_as_ref().is_empty());
+        let full_job = creators.full_block_jobs(7).unwrap().remove(&1).unwrap();
+        assert!(!full_job.future_job);
+        assert_eq!(full_job.merkle_path.inner_as_ref().len(), 1);
+        let job_ids = [future_job.job_id, empty_job.job_id, full_job.job_id];
+        assert!(job_ids[0] != job_ids[1] && job_ids[1] != job_ids[2]);
+        // The SetNewPrevHash of the template now activate the full job
+        assert_eq!(creators.job_id_from_template(7, 1), Some(full_job.job_id));
+        assert!(creators.empty_block_jobs(8).unwrap().is_empty());
+    }
+
+    #[test]
+    fn activates_future_jobs() {
+        let prev_hash = SetNewPrevHash {
+            template_id: 7,
+            prev_hash: [1; 32].into(),
+            header_timestamp: 1_600_000_000,
+            n_bits: 0x1d00ffff,
+            target: [0xff; 32].into(),
+        };
+        let message = mining_prev_hash(&prev_hash, 3, 9);
+        assert_eq!((message.channel_id, message.job_id), (3, 9));
+        assert_eq!(message.min_ntime, 1_600_000_000);
+        assert_eq!(message.nbits, 0x1d00ffff);
+        assert_eq!(message.prev_hash.to_vec(), vec![1; 32]);
+    }
+}
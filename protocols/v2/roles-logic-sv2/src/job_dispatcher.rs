@@ -1,14 +1,20 @@
 use crate::{
     common_properties::StandardChannel,
+    error_codes::{share_error, MiningErrorCode},
     errors::Error,
-    utils::{merkle_root_from_path, Id, Mutex},
+    utils::{is_valid_rolled_version, merkle_root_from_path, version_rolling_mask, Id, Mutex},
 };
 use bitcoin::hashes::{sha256d, Hash, HashEngine};
 use mining_sv2::{
-    NewExtendedMiningJob, NewMiningJob, SetNewPrevHash, SubmitSharesError, SubmitSharesStandard,
-    Target,
+    NewExtendedMiningJob, NewMiningJob, SetNewPrevHash, SubmitSharesError, SubmitSharesExtended,
+    SubmitSharesStandard, Target,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryInto,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use std::{collections::HashMap, convert::TryInto, sync::Arc};
 
 fn extended_to_standard_job_for_group_channel<'a>(
     extended: &NewExtendedMiningJob,
@@ -78,6 +84,9 @@ fn target_from_shares(
 struct DownstreamJob {
     merkle_root: Vec<u8>,
     extended_job_id: u32,
+    version: u32,
+    // Bits of version that the downstream is allowed to roll
+    version_rolling_mask: u32,
 }
 
 #[derive(Debug)]
@@ -86,6 +95,298 @@ struct ExtendedJobs {
     upstream_target: Vec<u8>,
 }
 
+/// Fields that identify a share submitted on a channel, the extranonce is empty for the standard
+/// shares
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ShareKey {
+    job_id: u32,
+    nonce: u32,
+    ntime: u32,
+    version: u32,
+    extranonce: Vec<u8>,
+}
+
+impl From<&SubmitSharesStandard> for ShareKey {
+    fn from(share: &SubmitSharesStandard) -> Self {
+        Self {
+            job_id: share.job_id,
+            nonce: share.nonce,
+            ntime: share.ntime,
+            version: share.version,
+            extranonce: Vec::new(),
+        }
+    }
+}
+
+impl From<&SubmitSharesExtended<'_>> for ShareKey {
+    fn from(share: &SubmitSharesExtended<'_>) -> Self {
+        Self {
+            job_id: share.job_id,
+            nonce: share.nonce,
+            ntime: share.ntime,
+            version: share.version,
+            extranonce: share.extranonce.to_vec(),
+        }
+    }
+}
+
+/// Per channel rolling set of the last accepted shares. Is used to reject shares that have already
+/// been submitted so that they are not credited twice.
+///
+/// For each channel only the last `capacity` shares are remembered, when a new share is inserted in
+/// a full channel the oldest one is dropped. Only the accepted shares must be inserted: a share
+/// rejected for another reason (eg difficulty too low) must still get that reason when resubmitted.
+#[derive(Debug)]
+pub struct DuplicateShareFilter {
+    capacity: usize,
+    // channel_id -> (shares in arrival order, shares)
+    channels: HashMap<u32, (VecDeque<ShareKey>, HashSet<ShareKey>)>,
+}
+
+impl DuplicateShareFilter {
+    pub const DEFAULT_CAPACITY: usize = 1024;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            channels: HashMap::new(),
+        }
+    }
+
+    /// Return true if the share has already been accepted on the channel
+    pub fn is_duplicate(&self, channel_id: u32, share: &ShareKey) -> bool {
+        match self.channels.get(&channel_id) {
+            Some((_, seen)) => seen.contains(share),
+            None => false,
+        }
+    }
+
+    /// Remember a share accepted on the channel
+    pub fn insert(&mut self, channel_id: u32, share: ShareKey) {
+        if self.capacity == 0 {
+            return;
+        }
+        let (order, seen) = self
+            .channels
+            .entry(channel_id)
+            .or_insert_with(|| (VecDeque::new(), HashSet::new()));
+        if seen.contains(&share) {
+            return;
+        }
+        if order.len() == self.capacity {
+            // order is not empty because capacity is bigger than 0
+            let oldest = order.pop_front().unwrap();
+            seen.remove(&oldest);
+        }
+        order.push_back(share.clone());
+        seen.insert(share);
+    }
+
+    /// Forget all the shares of a channel, to be called when the channel is closed
+    pub fn remove_channel(&mut self, channel_id: u32) {
+        self.channels.remove(&channel_id);
+    }
+
+    /// Forget all the shares, to be called on a new prev hash as the old jobs are no more valid
+    pub fn clear(&mut self) {
+        self.channels.clear();
+    }
+}
+
+impl Default for DuplicateShareFilter {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+/// Default grace period of `StaleJobs`
+pub const DEFAULT_STALE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Result of `StaleJobs::get_mut`
+#[derive(Debug, PartialEq, Eq)]
+pub enum StaleJob<J> {
+    /// The job has been replaced by the last prev hash but the grace period is not expired
+    InGracePeriod(J),
+    /// The job has been replaced by the last prev hash and the grace period is expired
+    Expired,
+    /// The job is not a stale job
+    Unknown,
+}
+
+/// Jobs replaced by the last SetNewPrevHash.
+///
+/// The downstreams keep mining the old jobs until the new prev hash reach them, so the shares for
+/// the old jobs are still accepted for `grace_period` after the prev hash instead of being
+/// rejected as soon as it change. Only the jobs replaced by the last prev hash are kept.
+#[derive(Debug)]
+pub struct StaleJobs<J> {
+    grace_period: Duration,
+    // job_id -> job
+    jobs: HashMap<u32, J>,
+    // When the grace period of `jobs` expire
+    expire_at: Option<Instant>,
+}
+
+impl<J> StaleJobs<J> {
+    pub fn new(grace_period: Duration) -> Self {
+        Self {
+            grace_period,
+            jobs: HashMap::new(),
+            expire_at: None,
+        }
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    /// Change the grace period, the jobs that are already stale keep the old one
+    pub fn set_grace_period(&mut self, grace_period: Duration) {
+        self.grace_period = grace_period;
+    }
+
+    /// `jobs` have been replaced by a prev hash received at `now`, the previous stale jobs are
+    /// dropped
+    pub fn on_new_prev_hash<I: IntoIterator<Item = (u32, J)>>(&mut self, jobs: I, now: Instant) {
+        self.jobs.clear();
+        if self.grace_period > Duration::from_secs(0) {
+            self.jobs.extend(jobs);
+            self.expire_at = Some(now + self.grace_period);
+        }
+    }
+
+    pub fn get_mut(&mut self, job_id: u32, now: Instant) -> StaleJob<&mut J> {
+        match (self.jobs.get_mut(&job_id), self.expire_at) {
+            (Some(job), Some(expire_at)) if now < expire_at => StaleJob::InGracePeriod(job),
+            (Some(_), _) => StaleJob::Expired,
+            (None, _) => StaleJob::Unknown,
+        }
+    }
+}
+
+impl<J> Default for StaleJobs<J> {
+    fn default() -> Self {
+        Self::new(DEFAULT_STALE_GRACE_PERIOD)
+    }
+}
+
+/// Jobs of a channel that are still valid, only the last `capacity` inserted jobs are kept.
+///
+/// Long lived channels with frequent job updates receive a lot of jobs between two prev hashes,
+/// when a new job is inserted in a full history the oldest one is evicted and the shares for it
+/// are rejected as for an unknown job. A `capacity` of 0 means no limit.
+#[derive(Debug)]
+pub struct JobHistory<J> {
+    capacity: usize,
+    // job ids in insertion order
+    order: VecDeque<u32>,
+    // job_id -> job
+    jobs: HashMap<u32, J>,
+    // Number of jobs evicted since the history has been created
+    evicted: u64,
+}
+
+impl<J> JobHistory<J> {
+    pub const DEFAULT_CAPACITY: usize = 64;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::new(),
+            jobs: HashMap::new(),
+            evicted: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Change the capacity, if the history has more jobs than `capacity` the oldest are evicted
+    /// and returned
+    pub fn set_capacity(&mut self, capacity: usize) -> Vec<(u32, J)> {
+        self.capacity = capacity;
+        let mut evicted = Vec::new();
+        while let Some(job) = self.evict() {
+            evicted.push(job);
+        }
+        evicted
+    }
+
+    /// Insert a job, if the history is full the oldest job is evicted and returned. A job that is
+    /// already in the history is replaced and keep its position.
+    pub fn insert(&mut self, job_id: u32, job: J) -> Option<(u32, J)> {
+        if self.jobs.insert(job_id, job).is_none() {
+            self.order.push_back(job_id);
+        }
+        self.evict()
+    }
+
+    fn evict(&mut self) -> Option<(u32, J)> {
+        if self.capacity == 0 || self.order.len() <= self.capacity {
+            return None;
+        }
+        // order is not empty because is longer than capacity
+        let job_id = self.order.pop_front().unwrap();
+        self.evicted += 1;
+        // Every id in order is in jobs
+        self.jobs.remove(&job_id).map(|job| (job_id, job))
+    }
+
+    pub fn get(&self, job_id: u32) -> Option<&J> {
+        self.jobs.get(&job_id)
+    }
+
+    pub fn contains(&self, job_id: u32) -> bool {
+        self.jobs.contains_key(&job_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Remove the jobs for which `remove` return true and return them
+    pub fn remove_if<F: FnMut(&J) -> bool>(&mut self, mut remove: F) -> HashMap<u32, J> {
+        let mut removed = HashMap::new();
+        let jobs = &mut self.jobs;
+        self.order.retain(|job_id| match jobs.get(job_id) {
+            Some(job) if remove(job) => {
+                // Is fine to unwrap, the job is in the map
+                removed.insert(*job_id, jobs.remove(job_id).unwrap());
+                false
+            }
+            _ => true,
+        });
+        removed
+    }
+
+    /// Remove all the jobs and return them
+    pub fn take(&mut self) -> HashMap<u32, J> {
+        self.order.clear();
+        std::mem::take(&mut self.jobs)
+    }
+
+    pub fn clear(&mut self) {
+        self.order.clear();
+        self.jobs.clear();
+    }
+
+    /// Number of jobs evicted because the history was full
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+}
+
+impl<J> Default for JobHistory<J> {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
 #[derive(Debug)]
 pub struct GroupChannelJobDispatcher {
     //channels: Vec<StandardChannel>,
@@ -95,11 +396,14 @@ pub struct GroupChannelJobDispatcher {
     // extedned_job_id -> standard_job_id -> standard_job
     future_jobs: HashMap<u32, HashMap<u32, DownstreamJob>>,
     // standard_job_id -> standard_job
-    jobs: HashMap<u32, DownstreamJob>,
+    jobs: JobHistory<DownstreamJob>,
     ids: Arc<Mutex<Id>>,
     // extended_id -> channel_id -> stanrd_id
     extended_id_to_job_id: HashMap<u32, HashMap<u32, u32>>,
     nbits: u32,
+    duplicate_shares: DuplicateShareFilter,
+    // standard_job_id -> standard_job, for the jobs replaced by the last prev hash
+    stale_jobs: StaleJobs<DownstreamJob>,
 }
 
 pub enum SendSharesResponse {
@@ -114,13 +418,31 @@ impl GroupChannelJobDispatcher {
             target: [0_u8; 32].into(),
             prev_hash: Vec::new(),
             future_jobs: HashMap::new(),
-            jobs: HashMap::new(),
+            jobs: JobHistory::default(),
             ids,
             nbits: 0,
             extended_id_to_job_id: HashMap::new(),
+            duplicate_shares: DuplicateShareFilter::default(),
+            stale_jobs: StaleJobs::default(),
         }
     }
 
+    /// Accept the shares for the jobs replaced by a prev hash for `grace_period` after the prev
+    /// hash, see `StaleJobs`
+    pub fn set_stale_grace_period(&mut self, grace_period: Duration) {
+        self.stale_jobs.set_grace_period(grace_period);
+    }
+
+    /// Keep only the last `capacity` active jobs, see `JobHistory`
+    pub fn set_job_history_capacity(&mut self, capacity: usize) {
+        self.jobs.set_capacity(capacity);
+    }
+
+    /// Number of active jobs evicted because the job history was full
+    pub fn evicted_jobs(&self) -> u64 {
+        self.jobs.evicted()
+    }
+
     /// When a downstream open a connection with a proxy, the proxy use this function to create a
     /// new mining job from the last valid new extended mining job.
     ///
@@ -154,6 +476,8 @@ impl GroupChannelJobDispatcher {
         let job = DownstreamJob {
             merkle_root: new_mining_job_message.merkle_root.to_vec(),
             extended_job_id: extended.job_id,
+            version: extended.version,
+            version_rolling_mask: version_rolling_mask(extended.version_rolling_allowed),
         };
         if extended.future_job {
             self.future_jobs
@@ -174,18 +498,43 @@ impl GroupChannelJobDispatcher {
         Some(new_mining_job_message)
     }
 
+    /// Job of a channel opened after the prev hash that activated `extended` (a non future job).
+    /// The job is active for the dispatcher but the returned NewMiningJob is a future job, the
+    /// downstream activate it with a SetNewPrevHash for its job id.
+    pub fn on_new_channel_active_job(
+        &mut self,
+        extended: &NewExtendedMiningJob,
+        channel: &StandardChannel,
+    ) -> Option<NewMiningJob<'static>> {
+        if extended.future_job {
+            return None;
+        }
+        let mut job = self.on_new_extended_mining_job(extended, channel)?;
+        job.future_job = true;
+        Some(job)
+    }
+
     pub fn on_new_prev_hash(
         &mut self,
         message: &SetNewPrevHash,
     ) -> Result<HashMap<u32, u32>, Error> {
         let jobs = self
             .future_jobs
-            .get_mut(&message.job_id)
+            .remove(&message.job_id)
             .ok_or(Error::PrevHashRequireNonExistentJobId(message.job_id))?;
-        std::mem::swap(&mut self.jobs, jobs);
+        let replaced = self.jobs.take();
+        for (job_id, job) in jobs {
+            self.jobs.insert(job_id, job);
+        }
+        self.stale_jobs.on_new_prev_hash(replaced, Instant::now());
         self.prev_hash = message.prev_hash.to_vec();
         self.nbits = message.nbits;
         self.future_jobs.clear();
+        // Job ids are never reused so the filter is cleared only to free memory, that can not be
+        // done while the shares for the stale jobs are still accepted
+        if self.stale_jobs.grace_period() == Duration::from_secs(0) {
+            self.duplicate_shares.clear();
+        }
         match self.extended_id_to_job_id.remove(&message.job_id) {
             Some(map) => {
                 self.extended_id_to_job_id.clear();
@@ -199,27 +548,34 @@ impl GroupChannelJobDispatcher {
     }
 
     // (response, upstream id)
-    pub fn on_submit_shares(&self, shares: SubmitSharesStandard) -> SendSharesResponse {
+    pub fn on_submit_shares(&mut self, shares: SubmitSharesStandard) -> SendSharesResponse {
+        let error = |code| SendSharesResponse::Invalid(share_error(&shares, code));
         let id = shares.job_id;
-        if let Some(job) = self.jobs.get(&id) {
-            let success = SubmitSharesStandard {
-                channel_id: shares.channel_id,
-                sequence_number: shares.sequence_number,
-                job_id: job.extended_job_id,
-                nonce: shares.nonce,
-                ntime: shares.ntime,
-                version: shares.version,
-            };
-            SendSharesResponse::Valid(success)
-        } else {
-            let error = SubmitSharesError {
-                channel_id: shares.channel_id,
-                sequence_number: shares.sequence_number,
-                // Below unwrap never panic
-                error_code: "".to_string().into_bytes().try_into().unwrap(),
-            };
-            SendSharesResponse::Invalid(error)
+        let job = match self.jobs.get(id) {
+            Some(job) => job,
+            None => match self.stale_jobs.get_mut(id, Instant::now()) {
+                StaleJob::InGracePeriod(job) => &*job,
+                StaleJob::Expired => return error(MiningErrorCode::StaleShare),
+                StaleJob::Unknown => return error(MiningErrorCode::InvalidJobId),
+            },
+        };
+        let share = ShareKey::from(&shares);
+        if self.duplicate_shares.is_duplicate(shares.channel_id, &share) {
+            return error(MiningErrorCode::DuplicateShare);
         }
+        if !is_valid_rolled_version(job.version, shares.version, job.version_rolling_mask) {
+            return error(MiningErrorCode::InvalidVersion);
+        }
+        let extended_job_id = job.extended_job_id;
+        self.duplicate_shares.insert(shares.channel_id, share);
+        SendSharesResponse::Valid(SubmitSharesStandard {
+            channel_id: shares.channel_id,
+            sequence_number: shares.sequence_number,
+            job_id: extended_job_id,
+            nonce: shares.nonce,
+            ntime: shares.ntime,
+            version: shares.version,
+        })
     }
 }
 
@@ -526,10 +882,12 @@ mod tests {
             target: [0_u8; 32].into(),
             prev_hash: Vec::new(),
             future_jobs: HashMap::new(),
-            jobs: HashMap::new(),
+            jobs: JobHistory::default(),
             ids: Arc::new(Mutex::new(Id::new())),
             nbits: 0,
             extended_id_to_job_id: HashMap::new(),
+            duplicate_shares: DuplicateShareFilter::default(),
+            stale_jobs: StaleJobs::default(),
         };
 
         let ids = Arc::new(Mutex::new(Id::new()));
@@ -544,6 +902,248 @@ mod tests {
         // assert_eq!(expect.ids, actual.ids);
     }
 
+    fn standard_share(job_id: u32, nonce: u32) -> ShareKey {
+        ShareKey::from(&SubmitSharesStandard {
+            channel_id: 0,
+            sequence_number: 0,
+            job_id,
+            nonce,
+            ntime: 100,
+            version: 2,
+        })
+    }
+
+    fn extended_share(nonce: u32, extranonce: Vec<u8>) -> ShareKey {
+        ShareKey::from(&SubmitSharesExtended {
+            channel_id: 0,
+            sequence_number: 0,
+            job_id: 1,
+            nonce,
+            ntime: 100,
+            version: 2,
+            extranonce: extranonce.try_into().unwrap(),
+        })
+    }
+
+    #[test]
+    fn rejects_duplicate_shares() {
+        let mut filter = DuplicateShareFilter::new(2);
+
+        assert!(!filter.is_duplicate(1, &standard_share(1, 10)));
+        // Only the inserted shares are duplicates
+        assert!(!filter.is_duplicate(1, &standard_share(1, 10)));
+        filter.insert(1, standard_share(1, 10));
+        assert!(filter.is_duplicate(1, &standard_share(1, 10)));
+        // Same share on a different channel is not a duplicate
+        assert!(!filter.is_duplicate(2, &standard_share(1, 10)));
+        // Different nonce is not a duplicate
+        assert!(!filter.is_duplicate(1, &standard_share(1, 11)));
+        filter.insert(1, standard_share(1, 11));
+
+        // Channel 1 is full so the oldest share is forgotten
+        filter.insert(1, standard_share(1, 12));
+        assert!(!filter.is_duplicate(1, &standard_share(1, 10)));
+        assert!(filter.is_duplicate(1, &standard_share(1, 12)));
+
+        filter.insert(2, standard_share(1, 10));
+        filter.clear();
+        assert!(!filter.is_duplicate(2, &standard_share(1, 10)));
+    }
+
+    #[test]
+    fn rejects_duplicate_extended_shares() {
+        let mut filter = DuplicateShareFilter::default();
+        filter.insert(1, extended_share(10, vec![1, 2]));
+        assert!(filter.is_duplicate(1, &extended_share(10, vec![1, 2])));
+        // The same header fields with another extranonce is another share
+        assert!(!filter.is_duplicate(1, &extended_share(10, vec![1, 3])));
+        // An extended share without extranonce is the same share as the standard one
+        filter.insert(1, extended_share(11, Vec::new()));
+        assert!(filter.is_duplicate(1, &standard_share(1, 11)));
+    }
+
+    #[test]
+    fn accepts_stale_jobs_in_grace_period() {
+        let now = Instant::now();
+        let mut stale_jobs = StaleJobs::new(Duration::from_secs(5));
+        assert_eq!(stale_jobs.get_mut(1, now), StaleJob::Unknown);
+
+        stale_jobs.on_new_prev_hash(vec![(1, "a"), (2, "b")], now);
+        assert_eq!(
+            stale_jobs.get_mut(1, now),
+            StaleJob::InGracePeriod(&mut "a")
+        );
+        assert_eq!(
+            stale_jobs.get_mut(2, now + Duration::from_secs(4)),
+            StaleJob::InGracePeriod(&mut "b")
+        );
+        assert_eq!(
+            stale_jobs.get_mut(2, now + Duration::from_secs(5)),
+            StaleJob::Expired
+        );
+        assert_eq!(stale_jobs.get_mut(3, now), StaleJob::Unknown);
+
+        // The next prev hash drop the previous stale jobs
+        let later = now + Duration::from_secs(10);
+        stale_jobs.on_new_prev_hash(vec![(3, "c")], later);
+        assert_eq!(stale_jobs.get_mut(1, later), StaleJob::Unknown);
+        assert_eq!(
+            stale_jobs.get_mut(3, later),
+            StaleJob::InGracePeriod(&mut "c")
+        );
+
+        // Without grace period the stale shares are rejected as unknown jobs
+        let mut stale_jobs = StaleJobs::new(Duration::from_secs(0));
+        stale_jobs.on_new_prev_hash(vec![(1, "a")], now);
+        assert_eq!(stale_jobs.get_mut(1, now), StaleJob::Unknown);
+    }
+
+    #[test]
+    fn evicts_the_oldest_jobs() {
+        let mut history = JobHistory::new(2);
+        assert_eq!(history.insert(1, "a"), None);
+        assert_eq!(history.insert(2, "b"), None);
+        // Replace a job do not evict anything
+        assert_eq!(history.insert(1, "c"), None);
+        assert_eq!(history.insert(3, "d"), Some((1, "c")));
+        assert_eq!(history.get(1), None);
+        assert_eq!(history.get(2), Some(&"b"));
+        assert_eq!(history.evicted(), 1);
+
+        let removed = history.remove_if(|job| *job == "b");
+        assert_eq!(removed.get(&2), Some(&"b"));
+        assert_eq!(history.len(), 1);
+        // Removed jobs are not evicted
+        assert_eq!(history.evicted(), 1);
+
+        assert_eq!(history.insert(4, "e"), None);
+        assert_eq!(history.set_capacity(1), vec![(3, "d")]);
+        assert_eq!(history.evicted(), 2);
+
+        // Without capacity the jobs are never evicted
+        let mut history = JobHistory::new(0);
+        for job_id in 0..1000 {
+            assert_eq!(history.insert(job_id, job_id), None);
+        }
+        assert_eq!(history.len(), 1000);
+    }
+
+    #[test]
+    fn activates_future_jobs_per_channel() {
+        use crate::job_creator::{coinbase, split_coinbase, EXTRANONCE_LEN};
+        let coinbase = coinbase(vec![1, 7], 2, 0, u32::MAX, &[], EXTRANONCE_LEN);
+        let (coinbase_tx_prefix, coinbase_tx_suffix) =
+            split_coinbase(&coinbase, 2, EXTRANONCE_LEN).unwrap();
+        let extended = |job_id, future_job| NewExtendedMiningJob {
+            channel_id: 1,
+            job_id,
+            future_job,
+            version: 0x2000_0000,
+            version_rolling_allowed: false,
+            merkle_path: vec![].into(),
+            coinbase_tx_prefix: coinbase_tx_prefix.clone(),
+            coinbase_tx_suffix: coinbase_tx_suffix.clone(),
+        };
+        let channel = |channel_id| StandardChannel {
+            channel_id,
+            group_id: 1,
+            target: [0xff; 32].into(),
+            extranonce: mining_sv2::Extranonce::new(),
+        };
+        let share = |job_id| SubmitSharesStandard {
+            channel_id: 2,
+            sequence_number: 0,
+            job_id,
+            nonce: job_id,
+            ntime: 0,
+            version: 0x2000_0000,
+        };
+        let mut dispatcher = GroupChannelJobDispatcher::new(Arc::new(Mutex::new(Id::new())));
+
+        // Future jobs are not active before the prev hash
+        let future = dispatcher
+            .on_new_extended_mining_job(&extended(10, true), &channel(2))
+            .unwrap();
+        assert!(future.future_job);
+        assert!(matches!(
+            dispatcher.on_submit_shares(share(future.job_id)),
+            SendSharesResponse::Invalid(_)
+        ));
+        let prev_hash = SetNewPrevHash {
+            channel_id: 1,
+            job_id: 10,
+            prev_hash: u256_from_int(45_u32),
+            min_ntime: 0,
+            nbits: 0,
+        };
+        let channel_id_to_job_id = dispatcher.on_new_prev_hash(&prev_hash).unwrap();
+        assert_eq!(channel_id_to_job_id.get(&2), Some(&future.job_id));
+        assert!(matches!(
+            dispatcher.on_submit_shares(share(future.job_id)),
+            SendSharesResponse::Valid(m) if m.job_id == 10
+        ));
+
+        // A channel opened later get the active job as a future job
+        assert!(dispatcher
+            .on_new_channel_active_job(&extended(11, true), &channel(3))
+            .is_none());
+        let active = dispatcher
+            .on_new_channel_active_job(&extended(10, false), &channel(3))
+            .unwrap();
+        assert!(active.future_job);
+        assert_eq!(active.merkle_root, future.merkle_root);
+        assert!(matches!(
+            dispatcher.on_submit_shares(share(active.job_id)),
+            SendSharesResponse::Valid(m) if m.job_id == 10
+        ));
+    }
+
+    #[test]
+    fn computes_the_merkle_root_of_each_standard_channel() {
+        use crate::job_creator::{coinbase, split_coinbase, EXTRANONCE_LEN};
+        let coinbase = coinbase(vec![1, 7], 2, 0, u32::MAX, &[], EXTRANONCE_LEN);
+        let (coinbase_tx_prefix, coinbase_tx_suffix) =
+            split_coinbase(&coinbase, 2, EXTRANONCE_LEN).unwrap();
+        let extended = NewExtendedMiningJob {
+            channel_id: 1,
+            job_id: 10,
+            future_job: false,
+            version: 0x2000_0000,
+            version_rolling_allowed: false,
+            merkle_path: vec![].into(),
+            coinbase_tx_prefix: coinbase_tx_prefix.clone(),
+            coinbase_tx_suffix: coinbase_tx_suffix.clone(),
+        };
+        let mut extranonces = mining_sv2::Extranonce::new();
+        let mut dispatcher = GroupChannelJobDispatcher::new(Arc::new(Mutex::new(Id::new())));
+
+        let mut merkle_roots = Vec::new();
+        for channel_id in 2..4 {
+            let extranonce: mining_sv2::Extranonce = extranonces.next().into();
+            let channel = StandardChannel {
+                channel_id,
+                group_id: 1,
+                target: [0xff; 32].into(),
+                extranonce: extranonce.clone(),
+            };
+            let job = dispatcher
+                .on_new_extended_mining_job(&extended, &channel)
+                .unwrap();
+            let extranonce: Vec<u8> = extranonce.into();
+            let expected = merkle_root_from_path(
+                coinbase_tx_prefix.inner_as_ref(),
+                coinbase_tx_suffix.inner_as_ref(),
+                &extranonce,
+                &[],
+            )
+            .unwrap();
+            assert_eq!(job.channel_id, channel_id);
+            assert_eq!(job.merkle_root.to_vec(), expected);
+            merkle_roots.push(expected);
+        }
+        assert_ne!(merkle_roots[0], merkle_roots[1]);
+    }
+
     //#[ignore]
     //#[test]
     //#[cfg(feature = "serde")]
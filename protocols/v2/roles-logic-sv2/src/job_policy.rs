@@ -0,0 +1,331 @@
+//! Operator policies of the custom jobs.
+//!
+//! A pool that accept SetCustomMiningJob mine on transactions that it did not choose. Before that
+//! the job is answered with Success the pool check the transactions of the job (the ones declared
+//! to the `BlockAssembler`) against a `JobPolicy`, a rejected job is answered with a
+//! SetCustomMiningJob.Error carrying the error code returned by the policy.
+//!
+//! The policies of this module are:
+//! * `MinFeeRate` reject the jobs that pay less fees per vbyte than the operator want
+//! * `MaxSigops` reject the jobs whose transactions have too many (legacy) sigops
+//! * `FilteringAlarm` never reject a job but log the jobs that leave out many of the transactions
+//!   that the Template Provider would have mined, a sign of transactions filtering
+//!
+//! More policies are combined with a `Vec<Box<dyn JobPolicy>>`, the first rejection win.
+use crate::{
+    error_codes::{set_custom_mining_job_error, MiningErrorCode},
+    utils::Mutex,
+};
+use bitcoin::{
+    blockdata::{opcodes::all as opcodes, script::Instruction},
+    Script, Transaction,
+};
+use mining_sv2::{SetCustomMiningJob, SetCustomMiningJobError};
+use std::collections::HashSet;
+
+/// Sigops cost of a block allowed by the consensus
+pub const MAX_BLOCK_SIGOPS_COST: u32 = 80_000;
+/// A legacy sigop cost this much
+pub const WITNESS_SCALE_FACTOR: u32 = 4;
+
+/// A custom job as seen by the policies
+#[derive(Debug, Clone, Copy)]
+pub struct DeclaredJob<'a> {
+    pub job: &'a SetCustomMiningJob<'a>,
+    /// Transactions of the job (coinbase excluded)
+    pub transactions: &'a [Transaction],
+    /// Transactions of the last template of the Template Provider, None if not yet received
+    pub template_transactions: Option<&'a [Transaction]>,
+}
+
+impl<'a> DeclaredJob<'a> {
+    /// Size of the transactions of the job in vbytes
+    pub fn vsize(&self) -> u64 {
+        let weight: usize = self.transactions.iter().map(|t| t.get_weight()).sum();
+        (weight as f64 / 4.0).ceil() as u64
+    }
+
+    /// Fees of the transactions, the coinbase value of the job minus the subsidy
+    pub fn fees(&self, block_subsidy: u64) -> u64 {
+        self.job
+            .coinbase_tx_value_remaining
+            .saturating_sub(block_subsidy)
+    }
+}
+
+pub trait JobPolicy: Send {
+    /// Ok if the job can be accepted, the error code of the SetCustomMiningJob.Error otherwise
+    fn check(&mut self, job: &DeclaredJob) -> Result<(), MiningErrorCode>;
+}
+
+/// Check `job` against `policy` and build the error of the rejected ones
+pub fn check_custom_job(
+    policy: &mut dyn JobPolicy,
+    job: &DeclaredJob,
+) -> Result<(), SetCustomMiningJobError<'static>> {
+    policy.check(job).map_err(|code| {
+        println!(
+            "Custom job {} on channel {} rejected: {}",
+            job.job.request_id, job.job.channel_id, code
+        );
+        set_custom_mining_job_error(job.job.channel_id, job.job.request_id, code)
+    })
+}
+
+/// Accept every job
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AcceptAll;
+
+impl JobPolicy for AcceptAll {
+    fn check(&mut self, _job: &DeclaredJob) -> Result<(), MiningErrorCode> {
+        Ok(())
+    }
+}
+
+impl JobPolicy for Vec<Box<dyn JobPolicy>> {
+    fn check(&mut self, job: &DeclaredJob) -> Result<(), MiningErrorCode> {
+        self.iter_mut().try_for_each(|policy| policy.check(job))
+    }
+}
+
+/// The policy can be shared between the connections of the pool
+impl<P: JobPolicy> JobPolicy for std::sync::Arc<Mutex<P>> {
+    fn check(&mut self, job: &DeclaredJob) -> Result<(), MiningErrorCode> {
+        // Is fine to unwrap on safe_lock
+        self.safe_lock(|policy| policy.check(job)).unwrap()
+    }
+}
+
+/// Reject the jobs that pay less than `min_sat_per_vbyte`, the jobs without transactions are
+/// always accepted
+#[derive(Debug, Clone, Copy)]
+pub struct MinFeeRate {
+    /// Subsidy of the blocks currently mined, what is left of the coinbase value are fees
+    pub block_subsidy: u64,
+    pub min_sat_per_vbyte: f64,
+}
+
+impl JobPolicy for MinFeeRate {
+    fn check(&mut self, job: &DeclaredJob) -> Result<(), MiningErrorCode> {
+        let vsize = job.vsize();
+        if vsize == 0 {
+            return Ok(());
+        }
+        let fee_rate = job.fees(self.block_subsidy) as f64 / vsize as f64;
+        if fee_rate < self.min_sat_per_vbyte {
+            Err(MiningErrorCode::FeeRateTooLow)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Reject the jobs whose transactions cost more than `max_sigops_cost`. Only the legacy sigops of
+/// the scripts are counted (the witness sigops need the spent outputs), every CHECKMULTISIG count
+/// as 20 like in bitcoind.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxSigops {
+    pub max_sigops_cost: u32,
+}
+
+impl Default for MaxSigops {
+    fn default() -> Self {
+        Self {
+            max_sigops_cost: MAX_BLOCK_SIGOPS_COST,
+        }
+    }
+}
+
+impl MaxSigops {
+    fn script_sigops(script: &Script) -> u32 {
+        script
+            .instructions()
+            // After an invalid push the script can not be parsed, what is before still count
+            .take_while(|instruction| instruction.is_ok())
+            .map(|instruction| match instruction {
+                Ok(Instruction::Op(op))
+                    if op == opcodes::OP_CHECKSIG || op == opcodes::OP_CHECKSIGVERIFY =>
+                {
+                    1
+                }
+                Ok(Instruction::Op(op))
+                    if op == opcodes::OP_CHECKMULTISIG || op == opcodes::OP_CHECKMULTISIGVERIFY =>
+                {
+                    20
+                }
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Legacy sigops cost of a transaction
+    pub fn sigops_cost(transaction: &Transaction) -> u32 {
+        let inputs: u32 = transaction
+            .input
+            .iter()
+            .map(|input| Self::script_sigops(&input.script_sig))
+            .sum();
+        let outputs: u32 = transaction
+            .output
+            .iter()
+            .map(|output| Self::script_sigops(&output.script_pubkey))
+            .sum();
+        (inputs + outputs) * WITNESS_SCALE_FACTOR
+    }
+}
+
+impl JobPolicy for MaxSigops {
+    fn check(&mut self, job: &DeclaredJob) -> Result<(), MiningErrorCode> {
+        let cost: u32 = job.transactions.iter().map(Self::sigops_cost).sum();
+        if cost > self.max_sigops_cost {
+            Err(MiningErrorCode::TooManySigops)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Log the jobs that leave out more than `max_missing_ratio` of the transactions of the last
+/// template. The job is accepted anyway: the transactions could be missing for good reasons (eg a
+/// different mempool) so only the operator can tell if the miner is filtering.
+#[derive(Debug, Clone, Copy)]
+pub struct FilteringAlarm {
+    pub max_missing_ratio: f64,
+    /// Jobs that raised the alarm
+    pub alarms: u64,
+}
+
+impl FilteringAlarm {
+    pub fn new(max_missing_ratio: f64) -> Self {
+        Self {
+            max_missing_ratio,
+            alarms: 0,
+        }
+    }
+}
+
+impl JobPolicy for FilteringAlarm {
+    fn check(&mut self, job: &DeclaredJob) -> Result<(), MiningErrorCode> {
+        let template_transactions = match job.template_transactions {
+            Some(transactions) if !transactions.is_empty() => transactions,
+            _ => return Ok(()),
+        };
+        let declared: HashSet<_> = job.transactions.iter().map(|t| t.txid()).collect();
+        let missing = template_transactions
+            .iter()
+            .filter(|t| !declared.contains(&t.txid()))
+            .count();
+        let ratio = missing as f64 / template_transactions.len() as f64;
+        if ratio > self.max_missing_ratio {
+            self.alarms += 1;
+            println!(
+                "Custom job {} on channel {} leave out {} of the {} transactions of the template",
+                job.job.request_id,
+                job.job.channel_id,
+                missing,
+                template_transactions.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::{
+        script::Builder,
+        transaction::{OutPoint, TxIn, TxOut},
+    };
+    use std::convert::TryInto;
+
+    fn transaction(lock_time: u32, script_pubkey: Script) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::from(vec![1, 2, 3, 4]),
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 50,
+                script_pubkey,
+            }],
+        }
+    }
+
+    fn custom_job(coinbase_tx_value_remaining: u64) -> SetCustomMiningJob<'static> {
+        SetCustomMiningJob {
+            channel_id: 2,
+            request_id: 1,
+            mining_job_token: vec![].try_into().unwrap(),
+            version: 0x2000_0000,
+            prev_hash: [3; 32].into(),
+            min_ntime: 0,
+            nbits: 0x1d00ffff,
+            coinbase_tx_version: 2,
+            coinbase_prefix: 0,
+            coinbase_tx_input_n_sequence: u32::MAX,
+            coinbase_tx_value_remaining,
+            coinbase_tx_outputs: vec![].into(),
+            coinbase_tx_locktime: 0,
+            merkle_path: vec![].into(),
+            extranonce_size: 4,
+            future_job: false,
+        }
+    }
+
+    #[test]
+    fn checks_fee_rate_sigops_and_filtering() {
+        let multisig = Builder::new()
+            .push_opcode(opcodes::OP_CHECKMULTISIG)
+            .push_opcode(opcodes::OP_CHECKSIG)
+            .into_script();
+        let transactions: Vec<Transaction> =
+            (0..4).map(|i| transaction(i, multisig.clone())).collect();
+        let job = custom_job(1_000);
+        let declared = DeclaredJob {
+            job: &job,
+            transactions: &transactions[..2],
+            template_transactions: Some(&transactions),
+        };
+        assert_eq!(declared.fees(600), 400);
+
+        let vsize = declared.vsize() as f64;
+        let mut fee_rate = MinFeeRate {
+            block_subsidy: 600,
+            min_sat_per_vbyte: 400.0 / vsize,
+        };
+        assert_eq!(fee_rate.check(&declared), Ok(()));
+        fee_rate.min_sat_per_vbyte += 0.1;
+        assert_eq!(
+            fee_rate.check(&declared),
+            Err(MiningErrorCode::FeeRateTooLow)
+        );
+
+        // 2 transactions * (20 + 1) sigops * 4
+        assert_eq!(MaxSigops::sigops_cost(&transactions[0]), 84);
+        let mut sigops = MaxSigops {
+            max_sigops_cost: 168,
+        };
+        assert_eq!(sigops.check(&declared), Ok(()));
+        sigops.max_sigops_cost = 167;
+        let error = check_custom_job(&mut sigops, &declared).unwrap_err();
+        assert_eq!((error.channel_id, error.request_id), (2, 1));
+        assert_eq!(error.error_code.to_vec(), b"too-many-sigops".to_vec());
+
+        let mut policies: Vec<Box<dyn JobPolicy>> = vec![
+            Box::new(FilteringAlarm::new(0.4)),
+            Box::new(MaxSigops::default()),
+        ];
+        assert_eq!(policies.check(&declared), Ok(()));
+        let mut alarm = FilteringAlarm::new(0.4);
+        alarm.check(&declared).unwrap();
+        assert_eq!(alarm.alarms, 1);
+        alarm.max_missing_ratio = 0.5;
+        alarm.check(&declared).unwrap();
+        assert_eq!(alarm.alarms, 1);
+    }
+}
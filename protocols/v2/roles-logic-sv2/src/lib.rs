@@ -30,15 +30,29 @@
 //! Handlers export the main traits needed in order to implement a valid Sv2 role.
 //! Routers in routing_logic are used by the traits in handlers for decide to which
 //! downstream/upstrem realy/send they use selectors in order to do that.
+pub mod block_assembler;
+pub mod channel_aggregator;
+pub mod channel_state;
+pub mod coinbase_outputs;
 pub mod common_properties;
+pub mod conformance;
+pub mod error_codes;
 pub mod errors;
+pub mod events;
+pub mod extensions;
 pub mod group_channel_logic;
 pub mod handlers;
 pub mod job_creator;
 pub mod job_dispatcher;
+pub mod job_policy;
+pub mod message_registry;
 pub mod parsers;
+pub mod pending_requests;
 pub mod routing_logic;
 pub mod selectors;
+pub mod share_accounting;
+pub mod telemetry;
+pub mod user_identity;
 pub mod utils;
 pub use bitcoin;
 pub use common_messages_sv2;
@@ -0,0 +1,173 @@
+//! Central registry of the known messages.
+//!
+//! Every message is identified by (extension_type, msg_type) and has a fixed channel_msg bit and
+//! (sub)protocol. The handlers validate the frame header against the registry before parsing the
+//! payload so that a malformed header produce `Error::BadHeader` instead of being parsed as
+//! (or rejected as) another message.
+use crate::errors::Error;
+use const_sv2::*;
+use framing_sv2::header::Header;
+
+/// (Sub)protocol of a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Common,
+    Mining,
+    JobNegotiation,
+    TemplateDistribution,
+}
+
+/// Why an header is not valid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadHeader {
+    /// Extension type (without channel_msg bit) not known
+    UnknownExtension(u16),
+    /// (extension_type, msg_type) not known
+    UnknownMessageType(u16, u8),
+    /// msg_type, expected channel_msg bit
+    WrongChannelBit(u8, bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageTypeInfo {
+    pub extension_type: u16,
+    pub msg_type: u8,
+    pub channel_bit: bool,
+    pub protocol: Protocol,
+}
+
+const fn info(msg_type: u8, channel_bit: bool, protocol: Protocol) -> MessageTypeInfo {
+    MessageTypeInfo {
+        extension_type: EXTENSION_TYPE_NO_EXTENSION,
+        msg_type,
+        channel_bit,
+        protocol,
+    }
+}
+
+#[rustfmt::skip]
+const REGISTRY: &[MessageTypeInfo] = &[
+    // COMMON
+    info(MESSAGE_TYPE_SETUP_CONNECTION, CHANNEL_BIT_SETUP_CONNECTION, Protocol::Common),
+    info(MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS, CHANNEL_BIT_SETUP_CONNECTION_SUCCESS, Protocol::Common),
+    info(MESSAGE_TYPE_SETUP_CONNECTION_ERROR, CHANNEL_BIT_SETUP_CONNECTION_ERROR, Protocol::Common),
+    info(MESSAGE_TYPE_CHANNEL_ENDPOINT_CHANGED, CHANNEL_BIT_CHANNEL_ENDPOINT_CHANGED, Protocol::Common),
+    // TEMPLATE DISTRIBUTION
+    info(MESSAGE_TYPE_COINBASE_OUTPUT_DATA_SIZE, CHANNEL_BIT_COINBASE_OUTPUT_DATA_SIZE, Protocol::TemplateDistribution),
+    info(MESSAGE_TYPE_NEW_TEMPLATE, CHANNEL_BIT_NEW_TEMPLATE, Protocol::TemplateDistribution),
+    info(MESSAGE_TYPE_SET_NEW_PREV_HASH, CHANNEL_BIT_SET_NEW_PREV_HASH, Protocol::TemplateDistribution),
+    info(MESSAGE_TYPE_REQUEST_TRANSACTION_DATA, CHANNEL_BIT_REQUEST_TRANSACTION_DATA, Protocol::TemplateDistribution),
+    info(MESSAGE_TYPE_REQUEST_TRANSACTION_DATA_SUCCESS, CHANNEL_BIT_REQUEST_TRANSACTION_DATA_SUCCESS, Protocol::TemplateDistribution),
+    info(MESSAGE_TYPE_REQUEST_TRANSACTION_DATA_ERROR, CHANNEL_BIT_REQUEST_TRANSACTION_DATA_ERROR, Protocol::TemplateDistribution),
+    info(MESSAGE_TYPE_SUBMIT_SOLUTION, CHANNEL_BIT_SUBMIT_SOLUTION, Protocol::TemplateDistribution),
+    // JOB NEGOTIATION
+    info(MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN, CHANNEL_BIT_ALLOCATE_MINING_JOB_TOKEN, Protocol::JobNegotiation),
+    info(MESSAGE_TYPE_ALLOCATE_MINING_JOB_SUCCESS, CHANNEL_BIT_ALLOCATE_MINING_JOB_SUCCESS, Protocol::JobNegotiation),
+    info(MESSAGE_TYPE_IDENTIFY_TRANSACTIONS, CHANNEL_BIT_IDENTIFY_TRANSACTIONS, Protocol::JobNegotiation),
+    info(MESSAGE_TYPE_IDENTIFY_TRANSACTIONS_SUCCESS, CHANNEL_BIT_IDENTIFY_TRANSACTIONS_SUCCESS, Protocol::JobNegotiation),
+    info(MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTION, CHANNEL_BIT_PROVIDE_MISSING_TRANSACTION, Protocol::JobNegotiation),
+    info(MESSAGE_TYPE_PROVIDE_MISSING_TRANSACTION_SUCCESS, CHANNEL_BIT_PROVIDE_MISSING_TRANSACTION_SUCCESS, Protocol::JobNegotiation),
+    info(MESSAGE_TYPE_COMMIT_MINING_JOB, CHANNEL_BIT_COMMIT_MINING_JOB, Protocol::JobNegotiation),
+    info(MESSAGE_TYPE_COMMIT_MINING_JOB_SUCCESS, CHANNEL_BIT_COMMIT_MINING_JOB_SUCCESS, Protocol::JobNegotiation),
+    info(MESSAGE_TYPE_COMMIT_MINING_JOB_ERROR, CHANNEL_BIT_COMMIT_MINING_JOB_ERROR, Protocol::JobNegotiation),
+    // MINING
+    info(MESSAGE_TYPE_CLOSE_CHANNEL, CHANNEL_BIT_CLOSE_CHANNEL, Protocol::Mining),
+    info(MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB, CHANNEL_BIT_NEW_EXTENDED_MINING_JOB, Protocol::Mining),
+    info(MESSAGE_TYPE_NEW_MINING_JOB, CHANNEL_BIT_NEW_MINING_JOB, Protocol::Mining),
+    info(MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL, CHANNEL_BIT_OPEN_EXTENDED_MINING_CHANNEL, Protocol::Mining),
+    info(MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL_SUCCES, CHANNEL_BIT_OPEN_EXTENDED_MINING_CHANNEL_SUCCES, Protocol::Mining),
+    info(MESSAGE_TYPE_OPEN_MINING_CHANNEL_ERROR, CHANNEL_BIT_OPEN_MINING_CHANNEL_ERROR, Protocol::Mining),
+    info(MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL, CHANNEL_BIT_OPEN_STANDARD_MINING_CHANNEL, Protocol::Mining),
+    info(MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS, CHANNEL_BIT_OPEN_STANDARD_MINING_CHANNEL_SUCCESS, Protocol::Mining),
+    info(MESSAGE_TYPE_RECONNECT, CHANNEL_BIT_RECONNECT, Protocol::Mining),
+    info(MESSAGE_TYPE_SET_CUSTOM_MINING_JOB, CHANNEL_BIT_SET_CUSTOM_MINING_JOB, Protocol::Mining),
+    info(MESSAGE_TYPE_SET_CUSTOM_MINING_JOB_ERROR, CHANNEL_BIT_SET_CUSTOM_MINING_JOB_ERROR, Protocol::Mining),
+    info(MESSAGE_TYPE_SET_CUSTOM_MINING_JOB_SUCCESS, CHANNEL_BIT_SET_CUSTOM_MINING_JOB_SUCCESS, Protocol::Mining),
+    info(MESSAGE_TYPE_SET_EXTRANONCE_PREFIX, CHANNEL_BIT_SET_EXTRANONCE_PREFIX, Protocol::Mining),
+    info(MESSAGE_TYPE_SET_GROUP_CHANNEL, CHANNEL_BIT_SET_GROUP_CHANNEL, Protocol::Mining),
+    info(MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH, CHANNEL_BIT_MINING_SET_NEW_PREV_HASH, Protocol::Mining),
+    info(MESSAGE_TYPE_SET_TARGET, CHANNEL_BIT_SET_TARGET, Protocol::Mining),
+    info(MESSAGE_TYPE_SUBMIT_SHARES_ERROR, CHANNEL_BIT_SUBMIT_SHARES_ERROR, Protocol::Mining),
+    info(MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED, CHANNEL_BIT_SUBMIT_SHARES_EXTENDED, Protocol::Mining),
+    info(MESSAGE_TYPE_SUBMIT_SHARES_STANDARD, CHANNEL_BIT_SUBMIT_SHARES_STANDARD, Protocol::Mining),
+    info(MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS, CHANNEL_BIT_SUBMIT_SHARES_SUCCESS, Protocol::Mining),
+    info(MESSAGE_TYPE_UPDATE_CHANNEL, CHANNEL_BIT_UPDATE_CHANNEL, Protocol::Mining),
+    info(MESSAGE_TYPE_UPDATE_CHANNEL_ERROR, CHANNEL_BIT_UPDATE_CHANNEL_ERROR, Protocol::Mining),
+];
+
+/// Return the registered message (extension_type without channel_msg bit, msg_type)
+pub fn lookup(extension_type: u16, msg_type: u8) -> Option<&'static MessageTypeInfo> {
+    REGISTRY
+        .iter()
+        .find(|m| m.extension_type == extension_type && m.msg_type == msg_type)
+}
+
+fn is_known_extension(extension_type: u16) -> bool {
+    REGISTRY.iter().any(|m| m.extension_type == extension_type)
+}
+
+/// Check that `header` is the header of a known message with the right channel_msg bit, return
+/// the msg_type
+pub fn validate_header(header: &Header) -> Result<u8, Error> {
+    let extension_type = header.ext_type_without_channel_msg();
+    let msg_type = header.msg_type();
+    if !is_known_extension(extension_type) {
+        return Err(Error::BadHeader(BadHeader::UnknownExtension(
+            extension_type,
+        )));
+    }
+    let info = lookup(extension_type, msg_type).ok_or(Error::BadHeader(
+        BadHeader::UnknownMessageType(extension_type, msg_type),
+    ))?;
+    if info.channel_bit != header.channel_msg() {
+        return Err(Error::BadHeader(BadHeader::WrongChannelBit(
+            msg_type,
+            info.channel_bit,
+        )));
+    }
+    Ok(msg_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(msg_type: u8, extension_type: u16) -> Header {
+        Header::from_len(0, msg_type, extension_type).unwrap()
+    }
+
+    #[test]
+    fn validates_headers() {
+        // SetTarget has the channel_msg bit set
+        let set_target = header(MESSAGE_TYPE_SET_TARGET, 1);
+        assert_eq!(
+            validate_header(&set_target).unwrap(),
+            MESSAGE_TYPE_SET_TARGET
+        );
+        assert_eq!(
+            lookup(0, MESSAGE_TYPE_SET_TARGET).unwrap().protocol,
+            Protocol::Mining
+        );
+
+        let no_channel_bit = header(MESSAGE_TYPE_SET_TARGET, 0);
+        assert!(matches!(
+            validate_header(&no_channel_bit),
+            Err(Error::BadHeader(BadHeader::WrongChannelBit(
+                MESSAGE_TYPE_SET_TARGET,
+                true
+            )))
+        ));
+
+        let unknown_extension = header(MESSAGE_TYPE_SETUP_CONNECTION, 0x10);
+        assert!(matches!(
+            validate_header(&unknown_extension),
+            Err(Error::BadHeader(BadHeader::UnknownExtension(0x10)))
+        ));
+
+        let unknown_message = header(0xff, 0);
+        assert!(matches!(
+            validate_header(&unknown_message),
+            Err(Error::BadHeader(BadHeader::UnknownMessageType(0, 0xff)))
+        ));
+    }
+}
@@ -80,8 +80,13 @@ use mining_sv2::{
 
 use core::convert::{TryFrom, TryInto};
 
+pub mod fast_shares;
+#[cfg(all(feature = "prop_test", not(feature = "with_serde")))]
+pub mod test_utils;
+
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub enum CommonMessages<'a> {
     ChannelEndpointChanged(ChannelEndpointChanged),
     #[cfg_attr(feature = "with_serde", serde(borrow))]
@@ -93,6 +98,7 @@ pub enum CommonMessages<'a> {
 
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub enum TemplateDistribution<'a> {
     CoinbaseOutputDataSize(CoinbaseOutputDataSize),
     #[cfg_attr(feature = "with_serde", serde(borrow))]
@@ -110,6 +116,7 @@ pub enum TemplateDistribution<'a> {
 
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub enum JobNegotiation<'a> {
     #[cfg_attr(feature = "with_serde", serde(borrow))]
     AllocateMiningJobToken(AllocateMiningJobToken<'a>),
@@ -132,6 +139,7 @@ pub enum JobNegotiation<'a> {
 
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub enum Mining<'a> {
     #[cfg_attr(feature = "with_serde", serde(borrow))]
     CloseChannel(CloseChannel<'a>),
@@ -918,6 +926,7 @@ impl<'a> TryFrom<(u8, &'a mut [u8])> for Mining<'a> {
 
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub enum MiningDeviceMessages<'a> {
     #[cfg_attr(feature = "with_serde", serde(borrow))]
     Common(CommonMessages<'a>),
@@ -960,6 +969,7 @@ impl<'a> TryFrom<(u8, &'a mut [u8])> for MiningDeviceMessages<'a> {
 
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub enum PoolMessages<'a> {
     #[cfg_attr(feature = "with_serde", serde(borrow))]
     Common(CommonMessages<'a>),
@@ -1048,11 +1058,18 @@ impl<'a> TryFrom<(u8, &'a mut [u8])> for PoolMessages<'a> {
         let is_common: Result<CommonMessageTypes, Error> = v.0.try_into();
         let is_mining: Result<MiningTypes, Error> = v.0.try_into();
         let is_job_negotiation: Result<JobNegotiationTypes, Error> = v.0.try_into();
-        match (is_common, is_mining, is_job_negotiation) {
-            (Ok(_), Err(_), Err(_)) => Ok(Self::Common(v.try_into()?)),
-            (Err(_), Ok(_), Err(_)) => Ok(Self::Mining(v.try_into()?)),
-            (Err(_), Err(_), Ok(_)) => Ok(Self::JobNegotiation(v.try_into()?)),
-            (Err(e), Err(_), Err(_)) => Err(e),
+        let is_template_distribution: Result<TemplateDistributionTypes, Error> = v.0.try_into();
+        match (
+            is_common,
+            is_mining,
+            is_job_negotiation,
+            is_template_distribution,
+        ) {
+            (Ok(_), Err(_), Err(_), Err(_)) => Ok(Self::Common(v.try_into()?)),
+            (Err(_), Ok(_), Err(_), Err(_)) => Ok(Self::Mining(v.try_into()?)),
+            (Err(_), Err(_), Ok(_), Err(_)) => Ok(Self::JobNegotiation(v.try_into()?)),
+            (Err(_), Err(_), Err(_), Ok(_)) => Ok(Self::TemplateDistribution(v.try_into()?)),
+            (Err(e), Err(_), Err(_), Err(_)) => Err(e),
             // This is an impossible state is safe to panic here
             _ => panic!(),
         }
@@ -1077,6 +1094,12 @@ impl<'a> From<SetupConnectionError<'a>> for CommonMessages<'a> {
     }
 }
 
+impl<'a> From<ChannelEndpointChanged> for CommonMessages<'a> {
+    fn from(v: ChannelEndpointChanged) -> Self {
+        CommonMessages::ChannelEndpointChanged(v)
+    }
+}
+
 impl<'a> From<OpenStandardMiningChannel<'a>> for Mining<'a> {
     fn from(v: OpenStandardMiningChannel<'a>) -> Self {
         Mining::OpenStandardMiningChannel(v)
@@ -1144,6 +1167,27 @@ impl<'decoder, B: AsMut<[u8]> + AsRef<[u8]>> TryFrom<TemplateDistribution<'decod
     }
 }
 
+/// Frame of a message relayed unchanged (`SendTo::RelaySameMessage`) by a proxy, converted to
+/// the message set of the remote: eg a `MiningDeviceMessages` frame received from a downstream
+/// become a `PoolMessages` frame for the upstream. The frames received from a connection are
+/// already serialized and are relayed as they are, a frame built from a message that the remote
+/// message set do not have fail with `Error::UnrelayableMessage`.
+pub trait RelayFrame<T>: Sized {
+    fn relay(self) -> Result<T, Error>;
+}
+
+impl<A, C, B> RelayFrame<Sv2Frame<C, B>> for Sv2Frame<A, B>
+where
+    A: TryInto<C> + binary_sv2::Serialize + binary_sv2::GetSize,
+    B: AsMut<[u8]> + AsRef<[u8]>,
+{
+    fn relay(self) -> Result<Sv2Frame<C, B>, Error> {
+        let message_type = self.get_header().map(|header| header.msg_type());
+        self.try_map(|message| message.try_into())
+            .map_err(|_| Error::UnrelayableMessage(message_type.unwrap_or_default()))
+    }
+}
+
 impl<'a> TryFrom<PoolMessages<'a>> for MiningDeviceMessages<'a> {
     type Error = ();
 
@@ -1156,3 +1200,54 @@ impl<'a> TryFrom<PoolMessages<'a>> for MiningDeviceMessages<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relay_frames_to_the_message_set_of_the_remote() {
+        let success = SubmitSharesSuccess {
+            channel_id: 1,
+            last_sequence_number: 2,
+            new_submits_accepted_count: 3,
+            new_shares_sum: 4,
+        };
+        let frame: Sv2Frame<MiningDeviceMessages, Vec<u8>> =
+            MiningDeviceMessages::Mining(Mining::SubmitSharesSuccess(success))
+                .try_into()
+                .unwrap();
+        let relayed: Sv2Frame<PoolMessages, Vec<u8>> = frame.relay().unwrap();
+        assert_eq!(
+            relayed.get_header().unwrap().msg_type(),
+            MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS
+        );
+
+        // The PoolMessages do not have the common messages of the mining devices
+        let setup_success = SetupConnectionSuccess {
+            used_version: 2,
+            flags: 0,
+        };
+        let message = MiningDeviceMessages::Common(setup_success.into());
+        let frame: Sv2Frame<MiningDeviceMessages, Vec<u8>> = message.try_into().unwrap();
+        let relayed: Result<Sv2Frame<PoolMessages, Vec<u8>>, Error> = frame.relay();
+        assert!(matches!(
+            relayed,
+            Err(Error::UnrelayableMessage(
+                MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS
+            ))
+        ));
+
+        // A received frame is relayed as it is
+        let message = MiningDeviceMessages::Common(setup_success.into());
+        let frame: Sv2Frame<MiningDeviceMessages, Vec<u8>> = message.try_into().unwrap();
+        let mut serialized = vec![0; frame.encoded_length()];
+        frame.serialize(&mut serialized).unwrap();
+        let received = Sv2Frame::<MiningDeviceMessages, Vec<u8>>::from_bytes(serialized).unwrap();
+        let relayed: Sv2Frame<PoolMessages, Vec<u8>> = received.relay().unwrap();
+        assert_eq!(
+            relayed.get_header().unwrap().msg_type(),
+            MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS
+        );
+    }
+}
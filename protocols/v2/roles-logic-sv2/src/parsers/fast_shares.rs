@@ -0,0 +1,184 @@
+//! Fast path parser of the shares.
+//!
+//! A pool spend most of its time on `SubmitSharesStandard` and `SubmitSharesExtended`. The general
+//! parser decode them into `Mining` through the binary_sv2 decoder, field by field. `parse_share`
+//! read the fields directly from the payload of the frame into a struct on the stack, nothing is
+//! allocated and the payload is not borrowed, so the share can outlive the frame buffer. Pools
+//! that process a lot of shares (>100k shares/s) can check the message type of the frame header
+//! with `is_share` and parse the shares with `parse_share`, every other message goes through the
+//! general parser.
+//!
+//! The payload must be exactly the message: a payload too short, with trailing bytes or with an
+//! extranonce longer than 32 bytes is rejected with `Error::MalformedShare`.
+use crate::errors::Error;
+use const_sv2::{MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED, MESSAGE_TYPE_SUBMIT_SHARES_STANDARD};
+use core::convert::TryInto;
+use mining_sv2::SubmitSharesStandard;
+
+/// channel_id, sequence_number, job_id, nonce, ntime and version
+const FIXED_FIELDS_LEN: usize = 24;
+const MAX_EXTRANONCE_LEN: usize = 32;
+
+/// `SubmitSharesExtended` with the extranonce copied in a fixed size array
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtendedShare {
+    pub channel_id: u32,
+    pub sequence_number: u32,
+    pub job_id: u32,
+    pub nonce: u32,
+    pub ntime: u32,
+    pub version: u32,
+    extranonce: [u8; MAX_EXTRANONCE_LEN],
+    extranonce_len: u8,
+}
+
+impl ExtendedShare {
+    pub fn extranonce(&self) -> &[u8] {
+        &self.extranonce[..self.extranonce_len as usize]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum FastShare {
+    Standard(SubmitSharesStandard),
+    Extended(ExtendedShare),
+}
+
+impl FastShare {
+    pub fn channel_id(&self) -> u32 {
+        match self {
+            Self::Standard(m) => m.channel_id,
+            Self::Extended(m) => m.channel_id,
+        }
+    }
+
+    pub fn sequence_number(&self) -> u32 {
+        match self {
+            Self::Standard(m) => m.sequence_number,
+            Self::Extended(m) => m.sequence_number,
+        }
+    }
+}
+
+/// True for the message types parsed by `parse_share`
+pub fn is_share(message_type: u8) -> bool {
+    message_type == MESSAGE_TYPE_SUBMIT_SHARES_STANDARD
+        || message_type == MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED
+}
+
+/// Parse the payload of a `SubmitSharesStandard` or a `SubmitSharesExtended` frame
+pub fn parse_share(message_type: u8, payload: &[u8]) -> Result<FastShare, Error> {
+    if !is_share(message_type) {
+        return Err(Error::WrongMessageType(message_type));
+    }
+    if payload.len() < FIXED_FIELDS_LEN {
+        return Err(Error::MalformedShare(message_type));
+    }
+    let (fixed, rest) = payload.split_at(FIXED_FIELDS_LEN);
+    let field = |i: usize| {
+        // Is fine to unwrap, the slice is always 4 bytes
+        u32::from_le_bytes(fixed[i * 4..i * 4 + 4].try_into().unwrap())
+    };
+    if message_type == MESSAGE_TYPE_SUBMIT_SHARES_STANDARD {
+        if !rest.is_empty() {
+            return Err(Error::MalformedShare(message_type));
+        }
+        return Ok(FastShare::Standard(SubmitSharesStandard {
+            channel_id: field(0),
+            sequence_number: field(1),
+            job_id: field(2),
+            nonce: field(3),
+            ntime: field(4),
+            version: field(5),
+        }));
+    }
+    let (extranonce_len, extranonce) = match rest.split_first() {
+        Some((&len, extranonce)) if len as usize <= MAX_EXTRANONCE_LEN => (len, extranonce),
+        _ => return Err(Error::MalformedShare(message_type)),
+    };
+    if extranonce.len() != extranonce_len as usize {
+        return Err(Error::MalformedShare(message_type));
+    }
+    let mut share = ExtendedShare {
+        channel_id: field(0),
+        sequence_number: field(1),
+        job_id: field(2),
+        nonce: field(3),
+        ntime: field(4),
+        version: field(5),
+        extranonce: [0; MAX_EXTRANONCE_LEN],
+        extranonce_len,
+    };
+    share.extranonce[..extranonce.len()].copy_from_slice(extranonce);
+    Ok(FastShare::Extended(share))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsers::{Mining, PoolMessages};
+    use framing_sv2::framing2::{Frame, Sv2Frame};
+    use mining_sv2::SubmitSharesExtended;
+
+    fn payload(message: Mining<'static>) -> Vec<u8> {
+        let frame: Sv2Frame<PoolMessages<'static>, Vec<u8>> =
+            PoolMessages::Mining(message).try_into().unwrap();
+        let mut bytes = vec![0; frame.encoded_length()];
+        frame.serialize(&mut bytes).unwrap();
+        bytes.split_off(const_sv2::SV2_FRAME_HEADER_SIZE)
+    }
+
+    #[test]
+    fn parse_the_shares_as_the_general_parser() {
+        let standard = SubmitSharesStandard {
+            channel_id: 1,
+            sequence_number: 7,
+            job_id: 3,
+            nonce: 0xdead_beef,
+            ntime: 1_650_000_000,
+            version: 0x2000_0000,
+        };
+        let standard_payload = payload(Mining::SubmitSharesStandard(standard));
+        match parse_share(MESSAGE_TYPE_SUBMIT_SHARES_STANDARD, &standard_payload).unwrap() {
+            FastShare::Standard(m) => {
+                assert_eq!((m.channel_id, m.sequence_number, m.job_id), (1, 7, 3));
+                assert_eq!(
+                    (m.nonce, m.ntime, m.version),
+                    (0xdead_beef, 1_650_000_000, 1 << 29)
+                );
+            }
+            share => panic!("Unexpected {:?}", share),
+        }
+
+        let extended = SubmitSharesExtended {
+            channel_id: 2,
+            sequence_number: 8,
+            job_id: 4,
+            nonce: 5,
+            ntime: 6,
+            version: 9,
+            extranonce: vec![0xab; 16].try_into().unwrap(),
+        };
+        let extended_payload = payload(Mining::SubmitSharesExtended(extended));
+        let share = parse_share(MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED, &extended_payload).unwrap();
+        assert_eq!((share.channel_id(), share.sequence_number()), (2, 8));
+        match share {
+            FastShare::Extended(m) => {
+                assert_eq!((m.job_id, m.nonce, m.ntime, m.version), (4, 5, 6, 9));
+                assert_eq!(m.extranonce(), &[0xab; 16][..]);
+            }
+            share => panic!("Unexpected {:?}", share),
+        }
+
+        let short = &extended_payload[..extended_payload.len() - 1];
+        assert!(parse_share(MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED, short).is_err());
+        let mut trailing = standard_payload.clone();
+        trailing.push(0);
+        assert!(parse_share(MESSAGE_TYPE_SUBMIT_SHARES_STANDARD, &trailing).is_err());
+        let mut too_long = standard_payload;
+        too_long.push(33);
+        too_long.extend_from_slice(&[0; 33]);
+        assert!(parse_share(MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED, &too_long).is_err());
+        assert!(parse_share(const_sv2::MESSAGE_TYPE_SET_TARGET, &extended_payload).is_err());
+    }
+}
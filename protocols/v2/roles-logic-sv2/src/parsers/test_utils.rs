@@ -0,0 +1,514 @@
+//! Random valid messages for the property tests, enabled by the `prop_test` feature.
+//!
+//! The `all_*` functions return one random message for every variant of a subprotocol, so a test
+//! that check them all can not forget a message. `assert_pool_message_round_trip` and
+//! `assert_mining_device_message_round_trip` encode a message in a frame, parse the frame as a role
+//! would do and check that the parsed message is the same. `RandomPoolMessage` and
+//! `RandomMiningDeviceMessage` can be used as quickcheck arguments.
+//!
+//! The role crates can use them in their own tests with:
+//! `roles_logic_sv2 = { path = "...", features = ["prop_test"] }` in `[dev-dependencies]`.
+use super::*;
+use binary_sv2::{to_bytes, Seq0255, Seq064K, Serialize, Str0255, B016M, B0255, B032, B064K, U256};
+use common_messages_sv2::Protocol;
+use framing_sv2::header::Header;
+use quickcheck::{Arbitrary, Gen};
+
+/// Random bytes, at most `max_len` and at most the size of `g`
+fn bytes(g: &mut Gen, max_len: usize) -> Vec<u8> {
+    let len = usize::arbitrary(g) % (max_len.min(g.size()) + 1);
+    (0..len).map(|_| u8::arbitrary(g)).collect()
+}
+
+fn b032(g: &mut Gen) -> B032<'static> {
+    // Can not fail, the len is checked by bytes
+    bytes(g, 32).try_into().unwrap()
+}
+
+fn b0255(g: &mut Gen) -> B0255<'static> {
+    bytes(g, 255).try_into().unwrap()
+}
+
+fn str0255(g: &mut Gen) -> Str0255<'static> {
+    bytes(g, 255).try_into().unwrap()
+}
+
+fn b064k(g: &mut Gen) -> B064K<'static> {
+    bytes(g, u16::MAX as usize).try_into().unwrap()
+}
+
+fn b016m(g: &mut Gen) -> B016M<'static> {
+    bytes(g, 2_usize.pow(24) - 1).try_into().unwrap()
+}
+
+fn u256(g: &mut Gen) -> U256<'static> {
+    let mut bytes = [0_u8; 32];
+    bytes.iter_mut().for_each(|byte| *byte = u8::arbitrary(g));
+    bytes.into()
+}
+
+fn seq0255<T>(g: &mut Gen, item: fn(&mut Gen) -> T) -> Seq0255<'static, T> {
+    let len = usize::arbitrary(g) % (g.size().min(255) + 1);
+    Seq0255::new((0..len).map(|_| item(g)).collect()).unwrap()
+}
+
+fn seq064k<T>(g: &mut Gen, item: fn(&mut Gen) -> T) -> Seq064K<'static, T> {
+    let len = usize::arbitrary(g) % (g.size().min(u16::MAX as usize) + 1);
+    Seq064K::new((0..len).map(|_| item(g)).collect()).unwrap()
+}
+
+/// One random message for every variant of `CommonMessages`
+pub fn all_common_messages(g: &mut Gen) -> Vec<CommonMessages<'static>> {
+    let protocol = *g
+        .choose(&[
+            Protocol::MiningProtocol,
+            Protocol::JobNegotiationProtocol,
+            Protocol::TemplateDistributionProtocol,
+            Protocol::JobDistributionProtocol,
+        ])
+        .unwrap();
+    vec![
+        CommonMessages::ChannelEndpointChanged(ChannelEndpointChanged {
+            channel_id: u32::arbitrary(g),
+        }),
+        CommonMessages::SetupConnection(SetupConnection {
+            protocol,
+            min_version: u16::arbitrary(g),
+            max_version: u16::arbitrary(g),
+            flags: u32::arbitrary(g),
+            endpoint_host: str0255(g),
+            endpoint_port: u16::arbitrary(g),
+            vendor: str0255(g),
+            hardware_version: str0255(g),
+            firmware: str0255(g),
+            device_id: str0255(g),
+        }),
+        CommonMessages::SetupConnectionError(SetupConnectionError {
+            flags: u32::arbitrary(g),
+            error_code: str0255(g),
+        }),
+        CommonMessages::SetupConnectionSuccess(SetupConnectionSuccess {
+            used_version: u16::arbitrary(g),
+            flags: u32::arbitrary(g),
+        }),
+    ]
+}
+
+/// One random message for every variant of `TemplateDistribution`
+pub fn all_template_distribution_messages(g: &mut Gen) -> Vec<TemplateDistribution<'static>> {
+    vec![
+        TemplateDistribution::CoinbaseOutputDataSize(CoinbaseOutputDataSize {
+            coinbase_output_max_additional_size: u32::arbitrary(g),
+        }),
+        TemplateDistribution::NewTemplate(NewTemplate {
+            template_id: u64::arbitrary(g),
+            future_template: bool::arbitrary(g),
+            version: u32::arbitrary(g),
+            coinbase_tx_version: u32::arbitrary(g),
+            coinbase_prefix: b0255(g),
+            coinbase_tx_input_sequence: u32::arbitrary(g),
+            coinbase_tx_value_remaining: u64::arbitrary(g),
+            coinbase_tx_outputs_count: u32::arbitrary(g),
+            coinbase_tx_outputs: b064k(g),
+            coinbase_tx_locktime: u32::arbitrary(g),
+            merkle_path: seq0255(g, u256),
+        }),
+        TemplateDistribution::RequestTransactionData(RequestTransactionData {
+            template_id: u64::arbitrary(g),
+        }),
+        TemplateDistribution::RequestTransactionDataError(RequestTransactionDataError {
+            template_id: u64::arbitrary(g),
+            error_code: str0255(g),
+        }),
+        TemplateDistribution::RequestTransactionDataSuccess(RequestTransactionDataSuccess {
+            template_id: u64::arbitrary(g),
+            excess_data: b064k(g),
+            transaction_list: seq064k(g, b016m),
+        }),
+        TemplateDistribution::SetNewPrevHash(SetNewPrevHash {
+            template_id: u64::arbitrary(g),
+            prev_hash: u256(g),
+            header_timestamp: u32::arbitrary(g),
+            n_bits: u32::arbitrary(g),
+            target: u256(g),
+        }),
+        TemplateDistribution::SubmitSolution(SubmitSolution {
+            template_id: u64::arbitrary(g),
+            version: u32::arbitrary(g),
+            header_timestamp: u32::arbitrary(g),
+            header_nonce: u32::arbitrary(g),
+            coinbase_tx: b064k(g),
+        }),
+    ]
+}
+
+/// One random message for every variant of `JobNegotiation`
+pub fn all_job_negotiation_messages(g: &mut Gen) -> Vec<JobNegotiation<'static>> {
+    vec![
+        JobNegotiation::AllocateMiningJobToken(AllocateMiningJobToken {
+            user_identifier: str0255(g),
+            request_id: u32::arbitrary(g),
+        }),
+        JobNegotiation::AllocateMiningJobTokenSuccess(AllocateMiningJobTokenSuccess {
+            request_id: u32::arbitrary(g),
+            mining_job_token: b0255(g),
+            coinbase_output_max_additional_size: u32::arbitrary(g),
+            async_mining_allowed: bool::arbitrary(g),
+        }),
+        JobNegotiation::CommitMiningJob(CommitMiningJob {
+            request_id: u32::arbitrary(g),
+            mining_job_token: u32::arbitrary(g),
+            version: u32::arbitrary(g),
+            coinbase_tx_version: u32::arbitrary(g),
+            coinbase_prefix: b0255(g),
+            coinbase_tx_input_n_sequence: u32::arbitrary(g),
+            coinbase_tx_value_remaining: u64::arbitrary(g),
+            coinbase_tx_outputs: seq064k(g, b064k),
+            coinbase_tx_locktime: u32::arbitrary(g),
+            min_extranonce_size: u16::arbitrary(g),
+            tx_short_hash_nonce: u64::arbitrary(g),
+            tx_short_hash_list: seq064k(g, u64::arbitrary),
+            tx_hash_list_hash: u256(g),
+            excess_data: b064k(g),
+        }),
+        JobNegotiation::CommitMiningJobSuccess(CommitMiningJobSuccess {
+            request_id: u32::arbitrary(g),
+            new_mining_job_token: b0255(g),
+        }),
+        JobNegotiation::CommitMiningJobError(CommitMiningJobError {
+            request_id: u32::arbitrary(g),
+            error_code: str0255(g),
+            error_details: b064k(g),
+        }),
+        JobNegotiation::IdentifyTransactions(IdentifyTransactions {
+            request_id: u32::arbitrary(g),
+        }),
+        JobNegotiation::IdentifyTransactionsSuccess(IdentifyTransactionsSuccess {
+            request_id: u32::arbitrary(g),
+            tx_hash_list: seq064k(g, u256),
+        }),
+        JobNegotiation::ProvideMissingTransactions(ProvideMissingTransactions {
+            request_id: u32::arbitrary(g),
+            unknown_tx_position_list: seq064k(g, u16::arbitrary),
+        }),
+        JobNegotiation::ProvideMissingTransactionsSuccess(ProvideMissingTransactionsSuccess {
+            request_id: u32::arbitrary(g),
+            transaction_list: seq064k(g, b016m),
+        }),
+    ]
+}
+
+/// One random message for every variant of `Mining`
+pub fn all_mining_messages(g: &mut Gen) -> Vec<Mining<'static>> {
+    vec![
+        Mining::CloseChannel(CloseChannel {
+            channel_id: u32::arbitrary(g),
+            reason_code: b032(g),
+        }),
+        Mining::NewExtendedMiningJob(NewExtendedMiningJob {
+            channel_id: u32::arbitrary(g),
+            job_id: u32::arbitrary(g),
+            future_job: bool::arbitrary(g),
+            version: u32::arbitrary(g),
+            version_rolling_allowed: bool::arbitrary(g),
+            merkle_path: seq0255(g, u256),
+            coinbase_tx_prefix: b064k(g),
+            coinbase_tx_suffix: b064k(g),
+        }),
+        Mining::NewMiningJob(NewMiningJob {
+            channel_id: u32::arbitrary(g),
+            job_id: u32::arbitrary(g),
+            future_job: bool::arbitrary(g),
+            version: u32::arbitrary(g),
+            merkle_root: b032(g),
+        }),
+        Mining::OpenExtendedMiningChannel(OpenExtendedMiningChannel {
+            request_id: u32::arbitrary(g),
+            user_identity: str0255(g),
+            nominal_hash_rate: f32::arbitrary(g),
+            max_target: u256(g),
+            min_extranonce_size: u16::arbitrary(g),
+        }),
+        Mining::OpenExtendedMiningChannelSuccess(OpenExtendedMiningChannelSuccess {
+            request_id: u32::arbitrary(g),
+            channel_id: u32::arbitrary(g),
+            target: u256(g),
+            extranonce_size: u16::arbitrary(g),
+            extranonce_prefix: b032(g),
+        }),
+        Mining::OpenMiningChannelError(OpenMiningChannelError {
+            request_id: u32::arbitrary(g),
+            error_code: b032(g),
+        }),
+        Mining::OpenStandardMiningChannel(OpenStandardMiningChannel {
+            request_id: u32::arbitrary(g).into(),
+            user_identity: str0255(g),
+            nominal_hash_rate: f32::arbitrary(g),
+            max_target: u256(g),
+        }),
+        Mining::OpenStandardMiningChannelSuccess(OpenStandardMiningChannelSuccess {
+            request_id: u32::arbitrary(g).into(),
+            channel_id: u32::arbitrary(g),
+            target: u256(g),
+            extranonce_prefix: b032(g),
+            group_channel_id: u32::arbitrary(g),
+        }),
+        Mining::Reconnect(Reconnect {
+            new_host: str0255(g),
+            new_port: u16::arbitrary(g),
+        }),
+        Mining::SetCustomMiningJob(SetCustomMiningJob {
+            channel_id: u32::arbitrary(g),
+            request_id: u32::arbitrary(g),
+            mining_job_token: b0255(g),
+            version: u32::arbitrary(g),
+            prev_hash: u256(g),
+            min_ntime: u32::arbitrary(g),
+            nbits: u32::arbitrary(g),
+            coinbase_tx_version: u32::arbitrary(g),
+            coinbase_prefix: u32::arbitrary(g),
+            coinbase_tx_input_n_sequence: u32::arbitrary(g),
+            coinbase_tx_value_remaining: u64::arbitrary(g),
+            coinbase_tx_outputs: seq064k(g, b064k),
+            coinbase_tx_locktime: u32::arbitrary(g),
+            merkle_path: seq0255(g, u256),
+            extranonce_size: u16::arbitrary(g),
+            future_job: bool::arbitrary(g),
+        }),
+        Mining::SetCustomMiningJobError(SetCustomMiningJobError {
+            channel_id: u32::arbitrary(g),
+            request_id: u32::arbitrary(g),
+            error_code: b032(g),
+        }),
+        Mining::SetCustomMiningJobSuccess(SetCustomMiningJobSuccess {
+            channel_id: u32::arbitrary(g),
+            request_id: u32::arbitrary(g),
+            job_id: u32::arbitrary(g),
+            coinbase_tx_prefix: b064k(g),
+            coinbase_tx_suffix: b064k(g),
+        }),
+        Mining::SetExtranoncePrefix(SetExtranoncePrefix {
+            channel_id: u32::arbitrary(g),
+            extranonce_prefix: b032(g),
+        }),
+        Mining::SetGroupChannel(SetGroupChannel {
+            group_channel_id: u32::arbitrary(g),
+            channel_ids: seq064k(g, u32::arbitrary),
+        }),
+        Mining::SetNewPrevHash(MiningSetNewPrevHash {
+            channel_id: u32::arbitrary(g),
+            job_id: u32::arbitrary(g),
+            prev_hash: u256(g),
+            min_ntime: u32::arbitrary(g),
+            nbits: u32::arbitrary(g),
+        }),
+        Mining::SetTarget(SetTarget {
+            channel_id: u32::arbitrary(g),
+            maximum_target: u256(g),
+        }),
+        Mining::SubmitSharesError(SubmitSharesError {
+            channel_id: u32::arbitrary(g),
+            sequence_number: u32::arbitrary(g),
+            error_code: b032(g),
+        }),
+        Mining::SubmitSharesExtended(SubmitSharesExtended {
+            channel_id: u32::arbitrary(g),
+            sequence_number: u32::arbitrary(g),
+            job_id: u32::arbitrary(g),
+            nonce: u32::arbitrary(g),
+            ntime: u32::arbitrary(g),
+            version: u32::arbitrary(g),
+            extranonce: b032(g),
+        }),
+        Mining::SubmitSharesStandard(SubmitSharesStandard {
+            channel_id: u32::arbitrary(g),
+            sequence_number: u32::arbitrary(g),
+            job_id: u32::arbitrary(g),
+            nonce: u32::arbitrary(g),
+            ntime: u32::arbitrary(g),
+            version: u32::arbitrary(g),
+        }),
+        Mining::SubmitSharesSuccess(SubmitSharesSuccess {
+            channel_id: u32::arbitrary(g),
+            last_sequence_number: u32::arbitrary(g),
+            new_submits_accepted_count: u32::arbitrary(g),
+            new_shares_sum: u64::arbitrary(g),
+        }),
+        Mining::UpdateChannel(UpdateChannel {
+            channel_id: u32::arbitrary(g),
+            nominal_hash_rate: f32::arbitrary(g),
+            maximum_target: u256(g),
+        }),
+        Mining::UpdateChannelError(UpdateChannelError {
+            channel_id: u32::arbitrary(g),
+            error_code: b032(g),
+        }),
+    ]
+}
+
+/// One random message for every variant of every subprotocol
+pub fn all_pool_messages(g: &mut Gen) -> Vec<PoolMessages<'static>> {
+    let mut messages: Vec<PoolMessages<'static>> = all_common_messages(g)
+        .into_iter()
+        .map(PoolMessages::Common)
+        .collect();
+    messages.extend(all_mining_messages(g).into_iter().map(PoolMessages::Mining));
+    messages.extend(
+        all_job_negotiation_messages(g)
+            .into_iter()
+            .map(PoolMessages::JobNegotiation),
+    );
+    messages.extend(
+        all_template_distribution_messages(g)
+            .into_iter()
+            .map(PoolMessages::TemplateDistribution),
+    );
+    messages
+}
+
+/// One random message for every variant of the messages exchanged with a mining device
+pub fn all_mining_device_messages(g: &mut Gen) -> Vec<MiningDeviceMessages<'static>> {
+    let mut messages: Vec<MiningDeviceMessages<'static>> = all_common_messages(g)
+        .into_iter()
+        .map(MiningDeviceMessages::Common)
+        .collect();
+    messages.extend(
+        all_mining_messages(g)
+            .into_iter()
+            .map(MiningDeviceMessages::Mining),
+    );
+    messages
+}
+
+/// A random `PoolMessages` of any variant
+#[derive(Clone, Debug)]
+pub struct RandomPoolMessage(pub PoolMessages<'static>);
+
+impl Arbitrary for RandomPoolMessage {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let messages = all_pool_messages(g);
+        let index = usize::arbitrary(g) % messages.len();
+        // Can not fail the index is in the vec
+        Self(messages.into_iter().nth(index).unwrap())
+    }
+}
+
+/// A random `MiningDeviceMessages` of any variant
+#[derive(Clone, Debug)]
+pub struct RandomMiningDeviceMessage(pub MiningDeviceMessages<'static>);
+
+impl Arbitrary for RandomMiningDeviceMessage {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let messages = all_mining_device_messages(g);
+        let index = usize::arbitrary(g) % messages.len();
+        Self(messages.into_iter().nth(index).unwrap())
+    }
+}
+
+/// Serialized frame of `message`
+fn encode<T: Serialize + GetSize>(message: T, message_type: u8, channel_bit: bool) -> Vec<u8> {
+    let frame: Sv2Frame<T, Vec<u8>> = Sv2Frame::from_message(message, message_type, 0, channel_bit)
+        .expect("The message is too big for a frame");
+    let mut bytes = vec![0; frame.encoded_length()];
+    frame.serialize(&mut bytes).unwrap();
+    bytes
+}
+
+/// Header of the frame `bytes`, checked against the message that has been encoded
+fn check_header(bytes: &[u8], message_type: u8, channel_bit: bool) -> Header {
+    let header = Header::from_bytes(bytes).expect("Invalid header");
+    assert_eq!(header.msg_type(), message_type);
+    assert_eq!(header.channel_msg(), channel_bit);
+    assert_eq!(header.len(), bytes.len() - Header::SIZE);
+    header
+}
+
+/// Encode `message` in a frame and parse the frame as `PoolMessages`, panic if the frame can not
+/// be parsed or if the parsed message is not `message`
+pub fn assert_pool_message_round_trip(message: PoolMessages<'static>) {
+    let (message_type, channel_bit) = (message.message_type(), message.channel_bit());
+    let expected = to_bytes(message.clone()).unwrap();
+    let mut frame = encode(message.clone(), message_type, channel_bit);
+    let header = check_header(&frame, message_type, channel_bit);
+    let parsed: PoolMessages = (header.msg_type(), &mut frame[Header::SIZE..])
+        .try_into()
+        .unwrap_or_else(|e| panic!("Can not parse {:?}: {:?}", message, e));
+    assert_eq!(parsed.message_type(), message_type);
+    assert_eq!(to_bytes(parsed).unwrap(), expected, "{:?}", message);
+}
+
+/// Encode `message` in a frame and parse the frame as `MiningDeviceMessages`, panic if the frame
+/// can not be parsed or if the parsed message is not `message`
+pub fn assert_mining_device_message_round_trip(message: MiningDeviceMessages<'static>) {
+    let (message_type, channel_bit) = (message.message_type(), message.channel_bit());
+    let expected = to_bytes(message.clone()).unwrap();
+    let mut frame = encode(message.clone(), message_type, channel_bit);
+    let header = check_header(&frame, message_type, channel_bit);
+    let parsed: MiningDeviceMessages = (header.msg_type(), &mut frame[Header::SIZE..])
+        .try_into()
+        .unwrap_or_else(|e| panic!("Can not parse {:?}: {:?}", message, e));
+    assert_eq!(parsed.message_type(), message_type);
+    assert_eq!(to_bytes(parsed).unwrap(), expected, "{:?}", message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Message types accepted by a parser
+    fn parsed_types(is_parsed: fn(u8) -> bool) -> HashSet<u8> {
+        (0..=u8::MAX).filter(|t| is_parsed(*t)).collect()
+    }
+
+    #[test]
+    fn generates_every_message_type() {
+        let mut g = Gen::new(16);
+        let generated: HashSet<u8> = all_pool_messages(&mut g)
+            .iter()
+            .map(|m| m.message_type())
+            .collect();
+        let mut expected = parsed_types(|t| CommonMessageTypes::try_from(t).is_ok());
+        expected.extend(parsed_types(|t| MiningTypes::try_from(t).is_ok()));
+        expected.extend(parsed_types(|t| JobNegotiationTypes::try_from(t).is_ok()));
+        expected.extend(parsed_types(|t| {
+            TemplateDistributionTypes::try_from(t).is_ok()
+        }));
+        assert_eq!(generated, expected);
+        assert_eq!(all_pool_messages(&mut g).len(), expected.len());
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        let mut g = Gen::new(64);
+        for _ in 0..10 {
+            all_pool_messages(&mut g)
+                .into_iter()
+                .for_each(assert_pool_message_round_trip);
+            all_mining_device_messages(&mut g)
+                .into_iter()
+                .for_each(assert_mining_device_message_round_trip);
+        }
+    }
+
+    #[test]
+    fn empty_messages_round_trip() {
+        // Every variable length field is empty
+        let mut g = Gen::new(0);
+        all_pool_messages(&mut g)
+            .into_iter()
+            .for_each(assert_pool_message_round_trip);
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn pool_message_round_trips(message: RandomPoolMessage) -> bool {
+        assert_pool_message_round_trip(message.0);
+        true
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn mining_device_message_round_trips(message: RandomMiningDeviceMessage) -> bool {
+        assert_mining_device_message_round_trip(message.0);
+        true
+    }
+}
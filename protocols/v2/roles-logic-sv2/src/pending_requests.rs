@@ -0,0 +1,126 @@
+//! Deadlines of the requests sent upstream.
+//!
+//! The messages with a `request_id` (`OpenStandardMiningChannel`, `OpenExtendedMiningChannel`,
+//! `SetCustomMiningJob`, ...) are answered by a success or an error with the same `request_id`. An
+//! upstream that never answer would leave the requester waiting forever, so the roles register
+//! every relayed request in `PendingRequests` and answer the requester with an error (see
+//! `error_codes::MiningErrorCode::RequestTimeout`) for the requests returned by
+//! `PendingRequests::expired`. This crate do no I/O, the roles decide when to check the deadlines.
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Time given to the upstream to answer a request if the role do not choose one
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct PendingRequest<R> {
+    deadline: Instant,
+    request: R,
+}
+
+/// Requests waiting for a response, `R` is what the role need to answer the requester if the
+/// request time out (eg the request id of the downstream)
+#[derive(Debug)]
+pub struct PendingRequests<R> {
+    timeout: Duration,
+    // request_id -> request
+    requests: HashMap<u32, PendingRequest<R>>,
+}
+
+impl<R> PendingRequests<R> {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            requests: HashMap::new(),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Used for the requests sent after the change
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Wait a response for `request_id` sent at `now`, return the deadline of the request
+    pub fn on_request(&mut self, request_id: u32, request: R, now: Instant) -> Instant {
+        let deadline = now + self.timeout;
+        self.requests
+            .insert(request_id, PendingRequest { deadline, request });
+        deadline
+    }
+
+    /// A success or an error for `request_id` has been received, None if the request is not
+    /// pending (it has already expired or it has never been sent)
+    pub fn on_response(&mut self, request_id: u32) -> Option<R> {
+        self.requests
+            .remove(&request_id)
+            .map(|pending| pending.request)
+    }
+
+    /// Remove and return the requests without a response at `now`
+    pub fn expired(&mut self, now: Instant) -> Vec<(u32, R)> {
+        let expired: Vec<u32> = self
+            .requests
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(request_id, _)| *request_id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|request_id| Some((request_id, self.on_response(request_id)?)))
+            .collect()
+    }
+
+    /// Earliest deadline of the pending requests
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.requests.values().map(|pending| pending.deadline).min()
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+impl<R> Default for PendingRequests<R> {
+    fn default() -> Self {
+        Self::new(DEFAULT_REQUEST_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_requests_without_response() {
+        let now = Instant::now();
+        let mut requests = PendingRequests::new(Duration::from_secs(10));
+        requests.on_request(1, "first", now);
+        requests.on_request(2, "second", now + Duration::from_secs(5));
+        assert_eq!(
+            requests.next_deadline(),
+            Some(now + Duration::from_secs(10))
+        );
+
+        assert!(requests.expired(now + Duration::from_secs(9)).is_empty());
+        assert_eq!(
+            requests.expired(now + Duration::from_secs(10)),
+            vec![(1, "first")]
+        );
+        // An expired request can not be answered
+        assert_eq!(requests.on_response(1), None);
+
+        assert_eq!(requests.on_response(2), Some("second"));
+        assert!(requests.expired(now + Duration::from_secs(60)).is_empty());
+        assert!(requests.is_empty());
+    }
+}
@@ -21,7 +21,7 @@
 //!
 use crate::{
     common_properties::{CommonDownstreamData, IsMiningDownstream, IsMiningUpstream, PairSettings},
-    errors::Error,
+    errors::{Error, RoutingError},
     selectors::{
         DownstreamMiningSelector, GeneralMiningSelector, NullDownstreamMiningSelector,
         UpstreamMiningSelctor,
@@ -32,15 +32,17 @@ use common_messages_sv2::{
     has_requires_std_job, Protocol, SetupConnection, SetupConnectionSuccess,
 };
 use mining_sv2::{OpenStandardMiningChannel, OpenStandardMiningChannelSuccess};
-use std::{collections::HashMap, fmt::Debug as D, marker::PhantomData, sync::Arc};
+use std::{collections::HashMap, fmt::Debug as D, marker::PhantomData, net::SocketAddr, sync::Arc};
 
 /// CommonRouter trait it define a router needed by
 /// crate::handlers::common::ParseUpstreamCommonMessages and
 /// crate::handlers::common::ParseDownstreamCommonMessages
 pub trait CommonRouter {
+    /// `remote_address` is the address of the downstream, if known
     fn on_setup_connection(
         &mut self,
         message: &SetupConnection,
+        remote_address: Option<SocketAddr>,
     ) -> Result<(CommonDownstreamData, SetupConnectionSuccess), Error>;
 }
 
@@ -79,6 +81,7 @@ impl CommonRouter for NoRouting {
     fn on_setup_connection(
         &mut self,
         _: &SetupConnection,
+        _: Option<SocketAddr>,
     ) -> Result<(CommonDownstreamData, SetupConnectionSuccess), Error> {
         unreachable!()
     }
@@ -110,7 +113,7 @@ impl<
 /// handle_message_..()
 #[derive(Debug)]
 pub enum CommonRoutingLogic<Router: 'static + CommonRouter> {
-    Proxy(&'static Mutex<Router>),
+    Proxy(Arc<Mutex<Router>>),
     None,
 }
 
@@ -135,7 +138,7 @@ pub enum MiningRoutingLogic<
     Sel: DownstreamMiningSelector<Down> + D,
     Router: 'static + MiningRouter<Down, Up, Sel>,
 > {
-    Proxy(&'static Mutex<Router>),
+    Proxy(Arc<Mutex<Router>>),
     None,
     _P(PhantomData<(Down, Up, Sel)>),
 }
@@ -144,7 +147,7 @@ impl<Router: CommonRouter> Clone for CommonRoutingLogic<Router> {
     fn clone(&self) -> Self {
         match self {
             Self::None => Self::None,
-            Self::Proxy(x) => Self::Proxy(x),
+            Self::Proxy(x) => Self::Proxy(x.clone()),
         }
     }
 }
@@ -159,9 +162,8 @@ impl<
     fn clone(&self) -> Self {
         match self {
             Self::None => Self::None,
-            Self::Proxy(x) => Self::Proxy(x),
-            // Variant used only for PhantomData safe to panic here
-            Self::_P(_) => panic!(),
+            Self::Proxy(x) => Self::Proxy(x.clone()),
+            Self::_P(_) => Self::_P(PhantomData),
         }
     }
 }
@@ -175,6 +177,7 @@ impl<
     fn on_setup_connection(
         &mut self,
         message: &SetupConnection,
+        remote_address: Option<SocketAddr>,
     ) -> Result<(CommonDownstreamData, SetupConnectionSuccess), Error> {
         let protocol = message.protocol;
         let min_v = message.min_version;
@@ -189,10 +192,15 @@ impl<
         let header_only = has_requires_std_job(pair_settings.flags);
         match (protocol, header_only) {
             (Protocol::MiningProtocol, true) => {
-                self.on_setup_connection_mining_header_only(&pair_settings)
+                let downstream_data =
+                    CommonDownstreamData::from_setup_connection(message, remote_address);
+                self.on_setup_connection_mining_header_only(&pair_settings, downstream_data)
+            }
+            (Protocol::MiningProtocol, false) => {
+                Err(RoutingError::UnsupportedFlags(pair_settings.flags).into())
             }
             // TODO add handler for other protocols
-            _ => panic!(),
+            (protocol, _) => Err(RoutingError::UnsupportedProtocol(protocol).into()),
         }
     }
 }
@@ -216,13 +224,16 @@ impl<
     ) -> Result<Arc<Mutex<Down>>, Error> {
         let upstream_request_id = request.get_request_id_as_u32();
         let original_request_id = upstream
-            // if we are here get_mapper should always return Some(mappe) so below unwrap is ok
-            .safe_lock(|u| u.get_mapper().unwrap().remove(upstream_request_id))
+            .safe_lock(|u| {
+                u.get_mapper()
+                    .map(|mapper| mapper.remove(upstream_request_id))
+            })
             // Is fine to unwrap a safe_lock result
             .unwrap()
+            .ok_or(RoutingError::NoRequestIdMapper)?
             .ok_or(Error::RequestIdNotMapped(upstream_request_id))?;
         request.update_id(original_request_id);
-        let downstreams = upstream
+        upstream
             .safe_lock(|u| {
                 let selector = u.get_remote_selector();
                 selector.on_open_standard_channel_success(
@@ -232,8 +243,7 @@ impl<
                 )
             })
             // Is fine to unwrap a safe_lock result
-            .unwrap();
-        downstreams
+            .unwrap()
     }
 
     /// At this point the Sv2 connection with downstream is initialized that means that
@@ -250,18 +260,16 @@ impl<
         let upstreams = self
             .downstream_to_upstream_map
             .get(downstream_mining_data)
-            // If we are here a list of possible upstream has been already selected so the below
-            // unwrap can not panic
-            .unwrap();
+            .ok_or(RoutingError::DownstreamNotFound)?;
         // TODO the upstream selection logic should be specified by the caller
-        let upstream =
-            Self::select_upstreams(&mut upstreams.to_vec()).ok_or(Error::NoUpstreamsConnected)?;
+        let upstream = Self::select_upstreams(&mut upstreams.to_vec())
+            .ok_or(RoutingError::NoUpstreamAvailable)?;
         let old_id = request.get_request_id_as_u32();
         let new_req_id = upstream
-            // if we are here get_mapper should always return Some(mappe) so below unwrap is ok
-            .safe_lock(|u| u.get_mapper().unwrap().on_open_channel(old_id))
+            .safe_lock(|u| u.get_mapper().map(|mapper| mapper.on_open_channel(old_id)))
             // Is fine to unwrap a safe_lock result
-            .unwrap();
+            .unwrap()
+            .ok_or(RoutingError::NoRequestIdMapper)?;
         request.update_id(new_req_id);
         self.on_open_standard_channel_request_header_only(downstream, request)
     }
@@ -361,26 +369,80 @@ impl<
     pub fn on_setup_connection_mining_header_only(
         &mut self,
         pair_settings: &PairSettings,
+        downstream_data: CommonDownstreamData,
     ) -> Result<(CommonDownstreamData, SetupConnectionSuccess), Error> {
         let mut upstreams = self.upstream_selector.on_setup_connection(pair_settings)?;
+        upstreams.0.retain(|upstream| {
+            upstream
+                .safe_lock(|u| u.accepts_downstream(&downstream_data))
+                // Is fine to unwrap a safe_lock result
+                .unwrap()
+        });
         // TODO the upstream selection logic should be specified by the caller
         let upstream =
-            Self::select_upstreams(&mut upstreams.0).ok_or(Error::NoUpstreamsConnected)?;
-        let downstream_data = CommonDownstreamData {
-            header_only: true,
-            work_selection: false,
-            version_rolling: false,
-        };
+            Self::select_upstreams(&mut upstreams.0).ok_or(RoutingError::NoUpstreamAvailable)?;
+        // The upstream has been selected because its version is in the range requested by the
+        // downstream
+        // Is fine to unwrap a safe_lock result
+        let (used_version, flags) = upstream
+            .safe_lock(|u| (u.get_version(), u.get_flags()))
+            .unwrap();
         let message = SetupConnectionSuccess {
-            used_version: 2,
-            // Is fine to unwrap a safe_lock result
-            flags: upstream.safe_lock(|u| u.get_flags()).unwrap(),
+            used_version,
+            flags,
         };
         self.downstream_to_upstream_map
-            .insert(downstream_data, vec![upstream]);
+            .insert(downstream_data.clone(), vec![upstream]);
         Ok((downstream_data, message))
     }
 
+    /// When an upstream fail (eg it is quarantined) the downstreams paired with it must be remapped
+    /// to another upstream. A new upstream is selected between the non quarantined and non
+    /// draining upstreams that have the same version and flags of the failed one (so that the
+    /// SetupConnectionSuccess already sent to the downstreams is still valid) and it replace the
+    /// failed upstream for every downstream.
+    ///
+    /// The new upstream is returned, it is duty of the caller to send ChannelEndpointChanged to the
+    /// remapped downstreams so that they reset the channel state and open new channels.
+    pub fn on_upstream_failover(
+        &mut self,
+        failed: &Arc<Mutex<Up>>,
+    ) -> Result<Arc<Mutex<Up>>, Error> {
+        // Is fine to unwrap a safe_lock result
+        let (version, flags) = failed
+            .safe_lock(|u| (u.get_version(), u.get_flags()))
+            .unwrap();
+        let mut candidates: Vec<Arc<Mutex<Up>>> = self
+            .upstream_selector
+            .upstreams
+            .iter()
+            .filter(|up| {
+                !Arc::ptr_eq(up, failed)
+                    // Is fine to unwrap a safe_lock result
+                    && up
+                        .safe_lock(|u| {
+                            !u.is_quarantined()
+                                && !u.is_draining()
+                                && u.get_version() == version
+                                && u.get_flags() == flags
+                        })
+                        .unwrap()
+            })
+            .cloned()
+            .collect();
+        let new_upstream =
+            Self::select_upstreams(&mut candidates).ok_or(RoutingError::NoUpstreamAvailable)?;
+        for upstreams in self.downstream_to_upstream_map.values_mut() {
+            for upstream in upstreams.iter_mut() {
+                if Arc::ptr_eq(upstream, failed) {
+                    *upstream = new_upstream.clone();
+                }
+            }
+            upstreams.dedup_by(|a, b| Arc::ptr_eq(a, b));
+        }
+        Ok(new_upstream)
+    }
+
     /// On open standard channel request:
     /// 1. an upstream must be selected between the possibles upstreams for this downstream, if the
     ///    downstream* is header only, just one upstream will be there so the choice is easy, if not
@@ -409,8 +471,9 @@ impl<
         let upstream = self
             .downstream_to_upstream_map
             .get(&downstream_mining_data)
-            // if we are here upstream has already been selected so is ok to unwrap here
-            .unwrap()[0]
+            .ok_or(RoutingError::DownstreamNotFound)?
+            .first()
+            .ok_or(RoutingError::NoUpstreamAvailable)?
             .clone();
         upstream
             .safe_lock(|u| {
@@ -418,7 +481,7 @@ impl<
                 selector.on_open_standard_channel_request(request.request_id.as_u32(), downstream)
             })
             // Is fine to unwrap a safe_lock result
-            .unwrap();
+            .unwrap()?;
         Ok(upstream)
     }
 }
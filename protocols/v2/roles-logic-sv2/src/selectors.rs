@@ -2,10 +2,14 @@
 //! a message should be ralyied, or to which remote or set of remotes a message should be sent.
 use crate::{
     common_properties::{IsDownstream, IsMiningDownstream, IsMiningUpstream, PairSettings},
-    errors::Error,
+    errors::{Error, RoutingError},
     utils::Mutex,
 };
-use std::{collections::HashMap, fmt::Debug as D, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug as D,
+    sync::Arc,
+};
 
 /// A DownstreamMiningSelector useful for routing messages in a mining proxy
 #[derive(Debug, Clone, Default)]
@@ -29,13 +33,51 @@ impl<Down: IsDownstream> ProxyDownstreamMiningSelector<Down> {
     {
         Arc::new(Mutex::new(Self::new()))
     }
+
+    /// Remove every downstream from the selector and return them, each downstream is returned only
+    /// once also if it has more than one channel.
+    pub fn remove_all_downstreams(&mut self) -> Vec<Arc<Mutex<Down>>> {
+        let mut downstreams: Vec<Arc<Mutex<Down>>> = Vec::new();
+        let all = self
+            .request_id_to_remotes
+            .drain()
+            .map(|(_, d)| d)
+            .chain(self.channel_id_to_downstreams.drain().flat_map(|(_, d)| d))
+            .chain(self.channel_id_to_downstream.drain().map(|(_, d)| d));
+        for downstream in all {
+            if !downstreams.iter().any(|d| Arc::ptr_eq(d, &downstream)) {
+                downstreams.push(downstream);
+            }
+        }
+        downstreams
+    }
+
+    /// Forget an open channel request that will not be answered (eg it timed out or the upstream
+    /// rejected it) and return the downstream that sent it
+    pub fn remove_request(&mut self, request_id: u32) -> Option<Arc<Mutex<Down>>> {
+        self.request_id_to_remotes.remove(&request_id)
+    }
+
+    /// Forget the standard channel `channel_id` and return the downstream that opened it. The
+    /// downstream is still in its group, it could have other channels in it.
+    pub fn remove_channel(&mut self, channel_id: u32) -> Option<Arc<Mutex<Down>>> {
+        self.channel_id_to_downstream.remove(&channel_id)
+    }
 }
 
 impl<Down: IsMiningDownstream> DownstreamMiningSelector<Down>
     for ProxyDownstreamMiningSelector<Down>
 {
-    fn on_open_standard_channel_request(&mut self, request_id: u32, downstream: Arc<Mutex<Down>>) {
+    fn on_open_standard_channel_request(
+        &mut self,
+        request_id: u32,
+        downstream: Arc<Mutex<Down>>,
+    ) -> Result<(), Error> {
+        if self.request_id_to_remotes.contains_key(&request_id) {
+            return Err(RoutingError::DuplicateRequestId(request_id).into());
+        }
         self.request_id_to_remotes.insert(request_id, downstream);
+        Ok(())
     }
 
     fn on_open_standard_channel_success(
@@ -71,16 +113,206 @@ impl<Down: IsMiningDownstream> DownstreamMiningSelector<Down>
 
 impl<Down: IsMiningDownstream> DownstreamSelector<Down> for ProxyDownstreamMiningSelector<Down> {}
 
+/// Virtual nodes of every downstream on the ring of a `ConsistentHashDownstreamMiningSelector`
+pub const DEFAULT_VIRTUAL_NODES: u32 = 64;
+
+/// Position on the ring of `key` (splitmix64 finalizer)
+fn ring_point(key: u64) -> u64 {
+    let mut x = key.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// A DownstreamMiningSelector that place the downstreams on a consistent hashing ring.
+///
+/// Every downstream get an id when it send its first open channel request and is placed on the
+/// ring with `virtual_nodes` points. A standard channel is routed to the downstream that opened
+/// it, a channel that have no owner (never opened trough this selector or opened by a downstream
+/// that has been removed) is routed to the first downstream that follow the channel id on the
+/// ring. When a downstream is added or removed only the channels that hash on its points move,
+/// the other channels keep their downstream.
+#[derive(Debug, Clone)]
+pub struct ConsistentHashDownstreamMiningSelector<Down: IsDownstream> {
+    virtual_nodes: u32,
+    ring: BTreeMap<u64, u32>,
+    downstreams: HashMap<u32, Arc<Mutex<Down>>>,
+    /// Address of the downstream mutex -> id of the downstream
+    downstream_ids: HashMap<usize, u32>,
+    next_id: u32,
+    request_id_to_remotes: HashMap<u32, Arc<Mutex<Down>>>,
+    channel_id_to_downstreams: HashMap<u32, Vec<Arc<Mutex<Down>>>>,
+    channel_id_to_downstream: HashMap<u32, u32>,
+}
+
+impl<Down: IsDownstream> Default for ConsistentHashDownstreamMiningSelector<Down> {
+    fn default() -> Self {
+        Self::new(DEFAULT_VIRTUAL_NODES)
+    }
+}
+
+impl<Down: IsDownstream> ConsistentHashDownstreamMiningSelector<Down> {
+    /// `virtual_nodes` is the number of points of every downstream on the ring, more points
+    /// spread the channels more evenly but make adding and removing a downstream slower
+    pub fn new(virtual_nodes: u32) -> Self {
+        Self {
+            virtual_nodes: virtual_nodes.max(1),
+            ring: BTreeMap::new(),
+            downstreams: HashMap::new(),
+            downstream_ids: HashMap::new(),
+            next_id: 0,
+            request_id_to_remotes: HashMap::new(),
+            channel_id_to_downstreams: HashMap::new(),
+            channel_id_to_downstream: HashMap::new(),
+        }
+    }
+
+    pub fn new_as_mutex(virtual_nodes: u32) -> Arc<Mutex<Self>>
+    where
+        Self: Sized,
+    {
+        Arc::new(Mutex::new(Self::new(virtual_nodes)))
+    }
+
+    fn point(downstream_id: u32, virtual_node: u32) -> u64 {
+        ring_point(((downstream_id as u64) << 32) | virtual_node as u64)
+    }
+
+    /// Place `downstream` on the ring and return its id, a downstream already on the ring keep
+    /// its id
+    pub fn add_downstream(&mut self, downstream: Arc<Mutex<Down>>) -> u32 {
+        let key = Arc::as_ptr(&downstream) as usize;
+        if let Some(id) = self.downstream_ids.get(&key) {
+            return *id;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        for virtual_node in 0..self.virtual_nodes {
+            // On a collision the point stay to the downstream that was there first
+            self.ring.entry(Self::point(id, virtual_node)).or_insert(id);
+        }
+        self.downstream_ids.insert(key, id);
+        self.downstreams.insert(id, downstream);
+        id
+    }
+
+    /// Remove `downstream` from the ring, from the groups and from its pending requests. Its
+    /// channels are moved to the next downstreams on the ring. Return the id that it had.
+    pub fn remove_downstream(&mut self, downstream: &Arc<Mutex<Down>>) -> Option<u32> {
+        let id = self
+            .downstream_ids
+            .remove(&(Arc::as_ptr(downstream) as usize))?;
+        self.downstreams.remove(&id);
+        for virtual_node in 0..self.virtual_nodes {
+            let point = Self::point(id, virtual_node);
+            if self.ring.get(&point) == Some(&id) {
+                self.ring.remove(&point);
+            }
+        }
+        self.request_id_to_remotes
+            .retain(|_, d| !Arc::ptr_eq(d, downstream));
+        self.channel_id_to_downstream.retain(|_, d| *d != id);
+        for downstreams in self.channel_id_to_downstreams.values_mut() {
+            downstreams.retain(|d| !Arc::ptr_eq(d, downstream));
+        }
+        self.channel_id_to_downstreams.retain(|_, d| !d.is_empty());
+        Some(id)
+    }
+
+    /// Downstream that follow `channel_id` on the ring, None if the ring is empty
+    pub fn hashed_downstream(&self, channel_id: u32) -> Option<Arc<Mutex<Down>>> {
+        let point = ring_point(channel_id as u64);
+        self.ring
+            .range(point..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .and_then(|(_, id)| self.downstreams.get(id))
+            .cloned()
+    }
+
+    /// Forget an open channel request that will not be answered (eg it timed out or the upstream
+    /// rejected it) and return the downstream that sent it
+    pub fn remove_request(&mut self, request_id: u32) -> Option<Arc<Mutex<Down>>> {
+        self.request_id_to_remotes.remove(&request_id)
+    }
+
+    /// Forget the owner of the standard channel `channel_id`, the channel is routed trough the
+    /// ring from now on. Return the downstream that opened it.
+    pub fn remove_channel(&mut self, channel_id: u32) -> Option<Arc<Mutex<Down>>> {
+        self.channel_id_to_downstream
+            .remove(&channel_id)
+            .and_then(|id| self.downstreams.get(&id))
+            .cloned()
+    }
+}
+
+impl<Down: IsMiningDownstream> DownstreamMiningSelector<Down>
+    for ConsistentHashDownstreamMiningSelector<Down>
+{
+    fn on_open_standard_channel_request(
+        &mut self,
+        request_id: u32,
+        downstream: Arc<Mutex<Down>>,
+    ) -> Result<(), Error> {
+        if self.request_id_to_remotes.contains_key(&request_id) {
+            return Err(RoutingError::DuplicateRequestId(request_id).into());
+        }
+        self.add_downstream(downstream.clone());
+        self.request_id_to_remotes.insert(request_id, downstream);
+        Ok(())
+    }
+
+    fn on_open_standard_channel_success(
+        &mut self,
+        request_id: u32,
+        g_channel_id: u32,
+        channel_id: u32,
+    ) -> Result<Arc<Mutex<Down>>, Error> {
+        let downstream = self
+            .request_id_to_remotes
+            .remove(&request_id)
+            .ok_or(Error::UnknownRequestId(request_id))?;
+        let id = self.add_downstream(downstream.clone());
+        self.channel_id_to_downstream.insert(channel_id, id);
+        match self.channel_id_to_downstreams.get_mut(&g_channel_id) {
+            None => {
+                self.channel_id_to_downstreams
+                    .insert(g_channel_id, vec![downstream.clone()]);
+            }
+            Some(x) => x.push(downstream.clone()),
+        }
+        Ok(downstream)
+    }
+
+    fn get_downstreams_in_channel(&self, channel_id: u32) -> Option<&Vec<Arc<Mutex<Down>>>> {
+        self.channel_id_to_downstreams.get(&channel_id)
+    }
+
+    fn downstream_from_channel_id(&self, channel_id: u32) -> Option<Arc<Mutex<Down>>> {
+        match self.channel_id_to_downstream.get(&channel_id) {
+            Some(id) => self.downstreams.get(id).cloned(),
+            None => self.hashed_downstream(channel_id),
+        }
+    }
+}
+
+impl<Down: IsMiningDownstream> DownstreamSelector<Down>
+    for ConsistentHashDownstreamMiningSelector<Down>
+{
+}
+
 /// Implemented by a selector used by an upstream mining node or and upstream mining node
 /// abstraction in order to find the right downstream to which a message should be sent or relayied
 pub trait DownstreamMiningSelector<Downstream: IsMiningDownstream>:
     DownstreamSelector<Downstream>
 {
+    /// Err(DuplicateRequestId) if a request with the same id is already pending, the pending
+    /// request is kept
     fn on_open_standard_channel_request(
         &mut self,
         request_id: u32,
         downstream: Arc<Mutex<Downstream>>,
-    );
+    ) -> Result<(), Error>;
 
     fn on_open_standard_channel_success(
         &mut self,
@@ -122,7 +354,7 @@ impl<Down: IsMiningDownstream + D> DownstreamMiningSelector<Down> for NullDownst
         &mut self,
         _request_id: u32,
         _downstream: Arc<Mutex<Down>>,
-    ) {
+    ) -> Result<(), Error> {
         unreachable!("on_open_standard_channel_request")
     }
 
@@ -211,7 +443,8 @@ impl<
     > UpstreamMiningSelctor<Down, Up, Sel> for GeneralMiningSelector<Sel, Down, Up>
 {
     /// Return the set of mining upstream nodes that can accept messages from a downstream with
-    /// the passed PairSettings and the sum of all the accepted flags
+    /// the passed PairSettings and the sum of all the accepted flags. Quarantined and draining
+    /// upstreams are skipped
     #[allow(clippy::type_complexity)]
     fn on_setup_connection(
         &mut self,
@@ -221,7 +454,9 @@ impl<
         let mut supported_flags: u32 = 0;
         for node in &self.upstreams {
             let is_pairable = node
-                .safe_lock(|node| node.is_pairable(pair_settings))
+                .safe_lock(|node| {
+                    node.is_pairable(pair_settings) && !node.is_quarantined() && !node.is_draining()
+                })
                 // Is ok to unwrap safe_lock result
                 .unwrap();
             if is_pairable {
@@ -241,3 +476,75 @@ impl<
         self.id_to_upstream.get(&upstream_id).cloned()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_channel(
+        selector: &mut ConsistentHashDownstreamMiningSelector<()>,
+        downstream: &Arc<Mutex<()>>,
+        channel_id: u32,
+    ) {
+        selector
+            .on_open_standard_channel_request(channel_id, downstream.clone())
+            .unwrap();
+        selector
+            .on_open_standard_channel_success(channel_id, 1, channel_id)
+            .unwrap();
+    }
+
+    #[test]
+    fn moves_only_the_channels_of_the_changed_downstream() {
+        let mut selector = ConsistentHashDownstreamMiningSelector::new(DEFAULT_VIRTUAL_NODES);
+        assert!(selector.downstream_from_channel_id(1).is_none());
+        let downstreams: Vec<Arc<Mutex<()>>> = (0..4).map(|_| Arc::new(Mutex::new(()))).collect();
+        for (channel_id, downstream) in downstreams.iter().enumerate() {
+            open_channel(&mut selector, downstream, channel_id as u32);
+        }
+        assert_eq!(selector.add_downstream(downstreams[2].clone()), 2);
+        selector
+            .on_open_standard_channel_request(9, downstreams[0].clone())
+            .unwrap();
+        assert!(matches!(
+            selector.on_open_standard_channel_request(9, downstreams[1].clone()),
+            Err(Error::Routing(RoutingError::DuplicateRequestId(9)))
+        ));
+        let pending = selector.remove_request(9).unwrap();
+        assert!(Arc::ptr_eq(&pending, &downstreams[0]));
+        assert_eq!(selector.get_downstreams_in_channel(1).unwrap().len(), 4);
+        let opened = selector.downstream_from_channel_id(3).unwrap();
+        assert!(Arc::ptr_eq(&opened, &downstreams[3]));
+
+        let owners = |selector: &ConsistentHashDownstreamMiningSelector<()>| {
+            (100..10_100)
+                .map(|channel_id| selector.downstream_from_channel_id(channel_id).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let before = owners(&selector);
+        for downstream in &downstreams {
+            let channels = before.iter().filter(|d| Arc::ptr_eq(d, downstream)).count();
+            assert!(channels > 1_500 && channels < 3_500);
+        }
+
+        // Only the channels of the removed downstream move, the opened ones are rehashed
+        assert_eq!(selector.remove_downstream(&downstreams[0]), Some(0));
+        assert_eq!(selector.get_downstreams_in_channel(1).unwrap().len(), 3);
+        let opened = selector.downstream_from_channel_id(0).unwrap();
+        assert!(!Arc::ptr_eq(&opened, &downstreams[0]));
+        let after = owners(&selector);
+        for (before, after) in before.iter().zip(after.iter()) {
+            if !Arc::ptr_eq(before, &downstreams[0]) {
+                assert!(Arc::ptr_eq(before, after));
+            }
+        }
+
+        // A new downstream only take channels
+        let new = Arc::new(Mutex::new(()));
+        assert_eq!(selector.add_downstream(new.clone()), 4);
+        let with_new = owners(&selector);
+        for (after, with_new) in after.iter().zip(with_new.iter()) {
+            assert!(Arc::ptr_eq(after, with_new) || Arc::ptr_eq(with_new, &new));
+        }
+    }
+}
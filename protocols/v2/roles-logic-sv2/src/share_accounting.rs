@@ -0,0 +1,135 @@
+//! Acknowledgement of the accepted shares of the channels.
+//!
+//! A `SubmitSharesSuccess` can acknowledge many shares: `last_sequence_number` is the sequence
+//! number of the most recent accepted share, `new_submits_accepted_count` and `new_shares_sum` are
+//! the accepted shares (and their sum) since the previous success sent on the channel.
+//! `ShareAccounting` keep these values for every channel so that the handlers do not have to
+//! build them. Rejected shares are answered one by one with `SubmitSharesError` and are not
+//! counted.
+use mining_sv2::SubmitSharesSuccess;
+use std::collections::HashMap;
+
+/// Accepted shares of a channel not yet acknowledged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct PendingShares {
+    last_sequence_number: u32,
+    accepted_count: u32,
+    shares_sum: u64,
+}
+
+#[derive(Debug)]
+pub struct ShareAccounting {
+    /// A success is built every `batch_size` accepted shares
+    batch_size: u32,
+    // channel_id -> pending shares
+    channels: HashMap<u32, PendingShares>,
+}
+
+impl ShareAccounting {
+    /// Acknowledge the shares in batches of `batch_size`, 1 acknowledge every share as soon as
+    /// is accepted. An incomplete batch is acknowledged by `flush`.
+    pub fn new(batch_size: u32) -> Self {
+        Self {
+            batch_size: batch_size.max(1),
+            channels: HashMap::new(),
+        }
+    }
+
+    /// Count an accepted share worth `shares` (eg 1, or the difficulty of the channel for pools
+    /// that weight the shares), return the success for the channel if the batch is complete
+    pub fn on_accepted(
+        &mut self,
+        channel_id: u32,
+        sequence_number: u32,
+        shares: u64,
+    ) -> Option<SubmitSharesSuccess> {
+        let pending = self.channels.entry(channel_id).or_default();
+        pending.last_sequence_number = sequence_number;
+        pending.accepted_count += 1;
+        pending.shares_sum = pending.shares_sum.saturating_add(shares);
+        if pending.accepted_count >= self.batch_size {
+            self.take_success(channel_id)
+        } else {
+            None
+        }
+    }
+
+    /// Success for the accepted shares of the channel that are not yet acknowledged
+    pub fn take_success(&mut self, channel_id: u32) -> Option<SubmitSharesSuccess> {
+        let pending = self.channels.get_mut(&channel_id)?;
+        if pending.accepted_count == 0 {
+            return None;
+        }
+        let success = SubmitSharesSuccess {
+            channel_id,
+            last_sequence_number: pending.last_sequence_number,
+            new_submits_accepted_count: pending.accepted_count,
+            new_shares_sum: pending.shares_sum,
+        };
+        pending.accepted_count = 0;
+        pending.shares_sum = 0;
+        Some(success)
+    }
+
+    /// Successes for every channel with accepted shares not yet acknowledged
+    pub fn flush(&mut self) -> Vec<SubmitSharesSuccess> {
+        let channel_ids: Vec<u32> = self.channels.keys().copied().collect();
+        channel_ids
+            .into_iter()
+            .filter_map(|channel_id| self.take_success(channel_id))
+            .collect()
+    }
+
+    /// Forget a closed channel, its pending shares are not acknowledged
+    pub fn remove_channel(&mut self, channel_id: u32) {
+        self.channels.remove(&channel_id);
+    }
+}
+
+impl Default for ShareAccounting {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acknowledges_every_share() {
+        let mut accounting = ShareAccounting::default();
+        let success = accounting.on_accepted(1, 10, 4).unwrap();
+        assert_eq!(success.last_sequence_number, 10);
+        assert_eq!(success.new_submits_accepted_count, 1);
+        assert_eq!(success.new_shares_sum, 4);
+        // The sum is for the shares since the last success
+        let success = accounting.on_accepted(1, 12, 4).unwrap();
+        assert_eq!(success.last_sequence_number, 12);
+        assert_eq!(success.new_shares_sum, 4);
+        assert!(accounting.flush().is_empty());
+    }
+
+    #[test]
+    fn aggregates_successes() {
+        let mut accounting = ShareAccounting::new(3);
+        assert!(accounting.on_accepted(1, 0, 1).is_none());
+        assert!(accounting.on_accepted(2, 0, 2).is_none());
+        assert!(accounting.on_accepted(1, 1, 1).is_none());
+        let success = accounting.on_accepted(1, 3, 1).unwrap();
+        assert_eq!(success.channel_id, 1);
+        assert_eq!(success.last_sequence_number, 3);
+        assert_eq!(success.new_submits_accepted_count, 3);
+        assert_eq!(success.new_shares_sum, 3);
+        assert!(accounting.take_success(1).is_none());
+
+        let successes = accounting.flush();
+        assert_eq!(successes.len(), 1);
+        assert_eq!(successes[0].channel_id, 2);
+        assert_eq!(successes[0].new_shares_sum, 2);
+
+        accounting.on_accepted(2, 1, 2);
+        accounting.remove_channel(2);
+        assert!(accounting.flush().is_empty());
+    }
+}
@@ -0,0 +1,168 @@
+//! Telemetry extension.
+//!
+//! Downstream devices report the hashrate, the temperature and the error counters of their
+//! channels to the upstream with SubmitTelemetry, a channel message of the
+//! `EXTENSION_TYPE_TELEMETRY` extension. The downstream build the message with
+//! `DeviceTelemetry::to_message`, the upstream register a `TelemetryHandler` in its `Extensions`
+//! and read the last report of every channel from the handler's `TelemetryStore`.
+//!
+//! SubmitTelemetry payload (little endian):
+//! * channel_id u32
+//! * hashrate f32, h/s
+//! * temperature f32, degrees Celsius
+//! * hardware_errors u32, counter since the channel has been opened
+//! * rejected_shares u32, counter since the channel has been opened
+use crate::{
+    errors::Error,
+    extensions::{ExtensionHandler, ExtensionMessage},
+    message_registry::BadHeader,
+    utils::Mutex,
+};
+use const_sv2::{
+    CHANNEL_BIT_SUBMIT_TELEMETRY, EXTENSION_TYPE_TELEMETRY, MESSAGE_TYPE_SUBMIT_TELEMETRY,
+};
+use std::{collections::HashMap, convert::TryInto, sync::Arc};
+
+/// Last report of every channel, channel_id -> report
+pub type TelemetryStore = Arc<Mutex<HashMap<u32, DeviceTelemetry>>>;
+
+/// SubmitTelemetry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviceTelemetry {
+    pub channel_id: u32,
+    pub hashrate: f32,
+    pub temperature: f32,
+    pub hardware_errors: u32,
+    pub rejected_shares: u32,
+}
+
+impl DeviceTelemetry {
+    const SIZE: usize = 20;
+
+    pub fn to_message(&self) -> ExtensionMessage {
+        let mut payload = Vec::with_capacity(Self::SIZE);
+        payload.extend_from_slice(&self.channel_id.to_le_bytes());
+        payload.extend_from_slice(&self.hashrate.to_le_bytes());
+        payload.extend_from_slice(&self.temperature.to_le_bytes());
+        payload.extend_from_slice(&self.hardware_errors.to_le_bytes());
+        payload.extend_from_slice(&self.rejected_shares.to_le_bytes());
+        ExtensionMessage {
+            extension_type: EXTENSION_TYPE_TELEMETRY,
+            msg_type: MESSAGE_TYPE_SUBMIT_TELEMETRY,
+            channel_msg: CHANNEL_BIT_SUBMIT_TELEMETRY,
+            payload,
+        }
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self, Error> {
+        if payload.len() != Self::SIZE {
+            return Err(Error::BinarySv2Error(binary_sv2::Error::OutOfBound));
+        }
+        // Is fine to unwrap, the chunks are 4 bytes long
+        let field = |i: usize| -> [u8; 4] { payload[i * 4..(i + 1) * 4].try_into().unwrap() };
+        Ok(Self {
+            channel_id: u32::from_le_bytes(field(0)),
+            hashrate: f32::from_le_bytes(field(1)),
+            temperature: f32::from_le_bytes(field(2)),
+            hardware_errors: u32::from_le_bytes(field(3)),
+            rejected_shares: u32::from_le_bytes(field(4)),
+        })
+    }
+}
+
+/// Upstream side of the extension, save every received report in the store
+#[derive(Debug)]
+pub struct TelemetryHandler {
+    store: TelemetryStore,
+}
+
+impl Default for TelemetryHandler {
+    fn default() -> Self {
+        Self::new(Arc::new(Mutex::new(HashMap::new())))
+    }
+}
+
+impl TelemetryHandler {
+    pub fn new(store: TelemetryStore) -> Self {
+        Self { store }
+    }
+
+    pub fn store(&self) -> TelemetryStore {
+        self.store.clone()
+    }
+}
+
+impl ExtensionHandler for TelemetryHandler {
+    fn extension_type(&self) -> u16 {
+        EXTENSION_TYPE_TELEMETRY
+    }
+
+    fn handle_message(
+        &mut self,
+        msg_type: u8,
+        channel_msg: bool,
+        payload: &mut [u8],
+    ) -> Result<Vec<ExtensionMessage>, Error> {
+        match msg_type {
+            MESSAGE_TYPE_SUBMIT_TELEMETRY if channel_msg == CHANNEL_BIT_SUBMIT_TELEMETRY => {
+                let report = DeviceTelemetry::from_payload(payload)?;
+                // Is fine to unwrap on safe_lock
+                self.store
+                    .safe_lock(|s| s.insert(report.channel_id, report))
+                    .unwrap();
+                Ok(Vec::new())
+            }
+            MESSAGE_TYPE_SUBMIT_TELEMETRY => Err(Error::BadHeader(BadHeader::WrongChannelBit(
+                msg_type,
+                CHANNEL_BIT_SUBMIT_TELEMETRY,
+            ))),
+            _ => Err(Error::BadHeader(BadHeader::UnknownMessageType(
+                EXTENSION_TYPE_TELEMETRY,
+                msg_type,
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::Extensions;
+
+    #[test]
+    fn reports_reach_the_store() {
+        let handler = TelemetryHandler::default();
+        let store = handler.store();
+        let mut upstream = Extensions::new();
+        upstream.register(Box::new(handler));
+        let mut downstream = Extensions::new();
+        downstream.register(Box::new(TelemetryHandler::default()));
+
+        let request = downstream.request_extensions(1);
+        upstream
+            .handle_message(&request.header().unwrap(), &mut request.payload.clone())
+            .unwrap();
+
+        let report = DeviceTelemetry {
+            channel_id: 7,
+            hashrate: 14.5e12,
+            temperature: 71.5,
+            hardware_errors: 3,
+            rejected_shares: 1,
+        };
+        let message = report.to_message();
+        let responses = upstream
+            .handle_message(&message.header().unwrap(), &mut message.payload.clone())
+            .unwrap();
+        assert!(responses.is_empty());
+        assert_eq!(
+            store.safe_lock(|s| s.get(&7).copied()).unwrap(),
+            Some(report)
+        );
+
+        let mut truncated = message.payload[..8].to_vec();
+        assert!(upstream
+            .handle_message(&message.header().unwrap(), &mut truncated)
+            .is_err());
+    }
+}
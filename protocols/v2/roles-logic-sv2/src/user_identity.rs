@@ -0,0 +1,217 @@
+//! Parsing of the `user_identity` of the OpenMiningChannel messages.
+//!
+//! Miners name their channels `account.worker`: the account is who get paid, the worker identify
+//! the machine (or the farm) so that the hash rate and the shares can be reported per worker. The
+//! worker is optional and can contain the separator (`account.rack1.s19` is the worker
+//! `rack1.s19` of `account`).
+//!
+//! The pool choose the rules (separator, lengths, allowed symbols) with `IdentityRules`, channels
+//! with an invalid identity should be rejected with the `unknown-user` error code.
+use std::{fmt, str};
+
+/// A `user_identity` split and normalized by `IdentityRules::parse`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UserIdentity {
+    pub account: String,
+    pub worker: Option<String>,
+}
+
+/// The canonical form `account.worker`, whatever separator the miner used
+impl fmt::Display for UserIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.worker {
+            Some(worker) => write!(f, "{}.{}", self.account, worker),
+            None => write!(f, "{}", self.account),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidUserIdentity {
+    NotUtf8,
+    EmptyAccount,
+    /// The rules require a worker name
+    MissingWorker,
+    AccountTooLong,
+    WorkerTooLong,
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for InvalidUserIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotUtf8 => write!(f, "User identity is not valid utf8"),
+            Self::EmptyAccount => write!(f, "Empty account"),
+            Self::MissingWorker => write!(f, "Missing worker name"),
+            Self::AccountTooLong => write!(f, "Account too long"),
+            Self::WorkerTooLong => write!(f, "Worker name too long"),
+            Self::InvalidCharacter(c) => write!(f, "Invalid character {:?}", c),
+        }
+    }
+}
+
+impl std::error::Error for InvalidUserIdentity {}
+
+/// How a `user_identity` is split and validated. Ascii alphanumeric characters are always
+/// allowed, the default rules accept `account.worker` identities with `-` and `_` as symbols.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentityRules {
+    /// Split the account from the worker, only the first occurrence is used
+    pub separator: char,
+    /// Symbols allowed in the account and in the worker beside the ascii alphanumeric characters
+    pub allowed_symbols: String,
+    pub max_account_len: usize,
+    pub max_worker_len: usize,
+    /// Reject the identities without a worker name
+    pub require_worker: bool,
+    /// Lowercase the account, only for the pools that use case insensitive accounts
+    pub lowercase_account: bool,
+}
+
+impl Default for IdentityRules {
+    fn default() -> Self {
+        Self {
+            separator: '.',
+            allowed_symbols: "-_".to_string(),
+            max_account_len: 64,
+            max_worker_len: 64,
+            require_worker: false,
+            lowercase_account: false,
+        }
+    }
+}
+
+impl IdentityRules {
+    /// Split `user_identity` in account and worker. Surrounding whitespace is ignored and an empty
+    /// worker (`account.`) is the same as no worker.
+    pub fn parse(&self, user_identity: &str) -> Result<UserIdentity, InvalidUserIdentity> {
+        let user_identity = user_identity.trim();
+        let (account, worker) = match user_identity.split_once(self.separator) {
+            Some((account, "")) => (account, None),
+            Some((account, worker)) => (account, Some(worker)),
+            None => (user_identity, None),
+        };
+        if account.is_empty() {
+            return Err(InvalidUserIdentity::EmptyAccount);
+        }
+        if account.chars().count() > self.max_account_len {
+            return Err(InvalidUserIdentity::AccountTooLong);
+        }
+        self.check_chars(account, false)?;
+        match worker {
+            Some(worker) => {
+                if worker.chars().count() > self.max_worker_len {
+                    return Err(InvalidUserIdentity::WorkerTooLong);
+                }
+                self.check_chars(worker, true)?;
+            }
+            None if self.require_worker => return Err(InvalidUserIdentity::MissingWorker),
+            None => (),
+        }
+        let account = match self.lowercase_account {
+            true => account.to_lowercase(),
+            false => account.to_string(),
+        };
+        Ok(UserIdentity {
+            account,
+            worker: worker.map(str::to_string),
+        })
+    }
+
+    /// Like `parse` for the raw bytes of the `user_identity` field
+    pub fn parse_bytes(&self, user_identity: &[u8]) -> Result<UserIdentity, InvalidUserIdentity> {
+        let user_identity =
+            str::from_utf8(user_identity).map_err(|_| InvalidUserIdentity::NotUtf8)?;
+        self.parse(user_identity)
+    }
+
+    fn check_chars(&self, s: &str, allow_separator: bool) -> Result<(), InvalidUserIdentity> {
+        match s.chars().find(|c| {
+            !(c.is_ascii_alphanumeric()
+                || self.allowed_symbols.contains(*c)
+                || (allow_separator && *c == self.separator))
+        }) {
+            Some(c) => Err(InvalidUserIdentity::InvalidCharacter(c)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(account: &str, worker: Option<&str>) -> UserIdentity {
+        UserIdentity {
+            account: account.to_string(),
+            worker: worker.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn splits_account_and_worker() {
+        let rules = IdentityRules::default();
+        assert_eq!(
+            rules.parse("alice.rig-1"),
+            Ok(identity("alice", Some("rig-1")))
+        );
+        assert_eq!(
+            rules.parse(" alice.rack1.s19 "),
+            Ok(identity("alice", Some("rack1.s19")))
+        );
+        assert_eq!(rules.parse("alice"), Ok(identity("alice", None)));
+        assert_eq!(rules.parse("alice."), Ok(identity("alice", None)));
+        assert_eq!(
+            rules.parse("alice.rack1.s19").unwrap().to_string(),
+            "alice.rack1.s19"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_identities() {
+        let rules = IdentityRules::default();
+        assert_eq!(rules.parse(""), Err(InvalidUserIdentity::EmptyAccount));
+        assert_eq!(rules.parse(".rig"), Err(InvalidUserIdentity::EmptyAccount));
+        assert_eq!(
+            rules.parse("al ice.rig"),
+            Err(InvalidUserIdentity::InvalidCharacter(' '))
+        );
+        assert_eq!(
+            rules.parse("alice.rig/1"),
+            Err(InvalidUserIdentity::InvalidCharacter('/'))
+        );
+        assert_eq!(
+            rules.parse(&"a".repeat(65)),
+            Err(InvalidUserIdentity::AccountTooLong)
+        );
+        assert_eq!(
+            rules.parse_bytes(&[0xff, 0xfe]),
+            Err(InvalidUserIdentity::NotUtf8)
+        );
+    }
+
+    #[test]
+    fn applies_custom_rules() {
+        let rules = IdentityRules {
+            separator: '_',
+            allowed_symbols: "-".to_string(),
+            max_worker_len: 4,
+            require_worker: true,
+            lowercase_account: true,
+            ..IdentityRules::default()
+        };
+        assert_eq!(rules.parse("Alice_s19"), Ok(identity("alice", Some("s19"))));
+        assert_eq!(
+            rules.parse("alice"),
+            Err(InvalidUserIdentity::MissingWorker)
+        );
+        assert_eq!(
+            rules.parse("alice_rig-01"),
+            Err(InvalidUserIdentity::WorkerTooLong)
+        );
+        assert_eq!(
+            rules.parse("alice.bob_s19"),
+            Err(InvalidUserIdentity::InvalidCharacter('.'))
+        );
+    }
+}
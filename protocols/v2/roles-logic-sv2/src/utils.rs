@@ -4,11 +4,12 @@ use binary_sv2::U256;
 use bitcoin::{
     blockdata::block::BlockHeader,
     hash_types::{BlockHash, TxMerkleNode},
-    hashes::{sha256d::Hash as DHash, Hash},
-    util::{hash::bitcoin_merkle_root, psbt::serialize::Deserialize},
+    hashes::{sha256d::Hash as DHash, Hash, HashEngine},
+    util::psbt::serialize::Deserialize,
     Transaction,
 };
 use std::{
+    collections::{BTreeMap, HashSet},
     convert::TryInto,
     sync::{Mutex as Mutex_, MutexGuard, PoisonError},
 }; //compact_target_from_u256
@@ -23,6 +24,14 @@ impl Id {
     pub fn new() -> Self {
         Self { state: 0 }
     }
+    /// Build a generator that continue from a previously saved state (see `Id::state`)
+    pub fn with_state(state: u32) -> Self {
+        Self { state }
+    }
+    /// Last generated id
+    pub fn state(&self) -> u32 {
+        self.state
+    }
     #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> u32 {
         self.state += 1;
@@ -36,6 +45,60 @@ impl Default for Id {
     }
 }
 
+/// Allocator of the channel ids of a connection. The ids are allocated in increasing order and
+/// wrap around after `u32::MAX`, an id is never given to a channel while another channel with the
+/// same id is alive: the ids in use (allocated or reserved) are skipped. A freed id is given again
+/// only after a full wrap around, so the late messages of a closed channel can not be mistaken for
+/// messages of a new channel. 0 is never allocated.
+#[derive(Debug, Default, Clone)]
+pub struct ChannelIdFactory {
+    last: u32,
+    in_use: HashSet<u32>,
+}
+
+impl ChannelIdFactory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocate an id that is not in use, None if every id is in use
+    pub fn allocate(&mut self) -> Option<u32> {
+        if self.in_use.len() >= u32::MAX as usize {
+            return None;
+        }
+        loop {
+            self.last = self.last.wrapping_add(1);
+            if self.last != 0 && self.in_use.insert(self.last) {
+                return Some(self.last);
+            }
+        }
+    }
+
+    /// Mark an id chosen elsewhere as in use (eg the group channel id of the connection), return
+    /// false if it is already in use
+    pub fn reserve(&mut self, id: u32) -> bool {
+        self.in_use.insert(id)
+    }
+
+    /// The channel `id` has been closed, return false if the id was not in use
+    pub fn free(&mut self, id: u32) -> bool {
+        self.in_use.remove(&id)
+    }
+
+    pub fn is_in_use(&self, id: u32) -> bool {
+        self.in_use.contains(&id)
+    }
+
+    /// Number of ids in use
+    pub fn len(&self) -> usize {
+        self.in_use.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.in_use.is_empty()
+    }
+}
+
 /// Safer Mutex wrapper
 #[derive(Debug)]
 pub struct Mutex<T: ?Sized>(Mutex_<T>);
@@ -60,25 +123,51 @@ impl<T> Mutex<T> {
     }
 }
 
-pub fn merkle_root_from_path(
+/// Build the serialized coinbase transaction splicing the extranonce between the coinbase prefix
+/// and suffix provided by upstream:
+/// coinbase = coinbase_tx_prefix + extranonce + coinbase_tx_suffix
+///
+/// When the extranonce is splitted between upstream and downstream (extended channels)
+/// `extranonce` must be extranonce_prefix + extranonce.
+pub fn build_coinbase(
     coinbase_tx_prefix: &[u8],
-    coinbase_tx_suffix: &[u8],
     extranonce: &[u8],
-    path: &[&[u8]],
-) -> Option<Vec<u8>> {
+    coinbase_tx_suffix: &[u8],
+) -> Vec<u8> {
     let mut coinbase =
-        Vec::with_capacity(coinbase_tx_prefix.len() + coinbase_tx_suffix.len() + extranonce.len());
+        Vec::with_capacity(coinbase_tx_prefix.len() + extranonce.len() + coinbase_tx_suffix.len());
     coinbase.extend_from_slice(coinbase_tx_prefix);
     coinbase.extend_from_slice(extranonce);
     coinbase.extend_from_slice(coinbase_tx_suffix);
+    coinbase
+}
+
+/// Return the merkle root of a block given the coinbase splitted in prefix, extranonce and suffix
+/// and the merkle path of the coinbase. Every element of the path is hashed with the
+/// current node, the coinbase txid being the first node:
+/// node = sha256d(node + path[i])
+///
+/// Return None if the coinbase is not a valid transaction or if an element of the path is not 32
+/// bytes long.
+pub fn merkle_root_from_path(
+    coinbase_tx_prefix: &[u8],
+    coinbase_tx_suffix: &[u8],
+    extranonce: &[u8],
+    path: &[&[u8]],
+) -> Option<Vec<u8>> {
+    let coinbase = build_coinbase(coinbase_tx_prefix, extranonce, coinbase_tx_suffix);
     let coinbase = Transaction::deserialize(&coinbase[..]).ok()?;
-    let mut hashes = vec![coinbase.txid().as_hash()];
+    let mut root = coinbase.txid().as_hash().into_inner();
     for hash in path {
-        hashes.push(Hash::from_slice(hash).ok()?)
+        if hash.len() != 32 {
+            return None;
+        }
+        let mut engine = DHash::engine();
+        engine.input(&root);
+        engine.input(hash);
+        root = DHash::from_engine(engine).into_inner();
     }
-
-    let root = bitcoin_merkle_root(hashes.into_iter());
-    Some(root.into_inner().to_vec())
+    Some(root.to_vec())
 }
 
 /// Returns a new `BlockHeader`.
@@ -136,32 +225,412 @@ pub(crate) fn new_header_hash<'decoder>(header: BlockHeader) -> U256<'decoder> {
     // below never panic an header hash is always U256
     hash.try_into().unwrap()
 }
+
+/// sha256d of the header of a share for a standard job, the header is built from the prev hash and
+/// the nbits of the SetNewPrevHash and from the merkle root of the NewMiningJob. The hash is
+/// returned as `Hash::into_inner` so that it can be checked with `Target::is_met_by`.
+pub fn standard_share_hash(
+    share: &mining_sv2::SubmitSharesStandard,
+    prev_hash: &[u8],
+    merkle_root: &[u8],
+    nbits: u32,
+) -> Result<[u8; 32], Error> {
+    let header = new_header(
+        share.version as i32,
+        prev_hash,
+        merkle_root,
+        share.ntime,
+        nbits,
+        share.nonce,
+    )?;
+    Ok(header.block_hash().into_inner())
+}
+
+/// BIP320 general purpose bits of the block version that mining devices can freely roll
+pub const BIP320_VERSION_MASK: u32 = 0x1fff_e000;
+
+/// Return the version rolling mask that a job allow to roll. If the job do not allow version
+/// rolling the mask is 0 and the version must be used as is.
+pub fn version_rolling_mask(version_rolling_allowed: bool) -> u32 {
+    if version_rolling_allowed {
+        BIP320_VERSION_MASK
+    } else {
+        0
+    }
+}
+
+/// Return the mask that should be advertised to a downstream (eg a translator answering an sv1
+/// `mining.configure`) given the mask requested by the downstream and the mask allowed upstream.
+/// Return None if the resulting mask has less than `min_bit_count` bits set.
+pub fn negotiate_version_rolling_mask(
+    requested_mask: u32,
+    allowed_mask: u32,
+    min_bit_count: u32,
+) -> Option<u32> {
+    let mask = requested_mask & allowed_mask;
+    if mask.count_ones() >= min_bit_count {
+        Some(mask)
+    } else {
+        None
+    }
+}
+
+/// Return true if `submitted_version` differ from `job_version` only in bits set in `mask`
+pub fn is_valid_rolled_version(job_version: u32, submitted_version: u32, mask: u32) -> bool {
+    (job_version ^ submitted_version) & !mask == 0
+}
+
 use bitcoin::util::uint::Uint256;
 
-fn u128_as_u256(v: u128) -> Uint256 {
-    let u128_min = [0_u8; 16];
-    let u128_b = v.to_be_bytes();
-    let u256 = [&u128_min[..], &u128_b[..]].concat();
-    // below never panic
-    Uint256::from_be_slice(&u256).unwrap()
+/// 256 bits target. A share (or a block) is valid if its hash, interpreted as a little endian 256
+/// bits integer, is less or equal than the target.
+///
+/// It can be converted from and to:
+/// * the little endian `U256` used by the sv2 messages (eg `SetTarget`)
+/// * the compact representation used by bitcoin block headers (nBits)
+/// * the "difficulty" used by pools, where difficulty 1 is the target 0x1d00ffff
+/// * the expected hash rate of a channel and the number of shares per minute that it should submit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Target(Uint256);
+
+impl Target {
+    /// Target of difficulty 1 in compact form
+    pub const DIFFICULTY_1_COMPACT: u32 = 0x1d00_ffff;
+
+    pub fn new(inner: Uint256) -> Self {
+        Self(inner)
+    }
+
+    pub fn into_inner(self) -> Uint256 {
+        self.0
+    }
+
+    /// The easiest possible target
+    pub fn max() -> Self {
+        Self(Uint256([u64::MAX; 4]))
+    }
+
+    pub fn from_le_bytes(mut bytes: [u8; 32]) -> Self {
+        bytes.reverse();
+        Self(Uint256::from_be_bytes(bytes))
+    }
+
+    pub fn to_le_bytes(&self) -> [u8; 32] {
+        let mut bytes = self.0.to_be_bytes();
+        bytes.reverse();
+        bytes
+    }
+
+    /// Return true if `hash` (a sha256d as returned by `Hash::into_inner`) meet the target
+    pub fn is_met_by(&self, hash: [u8; 32]) -> bool {
+        Self::from_le_bytes(hash) <= *self
+    }
+
+    /// Target from the nBits field of a block header
+    pub fn from_compact(bits: u32) -> Self {
+        Self(BlockHeader::u256_from_compact_target(bits))
+    }
+
+    /// Compact representation (nBits) of the target, precision is lost as only the 3 most
+    /// significant bytes are kept
+    pub fn to_compact(&self) -> u32 {
+        let bytes = self.0.to_be_bytes();
+        let mut size = (self.0.bits() + 7) / 8;
+        let mut compact = if size <= 3 {
+            (self.0.low_u64() << (8 * (3 - size))) as u32
+        } else {
+            let start = 32 - size;
+            u32::from_be_bytes([0, bytes[start], bytes[start + 1], bytes[start + 2]])
+        };
+        // The most significant bit of the mantissa is the sign bit, if set use one more byte
+        if compact & 0x0080_0000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+        compact | ((size as u32) << 24)
+    }
+
+    /// Target corresponding to the pool difficulty `difficulty`: target = difficulty_1 / difficulty
+    pub fn from_difficulty(difficulty: f64) -> Self {
+        if difficulty <= 0.0 {
+            return Self::max();
+        }
+        let difficulty_1 = u256_to_f64(&Self::from_compact(Self::DIFFICULTY_1_COMPACT).0);
+        Self(u256_from_f64(difficulty_1 / difficulty))
+    }
+
+    /// Pool difficulty of the target: difficulty = difficulty_1 / target
+    pub fn difficulty(&self) -> f64 {
+        let difficulty_1 = u256_to_f64(&Self::from_compact(Self::DIFFICULTY_1_COMPACT).0);
+        let target = u256_to_f64(&self.0);
+        if target == 0.0 {
+            return f64::INFINITY;
+        }
+        difficulty_1 / target
+    }
+
+    /// Target such that a device with `hash_per_second` submit on average `share_per_min` shares
+    /// per minute:
+    /// target = 2^256 * (share_per_min / 60) / hash_per_second
+    pub fn from_hash_rate(hash_per_second: f64, share_per_min: f64) -> Self {
+        if hash_per_second <= 0.0 || share_per_min <= 0.0 {
+            return Self::max();
+        }
+        let hashes_per_share = hash_per_second * 60.0 / share_per_min;
+        if hashes_per_share <= 1.0 {
+            return Self::max();
+        }
+        Self(u256_from_f64(2_f64.powi(256) / hashes_per_share))
+    }
+}
+
+impl<'a> From<U256<'a>> for Target {
+    fn from(v: U256<'a>) -> Self {
+        // below unwrap never panic a U256 is always 32 bytes
+        Self::from_le_bytes(v.to_vec().try_into().unwrap())
+    }
+}
+
+impl From<Target> for U256<'static> {
+    fn from(v: Target) -> Self {
+        v.to_le_bytes().into()
+    }
+}
+
+impl From<mining_sv2::Target> for Target {
+    fn from(v: mining_sv2::Target) -> Self {
+        let v: U256<'static> = v.into();
+        v.into()
+    }
 }
 
-/// target = u256_max * (shar_per_min / 60) * (2^32 / hash_per_second)
-/// target = u128_max * ((shar_per_min / 60) * (2^32 / hash_per_second) * u128_max)
+impl From<Target> for mining_sv2::Target {
+    fn from(v: Target) -> Self {
+        let v: U256<'static> = v.into();
+        v.into()
+    }
+}
+
+fn u256_to_f64(v: &Uint256) -> f64 {
+    v.to_be_bytes()
+        .iter()
+        .fold(0.0, |acc, byte| acc * 256.0 + *byte as f64)
+}
+
+fn u256_from_f64(v: f64) -> Uint256 {
+    if v.is_nan() || v < 1.0 {
+        return Uint256([0; 4]);
+    }
+    if v >= 2_f64.powi(256) {
+        return Target::max().0;
+    }
+    let exponent = v.log2().floor() as usize;
+    if exponent < 64 {
+        return Uint256([v as u64, 0, 0, 0]);
+    }
+    // Keep the 53 significant bits of the f64 and shift them in place
+    let mantissa = (v / 2_f64.powi(exponent as i32 - 52)) as u64;
+    Uint256([mantissa, 0, 0, 0]) << (exponent - 52)
+}
+
+/// Target such that a device with `hps` hash per second submit on average `shares_per_min` shares
+/// per minute
+pub fn target_for_hashrate(hps: f64, shares_per_min: f64) -> Target {
+    Target::from_hash_rate(hps, shares_per_min)
+}
+
+/// Same as `target_for_hashrate` but return the target as an sv2 `U256` ready to be put in
+/// `SetTarget` or `OpenStandardMiningChannelSuccess`
 pub fn target_from_hash_rate(hash_per_second: f32, share_per_min: f32) -> U256<'static> {
-    assert!(hash_per_second >= 1000000000.0);
-    let operand = (share_per_min as f64 / 60.0) * (u32::MAX as f64 / hash_per_second as f64);
-    assert!(operand <= 1.0);
-    let operand = operand * (u128::MAX as f64);
-    let target = u128_as_u256(u128::MAX) * u128_as_u256(operand as u128);
-    let mut target: [u8; 32] = target.to_be_bytes();
-    target.reverse();
-    target.into()
+    target_for_hashrate(hash_per_second as f64, share_per_min as f64).into()
+}
+
+/// Target assigned to a channel when it is opened, used by the upstreams that answer
+/// OpenStandardMiningChannel and OpenExtendedMiningChannel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelTargetPolicy {
+    /// Target of the channels that do not declare a nominal hash rate
+    pub initial_target: Target,
+    /// Expected shares per minute of the channels that declare a nominal hash rate, if None every
+    /// channel get `initial_target`
+    pub shares_per_minute: Option<f32>,
+    /// No channel get a target easier than the target of this difficulty, 0.0 means no floor
+    pub min_difficulty: f64,
+}
+
+impl ChannelTargetPolicy {
+    pub fn new(initial_target: Target) -> Self {
+        Self {
+            initial_target,
+            shares_per_minute: None,
+            min_difficulty: 0.0,
+        }
+    }
+
+    /// Target of a new channel with `nominal_hash_rate` h/s
+    pub fn target_for(&self, nominal_hash_rate: f32) -> Target {
+        let target = match self.shares_per_minute {
+            Some(shares_per_minute) if nominal_hash_rate > 0.0 => {
+                Target::from_hash_rate(nominal_hash_rate as f64, shares_per_minute as f64)
+            }
+            _ => self.initial_target,
+        };
+        target.min(self.max_target())
+    }
+
+    /// Easiest target allowed by `min_difficulty`
+    pub fn max_target(&self) -> Target {
+        Target::from_difficulty(self.min_difficulty)
+    }
+}
+
+/// How a proxy reconcile the SetTarget of an upstream with the target that the proxy chose for the
+/// channel (with `ChannelTargetPolicy` or raising the difficulty of a flooding channel). The
+/// reconciled target is never easier than the upstream one, otherwise the upstream would reject
+/// the shares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetConflictPolicy {
+    /// Keep the harder between the local and the upstream target
+    HonorStricter,
+    /// Replace the local target with the upstream one
+    HonorUpstream,
+    /// Scale the local target as the upstream target changed, so that the ratio between the
+    /// difficulty chosen by the proxy and the upstream one is kept
+    ScaleDown,
+}
+
+impl TargetConflictPolicy {
+    /// Target of a channel with the `local` target when the upstream change its target from
+    /// `previous_upstream` to `upstream`
+    pub fn reconcile(&self, local: Target, previous_upstream: Target, upstream: Target) -> Target {
+        match self {
+            Self::HonorStricter => local.min(upstream),
+            Self::HonorUpstream => upstream,
+            Self::ScaleDown => {
+                let scale = upstream.difficulty() / previous_upstream.difficulty();
+                if !scale.is_finite() || scale <= 0.0 {
+                    return local.min(upstream);
+                }
+                Target::from_difficulty(local.difficulty() * scale).min(upstream)
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::HonorStricter => "honor-stricter",
+            Self::HonorUpstream => "honor-upstream",
+            Self::ScaleDown => "scale-down",
+        }
+    }
+}
+
+impl std::str::FromStr for TargetConflictPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "honor-stricter" => Ok(Self::HonorStricter),
+            "honor-upstream" => Ok(Self::HonorUpstream),
+            "scale-down" => Ok(Self::ScaleDown),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How the sv1 difficulty of an sv2 target is rounded. The difficulty is always rounded up, so the
+/// target of the sv1 downstream is never easier than the sv2 target and every share that meet the
+/// sv1 difficulty is also valid upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DifficultyRounding {
+    /// Smallest float difficulty whose target is not easier than the sv2 target
+    Exact,
+    /// Round up to an integer, for the firmwares that parse `mining.set_difficulty` as an integer
+    /// (they would truncate the difficulty and submit shares easier than the sv2 target)
+    Integer,
+}
+
+/// Difficulty to send in an sv1 `mining.set_difficulty` for the sv2 `target`
+pub fn sv1_difficulty(target: Target, rounding: DifficultyRounding) -> f64 {
+    let mut difficulty = target.difficulty();
+    if !difficulty.is_finite() {
+        return difficulty;
+    }
+    // The f64 conversions can round the difficulty down by some ulps, that would give a target
+    // slightly easier than `target`
+    while Target::from_difficulty(difficulty) > target {
+        difficulty = f64::from_bits(difficulty.to_bits() + 1);
+    }
+    match rounding {
+        DifficultyRounding::Exact => difficulty,
+        DifficultyRounding::Integer => difficulty.ceil(),
+    }
+}
+
+/// Cached translation between the sv2 targets and the sv1 difficulties, used by a translator.
+/// Every channel of a translator usually get one of a few targets, and the sv1 shares are checked
+/// against the target of the difficulty sent to the downstream, so the same translations are done
+/// over and over. The targets of the power of two difficulties (the defaults of most ASIC
+/// firmwares) are precomputed.
+#[derive(Debug, Clone)]
+pub struct DifficultyTable {
+    rounding: DifficultyRounding,
+    difficulties: BTreeMap<Target, f64>,
+    // Keyed by the bits of the difficulty
+    targets: BTreeMap<u64, Target>,
+}
+
+impl DifficultyTable {
+    /// When a cache is full it is cleared, with vardiff the targets can be all different
+    pub const MAX_ENTRIES: usize = 4096;
+
+    pub fn new(rounding: DifficultyRounding) -> Self {
+        let targets = (0..=40)
+            .map(|exponent| {
+                let difficulty = 2_f64.powi(exponent);
+                (difficulty.to_bits(), Target::from_difficulty(difficulty))
+            })
+            .collect();
+        Self {
+            rounding,
+            difficulties: BTreeMap::new(),
+            targets,
+        }
+    }
+
+    pub fn rounding(&self) -> DifficultyRounding {
+        self.rounding
+    }
+
+    /// Difficulty to send to an sv1 downstream for the sv2 `target`, see `sv1_difficulty`
+    pub fn difficulty(&mut self, target: Target) -> f64 {
+        if let Some(difficulty) = self.difficulties.get(&target) {
+            return *difficulty;
+        }
+        let difficulty = sv1_difficulty(target, self.rounding);
+        if self.difficulties.len() >= Self::MAX_ENTRIES {
+            self.difficulties.clear();
+        }
+        self.difficulties.insert(target, difficulty);
+        difficulty
+    }
+
+    /// Target of the sv1 `difficulty`, the target used by the sv1 firmwares to check their shares
+    pub fn target(&mut self, difficulty: f64) -> Target {
+        if let Some(target) = self.targets.get(&difficulty.to_bits()) {
+            return *target;
+        }
+        let target = Target::from_difficulty(difficulty);
+        if self.targets.len() >= Self::MAX_ENTRIES {
+            self.targets.clear();
+        }
+        self.targets.insert(difficulty.to_bits(), target);
+        target
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    #[cfg(feature = "serde")]
     use super::*;
     use binary_sv2::{Seq0255, B064K, U256};
     #[cfg(feature = "serde")]
@@ -169,10 +638,8 @@ mod tests {
 
     #[cfg(feature = "serde")]
     use std::convert::TryInto;
-    #[cfg(feature = "serde")]
     use std::num::ParseIntError;
 
-    #[cfg(feature = "serde")]
     fn decode_hex(s: &str) -> Result<Vec<u8>, ParseIntError> {
         (0..s.len())
             .step_by(2)
@@ -362,4 +829,322 @@ mod tests {
 
         assert_eq!(actual, expect);
     }
+
+    #[test]
+    fn validates_rolled_version() {
+        let version = 0x2000_0000;
+        let mask = version_rolling_mask(true);
+
+        assert!(is_valid_rolled_version(version, version, mask));
+        assert!(is_valid_rolled_version(
+            version,
+            version | 0x1fff_e000,
+            mask
+        ));
+        // Bits outside of the BIP320 range can not be rolled
+        assert!(!is_valid_rolled_version(version, version | 0x1, mask));
+        assert!(!is_valid_rolled_version(version, 0x4000_0000, mask));
+        // No bits can be rolled if version rolling is not allowed
+        assert!(!is_valid_rolled_version(
+            version,
+            version | 0x2000,
+            version_rolling_mask(false)
+        ));
+    }
+
+    #[test]
+    fn negotiates_version_rolling_mask() {
+        assert_eq!(
+            negotiate_version_rolling_mask(0xffff_ffff, BIP320_VERSION_MASK, 2),
+            Some(BIP320_VERSION_MASK)
+        );
+        assert_eq!(
+            negotiate_version_rolling_mask(0x0000_6000, BIP320_VERSION_MASK, 2),
+            Some(0x0000_6000)
+        );
+        assert_eq!(
+            negotiate_version_rolling_mask(0x0000_6000, BIP320_VERSION_MASK, 3),
+            None
+        );
+        assert_eq!(negotiate_version_rolling_mask(0xffff_ffff, 0, 0), Some(0));
+    }
+
+    // Coinbase of the genesis block
+    const GENESIS_COINBASE: &str = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000";
+
+    fn sha256d(a: &[u8], b: &[u8]) -> Vec<u8> {
+        let mut engine = DHash::engine();
+        engine.input(a);
+        engine.input(b);
+        DHash::from_engine(engine).into_inner().to_vec()
+    }
+
+    #[test]
+    fn builds_coinbase() {
+        let coinbase = decode_hex(GENESIS_COINBASE).unwrap();
+        // Split the coinbase in the middle of the input script
+        let (prefix, rest) = coinbase.split_at(50);
+        let (extranonce, suffix) = rest.split_at(8);
+
+        assert_eq!(build_coinbase(prefix, extranonce, suffix), coinbase);
+    }
+
+    #[test]
+    fn gets_merkle_root_from_path_with_no_path() {
+        let coinbase = decode_hex(GENESIS_COINBASE).unwrap();
+        let (prefix, rest) = coinbase.split_at(50);
+        let (extranonce, suffix) = rest.split_at(8);
+
+        let mut expect =
+            decode_hex("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b").unwrap();
+        expect.reverse();
+
+        let actual = merkle_root_from_path(prefix, suffix, extranonce, &[]);
+        assert_eq!(actual, Some(expect));
+    }
+
+    #[test]
+    fn gets_merkle_root_from_multi_level_path() {
+        let coinbase = decode_hex(GENESIS_COINBASE).unwrap();
+        let (prefix, rest) = coinbase.split_at(50);
+        let (extranonce, suffix) = rest.split_at(8);
+        let coinbase_txid = Transaction::deserialize(&coinbase[..])
+            .unwrap()
+            .txid()
+            .as_hash()
+            .into_inner();
+        let tx_1 = [1_u8; 32];
+        let tx_2 = [2_u8; 32];
+        let tx_3 = [3_u8; 32];
+
+        // Merkle tree of [coinbase, tx_1, tx_2, tx_3]
+        let expect = sha256d(&sha256d(&coinbase_txid, &tx_1), &sha256d(&tx_2, &tx_3));
+
+        let right_node = sha256d(&tx_2, &tx_3);
+        let path: [&[u8]; 2] = [&tx_1, &right_node];
+        let actual = merkle_root_from_path(prefix, suffix, extranonce, &path);
+        assert_eq!(actual, Some(expect));
+    }
+
+    #[test]
+    fn fails_merkle_root_from_path_on_invalid_input() {
+        let coinbase = decode_hex(GENESIS_COINBASE).unwrap();
+        let (prefix, suffix) = coinbase.split_at(50);
+
+        assert_eq!(merkle_root_from_path(prefix, &suffix[1..], &[], &[]), None);
+        let short_hash = [0_u8; 31];
+        assert_eq!(
+            merkle_root_from_path(prefix, suffix, &[], &[&short_hash]),
+            None
+        );
+    }
+
+    #[test]
+    fn converts_target_from_and_to_compact() {
+        let target = Target::from_compact(Target::DIFFICULTY_1_COMPACT);
+        let mut expect = [0_u8; 32];
+        expect[26] = 0xff;
+        expect[27] = 0xff;
+        assert_eq!(target.to_le_bytes(), expect);
+        assert_eq!(target.to_compact(), Target::DIFFICULTY_1_COMPACT);
+
+        // Mainnet block 700000
+        assert_eq!(Target::from_compact(0x170e_d0eb).to_compact(), 0x170e_d0eb);
+        // Mantissa with the sign bit set need an extra byte
+        let target = Target::new(Uint256([0x80, 0, 0, 0]));
+        assert_eq!(target.to_compact(), 0x0200_8000);
+        assert_eq!(Target::from_compact(0x0200_8000), target);
+    }
+
+    #[test]
+    fn converts_target_from_and_to_difficulty() {
+        let difficulty_1 = Target::from_compact(Target::DIFFICULTY_1_COMPACT);
+        assert_eq!(Target::from_difficulty(1.0), difficulty_1);
+        assert!((difficulty_1.difficulty() - 1.0).abs() < f64::EPSILON);
+
+        let target = Target::from_difficulty(1024.0);
+        assert!((target.difficulty() - 1024.0).abs() < 1e-9);
+        assert!(target < difficulty_1);
+        assert_eq!(Target::from_difficulty(0.0), Target::max());
+    }
+
+    #[test]
+    fn allocates_channel_ids() {
+        let mut ids = ChannelIdFactory::new();
+        assert_eq!(ids.allocate(), Some(1));
+        assert!(ids.reserve(2));
+        assert!(!ids.reserve(2));
+        // 2 is reserved
+        assert_eq!(ids.allocate(), Some(3));
+        assert!(ids.free(1));
+        assert!(!ids.free(1));
+        // A freed id is not given again before a wrap around
+        assert_eq!(ids.allocate(), Some(4));
+        assert_eq!(ids.len(), 3);
+
+        // After u32::MAX the ids wrap around, skip 0 and the ids in use
+        let mut ids = ChannelIdFactory::new();
+        ids.reserve(1);
+        ids.reserve(2);
+        ids.last = u32::MAX - 1;
+        assert_eq!(ids.allocate(), Some(u32::MAX));
+        assert_eq!(ids.allocate(), Some(3));
+        assert!(ids.is_in_use(u32::MAX));
+        assert!(!ids.is_in_use(0));
+    }
+
+    #[test]
+    fn translates_sv1_difficulties() {
+        let mut table = DifficultyTable::new(DifficultyRounding::Exact);
+        let difficulty_1 = Target::from_compact(Target::DIFFICULTY_1_COMPACT);
+        assert_eq!(table.difficulty(difficulty_1), 1.0);
+        assert_eq!(table.target(1.0), difficulty_1);
+
+        // cgminer and the ASIC firmwares derived from it use target = difficulty_1 / difficulty
+        for exponent in [10, 13, 16, 20] {
+            let difficulty = 2_f64.powi(exponent);
+            let target = Target::new(difficulty_1.into_inner() >> exponent as usize);
+            assert_eq!(table.target(difficulty), target);
+            assert_eq!(table.difficulty(target), difficulty);
+        }
+        assert_eq!(table.target(3.0), Target::from_difficulty(3.0));
+
+        // A target that is not exactly a difficulty is rounded to a harder sv1 target
+        let mut integer_table = DifficultyTable::new(DifficultyRounding::Integer);
+        for &difficulty in &[0.5, 1000.5, 12345.678, 2_f64.powi(40) / 3.0] {
+            let target = Target::from_difficulty(difficulty);
+            let exact = table.difficulty(target);
+            assert!(Target::from_difficulty(exact) <= target);
+            assert!((exact - difficulty).abs() / difficulty < 1e-9);
+            let integer = integer_table.difficulty(target);
+            assert_eq!(integer, difficulty.ceil());
+            assert!(Target::from_difficulty(integer) <= target);
+        }
+        assert_eq!(
+            table.difficulty(Target::new(Uint256([0; 4]))),
+            f64::INFINITY
+        );
+    }
+
+    #[test]
+    fn gets_target_for_hashrate() {
+        // 2^32 h/s submitting 60 shares per minute means a share every 2^32 hashes
+        let target = target_for_hashrate(2_f64.powi(32), 60.0);
+        assert_eq!(target, Target::new(Uint256([0, 0, 0, 1 << 32])));
+
+        // Doubling the hashrate halves the target
+        let faster = target_for_hashrate(2_f64.powi(33), 60.0);
+        assert_eq!(faster, Target::new(Uint256([0, 0, 0, 1 << 31])));
+
+        assert_eq!(target_for_hashrate(0.0, 1.0), Target::max());
+        let u256: U256 = target.into();
+        assert_eq!(Target::from(u256), target);
+    }
+
+    #[test]
+    fn applies_channel_target_policy() {
+        let initial_target = Target::from_difficulty(16.0);
+        // Exactly representable as f32
+        let (small_miner, big_miner) = (2_f32.powi(30), 2_f32.powi(50));
+        let mut policy = ChannelTargetPolicy::new(initial_target);
+        assert_eq!(policy.target_for(0.0), initial_target);
+        assert_eq!(policy.target_for(big_miner), initial_target);
+
+        policy.shares_per_minute = Some(6.0);
+        assert_eq!(policy.target_for(0.0), initial_target);
+        assert_eq!(
+            policy.target_for(big_miner),
+            Target::from_hash_rate(big_miner as f64, 6.0)
+        );
+
+        // A small miner would get a target easier than the floor
+        policy.min_difficulty = 1024.0;
+        assert_eq!(
+            policy.target_for(small_miner),
+            Target::from_difficulty(1024.0)
+        );
+        // A big miner is above the floor
+        assert_eq!(
+            policy.target_for(big_miner),
+            Target::from_hash_rate(big_miner as f64, 6.0)
+        );
+    }
+
+    #[test]
+    fn reconciles_upstream_and_local_targets() {
+        let local = Target::from_difficulty(64.0);
+        let previous_upstream = Target::from_difficulty(16.0);
+        let harder_upstream = Target::from_difficulty(32.0);
+        let easier_upstream = Target::from_difficulty(8.0);
+
+        let policy = TargetConflictPolicy::HonorStricter;
+        assert_eq!(
+            policy.reconcile(local, previous_upstream, harder_upstream),
+            local
+        );
+        let hardest = Target::from_difficulty(128.0);
+        assert_eq!(policy.reconcile(local, previous_upstream, hardest), hardest);
+
+        let policy = TargetConflictPolicy::HonorUpstream;
+        assert_eq!(
+            policy.reconcile(local, previous_upstream, easier_upstream),
+            easier_upstream
+        );
+
+        // The local difficulty is 4 times the upstream one and it stay so
+        let policy = TargetConflictPolicy::ScaleDown;
+        let scaled = policy.reconcile(local, previous_upstream, harder_upstream);
+        assert!((scaled.difficulty() - 128.0).abs() < 0.01);
+        let scaled = policy.reconcile(local, previous_upstream, easier_upstream);
+        assert!((scaled.difficulty() - 32.0).abs() < 0.01);
+
+        assert_eq!("scale-down".parse(), Ok(TargetConflictPolicy::ScaleDown));
+        assert_eq!("stricter".parse::<TargetConflictPolicy>(), Err(()));
+    }
+
+    #[test]
+    fn checks_hash_against_target() {
+        let target = Target::from_compact(Target::DIFFICULTY_1_COMPACT);
+        let mut hash = target.to_le_bytes();
+        assert!(target.is_met_by(hash));
+        hash[0] = 1;
+        assert!(!target.is_met_by(hash));
+    }
+
+    #[test]
+    fn hashes_standard_shares() {
+        fn from_hex(hex: &str) -> Vec<u8> {
+            let mut bytes: Vec<u8> = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                .collect();
+            // The hashes are displayed in reverse order
+            bytes.reverse();
+            bytes
+        }
+        // Genesis block
+        let merkle_root =
+            from_hex("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b");
+        let mut share = mining_sv2::SubmitSharesStandard {
+            channel_id: 1,
+            sequence_number: 0,
+            job_id: 1,
+            nonce: 2083236893,
+            ntime: 1231006505,
+            version: 1,
+        };
+        let nbits = Target::DIFFICULTY_1_COMPACT;
+        let hash = standard_share_hash(&share, &[0; 32], &merkle_root, nbits).unwrap();
+        assert_eq!(
+            hash.to_vec(),
+            from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+        );
+        assert!(Target::from_compact(nbits).is_met_by(hash));
+        assert!(!Target::from_difficulty(1_000_000.0).is_met_by(hash));
+
+        share.nonce += 1;
+        let hash = standard_share_hash(&share, &[0; 32], &merkle_root, nbits).unwrap();
+        assert!(!Target::from_compact(nbits).is_met_by(hash));
+        assert!(standard_share_hash(&share, &[0; 31], &merkle_root, nbits).is_err());
+    }
 }
@@ -14,6 +14,7 @@ use core::convert::TryInto;
 ///
 #[repr(C)]
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct ChannelEndpointChanged {
     /// The channel which has changed endpoint.
     pub channel_id: u32,
@@ -0,0 +1,178 @@
+//! Typed flags for the `flags` field of [`crate::SetupConnection`],
+//! [`crate::SetupConnectionSuccess`] and [`crate::SetupConnectionError`].
+//!
+//! Each (sub)protocol define its own set of flags, flags are numbered starting from the most
+//! significant bit of the `u32` (flag 0 is `1 << 31`).
+use core::ops::{BitAnd, BitOr, Not};
+
+macro_rules! impl_flags {
+    (
+        $(#[$meta:meta])*
+        $name:ident {
+            $(
+                $(#[$flag_meta:meta])*
+                $flag:ident = $bit:expr;
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+        pub struct $name(u32);
+
+        impl $name {
+            $(
+                $(#[$flag_meta])*
+                pub const $flag: Self = Self(1 << (31 - $bit));
+            )*
+
+            pub const fn empty() -> Self {
+                Self(0)
+            }
+
+            /// Every flag defined for the protocol
+            pub const fn all() -> Self {
+                Self(0 $(| Self::$flag.0)*)
+            }
+
+            pub const fn bits(&self) -> u32 {
+                self.0
+            }
+
+            /// Build the flags dropping every bit that is not defined for the protocol
+            pub const fn from_bits_truncate(bits: u32) -> Self {
+                Self(bits & Self::all().0)
+            }
+
+            pub const fn is_empty(&self) -> bool {
+                self.0 == 0
+            }
+
+            pub const fn contains(&self, other: Self) -> bool {
+                self.0 & other.0 == other.0
+            }
+
+            pub fn insert(&mut self, other: Self) {
+                self.0 |= other.0
+            }
+
+            pub fn remove(&mut self, other: Self) {
+                self.0 &= !other.0
+            }
+        }
+
+        impl BitOr for $name {
+            type Output = Self;
+
+            fn bitor(self, rhs: Self) -> Self {
+                Self(self.0 | rhs.0)
+            }
+        }
+
+        impl BitAnd for $name {
+            type Output = Self;
+
+            fn bitand(self, rhs: Self) -> Self {
+                Self(self.0 & rhs.0)
+            }
+        }
+
+        impl Not for $name {
+            type Output = Self;
+
+            fn not(self) -> Self {
+                Self::from_bits_truncate(!self.0)
+            }
+        }
+
+        impl From<$name> for u32 {
+            fn from(v: $name) -> Self {
+                v.0
+            }
+        }
+    };
+}
+
+impl_flags! {
+    /// Flags sent by the client in [`crate::SetupConnection`] for the mining protocol
+    MiningFlags {
+        /// The downstream node requires standard jobs. It doesn’t understand group channels - it
+        /// is unable to process extended jobs sent to standard channels thru a group channel.
+        REQUIRES_STANDARD_JOBS = 0;
+        /// If set to 1, the client notifies the server that it will send SetCustomMiningJob on
+        /// this connection
+        REQUIRES_WORK_SELECTION = 1;
+        /// The client requires version rolling for efficiency or correct operation and the server
+        /// MUST NOT send jobs which do not allow version rolling
+        REQUIRES_VERSION_ROLLING = 2;
+    }
+}
+
+impl_flags! {
+    /// Flags sent by the server in [`crate::SetupConnectionSuccess`] for the mining protocol
+    MiningSuccessFlags {
+        /// Upstream node will not accept any changes to the version field
+        REQUIRES_FIXED_VERSION = 0;
+        /// Upstream node will not accept opening of a standard channel
+        REQUIRES_EXTENDED_CHANNELS = 1;
+    }
+}
+
+impl_flags! {
+    /// Flags sent by the client in [`crate::SetupConnection`] for the job negotiation protocol
+    JobNegotiationFlags {
+        /// The Job Negotiator requires that the mining_job_token in AllocateMiningJobToken.Success
+        /// can be used immediately on a mining connection in SetCustomMiningJob message
+        REQUIRES_ASYNC_JOB_MINING = 0;
+    }
+}
+
+/// Check the flags requested by a client against the flags supported by the server. If every
+/// requested flag is supported return the intersection of the two sets, if not return the
+/// unsupported flags.
+pub fn negotiate_flags(requested: u32, supported: u32) -> Result<u32, u32> {
+    let unsupported = requested & !supported;
+    if unsupported == 0 {
+        Ok(requested & supported)
+    } else {
+        Err(unsupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_bits() {
+        assert_eq!(MiningFlags::REQUIRES_STANDARD_JOBS.bits(), 0x8000_0000);
+        assert_eq!(MiningFlags::REQUIRES_WORK_SELECTION.bits(), 0x4000_0000);
+        assert_eq!(MiningFlags::REQUIRES_VERSION_ROLLING.bits(), 0x2000_0000);
+        assert_eq!(MiningFlags::all().bits(), 0xe000_0000);
+        assert_eq!(
+            MiningFlags::from_bits_truncate(u32::MAX),
+            MiningFlags::all()
+        );
+        let mut flags = MiningFlags::REQUIRES_STANDARD_JOBS | MiningFlags::REQUIRES_VERSION_ROLLING;
+        assert!(flags.contains(MiningFlags::REQUIRES_VERSION_ROLLING));
+        assert!(!flags.contains(MiningFlags::REQUIRES_WORK_SELECTION));
+        flags.remove(MiningFlags::REQUIRES_VERSION_ROLLING);
+        assert_eq!(flags, MiningFlags::REQUIRES_STANDARD_JOBS);
+        assert_eq!(
+            !flags,
+            MiningFlags::REQUIRES_WORK_SELECTION | MiningFlags::REQUIRES_VERSION_ROLLING
+        );
+    }
+
+    #[test]
+    fn negotiate() {
+        let requested =
+            (MiningFlags::REQUIRES_STANDARD_JOBS | MiningFlags::REQUIRES_WORK_SELECTION).bits();
+        let supported = MiningFlags::all().bits();
+        assert_eq!(negotiate_flags(requested, supported), Ok(requested));
+        let supported = MiningFlags::REQUIRES_STANDARD_JOBS.bits();
+        assert_eq!(
+            negotiate_flags(requested, supported),
+            Err(MiningFlags::REQUIRES_WORK_SELECTION.bits())
+        );
+    }
+}
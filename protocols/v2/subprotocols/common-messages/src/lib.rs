@@ -4,6 +4,7 @@
 //! The following protocol messages are common across all of the sv2 (sub)protocols.
 extern crate alloc;
 mod channel_endpoint_changed;
+mod flags;
 mod setup_connection;
 
 #[cfg(feature = "prop_test")]
@@ -14,6 +15,7 @@ use core::convert::TryInto;
 use quickcheck::{Arbitrary, Gen};
 
 pub use channel_endpoint_changed::ChannelEndpointChanged;
+pub use flags::{negotiate_flags, JobNegotiationFlags, MiningFlags, MiningSuccessFlags};
 pub use setup_connection::{
     has_requires_std_job, has_version_rolling, has_work_selection, Protocol, SetupConnection,
     SetupConnectionError, SetupConnectionSuccess,
@@ -1,3 +1,4 @@
+use crate::flags::{negotiate_flags, JobNegotiationFlags, MiningFlags};
 #[cfg(not(feature = "with_serde"))]
 use alloc::vec::Vec;
 #[cfg(not(feature = "with_serde"))]
@@ -24,6 +25,7 @@ use core::convert::TryInto;
 /// package in use.
 ///
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SetupConnection<'decoder> {
     /// [`Protocol`]
     pub protocol: Protocol,
@@ -52,7 +54,7 @@ pub struct SetupConnection<'decoder> {
 
 impl<'decoder> SetupConnection<'decoder> {
     pub fn set_requires_standard_job(&mut self) {
-        self.flags |= 0b_1000_0000_0000_0000_0000_0000_0000_0000
+        self.flags |= MiningFlags::REQUIRES_STANDARD_JOBS.bits()
     }
 
     /// Check if passed flags support self flag
@@ -63,24 +65,36 @@ impl<'decoder> SetupConnection<'decoder> {
             // [1] [1] -> true
             // [0] [1] -> false
             Protocol::MiningProtocol => {
-                let requires_work_selection_passed = (avaiable_flags >> 30) > 0;
-                let requires_version_rolling_passed = (avaiable_flags >> 29) > 0;
-
-                let requires_work_selection_self = (required_flags >> 30) > 0;
-                let requires_version_rolling_self = (required_flags >> 29) > 0;
-
-                let work_selection =
-                    !requires_work_selection_self || requires_work_selection_passed;
-                let version_rolling =
-                    !requires_version_rolling_self || requires_version_rolling_passed;
-
-                work_selection && version_rolling
+                let required = MiningFlags::from_bits_truncate(required_flags)
+                    & (MiningFlags::REQUIRES_WORK_SELECTION
+                        | MiningFlags::REQUIRES_VERSION_ROLLING);
+                MiningFlags::from_bits_truncate(avaiable_flags).contains(required)
+            }
+            Protocol::JobNegotiationProtocol => {
+                let required = JobNegotiationFlags::from_bits_truncate(required_flags);
+                JobNegotiationFlags::from_bits_truncate(avaiable_flags).contains(required)
             }
-            // TODO
-            _ => todo!(),
+            // No flags are defined for these protocols
+            Protocol::TemplateDistributionProtocol | Protocol::JobDistributionProtocol => true,
         }
     }
 
+    /// Check the requested flags against the flags supported by the server. If every requested
+    /// flag is supported return the flags for [`SetupConnectionSuccess`], if not return the
+    /// [`SetupConnectionError`] that the server must send back.
+    pub fn negotiate_flags(
+        &self,
+        supported_flags: u32,
+    ) -> Result<u32, SetupConnectionError<'static>> {
+        negotiate_flags(self.flags, supported_flags)
+            .map_err(SetupConnectionError::unsupported_feature_flags)
+    }
+
+    /// Typed flags, only meaningful if `protocol` is the mining protocol
+    pub fn mining_flags(&self) -> MiningFlags {
+        MiningFlags::from_bits_truncate(self.flags)
+    }
+
     /// Check if passed versions support self versions if yes return the biggest version avaiable
     pub fn get_version(&self, min_version: u16, max_version: u16) -> Option<u16> {
         if self.min_version > max_version || min_version > self.max_version {
@@ -90,24 +104,30 @@ impl<'decoder> SetupConnection<'decoder> {
         }
     }
 
+    /// Like [`SetupConnection::get_version`] but if the versions are not compatible return the
+    /// [`SetupConnectionError`] that the server must send back.
+    pub fn negotiate_version(
+        &self,
+        min_version: u16,
+        max_version: u16,
+    ) -> Result<u16, SetupConnectionError<'static>> {
+        self.get_version(min_version, max_version)
+            .ok_or_else(SetupConnectionError::protocol_version_mismatch)
+    }
+
     pub fn requires_standard_job(&self) -> bool {
         has_requires_std_job(self.flags)
     }
 }
 
 pub fn has_requires_std_job(flags: u32) -> bool {
-    let flag = flags >> 31;
-    flag != 0
+    MiningFlags::from_bits_truncate(flags).contains(MiningFlags::REQUIRES_STANDARD_JOBS)
 }
 pub fn has_version_rolling(flags: u32) -> bool {
-    let flags = flags << 1;
-    let flag = flags >> 31;
-    flag != 0
+    MiningFlags::from_bits_truncate(flags).contains(MiningFlags::REQUIRES_VERSION_ROLLING)
 }
 pub fn has_work_selection(flags: u32) -> bool {
-    let flags = flags << 2;
-    let flag = flags >> 31;
-    flag != 0
+    MiningFlags::from_bits_truncate(flags).contains(MiningFlags::REQUIRES_WORK_SELECTION)
 }
 
 #[repr(C)]
@@ -191,6 +211,7 @@ impl<'a> From<SetupConnection<'a>> for CSetupConnection {
 /// Response to [`SetupConnection`] message if the server accepts the connection. The client is
 /// required to verify the set of feature flags that the server supports and act accordingly.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 pub struct SetupConnectionSuccess {
     /// Selected version proposed by the connecting node that the upstream
@@ -214,6 +235,7 @@ pub struct SetupConnectionSuccess {
 /// MUST consistently support the same set of flags across all servers on the same hostname and
 /// port number. If flags is 0, the error is a result of some condition aside from unsupported flags.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SetupConnectionError<'decoder> {
     /// Flags indicating features causing an error.
     pub flags: u32,
@@ -226,6 +248,39 @@ pub struct SetupConnectionError<'decoder> {
     pub error_code: Str0255<'decoder>,
 }
 
+impl SetupConnectionError<'static> {
+    /// Error sent when some of the flags requested by the client are not supported, `flags` are the
+    /// unsupported flags
+    pub fn unsupported_feature_flags(flags: u32) -> Self {
+        let error_code = b"unsupported-feature-flags".to_vec();
+        Self {
+            flags,
+            // Is safe to unwrap an error code shorter than 255 bytes
+            error_code: Str0255::try_from(error_code).unwrap(),
+        }
+    }
+
+    /// Error sent when the server do not support the protocol requested by the client
+    pub fn unsupported_protocol() -> Self {
+        let error_code = b"unsupported-protocol".to_vec();
+        Self {
+            flags: 0,
+            // Is safe to unwrap an error code shorter than 255 bytes
+            error_code: Str0255::try_from(error_code).unwrap(),
+        }
+    }
+
+    /// Error sent when the client and the server do not have any protocol version in common
+    pub fn protocol_version_mismatch() -> Self {
+        let error_code = b"protocol-version-mismatch".to_vec();
+        Self {
+            flags: 0,
+            // Is safe to unwrap an error code shorter than 255 bytes
+            error_code: Str0255::try_from(error_code).unwrap(),
+        }
+    }
+}
+
 #[repr(C)]
 #[cfg(not(feature = "with_serde"))]
 #[derive(Debug, Clone)]
@@ -276,6 +331,7 @@ impl<'a> From<SetupConnectionError<'a>> for CSetupConnectionError {
 /// TemplateDistributionProtocol = [`SV2_TEMPLATE_DISTR_PROTOCOL_DISCRIMINANT`],
 /// JobDistributionProtocol = [`SV2_JOB_DISTR_PROTOCOL_DISCRIMINANT`],
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 #[allow(clippy::enum_variant_names)]
@@ -332,3 +388,34 @@ impl GetSize for Protocol {
         1
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn setup_connection(min_version: u16, max_version: u16) -> SetupConnection<'static> {
+        let empty = || Str0255::try_from(Vec::new()).unwrap();
+        SetupConnection {
+            protocol: Protocol::MiningProtocol,
+            min_version,
+            max_version,
+            flags: 0,
+            endpoint_host: empty(),
+            endpoint_port: 0,
+            vendor: empty(),
+            hardware_version: empty(),
+            firmware: empty(),
+            device_id: empty(),
+        }
+    }
+
+    #[test]
+    fn negotiate_version() {
+        assert_eq!(setup_connection(2, 2).negotiate_version(2, 2), Ok(2));
+        assert_eq!(setup_connection(2, 4).negotiate_version(1, 3), Ok(3));
+        let error = setup_connection(3, 4).negotiate_version(2, 2).unwrap_err();
+        assert_eq!(error, SetupConnectionError::protocol_version_mismatch());
+        assert_eq!(error.flags, 0);
+    }
+}
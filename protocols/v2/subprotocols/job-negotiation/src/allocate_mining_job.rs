@@ -11,6 +11,7 @@ use core::convert::TryInto;
 /// rate and only available on connections where this has been negotiated. Otherwise, only
 /// mining_job_token(s) from CreateMiningJob.Success are valid.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct AllocateMiningJobToken<'decoder> {
     /// Unconstrained sequence of bytes. Whatever is needed by the pool to
     /// identify/authenticate the client, e.g. “braiinstest”. Additional restrictions
@@ -30,6 +31,7 @@ pub struct AllocateMiningJobToken<'decoder> {
 /// transaction outputs regularly, it should simply prefer to use the maximum of all such output
 /// sizes as the coinbase_output_max_additional_size value.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct AllocateMiningJobTokenSuccess<'decoder> {
     /// Unique identifier for pairing the response.
     pub request_id: u32,
@@ -11,6 +11,7 @@ use core::convert::TryInto;
 /// upstream (pool) node.
 ///
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct CommitMiningJob<'decoder> {
     /// Unique identifier for pairing the response.
     pub request_id: u32,
@@ -63,6 +64,7 @@ pub struct CommitMiningJob<'decoder> {
 
 /// # CommitMiningJob.Success (Server->Client)
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct CommitMiningJobSuccess<'decoder> {
     /// Identifier of the original request.
     pub request_id: u32,
@@ -84,6 +86,7 @@ pub struct CommitMiningJobSuccess<'decoder> {
 /// * ‘invalid-mining-job-token’
 /// * ‘invalid-job-param-value-{}’ - {} is replaced by a particular field name from CommitMiningJob message
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct CommitMiningJobError<'decoder> {
     /// Identifier of the original request.
     pub request_id: u32,
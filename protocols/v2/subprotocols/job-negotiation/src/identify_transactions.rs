@@ -11,6 +11,7 @@ use core::convert::TryInto;
 /// collision in the tx_short_hash_list, or was unable to reconstruct the tx_hash_list_hash.
 ///
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct IdentifyTransactions {
     /// Unique identifier for pairing the response to the CommitMiningJob message.
     pub request_id: u32,
@@ -21,6 +22,7 @@ pub struct IdentifyTransactions {
 /// Sent by the Client in response to an IdentifyTransactions message to provide the full set of
 /// transaction data hashes.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct IdentifyTransactionsSuccess<'decoder> {
     /// Unique identifier for pairing the response to the
     /// CommitMiningJob/IdentifyTransactions message.
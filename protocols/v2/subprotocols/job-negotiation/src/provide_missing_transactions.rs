@@ -6,6 +6,7 @@ use binary_sv2::{Deserialize, Seq064K, Serialize, B016M};
 use core::convert::TryInto;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct ProvideMissingTransactions<'decoder> {
     /// Identifier of the original CreateMiningJob request.
     pub request_id: u32,
@@ -22,6 +23,7 @@ pub struct ProvideMissingTransactions<'decoder> {
 /// be supplied in ProvideMissingTransactions.
 ///
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct ProvideMissingTransactionsSuccess<'decoder> {
     /// Identifier of the original CreateMiningJob request.
     pub request_id: u32,
@@ -18,6 +18,7 @@ use core::convert::TryInto;
 /// channels.
 ///
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct CloseChannel<'decoder> {
     /// Channel identification.
     pub channel_id: u32,
@@ -215,7 +215,11 @@ pub struct Extranonce {
 
 impl<'a> From<U256<'a>> for Extranonce {
     fn from(v: U256<'a>) -> Self {
-        let inner = v.inner_as_ref();
+        // An extranonce shorter than 32 bytes (eg the prefix of an aggregated standard channel)
+        // is padded with zeros
+        let mut inner = [0_u8; 32];
+        let len = v.inner_as_ref().len();
+        inner[..len].copy_from_slice(v.inner_as_ref());
         // below unwraps never panics
         let head = u128::from_le_bytes(inner[..16].try_into().unwrap());
         let tail = u128::from_le_bytes(inner[16..].try_into().unwrap());
@@ -234,7 +238,11 @@ impl<'a> From<Extranonce> for U256<'a> {
 
 impl<'a> From<B032<'a>> for Extranonce {
     fn from(v: B032<'a>) -> Self {
-        let inner = v.inner_as_ref();
+        // An extranonce shorter than 32 bytes (eg the prefix of an aggregated standard channel)
+        // is padded with zeros
+        let mut inner = [0_u8; 32];
+        let len = v.inner_as_ref().len();
+        inner[..len].copy_from_slice(v.inner_as_ref());
         // below unwraps never panics
         let head = u128::from_le_bytes(inner[..16].try_into().unwrap());
         let tail = u128::from_le_bytes(inner[16..].try_into().unwrap());
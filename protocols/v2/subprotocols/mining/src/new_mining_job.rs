@@ -12,6 +12,7 @@ use core::convert::TryInto;
 /// If the future_job field is set to *False*, the client MUST start to mine on the new job as soon as
 /// possible after receiving this message.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct NewMiningJob<'decoder> {
     /// Channel identifier, this must be a standard channel.
     pub channel_id: u32,
@@ -51,6 +52,7 @@ pub struct NewMiningJob<'decoder> {
 /// expected behaviour for end mining devices).
 ///
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct NewExtendedMiningJob<'decoder> {
     /// For a group channel, the message is broadcasted to all standard
     /// channels belonging to the group. Otherwise, it is addressed to
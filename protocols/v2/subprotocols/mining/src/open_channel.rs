@@ -17,6 +17,7 @@ use core::convert::TryInto;
 /// Clients must also communicate information about their hashing power in order to receive
 /// well-calibrated job assignments.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct OpenStandardMiningChannel<'decoder> {
     /// Client-specified identifier for matching responses from upstream server.
     /// The value MUST be connection-wide unique and is not interpreted by
@@ -72,6 +73,7 @@ impl<'decoder> OpenStandardMiningChannel<'decoder> {
 /// # OpenStandardMiningChannel.Success (Server -> Client)
 /// Sent as a response for opening a standard channel, if successful.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct OpenStandardMiningChannelSuccess<'decoder> {
     /// Client-specified request ID from OpenStandardMiningChannel message,
     /// so that the client can pair responses with open channel requests.
@@ -112,6 +114,7 @@ impl<'decoder> OpenStandardMiningChannelSuccess<'decoder> {
 /// Similar to *OpenStandardMiningChannel* but requests to open an extended channel instead of
 /// standard channel.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct OpenExtendedMiningChannel<'decoder> {
     /// Client-specified identifier for matching responses from upstream server.
     /// The value MUST be connection-wide unique and is not interpreted by
@@ -141,6 +144,7 @@ pub struct OpenExtendedMiningChannel<'decoder> {
 /// # OpenExtendedMiningChannel.Success (Server -> Client)
 /// Sent as a response for opening an extended channel.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct OpenExtendedMiningChannelSuccess<'decoder> {
     /// Client-specified request ID from OpenStandardMiningChannel message,
     /// so that the client can pair responses with open channel requests.
@@ -161,6 +165,7 @@ pub struct OpenExtendedMiningChannelSuccess<'decoder> {
 
 /// # OpenMiningChannel.Error (Server -> Client)
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct OpenMiningChannelError<'decoder> {
     /// Client-specified request ID from OpenMiningChannel message.
     pub request_id: u32,
@@ -20,6 +20,7 @@ use core::convert::TryInto;
 /// able to redirect hashrate to an arbitrary server should the pool server get compromised and
 /// instructed to send reconnects to a new location.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct Reconnect<'decoder> {
     /// When empty, downstream node attempts to reconnect to its present
     /// host.
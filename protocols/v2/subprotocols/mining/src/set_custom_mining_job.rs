@@ -14,6 +14,7 @@ use core::convert::TryInto;
 /// been or will be negotiated between the Job Negotiator and Pool.
 ///
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SetCustomMiningJob<'decoder> {
     /// Extended channel identifier.
     pub channel_id: u32,
@@ -71,6 +72,7 @@ pub struct SetCustomMiningJob<'decoder> {
 /// the job immediately (by using the job_id provided within this response).
 ///
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SetCustomMiningJobSuccess<'decoder> {
     /// Extended channel identifier.
     pub channel_id: u32,
@@ -95,6 +97,7 @@ pub struct SetCustomMiningJobSuccess<'decoder> {
 /// * ‘invalid-job-param-value-{}’ - {} is replaced by a particular field name from SetCustomMiningJob message
 ///
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SetCustomMiningJobError<'decoder> {
     /// Extended channel identifier.
     pub channel_id: u32,
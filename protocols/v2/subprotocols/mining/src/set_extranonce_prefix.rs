@@ -12,6 +12,7 @@ use core::convert::TryInto;
 /// SetCustomMiningJob message). This message is applicable only for explicitly opened
 /// extended channels or standard channels (not group channels).
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SetExtranoncePrefix<'decoder> {
     /// Extended or standard channel identifier.
     pub channel_id: u32,
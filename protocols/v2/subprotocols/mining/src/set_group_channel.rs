@@ -22,6 +22,7 @@ use core::convert::TryInto;
 /// flag in SetupConnection.
 ///
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SetGroupChannel<'decoder> {
     /// Identifier of the group where the standard channel belongs.
     pub group_channel_id: u32,
@@ -14,6 +14,7 @@ use core::convert::TryInto;
 /// client have to be made invalid.
 /// Note: There is no need for block height in this message.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SetNewPrevHash<'decoder> {
     /// Group channel or channel that this prevhash is valid for.
     pub channel_id: u32,
@@ -17,6 +17,7 @@ use core::convert::TryInto;
 /// When SetTarget is sent to a group channel, the maximum target is applicable to all channels in
 /// the group.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SetTarget<'decoder> {
     /// Channel identifier.
     pub channel_id: u32,
@@ -9,6 +9,7 @@ use core::convert::TryInto;
 ///
 /// Client sends result of its hashing work to the server.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubmitSharesStandard {
     /// Channel identification.
     pub channel_id: u32,
@@ -32,6 +33,7 @@ pub struct SubmitSharesStandard {
 /// following additional field:
 /// * extranonce
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubmitSharesExtended<'decoder> {
     /// Channel identification.
     pub channel_id: u32,
@@ -68,6 +70,7 @@ pub struct SubmitSharesExtended<'decoder> {
 /// actually increasing. It can simply use the last one received when sending a response. It is the
 /// client’s responsibility to keep the sequence numbers correct/useful.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubmitSharesSuccess {
     /// Channel identifier.
     pub channel_id: u32,
@@ -90,7 +93,10 @@ pub struct SubmitSharesSuccess {
 /// * ‘invalid-channel-id’
 /// * ‘stale-share’
 /// * ‘difficulty-too-low’
+/// * ‘duplicate-share’
+/// * ‘invalid-version’
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubmitSharesError<'decoder> {
     pub channel_id: u32,
     pub sequence_number: u32,
@@ -15,6 +15,7 @@ use core::convert::TryInto;
 /// This message is an extended channel only message. Using it in other kind if channels should
 /// raise an error
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct UpdateChannel<'decoder> {
     /// Channel identification.
     pub channel_id: u32,
@@ -33,6 +34,7 @@ pub struct UpdateChannel<'decoder> {
 
 /// # Update.Error (Server -> Client)
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct UpdateChannelError<'decoder> {
     /// Channel identification.
     pub channel_id: u32,
@@ -20,6 +20,7 @@ use core::convert::TryInto;
 /// the Template Provider MUST consider the maximum additional bytes required in the output
 /// count variable-length integer in the coinbase transaction when complying with the size limits.
 #[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 pub struct CoinbaseOutputDataSize {
     /// The maximum additional serialized bytes which the pool will add in
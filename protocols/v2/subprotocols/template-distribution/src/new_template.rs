@@ -12,6 +12,7 @@ use core::convert::TryInto;
 /// The primary template-providing function. Note that the coinbase_tx_outputs bytes will appear
 /// as is at the end of the coinbase transaction.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct NewTemplate<'decoder> {
     /// Server’s identification of the template. Strictly increasing, the
     /// current UNIX time may be used in place of an ID.
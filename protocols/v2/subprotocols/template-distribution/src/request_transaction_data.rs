@@ -13,6 +13,7 @@ use core::convert::TryInto;
 /// transaction data for all transactions (excluding the coinbase transaction) included in a block, as
 /// well as any additional data which may be required by the Pool to validate the work.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
 pub struct RequestTransactionData {
     /// The template_id corresponding to a NewTemplate message.
@@ -47,6 +48,7 @@ pub struct RequestTransactionData {
 /// in-Template Negotiation Protocol signaling of support for the new fork (e.g. for soft-forks
 /// activated using [BIP 9]).
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct RequestTransactionDataSuccess<'decoder> {
     /// The template_id corresponding to a NewTemplate/RequestTransactionData message.
     pub template_id: u64,
@@ -112,6 +114,7 @@ impl<'a> From<RequestTransactionDataSuccess<'a>> for CRequestTransactionDataSucc
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct RequestTransactionDataError<'decoder> {
     /// The template_id corresponding to a NewTemplate/RequestTransactionData message.
     pub template_id: u64,
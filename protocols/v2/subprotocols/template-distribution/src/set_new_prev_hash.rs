@@ -17,6 +17,7 @@ use core::convert::TryInto;
 /// TODO: Define how many previous works the client has to track (2? 3?), and require that the
 /// server reference one of those in SetNewPrevHash.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SetNewPrevHash<'decoder> {
     /// template_id referenced in a previous NewTemplate message.
     pub template_id: u64,
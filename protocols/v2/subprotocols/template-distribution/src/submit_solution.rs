@@ -14,6 +14,7 @@ use core::convert::TryInto;
 /// MUST then immediately construct the corresponding full block and attempt to propagate it to
 /// the Bitcoin network.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "with_json", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubmitSolution<'decoder> {
     /// The template_id field as it appeared in NewTemplate.
     pub template_id: u64,
@@ -417,6 +417,11 @@ pub extern "C" fn next_frame(decoder: *mut DecoderWrapper) -> CResult<CSv2Messag
                 .map_err(|_| Sv2Error::Unknown)
                 .into()
         }
+        // The stream can not be decoded anymore
+        Err(codec_sv2::Error::FrameTooLarge { .. }) | Err(codec_sv2::Error::InvalidFragment) => {
+            Box::into_raw(decoder);
+            CResult::Err(Sv2Error::InvalidSv2Frame)
+        }
         Err(_) => {
             Box::into_raw(decoder);
             CResult::Err(Sv2Error::MissingBytes)
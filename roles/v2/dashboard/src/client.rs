@@ -0,0 +1,52 @@
+//! Client of the proxy control API, see `mining_proxy::admin`
+use async_std::{
+    io::BufReader,
+    net::{SocketAddr, TcpStream},
+    prelude::*,
+};
+use serde_json::{json, Value};
+
+pub struct AdminClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    next_id: u64,
+}
+
+impl AdminClient {
+    pub async fn connect(address: SocketAddr) -> Result<Self, String> {
+        let stream = TcpStream::connect(address)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            reader: BufReader::new(stream.clone()),
+            writer: stream,
+            next_id: 0,
+        })
+    }
+
+    /// Call `method` without params and return the result
+    pub async fn call(&mut self, method: &str) -> Result<Value, String> {
+        self.next_id += 1;
+        let mut request =
+            json!({ "jsonrpc": "2.0", "id": self.next_id, "method": method }).to_string();
+        request.push('\n');
+        self.writer
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut response = String::new();
+        match self.reader.read_line(&mut response).await {
+            Ok(0) => return Err("Connection closed".to_string()),
+            Ok(_) => (),
+            Err(e) => return Err(e.to_string()),
+        }
+        let mut response: Value = serde_json::from_str(&response).map_err(|e| e.to_string())?;
+        if let Some(error) = response.get("error") {
+            return Err(error["message"]
+                .as_str()
+                .unwrap_or("Unknown error")
+                .to_string());
+        }
+        Ok(response["result"].take())
+    }
+}
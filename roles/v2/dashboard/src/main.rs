@@ -0,0 +1,119 @@
+//! Web dashboard for the proxies.
+//!
+//! The dashboard poll the control API (see `mining_proxy::admin`) of every configured proxy and
+//! serve:
+//! * `/`: an HTML page with the workers, the upstreams and the recent connection events
+//! * `/api/snapshot`: the polled data as JSON
+//!
+//! Only the proxies expose a control API, the pool can not be monitored yet.
+mod client;
+mod monitor;
+mod page;
+
+use async_std::{
+    io::BufReader,
+    net::{SocketAddr, TcpListener, TcpStream},
+    prelude::*,
+    sync::{Arc, Mutex},
+    task,
+};
+use monitor::Snapshot;
+use serde::Deserialize;
+use std::time::Duration;
+
+const CONFIG_PATH: &str = "dashboard-config.toml";
+
+#[derive(Debug, Deserialize)]
+pub struct ProxyValues {
+    name: String,
+    /// Address of the control API of the proxy, see the proxy `admin_port`
+    admin_address: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    listen_address: String,
+    proxies: Vec<ProxyValues>,
+    /// How often the proxies are polled, default to 10 seconds
+    refresh_secs: Option<u64>,
+}
+
+impl Config {
+    fn load() -> Result<Self, String> {
+        let config_file = std::fs::read_to_string(CONFIG_PATH).map_err(|e| e.to_string())?;
+        toml::from_str(&config_file).map_err(|e| e.to_string())
+    }
+}
+
+async fn respond(mut stream: TcpStream, status: &str, content_type: &str, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+async fn serve_connection(stream: TcpStream, snapshot: Arc<Mutex<Snapshot>>, refresh_secs: u64) {
+    let mut reader = BufReader::new(stream.clone());
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).await.is_err() {
+        return;
+    }
+    // Headers are not used
+    let mut header = String::new();
+    while let Ok(n) = reader.read_line(&mut header).await {
+        if n == 0 || header == "\r\n" || header == "\n" {
+            break;
+        }
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+    if method != Some("GET") {
+        respond(stream, "405 Method Not Allowed", "text/plain", "").await;
+        return;
+    }
+    let snapshot = snapshot.lock().await.clone();
+    match path {
+        Some("/") => {
+            let page = page::render(&snapshot, refresh_secs);
+            respond(stream, "200 OK", "text/html; charset=utf-8", &page).await;
+        }
+        Some("/api/snapshot") => {
+            let body = snapshot.to_json().to_string();
+            respond(stream, "200 OK", "application/json", &body).await;
+        }
+        _ => respond(stream, "404 Not Found", "text/plain", "Not found").await,
+    }
+}
+
+#[async_std::main]
+async fn main() {
+    let config = Config::load().unwrap();
+    let refresh_secs = config.refresh_secs.unwrap_or(10);
+    let addresses: Vec<SocketAddr> = config
+        .proxies
+        .iter()
+        .map(|proxy| proxy.admin_address.parse().unwrap())
+        .collect();
+    let names = config.proxies.iter().map(|p| p.name.clone()).collect();
+    let snapshot = Arc::new(Mutex::new(Snapshot::new(names)));
+
+    task::spawn(monitor::run(
+        addresses,
+        Duration::from_secs(refresh_secs),
+        snapshot.clone(),
+    ));
+
+    let listener = TcpListener::bind(&config.listen_address).await.unwrap();
+    println!("Dashboard listening on http://{}", config.listen_address);
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        if let Ok(stream) = stream {
+            task::spawn(serve_connection(stream, snapshot.clone(), refresh_secs));
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Periodic polling of the monitored proxies
+use super::client::AdminClient;
+use async_std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    task,
+};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime};
+
+/// State of a proxy at the last poll, the values are the results of the control API methods
+#[derive(Debug, Clone)]
+pub struct ProxyState {
+    pub name: String,
+    pub stats: Value,
+    pub workers: Value,
+    pub recent_events: Value,
+    /// Set if the proxy could not be polled, the values are from the last successful poll
+    pub error: Option<String>,
+}
+
+impl ProxyState {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            stats: Value::Null,
+            workers: Value::Array(Vec::new()),
+            recent_events: Value::Array(Vec::new()),
+            error: Some("Not polled yet".to_string()),
+        }
+    }
+
+    async fn poll(&mut self, address: SocketAddr) -> Result<(), String> {
+        let mut client = AdminClient::connect(address).await?;
+        self.stats = client.call("stats").await?;
+        self.workers = client.call("workers").await?;
+        self.recent_events = client.call("recent_events").await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub proxies: Vec<ProxyState>,
+    pub updated: Option<SystemTime>,
+}
+
+impl Snapshot {
+    pub fn new(names: Vec<String>) -> Self {
+        Self {
+            proxies: names.into_iter().map(ProxyState::new).collect(),
+            updated: None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let proxies: Vec<Value> = self
+            .proxies
+            .iter()
+            .map(|proxy| {
+                json!({
+                    "name": proxy.name,
+                    "stats": proxy.stats,
+                    "workers": proxy.workers,
+                    "recent_events": proxy.recent_events,
+                    "error": proxy.error,
+                })
+            })
+            .collect();
+        json!({
+            "updated": self.updated.map(unix_time),
+            "proxies": proxies,
+        })
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Poll the proxy at `addresses[i]` into `snapshot.proxies[i]` every `refresh`
+pub async fn run(addresses: Vec<SocketAddr>, refresh: Duration, snapshot: Arc<Mutex<Snapshot>>) {
+    let mut states = snapshot.lock().await.proxies.clone();
+    loop {
+        for (state, address) in states.iter_mut().zip(&addresses) {
+            state.error = state.poll(*address).await.err();
+        }
+        {
+            let mut snapshot = snapshot.lock().await;
+            snapshot.proxies = states.clone();
+            snapshot.updated = Some(SystemTime::now());
+        }
+        task::sleep(refresh).await;
+    }
+}
@@ -0,0 +1,243 @@
+//! HTML page of the dashboard, rendered on every request from the last `Snapshot`
+use super::monitor::{unix_time, Snapshot};
+use serde_json::Value;
+use std::{fmt::Write, time::SystemTime};
+
+/// Events shown for each proxy, the newest first
+const SHOWN_EVENTS: usize = 20;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em}table{border-collapse:collapse;\
+margin-bottom:2em}th,td{border:1px solid #ccc;padding:4px 8px;text-align:right}\
+th{background:#eee}.bad{color:#b00}";
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Hash rate with SI prefix, eg `1.50 TH/s`
+fn format_hash_rate(hash_rate: f64) -> String {
+    let prefixes = ["", "K", "M", "G", "T", "P", "E"];
+    let mut hash_rate = hash_rate;
+    let mut prefix = 0;
+    while hash_rate >= 1000.0 && prefix < prefixes.len() - 1 {
+        hash_rate /= 1000.0;
+        prefix += 1;
+    }
+    format!("{:.2} {}H/s", hash_rate, prefixes[prefix])
+}
+
+/// Time left before the certificate expiry, `now` and `expiry` are unix times
+fn format_expiry(expiry: Option<u64>, now: u64) -> String {
+    match expiry {
+        None => "-".to_string(),
+        Some(expiry) if expiry <= now => "<span class=\"bad\">expired</span>".to_string(),
+        Some(expiry) => {
+            let left = expiry - now;
+            let text = format!(
+                "{}d {}h {}m",
+                left / 86400,
+                left % 86400 / 3600,
+                left % 3600 / 60
+            );
+            // Less than a day
+            match left < 86400 {
+                true => format!("<span class=\"bad\">{}</span>", text),
+                false => text,
+            }
+        }
+    }
+}
+
+fn as_array(value: &Value) -> &[Value] {
+    value.as_array().map(Vec::as_slice).unwrap_or(&[])
+}
+
+fn render_upstreams(page: &mut String, name: &str, stats: &Value, now: u64) {
+    for upstream in as_array(&stats["upstreams"]) {
+        let quarantined = upstream["quarantined"].as_bool().unwrap_or(false);
+        let _ = write!(
+            page,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.1}%</td><td>{}</td></tr>",
+            escape(name),
+            upstream["id"],
+            escape(upstream["address"].as_str().unwrap_or("")),
+            match quarantined {
+                true => "<span class=\"bad\">quarantined</span>",
+                false => "healthy",
+            },
+            upstream["error_rate"].as_f64().unwrap_or(0.0) * 100.0,
+            format_expiry(upstream["certificate_expiry"].as_u64(), now),
+        );
+    }
+}
+
+fn render_workers(page: &mut String, name: &str, workers: &Value) {
+    for worker in as_array(workers) {
+        let accepted = worker["accepted_shares"].as_u64().unwrap_or(0);
+        let rejected = worker["rejected_shares"].as_u64().unwrap_or(0);
+        let acceptance = match accepted + rejected {
+            0 => "-".to_string(),
+            total => format!("{:.1}%", accepted as f64 * 100.0 / total as f64),
+        };
+        let worker_name = match (worker["account"].as_str(), worker["worker"].as_str()) {
+            (Some(account), Some(worker)) => format!("{}.{}", account, worker),
+            (Some(account), None) => account.to_string(),
+            _ => "-".to_string(),
+        };
+        let _ = write!(
+            page,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>\
+             <td>{:.2}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(name),
+            worker["connection_id"],
+            worker["channel_id"],
+            escape(&worker_name),
+            escape(worker["remote_address"].as_str().unwrap_or("-")),
+            format_hash_rate(worker["hash_rate"].as_f64().unwrap_or(0.0)),
+            worker["reported_hash_rate"]
+                .as_f64()
+                .map(format_hash_rate)
+                .unwrap_or_else(|| "-".to_string()),
+            worker["difficulty"].as_f64().unwrap_or(0.0),
+            worker["submitted_shares"],
+            accepted,
+            rejected,
+            acceptance,
+        );
+    }
+}
+
+fn render_events(page: &mut String, name: &str, events: &Value) {
+    for event in as_array(events).iter().rev().take(SHOWN_EVENTS) {
+        let channel = match event["channel_id"].as_u64() {
+            Some(channel_id) => channel_id.to_string(),
+            None => "-".to_string(),
+        };
+        let _ = write!(
+            page,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(name),
+            event["time"],
+            escape(event["event"].as_str().unwrap_or("")),
+            event["connection_id"],
+            channel,
+        );
+    }
+}
+
+pub fn render(snapshot: &Snapshot, refresh_secs: u64) -> String {
+    let now = unix_time(SystemTime::now());
+    let mut page = String::new();
+    let _ = write!(
+        page,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"{}\"><title>Stratum V2 dashboard</title>\
+         <style>{}</style></head><body><h1>Stratum V2 dashboard</h1>",
+        refresh_secs, STYLE
+    );
+    match snapshot.updated {
+        Some(updated) => {
+            let _ = write!(
+                page,
+                "<p>Updated {}s ago</p>",
+                now.saturating_sub(unix_time(updated))
+            );
+        }
+        None => page.push_str("<p>Waiting for the first poll</p>"),
+    }
+    for proxy in &snapshot.proxies {
+        if let Some(error) = &proxy.error {
+            let _ = write!(
+                page,
+                "<p class=\"bad\">{}: {}</p>",
+                escape(&proxy.name),
+                escape(error)
+            );
+        }
+    }
+
+    page.push_str(
+        "<h2>Upstreams</h2><table><tr><th>Proxy</th><th>Id</th><th>Address</th><th>Status</th>\
+         <th>Error rate</th><th>Certificate expiry</th></tr>",
+    );
+    for proxy in &snapshot.proxies {
+        render_upstreams(&mut page, &proxy.name, &proxy.stats, now);
+    }
+    page.push_str("</table>");
+
+    page.push_str(
+        "<h2>Workers</h2><table><tr><th>Proxy</th><th>Connection</th><th>Channel</th>\
+         <th>Worker</th><th>Address</th><th>Hash rate</th><th>Reported hash rate</th><th>Difficulty</th>\
+         <th>Submitted</th><th>Accepted</th><th>Rejected</th><th>Acceptance</th></tr>",
+    );
+    for proxy in &snapshot.proxies {
+        render_workers(&mut page, &proxy.name, &proxy.workers);
+    }
+    page.push_str("</table>");
+
+    page.push_str(
+        "<h2>Recent events</h2><table><tr><th>Proxy</th><th>Time</th><th>Event</th>\
+         <th>Connection</th><th>Channel</th></tr>",
+    );
+    for proxy in &snapshot.proxies {
+        render_events(&mut page, &proxy.name, &proxy.recent_events);
+    }
+    page.push_str("</table></body></html>");
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn renders_workers_and_upstreams() {
+        let mut snapshot = Snapshot::new(vec!["<proxy>".to_string()]);
+        let now = unix_time(SystemTime::now());
+        snapshot.updated = Some(SystemTime::now());
+        snapshot.proxies[0].error = None;
+        snapshot.proxies[0].stats = json!({
+            "upstreams": [{
+                "id": 0,
+                "address": "127.0.0.1:34254",
+                "quarantined": false,
+                "error_rate": 0.25,
+                "certificate_expiry": now + 2 * 86400 + 3600,
+            }]
+        });
+        snapshot.proxies[0].workers = json!([{
+            "connection_id": 1,
+            "channel_id": 2,
+            "account": "alice",
+            "worker": "rig-1",
+            "remote_address": "10.0.0.1:4000",
+            "difficulty": 1.0,
+            "submitted_shares": 4,
+            "accepted_shares": 3,
+            "rejected_shares": 1,
+            "hash_rate": 1_500_000_000_000.0,
+            "reported_hash_rate": null,
+        }]);
+
+        let page = render(&snapshot, 10);
+        assert!(page.contains("&lt;proxy&gt;"));
+        assert!(!page.contains("<proxy>"));
+        assert!(page.contains("25.0%"));
+        assert!(page.contains("2d 1h 0m"));
+        assert!(page.contains("1.50 TH/s"));
+        assert!(page.contains("75.0%"));
+        assert!(page.contains("alice.rig-1"));
+    }
+
+    #[test]
+    fn formats_expiry() {
+        assert_eq!(format_expiry(None, 100), "-");
+        assert!(format_expiry(Some(100), 100).contains("expired"));
+        assert!(format_expiry(Some(160), 100).contains("bad"));
+        assert_eq!(format_expiry(Some(100 + 86400 + 60), 100), "1d 0h 1m");
+    }
+}
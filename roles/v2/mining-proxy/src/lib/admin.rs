@@ -0,0 +1,553 @@
+//! Local control API of the proxy.
+//!
+//! The proxy accept JSON-RPC 2.0 requests on the admin address, one request per line and one
+//! response per line. There is no authentication, the admin address must be reachable only by the
+//! operators (eg bound to 127.0.0.1).
+//!
+//! Methods:
+//! * `list_downstreams`: connected downstreams and their channels
+//! * `close_channel {connection_id, channel_id}`: close a downstream channel
+//! * `switch_upstream {upstream_id}`: move the downstreams paired with an upstream to another one
+//! * `drain_upstream {upstream_id}`: move the downstreams paired with an upstream to another one,
+//!   stop pairing new downstreams with it and close its connection
+//! * `set_target {connection_id, channel_id, difficulty}`: send SetTarget to a standard channel
+//! * `reload_config`: call the hook registered with `ProxyBuilder::on_reload`
+//! * `stats`: proxy and upstreams statistics, with their labels
+//! * `workers`: account, worker name, shares and hash rate of every standard channel
+//! * `recent_events`: last `ConnectionEvent`s published by the proxy
+//!
+//! ```txt
+//! --> {"jsonrpc": "2.0", "id": 1, "method": "close_channel", "params": {"connection_id": 3, "channel_id": 2}}
+//! <-- {"jsonrpc": "2.0", "id": 1, "result": null}
+//! ```
+use super::{
+    downstream_mining::DownstreamMiningNode, prev_hash_latency::LatencyHistogram,
+    proxy::ProxyStats, proxy_context::ProxyContext, upstream_mining::UpstreamMiningNode,
+};
+use async_std::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    prelude::*,
+    sync::Arc,
+    task,
+};
+use roles_logic_sv2::{
+    common_properties::{DownstreamChannel, IsUpstream},
+    events::ConnectionEvent,
+    utils::{ChannelTargetPolicy, Mutex, Target},
+};
+use serde_json::{json, Value};
+use std::time::{Instant, SystemTime};
+
+type ReloadFn = dyn Fn(&ProxyControl) -> Result<(), String> + Send + Sync;
+
+/// Called on `reload_config`, it apply the new configuration with `ProxyControl`
+#[derive(Clone)]
+pub struct ReloadHook(Arc<ReloadFn>);
+
+impl ReloadHook {
+    pub fn new(hook: impl Fn(&ProxyControl) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+}
+
+impl std::fmt::Debug for ReloadHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ReloadHook").finish()
+    }
+}
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The request is valid but the proxy can not execute it
+const REQUEST_FAILED: i64 = -32000;
+
+/// Runtime control of a running proxy, returned by `ProxyHandle::control`
+#[derive(Debug, Clone)]
+pub struct ProxyControl {
+    downstreams: Arc<Mutex<Vec<Arc<Mutex<DownstreamMiningNode>>>>>,
+    context: ProxyContext,
+}
+
+impl ProxyControl {
+    pub(crate) fn new(
+        downstreams: Arc<Mutex<Vec<Arc<Mutex<DownstreamMiningNode>>>>>,
+        context: ProxyContext,
+    ) -> Self {
+        Self {
+            downstreams,
+            context,
+        }
+    }
+
+    fn connected_downstreams(&self) -> Vec<Arc<Mutex<DownstreamMiningNode>>> {
+        self.downstreams
+            .safe_lock(|downstreams| {
+                downstreams
+                    .iter()
+                    .filter(|d| d.safe_lock(|d| d.is_connected()).unwrap())
+                    .cloned()
+                    .collect()
+            })
+            .unwrap()
+    }
+
+    fn downstream(&self, connection_id: u32) -> Option<Arc<Mutex<DownstreamMiningNode>>> {
+        self.connected_downstreams()
+            .into_iter()
+            .find(|d| d.safe_lock(|d| d.connection_id() == connection_id).unwrap())
+    }
+
+    fn upstream(&self, upstream_id: u32) -> Option<Arc<Mutex<UpstreamMiningNode>>> {
+        self.context
+            .upstreams()
+            .into_iter()
+            .find(|u| u.safe_lock(|u| u.get_id() == upstream_id).unwrap())
+    }
+
+    pub fn stats(&self) -> ProxyStats {
+        let (downstreams, channels) = self
+            .connected_downstreams()
+            .iter()
+            .map(|d| d.safe_lock(|d| d.channels_count()).unwrap())
+            .fold((0, 0), |(downstreams, channels), c| {
+                (downstreams + 1, channels + c)
+            });
+        let upstreams = self.context.upstreams();
+        let quarantined_upstreams = upstreams
+            .iter()
+            .filter(|u| u.safe_lock(|u| u.is_quarantined()).unwrap())
+            .count();
+        let evicted_jobs = upstreams
+            .iter()
+            .map(|u| u.safe_lock(|u| u.evicted_jobs()).unwrap())
+            .sum();
+        let target_conflicts = upstreams
+            .iter()
+            .map(|u| u.safe_lock(|u| u.target_conflicts()).unwrap())
+            .sum();
+        ProxyStats {
+            labels: self.context.labels().clone(),
+            downstreams,
+            channels,
+            upstreams: upstreams.len(),
+            quarantined_upstreams,
+            evicted_jobs,
+            target_conflicts,
+            parked_channel_requests: self.context.parked_channel_requests(),
+            prev_hash_latency: self.context.prev_hash_latency(),
+        }
+    }
+
+    /// Close the channel `channel_id` of the downstream `connection_id`, see
+    /// `DownstreamMiningNode::close_channel`
+    pub async fn close_channel(&self, connection_id: u32, channel_id: u32) -> Result<(), String> {
+        let downstream = self
+            .downstream(connection_id)
+            .ok_or_else(|| format!("Unknown connection {}", connection_id))?;
+        match DownstreamMiningNode::close_channel(downstream, channel_id).await {
+            true => Ok(()),
+            false => Err(format!("Unknown channel {}", channel_id)),
+        }
+    }
+
+    /// Move the downstreams paired with `upstream_id` to another upstream, return the id of the
+    /// new upstream
+    pub fn switch_upstream(&self, upstream_id: u32) -> Result<u32, String> {
+        let upstream = self
+            .upstream(upstream_id)
+            .ok_or_else(|| format!("Unknown upstream {}", upstream_id))?;
+        UpstreamMiningNode::switch_downstreams(upstream)
+            .map_err(|_| "No other upstream available".to_string())
+    }
+
+    /// Drain `upstream_id`, see `UpstreamMiningNode::drain`, return the id of the upstream that
+    /// serve its downstreams
+    pub async fn drain_upstream(&self, upstream_id: u32) -> Result<u32, String> {
+        let upstream = self
+            .upstream(upstream_id)
+            .ok_or_else(|| format!("Unknown upstream {}", upstream_id))?;
+        if upstream.safe_lock(|u| u.is_draining()).unwrap() {
+            return Err(format!("Upstream {} is already drained", upstream_id));
+        }
+        UpstreamMiningNode::drain(upstream)
+            .await
+            .map_err(|_| "No other upstream available".to_string())
+    }
+
+    /// Send SetTarget to the standard channel `channel_id` of the downstream `connection_id`
+    pub async fn set_target(
+        &self,
+        connection_id: u32,
+        channel_id: u32,
+        target: Target,
+    ) -> Result<(), String> {
+        let downstream = self
+            .downstream(connection_id)
+            .ok_or_else(|| format!("Unknown connection {}", connection_id))?;
+        DownstreamMiningNode::set_target(downstream, channel_id, target)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Target policy of the channels opened from now on
+    pub fn set_target_policy(&self, policy: Option<ChannelTargetPolicy>) {
+        self.context.set_target_policy(policy);
+    }
+
+    fn list_downstreams(&self) -> Value {
+        let downstreams: Vec<Value> = self
+            .connected_downstreams()
+            .iter()
+            .map(|d| {
+                d.safe_lock(|d| {
+                    let channels: Vec<Value> = d
+                        .channels()
+                        .iter()
+                        .map(|channel| match channel {
+                            DownstreamChannel::Standard(c) => json!({
+                                "channel_id": c.channel_id,
+                                "group_id": c.group_id,
+                                "difficulty": Target::from(c.target.clone()).difficulty(),
+                            }),
+                            DownstreamChannel::Group(id) => json!({ "group_id": id }),
+                            DownstreamChannel::Extended(id) => json!({ "channel_id": id }),
+                        })
+                        .collect();
+                    let device = d.downstream_data().map(|data| {
+                        json!({
+                            "flags": data.flags,
+                            "vendor": data.device.vendor,
+                            "hardware_version": data.device.hardware_version,
+                            "firmware": data.device.firmware,
+                            "device_id": data.device.device_id,
+                        })
+                    });
+                    json!({
+                        "connection_id": d.connection_id(),
+                        "remote_address": d.remote_address().map(|a| a.to_string()),
+                        "device": device,
+                        "channels": channels,
+                    })
+                })
+                .unwrap()
+            })
+            .collect();
+        Value::Array(downstreams)
+    }
+
+    fn stats_json(&self) -> Value {
+        let stats = self.stats();
+        let upstreams: Vec<Value> = self
+            .context
+            .upstreams()
+            .iter()
+            .map(|u| {
+                u.safe_lock(|u| {
+                    json!({
+                        "id": u.get_id(),
+                        "address": u.address().to_string(),
+                        "quarantined": u.is_quarantined(),
+                        "draining": u.is_draining(),
+                        "error_rate": u.error_rate(),
+                        "evicted_jobs": u.evicted_jobs(),
+                        "target_conflict": u.target_conflict().as_str(),
+                        "target_conflicts": u.target_conflicts(),
+                        "certificate_expiry": u.certificate_expiry().map(unix_time),
+                        "labels": u.labels().to_json(),
+                    })
+                })
+                .unwrap()
+            })
+            .collect();
+        json!({
+            "labels": stats.labels.to_json(),
+            "downstreams": stats.downstreams,
+            "channels": stats.channels,
+            "quarantined_upstreams": stats.quarantined_upstreams,
+            "evicted_jobs": stats.evicted_jobs,
+            "target_conflicts": stats.target_conflicts,
+            "parked_channel_requests": stats.parked_channel_requests,
+            "upstreams": upstreams,
+            "prev_hash_latency": latency_json(&stats.prev_hash_latency),
+        })
+    }
+
+    fn workers(&self) -> Value {
+        let now = Instant::now();
+        let workers: Vec<Value> = self
+            .connected_downstreams()
+            .iter()
+            .flat_map(|d| {
+                d.safe_lock(|d| {
+                    let telemetry = d.telemetry();
+                    d.standard_channels()
+                        .into_iter()
+                        .filter_map(|channel| {
+                            let stats = d.share_stats().get(&channel.channel_id)?;
+                            let reported_hash_rate = telemetry
+                                .iter()
+                                .find(|t| t.channel_id == channel.channel_id)
+                                .map(|t| t.hashrate);
+                            let worker = d.worker(channel.channel_id);
+                            Some(json!({
+                                "connection_id": d.connection_id(),
+                                "channel_id": channel.channel_id,
+                                "account": worker.map(|w| w.account.clone()),
+                                "worker": worker.and_then(|w| w.worker.clone()),
+                                "remote_address": d.remote_address().map(|a| a.to_string()),
+                                "difficulty": Target::from(channel.target).difficulty(),
+                                "submitted_shares": stats.submitted,
+                                "accepted_shares": stats.accepted,
+                                "rejected_shares": stats.rejected,
+                                "hash_rate": stats.hash_rate(now),
+                                "reported_hash_rate": reported_hash_rate,
+                            }))
+                        })
+                        .collect::<Vec<Value>>()
+                })
+                .unwrap()
+            })
+            .collect();
+        Value::Array(workers)
+    }
+
+    fn recent_events(&self) -> Value {
+        let events: Vec<Value> = self
+            .context
+            .recent_events()
+            .into_iter()
+            .map(|(time, event)| {
+                let mut value = json!({
+                    "time": unix_time(time),
+                    "connection_id": event.connection_id(),
+                });
+                let (name, remote_address, channel_id) = match event {
+                    ConnectionEvent::Accepted { remote_address, .. } => {
+                        ("accepted", remote_address, None)
+                    }
+                    ConnectionEvent::HandshakeFailed { .. } => ("handshake_failed", None, None),
+                    ConnectionEvent::Paired { .. } => ("paired", None, None),
+                    ConnectionEvent::ChannelOpened { channel_id, .. } => {
+                        ("channel_opened", None, Some(channel_id))
+                    }
+                    ConnectionEvent::ChannelClosed { channel_id, .. } => {
+                        ("channel_closed", None, Some(channel_id))
+                    }
+                    ConnectionEvent::BlockFound { channel_id, .. } => {
+                        ("block_found", None, Some(channel_id))
+                    }
+                    ConnectionEvent::Disconnected { .. } => ("disconnected", None, None),
+                };
+                value["event"] = json!(name);
+                if let Some(remote_address) = remote_address {
+                    value["remote_address"] = json!(remote_address.to_string());
+                }
+                if let Some(channel_id) = channel_id {
+                    value["channel_id"] = json!(channel_id);
+                }
+                value
+            })
+            .collect();
+        Value::Array(events)
+    }
+}
+
+/// Latencies in milliseconds
+fn latency_json(histogram: &LatencyHistogram) -> Value {
+    let millis = |d: Option<std::time::Duration>| d.map(|d| d.as_secs_f64() * 1000.0);
+    json!({
+        "count": histogram.count(),
+        "mean_ms": millis(histogram.mean()),
+        "p50_ms": millis(histogram.quantile(0.5)),
+        "p90_ms": millis(histogram.quantile(0.9)),
+        "p99_ms": millis(histogram.quantile(0.99)),
+        "max_ms": millis(histogram.max()),
+    })
+}
+
+/// Seconds since the unix epoch
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn param_u32(params: &Value, name: &str) -> Result<u32, (i64, String)> {
+    params
+        .get(name)
+        .and_then(Value::as_u64)
+        .filter(|value| *value <= u32::MAX as u64)
+        .map(|value| value as u32)
+        .ok_or_else(|| (INVALID_PARAMS, format!("Missing or invalid param {}", name)))
+}
+
+async fn execute(
+    control: &ProxyControl,
+    reload: Option<&ReloadHook>,
+    method: &str,
+    params: &Value,
+) -> Result<Value, (i64, String)> {
+    let failed = |e: String| (REQUEST_FAILED, e);
+    match method {
+        "list_downstreams" => Ok(control.list_downstreams()),
+        "close_channel" => {
+            let connection_id = param_u32(params, "connection_id")?;
+            let channel_id = param_u32(params, "channel_id")?;
+            control
+                .close_channel(connection_id, channel_id)
+                .await
+                .map_err(failed)?;
+            Ok(Value::Null)
+        }
+        "switch_upstream" => {
+            let upstream_id = param_u32(params, "upstream_id")?;
+            let new_upstream_id = control.switch_upstream(upstream_id).map_err(failed)?;
+            Ok(json!({ "upstream_id": new_upstream_id }))
+        }
+        "drain_upstream" => {
+            let upstream_id = param_u32(params, "upstream_id")?;
+            let new_upstream_id = control.drain_upstream(upstream_id).await.map_err(failed)?;
+            Ok(json!({ "upstream_id": new_upstream_id }))
+        }
+        "set_target" => {
+            let connection_id = param_u32(params, "connection_id")?;
+            let channel_id = param_u32(params, "channel_id")?;
+            let difficulty = params
+                .get("difficulty")
+                .and_then(Value::as_f64)
+                .filter(|difficulty| *difficulty > 0.0)
+                .ok_or_else(|| (INVALID_PARAMS, "Missing or invalid param difficulty".into()))?;
+            control
+                .set_target(
+                    connection_id,
+                    channel_id,
+                    Target::from_difficulty(difficulty),
+                )
+                .await
+                .map_err(failed)?;
+            Ok(Value::Null)
+        }
+        "reload_config" => match reload {
+            Some(reload) => (reload.0)(control).map(|_| Value::Null).map_err(failed),
+            None => Err(failed("Reload is not supported".into())),
+        },
+        "stats" => Ok(control.stats_json()),
+        "workers" => Ok(control.workers()),
+        "recent_events" => Ok(control.recent_events()),
+        _ => Err((METHOD_NOT_FOUND, format!("Unknown method {}", method))),
+    }
+}
+
+/// Execute a JSON-RPC request and return the serialized response
+pub async fn handle_request(
+    control: &ProxyControl,
+    reload: Option<&ReloadHook>,
+    request: &str,
+) -> String {
+    let request: Value = match serde_json::from_str(request) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = json!({ "code": PARSE_ERROR, "message": e.to_string() });
+            return json!({ "jsonrpc": "2.0", "id": Value::Null, "error": error }).to_string();
+        }
+    };
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = request.get("method").and_then(Value::as_str).unwrap_or("");
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+    match execute(control, reload, method, &params).await {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }).to_string(),
+        Err((code, message)) => {
+            let error = json!({ "code": code, "message": message });
+            json!({ "jsonrpc": "2.0", "id": id, "error": error }).to_string()
+        }
+    }
+}
+
+async fn serve_connection(stream: TcpStream, control: ProxyControl, reload: Option<ReloadHook>) {
+    let mut writer = stream.clone();
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(Ok(line)) = lines.next().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut response = handle_request(&control, reload.as_ref(), &line).await;
+        response.push('\n');
+        if writer.write_all(response.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Accept admin connections on `listener`
+pub async fn serve(listener: TcpListener, control: ProxyControl, reload: Option<ReloadHook>) {
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        if let Ok(stream) = stream {
+            task::spawn(serve_connection(stream, control.clone(), reload.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn handles_json_rpc_requests() {
+        let control = ProxyControl::new(Arc::new(Mutex::new(Vec::new())), ProxyContext::new(2, 2));
+        let response = |request: &'static str| {
+            let control = control.clone();
+            async move {
+                let response = handle_request(&control, None, request).await;
+                serde_json::from_str::<Value>(&response).unwrap()
+            }
+        };
+
+        let res = response(r#"{"jsonrpc": "2.0", "id": 1, "method": "stats"}"#).await;
+        assert_eq!(res["id"], 1);
+        assert_eq!(res["result"]["downstreams"], 0);
+        assert_eq!(res["result"]["labels"], json!({}));
+
+        let res = response(r#"{"jsonrpc": "2.0", "id": 2, "method": "list_downstreams"}"#).await;
+        assert_eq!(res["result"], json!([]));
+
+        let res = response(r#"{"jsonrpc": "2.0", "id": 3, "method": "unknown"}"#).await;
+        assert_eq!(res["error"]["code"], METHOD_NOT_FOUND);
+
+        let res = response(r#"{"jsonrpc": "2.0", "id": 4, "method": "close_channel"}"#).await;
+        assert_eq!(res["error"]["code"], INVALID_PARAMS);
+
+        let res = response(
+            r#"{"jsonrpc": "2.0", "id": 5, "method": "close_channel",
+                "params": {"connection_id": 1, "channel_id": 1}}"#,
+        )
+        .await;
+        assert_eq!(res["error"]["code"], REQUEST_FAILED);
+
+        let res = response(r#"{"jsonrpc": "2.0", "id": 6, "method": "reload_config"}"#).await;
+        assert_eq!(res["error"]["code"], REQUEST_FAILED);
+
+        let res = response(r#"{"jsonrpc": "2.0", "id": 7, "method": "workers"}"#).await;
+        assert_eq!(res["result"], json!([]));
+
+        control.context.publish(ConnectionEvent::ChannelOpened {
+            connection_id: 1,
+            channel_id: 2,
+        });
+        let res = response(r#"{"jsonrpc": "2.0", "id": 8, "method": "recent_events"}"#).await;
+        assert_eq!(res["result"][0]["event"], "channel_opened");
+        assert_eq!(res["result"][0]["channel_id"], 2);
+
+        let res = response(
+            r#"{"jsonrpc": "2.0", "id": 9, "method": "drain_upstream", "params": {"upstream_id": 1}}"#,
+        )
+        .await;
+        assert_eq!(res["error"]["code"], REQUEST_FAILED);
+
+        let res = response("{").await;
+        assert_eq!(res["error"]["code"], PARSE_ERROR);
+        assert_eq!(res["id"], Value::Null);
+    }
+}
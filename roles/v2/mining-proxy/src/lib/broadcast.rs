@@ -0,0 +1,153 @@
+//! Deduplicated fan-out of the upstream jobs to the downstream channels.
+//!
+//! When many channels share a job the messages sent to each one of them only differ in the
+//! channel_id, that is the first field of the payload. `JobBroadcast` serialize a message once per
+//! distinct job/target and build the frame of every other channel copying the cached bytes and
+//! overwriting the channel_id, so that the proxy do not serialize the same message once per channel.
+use super::downstream_mining::StdFrame as DownstreamFrame;
+use codec_sv2::Frame;
+use const_sv2::SV2_FRAME_HEADER_SIZE;
+use roles_logic_sv2::parsers::{Mining, MiningDeviceMessages};
+use std::{collections::HashMap, convert::TryInto};
+
+/// Content of a message without the channel_id
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BroadcastKey {
+    NewMiningJob {
+        job_id: u32,
+        future_job: bool,
+        version: u32,
+        merkle_root: Vec<u8>,
+    },
+    SetNewPrevHash {
+        job_id: u32,
+        prev_hash: Vec<u8>,
+        min_ntime: u32,
+        nbits: u32,
+    },
+    SetTarget {
+        maximum_target: Vec<u8>,
+    },
+}
+
+impl BroadcastKey {
+    /// (channel_id, key) for the messages that can be broadcasted, None for the others
+    fn from_message(message: &Mining) -> Option<(u32, Self)> {
+        match message {
+            Mining::NewMiningJob(m) => Some((
+                m.channel_id,
+                Self::NewMiningJob {
+                    job_id: m.job_id,
+                    future_job: m.future_job,
+                    version: m.version,
+                    merkle_root: m.merkle_root.to_vec(),
+                },
+            )),
+            Mining::SetNewPrevHash(m) => Some((
+                m.channel_id,
+                Self::SetNewPrevHash {
+                    job_id: m.job_id,
+                    prev_hash: m.prev_hash.to_vec(),
+                    min_ntime: m.min_ntime,
+                    nbits: m.nbits,
+                },
+            )),
+            Mining::SetTarget(m) => Some((
+                m.channel_id,
+                Self::SetTarget {
+                    maximum_target: m.maximum_target.to_vec(),
+                },
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Serialized frames of a fan-out, to be used for a single batch of messages relayed downstream
+#[derive(Debug, Default)]
+pub struct JobBroadcast {
+    frames: HashMap<BroadcastKey, Vec<u8>>,
+    serialized: usize,
+}
+
+impl JobBroadcast {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Downstream frame for `message`, the message is serialized only if no frame with the same
+    /// content (but another channel_id) has been built before
+    pub fn frame(&mut self, message: Mining<'static>) -> DownstreamFrame {
+        let (channel_id, key) = match BroadcastKey::from_message(&message) {
+            Some(key) => key,
+            None => return MiningDeviceMessages::Mining(message).try_into().unwrap(),
+        };
+        let mut bytes = match self.frames.get(&key) {
+            Some(bytes) => bytes.clone(),
+            None => {
+                let frame: DownstreamFrame =
+                    MiningDeviceMessages::Mining(message).try_into().unwrap();
+                let mut bytes = vec![0; frame.encoded_length()].into();
+                // Is fine to unwrap, the buffer has the size of the encoded frame
+                frame.serialize(&mut bytes).unwrap();
+                let bytes: Vec<u8> = bytes.into();
+                self.serialized += 1;
+                self.frames.insert(key, bytes.clone());
+                bytes
+            }
+        };
+        bytes[SV2_FRAME_HEADER_SIZE..SV2_FRAME_HEADER_SIZE + 4]
+            .copy_from_slice(&channel_id.to_le_bytes());
+        DownstreamFrame::from_bytes_unchecked(bytes.into())
+    }
+
+    /// Number of messages actually serialized
+    pub fn serialized(&self) -> usize {
+        self.serialized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use roles_logic_sv2::mining_sv2::{NewMiningJob, SetNewPrevHash};
+
+    fn prev_hash(channel_id: u32) -> Mining<'static> {
+        Mining::SetNewPrevHash(SetNewPrevHash {
+            channel_id,
+            job_id: 3,
+            prev_hash: [1; 32].to_vec().try_into().unwrap(),
+            min_ntime: 10,
+            nbits: 20,
+        })
+    }
+
+    fn serialize(frame: DownstreamFrame) -> Vec<u8> {
+        let mut bytes = vec![0; frame.encoded_length()].into();
+        frame.serialize(&mut bytes).unwrap();
+        bytes.into()
+    }
+
+    #[test]
+    fn serializes_once_per_job() {
+        let mut broadcast = JobBroadcast::new();
+        for channel_id in 0..10 {
+            let frame = broadcast.frame(prev_hash(channel_id));
+            let expected: DownstreamFrame = MiningDeviceMessages::Mining(prev_hash(channel_id))
+                .try_into()
+                .unwrap();
+            assert_eq!(serialize(frame), serialize(expected));
+        }
+        assert_eq!(broadcast.serialized(), 1);
+
+        let job = Mining::NewMiningJob(NewMiningJob {
+            channel_id: 4,
+            job_id: 3,
+            future_job: false,
+            version: 2,
+            merkle_root: [2; 32].to_vec().try_into().unwrap(),
+        });
+        broadcast.frame(job);
+        assert_eq!(broadcast.serialized(), 2);
+    }
+}
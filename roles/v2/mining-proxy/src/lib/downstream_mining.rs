@@ -1,28 +1,79 @@
-use super::upstream_mining::{JobDispatcher, StdFrame as UpstreamFrame, UpstreamMiningNode};
+use super::{
+    proxy_context::{ParkedRequest, ProxyContext},
+    share_rate::{ShareRateAction, ShareRateMonitor},
+    upstream_mining::{JobDispatcher, StdFrame as UpstreamFrame, UpstreamMiningNode},
+};
 use async_channel::{Receiver, SendError, Sender};
 use roles_logic_sv2::{
-    common_messages_sv2::{SetupConnection, SetupConnectionSuccess},
+    channel_aggregator::AggregatedMessage,
+    channel_state::ChannelState,
+    common_messages_sv2::{ChannelEndpointChanged, SetupConnection, SetupConnectionSuccess},
     common_properties::{
-        CommonDownstreamData, DownstreamChannel, IsDownstream, IsMiningDownstream,
+        CommonDownstreamData, DownstreamChannel, IsDownstream, IsMiningDownstream, IsUpstream,
+        JobIdMapper, StandardChannel,
+    },
+    conformance::ConformanceGuard,
+    error_codes::{
+        open_mining_channel_error, setup_connection_error, share_error, MiningErrorCode,
     },
     errors::Error,
+    events::ConnectionEvent,
+    extensions::Extensions,
     handlers::{
         common::{ParseDownstreamCommonMessages, SendTo as SendToCommon},
         mining::{ParseDownstreamMiningMessages, SendTo, SupportedChannelTypes},
     },
+    job_dispatcher::SendSharesResponse,
     mining_sv2::*,
-    parsers::{Mining, MiningDeviceMessages, PoolMessages},
+    parsers::{CommonMessages, Mining, MiningDeviceMessages, PoolMessages, RelayFrame},
     routing_logic::MiningProxyRoutingLogic,
-    utils::Mutex,
+    telemetry::{DeviceTelemetry, TelemetryHandler, TelemetryStore},
+    user_identity::{IdentityRules, UserIdentity},
+    utils::{standard_share_hash, Mutex, Target, TargetConflictPolicy},
 };
-use std::collections::HashMap;
+use std::{collections::HashMap, convert::TryFrom, time::Instant};
 
 use codec_sv2::{Frame, StandardEitherFrame, StandardSv2Frame};
+use const_sv2::MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL;
 
 pub type Message = MiningDeviceMessages<'static>;
 pub type StdFrame = StandardSv2Frame<Message>;
 pub type EitherFrame = StandardEitherFrame<Message>;
 
+/// Shares submitted on a channel since it has been opened
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShareStats {
+    pub submitted: u64,
+    /// Acknowledged by the upstream with SubmitSharesSuccess
+    pub accepted: u64,
+    /// Rejected by the upstream or by the proxy
+    pub rejected: u64,
+    /// Sum of the difficulty of the accepted shares
+    pub accepted_difficulty: f64,
+    pub since: Instant,
+}
+
+impl ShareStats {
+    fn new(since: Instant) -> Self {
+        Self {
+            submitted: 0,
+            accepted: 0,
+            rejected: 0,
+            accepted_difficulty: 0.0,
+            since,
+        }
+    }
+
+    /// Hash rate estimated from the accepted shares, h/s
+    pub fn hash_rate(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.since).as_secs_f64();
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+        self.accepted_difficulty * 2_f64.powi(32) / elapsed
+    }
+}
+
 /// 1 to 1 connection with a downstream node that implement the mining (sub)protocol can be either
 /// a mining device or a downstream proxy.
 #[derive(Debug)]
@@ -32,7 +83,46 @@ pub struct DownstreamMiningNode {
     pub status: DownstreamMiningNodeStatus,
     // channel_id/group_id -> group_id
     channel_id_to_group_id: HashMap<u32, u32>,
-    pub prev_job_id: Option<u32>,
+    /// channel_id/group_id -> job state of the jobs relayed to the downstream, the merkle root is
+    /// kept for the standard jobs
+    job_states: HashMap<u32, ChannelState<Option<Vec<u8>>>>,
+    /// The jobs of the upstreams are relayed with ids unique on the connection
+    job_id_mapper: JobIdMapper,
+    /// channel_id -> upstream that serve the channel, the shares of the channel are relayed to it
+    channel_upstreams: HashMap<u32, Arc<Mutex<UpstreamMiningNode>>>,
+    connection_handle: Option<ConnectionHandle>,
+    context: ProxyContext,
+    extensions: Arc<Mutex<Extensions>>,
+    /// request_id -> nominal_hash_rate of the OpenStandardMiningChannel waiting for a response
+    requested_hash_rates: HashMap<u32, f32>,
+    /// request_id -> user_identity of the OpenStandardMiningChannel waiting for a response
+    requested_workers: HashMap<u32, UserIdentity>,
+    /// request_id -> upstream the OpenStandardMiningChannel waiting for a response was relayed to
+    requested_upstreams: HashMap<u32, Arc<Mutex<UpstreamMiningNode>>>,
+    /// Telemetry reported by the downstream channels, see `roles_logic_sv2::telemetry`
+    telemetry: TelemetryStore,
+    /// Upstream that serve the channels of the downstream from its aggregated extended channel
+    aggregating_upstream: Option<Arc<Mutex<UpstreamMiningNode>>>,
+    /// Address of the downstream, read from the PROXY header if the proxy protocol is enabled
+    remote_address: Option<SocketAddr>,
+    /// Identify the connection in the published `ConnectionEvent`s
+    connection_id: u32,
+    /// channel_id -> shares submitted on the channel
+    share_stats: HashMap<u32, ShareStats>,
+    /// channel_id -> user_identity the channel was opened with
+    workers: HashMap<u32, UserIdentity>,
+    /// channel_id -> share rate of the standard channels, see `ProxyContext::share_rate_limit`
+    share_rates: HashMap<u32, ShareRateMonitor>,
+    /// Actions requested by the share rate monitors while handling a share, executed by `next`
+    share_rate_actions: Vec<(u32, ShareRateAction)>,
+    /// channel_id -> (upstream target, local target) of the standard channels, see
+    /// `on_upstream_target`
+    targets: HashMap<u32, (Target, Target)>,
+    /// Channel of the share being relayed if it solve a block, `next` relay it ahead of the queued
+    /// frames
+    block_found: Option<u32>,
+    /// Check the ordering of the messages of the connection, see `roles_logic_sv2::conformance`
+    conformance: Arc<Mutex<ConformanceGuard>>,
 }
 
 #[derive(Debug)]
@@ -88,16 +178,69 @@ impl DownstreamMiningNode {
     pub fn add_channel(&mut self, channel: DownstreamChannel) {
         self.channel_id_to_group_id
             .insert(channel.channel_id(), channel.group_id());
+        self.context.publish(ConnectionEvent::ChannelOpened {
+            connection_id: self.connection_id,
+            channel_id: channel.channel_id(),
+        });
+        self.share_stats
+            .insert(channel.channel_id(), ShareStats::new(Instant::now()));
+        if let (Some(limit), DownstreamChannel::Standard(c)) =
+            (self.context.share_rate_limit(), &channel)
+        {
+            let monitor = ShareRateMonitor::new(limit, c.target.clone().into());
+            self.share_rates.insert(c.channel_id, monitor);
+        }
         self.status.add_channel(channel);
     }
 
-    pub fn new(receiver: Receiver<EitherFrame>, sender: Sender<EitherFrame>) -> Self {
+    pub fn new(
+        receiver: Receiver<EitherFrame>,
+        sender: Sender<EitherFrame>,
+        connection_handle: ConnectionHandle,
+        context: ProxyContext,
+    ) -> Self {
+        let telemetry_handler = TelemetryHandler::default();
+        let telemetry = telemetry_handler.store();
+        let mut extensions = Extensions::new();
+        extensions.register(Box::new(telemetry_handler));
         Self {
             receiver,
             sender,
             status: DownstreamMiningNodeStatus::Initializing,
             channel_id_to_group_id: HashMap::new(),
-            prev_job_id: None,
+            job_states: HashMap::new(),
+            job_id_mapper: JobIdMapper::new(),
+            channel_upstreams: HashMap::new(),
+            connection_handle: Some(connection_handle),
+            context,
+            extensions: Arc::new(Mutex::new(extensions)),
+            requested_hash_rates: HashMap::new(),
+            requested_workers: HashMap::new(),
+            requested_upstreams: HashMap::new(),
+            telemetry,
+            aggregating_upstream: None,
+            remote_address: None,
+            connection_id: 0,
+            share_stats: HashMap::new(),
+            workers: HashMap::new(),
+            share_rates: HashMap::new(),
+            share_rate_actions: Vec::new(),
+            targets: HashMap::new(),
+            block_found: None,
+            conformance: Arc::new(Mutex::new(ConformanceGuard::new())),
+        }
+    }
+
+    /// Address of the downstream, see `ProxyContext::proxy_protocol`
+    pub fn remote_address(&self) -> Option<SocketAddr> {
+        self.remote_address
+    }
+
+    /// Flags and device negotiated with SetupConnection, None before that the downstream is paired
+    pub fn downstream_data(&self) -> Option<&CommonDownstreamData> {
+        match &self.status {
+            DownstreamMiningNodeStatus::Initializing => None,
+            DownstreamMiningNodeStatus::Paired((data, _)) => Some(data),
         }
     }
 
@@ -114,25 +257,37 @@ impl DownstreamMiningNode {
             let setup_connection_success: MiningDeviceMessages = setup_connection_success.into();
 
             {
-                DownstreamMiningNode::send(
+                // If the downstream is already closed the loop below publish the disconnection
+                let _ = DownstreamMiningNode::send(
                     self_mutex.clone(),
                     setup_connection_success.try_into().unwrap(),
                 )
-                .await
-                .unwrap();
+                .await;
             }
 
-            task::spawn(async move {
-                loop {
-                    let receiver = self_mutex
-                        .safe_lock(|self_| self_.receiver.clone())
-                        .unwrap();
-                    let message = receiver.recv().await.unwrap();
-                    let incoming: StdFrame = message.try_into().unwrap();
-                    Self::next(self_mutex.clone(), incoming).await
-                }
-            })
-            .await;
+            // The messages are handled in the task of the connection, that with
+            // `ProxyBuilder::listener_workers` run on the worker of the connection
+            loop {
+                let receiver = self_mutex
+                    .safe_lock(|self_| self_.receiver.clone())
+                    .unwrap();
+                let message = match receiver.recv().await {
+                    Ok(message) => message,
+                    // Connection has been closed
+                    Err(_) => {
+                        self_mutex
+                            .safe_lock(|self_| {
+                                self_.context.publish(ConnectionEvent::Disconnected {
+                                    connection_id: self_.connection_id,
+                                })
+                            })
+                            .unwrap();
+                        break;
+                    }
+                };
+                let incoming: StdFrame = message.try_into().unwrap();
+                Self::next(self_mutex.clone(), incoming).await
+            }
         } else {
             panic!()
         }
@@ -140,62 +295,935 @@ impl DownstreamMiningNode {
 
     /// Parse the received message and relay it to the right upstream
     pub async fn next(self_mutex: Arc<Mutex<Self>>, mut incoming: StdFrame) {
-        let message_type = incoming.get_header().unwrap().msg_type();
+        let header = incoming.get_header().unwrap();
+        if header.msg_type() == MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL {
+            incoming = match Self::park_channel_request(self_mutex.clone(), incoming).await {
+                Some(incoming) => incoming,
+                None => return,
+            };
+        }
         let payload = incoming.payload();
 
-        let routing_logic = crate::get_routing_logic();
+        let routing_logic = self_mutex
+            .safe_lock(|self_| self_.context.get_routing_logic())
+            .unwrap();
 
         let next_message_to_send = ParseDownstreamMiningMessages::handle_message_mining(
             self_mutex.clone(),
-            message_type,
+            header,
             payload,
             routing_logic,
         );
+        let block_found = self_mutex
+            .safe_lock(|self_| self_.block_found.take())
+            .unwrap();
 
         match next_message_to_send {
-            Ok(SendTo::RelaySameMessage(upstream_mutex)) => {
-                let sv2_frame: codec_sv2::Sv2Frame<PoolMessages, Vec<u8>> =
-                    incoming.map(|payload| payload.try_into().unwrap());
-                UpstreamMiningNode::send(upstream_mutex.clone(), sv2_frame)
-                    .await
-                    .unwrap();
-            }
+            Ok(SendTo::RelaySameMessage(upstream_mutex)) => match incoming.relay() {
+                Ok(sv2_frame) => {
+                    on_upstream_send(
+                        UpstreamMiningNode::send(upstream_mutex.clone(), sv2_frame).await,
+                    );
+                }
+                Err(e) => println!("Downstream error: {:?}", e),
+            },
             Ok(SendTo::RelayNewMessage(upstream_mutex, message)) => {
+                let share_channel_id = match &message {
+                    Mining::SubmitSharesStandard(m) => Some(m.channel_id),
+                    Mining::SubmitSharesExtended(m) => Some(m.channel_id),
+                    _ => None,
+                };
                 let message = PoolMessages::Mining(message);
                 let frame: UpstreamFrame = message.try_into().unwrap();
-                UpstreamMiningNode::send(upstream_mutex.clone(), frame)
-                    .await
-                    .unwrap();
+                match (share_channel_id, block_found) {
+                    (Some(_), Some(channel_id)) => {
+                        Self::on_block_found(self_mutex.clone(), channel_id);
+                        on_upstream_send(
+                            UpstreamMiningNode::submit_block_solution(
+                                upstream_mutex.clone(),
+                                frame,
+                            )
+                            .await,
+                        );
+                    }
+                    (Some(channel_id), None) => {
+                        on_upstream_send(
+                            UpstreamMiningNode::submit_share(
+                                upstream_mutex.clone(),
+                                channel_id,
+                                frame,
+                            )
+                            .await,
+                        );
+                    }
+                    (None, _) => {
+                        on_upstream_send(
+                            UpstreamMiningNode::send(upstream_mutex.clone(), frame).await,
+                        );
+                    }
+                }
             }
+            // Already checked by the handler
             Ok(SendTo::Respond(message)) => {
                 let message = MiningDeviceMessages::Mining(message);
                 let frame: StdFrame = message.try_into().unwrap();
-                DownstreamMiningNode::send(self_mutex.clone(), frame)
-                    .await
-                    .unwrap();
+                let _ = DownstreamMiningNode::write(self_mutex.clone(), frame).await;
             }
-            Ok(SendTo::Multiple(_sends_to)) => {
-                todo!();
+            Ok(SendTo::Multiple(sends_to)) => {
+                for send_to in sends_to {
+                    match send_to {
+                        SendTo::RelayNewMessage(upstream_mutex, message) => {
+                            let message = PoolMessages::Mining(message);
+                            let frame: UpstreamFrame = message.try_into().unwrap();
+                            on_upstream_send(UpstreamMiningNode::send(upstream_mutex, frame).await);
+                        }
+                        SendTo::Respond(message) => {
+                            let message = MiningDeviceMessages::Mining(message);
+                            let frame: StdFrame = message.try_into().unwrap();
+                            let _ = DownstreamMiningNode::write(self_mutex.clone(), frame).await;
+                        }
+                        SendTo::RelaySameMessage(upstream_mutex) => {
+                            match incoming.clone().relay() {
+                                Ok(frame) => {
+                                    on_upstream_send(
+                                        UpstreamMiningNode::send(upstream_mutex, frame).await,
+                                    );
+                                }
+                                Err(e) => println!("Downstream error: {:?}", e),
+                            }
+                        }
+                        SendTo::Extension(messages) => {
+                            for message in messages {
+                                // Is fine to unwrap, the handlers only build messages that fit in
+                                // a frame
+                                let frame =
+                                    StdFrame::from_bytes(message.to_frame_bytes().unwrap().into())
+                                        .unwrap();
+                                let _ = DownstreamMiningNode::send(self_mutex.clone(), frame).await;
+                            }
+                        }
+                        SendTo::None(_) => (),
+                        SendTo::Multiple(_) => {
+                            println!("Downstream error: nested SendTo::Multiple dropped")
+                        }
+                    }
+                }
             }
             Ok(SendTo::None(_)) => (),
-            Err(Error::UnexpectedMessage) => todo!("148"),
-            Err(_) => todo!("149"),
+            Ok(SendTo::Extension(messages)) => {
+                for message in messages {
+                    // Is fine to unwrap, the handlers only build messages that fit in a frame
+                    let frame =
+                        StdFrame::from_bytes(message.to_frame_bytes().unwrap().into()).unwrap();
+                    let _ = DownstreamMiningNode::send(self_mutex.clone(), frame).await;
+                }
+            }
+            // The message is out of order (eg a share before the job), drop it and keep the
+            // connection
+            Err(Error::ProtocolViolation(violation)) => {
+                println!("Downstream error: {}, message dropped", violation)
+            }
+            // The message is valid but the proxy do not expect it, drop it and keep the connection
+            Err(Error::UnexpectedMessage) => {
+                println!("Downstream error: unexpected message {}", header.msg_type())
+            }
+            // Malformed message, the downstream can not be trusted anymore
+            Err(e) => {
+                println!("Downstream error: {:?}, closing the connection", e);
+                self_mutex.safe_lock(|self_| self_.disconnect()).unwrap();
+            }
+        }
+
+        let actions = self_mutex
+            .safe_lock(|self_| std::mem::take(&mut self_.share_rate_actions))
+            .unwrap();
+        for (channel_id, action) in actions {
+            Self::on_share_rate_action(self_mutex.clone(), channel_id, action).await;
+        }
+    }
+
+    /// True if one of the upstreams paired with the downstream can serve it, the parked channel
+    /// requests are relayed when this become true
+    fn has_available_upstream(self_mutex: &Arc<Mutex<Self>>) -> bool {
+        let (downstream_data, context) = self_mutex
+            .safe_lock(|self_| (self_.downstream_data().cloned(), self_.context.clone()))
+            .unwrap();
+        let downstream_data = match downstream_data {
+            Some(downstream_data) => downstream_data,
+            // The router reject the requests of a downstream that is not paired
+            None => return true,
+        };
+        context.paired_upstreams(&downstream_data).iter().any(|u| {
+            u.safe_lock(|u| !u.is_quarantined() && !u.is_draining())
+                .unwrap()
+        })
+    }
+
+    /// Park an OpenStandardMiningChannel received while no upstream can serve the downstream, see
+    /// `parked_requests`. Return the frame if the request must be handled now.
+    async fn park_channel_request(
+        self_mutex: Arc<Mutex<Self>>,
+        mut incoming: StdFrame,
+    ) -> Option<StdFrame> {
+        let context = self_mutex.safe_lock(|self_| self_.context.clone()).unwrap();
+        let limits = match context.park_limits() {
+            Some(limits) => limits,
+            None => return Some(incoming),
+        };
+        if Self::has_available_upstream(&self_mutex) {
+            return Some(incoming);
+        }
+        let msg_type = incoming.get_header().unwrap().msg_type();
+        let message: Result<Mining, _> = (msg_type, incoming.payload()).try_into();
+        let request_id = match message {
+            Ok(Mining::OpenStandardMiningChannel(m)) => m.get_request_id_as_u32(),
+            // Malformed requests are rejected by the handler
+            _ => return Some(incoming),
+        };
+        if context.park_channel_request((self_mutex.clone(), incoming, request_id)) {
+            task::spawn(async move {
+                task::sleep(limits.timeout).await;
+                for (downstream, _, request_id) in context.expired_channel_requests() {
+                    Self::reject_parked_request(downstream, request_id).await;
+                }
+            });
+        } else {
+            Self::reject_parked_request(self_mutex, request_id).await;
+        }
+        None
+    }
+
+    async fn reject_parked_request(self_mutex: Arc<Mutex<Self>>, request_id: u32) {
+        let error = open_mining_channel_error(request_id, MiningErrorCode::UpstreamNotReady);
+        let message = MiningDeviceMessages::Mining(Mining::OpenMiningChannelError(error));
+        let frame: StdFrame = message.try_into().unwrap();
+        // The downstream could be already disconnected
+        let _ = DownstreamMiningNode::send(self_mutex, frame).await;
+    }
+
+    /// Relay the parked channel requests of the downstreams that have an available upstream again,
+    /// called when an upstream come back
+    pub async fn release_parked_requests(context: ProxyContext) {
+        let released: Vec<ParkedRequest> =
+            context.release_channel_requests(|(downstream, _, _)| {
+                Self::has_available_upstream(downstream)
+            });
+        if !released.is_empty() {
+            println!("Relaying {} parked channel requests", released.len());
+        }
+        for (downstream, frame, _) in released {
+            if downstream.safe_lock(|d| d.is_connected()).unwrap() {
+                Self::next(downstream, frame).await;
+            }
+        }
+    }
+
+    fn on_block_found(self_mutex: Arc<Mutex<Self>>, channel_id: u32) {
+        self_mutex
+            .safe_lock(|self_| {
+                println!(
+                    "Block found on channel {} of connection {}",
+                    channel_id, self_.connection_id
+                );
+                self_.context.publish(ConnectionEvent::BlockFound {
+                    connection_id: self_.connection_id,
+                    channel_id,
+                });
+            })
+            .unwrap();
+    }
+
+    /// Raise the difficulty of a channel that submit too many shares or close it, see `share_rate`
+    async fn on_share_rate_action(
+        self_mutex: Arc<Mutex<Self>>,
+        channel_id: u32,
+        action: ShareRateAction,
+    ) {
+        let connection_id = self_mutex.safe_lock(|self_| self_.connection_id).unwrap();
+        match action {
+            ShareRateAction::Accept => (),
+            ShareRateAction::RaiseDifficulty(target) => {
+                println!(
+                    "Channel {} of connection {} is flooding, raising the difficulty to {}",
+                    channel_id,
+                    connection_id,
+                    target.difficulty()
+                );
+                // The downstream could be already disconnected
+                let _ = Self::set_target(self_mutex, channel_id, target).await;
+            }
+            ShareRateAction::Close => {
+                println!(
+                    "Channel {} of connection {} is still flooding, closing it",
+                    channel_id, connection_id
+                );
+                Self::close_channel_with_reason(
+                    self_mutex,
+                    channel_id,
+                    MiningErrorCode::ShareRateExceeded.as_str(),
+                )
+                .await;
+            }
+        }
+    }
+
+    /// Remove every opened channel and return the ids of the removed channels. Used when the
+    /// channels are remapped to another upstream, the downstream must open new channels.
+    pub fn reset_channels(&mut self) -> Vec<u32> {
+        self.channel_id_to_group_id.clear();
+        self.job_states.clear();
+        self.job_id_mapper.clear();
+        self.channel_upstreams.clear();
+        self.share_stats.clear();
+        self.workers.clear();
+        self.share_rates.clear();
+        self.targets.clear();
+        self.aggregating_upstream = None;
+        let channel_ids: Vec<u32> = match &mut self.status {
+            DownstreamMiningNodeStatus::Initializing => Vec::new(),
+            DownstreamMiningNodeStatus::Paired((_, channels)) => channels
+                .drain()
+                .flat_map(|(_, channels)| channels)
+                .map(|channel| channel.channel_id())
+                .collect(),
+        };
+        for channel_id in &channel_ids {
+            self.context.publish(ConnectionEvent::ChannelClosed {
+                connection_id: self.connection_id,
+                channel_id: *channel_id,
+            });
+        }
+        channel_ids
+    }
+
+    /// Reset the downstream channels and send a ChannelEndpointChanged for each one of them, so
+    /// that the downstream reset the channels state and open new channels.
+    pub async fn on_channel_endpoint_changed(self_mutex: Arc<Mutex<Self>>) {
+        let channel_ids = self_mutex
+            .safe_lock(|self_| self_.reset_channels())
+            .unwrap();
+        for channel_id in channel_ids {
+            let message: MiningDeviceMessages = ChannelEndpointChanged { channel_id }.into();
+            let frame: StdFrame = message.try_into().unwrap();
+            let _ = DownstreamMiningNode::send(self_mutex.clone(), frame).await;
+        }
+    }
+
+    /// Forget the channel `channel_id`, return None if the downstream did not open it
+    fn remove_channel(&mut self, channel_id: u32) -> Option<DownstreamChannel> {
+        let group_id = self.channel_id_to_group_id.remove(&channel_id)?;
+        self.job_states.remove(&channel_id);
+        self.channel_upstreams.remove(&channel_id);
+        self.share_stats.remove(&channel_id);
+        self.workers.remove(&channel_id);
+        self.share_rates.remove(&channel_id);
+        self.targets.remove(&channel_id);
+        let group = self.status.get_channels().get_mut(&group_id)?;
+        let index = group.iter().position(|c| c.channel_id() == channel_id)?;
+        let channel = group.remove(index);
+        if group.is_empty() {
+            self.status.get_channels().remove(&group_id);
+        }
+        self.context.publish(ConnectionEvent::ChannelClosed {
+            connection_id: self.connection_id,
+            channel_id,
+        });
+        Some(channel)
+    }
+
+    /// Close the channel `channel_id` on request of the operator: the upstream channel is closed
+    /// (unless it serve other channels, as the aggregated extended channel) and the downstream
+    /// receive a CloseChannel. Return false if the downstream did not open the channel.
+    pub async fn close_channel(self_mutex: Arc<Mutex<Self>>, channel_id: u32) -> bool {
+        Self::close_channel_with_reason(self_mutex, channel_id, "closed-by-operator").await
+    }
+
+    /// Close the channel `channel_id` as in `close_channel`, the downstream get a CloseChannel with
+    /// `reason`
+    async fn close_channel_with_reason(
+        self_mutex: Arc<Mutex<Self>>,
+        channel_id: u32,
+        reason: &str,
+    ) -> bool {
+        let (removed, upstreams) = self_mutex
+            .safe_lock(|self_| {
+                (
+                    self_.remove_channel(channel_id).is_some(),
+                    self_.context.upstreams(),
+                )
+            })
+            .unwrap();
+        if !removed {
+            return false;
+        }
+        for upstream in upstreams {
+            let close = upstream
+                .safe_lock(|u| u.on_downstream_channel_closed(channel_id, &self_mutex))
+                .unwrap();
+            if let Some(frame) = close {
+                // The channel is already gone for the downstream, an unreachable upstream is
+                // handled by its health check
+                let _ = UpstreamMiningNode::send(upstream, frame).await;
+            }
+        }
+        let message = MiningDeviceMessages::Mining(Mining::CloseChannel(CloseChannel {
+            channel_id,
+            // Is safe to unwrap a reason code shorter than 32 bytes
+            reason_code: reason.to_string().try_into().unwrap(),
+        }));
+        let frame: StdFrame = message.try_into().unwrap();
+        // The downstream could be already disconnected
+        let _ = Self::send(self_mutex, frame).await;
+        true
+    }
+
+    /// Send SetTarget for the standard channel `channel_id`. The target can only be harder than
+    /// the target that the channel was opened with and than the last target set by the upstream,
+    /// otherwise the upstream would reject the shares.
+    pub async fn set_target(
+        self_mutex: Arc<Mutex<Self>>,
+        channel_id: u32,
+        target: Target,
+    ) -> Result<(), &'static str> {
+        let opened_with = self_mutex
+            .safe_lock(|self_| {
+                let opened_with = self_
+                    .standard_channels()
+                    .into_iter()
+                    .find(|channel| channel.channel_id == channel_id)
+                    .map(|channel| Target::from(channel.target))?;
+                match self_.targets.get(&channel_id) {
+                    Some((upstream, _)) => Some(opened_with.min(*upstream)),
+                    None => Some(opened_with),
+                }
+            })
+            .unwrap()
+            .ok_or("unknown-channel")?;
+        if target > opened_with {
+            return Err("target-too-easy");
+        }
+        self_mutex
+            .safe_lock(|self_| {
+                if let Some((_, local)) = self_.targets.get_mut(&channel_id) {
+                    *local = target;
+                }
+            })
+            .unwrap();
+        let message = MiningDeviceMessages::Mining(Mining::SetTarget(SetTarget {
+            channel_id,
+            maximum_target: target.into(),
+        }));
+        let frame: StdFrame = message.try_into().unwrap();
+        Self::send(self_mutex, frame)
+            .await
+            .map_err(|_| "downstream-disconnected")
+    }
+
+    /// Record the target that the upstream set for the standard channel `channel_id` and the
+    /// target that the proxy relayed to the downstream
+    pub fn set_channel_targets(&mut self, channel_id: u32, upstream: Target, local: Target) {
+        self.targets.insert(channel_id, (upstream, local));
+    }
+
+    /// Reconcile the SetTarget sent by the upstream for the standard channel `channel_id` with the
+    /// local target of the channel (set by the target policy, the share rate limit or the admin).
+    /// Return the previous local target and the new one, None if the channel is not known.
+    pub fn on_upstream_target(
+        &mut self,
+        channel_id: u32,
+        upstream: Target,
+        policy: TargetConflictPolicy,
+    ) -> Option<(Target, Target)> {
+        let (previous_upstream, local) = *self.targets.get(&channel_id)?;
+        let target = policy.reconcile(local, previous_upstream, upstream);
+        self.targets.insert(channel_id, (upstream, target));
+        if let Some(monitor) = self.share_rates.get_mut(&channel_id) {
+            monitor.set_target(target);
+        }
+        Some((local, target))
+    }
+
+    /// Every channel opened by the downstream
+    pub fn channels(&self) -> Vec<DownstreamChannel> {
+        match &self.status {
+            DownstreamMiningNodeStatus::Initializing => Vec::new(),
+            DownstreamMiningNodeStatus::Paired((_, channels)) => {
+                channels.values().flatten().cloned().collect()
+            }
+        }
+    }
+
+    /// Identify the connection in the published `ConnectionEvent`s
+    pub fn connection_id(&self) -> u32 {
+        self.connection_id
+    }
+
+    /// Standard channels opened by the downstream
+    pub fn standard_channels(&self) -> Vec<StandardChannel> {
+        match &self.status {
+            DownstreamMiningNodeStatus::Initializing => Vec::new(),
+            DownstreamMiningNodeStatus::Paired((_, channels)) => channels
+                .values()
+                .flatten()
+                .filter_map(|channel| match channel {
+                    DownstreamChannel::Standard(channel) => Some(channel.clone()),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Number of channels opened by the downstream
+    pub fn channels_count(&self) -> usize {
+        match &self.status {
+            DownstreamMiningNodeStatus::Initializing => 0,
+            DownstreamMiningNodeStatus::Paired((_, channels)) => {
+                channels.values().map(Vec::len).sum()
+            }
+        }
+    }
+
+    /// Nominal hash rate declared in the OpenStandardMiningChannel with `request_id`, 0.0 if not
+    /// known
+    pub fn take_requested_hash_rate(&mut self, request_id: u32) -> f32 {
+        self.requested_hash_rates.remove(&request_id).unwrap_or(0.0)
+    }
+
+    /// Assign the user_identity and the upstream of the OpenStandardMiningChannel with
+    /// `request_id` to the opened channel
+    pub fn assign_request(&mut self, request_id: u32, channel_id: u32) {
+        if let Some(worker) = self.requested_workers.remove(&request_id) {
+            self.workers.insert(channel_id, worker);
+        }
+        if let Some(upstream) = self.requested_upstreams.remove(&request_id) {
+            self.channel_upstreams.insert(channel_id, upstream);
+        }
+    }
+
+    /// The user_identity the channel was opened with, None if it is not a valid `account.worker`
+    pub fn worker(&self, channel_id: u32) -> Option<&UserIdentity> {
+        self.workers.get(&channel_id)
+    }
+
+    /// Answer an OpenStandardMiningChannel served by the aggregated extended channel of `up`, the
+    /// success is followed by the jobs and the prev hash that the channel need to start mining
+    #[allow(clippy::type_complexity)]
+    fn on_aggregated_channel(
+        &mut self,
+        up: Arc<Mutex<UpstreamMiningNode>>,
+        nominal_hash_rate: f32,
+        worker: Option<UserIdentity>,
+        opened: Result<
+            (
+                OpenStandardMiningChannelSuccess<'static>,
+                Vec<AggregatedMessage>,
+            ),
+            OpenMiningChannelError<'static>,
+        >,
+    ) -> Result<SendTo<UpstreamMiningNode>, Error> {
+        let (mut success, messages) = match opened {
+            Ok(opened) => opened,
+            Err(error) => return Ok(SendTo::Respond(Mining::OpenMiningChannelError(error))),
+        };
+        let target = self
+            .context
+            .channel_target(success.target.clone().into(), nominal_hash_rate);
+        success
+            .target
+            .inner_as_mut()
+            .copy_from_slice(&target.to_le_bytes());
+        self.add_channel(DownstreamChannel::Standard(StandardChannel {
+            channel_id: success.channel_id,
+            group_id: success.group_channel_id,
+            target: success.target.clone().into(),
+            extranonce: success.extranonce_prefix.clone().into(),
+        }));
+        if let Some(worker) = worker {
+            self.workers.insert(success.channel_id, worker);
+        }
+        // Is fine to unwrap on safe_lock
+        let upstream_id = up.safe_lock(|u| u.get_id()).unwrap();
+        self.channel_upstreams
+            .insert(success.channel_id, up.clone());
+        self.aggregating_upstream = Some(up);
+        let mut responses = vec![SendTo::Respond(Mining::OpenStandardMiningChannelSuccess(
+            success,
+        ))];
+        for message in messages {
+            let mut message: Mining = message.into();
+            self.map_job_id(upstream_id, &mut message);
+            self.on_job_message(&message);
+            responses.push(SendTo::Respond(message));
+        }
+        Ok(SendTo::Multiple(responses))
+    }
+
+    /// Update the job state of the channel with a job relayed to the downstream, the job replaced
+    /// by the new job is forgotten. Jobs that are not valid for the channel state are not
+    /// tracked.
+    pub fn on_new_job(
+        &mut self,
+        channel_id: u32,
+        job_id: u32,
+        future_job: bool,
+        merkle_root: Option<Vec<u8>>,
+    ) {
+        let replaced = self
+            .job_states
+            .entry(channel_id)
+            .or_default()
+            .on_new_job(job_id, future_job, merkle_root)
+            .ok()
+            .flatten();
+        if let Some(replaced) = replaced {
+            self.job_id_mapper.remove(replaced);
+        }
+    }
+
+    /// Replace the job id of a job or a prev hash of the upstream `upstream_id` with the id used
+    /// on the downstream connection, see `JobIdMapper`
+    pub fn map_job_id(&mut self, upstream_id: u32, message: &mut Mining) {
+        let job_id = match message {
+            Mining::NewMiningJob(m) => &mut m.job_id,
+            Mining::NewExtendedMiningJob(m) => &mut m.job_id,
+            Mining::SetNewPrevHash(m) => &mut m.job_id,
+            _ => return,
+        };
+        *job_id = self.job_id_mapper.on_new_job(upstream_id, *job_id);
+    }
+
+    /// Update the job state of the channel with a prev hash relayed to the downstream
+    pub fn on_set_new_prev_hash(&mut self, prev_hash: &SetNewPrevHash) {
+        let _ = self
+            .job_states
+            .entry(prev_hash.channel_id)
+            .or_default()
+            .on_set_new_prev_hash(prev_hash.as_static());
+    }
+
+    /// Update the job state for a message relayed to the downstream, see `on_new_job`
+    pub fn on_job_message(&mut self, message: &Mining) {
+        match message {
+            Mining::NewMiningJob(m) => self.on_new_job(
+                m.channel_id,
+                m.job_id,
+                m.future_job,
+                Some(m.merkle_root.to_vec()),
+            ),
+            Mining::NewExtendedMiningJob(m) => {
+                self.on_new_job(m.channel_id, m.job_id, m.future_job, None)
+            }
+            Mining::SetNewPrevHash(m) => self.on_set_new_prev_hash(m),
+            _ => (),
+        }
+    }
+
+    /// Update the share statistics of the channel for a SubmitSharesSuccess or a
+    /// SubmitSharesError relayed to the downstream
+    pub fn on_share_result(&mut self, message: &Mining) {
+        match message {
+            Mining::SubmitSharesSuccess(m) => {
+                let difficulty = self
+                    .standard_channels()
+                    .into_iter()
+                    .find(|channel| channel.channel_id == m.channel_id)
+                    .map(|channel| Target::from(channel.target).difficulty())
+                    .unwrap_or(0.0);
+                if let Some(stats) = self.share_stats.get_mut(&m.channel_id) {
+                    stats.accepted += m.new_submits_accepted_count as u64;
+                    stats.accepted_difficulty += difficulty * m.new_submits_accepted_count as f64;
+                }
+            }
+            Mining::SubmitSharesError(m) => {
+                if let Some(stats) = self.share_stats.get_mut(&m.channel_id) {
+                    stats.rejected += 1;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Share statistics of every channel of the downstream, channel_id -> stats
+    pub fn share_stats(&self) -> &HashMap<u32, ShareStats> {
+        &self.share_stats
+    }
+
+    /// Relay a share upstream or reject it
+    fn relay_share_standard(
+        &mut self,
+        m: SubmitSharesStandard,
+    ) -> Result<SendTo<UpstreamMiningNode>, Error> {
+        println!("{:?}", m);
+        // The job has been relayed on a previous connection of the downstream
+        if !self.job_id_mapper.is_current_epoch(m.job_id) {
+            return Ok(SendTo::Respond(Mining::SubmitSharesError(share_error(
+                &m,
+                MiningErrorCode::StaleShare,
+            ))));
+        }
+        // The aggregated channels have an exact job state, shares for jobs that are not active
+        // are not relayed
+        if self.aggregating_upstream.is_some() {
+            if let Err(error_code) = self.check_share(&m) {
+                return Ok(SendTo::Respond(Mining::SubmitSharesError(share_error(
+                    &m, error_code,
+                ))));
+            }
+        }
+        // Shares that obviously do not meet the target are not worth the upstream bandwidth
+        match self.check_share_target(&m) {
+            Ok(true) => self.block_found = Some(m.channel_id),
+            Ok(false) => (),
+            Err(error_code) => {
+                return Ok(SendTo::Respond(Mining::SubmitSharesError(share_error(
+                    &m, error_code,
+                ))))
+            }
+        }
+        let (upstream_id, upstream_job_id) = match self.job_id_mapper.get(m.job_id) {
+            Some(upstream_job) => upstream_job,
+            None => {
+                return Ok(SendTo::Respond(Mining::SubmitSharesError(share_error(
+                    &m,
+                    MiningErrorCode::InvalidJobId,
+                ))))
+            }
+        };
+        if let Some(up) = &self.aggregating_upstream {
+            let m = SubmitSharesStandard {
+                job_id: upstream_job_id,
+                ..m
+            };
+            if let Some(share) = up.safe_lock(|u| u.submit_aggregated_share(&m)).unwrap() {
+                return match share {
+                    Ok(share) => Ok(SendTo::RelayNewMessage(
+                        up.clone(),
+                        Mining::SubmitSharesExtended(share),
+                    )),
+                    Err(error) => Ok(SendTo::Respond(Mining::SubmitSharesError(error))),
+                };
+            }
+        }
+        let (group_id, remote) = match (
+            self.channel_id_to_group_id.get(&m.channel_id),
+            self.channel_upstreams.get(&m.channel_id),
+        ) {
+            (Some(group_id), Some(remote)) => (*group_id, remote.clone()),
+            _ => {
+                return Ok(SendTo::Respond(Mining::SubmitSharesError(share_error(
+                    &m,
+                    MiningErrorCode::InvalidChannelId,
+                ))))
+            }
+        };
+        let share = SubmitSharesStandard {
+            job_id: upstream_job_id,
+            ..m
+        };
+        remote
+            .safe_lock(|r| {
+                // The job has been relayed by another upstream, eg before a failover
+                if r.get_id() != upstream_id {
+                    return Ok(SendTo::Respond(Mining::SubmitSharesError(share_error(
+                        &m,
+                        MiningErrorCode::StaleShare,
+                    ))));
+                }
+                match r.channel_id_to_job_dispatcher.get_mut(&group_id) {
+                    Some(JobDispatcher::Group(dispatcher)) => {
+                        match dispatcher.on_submit_shares(share) {
+                            SendSharesResponse::Valid(share) => Ok(SendTo::RelayNewMessage(
+                                remote.clone(),
+                                Mining::SubmitSharesStandard(share),
+                            )),
+                            SendSharesResponse::Invalid(error) => {
+                                Ok(SendTo::Respond(Mining::SubmitSharesError(error)))
+                            }
+                        }
+                    }
+                    // The jobs of the header only upstreams are relayed as they are
+                    _ => Ok(SendTo::RelayNewMessage(
+                        remote.clone(),
+                        Mining::SubmitSharesStandard(share),
+                    )),
+                }
+            })
+            .unwrap()
+    }
+
+    /// Error code for a share that is not for the active job of the channel
+    fn check_share(&self, share: &SubmitSharesStandard) -> Result<(), MiningErrorCode> {
+        let state = self
+            .job_states
+            .get(&share.channel_id)
+            .ok_or(MiningErrorCode::InvalidJobId)?;
+        match state.on_share(share.job_id) {
+            Ok(_) => Ok(()),
+            Err(Error::ShareForInactiveJob(_)) => Err(MiningErrorCode::StaleShare),
+            Err(_) => Err(MiningErrorCode::InvalidJobId),
+        }
+    }
+
+    /// Reject a share for the active standard job of the channel that do not meet the target the
+    /// channel was opened with, return true if the share meet the bitcoin target. The shares for
+    /// the other jobs can not be checked (eg the stale jobs in the grace period), they are left to
+    /// the upstream.
+    fn check_share_target(&self, share: &SubmitSharesStandard) -> Result<bool, MiningErrorCode> {
+        let state = match self.job_states.get(&share.channel_id) {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        let (merkle_root, prev_hash) = match (state.on_share(share.job_id), state.prev_hash()) {
+            (Ok(Some(merkle_root)), Some(prev_hash)) => (merkle_root, prev_hash),
+            _ => return Ok(false),
+        };
+        let hash = match standard_share_hash(
+            share,
+            prev_hash.prev_hash.inner_as_ref(),
+            merkle_root,
+            prev_hash.nbits,
+        ) {
+            Ok(hash) => hash,
+            Err(_) => return Ok(false),
+        };
+        match self.channel_target(share.channel_id) {
+            Some(target) if !target.is_met_by(hash) => Err(MiningErrorCode::DifficultyTooLow),
+            _ => Ok(Target::from_compact(prev_hash.nbits).is_met_by(hash)),
+        }
+    }
+
+    /// Target that the standard channel `channel_id` was opened with
+    fn channel_target(&self, channel_id: u32) -> Option<Target> {
+        let group_id = self.channel_id_to_group_id.get(&channel_id)?;
+        let channels = match &self.status {
+            DownstreamMiningNodeStatus::Initializing => return None,
+            DownstreamMiningNodeStatus::Paired((_, channels)) => channels.get(group_id)?,
+        };
+        channels.iter().find_map(|channel| match channel {
+            DownstreamChannel::Standard(c) if c.channel_id == channel_id => {
+                Some(c.target.clone().into())
+            }
+            _ => None,
+        })
+    }
+
+    /// Last telemetry report of every channel of the downstream
+    pub fn telemetry(&self) -> Vec<DeviceTelemetry> {
+        self.telemetry
+            .safe_lock(|t| t.values().copied().collect())
+            .unwrap()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        !self.sender.is_closed()
+    }
+
+    /// Close the connection with the downstream, used when the paired upstream is quarantined so
+    /// that the downstream can reconnect and be paired with another upstream
+    pub fn disconnect(&self) {
+        self.receiver.close();
+        self.sender.close();
+    }
+
+    /// Gracefully close the connection: send CloseChannel for every opened channel and Reconnect
+    /// so that the downstream reconnect to the proxy (or to another one) as soon as possible, then
+    /// flush the outbound queue and join the connection tasks.
+    pub async fn shutdown(self_mutex: Arc<Mutex<Self>>) {
+        let (channel_ids, sender, connection_handle) = self_mutex
+            .safe_lock(|self_| {
+                let channel_ids: Vec<u32> = match &self_.status {
+                    DownstreamMiningNodeStatus::Initializing => Vec::new(),
+                    DownstreamMiningNodeStatus::Paired((_, channels)) => channels
+                        .values()
+                        .flatten()
+                        .map(|channel| channel.channel_id())
+                        .collect(),
+                };
+                for channel_id in &channel_ids {
+                    self_.context.publish(ConnectionEvent::ChannelClosed {
+                        connection_id: self_.connection_id,
+                        channel_id: *channel_id,
+                    });
+                }
+                (
+                    channel_ids,
+                    self_.sender.clone(),
+                    self_.connection_handle.take(),
+                )
+            })
+            .unwrap();
+        let mut messages: Vec<Mining> = channel_ids
+            .into_iter()
+            .map(|channel_id| {
+                Mining::CloseChannel(CloseChannel {
+                    channel_id,
+                    // Is safe to unwrap a reason code shorter than 32 bytes
+                    reason_code: "proxy-shutdown".to_string().try_into().unwrap(),
+                })
+            })
+            .collect();
+        // Empty host and port 0 mean: reconnect to the present host and port
+        messages.push(Mining::Reconnect(Reconnect {
+            new_host: String::new().try_into().unwrap(),
+            new_port: 0,
+        }));
+        for message in messages {
+            let frame: StdFrame = MiningDeviceMessages::Mining(message).try_into().unwrap();
+            // Downstream could be already disconnected
+            if sender.send(frame.into()).await.is_err() {
+                break;
+            }
+        }
+        if let Some(connection_handle) = connection_handle {
+            connection_handle.shutdown(&sender).await;
         }
     }
 
     /// Send a message downstream
     pub async fn send(
+        self_mutex: Arc<Mutex<Self>>,
+        mut sv2_frame: StdFrame,
+    ) -> Result<(), SendError<StdFrame>> {
+        self_mutex
+            .safe_lock(|self_| self_.observe_sent(&mut sv2_frame))
+            .unwrap();
+        Self::write(self_mutex, sv2_frame).await
+    }
+
+    /// Check a frame sent to the downstream with the guard of the connection, the frames relayed
+    /// as received from the upstream are parsed
+    fn observe_sent(&self, frame: &mut StdFrame) {
+        if let Some(message) = frame.message() {
+            self.conformance
+                .safe_lock(|g| g.observe_sent(message))
+                .unwrap();
+            return;
+        }
+        // Is fine to unwrap, a Sv2Frame always has an header
+        let header = frame.get_header().unwrap();
+        if header.ext_type_without_channel_msg() != 0 {
+            return;
+        }
+        if let Ok(message) = MiningDeviceMessages::try_from((header.msg_type(), frame.payload())) {
+            self.conformance
+                .safe_lock(|g| g.observe_sent(&message))
+                .unwrap();
+        }
+    }
+
+    /// Like `send` but the frame is not checked by the guard
+    async fn write(
         self_mutex: Arc<Mutex<Self>>,
         sv2_frame: StdFrame,
     ) -> Result<(), SendError<StdFrame>> {
         let either_frame = sv2_frame.into();
         let sender = self_mutex.safe_lock(|self_| self_.sender.clone()).unwrap();
-        match sender.send(either_frame).await {
-            Ok(_) => Ok(()),
-            Err(_) => {
-                todo!("172")
-            }
-        }
+        // Fail only if the connection is closed, the receive loop of the node publish the
+        // disconnection. Is fine to unwrap the frame has been built from a StdFrame
+        sender
+            .send(either_frame)
+            .await
+            .map_err(|e| SendError(e.0.try_into().unwrap()))
     }
 }
 
@@ -210,7 +1238,23 @@ impl
     > for DownstreamMiningNode
 {
     fn get_channel_type(&self) -> SupportedChannelTypes {
-        SupportedChannelTypes::Group
+        // An header only downstream can not receive the extended jobs, the merkle root of its jobs
+        // is computed by the proxy whatever are the configured channel types
+        if self.is_header_only() {
+            return SupportedChannelTypes::Standard;
+        }
+        match self.context.downstream_channel_types() {
+            Some(types) => types,
+            None => SupportedChannelTypes::Group,
+        }
+    }
+
+    fn get_extensions(&self) -> Option<Arc<Mutex<Extensions>>> {
+        Some(self.extensions.clone())
+    }
+
+    fn get_conformance_guard(&self) -> Option<Arc<Mutex<ConformanceGuard>>> {
+        Some(self.conformance.clone())
     }
 
     fn is_work_selection_enabled(&self) -> bool {
@@ -219,17 +1263,46 @@ impl
 
     fn handle_open_standard_mining_channel(
         &mut self,
-        _: OpenStandardMiningChannel,
+        m: OpenStandardMiningChannel,
         up: Option<Arc<Mutex<UpstreamMiningNode>>>,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
-        Ok(SendTo::RelaySameMessage(up.unwrap()))
+        let up = up.unwrap();
+        // The request id has already been replaced with the upstream one, the declared hash rate
+        // is saved with the original request id to choose the channel target on success
+        let upstream_request_id = m.get_request_id_as_u32();
+        // The upstream validate the identity, the proxy only use it to report per worker stats
+        let worker = IdentityRules::default()
+            .parse_bytes(m.user_identity.inner_as_ref())
+            .ok();
+        if let Some(opened) = up
+            .safe_lock(|u| u.open_aggregated_channel(upstream_request_id))
+            .unwrap()
+        {
+            return self.on_aggregated_channel(up, m.nominal_hash_rate, worker, opened);
+        }
+        if let Some(request_id) = up
+            .safe_lock(|u| u.downstream_request_id(upstream_request_id))
+            .unwrap()
+        {
+            UpstreamMiningNode::wait_response(up.clone(), upstream_request_id, request_id);
+            self.requested_upstreams.insert(request_id, up.clone());
+            self.requested_hash_rates
+                .insert(request_id, m.nominal_hash_rate);
+            if let Some(worker) = worker {
+                self.requested_workers.insert(request_id, worker);
+            }
+        }
+        Ok(SendTo::RelaySameMessage(up))
     }
 
     fn handle_open_extended_mining_channel(
         &mut self,
-        _: OpenExtendedMiningChannel,
+        m: OpenExtendedMiningChannel,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
-        todo!()
+        // The proxy do not relay extended channels yet
+        Ok(SendTo::Respond(Mining::OpenMiningChannelError(
+            open_mining_channel_error(m.request_id, MiningErrorCode::UnsupportedChannelType),
+        )))
     }
 
     fn handle_update_channel(
@@ -243,35 +1316,29 @@ impl
         &mut self,
         m: SubmitSharesStandard,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
-        println!("{:?}", m);
-        match self.channel_id_to_group_id.get(&m.channel_id) {
-            Some(group_id) => match crate::upstream_from_job_id(m.job_id) {
-                Some(remote) => {
-                    remote.safe_lock(|r| {
-                        match r.channel_id_to_job_dispatcher.get(group_id) {
-                            Some(JobDispatcher::Group(dispatcher)) => {
-                                match dispatcher.on_submit_shares(m) {
-                                    roles_logic_sv2::job_dispatcher::SendSharesResponse::Valid(m) => {
-                                        // This could just relay same message and change the
-                                        // job_id as we do for request_ids
-                                        let message = Mining::SubmitSharesStandard(m);
-                                        Ok(SendTo::RelayNewMessage(remote.clone(),message))
-                                    },
-                                    roles_logic_sv2::job_dispatcher::SendSharesResponse::Invalid(m) => {
-                                        let message = Mining::SubmitSharesError(m);
-                                        Ok(SendTo::Respond(message))
-                                    }
-                                }
-                            },
-                            Some(_) => todo!(),
-                            None => todo!(),
-                        }
-                    }).unwrap()
-                }
-                None => todo!(),
-            },
-            None => todo!(),
+        let channel_id = m.channel_id;
+        if let Some(stats) = self.share_stats.get_mut(&channel_id) {
+            stats.submitted += 1;
+        }
+        let action = match self.share_rates.get_mut(&channel_id) {
+            Some(monitor) => monitor.on_share(Instant::now()),
+            None => ShareRateAction::Accept,
+        };
+        let result = match action {
+            // The channel is going to be closed, its shares are no more relayed
+            ShareRateAction::Close => Ok(SendTo::Respond(Mining::SubmitSharesError(share_error(
+                &m,
+                MiningErrorCode::ShareRateExceeded,
+            )))),
+            _ => self.relay_share_standard(m),
+        };
+        if action != ShareRateAction::Accept {
+            self.share_rate_actions.push((channel_id, action));
+        }
+        if let Ok(SendTo::Respond(message)) = &result {
+            self.on_share_result(message);
         }
+        result
     }
 
     fn handle_submit_shares_extended(
@@ -294,66 +1361,201 @@ impl
         MiningProxyRoutingLogic<Self, UpstreamMiningNode, ProxyRemoteSelector>,
     > for DownstreamMiningNode
 {
+    fn get_supported_versions(&self) -> (u16, u16) {
+        (
+            self.context.min_supported_version(),
+            self.context.max_supported_version(),
+        )
+    }
+
+    fn get_remote_address(&self) -> Option<SocketAddr> {
+        self.remote_address
+    }
+
     fn handle_setup_connection(
         &mut self,
-        _: SetupConnection,
+        m: SetupConnection,
         result: Option<Result<(CommonDownstreamData, SetupConnectionSuccess), Error>>,
     ) -> Result<roles_logic_sv2::handlers::common::SendTo, Error> {
-        let (data, message) = result.unwrap().unwrap();
-        self.status.pair(data);
-        Ok(SendToCommon::RelayNewMessage(
-            Arc::new(Mutex::new(())),
-            message.try_into().unwrap(),
-        ))
+        // The proxy always pass its routing logic so result is always Some
+        match result.unwrap() {
+            Ok((data, message)) => {
+                self.status.pair(data);
+                Ok(SendToCommon::RelayNewMessage(
+                    Arc::new(Mutex::new(())),
+                    message.try_into().unwrap(),
+                ))
+            }
+            Err(e) => {
+                println!("Downstream setup connection refused: {}", e);
+                Ok(SendToCommon::Respond(CommonMessages::SetupConnectionError(
+                    setup_connection_error(&e, m.flags),
+                )))
+            }
+        }
     }
 }
 
-use async_std::{net::TcpListener, prelude::*};
-use network_helpers::PlainConnection;
-use std::net::SocketAddr;
+use super::{proxy_protocol, workers::Workers};
+use async_std::{
+    net::{TcpListener, TcpStream},
+    prelude::*,
+};
+use network_helpers::{ConnectionHandle, PlainConnection};
+use std::{net::SocketAddr, time::Duration};
 
-pub async fn listen_for_downstream_mining(address: SocketAddr) {
-    let listner = TcpListener::bind(address).await.unwrap();
+/// Max time that a downstream has to send the PROXY header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The upstream is reconnected by `UpstreamMiningNode::send`, if it can not be reconnected the
+/// message is dropped
+fn on_upstream_send<T>(result: Result<(), SendError<T>>) {
+    if result.is_err() {
+        println!("Downstream error: upstream not reachable, message dropped");
+    }
+}
+
+/// Accept downstream connections, every accepted downstream is added to `downstreams` so that it
+/// can be gracefully closed when the proxy shutdown
+pub async fn listen_for_downstream_mining(
+    listner: TcpListener,
+    downstreams: Arc<Mutex<Vec<Arc<Mutex<DownstreamMiningNode>>>>>,
+    context: ProxyContext,
+) {
     let mut incoming = listner.incoming();
+    let mut workers = context.listener_workers().map(|(workers, sharding)| {
+        Workers::spawn(workers, sharding, downstreams.clone(), context.clone())
+    });
 
     while let Some(stream) = incoming.next().await {
         let stream = stream.unwrap();
-        let (receiver, sender): (Receiver<EitherFrame>, Sender<EitherFrame>) =
-            PlainConnection::new(stream, 10).await;
-        let node = DownstreamMiningNode::new(receiver, sender);
-
-        task::spawn(async move {
-            let mut incoming: StdFrame = node.receiver.recv().await.unwrap().try_into().unwrap();
-            let message_type = incoming.get_header().unwrap().msg_type();
-            let payload = incoming.payload();
-            let routing_logic = crate::get_common_routing_logic();
-            let node = Arc::new(Mutex::new(node));
-
-            // Call handle_setup_connection or fail
-            match DownstreamMiningNode::handle_message_common(
-                node.clone(),
-                message_type,
-                payload,
-                routing_logic,
-            ) {
-                Ok(SendToCommon::RelayNewMessage(_, message)) => {
-                    let message = match message {
-                        roles_logic_sv2::parsers::CommonMessages::SetupConnectionSuccess(m) => m,
-                        _ => panic!(),
-                    };
-                    DownstreamMiningNode::start(node, message).await
+        // The PROXY header is read in its own task so that a slow downstream do not block the
+        // listener
+        match &mut workers {
+            Some(workers) => workers.dispatch(stream).await,
+            None => {
+                task::spawn(on_downstream_connection(
+                    stream,
+                    downstreams.clone(),
+                    context.clone(),
+                ));
+            }
+        }
+    }
+}
+
+/// Serve a downstream connection until it is closed
+pub(crate) async fn on_downstream_connection(
+    mut stream: TcpStream,
+    downstreams: Arc<Mutex<Vec<Arc<Mutex<DownstreamMiningNode>>>>>,
+    context: ProxyContext,
+) {
+    let connection_id = context.next_connection_id();
+    context.publish(ConnectionEvent::Accepted {
+        connection_id,
+        remote_address: stream.peer_addr().ok(),
+    });
+    let remote_address = match context.proxy_protocol() {
+        true => {
+            match async_std::io::timeout(
+                PROXY_HEADER_TIMEOUT,
+                proxy_protocol::read_header(&mut stream),
+            )
+            .await
+            {
+                Ok(address) => address,
+                // Without a valid header the address of the downstream is unknown, drop it
+                Err(_) => {
+                    context.publish(ConnectionEvent::HandshakeFailed { connection_id });
+                    let _ = stream.shutdown(async_std::net::Shutdown::Both);
+                    return;
                 }
-                _ => panic!(),
             }
-        });
+        }
+        false => stream.peer_addr().ok(),
+    };
+    let (receiver, sender, connection_handle): (
+        Receiver<EitherFrame>,
+        Sender<EitherFrame>,
+        ConnectionHandle,
+    ) = PlainConnection::new_with_handle(stream, 10).await;
+    let mut node = DownstreamMiningNode::new(receiver, sender, connection_handle, context);
+    node.remote_address = remote_address;
+    node.connection_id = connection_id;
+    // A downstream that reconnects get another epoch, so the shares for the jobs of the previous
+    // connection are rejected as stale
+    node.job_id_mapper = JobIdMapper::with_epoch(connection_id as u8);
+    let receiver = node.receiver.clone();
+    let node = Arc::new(Mutex::new(node));
+    downstreams
+        .safe_lock(|downstreams| {
+            // Forget the downstreams that are already disconnected
+            downstreams.retain(|d| d.safe_lock(|d| d.is_connected()).unwrap());
+            downstreams.push(node.clone());
+        })
+        .unwrap();
+
+    let mut incoming: StdFrame = match receiver.recv().await.map(|m| m.try_into()) {
+        Ok(Ok(incoming)) => incoming,
+        // Closed before SetupConnection or not an sv2 frame
+        _ => {
+            node.safe_lock(|n| {
+                n.context
+                    .publish(ConnectionEvent::HandshakeFailed { connection_id });
+                n.disconnect();
+            })
+            .unwrap();
+            return;
+        }
+    };
+    let header = incoming.get_header().unwrap();
+    let payload = incoming.payload();
+    let routing_logic = node
+        .safe_lock(|node| node.context.get_common_routing_logic())
+        .unwrap();
+
+    // Call handle_setup_connection or fail
+    match DownstreamMiningNode::handle_message_common(node.clone(), header, payload, routing_logic)
+    {
+        Ok(SendToCommon::RelayNewMessage(
+            _,
+            roles_logic_sv2::parsers::CommonMessages::SetupConnectionSuccess(message),
+        )) => {
+            node.safe_lock(|n| n.context.publish(ConnectionEvent::Paired { connection_id }))
+                .unwrap();
+            DownstreamMiningNode::start(node, message).await
+        }
+        // Downstream requested an unsupported protocol version or it can not be routed to any
+        // upstream, send the error and drop the connection
+        Ok(SendToCommon::Respond(
+            message @ roles_logic_sv2::parsers::CommonMessages::SetupConnectionError(_),
+        )) => {
+            let frame: StdFrame = MiningDeviceMessages::Common(message).try_into().unwrap();
+            let _ = DownstreamMiningNode::send(node.clone(), frame).await;
+            node.safe_lock(|n| {
+                n.context
+                    .publish(ConnectionEvent::HandshakeFailed { connection_id });
+                n.disconnect();
+            })
+            .unwrap();
+        }
+        // Not a SetupConnection or malformed
+        _ => {
+            node.safe_lock(|n| {
+                n.context
+                    .publish(ConnectionEvent::HandshakeFailed { connection_id });
+                n.disconnect();
+            })
+            .unwrap();
+        }
     }
 }
 
 impl IsDownstream for DownstreamMiningNode {
     fn get_downstream_mining_data(&self) -> CommonDownstreamData {
-        match self.status {
+        match &self.status {
             DownstreamMiningNodeStatus::Initializing => panic!(),
-            DownstreamMiningNodeStatus::Paired((settings, _)) => settings,
+            DownstreamMiningNodeStatus::Paired((settings, _)) => settings.clone(),
         }
     }
 }
@@ -0,0 +1,60 @@
+//! Operator labels of the listener and of the upstreams.
+//!
+//! A farm spread over more sites run many proxies, the labels (name, region, tier) assigned by the
+//! operator in the configuration are reported with the statistics of the admin API, with every
+//! upstream and with the events published on `ProxyBuilder::labeled_events`, so that the
+//! dashboards can be sliced by label without keeping a map of addresses to sites.
+use roles_logic_sv2::events::ConnectionEvent;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Deserialize)]
+pub struct Labels {
+    pub name: Option<String>,
+    pub region: Option<String>,
+    pub tier: Option<String>,
+}
+
+impl Labels {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.region.is_none() && self.tier.is_none()
+    }
+
+    /// Labels as a JSON object, the labels that are not set are omitted
+    pub fn to_json(&self) -> Value {
+        let mut value = json!({});
+        let labels = [
+            ("name", &self.name),
+            ("region", &self.region),
+            ("tier", &self.tier),
+        ];
+        for (key, label) in labels.iter() {
+            if let Some(label) = label {
+                value[*key] = json!(label);
+            }
+        }
+        value
+    }
+}
+
+/// A `ConnectionEvent` with the labels of the listener that accepted the connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabeledEvent {
+    pub labels: Arc<Labels>,
+    pub event: ConnectionEvent,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn omits_the_labels_not_set() {
+        let labels: Labels = toml::from_str("name = \"proxy-1\"\ntier = \"edge\"").unwrap();
+        assert!(!labels.is_empty());
+        assert_eq!(labels.to_json(), json!({"name": "proxy-1", "tier": "edge"}));
+        assert!(Labels::default().is_empty());
+        assert_eq!(Labels::default().to_json(), json!({}));
+    }
+}
@@ -1,2 +1,19 @@
+//! Sv2 mining proxy, see `proxy::Proxy` to embed the proxy in another application.
+pub mod admin;
+pub mod broadcast;
 pub mod downstream_mining;
+pub mod labels;
+pub mod parked_requests;
+pub mod prev_hash_latency;
+pub mod proxy;
+pub mod proxy_context;
+pub mod proxy_protocol;
+pub mod share_rate;
+pub mod snapshot;
+pub mod upstream_dns;
+pub mod upstream_health;
 pub mod upstream_mining;
+pub mod upstream_mux;
+pub mod workers;
+
+pub use proxy::{Proxy, ProxyBuilder, ProxyHandle, ProxyStats};
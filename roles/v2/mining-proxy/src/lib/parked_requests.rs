@@ -0,0 +1,116 @@
+//! Channel requests of the downstreams received while their upstream is not available.
+//!
+//! When every upstream that can serve a downstream is quarantined or draining the proxy can not
+//! open the channels of the downstream. With `ProxyBuilder::park_channel_requests` the
+//! OpenStandardMiningChannel requests received in that state are parked instead of being rejected,
+//! and they are relayed as soon as the upstream come back (eg at the end of the quarantine). A
+//! request that is parked for more than `timeout` is answered with an OpenMiningChannelError
+//! (`upstream-not-ready`), as is a request received when `capacity` requests are already parked.
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParkLimits {
+    /// Max number of requests parked at the same time, for every proxy instance
+    pub capacity: usize,
+    /// Time after which a parked request is answered with an error
+    pub timeout: Duration,
+}
+
+/// Parked requests in arrival order
+#[derive(Debug)]
+pub struct ParkedRequests<T> {
+    limits: ParkLimits,
+    requests: VecDeque<(Instant, T)>,
+}
+
+impl<T> ParkedRequests<T> {
+    pub fn new(limits: ParkLimits) -> Self {
+        Self {
+            limits,
+            requests: VecDeque::new(),
+        }
+    }
+
+    pub fn limits(&self) -> ParkLimits {
+        self.limits
+    }
+
+    /// Park a request received at `now`, if there are already `capacity` parked requests the
+    /// request is given back
+    pub fn park(&mut self, request: T, now: Instant) -> Result<(), T> {
+        if self.requests.len() >= self.limits.capacity {
+            return Err(request);
+        }
+        self.requests.push_back((now, request));
+        Ok(())
+    }
+
+    /// Remove and return the requests that are parked for `timeout` or more
+    pub fn expired(&mut self, now: Instant) -> Vec<T> {
+        let mut expired = Vec::new();
+        while let Some((parked_at, _)) = self.requests.front() {
+            if now.saturating_duration_since(*parked_at) < self.limits.timeout {
+                break;
+            }
+            // Is fine to unwrap, the front is Some
+            expired.push(self.requests.pop_front().unwrap().1);
+        }
+        expired
+    }
+
+    /// Remove and return the requests for which `release` return true, eg the requests of the
+    /// downstreams whose upstream is available again
+    pub fn release<F: FnMut(&T) -> bool>(&mut self, mut release: F) -> Vec<T> {
+        let mut released = Vec::new();
+        let mut parked = VecDeque::with_capacity(self.requests.len());
+        for (parked_at, request) in self.requests.drain(..) {
+            if release(&request) {
+                released.push(request);
+            } else {
+                parked.push_back((parked_at, request));
+            }
+        }
+        self.requests = parked;
+        released
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parks_requests_until_released_or_expired() {
+        let limits = ParkLimits {
+            capacity: 2,
+            timeout: Duration::from_secs(10),
+        };
+        let mut parked = ParkedRequests::new(limits);
+        let start = Instant::now();
+
+        assert_eq!(parked.park(1, start), Ok(()));
+        assert_eq!(parked.park(2, start + Duration::from_secs(5)), Ok(()));
+        // Full
+        assert_eq!(parked.park(3, start + Duration::from_secs(5)), Err(3));
+
+        assert!(parked.expired(start + Duration::from_secs(9)).is_empty());
+        assert_eq!(parked.expired(start + Duration::from_secs(10)), vec![1]);
+        assert_eq!(parked.len(), 1);
+
+        assert_eq!(parked.park(4, start + Duration::from_secs(11)), Ok(()));
+        assert_eq!(parked.release(|request| *request == 4), vec![4]);
+        assert_eq!(parked.release(|_| true), vec![2]);
+        assert!(parked.is_empty());
+    }
+}
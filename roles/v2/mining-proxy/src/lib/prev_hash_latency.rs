@@ -0,0 +1,127 @@
+//! Propagation latency of the prev hashes.
+//!
+//! Until a downstream get the SetNewPrevHash of a new block it mine on the old one, so the time
+//! that the proxy take to relay a prev hash is hash rate wasted. For every SetNewPrevHash received
+//! from an upstream the proxy measure the time from when the frame is read to when the prev hash
+//! relayed to each downstream has been queued on the downstream connection (where it is written
+//! ahead of the other frames, see `network_helpers::CONTROL_MESSAGE_TYPES`). The latencies are
+//! collected in a `LatencyHistogram`, reported by `ProxyStats`.
+use std::time::Duration;
+
+/// Upper bounds of the buckets of the histogram in microseconds, the last bucket is unbounded
+pub const BUCKETS_US: [u64; 12] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LatencyHistogram {
+    counts: [u64; 13],
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros();
+        let bucket = BUCKETS_US
+            .iter()
+            .position(|bound| micros <= *bound as u128)
+            .unwrap_or(BUCKETS_US.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// Number of the recorded latencies
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum / self.count as u32)
+        }
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.max)
+        }
+    }
+
+    /// Upper bound of the bucket that contain the `quantile` (between 0 and 1) of the latencies,
+    /// the max latency if it is in the last bucket
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(match BUCKETS_US.get(bucket) {
+                    Some(bound) => Duration::from_micros(*bound).min(self.max),
+                    None => self.max,
+                });
+            }
+        }
+        Some(self.max)
+    }
+
+    /// (upper bound, latencies) of every bucket, the upper bound of the last one is None
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| {
+                (
+                    BUCKETS_US.get(bucket).map(|b| Duration::from_micros(*b)),
+                    *count,
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_quantiles_of_the_latencies() {
+        let mut histogram = LatencyHistogram::new();
+        assert_eq!(histogram.quantile(0.5), None);
+        assert_eq!(histogram.mean(), None);
+
+        for _ in 0..90 {
+            histogram.record(Duration::from_micros(300));
+        }
+        for _ in 0..9 {
+            histogram.record(Duration::from_millis(20));
+        }
+        histogram.record(Duration::from_secs(3));
+
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(500)));
+        assert_eq!(histogram.quantile(0.9), Some(Duration::from_micros(500)));
+        assert_eq!(histogram.quantile(0.95), Some(Duration::from_millis(25)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_secs(3)));
+        assert_eq!(histogram.max(), Some(Duration::from_secs(3)));
+        assert_eq!(
+            histogram.buckets()[2],
+            (Some(Duration::from_micros(500)), 90)
+        );
+        assert_eq!(histogram.buckets()[12], (None, 1));
+    }
+}
@@ -0,0 +1,689 @@
+//! Embedding API for the mining proxy.
+//!
+//! ```ignore
+//! let proxy = Proxy::builder()
+//!     .listen(listen_address)
+//!     .upstream(pool_address, pool_authority_pub_key)
+//!     .spawn()
+//!     .await?;
+//! println!("{:?}", proxy.stats());
+//! proxy.shutdown().await?;
+//! ```
+//!
+//! Every spawned proxy has its own `ProxyContext` so more than one proxy can run in the same
+//! process.
+use super::{
+    admin::{self, ProxyControl, ReloadHook},
+    downstream_mining::{listen_for_downstream_mining, DownstreamMiningNode},
+    labels::{LabeledEvent, Labels},
+    parked_requests::ParkLimits,
+    prev_hash_latency::LatencyHistogram,
+    proxy_context::ProxyContext,
+    share_rate::ShareRateLimit,
+    snapshot::{ChannelSnapshot, ProxySnapshot, UpstreamSnapshot},
+    upstream_dns::{ResolvedEndpoint, UpstreamHostname},
+    upstream_mining::{scan, UpstreamMiningNode, UpstreamTransport},
+    upstream_mux::SharedUpstreams,
+    workers::Sharding,
+};
+use async_channel::Sender;
+use async_std::{net::TcpListener, task};
+use codec_sv2::PaddingPolicy;
+use network_helpers::rustls::ClientConfig;
+use roles_logic_sv2::{
+    common_properties::IsUpstream,
+    events::ConnectionEvent,
+    handlers::mining::SupportedChannelTypes,
+    telemetry::DeviceTelemetry,
+    utils::{ChannelTargetPolicy, Id, Mutex, TargetConflictPolicy},
+};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+#[derive(Debug)]
+pub enum Error {
+    /// `ProxyBuilder::listen` has not been called
+    MissingListenAddress,
+    /// `ProxyBuilder::upstream` has not been called
+    MissingUpstreams,
+    /// Min supported version is greater than max supported version
+    InvalidVersions(u16, u16),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::MissingListenAddress => write!(f, "Missing listen address"),
+            Error::MissingUpstreams => write!(f, "At least one upstream is needed"),
+            Error::InvalidVersions(min, max) => {
+                write!(f, "Invalid supported versions: min {} max {}", min, max)
+            }
+            Error::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+pub struct Proxy;
+
+impl Proxy {
+    pub fn builder() -> ProxyBuilder {
+        ProxyBuilder::default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyBuilder {
+    listen_address: Option<SocketAddr>,
+    upstreams: Vec<(
+        SocketAddr,
+        UpstreamTransport,
+        Labels,
+        Option<UpstreamHostname>,
+        TargetConflictPolicy,
+    )>,
+    min_supported_version: u16,
+    max_supported_version: u16,
+    snapshot_path: Option<PathBuf>,
+    target_policy: Option<ChannelTargetPolicy>,
+    aggregated_hash_rate: Option<f32>,
+    share_batch_window: Option<Duration>,
+    stale_grace_period: Option<Duration>,
+    job_history: Option<usize>,
+    request_timeout: Option<Duration>,
+    share_rate_limit: Option<ShareRateLimit>,
+    resume_sessions: bool,
+    park_limits: Option<ParkLimits>,
+    proxy_protocol: bool,
+    listener_workers: Option<(usize, Sharding)>,
+    downstream_channel_types: Option<SupportedChannelTypes>,
+    shared_upstreams: Option<SharedUpstreams>,
+    events: Option<Sender<ConnectionEvent>>,
+    labeled_events: Option<Sender<LabeledEvent>>,
+    labels: Labels,
+    admin_address: Option<SocketAddr>,
+    reload: Option<ReloadHook>,
+}
+
+impl Default for ProxyBuilder {
+    fn default() -> Self {
+        Self {
+            listen_address: None,
+            upstreams: Vec::new(),
+            min_supported_version: const_sv2::SV2_MIN_PROTOCOL_VERSION,
+            max_supported_version: const_sv2::SV2_MAX_PROTOCOL_VERSION,
+            snapshot_path: None,
+            target_policy: None,
+            aggregated_hash_rate: None,
+            share_batch_window: None,
+            stale_grace_period: None,
+            job_history: None,
+            request_timeout: None,
+            share_rate_limit: None,
+            resume_sessions: false,
+            park_limits: None,
+            proxy_protocol: false,
+            listener_workers: None,
+            downstream_channel_types: None,
+            shared_upstreams: None,
+            events: None,
+            labeled_events: None,
+            labels: Labels::default(),
+            admin_address: None,
+            reload: None,
+        }
+    }
+}
+
+impl ProxyBuilder {
+    /// Address where the proxy accept downstream connections
+    pub fn listen(mut self, address: SocketAddr) -> Self {
+        self.listen_address = Some(address);
+        self
+    }
+
+    /// Add an upstream, `authority_public_key` is used to authenticate the upstream during the
+    /// noise handshake
+    pub fn upstream(mut self, address: SocketAddr, authority_public_key: [u8; 32]) -> Self {
+        self.upstreams.push((
+            address,
+            UpstreamTransport::Noise(authority_public_key, PaddingPolicy::None),
+            Labels::default(),
+            None,
+            TargetConflictPolicy::HonorStricter,
+        ));
+        self
+    }
+
+    /// Add an upstream reached through TLS instead of noise, for networks that only allow TLS
+    /// egress. `config` is used to authenticate `server_name`, see
+    /// `network_helpers::tls_client_config`
+    pub fn upstream_tls(
+        mut self,
+        address: SocketAddr,
+        server_name: String,
+        config: Arc<ClientConfig>,
+    ) -> Self {
+        let transport = UpstreamTransport::Tls {
+            server_name,
+            config,
+        };
+        self.upstreams.push((
+            address,
+            transport,
+            Labels::default(),
+            None,
+            TargetConflictPolicy::HonorStricter,
+        ));
+        self
+    }
+
+    /// Operator labels of the last upstream added with `upstream` or `upstream_tls`, reported by
+    /// the admin API, see `labels`
+    pub fn upstream_labels(mut self, labels: Labels) -> Self {
+        if let Some((_, _, upstream_labels, _, _)) = self.upstreams.last_mut() {
+            *upstream_labels = labels;
+        }
+        self
+    }
+
+    /// Padding of the frames sent to the last upstream added with `upstream`, the upstream must
+    /// support the padding frames, see `codec_sv2::PaddingPolicy`. The TLS upstreams are not
+    /// padded
+    pub fn upstream_padding(mut self, padding: PaddingPolicy) -> Self {
+        if let Some((_, UpstreamTransport::Noise(_, upstream_padding), _, _, _)) =
+            self.upstreams.last_mut()
+        {
+            *upstream_padding = padding;
+        }
+        self
+    }
+
+    /// Hostname that the address of the last upstream added with `upstream` or `upstream_tls` has
+    /// been resolved from. The hostname is resolved again every `hostname.interval` and the
+    /// upstream reconnect to the new records following `hostname.pinning`, see `upstream_dns`
+    pub fn upstream_hostname(mut self, hostname: UpstreamHostname) -> Self {
+        if let Some((_, _, _, upstream_hostname, _)) = self.upstreams.last_mut() {
+            *upstream_hostname = Some(hostname);
+        }
+        self
+    }
+
+    /// How the last upstream added with `upstream` or `upstream_tls` reconcile its SetTarget with
+    /// the local target of the channel (target policy, share rate limit, admin), by default the
+    /// stricter target is kept, see `TargetConflictPolicy`
+    pub fn upstream_target_conflict(mut self, policy: TargetConflictPolicy) -> Self {
+        if let Some((_, _, _, _, target_conflict)) = self.upstreams.last_mut() {
+            *target_conflict = policy;
+        }
+        self
+    }
+
+    /// Operator labels of the listener, reported by `ProxyStats`, by the admin API and with the
+    /// events published on `labeled_events`, see `labels`
+    pub fn labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Protocol versions accepted from the downstreams, default to the versions supported by this
+    /// implementation
+    pub fn supported_versions(mut self, min: u16, max: u16) -> Self {
+        self.min_supported_version = min;
+        self.max_supported_version = max;
+        self
+    }
+
+    /// Restore the proxy state from `path` on spawn (if the file exists) and save it on shutdown,
+    /// see `snapshot`
+    pub fn snapshot<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.snapshot_path = Some(path.into());
+        self
+    }
+
+    /// Initial target and minimum difficulty of the downstream channels, by default the proxy relay
+    /// the target set by the upstream
+    pub fn channel_target_policy(mut self, policy: ChannelTargetPolicy) -> Self {
+        self.target_policy = Some(policy);
+        self
+    }
+
+    /// Serve the standard channels of the downstreams from one extended channel per upstream,
+    /// opened with `nominal_hash_rate`, instead of opening a standard channel upstream for each
+    /// one of them
+    pub fn aggregate_standard_channels(mut self, nominal_hash_rate: f32) -> Self {
+        self.aggregated_hash_rate = Some(nominal_hash_rate);
+        self
+    }
+
+    /// Relay the shares to the upstreams in batches, every batch contain the shares received within
+    /// `window`. The upstream can acknowledge a whole batch with a single SubmitSharesSuccess (the
+    /// success acknowledge every share up to `last_sequence_number`), that is relayed to the
+    /// downstream channels that submitted the shares.
+    pub fn batch_shares(mut self, window: Duration) -> Self {
+        self.share_batch_window = Some(window);
+        self
+    }
+
+    /// Keep accepting the shares for the jobs replaced by a SetNewPrevHash for `grace_period`
+    /// after the prev hash, the downstreams that are still mining them do not get spurious
+    /// rejections. A zero grace period reject them as soon as the prev hash change.
+    pub fn stale_share_grace_period(mut self, grace_period: Duration) -> Self {
+        self.stale_grace_period = Some(grace_period);
+        self
+    }
+
+    /// Keep only the last `capacity` jobs of every group channel, the shares for the older jobs
+    /// are rejected. Bound the memory used by the long lived channels that get a lot of job
+    /// updates between two prev hashes, 0 means no limit.
+    pub fn job_history(mut self, capacity: usize) -> Self {
+        self.job_history = Some(capacity);
+        self
+    }
+
+    /// Resume the aggregated standard channels when an upstream reconnects: the proxy opens an
+    /// equivalent extended channel on the new connection and keeps serving the downstream channels
+    /// from it, the downstreams get the jobs of the new channel instead of being reset. Only
+    /// useful with `aggregate_standard_channels`.
+    pub fn resume_sessions(mut self) -> Self {
+        self.resume_sessions = true;
+        self
+    }
+
+    /// Park the channel requests received while the upstream of the downstream is quarantined or
+    /// draining and relay them when it come back, instead of rejecting them. The downstreams of a
+    /// quarantined upstream without a failover are kept connected and their channels are reopened
+    /// when the upstream come back. At most `capacity` requests are parked and every request
+    /// wait at most `timeout`, see `parked_requests`.
+    pub fn park_channel_requests(mut self, capacity: usize, timeout: Duration) -> Self {
+        self.park_limits = Some(ParkLimits { capacity, timeout });
+        self
+    }
+
+    /// Time given to the upstreams to answer the open channel requests relayed by the proxy, the
+    /// downstream get an OpenMiningChannelError with the `request-timeout` code if the upstream
+    /// do not answer in time. An upstream that do not answer the SetupConnection of the proxy in
+    /// time is never paired. Default to `pending_requests::DEFAULT_REQUEST_TIMEOUT`.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Protect the upstreams from the downstream standard channels that submit more than
+    /// `limit.max_shares_per_minute` shares per minute: their difficulty is raised with a
+    /// SetTarget, if they keep flooding after `limit.max_escalations` raises they are closed with
+    /// the `share-rate-exceeded` code, see `share_rate`
+    pub fn share_rate_limit(mut self, limit: ShareRateLimit) -> Self {
+        self.share_rate_limit = Some(limit);
+        self
+    }
+
+    /// The proxy is behind a load balancer that send a PROXY protocol header (v1 or v2) at the
+    /// start of every downstream connection, the header contain the address of the downstream.
+    /// Connections without a valid header are dropped.
+    pub fn proxy_protocol(mut self) -> Self {
+        self.proxy_protocol = true;
+        self
+    }
+
+    /// Serve the downstream connections on `workers` threads (eg one per core), every worker run
+    /// its own executor and get the connections chosen by `sharding`, see `workers`. Useful when a
+    /// single executor can not keep up with the messages of many thousands of downstreams.
+    pub fn listener_workers(mut self, workers: usize, sharding: Sharding) -> Self {
+        self.listener_workers = Some((workers, sharding));
+        self
+    }
+
+    /// Channel types that the downstreams can open, by default group channels. The header only
+    /// downstreams (eg mining devices) always open standard channels, they only receive
+    /// NewMiningJob whose merkle root is computed by the proxy.
+    /// Extended channels are not relayed yet, their requests get an OpenMiningChannelError with
+    /// the `unsupported-channel-type` code.
+    pub fn downstream_channel_types(mut self, types: SupportedChannelTypes) -> Self {
+        self.downstream_channel_types = Some(types);
+        self
+    }
+
+    /// Share the upstream connections with the other proxies spawned with (a clone of)
+    /// `shared_upstreams`, the proxies open one logical session each on a single connection per
+    /// upstream address, see `upstream_mux`
+    pub fn shared_upstreams(mut self, shared_upstreams: SharedUpstreams) -> Self {
+        self.shared_upstreams = Some(shared_upstreams);
+        self
+    }
+
+    /// Publish the lifecycle events of the downstream connections on `events`, see
+    /// `roles_logic_sv2::events`. Events are dropped when `events` is full.
+    pub fn events(mut self, events: Sender<ConnectionEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Like `events` but every event carry the labels of the listener, useful when the events of
+    /// more proxies are collected by the same observer
+    pub fn labeled_events(mut self, events: Sender<LabeledEvent>) -> Self {
+        self.labeled_events = Some(events);
+        self
+    }
+
+    /// Serve the control API on `address`, see `admin`. The API is not authenticated, `address`
+    /// should be a loopback address.
+    pub fn admin(mut self, address: SocketAddr) -> Self {
+        self.admin_address = Some(address);
+        self
+    }
+
+    /// Called by the `reload_config` admin method, `hook` read the configuration again and apply
+    /// it with `ProxyControl`
+    pub fn on_reload(
+        mut self,
+        hook: impl Fn(&ProxyControl) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.reload = Some(ReloadHook::new(hook));
+        self
+    }
+
+    /// Connect to the upstreams, bind the listen address and start accepting downstreams
+    pub async fn spawn(self) -> Result<ProxyHandle, Error> {
+        let listen_address = self.listen_address.ok_or(Error::MissingListenAddress)?;
+        if self.upstreams.is_empty() {
+            return Err(Error::MissingUpstreams);
+        }
+        if self.min_supported_version > self.max_supported_version {
+            return Err(Error::InvalidVersions(
+                self.min_supported_version,
+                self.max_supported_version,
+            ));
+        }
+        let snapshot = match &self.snapshot_path {
+            Some(path) => ProxySnapshot::load(path)?,
+            None => None,
+        };
+        let listener = TcpListener::bind(listen_address).await?;
+        let admin_listener = match self.admin_address {
+            Some(address) => Some(TcpListener::bind(address).await?),
+            None => None,
+        };
+
+        let mut context = ProxyContext::new(self.min_supported_version, self.max_supported_version);
+        context.set_target_policy(self.target_policy);
+        context.set_aggregated_hash_rate(self.aggregated_hash_rate);
+        context.set_share_batch_window(self.share_batch_window);
+        context.set_stale_grace_period(self.stale_grace_period);
+        context.set_job_history(self.job_history);
+        context.set_request_timeout(self.request_timeout);
+        context.set_share_rate_limit(self.share_rate_limit);
+        context.set_resume_sessions(self.resume_sessions);
+        context.set_park_limits(self.park_limits);
+        context.set_proxy_protocol(self.proxy_protocol);
+        context.set_listener_workers(self.listener_workers);
+        context.set_downstream_channel_types(self.downstream_channel_types);
+        context.set_shared_upstreams(self.shared_upstreams);
+        context.set_events(self.events);
+        context.set_labeled_events(self.labeled_events);
+        context.set_labels(self.labels);
+        let job_ids = Arc::new(Mutex::new(Id::new()));
+        let upstreams: Vec<Arc<Mutex<UpstreamMiningNode>>> = self
+            .upstreams
+            .into_iter()
+            .enumerate()
+            .map(
+                |(index, (address, transport, labels, hostname, target_conflict))| {
+                    let mut upstream = UpstreamMiningNode::new(
+                        index as u32,
+                        address,
+                        transport,
+                        job_ids.clone(),
+                        context.clone(),
+                    );
+                    upstream.set_labels(labels);
+                    upstream.set_target_conflict(target_conflict);
+                    let has_hostname = hostname.is_some();
+                    if let Some(hostname) = hostname {
+                        upstream.set_endpoint(ResolvedEndpoint::new(hostname, vec![address]));
+                    }
+                    let upstream = Arc::new(Mutex::new(upstream));
+                    if has_hostname {
+                        task::spawn(UpstreamMiningNode::refresh_endpoint(Arc::downgrade(
+                            &upstream,
+                        )));
+                    }
+                    upstream
+                },
+            )
+            .collect();
+        let mut restored_channels = Vec::new();
+        if let Some(snapshot) = snapshot {
+            job_ids
+                .safe_lock(|ids| *ids = Id::with_state(snapshot.job_ids))
+                .unwrap();
+            context.restore(snapshot.downstream_ids);
+            for up in snapshot.upstreams {
+                if let Some(upstream) = upstreams.get(up.id as usize) {
+                    upstream
+                        .safe_lock(|u| {
+                            u.restore_request_id_mapper(up.request_ids, up.next_request_id)
+                        })
+                        .unwrap();
+                }
+            }
+            restored_channels = snapshot.channels;
+        }
+        context.set_upstreams(upstreams.clone());
+        scan(upstreams).await;
+
+        let downstreams = Arc::new(Mutex::new(Vec::new()));
+        let listener = task::spawn(listen_for_downstream_mining(
+            listener,
+            downstreams.clone(),
+            context.clone(),
+        ));
+        let admin = match admin_listener {
+            Some(admin_listener) => {
+                let control = ProxyControl::new(downstreams.clone(), context.clone());
+                Some(task::spawn(admin::serve(
+                    admin_listener,
+                    control,
+                    self.reload,
+                )))
+            }
+            None => None,
+        };
+        Ok(ProxyHandle {
+            listener,
+            admin,
+            downstreams,
+            context,
+            job_ids,
+            snapshot_path: self.snapshot_path,
+            restored_channels,
+        })
+    }
+}
+
+/// Statistics of a running proxy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyStats {
+    /// Operator labels of the listener
+    pub labels: Labels,
+    /// Connected downstreams
+    pub downstreams: usize,
+    /// Channels opened by the connected downstreams
+    pub channels: usize,
+    pub upstreams: usize,
+    pub quarantined_upstreams: usize,
+    /// Jobs evicted from the job histories of the upstreams, see `ProxyBuilder::job_history`
+    pub evicted_jobs: u64,
+    /// Upstream SetTarget relayed with a target different from the upstream one, see
+    /// `ProxyBuilder::upstream_target_conflict`
+    pub target_conflicts: u64,
+    /// Channel requests waiting for an upstream, see `ProxyBuilder::park_channel_requests`
+    pub parked_channel_requests: usize,
+    /// Propagation latency of the prev hashes, see `prev_hash_latency`
+    pub prev_hash_latency: LatencyHistogram,
+}
+
+/// Handle to a running proxy returned by `ProxyBuilder::spawn`
+#[derive(Debug)]
+pub struct ProxyHandle {
+    listener: task::JoinHandle<()>,
+    admin: Option<task::JoinHandle<()>>,
+    downstreams: Arc<Mutex<Vec<Arc<Mutex<DownstreamMiningNode>>>>>,
+    context: ProxyContext,
+    job_ids: Arc<Mutex<Id>>,
+    snapshot_path: Option<PathBuf>,
+    restored_channels: Vec<ChannelSnapshot>,
+}
+
+impl ProxyHandle {
+    pub fn stats(&self) -> ProxyStats {
+        self.control().stats()
+    }
+
+    /// Runtime control of the proxy, the same operations of the admin API
+    pub fn control(&self) -> ProxyControl {
+        ProxyControl::new(self.downstreams.clone(), self.context.clone())
+    }
+
+    /// Address of every connected downstream, for the downstreams connected through a load
+    /// balancer is the address in the PROXY header
+    pub fn downstream_addresses(&self) -> Vec<SocketAddr> {
+        self.downstreams
+            .safe_lock(|downstreams| {
+                downstreams
+                    .iter()
+                    .filter_map(|d| {
+                        d.safe_lock(|d| match d.is_connected() {
+                            true => d.remote_address(),
+                            false => None,
+                        })
+                        .unwrap()
+                    })
+                    .collect()
+            })
+            .unwrap()
+    }
+
+    /// Last telemetry report of every channel of the connected downstreams that negotiated the
+    /// telemetry extension
+    pub fn telemetry(&self) -> Vec<DeviceTelemetry> {
+        self.downstreams
+            .safe_lock(|downstreams| {
+                downstreams
+                    .iter()
+                    .flat_map(|d| {
+                        d.safe_lock(|d| {
+                            if d.is_connected() {
+                                d.telemetry()
+                            } else {
+                                Vec::new()
+                            }
+                        })
+                        .unwrap()
+                    })
+                    .collect()
+            })
+            .unwrap()
+    }
+
+    /// Standard channels that were open when the restored snapshot was saved
+    pub fn restored_channels(&self) -> &[ChannelSnapshot] {
+        &self.restored_channels
+    }
+
+    /// Current state of the proxy, see `snapshot`
+    pub fn snapshot(&self) -> ProxySnapshot {
+        let job_ids = self.job_ids.safe_lock(|ids| ids.state()).unwrap();
+        let downstream_ids = self.context.state();
+        let upstreams = self
+            .context
+            .upstreams()
+            .iter()
+            .map(|upstream| {
+                upstream
+                    .safe_lock(|u| {
+                        let (request_ids, next_request_id) = u.request_id_mapper_state();
+                        UpstreamSnapshot {
+                            id: u.get_id(),
+                            next_request_id,
+                            request_ids,
+                        }
+                    })
+                    .unwrap()
+            })
+            .collect();
+        let channels = self
+            .downstreams
+            .safe_lock(|downstreams| {
+                downstreams
+                    .iter()
+                    .flat_map(|d| d.safe_lock(|d| d.standard_channels()).unwrap())
+                    .map(ChannelSnapshot::from)
+                    .collect()
+            })
+            .unwrap();
+        ProxySnapshot {
+            job_ids,
+            downstream_ids,
+            upstreams,
+            channels,
+        }
+    }
+
+    /// Graceful shutdown:
+    /// 1. save the proxy state if a snapshot path is configured and stop accepting new downstreams
+    /// 2. send CloseChannel and Reconnect to every connected downstream, flush the outbound queues
+    ///    and join the connection tasks
+    /// 3. close the connections with the upstreams
+    pub async fn shutdown(self) -> Result<(), Error> {
+        let saved = match &self.snapshot_path {
+            Some(path) => self.snapshot().save(path),
+            None => Ok(()),
+        };
+        self.listener.cancel().await;
+        if let Some(admin) = self.admin {
+            admin.cancel().await;
+        }
+
+        let downstreams = self.downstreams.safe_lock(std::mem::take).unwrap();
+        let closing: Vec<task::JoinHandle<()>> = downstreams
+            .into_iter()
+            .map(|downstream| task::spawn(DownstreamMiningNode::shutdown(downstream)))
+            .collect();
+        for downstream in closing {
+            downstream.await;
+        }
+
+        for upstream in self.context.upstreams() {
+            UpstreamMiningNode::shutdown(upstream).await;
+        }
+        Ok(saved?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[async_std::test]
+    async fn spawn_checks_the_builder() {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+        let res = Proxy::builder().upstream(address, [0; 32]).spawn().await;
+        assert!(matches!(res, Err(Error::MissingListenAddress)));
+        let res = Proxy::builder().listen(address).spawn().await;
+        assert!(matches!(res, Err(Error::MissingUpstreams)));
+        let res = Proxy::builder()
+            .listen(address)
+            .upstream(address, [0; 32])
+            .supported_versions(3, 2)
+            .spawn()
+            .await;
+        assert!(matches!(res, Err(Error::InvalidVersions(3, 2))));
+    }
+}
@@ -0,0 +1,449 @@
+//! State shared by every downstream and upstream of a proxy instance.
+//!
+//! Each proxy instance own a `ProxyContext` that is cloned into every `DownstreamMiningNode` and
+//! `UpstreamMiningNode`, so more than one independent proxy can run in the same process.
+use super::{
+    downstream_mining::{DownstreamMiningNode, StdFrame},
+    labels::{LabeledEvent, Labels},
+    parked_requests::{ParkLimits, ParkedRequests},
+    prev_hash_latency::LatencyHistogram,
+    share_rate::ShareRateLimit,
+    upstream_mining::{ProxyRemoteSelector, UpstreamMiningNode},
+    upstream_mux::SharedUpstreams,
+    workers::Sharding,
+};
+use async_channel::Sender;
+use roles_logic_sv2::{
+    common_properties::CommonDownstreamData,
+    errors::Error,
+    events::ConnectionEvent,
+    handlers::mining::SupportedChannelTypes,
+    routing_logic::{CommonRoutingLogic, MiningProxyRoutingLogic, MiningRoutingLogic},
+    selectors::GeneralMiningSelector,
+    utils::{ChannelTargetPolicy, Id, Mutex, Target},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Number of published events kept for `ProxyContext::recent_events`
+pub const RECENT_EVENTS: usize = 100;
+
+/// OpenStandardMiningChannel parked by a downstream: (downstream, request frame, request id), see
+/// `parked_requests`
+pub type ParkedRequest = (Arc<Mutex<DownstreamMiningNode>>, StdFrame, u32);
+
+pub type RLogic =
+    MiningProxyRoutingLogic<DownstreamMiningNode, UpstreamMiningNode, ProxyRemoteSelector>;
+
+/// Panic when we are looking one of the below mutex would force the proxy to go down as every
+/// part of the program depend on them.
+/// SAFTEY note: we use shared mutable memory instead of a dedicated task to change the mutable
+/// state and communicate with the other parts of the program via messages cause it is impossible
+/// for a task to panic while is using one of the two below Mutex.
+#[derive(Debug, Clone)]
+pub struct ProxyContext {
+    routing_logic: Arc<Mutex<RLogic>>,
+    min_supported_version: u16,
+    max_supported_version: u16,
+    /// Shared so that it can be changed while the proxy is running, see `admin`
+    target_policy: Arc<Mutex<Option<ChannelTargetPolicy>>>,
+    /// If Some the standard channels are aggregated in one upstream extended channel opened with
+    /// this nominal hash rate, see `roles_logic_sv2::channel_aggregator`
+    aggregated_hash_rate: Option<f32>,
+    /// If Some the shares relayed upstream are batched in windows of this duration
+    share_batch_window: Option<Duration>,
+    /// If Some the shares for the jobs replaced by a prev hash are accepted for this long, see
+    /// `roles_logic_sv2::job_dispatcher::StaleJobs`
+    stale_grace_period: Option<Duration>,
+    /// If Some the number of jobs kept for every group channel, see
+    /// `roles_logic_sv2::job_dispatcher::JobHistory`
+    job_history: Option<usize>,
+    /// If Some the time given to the upstreams to answer a request, see
+    /// `roles_logic_sv2::pending_requests`
+    request_timeout: Option<Duration>,
+    /// If Some the standard channels that submit too many shares get a higher difficulty and then
+    /// are closed, see `share_rate`
+    share_rate_limit: Option<ShareRateLimit>,
+    /// If true the aggregated standard channels survive a reconnection with the upstream, see
+    /// `ChannelAggregator::resume`
+    resume_sessions: bool,
+    /// If Some the channel requests received while the upstream of the downstream is not
+    /// available are parked, see `parked_requests`
+    parked_requests: Option<Arc<Mutex<ParkedRequests<ParkedRequest>>>>,
+    /// If true every downstream connection start with a PROXY protocol header
+    proxy_protocol: bool,
+    /// If Some the downstream connections are served by this many worker threads, see `workers`
+    listener_workers: Option<(usize, Sharding)>,
+    /// If Some the channel types accepted from every downstream, otherwise they depend on the
+    /// SetupConnection flags of the downstream
+    downstream_channel_types: Option<SupportedChannelTypes>,
+    /// If Some the upstream connections are shared with the other proxies of the process, see
+    /// `upstream_mux`
+    shared_upstreams: Option<SharedUpstreams>,
+    /// Observer of the downstream connections, see `ProxyContext::publish`
+    events: Option<Sender<ConnectionEvent>>,
+    /// Like `events` but every event carry `labels`
+    labeled_events: Option<Sender<LabeledEvent>>,
+    /// Operator labels of the listener, see `labels`
+    labels: Arc<Labels>,
+    /// Last `RECENT_EVENTS` published events, kept also if there is no observer
+    recent_events: Arc<Mutex<VecDeque<(SystemTime, ConnectionEvent)>>>,
+    connection_ids: Arc<Mutex<Id>>,
+    /// Time taken to relay the SetNewPrevHash of the upstreams, see `prev_hash_latency`
+    prev_hash_latency: Arc<Mutex<LatencyHistogram>>,
+}
+
+impl ProxyContext {
+    /// Create a context without upstreams, upstreams are added with `ProxyContext::set_upstreams`
+    pub fn new(min_supported_version: u16, max_supported_version: u16) -> Self {
+        let routing_logic = MiningProxyRoutingLogic {
+            upstream_selector: GeneralMiningSelector::new(Vec::new()),
+            downstream_id_generator: Id::new(),
+            downstream_to_upstream_map: HashMap::new(),
+        };
+        Self {
+            routing_logic: Arc::new(Mutex::new(routing_logic)),
+            min_supported_version,
+            max_supported_version,
+            target_policy: Arc::new(Mutex::new(None)),
+            aggregated_hash_rate: None,
+            share_batch_window: None,
+            stale_grace_period: None,
+            job_history: None,
+            request_timeout: None,
+            share_rate_limit: None,
+            resume_sessions: false,
+            parked_requests: None,
+            proxy_protocol: false,
+            listener_workers: None,
+            downstream_channel_types: None,
+            shared_upstreams: None,
+            events: None,
+            labeled_events: None,
+            labels: Arc::new(Labels::default()),
+            recent_events: Arc::new(Mutex::new(VecDeque::with_capacity(RECENT_EVENTS))),
+            connection_ids: Arc::new(Mutex::new(Id::new())),
+            prev_hash_latency: Arc::new(Mutex::new(LatencyHistogram::new())),
+        }
+    }
+
+    /// Publish the lifecycle events of the downstream connections on `events`
+    pub fn set_events(&mut self, events: Option<Sender<ConnectionEvent>>) {
+        self.events = events;
+    }
+
+    /// Publish the lifecycle events of the downstream connections with the labels of the listener
+    /// on `events`
+    pub fn set_labeled_events(&mut self, events: Option<Sender<LabeledEvent>>) {
+        self.labeled_events = events;
+    }
+
+    pub fn set_labels(&mut self, labels: Labels) {
+        self.labels = Arc::new(labels);
+    }
+
+    /// Operator labels of the listener
+    pub fn labels(&self) -> &Labels {
+        &self.labels
+    }
+
+    /// Publish `event` if there is an observer. The event is dropped if the channel is full, so
+    /// that a slow observer can not stall the proxy.
+    pub fn publish(&self, event: ConnectionEvent) {
+        self.recent_events
+            .safe_lock(|recent_events| {
+                if recent_events.len() == RECENT_EVENTS {
+                    recent_events.pop_front();
+                }
+                recent_events.push_back((SystemTime::now(), event));
+            })
+            .unwrap();
+        if let Some(events) = &self.events {
+            let _ = events.try_send(event);
+        }
+        if let Some(events) = &self.labeled_events {
+            let _ = events.try_send(LabeledEvent {
+                labels: self.labels.clone(),
+                event,
+            });
+        }
+    }
+
+    /// Last published events, the oldest first
+    pub fn recent_events(&self) -> Vec<(SystemTime, ConnectionEvent)> {
+        self.recent_events
+            .safe_lock(|recent_events| recent_events.iter().copied().collect())
+            .unwrap()
+    }
+
+    /// A SetNewPrevHash has been relayed to a downstream `latency` after that it was received
+    pub fn record_prev_hash_latency(&self, latency: Duration) {
+        self.prev_hash_latency
+            .safe_lock(|histogram| histogram.record(latency))
+            .unwrap();
+    }
+
+    pub fn prev_hash_latency(&self) -> LatencyHistogram {
+        self.prev_hash_latency
+            .safe_lock(|histogram| *histogram)
+            .unwrap()
+    }
+
+    /// Id of a new downstream connection, used in the `ConnectionEvent`s
+    pub fn next_connection_id(&self) -> u32 {
+        self.connection_ids.safe_lock(|ids| ids.next()).unwrap()
+    }
+
+    /// Read the address of the downstreams from the PROXY protocol header (v1 or v2) sent by the
+    /// load balancer in front of the proxy, see `proxy_protocol`
+    pub fn set_proxy_protocol(&mut self, enabled: bool) {
+        self.proxy_protocol = enabled;
+    }
+
+    pub fn proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    /// Serve the downstream connections on `workers` threads chosen with `Sharding`, if None they
+    /// are served by the async-std executor
+    pub fn set_listener_workers(&mut self, workers: Option<(usize, Sharding)>) {
+        self.listener_workers = workers;
+    }
+
+    pub fn listener_workers(&self) -> Option<(usize, Sharding)> {
+        self.listener_workers
+    }
+
+    /// Open the upstream connections as sessions of the connections in `shared_upstreams`
+    pub fn set_shared_upstreams(&mut self, shared_upstreams: Option<SharedUpstreams>) {
+        self.shared_upstreams = shared_upstreams;
+    }
+
+    pub fn shared_upstreams(&self) -> Option<SharedUpstreams> {
+        self.shared_upstreams.clone()
+    }
+
+    /// Batch the shares relayed to an upstream: the shares received within `window` are sent
+    /// together when the window expire. If None every share is relayed as soon as is received.
+    pub fn set_share_batch_window(&mut self, window: Option<Duration>) {
+        self.share_batch_window = window;
+    }
+
+    pub fn share_batch_window(&self) -> Option<Duration> {
+        self.share_batch_window
+    }
+
+    /// Grace period of the stale shares, if None the default of `StaleJobs` is used
+    pub fn set_stale_grace_period(&mut self, grace_period: Option<Duration>) {
+        self.stale_grace_period = grace_period;
+    }
+
+    pub fn stale_grace_period(&self) -> Option<Duration> {
+        self.stale_grace_period
+    }
+
+    /// Capacity of the job history of the group channels, if None the default of `JobHistory` is
+    /// used
+    pub fn set_job_history(&mut self, capacity: Option<usize>) {
+        self.job_history = capacity;
+    }
+
+    pub fn job_history(&self) -> Option<usize> {
+        self.job_history
+    }
+
+    /// Timeout of the requests relayed upstream, if None the default of `PendingRequests` is used
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.request_timeout = timeout;
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.request_timeout
+    }
+
+    /// Share rate limit of the downstream standard channels, if None the share rate is not checked
+    pub fn set_share_rate_limit(&mut self, limit: Option<ShareRateLimit>) {
+        self.share_rate_limit = limit;
+    }
+
+    pub fn share_rate_limit(&self) -> Option<ShareRateLimit> {
+        self.share_rate_limit
+    }
+
+    /// Channel types accepted from the downstreams, if None header only downstreams can open
+    /// standard channels and the others group channels
+    pub fn set_downstream_channel_types(&mut self, types: Option<SupportedChannelTypes>) {
+        self.downstream_channel_types = types;
+    }
+
+    pub fn downstream_channel_types(&self) -> Option<SupportedChannelTypes> {
+        self.downstream_channel_types
+    }
+
+    /// Keep the aggregated standard channels of the downstreams when the upstream reconnects, if
+    /// false they are dropped with the extended channel
+    pub fn set_resume_sessions(&mut self, enabled: bool) {
+        self.resume_sessions = enabled;
+    }
+
+    pub fn resume_sessions(&self) -> bool {
+        self.resume_sessions
+    }
+
+    /// Park the channel requests received while no upstream can serve the downstream, if None
+    /// they are rejected
+    pub fn set_park_limits(&mut self, limits: Option<ParkLimits>) {
+        self.parked_requests =
+            limits.map(|limits| Arc::new(Mutex::new(ParkedRequests::new(limits))));
+    }
+
+    pub fn park_limits(&self) -> Option<ParkLimits> {
+        self.parked_requests
+            .as_ref()
+            .map(|parked| parked.safe_lock(|parked| parked.limits()).unwrap())
+    }
+
+    /// Park a channel request, return false if parking is disabled or if the queue is full
+    pub fn park_channel_request(&self, request: ParkedRequest) -> bool {
+        match &self.parked_requests {
+            Some(parked) => parked
+                .safe_lock(|parked| parked.park(request, Instant::now()).is_ok())
+                .unwrap(),
+            None => false,
+        }
+    }
+
+    /// Remove the parked requests that are waiting since more than the timeout
+    pub fn expired_channel_requests(&self) -> Vec<ParkedRequest> {
+        match &self.parked_requests {
+            Some(parked) => parked
+                .safe_lock(|parked| parked.expired(Instant::now()))
+                .unwrap(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Remove the parked requests for which `release` return true
+    pub fn release_channel_requests<F: FnMut(&ParkedRequest) -> bool>(
+        &self,
+        release: F,
+    ) -> Vec<ParkedRequest> {
+        match &self.parked_requests {
+            Some(parked) => parked.safe_lock(|parked| parked.release(release)).unwrap(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Number of parked channel requests
+    pub fn parked_channel_requests(&self) -> usize {
+        match &self.parked_requests {
+            Some(parked) => parked.safe_lock(|parked| parked.len()).unwrap(),
+            None => 0,
+        }
+    }
+
+    /// Aggregate the standard channels of the downstreams in one extended channel per upstream,
+    /// opened with `nominal_hash_rate`. If None every standard channel is relayed upstream.
+    pub fn set_aggregated_hash_rate(&mut self, nominal_hash_rate: Option<f32>) {
+        self.aggregated_hash_rate = nominal_hash_rate;
+    }
+
+    pub fn aggregated_hash_rate(&self) -> Option<f32> {
+        self.aggregated_hash_rate
+    }
+
+    /// Policy used to choose the target of the downstream channels, if None the target set by the
+    /// upstream is relayed as is. The channels already opened keep their target.
+    pub fn set_target_policy(&self, policy: Option<ChannelTargetPolicy>) {
+        self.target_policy
+            .safe_lock(|target_policy| *target_policy = policy)
+            .unwrap();
+    }
+
+    /// Target of a new downstream channel. The proxy can only make the target set by the upstream
+    /// harder, otherwise the upstream would reject the shares.
+    pub fn channel_target(&self, upstream_target: Target, nominal_hash_rate: f32) -> Target {
+        let policy = self.target_policy.safe_lock(|policy| *policy).unwrap();
+        match policy {
+            Some(policy) => upstream_target.min(policy.target_for(nominal_hash_rate)),
+            None => upstream_target,
+        }
+    }
+
+    /// Replace the upstreams used to pair new downstreams. Downstreams already paired keep using
+    /// the upstream that they are paired with.
+    pub fn set_upstreams(&self, upstreams: Vec<Arc<Mutex<UpstreamMiningNode>>>) {
+        self.routing_logic
+            .safe_lock(|r_logic| r_logic.upstream_selector = GeneralMiningSelector::new(upstreams))
+            .unwrap();
+    }
+
+    pub fn upstreams(&self) -> Vec<Arc<Mutex<UpstreamMiningNode>>> {
+        self.routing_logic
+            .safe_lock(|r_logic| r_logic.upstream_selector.upstreams.clone())
+            .unwrap()
+    }
+
+    /// Upstreams that the router paired with a downstream on setup connection
+    pub fn paired_upstreams(
+        &self,
+        downstream_data: &CommonDownstreamData,
+    ) -> Vec<Arc<Mutex<UpstreamMiningNode>>> {
+        self.routing_logic
+            .safe_lock(|r_logic| {
+                r_logic
+                    .downstream_to_upstream_map
+                    .get(downstream_data)
+                    .cloned()
+                    .unwrap_or_default()
+            })
+            .unwrap()
+    }
+
+    pub fn min_supported_version(&self) -> u16 {
+        self.min_supported_version
+    }
+
+    pub fn max_supported_version(&self) -> u16 {
+        self.max_supported_version
+    }
+
+    pub fn get_routing_logic(
+        &self,
+    ) -> MiningRoutingLogic<DownstreamMiningNode, UpstreamMiningNode, ProxyRemoteSelector, RLogic>
+    {
+        MiningRoutingLogic::Proxy(self.routing_logic.clone())
+    }
+
+    pub fn get_common_routing_logic(&self) -> CommonRoutingLogic<RLogic> {
+        CommonRoutingLogic::Proxy(self.routing_logic.clone())
+    }
+
+    /// Last generated downstream id
+    pub fn state(&self) -> u32 {
+        self.routing_logic
+            .safe_lock(|r_logic| r_logic.downstream_id_generator.state())
+            .unwrap()
+    }
+
+    /// Restore a state returned by `ProxyContext::state`
+    pub fn restore(&self, downstream_ids: u32) {
+        self.routing_logic
+            .safe_lock(|r_logic| r_logic.downstream_id_generator = Id::with_state(downstream_ids))
+            .unwrap();
+    }
+
+    /// Remap the downstreams paired with a failed upstream to another upstream, return the new
+    /// upstream or an error if no other upstream is available
+    pub fn failover_upstream(
+        &self,
+        upstream: &Arc<Mutex<UpstreamMiningNode>>,
+    ) -> Result<Arc<Mutex<UpstreamMiningNode>>, Error> {
+        self.routing_logic
+            .safe_lock(|r_logic| r_logic.on_upstream_failover(upstream))
+            .unwrap()
+    }
+}
@@ -0,0 +1,144 @@
+//! HAProxy PROXY protocol (v1 and v2) on the downstream connections.
+//!
+//! When the proxy is behind an L4 load balancer the address of an accepted connection is the
+//! address of the load balancer. If the load balancer use the PROXY protocol the first bytes of
+//! every connection are a header with the address of the actual client, the header is read and
+//! removed before the sv2 frames. When the PROXY protocol is enabled the header is mandatory.
+//! See https://www.haproxy.org/download/2.6/doc/proxy-protocol.txt
+use async_std::{io::ReadExt, net::TcpStream};
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Max length of a v1 header, CRLF included
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+/// Signature, version and command, family and protocol, length of the addresses
+const V2_HEADER_LEN: usize = 16;
+
+fn invalid(reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid PROXY header: {}", reason),
+    )
+}
+
+/// Read the PROXY header at the start of `stream`, return the address of the client or None if
+/// the load balancer do not know it (v1 UNKNOWN, v2 LOCAL or a not inet family)
+pub async fn read_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>> {
+    let mut prefix = [0_u8; V1_PREFIX.len()];
+    stream.read_exact(&mut prefix).await?;
+    if prefix == V1_PREFIX {
+        let mut line = prefix.to_vec();
+        let mut byte = [0_u8];
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LEN {
+                return Err(invalid("v1 header too long"));
+            }
+            stream.read_exact(&mut byte).await?;
+            line.push(byte[0]);
+        }
+        parse_v1(&line)
+    } else if prefix == V2_SIGNATURE[..V1_PREFIX.len()] {
+        let mut header = [0_u8; V2_HEADER_LEN];
+        header[..V1_PREFIX.len()].copy_from_slice(&prefix);
+        stream.read_exact(&mut header[V1_PREFIX.len()..]).await?;
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        let mut addresses = vec![0; len];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(&header, &addresses)
+    } else {
+        Err(invalid("missing header"))
+    }
+}
+
+/// Parse a v1 header: `PROXY TCP4|TCP6|UNKNOWN src dst src_port dst_port\r\n`
+pub fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or_else(|| invalid("v1 header is not a line"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => (),
+        _ => return Err(invalid("v1 unknown protocol")),
+    }
+    let ip: IpAddr = fields[2]
+        .parse()
+        .map_err(|_| invalid("v1 invalid address"))?;
+    let port: u16 = fields[4].parse().map_err(|_| invalid("v1 invalid port"))?;
+    match (fields[1], ip) {
+        ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => Ok(Some(SocketAddr::new(ip, port))),
+        _ => Err(invalid("v1 address do not match the protocol")),
+    }
+}
+
+/// Parse a v2 header, `addresses` are the bytes that follow the fixed size header
+pub fn parse_v2(header: &[u8; V2_HEADER_LEN], addresses: &[u8]) -> Result<Option<SocketAddr>> {
+    if header[..12] != V2_SIGNATURE {
+        return Err(invalid("v2 wrong signature"));
+    }
+    if header[12] >> 4 != 2 {
+        return Err(invalid("v2 unsupported version"));
+    }
+    match header[12] & 0x0F {
+        // LOCAL: connection opened by the load balancer itself (health checks)
+        0 => return Ok(None),
+        1 => (),
+        _ => return Err(invalid("v2 unknown command")),
+    }
+    match header[13] >> 4 {
+        // AF_INET: src addr, dst addr, src port, dst port
+        1 if addresses.len() >= 12 => {
+            let mut ip = [0_u8; 4];
+            ip.copy_from_slice(&addresses[..4]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let mut ip = [0_u8; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        1 | 2 => Err(invalid("v2 addresses too short")),
+        // AF_UNSPEC and AF_UNIX do not have an ip address
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_v1_and_v2_headers() {
+        let expected: SocketAddr = "192.168.0.1:56324".parse().unwrap();
+        let v1 = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n";
+        assert_eq!(parse_v1(v1).unwrap(), Some(expected));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(parse_v1(b"PROXY TCP6 192.168.0.1 192.168.0.11 56324 443\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443").is_err());
+
+        let mut header = [0_u8; V2_HEADER_LEN];
+        header[..12].copy_from_slice(&V2_SIGNATURE);
+        header[12] = 0x21;
+        header[13] = 0x11;
+        header[14..].copy_from_slice(&12_u16.to_be_bytes());
+        let addresses = [192, 168, 0, 1, 192, 168, 0, 11, 0xDC, 0x04, 0x01, 0xBB];
+        assert_eq!(parse_v2(&header, &addresses).unwrap(), Some(expected));
+        assert!(parse_v2(&header, &addresses[..8]).is_err());
+
+        // LOCAL command
+        header[12] = 0x20;
+        assert_eq!(parse_v2(&header, &addresses).unwrap(), None);
+        header[0] = 0;
+        assert!(parse_v2(&header, &addresses).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+//! Share flood protection of the downstream channels.
+//!
+//! The target of a channel imply how many shares the channel should submit (eg with the
+//! `ChannelTargetPolicy::shares_per_minute` of the proxy). A device that submit far more shares
+//! than that (a broken firmware that ignore SetTarget, or an abusive client) waste the upstream
+//! link, so every standard channel count the shares submitted in the last `RATE_WINDOW`. When the
+//! count exceeds `ShareRateLimit::max_shares_per_minute` the difficulty of the channel is raised
+//! with a SetTarget, if the channel keep flooding after `ShareRateLimit::max_escalations` raises
+//! it is closed with the `share-rate-exceeded` error code.
+use roles_logic_sv2::utils::Target;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Shares older than that are not counted
+pub const RATE_WINDOW: Duration = Duration::from_secs(60);
+/// The difficulty is raised at least by this factor
+pub const MIN_DIFFICULTY_FACTOR: f64 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShareRateLimit {
+    /// Shares in `RATE_WINDOW` above which a channel is flooding, it should be some times the
+    /// share rate implied by the targets of the channels
+    pub max_shares_per_minute: u32,
+    /// Times that the difficulty is raised before closing the channel
+    pub max_escalations: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShareRateAction {
+    /// The channel is within the limit
+    Accept,
+    /// The channel is flooding, send a SetTarget with the new target
+    RaiseDifficulty(Target),
+    /// The channel is still flooding after `ShareRateLimit::max_escalations` raises, close it
+    Close,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShareRateMonitor {
+    limit: ShareRateLimit,
+    /// When the shares of the window have been submitted
+    shares: VecDeque<Instant>,
+    target: Target,
+    escalations: u32,
+}
+
+impl ShareRateMonitor {
+    /// Monitor of a channel opened with `target`
+    pub fn new(limit: ShareRateLimit, target: Target) -> Self {
+        Self {
+            limit,
+            shares: VecDeque::new(),
+            target,
+            escalations: 0,
+        }
+    }
+
+    /// Current target of the channel, harder than the opening one if the difficulty was raised
+    pub fn target(&self) -> Target {
+        self.target
+    }
+
+    /// Replace the target of the channel, eg after an upstream SetTarget
+    pub fn set_target(&mut self, target: Target) {
+        self.target = target;
+    }
+
+    /// Count a share submitted at `now` and return what the proxy should do with the channel
+    pub fn on_share(&mut self, now: Instant) -> ShareRateAction {
+        self.shares.push_back(now);
+        while let Some(time) = self.shares.front() {
+            if now.saturating_duration_since(*time) > RATE_WINDOW {
+                self.shares.pop_front();
+            } else {
+                break;
+            }
+        }
+        let max_shares = self.limit.max_shares_per_minute.max(1);
+        if self.shares.len() <= max_shares as usize {
+            return ShareRateAction::Accept;
+        }
+        if self.escalations >= self.limit.max_escalations {
+            return ShareRateAction::Close;
+        }
+        self.escalations += 1;
+        let factor = (self.shares.len() as f64 / max_shares as f64).max(MIN_DIFFICULTY_FACTOR);
+        self.target = self
+            .target
+            .min(Target::from_difficulty(self.target.difficulty() * factor));
+        // The shares of the old target must not count against the new one
+        self.shares.clear();
+        ShareRateAction::RaiseDifficulty(self.target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raises_difficulty_and_then_closes_flooding_channels() {
+        let limit = ShareRateLimit {
+            max_shares_per_minute: 10,
+            max_escalations: 1,
+        };
+        let target = Target::from_difficulty(1.0);
+        let mut monitor = ShareRateMonitor::new(limit, target);
+        let start = Instant::now();
+
+        // Shares spread over more than the window are never too many
+        for second in 0..30 {
+            let now = start + Duration::from_secs(second * 10);
+            assert_eq!(monitor.on_share(now), ShareRateAction::Accept);
+        }
+
+        let now = start + Duration::from_secs(600);
+        for _ in 0..10 {
+            assert_eq!(monitor.on_share(now), ShareRateAction::Accept);
+        }
+        let raised = match monitor.on_share(now) {
+            ShareRateAction::RaiseDifficulty(raised) => raised,
+            action => panic!("Unexpected {:?}", action),
+        };
+        assert!(raised < target);
+        assert!(raised.difficulty() >= 2.0 * 0.99);
+        assert_eq!(monitor.target(), raised);
+
+        for _ in 0..10 {
+            assert_eq!(monitor.on_share(now), ShareRateAction::Accept);
+        }
+        assert_eq!(monitor.on_share(now), ShareRateAction::Close);
+    }
+}
@@ -0,0 +1,109 @@
+//! Snapshot of the proxy state.
+//!
+//! When a snapshot path is configured the proxy save its state on shutdown and restore it on
+//! startup. Restored are the job and downstream id generators and the request id mappings of every
+//! upstream, so that after a fast restart the proxy do not hand out ids that the downstreams still
+//! use.
+//! The standard channels (channel id, group id, target and extranonce prefix) are saved too and
+//! made available to the embedding application with `ProxyHandle::restored_channels`.
+//!
+//! Upstreams are matched by id, that is the position of the upstream in the builder (or config).
+//! The snapshot is saved as toml.
+use roles_logic_sv2::common_properties::StandardChannel;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxySnapshot {
+    /// Last generated job id
+    pub job_ids: u32,
+    /// Last generated downstream id
+    pub downstream_ids: u32,
+    pub upstreams: Vec<UpstreamSnapshot>,
+    pub channels: Vec<ChannelSnapshot>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpstreamSnapshot {
+    pub id: u32,
+    pub next_request_id: u32,
+    /// upstream request id -> downstream request id
+    pub request_ids: Vec<(u32, u32)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelSnapshot {
+    pub channel_id: u32,
+    pub group_id: u32,
+    pub target: Vec<u8>,
+    pub extranonce_prefix: Vec<u8>,
+}
+
+impl From<StandardChannel> for ChannelSnapshot {
+    fn from(channel: StandardChannel) -> Self {
+        let target: binary_sv2::U256 = channel.target.into();
+        Self {
+            channel_id: channel.channel_id,
+            group_id: channel.group_id,
+            target: target.inner_as_ref().to_vec(),
+            extranonce_prefix: channel.extranonce.into(),
+        }
+    }
+}
+
+impl ProxySnapshot {
+    /// Load a snapshot, return None if the file do not exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Self>, Error> {
+        let snapshot = match std::fs::read_to_string(path) {
+            Ok(snapshot) => snapshot,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        toml::from_str(&snapshot)
+            .map(Some)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+
+    /// Save the snapshot, the file is written only when the whole snapshot is on disk so that a
+    /// crash during the save do not corrupt the previous snapshot
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let snapshot =
+            toml::to_string(self).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, snapshot)?;
+        std::fs::rename(tmp_path, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("proxy-snapshot-{}.toml", std::process::id()));
+        assert_eq!(ProxySnapshot::load(&path).unwrap(), None);
+        let snapshot = ProxySnapshot {
+            job_ids: 42,
+            downstream_ids: 3,
+            upstreams: vec![UpstreamSnapshot {
+                id: 1,
+                next_request_id: 2,
+                request_ids: vec![(1, 10)],
+            }],
+            channels: vec![ChannelSnapshot {
+                channel_id: 1,
+                group_id: 2,
+                target: vec![0xff; 32],
+                extranonce_prefix: vec![1; 32],
+            }],
+        };
+        snapshot.save(&path).unwrap();
+        assert_eq!(ProxySnapshot::load(&path).unwrap(), Some(snapshot));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+//! DNS re-resolution of the upstreams configured with an hostname.
+//!
+//! Pools behind anycast or GeoDNS change the records of their hostname over time, an upstream
+//! resolved only at startup keep reconnecting to an address that the pool may have retired. The
+//! hostname of an upstream added with `ProxyBuilder::upstream_hostname` is resolved again every
+//! `UpstreamHostname::interval`, and when the upstream reconnects the addresses are tried in the
+//! order given by `EndpointPinning`. A live connection is never closed because the records changed,
+//! the upstream move only when it reconnects.
+use async_std::net::ToSocketAddrs;
+use std::{io, net::SocketAddr, time::Duration};
+
+/// Interval of the re-resolution if not configured
+pub const DEFAULT_RESOLVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Which address an upstream reconnect to when the records of its hostname change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointPinning {
+    /// Reconnect to the current address first, even if the records no longer contain it. For
+    /// anycast addresses and for the pools that keep the state of the session on the endpoint
+    Sticky,
+    /// Reconnect to the addresses in the records, the current address is tried last if the
+    /// records no longer contain it. For GeoDNS
+    Migrate,
+}
+
+impl std::str::FromStr for EndpointPinning {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sticky" => Ok(EndpointPinning::Sticky),
+            "migrate" => Ok(EndpointPinning::Migrate),
+            _ => Err(format!("Unknown endpoint pinning {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamHostname {
+    pub host: String,
+    pub port: u16,
+    pub pinning: EndpointPinning,
+    pub interval: Duration,
+}
+
+impl UpstreamHostname {
+    pub fn new(host: String, port: u16) -> Self {
+        Self {
+            host,
+            port,
+            pinning: EndpointPinning::Sticky,
+            interval: DEFAULT_RESOLVE_INTERVAL,
+        }
+    }
+
+    /// Resolve the hostname
+    pub async fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        let addresses = (self.host.as_str(), self.port).to_socket_addrs().await?;
+        Ok(addresses.collect())
+    }
+}
+
+/// Hostname of an upstream and the addresses of its last resolution
+#[derive(Debug, Clone)]
+pub struct ResolvedEndpoint {
+    hostname: UpstreamHostname,
+    addresses: Vec<SocketAddr>,
+}
+
+impl ResolvedEndpoint {
+    pub fn new(hostname: UpstreamHostname, addresses: Vec<SocketAddr>) -> Self {
+        Self {
+            hostname,
+            addresses,
+        }
+    }
+
+    pub fn hostname(&self) -> &UpstreamHostname {
+        &self.hostname
+    }
+
+    pub fn addresses(&self) -> &[SocketAddr] {
+        &self.addresses
+    }
+
+    /// Replace the resolved addresses, return true if the records changed (the order is ignored).
+    /// An empty resolution is ignored so that a failing DNS server do not clear the addresses.
+    pub fn update(&mut self, addresses: Vec<SocketAddr>) -> bool {
+        if addresses.is_empty() {
+            return false;
+        }
+        let changed = addresses.len() != self.addresses.len()
+            || addresses.iter().any(|a| !self.addresses.contains(a));
+        self.addresses = addresses;
+        changed
+    }
+
+    /// Addresses to try in order when the upstream connected to `current` reconnect
+    pub fn candidates(&self, current: SocketAddr) -> Vec<SocketAddr> {
+        let others = self.addresses.iter().copied().filter(|a| *a != current);
+        match self.hostname.pinning {
+            EndpointPinning::Sticky => std::iter::once(current).chain(others).collect(),
+            EndpointPinning::Migrate if self.addresses.contains(&current) => {
+                std::iter::once(current).chain(others).collect()
+            }
+            EndpointPinning::Migrate => others.chain(std::iter::once(current)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_the_addresses_by_pinning() {
+        let old: SocketAddr = "10.0.0.1:34254".parse().unwrap();
+        let new: SocketAddr = "10.0.0.2:34254".parse().unwrap();
+        let mut hostname = UpstreamHostname::new("pool.example.com".to_string(), 34254);
+        let mut endpoint = ResolvedEndpoint::new(hostname.clone(), vec![old]);
+        assert_eq!(endpoint.candidates(old), vec![old]);
+
+        assert!(!endpoint.update(vec![]));
+        assert!(!endpoint.update(vec![old]));
+        assert!(endpoint.update(vec![new]));
+        assert_eq!(endpoint.candidates(old), vec![old, new]);
+
+        hostname.pinning = "migrate".parse().unwrap();
+        let mut endpoint = ResolvedEndpoint::new(hostname, vec![old, new]);
+        assert_eq!(endpoint.candidates(old), vec![old, new]);
+        assert!(endpoint.update(vec![new]));
+        assert_eq!(endpoint.candidates(old), vec![new, old]);
+        assert_eq!(endpoint.candidates(new), vec![new]);
+    }
+}
@@ -0,0 +1,229 @@
+//! Health of an upstream node.
+//!
+//! Every upstream keep the events (accepted shares, rejected shares, protocol errors and
+//! reconnections) of the last `HEALTH_WINDOW`. When the error rate of the window exceeds
+//! `MAX_ERROR_RATE` the upstream is quarantined: it is no more paired with new downstreams and the
+//! downstreams that are using it are disconnected so that they reconnect and get paired with an
+//! healthy upstream.
+//!
+//! When the quarantine expires the upstream is probed, if the probe succeeds the upstream is put on
+//! probation: the first accepted share make it healthy again, the first error quarantine it again
+//! for a doubled period.
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Events older than that are not used to compute the error rate
+pub const HEALTH_WINDOW: Duration = Duration::from_secs(300);
+/// Error rate above which an upstream is quarantined
+pub const MAX_ERROR_RATE: f32 = 0.5;
+/// Min number of events in the window needed to evaluate the error rate
+pub const MIN_EVENTS: usize = 10;
+/// Duration of the first quarantine, it double every time that the upstream fail a probe or the
+/// probation
+pub const QUARANTINE_PERIOD: Duration = Duration::from_secs(30);
+pub const MAX_QUARANTINE_PERIOD: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamEvent {
+    AcceptedShare,
+    RejectedShare,
+    ProtocolError,
+    Reconnect,
+}
+
+impl UpstreamEvent {
+    fn is_error(&self) -> bool {
+        !matches!(self, Self::AcceptedShare)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthStatus {
+    Healthy,
+    Quarantined(Instant),
+    Probation,
+}
+
+#[derive(Debug, Clone)]
+pub struct UpstreamHealth {
+    events: VecDeque<(Instant, UpstreamEvent)>,
+    status: HealthStatus,
+    quarantine_period: Duration,
+    /// Set when the upstream is quarantined and reset by `take_new_quarantine`
+    new_quarantine: bool,
+}
+
+impl UpstreamHealth {
+    pub fn new() -> Self {
+        Self {
+            events: VecDeque::new(),
+            status: HealthStatus::Healthy,
+            quarantine_period: QUARANTINE_PERIOD,
+            new_quarantine: false,
+        }
+    }
+
+    pub fn is_quarantined(&self) -> bool {
+        matches!(self.status, HealthStatus::Quarantined(_))
+    }
+
+    /// When the quarantine expires, None if the upstream is not quarantined
+    pub fn quarantined_until(&self) -> Option<Instant> {
+        match self.status {
+            HealthStatus::Quarantined(until) => Some(until),
+            _ => None,
+        }
+    }
+
+    /// Ratio between errors and events in the window
+    pub fn error_rate(&self) -> f32 {
+        if self.events.is_empty() {
+            return 0.0;
+        }
+        let errors = self.events.iter().filter(|(_, e)| e.is_error()).count();
+        errors as f32 / self.events.len() as f32
+    }
+
+    pub fn on_event(&mut self, event: UpstreamEvent, now: Instant) {
+        match self.status {
+            // Upstream is not used while quarantined so new events are not meaningful
+            HealthStatus::Quarantined(_) => (),
+            HealthStatus::Probation => {
+                if event.is_error() {
+                    self.double_quarantine_period();
+                    self.quarantine(now);
+                } else {
+                    self.status = HealthStatus::Healthy;
+                    self.quarantine_period = QUARANTINE_PERIOD;
+                    self.events.push_back((now, event));
+                }
+            }
+            HealthStatus::Healthy => {
+                self.events.push_back((now, event));
+                while let Some((time, _)) = self.events.front() {
+                    if now.saturating_duration_since(*time) > HEALTH_WINDOW {
+                        self.events.pop_front();
+                    } else {
+                        break;
+                    }
+                }
+                if self.events.len() >= MIN_EVENTS && self.error_rate() > MAX_ERROR_RATE {
+                    self.quarantine(now);
+                }
+            }
+        }
+    }
+
+    /// Return true if the quarantine is expired and the upstream should be probed
+    pub fn should_probe(&self, now: Instant) -> bool {
+        match self.status {
+            HealthStatus::Quarantined(until) => now >= until,
+            _ => false,
+        }
+    }
+
+    /// If the probe succeeded the upstream is put on probation, if not the quarantine is extended
+    pub fn on_probe(&mut self, success: bool, now: Instant) {
+        if !self.is_quarantined() {
+            return;
+        }
+        if success {
+            self.status = HealthStatus::Probation;
+        } else {
+            self.double_quarantine_period();
+            self.status = HealthStatus::Quarantined(now + self.quarantine_period);
+        }
+    }
+
+    /// Return true only the first time that is called after that the upstream has been quarantined
+    pub fn take_new_quarantine(&mut self) -> bool {
+        std::mem::replace(&mut self.new_quarantine, false)
+    }
+
+    fn quarantine(&mut self, now: Instant) {
+        self.events.clear();
+        self.status = HealthStatus::Quarantined(now + self.quarantine_period);
+        self.new_quarantine = true;
+    }
+
+    fn double_quarantine_period(&mut self) {
+        self.quarantine_period = std::cmp::min(self.quarantine_period * 2, MAX_QUARANTINE_PERIOD);
+    }
+}
+
+impl Default for UpstreamHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarantine_and_recover() {
+        let now = Instant::now();
+        let mut health = UpstreamHealth::new();
+        for _ in 0..MIN_EVENTS {
+            health.on_event(UpstreamEvent::AcceptedShare, now);
+        }
+        for _ in 0..MIN_EVENTS {
+            health.on_event(UpstreamEvent::RejectedShare, now);
+        }
+        assert_eq!(health.status, HealthStatus::Healthy);
+        health.on_event(UpstreamEvent::ProtocolError, now);
+        assert!(health.is_quarantined());
+        assert!(health.take_new_quarantine());
+        assert!(!health.take_new_quarantine());
+
+        assert!(!health.should_probe(now));
+        let after_quarantine = now + QUARANTINE_PERIOD;
+        assert!(health.should_probe(after_quarantine));
+
+        // Failed probe double the quarantine
+        health.on_probe(false, after_quarantine);
+        assert_eq!(
+            health.quarantined_until(),
+            Some(after_quarantine + QUARANTINE_PERIOD * 2)
+        );
+        let after_quarantine = after_quarantine + QUARANTINE_PERIOD * 2;
+
+        health.on_probe(true, after_quarantine);
+        assert_eq!(health.status, HealthStatus::Probation);
+        health.on_event(UpstreamEvent::AcceptedShare, after_quarantine);
+        assert_eq!(health.status, HealthStatus::Healthy);
+        assert_eq!(health.error_rate(), 0.0);
+    }
+
+    #[test]
+    fn error_during_probation_quarantine_again() {
+        let now = Instant::now();
+        let mut health = UpstreamHealth::new();
+        for _ in 0..MIN_EVENTS {
+            health.on_event(UpstreamEvent::Reconnect, now);
+        }
+        assert!(health.is_quarantined());
+        let after_quarantine = now + QUARANTINE_PERIOD;
+        health.on_probe(true, after_quarantine);
+        health.on_event(UpstreamEvent::RejectedShare, after_quarantine);
+        assert_eq!(
+            health.quarantined_until(),
+            Some(after_quarantine + QUARANTINE_PERIOD * 2)
+        );
+    }
+
+    #[test]
+    fn old_events_are_not_counted() {
+        let now = Instant::now();
+        let mut health = UpstreamHealth::new();
+        for _ in 0..MIN_EVENTS - 1 {
+            health.on_event(UpstreamEvent::RejectedShare, now);
+        }
+        let later = now + HEALTH_WINDOW + Duration::from_secs(1);
+        health.on_event(UpstreamEvent::RejectedShare, later);
+        assert_eq!(health.status, HealthStatus::Healthy);
+    }
+}
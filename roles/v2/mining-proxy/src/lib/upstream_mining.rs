@@ -1,31 +1,82 @@
-use super::downstream_mining::{DownstreamMiningNode, StdFrame as DownstreamFrame};
+use super::{
+    broadcast::JobBroadcast,
+    downstream_mining::{DownstreamMiningNode, StdFrame as DownstreamFrame},
+    labels::Labels,
+    proxy_context::ProxyContext,
+    upstream_dns::ResolvedEndpoint,
+    upstream_health::{UpstreamEvent, UpstreamHealth},
+};
 use async_channel::{Receiver, SendError, Sender};
 use async_recursion::async_recursion;
 use async_std::{net::TcpStream, task};
-use codec_sv2::{Frame, HandshakeRole, Initiator, StandardEitherFrame, StandardSv2Frame};
-use network_helpers::Connection;
+use codec_sv2::{
+    Frame, HandshakeRole, Initiator, PaddingPolicy, StandardEitherFrame, StandardSv2Frame,
+};
+use network_helpers::{rustls::ClientConfig, Connection, ConnectionHandle, TlsConnection};
 use roles_logic_sv2::{
-    common_messages_sv2::{Protocol, SetupConnection},
+    channel_aggregator::{AggregatedMessage, ChannelAggregator},
+    common_messages_sv2::{MiningFlags, Protocol, SetupConnection},
     common_properties::{
-        DownstreamChannel, IsMiningDownstream, IsMiningUpstream, IsUpstream, RequestIdMapper,
-        StandardChannel, UpstreamChannel,
+        DownstreamChannel, IsMiningDownstream, IsMiningUpstream, IsUpstream, PairSettings,
+        RequestIdMapper, StandardChannel, UpstreamChannel,
     },
+    error_codes::{open_mining_channel_error, MiningErrorCode},
     errors::Error,
     handlers::mining::{ParseUpstreamMiningMessages, SendTo, SupportedChannelTypes},
     job_dispatcher::GroupChannelJobDispatcher,
     mining_sv2::*,
-    parsers::{CommonMessages, Mining, MiningDeviceMessages, PoolMessages},
+    parsers::{
+        CommonMessageTypes, CommonMessages, Mining, MiningDeviceMessages, PoolMessages, RelayFrame,
+    },
+    pending_requests::{PendingRequests, DEFAULT_REQUEST_TIMEOUT},
     routing_logic::MiningProxyRoutingLogic,
     selectors::{DownstreamMiningSelector, ProxyDownstreamMiningSelector as Prs},
-    utils::{Id, Mutex},
+    utils::{Id, Mutex, Target, TargetConflictPolicy},
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Weak},
+    time::{Instant, SystemTime},
 };
-use std::{collections::HashMap, sync::Arc};
 
 pub type Message = PoolMessages<'static>;
 pub type StdFrame = StandardSv2Frame<Message>;
 pub type EitherFrame = StandardEitherFrame<Message>;
 pub type ProxyRemoteSelector = Prs<DownstreamMiningNode>;
 
+/// Min extranonce size requested for the aggregated extended channel, every standard channel use
+/// a slice of it so it limit the number of standard channels that the upstream can serve
+const AGGREGATED_EXTRANONCE_SIZE: u16 = 2;
+
+/// How the proxy connect to an upstream
+#[derive(Clone)]
+pub enum UpstreamTransport {
+    /// Noise encrypted connection, the upstream certificate must be signed by the authority key.
+    /// The frames sent to the upstream are padded following the `PaddingPolicy`
+    Noise([u8; 32], PaddingPolicy),
+    /// Plain sv2 frames inside a TLS connection with `server_name`
+    Tls {
+        server_name: String,
+        config: Arc<ClientConfig>,
+    },
+}
+
+impl std::fmt::Debug for UpstreamTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Noise(authority_public_key, padding) => f
+                .debug_tuple("Noise")
+                .field(authority_public_key)
+                .field(padding)
+                .finish(),
+            Self::Tls { server_name, .. } => f
+                .debug_struct("Tls")
+                .field("server_name", server_name)
+                .finish(),
+        }
+    }
+}
+
 /// 1 to 1 connection with a pool
 /// Can be either a mining pool or another proxy
 /// 1 to 1 connection with an upstream node that implement the mining (sub)protocol can be either a a pool or an
@@ -34,6 +85,9 @@ pub type ProxyRemoteSelector = Prs<DownstreamMiningNode>;
 struct UpstreamMiningConnection {
     receiver: Receiver<EitherFrame>,
     sender: Sender<EitherFrame>,
+    /// Frames written ahead of the ones queued in `sender`, None if the transport has no priority
+    /// lane (TLS and shared connections)
+    priority: Option<Sender<EitherFrame>>,
 }
 
 impl UpstreamMiningConnection {
@@ -44,6 +98,11 @@ impl UpstreamMiningConnection {
             Err(e) => Err(e),
         }
     }
+
+    async fn send_priority(&mut self, sv2_frame: StdFrame) -> Result<(), SendError<EitherFrame>> {
+        let sender = self.priority.as_ref().unwrap_or(&self.sender);
+        sender.send(sv2_frame.into()).await
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -74,20 +133,44 @@ pub struct UpstreamMiningNode {
     //port: u32,
     connection: Option<UpstreamMiningConnection>,
     sv2_connection: Option<Sv2MiningConnection>,
-    authority_public_key: [u8; 32],
+    transport: UpstreamTransport,
     /// group_channel id/channel_id -> dispatcher
     pub channel_id_to_job_dispatcher: HashMap<u32, JobDispatcher>,
     /// Each relayed message that has a `request_id` field must have a unique `request_id` number,
     /// connection-wise.
     /// The `request_id` from the downstream is NOT guaranteed to be unique, so it must be changed.
     request_id_mapper: RequestIdMapper,
+    /// Open channel requests relayed upstream, upstream request id -> downstream request id. A
+    /// request answered with a success stay here until it expire but it is no more in
+    /// `request_id_mapper` so it is ignored.
+    pending_requests: PendingRequests<u32>,
     downstream_selector: ProxyRemoteSelector,
     last_prev_hash: Option<SetNewPrevHash<'static>>,
+    /// The active job (non future) first, followed by the future jobs received after the last
+    /// prev hash. Sent to the channels opened after the last prev hash, see `jobs_for_new_channel`
     last_extended_jobs: Vec<NewExtendedMiningJob<'static>>,
+    health: UpstreamHealth,
+    /// Set by `drain`, the upstream is no more used and never reconnected
+    draining: bool,
+    connection_handle: Option<ConnectionHandle>,
+    context: ProxyContext,
+    /// Extended channel that serve the downstream standard channels, only if the proxy aggregate
+    /// the standard channels, see `ProxyContext::aggregated_hash_rate`
+    aggregator: Option<ChannelAggregator>,
+    /// Shares of every upstream channel waiting for the batch window of the channel to expire,
+    /// see `ProxyContext::share_batch_window`
+    pending_shares: HashMap<u32, Vec<StdFrame>>,
+    /// Operator labels of the upstream, see `labels`
+    labels: Labels,
+    /// Set if the upstream is configured with an hostname, see `upstream_dns`
+    endpoint: Option<ResolvedEndpoint>,
+    /// How a SetTarget of the upstream is reconciled with the local target of the channel
+    target_conflict: TargetConflictPolicy,
+    /// SetTarget of the upstream relayed with a target different from the upstream one
+    target_conflicts: u64,
 }
 
-use crate::{max_supported_version, min_supported_version};
-use core::convert::TryInto;
+use core::convert::{TryFrom, TryInto};
 use std::net::SocketAddr;
 
 /// It assume that endpoint NEVER change flags and version!
@@ -95,10 +178,15 @@ impl UpstreamMiningNode {
     pub fn new(
         id: u32,
         address: SocketAddr,
-        authority_public_key: [u8; 32],
+        transport: UpstreamTransport,
         job_ids: Arc<Mutex<Id>>,
+        context: ProxyContext,
     ) -> Self {
         let request_id_mapper = RequestIdMapper::new();
+        let mut pending_requests = PendingRequests::default();
+        if let Some(timeout) = context.request_timeout() {
+            pending_requests.set_timeout(timeout);
+        }
         let downstream_selector = ProxyRemoteSelector::new();
         Self {
             id,
@@ -107,12 +195,568 @@ impl UpstreamMiningNode {
             address,
             connection: None,
             sv2_connection: None,
-            authority_public_key,
+            transport,
             channel_id_to_job_dispatcher: HashMap::new(),
             request_id_mapper,
+            pending_requests,
             downstream_selector,
             last_prev_hash: None,
             last_extended_jobs: Vec::new(),
+            health: UpstreamHealth::new(),
+            draining: false,
+            connection_handle: None,
+            context,
+            aggregator: None,
+            pending_shares: HashMap::new(),
+            labels: Labels::default(),
+            endpoint: None,
+            target_conflict: TargetConflictPolicy::HonorStricter,
+            target_conflicts: 0,
+        }
+    }
+
+    pub fn set_labels(&mut self, labels: Labels) {
+        self.labels = labels;
+    }
+
+    /// Operator labels of the upstream
+    pub fn labels(&self) -> &Labels {
+        &self.labels
+    }
+
+    pub fn set_endpoint(&mut self, endpoint: ResolvedEndpoint) {
+        self.endpoint = Some(endpoint);
+    }
+
+    /// Hostname of the upstream and its resolved addresses, None if configured with an address
+    pub fn endpoint(&self) -> Option<&ResolvedEndpoint> {
+        self.endpoint.as_ref()
+    }
+
+    /// Resolve the hostname of the upstream every `UpstreamHostname::interval`, until the upstream
+    /// is dropped or drained. The new addresses are used when the upstream reconnects.
+    pub async fn refresh_endpoint(self_mutex: Weak<Mutex<Self>>) {
+        loop {
+            let hostname = match self_mutex.upgrade() {
+                Some(self_mutex) => self_mutex
+                    .safe_lock(|self_| match (&self_.endpoint, self_.draining) {
+                        (Some(endpoint), false) => Some(endpoint.hostname().clone()),
+                        _ => None,
+                    })
+                    .unwrap(),
+                None => None,
+            };
+            let hostname = match hostname {
+                Some(hostname) => hostname,
+                None => break,
+            };
+            task::sleep(hostname.interval).await;
+            let addresses = match hostname.resolve().await {
+                Ok(addresses) => addresses,
+                Err(e) => {
+                    println!("Can not resolve upstream {}: {}", hostname.host, e);
+                    continue;
+                }
+            };
+            if let Some(self_mutex) = self_mutex.upgrade() {
+                self_mutex
+                    .safe_lock(|self_| {
+                        let changed = self_
+                            .endpoint
+                            .as_mut()
+                            .map(|endpoint| endpoint.update(addresses.clone()))
+                            .unwrap_or(false);
+                        if changed {
+                            println!(
+                                "Upstream {} resolved to {:?}, connected to {}",
+                                hostname.host, addresses, self_.address
+                            );
+                        }
+                    })
+                    .unwrap();
+            }
+        }
+    }
+
+    /// Relay a share for the upstream channel `channel_id`. If a batch window is configured the
+    /// first share of the channel open the window, the shares received for the channel within the
+    /// window are queued and written together when the window expire. The upstream can
+    /// acknowledge the batch with one SubmitSharesSuccess for the `last_sequence_number` of the
+    /// batch.
+    pub async fn submit_share(
+        self_mutex: Arc<Mutex<Self>>,
+        channel_id: u32,
+        share: StdFrame,
+    ) -> Result<(), SendError<EitherFrame>> {
+        let window = self_mutex
+            .safe_lock(|self_| self_.context.share_batch_window())
+            .unwrap();
+        let window = match window {
+            Some(window) => window,
+            None => return Self::send(self_mutex, share).await,
+        };
+        let opens_batch = self_mutex
+            .safe_lock(|self_| {
+                let pending = self_.pending_shares.entry(channel_id).or_default();
+                pending.push(share);
+                pending.len() == 1
+            })
+            .unwrap();
+        if opens_batch {
+            task::spawn(async move {
+                task::sleep(window).await;
+                Self::flush_shares(self_mutex, channel_id).await;
+            });
+        }
+        Ok(())
+    }
+
+    /// Relay a share that solve a block, the share is never batched and is written ahead of the
+    /// frames already queued for the upstream
+    pub async fn submit_block_solution(
+        self_mutex: Arc<Mutex<Self>>,
+        share: StdFrame,
+    ) -> Result<(), SendError<EitherFrame>> {
+        let connection = self_mutex
+            .safe_lock(|self_| {
+                self_
+                    .connection
+                    .clone()
+                    .filter(|_| self_.sv2_connection.is_some())
+            })
+            .unwrap();
+        match connection {
+            Some(mut connection) => connection.send_priority(share).await,
+            // Let `send` reconnect the upstream
+            None => Self::send(self_mutex, share).await,
+        }
+    }
+
+    /// Send the shares batched for `channel_id`. The shares are queued back to back in the
+    /// connection so the writer send them in a single write.
+    async fn flush_shares(self_mutex: Arc<Mutex<Self>>, channel_id: u32) {
+        let shares = self_mutex
+            .safe_lock(|self_| self_.pending_shares.remove(&channel_id))
+            .unwrap()
+            .unwrap_or_default();
+        for share in shares {
+            if Self::send(self_mutex.clone(), share).await.is_err() {
+                // The connection is down, the downstreams resubmit on the new channels
+                break;
+            }
+        }
+    }
+
+    /// Number of shares waiting for the batch window of `channel_id` to expire
+    pub fn pending_shares(&self, channel_id: u32) -> usize {
+        self.pending_shares
+            .get(&channel_id)
+            .map(|shares| shares.len())
+            .unwrap_or(0)
+    }
+
+    /// Wait the response to an open channel request relayed upstream, if the upstream do not
+    /// answer in time the downstream get an OpenMiningChannelError with the `request-timeout` code
+    pub fn wait_response(
+        self_mutex: Arc<Mutex<Self>>,
+        upstream_request_id: u32,
+        downstream_request_id: u32,
+    ) {
+        let deadline = self_mutex
+            .safe_lock(|self_| {
+                self_.pending_requests.on_request(
+                    upstream_request_id,
+                    downstream_request_id,
+                    Instant::now(),
+                )
+            })
+            .unwrap();
+        task::spawn(async move {
+            task::sleep(deadline.saturating_duration_since(Instant::now())).await;
+            Self::expire_requests(self_mutex).await;
+        });
+    }
+
+    async fn expire_requests(self_mutex: Arc<Mutex<Self>>) {
+        let timed_out: Vec<(Arc<Mutex<DownstreamMiningNode>>, u32)> = self_mutex
+            .safe_lock(|self_| {
+                let expired = self_.pending_requests.expired(Instant::now());
+                expired
+                    .into_iter()
+                    .filter_map(|(upstream_request_id, request_id)| {
+                        // The request has been answered if it is no more mapped
+                        self_.request_id_mapper.remove(upstream_request_id)?;
+                        let downstream = self_
+                            .downstream_selector
+                            .remove_request(upstream_request_id)?;
+                        Some((downstream, request_id))
+                    })
+                    .collect()
+            })
+            .unwrap();
+        for (downstream, request_id) in timed_out {
+            let error = open_mining_channel_error(request_id, MiningErrorCode::RequestTimeout);
+            let message = MiningDeviceMessages::Mining(Mining::OpenMiningChannelError(error));
+            let frame: DownstreamFrame = message.try_into().unwrap();
+            let _ = DownstreamMiningNode::send(downstream, frame).await;
+        }
+    }
+
+    /// Open a standard channel served by the aggregated extended channel, None if the proxy do not
+    /// aggregate the standard channels
+    #[allow(clippy::type_complexity)]
+    pub fn open_aggregated_channel(
+        &mut self,
+        upstream_request_id: u32,
+    ) -> Option<
+        Result<
+            (
+                OpenStandardMiningChannelSuccess<'static>,
+                Vec<AggregatedMessage>,
+            ),
+            OpenMiningChannelError<'static>,
+        >,
+    > {
+        self.context.aggregated_hash_rate()?;
+        let request_id = self
+            .request_id_mapper
+            .remove(upstream_request_id)
+            .unwrap_or(upstream_request_id);
+        let error = |code| open_mining_channel_error(request_id, code);
+        let aggregator = match self.aggregator.as_mut() {
+            Some(aggregator) => aggregator,
+            None => return Some(Err(error(MiningErrorCode::UpstreamNotReady))),
+        };
+        let (success, messages) = match aggregator.open_standard_channel(request_id) {
+            Ok(opened) => opened,
+            Err(_) => return Some(Err(error(MiningErrorCode::ExtranonceSpaceExhausted))),
+        };
+        // Register the channel so that the upstream messages for the channel reach the downstream
+        if self
+            .downstream_selector
+            .on_open_standard_channel_success(
+                upstream_request_id,
+                success.group_channel_id,
+                success.channel_id,
+            )
+            .is_err()
+        {
+            return Some(Err(error(MiningErrorCode::UnknownRequestId)));
+        }
+        Some(Ok((success, messages)))
+    }
+
+    /// Forget the standard channel `channel_id` closed by `downstream`, return the CloseChannel to
+    /// send upstream or None if the channel is not open on this upstream or is served by the
+    /// aggregated extended channel
+    pub fn on_downstream_channel_closed(
+        &mut self,
+        channel_id: u32,
+        downstream: &Arc<Mutex<DownstreamMiningNode>>,
+    ) -> Option<StdFrame> {
+        match self
+            .downstream_selector
+            .downstream_from_channel_id(channel_id)
+        {
+            Some(opened_by) if Arc::ptr_eq(&opened_by, downstream) => (),
+            _ => return None,
+        };
+        self.downstream_selector.remove_channel(channel_id);
+        if let Some(aggregator) = self.aggregator.as_mut() {
+            if aggregator.has_channel(channel_id) {
+                aggregator.close_standard_channel(channel_id);
+                return None;
+            }
+        }
+        let message = PoolMessages::Mining(Mining::CloseChannel(CloseChannel {
+            channel_id,
+            // Is safe to unwrap a reason code shorter than 32 bytes
+            reason_code: "closed-by-operator".to_string().try_into().unwrap(),
+        }));
+        Some(message.try_into().unwrap())
+    }
+
+    /// Translate a share of a standard channel served by the aggregated extended channel, None if
+    /// the channel is not aggregated
+    pub fn submit_aggregated_share(
+        &mut self,
+        share: &SubmitSharesStandard,
+    ) -> Option<Result<SubmitSharesExtended<'static>, SubmitSharesError<'static>>> {
+        self.aggregator
+            .as_mut()
+            .filter(|aggregator| aggregator.has_channel(share.channel_id))
+            .map(|aggregator| aggregator.on_submit_shares_standard(share))
+    }
+
+    fn aggregator_for(&mut self, channel_id: u32) -> Option<&mut ChannelAggregator> {
+        self.aggregator
+            .as_mut()
+            .filter(|aggregator| aggregator.channel_id() == channel_id)
+    }
+
+    /// Relay each message to the downstream that opened the channel, (channel_id, message)
+    fn relay_to_channels(
+        &self,
+        messages: Vec<(u32, Mining<'static>)>,
+    ) -> SendTo<DownstreamMiningNode> {
+        SendTo::Multiple(
+            messages
+                .into_iter()
+                .filter_map(|(channel_id, message)| {
+                    let downstream = self
+                        .downstream_selector
+                        .downstream_from_channel_id(channel_id)?;
+                    Some(SendTo::RelayNewMessage(downstream, message))
+                })
+                .collect(),
+        )
+    }
+
+    /// OpenExtendedMiningChannel for the aggregated extended channel, None if the proxy do not
+    /// aggregate the standard channels. A new connection always need a new extended channel.
+    fn new_open_extended_channel_frame(&mut self) -> Option<StdFrame> {
+        let nominal_hash_rate = self.context.aggregated_hash_rate()?;
+        // The aggregator is resumed when the new extended channel is opened
+        if !self.context.resume_sessions() {
+            self.aggregator = None;
+        }
+        let request_id = self.request_id_mapper.on_open_channel(0);
+        let open_channel = PoolMessages::Mining(Mining::OpenExtendedMiningChannel(
+            OpenExtendedMiningChannel {
+                request_id,
+                user_identity: String::new().try_into().unwrap(),
+                nominal_hash_rate,
+                max_target: [0xff; 32].to_vec().try_into().unwrap(),
+                min_extranonce_size: AGGREGATED_EXTRANONCE_SIZE,
+            },
+        ));
+        Some(open_channel.try_into().unwrap())
+    }
+
+    /// Request id mappings and next request id, see `RequestIdMapper::state`
+    pub fn request_id_mapper_state(&self) -> (Vec<(u32, u32)>, u32) {
+        self.request_id_mapper.state()
+    }
+
+    /// Downstream request id mapped to `upstream_request_id`
+    pub fn downstream_request_id(&self, upstream_request_id: u32) -> Option<u32> {
+        self.request_id_mapper.get(upstream_request_id)
+    }
+
+    pub fn restore_request_id_mapper(&mut self, mappings: Vec<(u32, u32)>, next_id: u32) {
+        self.request_id_mapper = RequestIdMapper::restore(mappings, next_id);
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Error rate of the last `HEALTH_WINDOW`, see `UpstreamHealth::error_rate`
+    pub fn error_rate(&self) -> f32 {
+        self.health.error_rate()
+    }
+
+    pub fn set_target_conflict(&mut self, policy: TargetConflictPolicy) {
+        self.target_conflict = policy;
+    }
+
+    /// How a SetTarget of the upstream is reconciled with the local target of the channel
+    pub fn target_conflict(&self) -> TargetConflictPolicy {
+        self.target_conflict
+    }
+
+    /// Number of SetTarget of the upstream relayed with a target different from the upstream one
+    pub fn target_conflicts(&self) -> u64 {
+        self.target_conflicts
+    }
+
+    /// Jobs evicted from the job history of the group channels and of the aggregated channel,
+    /// see `ProxyBuilder::job_history`
+    pub fn evicted_jobs(&self) -> u64 {
+        let dispatchers = self
+            .channel_id_to_job_dispatcher
+            .values()
+            .map(|dispatcher| match dispatcher {
+                JobDispatcher::Group(dispatcher) => dispatcher.evicted_jobs(),
+                JobDispatcher::None => 0,
+            })
+            .sum::<u64>();
+        let aggregator = self
+            .aggregator
+            .as_ref()
+            .map(|aggregator| aggregator.evicted_jobs())
+            .unwrap_or(0);
+        dispatchers + aggregator
+    }
+
+    /// When the certificate of the upstream expires, None for the TLS upstreams and when the
+    /// upstream is not connected
+    pub fn certificate_expiry(&self) -> Option<SystemTime> {
+        self.connection_handle
+            .as_ref()
+            .and_then(|handle| handle.remote_certificate_expiry())
+    }
+
+    /// Record an event in the upstream health. If the upstream get quarantined every paired
+    /// downstream is disconnected, so that it can reconnect and be paired with an healthy
+    /// upstream, and the upstream is periodically probed for recovery.
+    fn on_health_event(self_mutex: Arc<Mutex<Self>>, event: UpstreamEvent) {
+        self_mutex
+            .safe_lock(|self_| self_.health.on_event(event, Instant::now()))
+            .unwrap();
+        Self::check_health(self_mutex);
+    }
+
+    fn check_health(self_mutex: Arc<Mutex<Self>>) {
+        let quarantined = self_mutex
+            .safe_lock(|self_| {
+                if self_.health.take_new_quarantine() {
+                    Some((self_.id, self_.downstream_selector.remove_all_downstreams()))
+                } else {
+                    None
+                }
+            })
+            .unwrap();
+        if let Some((id, downstreams)) = quarantined {
+            let context = self_mutex.safe_lock(|self_| self_.context.clone()).unwrap();
+            match context.failover_upstream(&self_mutex) {
+                // Downstreams are notified with ChannelEndpointChanged and open new channels that
+                // are routed to the new upstream
+                Ok(new_upstream) => {
+                    let new_id = new_upstream.safe_lock(|u| u.id).unwrap();
+                    println!(
+                        "Upstream {} quarantined, remapping {} downstreams to upstream {}",
+                        id,
+                        downstreams.len(),
+                        new_id
+                    );
+                    for downstream in downstreams {
+                        task::spawn(DownstreamMiningNode::on_channel_endpoint_changed(
+                            downstream,
+                        ));
+                    }
+                }
+                // The downstreams keep the connection and their new channel requests are parked
+                // until the upstream come back
+                Err(_) if context.park_limits().is_some() => {
+                    println!(
+                        "Upstream {} quarantined, parking the channels of {} downstreams",
+                        id,
+                        downstreams.len()
+                    );
+                    for downstream in downstreams {
+                        task::spawn(DownstreamMiningNode::on_channel_endpoint_changed(
+                            downstream,
+                        ));
+                    }
+                }
+                Err(_) => {
+                    println!(
+                        "Upstream {} quarantined, disconnecting {} downstreams",
+                        id,
+                        downstreams.len()
+                    );
+                    for downstream in downstreams {
+                        downstream.safe_lock(|d| d.disconnect()).unwrap();
+                    }
+                }
+            }
+            task::spawn(Self::probe(self_mutex));
+        }
+    }
+
+    /// Remap the downstreams paired with this upstream to another upstream, as on quarantine but
+    /// on request of the operator. The downstreams are notified with ChannelEndpointChanged. Return
+    /// the id of the new upstream.
+    pub fn switch_downstreams(self_mutex: Arc<Mutex<Self>>) -> Result<u32, Error> {
+        let context = self_mutex.safe_lock(|self_| self_.context.clone()).unwrap();
+        let new_upstream = context.failover_upstream(&self_mutex)?;
+        let downstreams = self_mutex
+            .safe_lock(|self_| self_.downstream_selector.remove_all_downstreams())
+            .unwrap();
+        for downstream in downstreams {
+            task::spawn(DownstreamMiningNode::on_channel_endpoint_changed(
+                downstream,
+            ));
+        }
+        Ok(new_upstream.safe_lock(|u| u.id).unwrap())
+    }
+
+    /// Drain the upstream, eg before a maintenance window of the pool: it is no more paired with new
+    /// downstreams, the paired downstreams are remapped to another upstream as in
+    /// `switch_downstreams` (so they open new channels and get fresh jobs from the new upstream)
+    /// and then the connection is closed. Return the id of the new upstream, if there is no other
+    /// upstream the upstream is left untouched.
+    pub async fn drain(self_mutex: Arc<Mutex<Self>>) -> Result<u32, Error> {
+        // Set before the remap so that the downstreams that connect meanwhile are not paired with
+        // this upstream
+        self_mutex.safe_lock(|self_| self_.draining = true).unwrap();
+        let new_upstream_id = match Self::switch_downstreams(self_mutex.clone()) {
+            Ok(id) => id,
+            Err(e) => {
+                self_mutex
+                    .safe_lock(|self_| self_.draining = false)
+                    .unwrap();
+                return Err(e);
+            }
+        };
+        let id = self_mutex.safe_lock(|self_| self_.id).unwrap();
+        println!(
+            "Upstream {} drained to upstream {}, closing the connection",
+            id, new_upstream_id
+        );
+        Self::shutdown(self_mutex).await;
+        Ok(new_upstream_id)
+    }
+
+    /// Wait for the quarantine to expire and then try to reach the upstream, until the upstream is
+    /// reachable
+    async fn probe(self_mutex: Arc<Mutex<Self>>) {
+        loop {
+            let (until, address) = self_mutex
+                .safe_lock(|self_| (self_.health.quarantined_until(), self_.address))
+                .unwrap();
+            let until = match until {
+                Some(until) => until,
+                None => break,
+            };
+            task::sleep(until.saturating_duration_since(Instant::now())).await;
+            let should_probe = self_mutex
+                .safe_lock(|self_| self_.health.should_probe(Instant::now()))
+                .unwrap();
+            if !should_probe {
+                continue;
+            }
+            let reachable = TcpStream::connect(address).await.is_ok();
+            let quarantined = self_mutex
+                .safe_lock(|self_| {
+                    self_.health.on_probe(reachable, Instant::now());
+                    self_.health.is_quarantined()
+                })
+                .unwrap();
+            if !quarantined {
+                println!("Upstream {} is on probation", address);
+                let context = self_mutex.safe_lock(|self_| self_.context.clone()).unwrap();
+                DownstreamMiningNode::release_parked_requests(context).await;
+                break;
+            }
+        }
+    }
+
+    /// Gracefully close the connection with the upstream flushing the outbound queue
+    pub async fn shutdown(self_mutex: Arc<Mutex<Self>>) {
+        let (connection, connection_handle) = self_mutex
+            .safe_lock(|self_| (self_.connection.take(), self_.connection_handle.take()))
+            .unwrap();
+        match (connection, connection_handle) {
+            (Some(connection), Some(connection_handle)) => {
+                connection_handle.shutdown(&connection.sender).await
+            }
+            // Session on a shared connection, see `upstream_mux`
+            (Some(connection), None) => {
+                connection.sender.close();
+            }
+            (None, _) => (),
         }
     }
 
@@ -134,13 +778,14 @@ impl UpstreamMiningNode {
         match (connection.as_mut(), has_sv2_connetcion) {
             (Some(connection), true) => match connection.send(sv2_frame).await {
                 Ok(_) => Ok(()),
-                Err(_e) => {
-                    Self::connect(self_mutex.clone()).await.unwrap();
-                    // It assume that enpoint NEVER change flags and version!
-                    match Self::setup_connection(self_mutex).await {
-                        Ok(()) => Ok(()),
-                        Err(()) => panic!(),
+                Err(e) => {
+                    Self::on_health_event(self_mutex.clone(), UpstreamEvent::Reconnect);
+                    Self::drop_connection(&self_mutex);
+                    if Self::connect(self_mutex.clone()).await.is_err() {
+                        return Err(e);
                     }
+                    // It assume that enpoint NEVER change flags and version!
+                    Self::setup_connection(self_mutex).await.map_err(|_| e)
                 }
             },
             // It assume that no downstream try to send messages before that the upstream is
@@ -151,16 +796,22 @@ impl UpstreamMiningNode {
                 Ok(_) => Ok(()),
                 Err(e) => Err(e),
             },
+            // A drained upstream is never reconnected
+            (None, _) if self_mutex.safe_lock(|self_| self_.draining).unwrap() => {
+                Err(SendError(sv2_frame.into()))
+            }
             (None, _) => {
-                Self::connect(self_mutex.clone()).await.unwrap();
+                if Self::connect(self_mutex.clone()).await.is_err() {
+                    return Err(SendError(sv2_frame.into()));
+                }
                 let mut connection = self_mutex
                     .safe_lock(|self_| self_.connection.clone())
                     .unwrap();
-                match connection.as_mut().unwrap().send(sv2_frame).await {
-                    Ok(_) => match Self::setup_connection(self_mutex).await {
-                        Ok(()) => Ok(()),
-                        Err(()) => panic!(),
-                    },
+                // Is fine to unwrap, `connect` succeeded
+                match connection.as_mut().unwrap().send(sv2_frame.clone()).await {
+                    Ok(_) => Self::setup_connection(self_mutex)
+                        .await
+                        .map_err(|_| SendError(sv2_frame.into())),
                     Err(e) => {
                         //Self::connect(self_mutex.clone()).await.unwrap();
                         Err(e)
@@ -178,14 +829,48 @@ impl UpstreamMiningNode {
             Some(connection) => match connection.receiver.recv().await {
                 Ok(m) => Ok(m.try_into()?),
                 Err(_) => {
-                    Self::connect(self_mutex).await?;
+                    Self::on_health_event(self_mutex.clone(), UpstreamEvent::Reconnect);
+                    // The next `send` open a new connection
+                    Self::drop_connection(&self_mutex);
                     Err(())
                 }
             },
-            None => todo!("177"),
+            // The connection has been dropped while waiting the response
+            None => Err(()),
         }
     }
 
+    /// Wait the response to a SetupConnection, an upstream that do not answer within the request
+    /// timeout is dropped
+    async fn receive_setup_response(self_mutex: Arc<Mutex<Self>>) -> Result<StdFrame, ()> {
+        let timeout = self_mutex
+            .safe_lock(|self_| self_.context.request_timeout())
+            .unwrap()
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+        let cloned = self_mutex.clone();
+        match async_std::future::timeout(timeout, Self::receive(cloned)).await {
+            Ok(response) => response,
+            Err(_) => {
+                Self::on_health_event(self_mutex.clone(), UpstreamEvent::Reconnect);
+                Self::drop_connection(&self_mutex);
+                Err(())
+            }
+        }
+    }
+
+    /// Forget the connection with the upstream and close it, the pending frames are dropped
+    fn drop_connection(self_mutex: &Arc<Mutex<Self>>) {
+        self_mutex
+            .safe_lock(|self_| {
+                if let Some(connection) = self_.connection.take() {
+                    connection.sender.close();
+                    connection.receiver.close();
+                }
+                self_.connection_handle = None;
+            })
+            .unwrap();
+    }
+
     async fn connect(self_mutex: Arc<Mutex<Self>>) -> Result<(), ()> {
         let has_connection = self_mutex
             .safe_lock(|self_| self_.connection.is_some())
@@ -193,17 +878,55 @@ impl UpstreamMiningNode {
         match has_connection {
             true => Ok(()),
             false => {
-                let (address, authority_public_key) = self_mutex
-                    .safe_lock(|self_| (self_.address, self_.authority_public_key))
+                let (candidates, transport, shared_upstreams) = self_mutex
+                    .safe_lock(|self_| {
+                        let candidates = match &self_.endpoint {
+                            Some(endpoint) => endpoint.candidates(self_.address),
+                            None => vec![self_.address],
+                        };
+                        (
+                            candidates,
+                            self_.transport.clone(),
+                            self_.context.shared_upstreams(),
+                        )
+                    })
                     .unwrap();
-                let socket = TcpStream::connect(address).await.map_err(|_| ())?;
-                let initiator = Initiator::from_raw_k(authority_public_key).unwrap();
-                let (receiver, sender) =
-                    Connection::new(socket, HandshakeRole::Initiator(initiator), 10).await;
-                let connection = UpstreamMiningConnection { receiver, sender };
+                // The resolved addresses of the upstream are tried in order, see `upstream_dns`
+                let mut connected = Err(());
+                for address in candidates {
+                    connected = match &shared_upstreams {
+                        Some(shared_upstreams) => shared_upstreams
+                            .session(address, &transport)
+                            .await
+                            .map(|(receiver, sender)| (receiver, sender, None, None)),
+                        None => open_connection_with_priority(address, &transport)
+                            .await
+                            .map(|(receiver, sender, priority, connection_handle)| {
+                                (receiver, sender, priority, Some(connection_handle))
+                            }),
+                    }
+                    .map(|connection| (address, connection));
+                    if connected.is_ok() {
+                        break;
+                    }
+                }
+                let (address, (receiver, sender, priority, connection_handle)) = connected?;
+                let connection = UpstreamMiningConnection {
+                    receiver,
+                    sender,
+                    priority,
+                };
                 self_mutex
                     .safe_lock(|self_| {
+                        if self_.address != address {
+                            println!(
+                                "Upstream {} moved from {} to {}",
+                                self_.id, self_.address, address
+                            );
+                            self_.address = address;
+                        }
                         self_.connection = Some(connection);
+                        self_.connection_handle = connection_handle;
                     })
                     .unwrap();
                 Ok(())
@@ -227,20 +950,32 @@ impl UpstreamMiningNode {
                     .await
                     .map_err(|_| ())?;
 
-                let cloned = self_mutex.clone();
-                let mut response = task::spawn(async { Self::receive(cloned).await }).await?;
+                let mut response = Self::receive_setup_response(self_mutex.clone()).await?;
 
                 let message_type = response.get_header().unwrap().msg_type();
                 let payload = response.payload();
                 match (message_type, payload).try_into() {
                     Ok(CommonMessages::SetupConnectionSuccess(_)) => {
-                        let receiver = self_mutex
-                            .safe_lock(|self_| self_.connection.clone().unwrap().receiver)
+                        let (receiver, open_channel) = self_mutex
+                            .safe_lock(|self_| {
+                                (
+                                    self_.connection.clone().unwrap().receiver,
+                                    self_.new_open_extended_channel_frame(),
+                                )
+                            })
                             .unwrap();
+                        if let Some(frame) = open_channel {
+                            Self::send(self_mutex.clone(), frame)
+                                .await
+                                .map_err(|_| ())?;
+                        }
                         Self::relay_incoming_messages(self_mutex, receiver);
                         Ok(())
                     }
-                    _ => panic!(),
+                    _ => {
+                        Self::drop_connection(&self_mutex);
+                        Err(())
+                    }
                 }
             }
         }
@@ -253,7 +988,11 @@ impl UpstreamMiningNode {
     ) {
         task::spawn(async move {
             loop {
-                let message = receiver.recv().await.unwrap();
+                let message = match receiver.recv().await {
+                    Ok(message) => message,
+                    // Connection has been closed
+                    Err(_) => break,
+                };
                 let incoming: StdFrame = message.try_into().unwrap();
                 Self::next(self_.clone(), incoming).await;
             }
@@ -261,71 +1000,133 @@ impl UpstreamMiningNode {
     }
 
     pub async fn next(self_mutex: Arc<Mutex<Self>>, mut incoming: StdFrame) {
-        let message_type = incoming.get_header().unwrap().msg_type();
+        let received = Instant::now();
+        let header = incoming.get_header().unwrap();
+        let message_type = header.msg_type();
         let payload = incoming.payload();
+        // The prev hashes relayed to the downstreams are timed, see `prev_hash_latency`
+        let is_prev_hash = message_type == const_sv2::MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH;
+
+        if CommonMessageTypes::try_from(message_type).is_ok() {
+            Self::next_common(self_mutex, message_type, payload).await;
+            return;
+        }
 
-        let routing_logic = crate::get_routing_logic();
+        let routing_logic = self_mutex
+            .safe_lock(|self_| self_.context.get_routing_logic())
+            .unwrap();
 
         let next_message_to_send = UpstreamMiningNode::handle_message_mining(
             self_mutex.clone(),
-            message_type,
+            header,
             payload,
             routing_logic,
         );
+        // A send fail only if the downstream is closed, it is forgotten when its connection end
         match next_message_to_send {
-            Ok(SendTo::RelaySameMessage(downstream)) => {
-                let sv2_frame: codec_sv2::Sv2Frame<MiningDeviceMessages, Vec<u8>> =
-                    incoming.map(|payload| payload.try_into().unwrap());
-
-                DownstreamMiningNode::send(downstream.clone(), sv2_frame)
-                    .await
-                    .unwrap();
-            }
-            Ok(SendTo::RelayNewMessage(downstream_mutex, message)) => {
+            Ok(SendTo::RelaySameMessage(downstream)) => match incoming.relay() {
+                Ok(sv2_frame) => {
+                    let _ = DownstreamMiningNode::send(downstream.clone(), sv2_frame).await;
+                    Self::on_relayed(&self_mutex, is_prev_hash, received);
+                }
+                Err(e) => println!("Upstream error: {:?}", e),
+            },
+            Ok(SendTo::RelayNewMessage(downstream_mutex, mut message)) => {
+                on_message_relayed(&self_mutex, &downstream_mutex, &mut message);
                 let message = MiningDeviceMessages::Mining(message);
                 let frame: DownstreamFrame = message.try_into().unwrap();
-                DownstreamMiningNode::send(downstream_mutex, frame)
-                    .await
-                    .unwrap();
+                let _ = DownstreamMiningNode::send(downstream_mutex, frame).await;
+                Self::on_relayed(&self_mutex, is_prev_hash, received);
             }
             Ok(SendTo::Respond(message)) => {
                 let message = PoolMessages::Mining(message);
                 let frame: StdFrame = message.try_into().unwrap();
-                UpstreamMiningNode::send(self_mutex, frame).await.unwrap();
+                let _ = UpstreamMiningNode::send(self_mutex.clone(), frame).await;
             }
             Ok(SendTo::Multiple(sends_to)) => {
+                // Jobs and prev hashes for many channels are serialized once per distinct job
+                let mut broadcast = JobBroadcast::new();
                 for send_to in sends_to {
                     match send_to {
-                        SendTo::RelayNewMessage(downstream_mutex, message) => {
-                            let message = MiningDeviceMessages::Mining(message);
-                            let frame: DownstreamFrame = message.try_into().unwrap();
-                            DownstreamMiningNode::send(downstream_mutex, frame)
-                                .await
-                                .unwrap();
+                        SendTo::RelayNewMessage(downstream_mutex, mut message) => {
+                            on_message_relayed(&self_mutex, &downstream_mutex, &mut message);
+                            let frame = broadcast.frame(message);
+                            let _ = DownstreamMiningNode::send(downstream_mutex, frame).await;
+                            Self::on_relayed(&self_mutex, is_prev_hash, received);
                         }
                         SendTo::RelaySameMessage(downstream_mutex) => {
-                            let frame: codec_sv2::Sv2Frame<MiningDeviceMessages, Vec<u8>> =
-                                incoming.clone().map(|payload| payload.try_into().unwrap());
-                            DownstreamMiningNode::send(downstream_mutex, frame)
-                                .await
-                                .unwrap();
+                            match incoming.clone().relay() {
+                                Ok(frame) => {
+                                    let _ =
+                                        DownstreamMiningNode::send(downstream_mutex, frame).await;
+                                    Self::on_relayed(&self_mutex, is_prev_hash, received);
+                                }
+                                Err(e) => println!("Upstream error: {:?}", e),
+                            }
                         }
                         SendTo::Respond(message) => {
                             let message = PoolMessages::Mining(message);
                             let frame: StdFrame = message.try_into().unwrap();
-                            UpstreamMiningNode::send(self_mutex.clone(), frame)
-                                .await
-                                .unwrap();
+                            let _ = UpstreamMiningNode::send(self_mutex.clone(), frame).await;
                         }
                         SendTo::None(_) => (),
+                        // The proxy do not register extensions
+                        SendTo::Extension(_) => (),
                         SendTo::Multiple(_) => panic!("Nested SendTo::Multiple not supported"),
                     }
                 }
             }
             Ok(SendTo::None(_)) => (),
+            // The proxy do not register extensions
+            Ok(SendTo::Extension(_)) => (),
             Err(Error::NoDownstreamsConnected) => (),
-            Err(Error::UnexpectedMessage) => todo!(),
-            Err(_) => todo!(),
+            Err(e) => {
+                println!("Upstream error: {:?}", e);
+                self_mutex
+                    .safe_lock(|self_| {
+                        self_
+                            .health
+                            .on_event(UpstreamEvent::ProtocolError, Instant::now())
+                    })
+                    .unwrap();
+            }
+        }
+        // Handlers record accepted and rejected shares in the upstream health
+        Self::check_health(self_mutex);
+    }
+
+    /// A message received at `received` has been queued on a downstream connection
+    fn on_relayed(self_mutex: &Arc<Mutex<Self>>, is_prev_hash: bool, received: Instant) {
+        if is_prev_hash {
+            self_mutex
+                .safe_lock(|self_| self_.context.record_prev_hash_latency(received.elapsed()))
+                .unwrap();
+        }
+    }
+
+    /// After the connection setup the only common message that an upstream can send is
+    /// ChannelEndpointChanged, every downstream in the changed channel is notified so that it
+    /// reset the channel state and open a new channel.
+    async fn next_common(self_mutex: Arc<Mutex<Self>>, message_type: u8, payload: &mut [u8]) {
+        match (message_type, payload).try_into() {
+            Ok(CommonMessages::ChannelEndpointChanged(m)) => {
+                let downstreams = self_mutex
+                    .safe_lock(|self_| {
+                        let selector = &self_.downstream_selector;
+                        match selector.downstream_from_channel_id(m.channel_id) {
+                            Some(downstream) => vec![downstream],
+                            None => selector
+                                .get_downstreams_in_channel(m.channel_id)
+                                .cloned()
+                                .unwrap_or_default(),
+                        }
+                    })
+                    .unwrap();
+                for downstream in downstreams {
+                    DownstreamMiningNode::on_channel_endpoint_changed(downstream).await;
+                }
+            }
+            _ => Self::on_health_event(self_mutex, UpstreamEvent::ProtocolError),
         }
     }
 
@@ -334,9 +1135,17 @@ impl UpstreamMiningNode {
         self_mutex: Arc<Mutex<Self>>,
         flags: Option<u32>,
     ) -> Result<(), ()> {
-        let flags = flags.unwrap_or(0b0111_0000_0000_0000_0000_0000_0000_0000);
-        let min_version = min_supported_version();
-        let max_version = max_supported_version();
+        let flags = flags.unwrap_or(
+            (MiningFlags::REQUIRES_WORK_SELECTION | MiningFlags::REQUIRES_VERSION_ROLLING).bits(),
+        );
+        let (min_version, max_version) = self_mutex
+            .safe_lock(|self_| {
+                (
+                    self_.context.min_supported_version(),
+                    self_.context.max_supported_version(),
+                )
+            })
+            .unwrap();
         let frame = self_mutex
             .safe_lock(|self_| self_.new_setup_connection_frame(flags, min_version, max_version))
             .unwrap();
@@ -344,29 +1153,38 @@ impl UpstreamMiningNode {
             .await
             .map_err(|_| ())?;
 
-        let cloned = self_mutex.clone();
-        let mut response = task::spawn(async { Self::receive(cloned).await }).await?;
+        let mut response = Self::receive_setup_response(self_mutex.clone()).await?;
 
         let message_type = response.get_header().unwrap().msg_type();
         let payload = response.payload();
         match (message_type, payload).try_into() {
             Ok(CommonMessages::SetupConnectionSuccess(m)) => {
-                let receiver = self_mutex
+                let (receiver, open_channel) = self_mutex
                     .safe_lock(|self_| {
                         self_.sv2_connection = Some(Sv2MiningConnection {
                             version: m.used_version,
                             setup_connection_flags: flags,
                             setup_connection_success_flags: m.flags,
                         });
-                        self_.connection.clone().unwrap().receiver
+                        (
+                            self_.connection.clone().unwrap().receiver,
+                            self_.new_open_extended_channel_frame(),
+                        )
                     })
                     .unwrap();
+                if let Some(frame) = open_channel {
+                    Self::send(self_mutex.clone(), frame)
+                        .await
+                        .map_err(|_| ())?;
+                }
                 Self::relay_incoming_messages(self_mutex, receiver);
                 Ok(())
             }
             Ok(CommonMessages::SetupConnectionError(m)) => {
                 if m.flags != 0 {
-                    let flags = flags ^ m.flags;
+                    let flags = (MiningFlags::from_bits_truncate(flags)
+                        & !MiningFlags::from_bits_truncate(m.flags))
+                    .bits();
                     // We need to send SetupConnection again as we do not yet know the version of
                     // upstream
                     // debounce this?
@@ -375,8 +1193,11 @@ impl UpstreamMiningNode {
                     Err(())
                 }
             }
-            Ok(_) => todo!("356"),
-            Err(_) => todo!("357"),
+            // Unexpected or malformed response
+            Ok(_) | Err(_) => {
+                Self::drop_connection(&self_mutex);
+                Err(())
+            }
         }
     }
 
@@ -446,7 +1267,11 @@ impl
     > for UpstreamMiningNode
 {
     fn get_channel_type(&self) -> SupportedChannelTypes {
-        SupportedChannelTypes::Group
+        match self.context.aggregated_hash_rate() {
+            // The aggregated standard channels are served by an extended channel
+            Some(_) => SupportedChannelTypes::GroupAndExtended,
+            None => SupportedChannelTypes::Group,
+        }
     }
 
     fn is_work_selection_enabled(&self) -> bool {
@@ -455,63 +1280,81 @@ impl
 
     fn handle_open_standard_mining_channel_success(
         &mut self,
-        m: OpenStandardMiningChannelSuccess,
+        mut m: OpenStandardMiningChannelSuccess,
         remote: Option<Arc<Mutex<DownstreamMiningNode>>>,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        let nominal_hash_rate = remote
+            .as_ref()
+            .unwrap()
+            .safe_lock(|r| {
+                r.assign_request(m.get_request_id_as_u32(), m.channel_id);
+                r.take_requested_hash_rate(m.get_request_id_as_u32())
+            })
+            .unwrap();
+        let upstream_target: Target = m.target.clone().into();
+        let target = self
+            .context
+            .channel_target(upstream_target, nominal_hash_rate);
+        // The target is updated in place so that the relayed frame carry it
+        m.target
+            .inner_as_mut()
+            .copy_from_slice(&target.to_le_bytes());
         let down_is_header_only = remote
             .as_ref()
             .unwrap()
             .safe_lock(|remote| remote.is_header_only())
             .unwrap();
         let up_is_header_only = self.is_header_only();
-        match (down_is_header_only, up_is_header_only) {
-            (true, true) => {
-                let channel = DownstreamChannel::Standard(StandardChannel {
-                    channel_id: m.channel_id,
-                    group_id: m.group_channel_id,
-                    target: m.target.into(),
-                    extranonce: m.extranonce_prefix.into(),
-                });
-                remote
-                    .as_ref()
-                    .unwrap()
-                    .safe_lock(|r| r.add_channel(channel))
-                    .unwrap();
-            }
+        let channel = match (down_is_header_only, up_is_header_only) {
+            (true, true) => DownstreamChannel::Standard(StandardChannel {
+                channel_id: m.channel_id,
+                group_id: m.group_channel_id,
+                target: m.target.into(),
+                extranonce: m.extranonce_prefix.into(),
+            }),
             (true, false) => {
-                let channel = DownstreamChannel::Standard(StandardChannel {
-                    channel_id: m.channel_id,
-                    group_id: m.group_channel_id,
-                    target: m.target.into(),
-                    extranonce: m.extranonce_prefix.into(),
-                });
                 if self
                     .channel_id_to_job_dispatcher
                     .get_mut(&m.group_channel_id)
                     .is_none()
                 {
-                    let dispatcher = GroupChannelJobDispatcher::new(self.job_ids.clone());
+                    let mut dispatcher = GroupChannelJobDispatcher::new(self.job_ids.clone());
+                    if let Some(grace_period) = self.context.stale_grace_period() {
+                        dispatcher.set_stale_grace_period(grace_period);
+                    }
+                    if let Some(capacity) = self.context.job_history() {
+                        dispatcher.set_job_history_capacity(capacity);
+                    }
                     self.channel_id_to_job_dispatcher
                         .insert(m.group_channel_id, JobDispatcher::Group(dispatcher));
                 }
-                remote
-                    .as_ref()
-                    .unwrap()
-                    .safe_lock(|r| r.add_channel(channel))
-                    .unwrap();
-            }
-            (false, true) => {
-                todo!()
-            }
-            (false, false) => {
-                let channel = DownstreamChannel::Group(m.group_channel_id);
-                remote
-                    .as_ref()
-                    .unwrap()
-                    .safe_lock(|r| r.add_channel(channel))
-                    .unwrap();
+                DownstreamChannel::Standard(StandardChannel {
+                    channel_id: m.channel_id,
+                    group_id: m.group_channel_id,
+                    target: m.target.into(),
+                    extranonce: m.extranonce_prefix.into(),
+                })
             }
-        }
+            // The upstream send only standard jobs, they are relayed as they are also to the
+            // downstreams that could handle the extended ones
+            (false, true) => DownstreamChannel::Standard(StandardChannel {
+                channel_id: m.channel_id,
+                group_id: m.group_channel_id,
+                target: m.target.into(),
+                extranonce: m.extranonce_prefix.into(),
+            }),
+            (false, false) => DownstreamChannel::Group(m.group_channel_id),
+        };
+        remote
+            .as_ref()
+            .unwrap()
+            .safe_lock(|r| {
+                if let DownstreamChannel::Standard(c) = &channel {
+                    r.set_channel_targets(c.channel_id, upstream_target, target);
+                }
+                r.add_channel(channel.clone())
+            })
+            .unwrap();
 
         let open_channel = SendTo::RelaySameMessage(remote.clone().unwrap());
 
@@ -521,26 +1364,16 @@ impl
             }
             (Some(new_prev_hash), _) => {
                 let mut responses = vec![open_channel];
-                let downstream = vec![remote.clone().unwrap()];
-                let mut dispatcher = self
+                let dispatcher = self
                     .channel_id_to_job_dispatcher
                     .get_mut(&m.group_channel_id);
-                let new_prev_hash = Mining::SetNewPrevHash(SetNewPrevHash {
-                    channel_id: m.channel_id,
-                    job_id: new_prev_hash.job_id,
-                    prev_hash: new_prev_hash.prev_hash.clone().into_static(),
-                    min_ntime: new_prev_hash.min_ntime,
-                    nbits: new_prev_hash.nbits,
-                });
-                responses.push(SendTo::RelayNewMessage(remote.unwrap(), new_prev_hash));
-                for job in &self.last_extended_jobs {
-                    // TODO the below unwrap is not safe
-                    for job in
-                        jobs_to_relay(self.id, job, &downstream, dispatcher.as_mut().unwrap())
-                    {
-                        responses.push(job)
-                    }
-                }
+                responses.extend(jobs_for_new_channel(
+                    &self.last_extended_jobs,
+                    new_prev_hash,
+                    &channel,
+                    &remote.unwrap(),
+                    dispatcher,
+                ));
                 Ok(SendTo::Multiple(responses))
             }
             (None, 0) => Ok(open_channel),
@@ -550,16 +1383,63 @@ impl
 
     fn handle_open_extended_mining_channel_success(
         &mut self,
-        _m: OpenExtendedMiningChannelSuccess,
+        m: OpenExtendedMiningChannelSuccess,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("450")
+        // The only extended channel opened by the proxy is the aggregated one
+        self.request_id_mapper.remove(m.request_id);
+        if let Some(aggregator) = self.aggregator.as_mut() {
+            match aggregator.resume(&m) {
+                Ok(messages) => {
+                    println!(
+                        "Upstream {} reconnected, aggregated channels resumed",
+                        self.id
+                    );
+                    let messages = messages
+                        .into_iter()
+                        .map(|message| (message.channel_id(), message.into()))
+                        .collect();
+                    return Ok(self.relay_to_channels(messages));
+                }
+                Err(_) => println!(
+                    "Upstream {} reconnected, aggregated channels can not be resumed",
+                    self.id
+                ),
+            }
+        }
+        let mut aggregator = ChannelAggregator::new(&m, self.job_ids.clone())?;
+        if let Some(grace_period) = self.context.stale_grace_period() {
+            aggregator.set_stale_grace_period(grace_period);
+        }
+        if let Some(capacity) = self.context.job_history() {
+            aggregator.set_job_history_capacity(capacity);
+        }
+        self.aggregator = Some(aggregator);
+        Ok(SendTo::None(None))
     }
 
     fn handle_open_mining_channel_error(
         &mut self,
-        _m: OpenMiningChannelError,
+        m: OpenMiningChannelError,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("460")
+        let upstream_request_id = m.request_id;
+        self.pending_requests.on_response(upstream_request_id);
+        let request_id = self
+            .request_id_mapper
+            .remove(upstream_request_id)
+            .ok_or(Error::RequestIdNotMapped(upstream_request_id))?;
+        let downstream = self
+            .downstream_selector
+            .remove_request(upstream_request_id)
+            .ok_or(Error::UnknownRequestId(upstream_request_id))?;
+        let error = OpenMiningChannelError {
+            request_id,
+            // Is safe to unwrap, the error code is already a Str032
+            error_code: m.error_code.to_vec().try_into().unwrap(),
+        };
+        Ok(SendTo::RelayNewMessage(
+            downstream,
+            Mining::OpenMiningChannelError(error),
+        ))
     }
 
     fn handle_update_channel_error(
@@ -587,20 +1467,58 @@ impl
         &mut self,
         m: SubmitSharesSuccess,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        self.health
+            .on_event(UpstreamEvent::AcceptedShare, Instant::now());
+        if let Some(aggregator) = self.aggregator_for(m.channel_id) {
+            let messages = aggregator
+                .on_submit_shares_success(&m)
+                .into_iter()
+                .map(|success| (success.channel_id, Mining::SubmitSharesSuccess(success)))
+                .collect();
+            return Ok(self.relay_to_channels(messages));
+        }
+        // Relayed as a new message so that the downstream can update the share statistics
         match &self
             .downstream_selector
             .downstream_from_channel_id(m.channel_id)
         {
-            Some(d) => Ok(SendTo::RelaySameMessage(d.clone())),
-            None => todo!(),
+            Some(d) => Ok(SendTo::RelayNewMessage(
+                d.clone(),
+                Mining::SubmitSharesSuccess(m),
+            )),
+            // The channel has been closed, eg the downstream reconnected
+            None => Ok(SendTo::None(None)),
         }
     }
 
     fn handle_submit_shares_error(
         &mut self,
-        _m: SubmitSharesError,
+        m: SubmitSharesError,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        Ok(SendTo::None(None))
+        self.health
+            .on_event(UpstreamEvent::RejectedShare, Instant::now());
+        if let Some(aggregator) = self.aggregator_for(m.channel_id) {
+            let messages = aggregator
+                .on_submit_shares_error(&m)
+                .map(|error| (error.channel_id, Mining::SubmitSharesError(error)))
+                .into_iter()
+                .collect();
+            return Ok(self.relay_to_channels(messages));
+        }
+        match self
+            .downstream_selector
+            .downstream_from_channel_id(m.channel_id)
+        {
+            Some(d) => Ok(SendTo::RelayNewMessage(
+                d,
+                Mining::SubmitSharesError(SubmitSharesError {
+                    channel_id: m.channel_id,
+                    sequence_number: m.sequence_number,
+                    error_code: m.error_code.into_static(),
+                }),
+            )),
+            None => Ok(SendTo::None(None)),
+        }
     }
 
     fn handle_new_mining_job(
@@ -614,12 +1532,11 @@ impl
         {
             Some(downstreams) => {
                 let downstream = &downstreams[0];
-                crate::add_job_id(
-                    m.job_id,
-                    self.id,
-                    downstream.safe_lock(|d| d.prev_job_id).unwrap(),
-                );
-                Ok(SendTo::RelaySameMessage(downstream.clone()))
+                // The job id is mapped and the job state updated when the job is relayed
+                Ok(SendTo::RelayNewMessage(
+                    downstream.clone(),
+                    Mining::NewMiningJob(m.as_static()),
+                ))
             }
             None => Err(Error::NoDownstreamsConnected),
         }
@@ -629,19 +1546,30 @@ impl
         &mut self,
         m: NewExtendedMiningJob,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        self.last_extended_jobs.push(m.as_static());
-        let id = self.id;
+        if let Some(aggregator) = self.aggregator_for(m.channel_id) {
+            let messages = aggregator
+                .on_new_extended_mining_job(&m)
+                .into_iter()
+                .map(|job| (job.channel_id, Mining::NewMiningJob(job)))
+                .collect();
+            return Ok(self.relay_to_channels(messages));
+        }
+        if !m.future_job {
+            // Replace the active job
+            self.last_extended_jobs.retain(|job| job.future_job);
+            self.last_extended_jobs.insert(0, m.as_static());
+        } else {
+            self.last_extended_jobs.push(m.as_static());
+        }
         let downstreams = self
             .downstream_selector
             .get_downstreams_in_channel(m.channel_id)
             .ok_or(Error::NoDownstreamsConnected)?;
 
-        let dispacther = self
-            .channel_id_to_job_dispatcher
-            .get_mut(&m.channel_id)
-            .unwrap();
+        // There is no dispatcher if no header only downstream opened a channel in the group
+        let dispacther = self.channel_id_to_job_dispatcher.get_mut(&m.channel_id);
 
-        let messages = jobs_to_relay(id, &m, downstreams, dispacther);
+        let messages = jobs_to_relay(&m, downstreams, dispacther);
 
         Ok(SendTo::Multiple(messages))
     }
@@ -650,12 +1578,25 @@ impl
         &mut self,
         m: SetNewPrevHash,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        if let Some(aggregator) = self.aggregator_for(m.channel_id) {
+            let messages = aggregator
+                .on_set_new_prev_hash(&m)?
+                .into_iter()
+                .map(|prev_hash| (prev_hash.channel_id, Mining::SetNewPrevHash(prev_hash)))
+                .collect();
+            return Ok(self.relay_to_channels(messages));
+        }
         self.last_prev_hash = Some(m.as_static());
+        // The activated job is the active job, the other future jobs are dropped
         self.last_extended_jobs = self
             .last_extended_jobs
             .clone()
             .into_iter()
             .filter(|x| x.job_id == m.job_id)
+            .map(|mut x| {
+                x.future_job = false;
+                x
+            })
             .collect();
         match (
             self.is_header_only(),
@@ -667,7 +1608,10 @@ impl
                     .get_downstreams_in_channel(m.channel_id)
                     .ok_or(Error::NoDownstreamsConnected)?;
                 // If upstream is header only one and only one downstream is in channel
-                Ok(SendTo::RelaySameMessage(downstreams[0].clone()))
+                Ok(SendTo::RelayNewMessage(
+                    downstreams[0].clone(),
+                    Mining::SetNewPrevHash(m.as_static()),
+                ))
             }
             (false, Some(JobDispatcher::Group(dispatcher))) => {
                 let mut channel_id_to_job_id = dispatcher.on_new_prev_hash(&m).unwrap();
@@ -683,7 +1627,13 @@ impl
                             for channel in d.status.get_channels().get_mut(&m.channel_id).unwrap() {
                                 match channel {
                                     DownstreamChannel::Extended(_) => todo!(),
-                                    DownstreamChannel::Group(_) => todo!(),
+                                    // Downstreams that received the extended jobs of the group
+                                    DownstreamChannel::Group(_) => {
+                                        messages.push(SendTo::RelayNewMessage(
+                                            downstream.clone(),
+                                            Mining::SetNewPrevHash(m.as_static()),
+                                        ));
+                                    }
                                     DownstreamChannel::Standard(channel) => {
                                         let new_prev_hash = SetNewPrevHash {
                                             channel_id: channel.channel_id,
@@ -707,7 +1657,23 @@ impl
                 }
                 Ok(SendTo::Multiple(messages))
             }
-            (false, None) => Ok(SendTo::None(None)),
+            // Only downstreams that received the extended jobs of the group
+            (false, None) => {
+                let downstreams = self
+                    .downstream_selector
+                    .get_downstreams_in_channel(m.channel_id)
+                    .ok_or(Error::NoDownstreamsConnected)?;
+                let messages = downstreams
+                    .iter()
+                    .map(|downstream| {
+                        SendTo::RelayNewMessage(
+                            downstream.clone(),
+                            Mining::SetNewPrevHash(m.as_static()),
+                        )
+                    })
+                    .collect();
+                Ok(SendTo::Multiple(messages))
+            }
             _ => panic!(),
         }
     }
@@ -726,8 +1692,47 @@ impl
         todo!("560")
     }
 
-    fn handle_set_target(&mut self, _m: SetTarget) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("570")
+    fn handle_set_target(&mut self, m: SetTarget) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        let downstream = match self
+            .downstream_selector
+            .downstream_from_channel_id(m.channel_id)
+        {
+            Some(d) => d,
+            // The channel has been closed, eg the downstream reconnected
+            None => return Ok(SendTo::None(None)),
+        };
+        let upstream: Target = m.maximum_target.clone().into();
+        let policy = self.target_conflict;
+        let reconciled = downstream
+            .safe_lock(|d| d.on_upstream_target(m.channel_id, upstream, policy))
+            .unwrap();
+        let (local, target) = match reconciled {
+            Some(reconciled) => reconciled,
+            None => return Ok(SendTo::None(None)),
+        };
+        if target != upstream {
+            self.target_conflicts += 1;
+        }
+        println!(
+            "Upstream {} set difficulty {} on channel {}, local difficulty {}, {}: difficulty {}",
+            self.id,
+            upstream.difficulty(),
+            m.channel_id,
+            local.difficulty(),
+            policy.as_str(),
+            target.difficulty()
+        );
+        if target == local {
+            return Ok(SendTo::None(None));
+        }
+        let set_target = SetTarget {
+            channel_id: m.channel_id,
+            maximum_target: target.into(),
+        };
+        Ok(SendTo::RelayNewMessage(
+            downstream,
+            Mining::SetTarget(set_target),
+        ))
     }
 
     fn handle_reconnect(&mut self, _m: Reconnect) -> Result<SendTo<DownstreamMiningNode>, Error> {
@@ -739,15 +1744,67 @@ impl
     }
 }
 
+/// Open a connection with the upstream at `address`
+pub(crate) async fn open_connection(
+    address: SocketAddr,
+    transport: &UpstreamTransport,
+) -> Result<(Receiver<EitherFrame>, Sender<EitherFrame>, ConnectionHandle), ()> {
+    let (receiver, sender, _, connection_handle) =
+        open_connection_with_priority(address, transport).await?;
+    Ok((receiver, sender, connection_handle))
+}
+
+/// Like `open_connection` but also return the priority sender of the connection, if the transport
+/// has one
+#[allow(clippy::type_complexity)]
+pub(crate) async fn open_connection_with_priority(
+    address: SocketAddr,
+    transport: &UpstreamTransport,
+) -> Result<
+    (
+        Receiver<EitherFrame>,
+        Sender<EitherFrame>,
+        Option<Sender<EitherFrame>>,
+        ConnectionHandle,
+    ),
+    (),
+> {
+    let socket = TcpStream::connect(address).await.map_err(|_| ())?;
+    match transport {
+        UpstreamTransport::Noise(authority_public_key, padding) => {
+            let initiator = Initiator::from_raw_k(*authority_public_key).unwrap();
+            let role = HandshakeRole::Initiator(initiator);
+            let (receiver, sender, priority, connection_handle) =
+                Connection::new_with_padding(socket, role, 10, *padding).await;
+            Ok((receiver, sender, Some(priority), connection_handle))
+        }
+        UpstreamTransport::Tls {
+            server_name,
+            config,
+        } => {
+            let (receiver, sender, connection_handle) =
+                TlsConnection::connect(socket, server_name, config.clone(), 10)
+                    .await
+                    .map_err(|_| ())?;
+            Ok((receiver, sender, None, connection_handle))
+        }
+    }
+}
+
 pub async fn scan(nodes: Vec<Arc<Mutex<UpstreamMiningNode>>>) {
     let spawn_tasks: Vec<task::JoinHandle<()>> = nodes
         .iter()
         .map(|node| {
             let node = node.clone();
             task::spawn(async move {
-                UpstreamMiningNode::setup_flag_and_version(node, None)
+                // An upstream that can not be set up is never paired, see `is_pairable`
+                if UpstreamMiningNode::setup_flag_and_version(node.clone(), None)
                     .await
-                    .unwrap();
+                    .is_err()
+                {
+                    let address = node.safe_lock(|node| node.address).unwrap();
+                    println!("Upstream {} setup failed", address);
+                }
             })
         })
         .collect();
@@ -769,6 +1826,23 @@ impl IsUpstream<DownstreamMiningNode, ProxyRemoteSelector> for UpstreamMiningNod
         vec![Protocol::MiningProtocol]
     }
 
+    fn is_pairable(&self, pair_settings: &PairSettings) -> bool {
+        match self.sv2_connection {
+            Some(sv2_connection) => {
+                let version = sv2_connection.version;
+                version >= pair_settings.min_v
+                    && version <= pair_settings.max_v
+                    && SetupConnection::check_flags(
+                        pair_settings.protocol,
+                        pair_settings.flags,
+                        sv2_connection.setup_connection_flags,
+                    )
+            }
+            // The upstream has not completed the SetupConnection
+            None => false,
+        }
+    }
+
     fn get_id(&self) -> u32 {
         self.id
     }
@@ -780,6 +1854,14 @@ impl IsUpstream<DownstreamMiningNode, ProxyRemoteSelector> for UpstreamMiningNod
     fn get_remote_selector(&mut self) -> &mut ProxyRemoteSelector {
         &mut self.downstream_selector
     }
+
+    fn is_quarantined(&self) -> bool {
+        self.health.is_quarantined()
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining
+    }
 }
 impl IsMiningUpstream<DownstreamMiningNode, ProxyRemoteSelector> for UpstreamMiningNode {
     fn total_hash_rate(&self) -> u64 {
@@ -796,41 +1878,131 @@ impl IsMiningUpstream<DownstreamMiningNode, ProxyRemoteSelector> for UpstreamMin
     }
 }
 
+/// Map the job id of a relayed message to the id used on the downstream connection, then update
+/// the job state and the share statistics of the downstream channel
+fn on_message_relayed(
+    self_mutex: &Arc<Mutex<UpstreamMiningNode>>,
+    downstream: &Arc<Mutex<DownstreamMiningNode>>,
+    message: &mut Mining,
+) {
+    let upstream_id = self_mutex.safe_lock(|self_| self_.id).unwrap();
+    downstream
+        .safe_lock(|d| {
+            d.map_job_id(upstream_id, message);
+            d.on_share_result(message);
+            d.on_job_message(message)
+        })
+        .unwrap();
+}
+
+/// Jobs for the downstreams in the group of `m`, the header only downstreams (that have only
+/// standard channels) receive a NewMiningJob whose merkle root is computed by the proxy for the
+/// extranonce of the channel, the other ones receive the extended job
 fn jobs_to_relay(
-    id: u32,
     m: &NewExtendedMiningJob,
     downstreams: &[Arc<Mutex<DownstreamMiningNode>>],
-    dispacther: &mut JobDispatcher,
+    mut dispacther: Option<&mut JobDispatcher>,
 ) -> Vec<SendTo<DownstreamMiningNode>> {
     let mut messages = Vec::with_capacity(downstreams.len());
     for downstream in downstreams {
         downstream
             .safe_lock(|d| {
-                let prev_id = d.prev_job_id;
+                let mut group_job = false;
                 for channel in d.status.get_channels().get_mut(&m.channel_id).unwrap() {
                     match channel {
                         DownstreamChannel::Extended(_) => todo!(),
                         DownstreamChannel::Group(_) => {
-                            crate::add_job_id(m.job_id, id, prev_id);
-                            messages.push(SendTo::RelaySameMessage(downstream.clone()))
+                            group_job = true;
                         }
+                        // The job state of the standard channels is updated when the job is relayed
                         DownstreamChannel::Standard(channel) => {
-                            if let JobDispatcher::Group(d) = dispacther {
-                                let job = d.on_new_extended_mining_job(m, channel).unwrap();
-                                crate::add_job_id(job.job_id, id, prev_id);
-                                let message = Mining::NewMiningJob(job);
-                                messages.push(SendTo::RelayNewMessage(downstream.clone(), message));
-                            } else {
-                                panic!()
+                            let job = match dispacther.as_mut() {
+                                Some(JobDispatcher::Group(d)) => {
+                                    d.on_new_extended_mining_job(m, channel)
+                                }
+                                // A standard channel without dispatcher is opened with an header
+                                // only upstream, that never send extended jobs
+                                _ => None,
                             };
+                            match job {
+                                Some(job) => {
+                                    let message = Mining::NewMiningJob(job);
+                                    messages
+                                        .push(SendTo::RelayNewMessage(downstream.clone(), message));
+                                }
+                                None => println!(
+                                    "Can not build the job {} for the standard channel {}",
+                                    m.job_id, channel.channel_id
+                                ),
+                            }
                         }
                     }
                 }
+                // The job id is mapped and the job state updated when the job is relayed
+                if group_job {
+                    messages.push(SendTo::RelayNewMessage(
+                        downstream.clone(),
+                        Mining::NewExtendedMiningJob(m.as_static()),
+                    ));
+                }
             })
             .unwrap();
     }
     messages
 }
+
+/// Jobs of a channel opened after the prev hash `prev_hash`, `jobs` are the active job followed by
+/// the future jobs (see `UpstreamMiningNode::last_extended_jobs`). The active job is sent as a
+/// future job activated by a SetNewPrevHash, that drop the future jobs of the downstream, so the
+/// future jobs are sent after it.
+fn jobs_for_new_channel(
+    jobs: &[NewExtendedMiningJob<'static>],
+    prev_hash: &SetNewPrevHash<'static>,
+    channel: &DownstreamChannel,
+    downstream: &Arc<Mutex<DownstreamMiningNode>>,
+    mut dispatcher: Option<&mut JobDispatcher>,
+) -> Vec<SendTo<DownstreamMiningNode>> {
+    let mut messages = Vec::with_capacity(jobs.len() + 1);
+    for job in jobs {
+        let (channel_id, job_id, message) = match (channel, dispatcher.as_mut()) {
+            (DownstreamChannel::Standard(channel), Some(JobDispatcher::Group(d))) => {
+                let new_job = if job.future_job {
+                    d.on_new_extended_mining_job(job, channel)
+                } else {
+                    d.on_new_channel_active_job(job, channel)
+                };
+                match new_job {
+                    Some(new_job) => (
+                        new_job.channel_id,
+                        new_job.job_id,
+                        Mining::NewMiningJob(new_job),
+                    ),
+                    None => continue,
+                }
+            }
+            (DownstreamChannel::Group(group_id), _) => {
+                let mut new_job = job.clone();
+                new_job.future_job = true;
+                (*group_id, job.job_id, Mining::NewExtendedMiningJob(new_job))
+            }
+            _ => continue,
+        };
+        // The job id is mapped and the job state updated when the job is relayed
+        messages.push(SendTo::RelayNewMessage(downstream.clone(), message));
+        if !job.future_job {
+            let prev_hash = Mining::SetNewPrevHash(SetNewPrevHash {
+                channel_id,
+                job_id,
+                prev_hash: prev_hash.prev_hash.clone(),
+                min_ntime: prev_hash.min_ntime,
+                nbits: prev_hash.nbits,
+            });
+            messages.push(SendTo::RelayNewMessage(downstream.clone(), prev_hash));
+        }
+    }
+    messages
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -845,7 +2017,14 @@ mod tests {
             215, 11, 47, 78, 34, 232, 25, 192, 195, 168, 170, 209, 95, 181, 40, 114, 154, 226, 176,
             190, 90, 169, 238, 89, 191, 183, 97, 63, 194, 119, 11, 31,
         ];
-        let actual = UpstreamMiningNode::new(id, address, authority_public_key, job_ids);
+        let context = ProxyContext::new(2, 2);
+        let actual = UpstreamMiningNode::new(
+            id,
+            address,
+            UpstreamTransport::Noise(authority_public_key, PaddingPolicy::None),
+            job_ids,
+            context,
+        );
 
         assert_eq!(actual.id, id);
 
@@ -866,10 +2045,14 @@ mod tests {
         // How to test
         // assert_eq!(actual.downstream_selector, ProxyRemoteSelector::new());
 
-        assert_eq!(actual.authority_public_key, authority_public_key);
+        assert!(
+            matches!(actual.transport, UpstreamTransport::Noise(key, _) if key == authority_public_key)
+        );
         assert!(actual.channel_id_to_job_dispatcher.is_empty());
         assert_eq!(actual.request_id_mapper, RequestIdMapper::new());
         assert!(actual.last_prev_hash.is_none());
         assert!(actual.last_extended_jobs.is_empty());
+        assert!(!actual.health.is_quarantined());
+        assert!(!actual.draining);
     }
 }
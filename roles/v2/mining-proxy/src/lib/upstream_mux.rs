@@ -0,0 +1,555 @@
+//! Upstream connections shared by the proxies of the same process.
+//!
+//! Large deployments run many proxy instances in one process (one per listen address, per
+//! tenant, ...) and each instance would open its own TCP and noise session with the pool. Proxies
+//! spawned with the same `SharedUpstreams` open instead a logical session on a single connection
+//! per upstream address:
+//!
+//! * the first `SetupConnection` is relayed upstream, the sessions opened later are answered with
+//!   the `SetupConnectionSuccess` received for it, so every session must use the same flags and
+//!   versions (that is always the case for the proxies of this crate)
+//! * the `request_id` of the open channel requests is replaced with an id unique on the shared
+//!   connection and restored in the response, that is relayed to the session that sent the request
+//! * the channel messages are relayed to the session that opened the channel, the messages for a
+//!   group channel to every session with a channel in the group, the other messages (eg
+//!   `Reconnect`) to every session
+//!
+//! The shared connection is closed when the last session is closed, if the connection is lost
+//! every session is closed and the proxies reconnect as usual. The sessions do not know the
+//! certificate of the upstream so `UpstreamMiningNode::certificate_expiry` is always None.
+use super::upstream_mining::{open_connection, EitherFrame, StdFrame, UpstreamTransport};
+use async_channel::{bounded, Receiver, Sender};
+use async_std::{sync::Mutex as AsyncMutex, task};
+use codec_sv2::Frame;
+use const_sv2::{
+    MESSAGE_TYPE_CLOSE_CHANNEL, MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL,
+    MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL_SUCCES, MESSAGE_TYPE_OPEN_MINING_CHANNEL_ERROR,
+    MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL, MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS,
+    MESSAGE_TYPE_SETUP_CONNECTION, MESSAGE_TYPE_SETUP_CONNECTION_ERROR,
+    MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS, SV2_FRAME_HEADER_SIZE,
+};
+use network_helpers::ConnectionHandle;
+use roles_logic_sv2::utils::{Id, Mutex};
+use std::{collections::HashMap, convert::TryInto, net::SocketAddr, sync::Arc};
+
+/// Capacity of the channels between the shared connection and a session
+const SESSION_CAPACITY: usize = 10;
+
+type Connections = Arc<AsyncMutex<HashMap<SocketAddr, Arc<Mutex<SharedConnection>>>>>;
+
+/// Registry of the shared upstream connections, clone it in every proxy that must share them
+/// (see `ProxyBuilder::shared_upstreams`)
+#[derive(Clone, Default)]
+pub struct SharedUpstreams {
+    // The lock is held while connecting so that concurrent sessions do not open more than one
+    // connection with the same upstream
+    connections: Connections,
+}
+
+impl std::fmt::Debug for SharedUpstreams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedUpstreams").finish()
+    }
+}
+
+impl SharedUpstreams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open a session on the connection with the upstream at `address`, the connection is opened
+    /// with `transport` if there is not one already. The session is used like a connection
+    /// returned by `network_helpers::Connection`.
+    pub async fn session(
+        &self,
+        address: SocketAddr,
+        transport: &UpstreamTransport,
+    ) -> Result<(Receiver<EitherFrame>, Sender<EitherFrame>), ()> {
+        let mut connections = self.connections.lock().await;
+        let connection = match connections.get(&address) {
+            Some(connection) => connection.clone(),
+            None => {
+                let (receiver, sender, connection_handle) =
+                    open_connection(address, transport).await?;
+                let connection =
+                    Arc::new(Mutex::new(SharedConnection::new(sender, connection_handle)));
+                connections.insert(address, connection.clone());
+                let connections = self.connections.clone();
+                let shared = connection.clone();
+                task::spawn(async move {
+                    relay_upstream_frames(shared.clone(), receiver).await;
+                    close_connection(&connections, address, &shared).await;
+                });
+                connection
+            }
+        };
+        let (to_session, session_receiver) = bounded(SESSION_CAPACITY);
+        let (session_sender, from_session) = bounded(SESSION_CAPACITY);
+        let session_id = connection
+            .safe_lock(|c| c.add_session(to_session, from_session.clone()))
+            .unwrap();
+        drop(connections);
+
+        let connections = self.connections.clone();
+        task::spawn(async move {
+            while let Ok(frame) = from_session.recv().await {
+                if relay_session_frame(&connection, session_id, frame)
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            remove_session(&connections, address, &connection, session_id).await;
+        });
+        Ok((session_receiver, session_sender))
+    }
+}
+
+/// A serialized frame, it can be rewritten in place and copied to more than one session
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RawFrame {
+    msg_type: u8,
+    channel_msg: bool,
+    bytes: Vec<u8>,
+}
+
+impl RawFrame {
+    fn new(frame: EitherFrame) -> Result<Self, ()> {
+        let frame: StdFrame = frame.try_into()?;
+        let header = frame.get_header().ok_or(())?;
+        let mut bytes = vec![0; frame.encoded_length()].into();
+        frame.serialize(&mut bytes).map_err(|_| ())?;
+        let bytes: Vec<u8> = bytes.into();
+        Ok(Self {
+            msg_type: header.msg_type(),
+            channel_msg: header.channel_msg(),
+            bytes,
+        })
+    }
+
+    /// The `u32` field of the payload that start at `offset`, the ids are always `u32` so this is
+    /// enough to route the frames without deserializing them
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let start = SV2_FRAME_HEADER_SIZE + offset;
+        let bytes = self.bytes.get(start..start + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn set_u32_at(&mut self, offset: usize, value: u32) {
+        let start = SV2_FRAME_HEADER_SIZE + offset;
+        if let Some(bytes) = self.bytes.get_mut(start..start + 4) {
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// The last `u32` of the payload
+    fn last_u32(&self) -> Option<u32> {
+        let offset = self.bytes.len().checked_sub(SV2_FRAME_HEADER_SIZE + 4)?;
+        self.u32_at(offset)
+    }
+
+    fn to_frame(&self) -> EitherFrame {
+        StdFrame::from_bytes_unchecked(self.bytes.clone().into()).into()
+    }
+}
+
+/// Which session receive the frames of the upstream, kept apart from the IO so that it can be
+/// tested
+#[derive(Debug, Default)]
+struct Routes {
+    request_ids: Id,
+    /// Shared connection request id -> session id and request id of the session
+    requests: HashMap<u32, (u32, u32)>,
+    /// Channel or group channel id -> sessions with the channel
+    channels: HashMap<u32, Vec<u32>>,
+}
+
+impl Routes {
+    /// Replace the request id of an open channel request sent by `session_id`
+    fn on_open_channel(&mut self, session_id: u32, request: &mut RawFrame) {
+        if let Some(request_id) = request.u32_at(0) {
+            let shared_request_id = self.request_ids.next();
+            self.requests
+                .insert(shared_request_id, (session_id, request_id));
+            request.set_u32_at(0, shared_request_id);
+        }
+    }
+
+    /// Restore the request id of the response to an open channel request and register the opened
+    /// channel, return the session that sent the request
+    fn on_open_channel_response(&mut self, response: &mut RawFrame) -> Option<u32> {
+        let (session_id, request_id) = self.requests.remove(&response.u32_at(0)?)?;
+        response.set_u32_at(0, request_id);
+        match response.msg_type {
+            MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS => {
+                self.add_channel(session_id, response.u32_at(4)?);
+                // group_channel_id is the last field
+                self.add_channel(session_id, response.last_u32()?);
+            }
+            MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL_SUCCES => {
+                self.add_channel(session_id, response.u32_at(4)?);
+            }
+            _ => (),
+        }
+        Some(session_id)
+    }
+
+    fn add_channel(&mut self, session_id: u32, channel_id: u32) {
+        let sessions = self.channels.entry(channel_id).or_default();
+        if !sessions.contains(&session_id) {
+            sessions.push(session_id);
+        }
+    }
+
+    fn remove_channel(&mut self, session_id: u32, channel_id: u32) {
+        if let Some(sessions) = self.channels.get_mut(&channel_id) {
+            sessions.retain(|id| *id != session_id);
+            if sessions.is_empty() {
+                self.channels.remove(&channel_id);
+            }
+        }
+    }
+
+    fn sessions(&self, channel_id: u32) -> &[u32] {
+        self.channels
+            .get(&channel_id)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    fn remove_session(&mut self, session_id: u32) {
+        self.requests.retain(|_, (id, _)| *id != session_id);
+        self.channels.retain(|_, sessions| {
+            sessions.retain(|id| *id != session_id);
+            !sessions.is_empty()
+        });
+    }
+}
+
+#[derive(Debug)]
+enum SetupState {
+    NotSent,
+    /// Sessions waiting for the response to the relayed SetupConnection
+    Pending(Vec<u32>),
+    /// The SetupConnectionSuccess sent to every new session
+    Done(RawFrame),
+}
+
+#[derive(Debug)]
+struct Session {
+    to_session: Sender<EitherFrame>,
+    from_session: Receiver<EitherFrame>,
+}
+
+#[derive(Debug)]
+struct SharedConnection {
+    sender: Sender<EitherFrame>,
+    connection_handle: Option<ConnectionHandle>,
+    session_ids: Id,
+    sessions: HashMap<u32, Session>,
+    setup: SetupState,
+    routes: Routes,
+}
+
+impl SharedConnection {
+    fn new(sender: Sender<EitherFrame>, connection_handle: ConnectionHandle) -> Self {
+        Self {
+            sender,
+            connection_handle: Some(connection_handle),
+            session_ids: Id::new(),
+            sessions: HashMap::new(),
+            setup: SetupState::NotSent,
+            routes: Routes::default(),
+        }
+    }
+
+    fn add_session(
+        &mut self,
+        to_session: Sender<EitherFrame>,
+        from_session: Receiver<EitherFrame>,
+    ) -> u32 {
+        let session_id = self.session_ids.next();
+        let session = Session {
+            to_session,
+            from_session,
+        };
+        self.sessions.insert(session_id, session);
+        session_id
+    }
+
+    /// Copies of `frame` for `session_ids`
+    fn to_sessions(
+        &self,
+        session_ids: &[u32],
+        frame: &RawFrame,
+    ) -> Vec<(Sender<EitherFrame>, EitherFrame)> {
+        session_ids
+            .iter()
+            .filter_map(|id| self.sessions.get(id))
+            .map(|session| (session.to_session.clone(), frame.to_frame()))
+            .collect()
+    }
+
+    /// Where a frame received from the upstream must be relayed
+    fn route_upstream_frame(
+        &mut self,
+        mut frame: RawFrame,
+    ) -> Vec<(Sender<EitherFrame>, EitherFrame)> {
+        match frame.msg_type {
+            MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS | MESSAGE_TYPE_SETUP_CONNECTION_ERROR => {
+                let pending = match std::mem::replace(&mut self.setup, SetupState::NotSent) {
+                    SetupState::Pending(pending) => pending,
+                    _ => Vec::new(),
+                };
+                let sends = self.to_sessions(&pending, &frame);
+                if frame.msg_type == MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS {
+                    self.setup = SetupState::Done(frame);
+                }
+                sends
+            }
+            MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS
+            | MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL_SUCCES
+            | MESSAGE_TYPE_OPEN_MINING_CHANNEL_ERROR => {
+                match self.routes.on_open_channel_response(&mut frame) {
+                    Some(session_id) => self.to_sessions(&[session_id], &frame),
+                    None => Vec::new(),
+                }
+            }
+            _ if frame.channel_msg => match frame.u32_at(0) {
+                Some(channel_id) => self.to_sessions(self.routes.sessions(channel_id), &frame),
+                None => Vec::new(),
+            },
+            _ => {
+                let session_ids: Vec<u32> = self.sessions.keys().copied().collect();
+                self.to_sessions(&session_ids, &frame)
+            }
+        }
+    }
+
+    /// Where a frame received from `session_id` must be relayed, None if the frame is not relayed
+    fn route_session_frame(
+        &mut self,
+        session_id: u32,
+        mut frame: RawFrame,
+    ) -> Option<(Sender<EitherFrame>, EitherFrame)> {
+        match frame.msg_type {
+            MESSAGE_TYPE_SETUP_CONNECTION => match &mut self.setup {
+                SetupState::NotSent => self.setup = SetupState::Pending(vec![session_id]),
+                // The session get the response of the SetupConnection already relayed
+                SetupState::Pending(pending) => {
+                    pending.push(session_id);
+                    return None;
+                }
+                SetupState::Done(success) => {
+                    let to_session = self.sessions.get(&session_id)?.to_session.clone();
+                    return Some((to_session, success.to_frame()));
+                }
+            },
+            MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL
+            | MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL => {
+                self.routes.on_open_channel(session_id, &mut frame)
+            }
+            MESSAGE_TYPE_CLOSE_CHANNEL => {
+                if let Some(channel_id) = frame.u32_at(0) {
+                    self.routes.remove_channel(session_id, channel_id);
+                }
+            }
+            _ => (),
+        }
+        Some((self.sender.clone(), frame.to_frame()))
+    }
+}
+
+async fn relay_upstream_frames(
+    connection: Arc<Mutex<SharedConnection>>,
+    receiver: Receiver<EitherFrame>,
+) {
+    while let Ok(frame) = receiver.recv().await {
+        let frame = match RawFrame::new(frame) {
+            Ok(frame) => frame,
+            Err(()) => continue,
+        };
+        let sends = connection
+            .safe_lock(|c| c.route_upstream_frame(frame))
+            .unwrap();
+        for (to_session, frame) in sends {
+            // A closed session is removed by its own task
+            let _ = to_session.send(frame).await;
+        }
+    }
+}
+
+async fn relay_session_frame(
+    connection: &Arc<Mutex<SharedConnection>>,
+    session_id: u32,
+    frame: EitherFrame,
+) -> Result<(), ()> {
+    let frame = RawFrame::new(frame)?;
+    let next = connection
+        .safe_lock(|c| c.route_session_frame(session_id, frame))
+        .unwrap();
+    match next {
+        Some((sender, frame)) => sender.send(frame).await.map_err(|_| ()),
+        None => Ok(()),
+    }
+}
+
+/// Remove a closed session, the shared connection is closed with the last session
+async fn remove_session(
+    connections: &Connections,
+    address: SocketAddr,
+    connection: &Arc<Mutex<SharedConnection>>,
+    session_id: u32,
+) {
+    // Locked before the connection so that no session is added while the connection is closed
+    let mut connections = connections.lock().await;
+    let last_session = connection
+        .safe_lock(|c| {
+            if let Some(session) = c.sessions.remove(&session_id) {
+                session.to_session.close();
+                session.from_session.close();
+            }
+            c.routes.remove_session(session_id);
+            match c.sessions.is_empty() {
+                true => c.connection_handle.take().map(|h| (h, c.sender.clone())),
+                false => None,
+            }
+        })
+        .unwrap();
+    if let Some((connection_handle, sender)) = last_session {
+        if is_registered(&connections, address, connection) {
+            connections.remove(&address);
+        }
+        drop(connections);
+        connection_handle.shutdown(&sender).await;
+    }
+}
+
+/// The upstream closed the connection, close every session
+async fn close_connection(
+    connections: &Connections,
+    address: SocketAddr,
+    connection: &Arc<Mutex<SharedConnection>>,
+) {
+    let mut connections = connections.lock().await;
+    if is_registered(&connections, address, connection) {
+        connections.remove(&address);
+    }
+    connection
+        .safe_lock(|c| {
+            c.sender.close();
+            for (_, session) in c.sessions.drain() {
+                session.to_session.close();
+                session.from_session.close();
+            }
+        })
+        .unwrap();
+}
+
+fn is_registered(
+    connections: &HashMap<SocketAddr, Arc<Mutex<SharedConnection>>>,
+    address: SocketAddr,
+    connection: &Arc<Mutex<SharedConnection>>,
+) -> bool {
+    connections
+        .get(&address)
+        .map(|registered| Arc::ptr_eq(registered, connection))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use binary_sv2::B032;
+    use roles_logic_sv2::{
+        mining_sv2::{OpenStandardMiningChannel, OpenStandardMiningChannelSuccess, SetNewPrevHash},
+        parsers::{Mining, PoolMessages},
+    };
+
+    fn raw(message: Mining<'static>) -> RawFrame {
+        let frame: StdFrame = PoolMessages::Mining(message).try_into().unwrap();
+        RawFrame::new(frame.into()).unwrap()
+    }
+
+    fn open_channel(request_id: u32) -> RawFrame {
+        raw(Mining::OpenStandardMiningChannel(
+            OpenStandardMiningChannel {
+                request_id: request_id.into(),
+                user_identity: "user".to_string().try_into().unwrap(),
+                nominal_hash_rate: 10.0,
+                max_target: [0xff; 32].to_vec().try_into().unwrap(),
+            },
+        ))
+    }
+
+    fn open_channel_success(request_id: u32, channel_id: u32, group: u32) -> RawFrame {
+        let extranonce_prefix: B032 = vec![1, 2].try_into().unwrap();
+        raw(Mining::OpenStandardMiningChannelSuccess(
+            OpenStandardMiningChannelSuccess {
+                request_id: request_id.into(),
+                channel_id,
+                target: [0xff; 32].to_vec().try_into().unwrap(),
+                extranonce_prefix,
+                group_channel_id: group,
+            },
+        ))
+    }
+
+    fn prev_hash(channel_id: u32) -> RawFrame {
+        raw(Mining::SetNewPrevHash(SetNewPrevHash {
+            channel_id,
+            job_id: 1,
+            prev_hash: [0; 32].to_vec().try_into().unwrap(),
+            min_ntime: 0,
+            nbits: 0,
+        }))
+    }
+
+    #[test]
+    fn remaps_request_ids() {
+        let mut routes = Routes::default();
+        // Two sessions with the same request id
+        let mut first = open_channel(1);
+        let mut second = open_channel(1);
+        routes.on_open_channel(1, &mut first);
+        routes.on_open_channel(2, &mut second);
+        assert_ne!(first.u32_at(0), second.u32_at(0));
+
+        let shared_request_id = second.u32_at(0).unwrap();
+        let mut response = open_channel_success(shared_request_id, 7, 3);
+        assert_eq!(routes.on_open_channel_response(&mut response), Some(2));
+        assert_eq!(response.u32_at(0), Some(1));
+        // Already answered
+        let mut response = open_channel_success(shared_request_id, 7, 3);
+        assert_eq!(routes.on_open_channel_response(&mut response), None);
+
+        let mut response = open_channel_success(first.u32_at(0).unwrap(), 8, 3);
+        assert_eq!(routes.on_open_channel_response(&mut response), Some(1));
+        assert_eq!(routes.sessions(7), &[2]);
+        assert_eq!(routes.sessions(8), &[1]);
+        assert_eq!(routes.sessions(3), &[2, 1]);
+    }
+
+    #[test]
+    fn routes_channel_messages() {
+        let mut routes = Routes::default();
+        let mut request = open_channel(1);
+        routes.on_open_channel(1, &mut request);
+        let mut response = open_channel_success(request.u32_at(0).unwrap(), 7, 3);
+        routes.on_open_channel_response(&mut response);
+
+        let frame = prev_hash(7);
+        assert!(frame.channel_msg);
+        assert_eq!(routes.sessions(frame.u32_at(0).unwrap()), &[1]);
+        assert_eq!(
+            routes.sessions(prev_hash(9).u32_at(0).unwrap()),
+            &[] as &[u32]
+        );
+
+        routes.remove_channel(1, 7);
+        assert!(routes.sessions(7).is_empty());
+        routes.remove_session(1);
+        assert!(routes.sessions(3).is_empty());
+    }
+}
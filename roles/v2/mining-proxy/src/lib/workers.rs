@@ -0,0 +1,147 @@
+//! Downstream connections served by dedicated worker threads.
+//!
+//! By default every accepted downstream is served by a task of the async-std executor. With
+//! `ProxyBuilder::listener_workers` the listener dispatch the accepted connections to N workers,
+//! every worker is an OS thread that run its own executor, so the handling of the downstream
+//! messages (parsing, routing, share validation) is spread on N threads that do not compete with
+//! the rest of the proxy. The socket reader and writer tasks of the connections
+//! (`network_helpers`) still run on the async-std executor.
+//!
+//! The worker of a connection is chosen by `Sharding`: in accept order, or by the IP of the peer
+//! so that the connections of a farm behind one address are always served by the same worker.
+//! With `ProxyBuilder::proxy_protocol` the peer is the load balancer, so the accept order should
+//! be used.
+use super::{downstream_mining::on_downstream_connection, proxy_context::ProxyContext};
+use crate::downstream_mining::DownstreamMiningNode;
+use async_channel::{unbounded, Sender};
+use async_std::net::TcpStream;
+use futures::{executor::LocalPool, task::LocalSpawnExt};
+use roles_logic_sv2::utils::Mutex;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    sync::Arc,
+};
+
+/// How the listener choose the worker of an accepted connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sharding {
+    /// Round robin
+    AcceptOrder,
+    /// The connections from the same IP are served by the same worker
+    PeerIp,
+}
+
+impl Sharding {
+    /// Worker of the `accepted`th connection, that come from `peer`
+    pub fn worker(&self, accepted: usize, peer: Option<IpAddr>, workers: usize) -> usize {
+        match (self, peer) {
+            (Sharding::PeerIp, Some(ip)) => {
+                // DefaultHasher::new use fixed keys, an IP is always mapped to the same worker
+                let mut hasher = DefaultHasher::new();
+                ip.hash(&mut hasher);
+                (hasher.finish() % workers as u64) as usize
+            }
+            _ => accepted % workers,
+        }
+    }
+}
+
+impl std::str::FromStr for Sharding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accept-order" => Ok(Sharding::AcceptOrder),
+            "peer-ip" => Ok(Sharding::PeerIp),
+            _ => Err(format!("Unknown sharding {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Workers {
+    senders: Vec<Sender<TcpStream>>,
+    sharding: Sharding,
+    accepted: usize,
+}
+
+impl Workers {
+    /// Start `workers` threads, they stop when `Workers` is dropped and their connections are
+    /// closed
+    pub(crate) fn spawn(
+        workers: usize,
+        sharding: Sharding,
+        downstreams: Arc<Mutex<Vec<Arc<Mutex<DownstreamMiningNode>>>>>,
+        context: ProxyContext,
+    ) -> Self {
+        let senders = (0..workers.max(1))
+            .map(|index| {
+                let (sender, receiver) = unbounded::<TcpStream>();
+                let downstreams = downstreams.clone();
+                let context = context.clone();
+                std::thread::Builder::new()
+                    .name(format!("downstream-worker-{}", index))
+                    .spawn(move || {
+                        let mut pool = LocalPool::new();
+                        let spawner = pool.spawner();
+                        pool.run_until(async move {
+                            while let Ok(stream) = receiver.recv().await {
+                                // Can not fail, the pool is running
+                                spawner
+                                    .spawn_local(on_downstream_connection(
+                                        stream,
+                                        downstreams.clone(),
+                                        context.clone(),
+                                    ))
+                                    .unwrap();
+                            }
+                        });
+                        // The listener stopped, serve the connections until they are closed
+                        pool.run();
+                    })
+                    .expect("Can not spawn a downstream worker");
+                sender
+            })
+            .collect();
+        Self {
+            senders,
+            sharding,
+            accepted: 0,
+        }
+    }
+
+    pub(crate) async fn dispatch(&mut self, stream: TcpStream) {
+        let peer = stream.peer_addr().ok().map(|peer| peer.ip());
+        let worker = self
+            .sharding
+            .worker(self.accepted, peer, self.senders.len());
+        self.accepted = self.accepted.wrapping_add(1);
+        // The workers run as long as their sender is alive
+        let _ = self.senders[worker].send(stream).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shards_connections() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let workers: Vec<usize> = (0..6)
+            .map(|accepted| Sharding::AcceptOrder.worker(accepted, Some(ip), 4))
+            .collect();
+        assert_eq!(workers, vec![0, 1, 2, 3, 0, 1]);
+
+        let worker = Sharding::PeerIp.worker(0, Some(ip), 4);
+        assert!(worker < 4);
+        assert!((1..10).all(|accepted| Sharding::PeerIp.worker(accepted, Some(ip), 4) == worker));
+        // Without the peer address the connections are dispatched in accept order
+        assert_eq!(Sharding::PeerIp.worker(5, None, 4), 1);
+
+        assert_eq!("peer-ip".parse(), Ok(Sharding::PeerIp));
+        assert!("random".parse::<Sharding>().is_err());
+    }
+}
@@ -17,94 +17,80 @@
 //! A Downstream that signal the capacity to handle group channels can open more than one channel.
 //! A Downstream that signal the incapacity to handle group channels can open only one channel.
 //!
-mod lib;
-use std::net::{IpAddr, SocketAddr};
-
-use lib::upstream_mining::UpstreamMiningNode;
-use once_cell::sync::Lazy;
-use serde::Deserialize;
-use std::str::FromStr;
-
+use codec_sv2::PaddingPolicy;
+use mining_proxy::{
+    labels::Labels, share_rate::ShareRateLimit, upstream_dns::UpstreamHostname, workers::Sharding,
+    Proxy,
+};
+use network_helpers::{rustls::ClientConfig, tls_client_config};
 use roles_logic_sv2::{
-    routing_logic::{CommonRoutingLogic, MiningProxyRoutingLogic, MiningRoutingLogic},
-    selectors::{GeneralMiningSelector, UpstreamMiningSelctor},
-    utils::{Id, Mutex},
+    handlers::mining::SupportedChannelTypes,
+    utils::{ChannelTargetPolicy, Target},
+};
+use serde::Deserialize;
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
-use std::{collections::HashMap, sync::Arc};
-
-type RLogic = MiningProxyRoutingLogic<
-    crate::lib::downstream_mining::DownstreamMiningNode,
-    crate::lib::upstream_mining::UpstreamMiningNode,
-    crate::lib::upstream_mining::ProxyRemoteSelector,
->;
-
-pub fn max_supported_version() -> u16 {
-    let config_file = std::fs::read_to_string("proxy-config.toml").unwrap();
-    let config: Config = toml::from_str(&config_file).unwrap();
-    config.max_supported_version
-}
-pub fn min_supported_version() -> u16 {
-    let config_file = std::fs::read_to_string("proxy-config.toml").unwrap();
-    let config: Config = toml::from_str(&config_file).unwrap();
-    config.min_supported_version
-}
-
-/// Panic whene we are looking one of this 2 global mutex would force the proxy to go down as every
-/// part of the program depend on them.
-/// SAFTEY note: we use global mutable memory instead of a dedicated struct that use a dedicated
-/// task to change the mutable state and communicate with the other parts of the program via
-/// messages cause it is impossible for a task to panic while is using one of the two below Mutex.
-/// So it make sense to use shared mutable memory to lower the complexity of the codebase and to
-/// have some performance gain.
-static ROUTING_LOGIC: Lazy<Mutex<RLogic>> = Lazy::new(|| Mutex::new(initialize_r_logic()));
-static JOB_ID_TO_UPSTREAM_ID: Lazy<Mutex<HashMap<u32, u32>>> =
-    Lazy::new(|| Mutex::new(HashMap::new()));
-
-async fn initialize_upstreams() {
-    let upstreams = ROUTING_LOGIC
-        .safe_lock(|r_logic| r_logic.upstream_selector.upstreams.clone())
-        .unwrap();
-    crate::lib::upstream_mining::scan(upstreams).await;
-}
-
-pub fn get_routing_logic() -> MiningRoutingLogic<
-    crate::lib::downstream_mining::DownstreamMiningNode,
-    crate::lib::upstream_mining::UpstreamMiningNode,
-    crate::lib::upstream_mining::ProxyRemoteSelector,
-    RLogic,
-> {
-    MiningRoutingLogic::Proxy(&ROUTING_LOGIC)
-}
-pub fn get_common_routing_logic() -> CommonRoutingLogic<RLogic> {
-    CommonRoutingLogic::Proxy(&ROUTING_LOGIC)
-}
-
-pub fn upstream_from_job_id(job_id: u32) -> Option<Arc<Mutex<UpstreamMiningNode>>> {
-    let upstream_id: u32;
-    upstream_id = JOB_ID_TO_UPSTREAM_ID
-        .safe_lock(|x| *x.get(&job_id).unwrap())
-        .unwrap();
-    ROUTING_LOGIC
-        .safe_lock(|rlogic| rlogic.upstream_selector.get_upstream(upstream_id))
-        .unwrap()
-}
 
-pub fn add_job_id(job_id: u32, up_id: u32, prev_job_id: Option<u32>) {
-    if let Some(prev_job_id) = prev_job_id {
-        JOB_ID_TO_UPSTREAM_ID
-            .safe_lock(|x| x.remove(&prev_job_id))
-            .unwrap();
-    }
-    JOB_ID_TO_UPSTREAM_ID
-        .safe_lock(|x| x.insert(job_id, up_id))
-        .unwrap();
-}
+const CONFIG_PATH: &str = "proxy-config.toml";
 
 #[derive(Debug, Deserialize)]
 pub struct UpstreamValues {
+    /// IP or hostname of the upstream, an hostname is resolved again every `dns_refresh_secs`
     address: String,
     port: u16,
-    pub_key: [u8; 32],
+    /// Authority key of the noise upstreams
+    pub_key: Option<[u8; 32]>,
+    /// If set the upstream is reached through TLS and its certificate must be valid for this name
+    tls_server_name: Option<String>,
+    /// PEM file with the CA certificates trusted for the TLS upstream, default to the webpki roots
+    tls_ca_cert: Option<String>,
+    /// Operator labels of the upstream (`name`, `region`, `tier`), see `mining_proxy::labels`
+    labels: Option<Labels>,
+    /// Seconds between two resolutions of the upstream hostname, default to 60
+    dns_refresh_secs: Option<u64>,
+    /// Address used when the upstream hostname reconnect, `sticky` (default) keep the current
+    /// address, `migrate` follow the DNS records, see `mining_proxy::upstream_dns`
+    endpoint_pinning: Option<String>,
+    /// If set the frames sent to the noise upstream are padded to a multiple of this len, the
+    /// upstream must support the padding frames
+    padding_bucket: Option<usize>,
+    /// How a SetTarget of the upstream is reconciled with the local target of the channel:
+    /// `honor-stricter` (default), `honor-upstream` or `scale-down`
+    target_conflict: Option<String>,
+}
+
+impl UpstreamValues {
+    fn tls_config(&self) -> Arc<ClientConfig> {
+        let ca_certs = self
+            .tls_ca_cert
+            .as_ref()
+            .map(|path| std::fs::read(path).unwrap());
+        tls_client_config(ca_certs.as_deref()).unwrap()
+    }
+
+    /// Address of the upstream and the hostname that it has been resolved from, if `address` is
+    /// not an IP
+    async fn resolve(&self) -> (SocketAddr, Option<UpstreamHostname>) {
+        if let Ok(ip) = IpAddr::from_str(&self.address) {
+            return (SocketAddr::new(ip, self.port), None);
+        }
+        let mut hostname = UpstreamHostname::new(self.address.clone(), self.port);
+        if let Some(secs) = self.dns_refresh_secs {
+            hostname.interval = Duration::from_secs(secs);
+        }
+        if let Some(pinning) = &self.endpoint_pinning {
+            hostname.pinning = pinning.parse().unwrap();
+        }
+        let addresses = hostname.resolve().await.unwrap();
+        let address = *addresses
+            .first()
+            .expect("Upstream hostname without addresses");
+        (address, Some(hostname))
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -114,33 +100,77 @@ pub struct Config {
     listen_mining_port: u16,
     max_supported_version: u16,
     min_supported_version: u16,
+    /// If set the proxy state is saved here on shutdown and restored on startup
+    snapshot_path: Option<String>,
+    /// Difficulty of the downstream channels that do not declare their hash rate
+    initial_difficulty: Option<f64>,
+    /// Downstream channels that declare their hash rate get a target that make them submit this
+    /// many shares per minute
+    shares_per_minute: Option<f32>,
+    /// No downstream channel get a difficulty lower than this
+    min_difficulty: Option<f64>,
+    /// If set the downstream standard channels are served by one extended channel per upstream
+    /// opened with this nominal hash rate
+    aggregated_hash_rate: Option<f32>,
+    /// If set the shares are relayed upstream in batches, every batch contain the shares received
+    /// within this many milliseconds
+    share_batch_window_ms: Option<u64>,
+    /// Shares for the jobs replaced by a prev hash are accepted for this many seconds after the
+    /// prev hash, default to 5
+    stale_share_grace_secs: Option<u64>,
+    /// Max number of jobs kept for every group channel, 0 means no limit, default to 64
+    job_history: Option<usize>,
+    /// Seconds given to the upstream to answer an open channel request, default to 30
+    request_timeout_secs: Option<u64>,
+    /// If set a standard channel that submit more than this many shares in a minute get a higher
+    /// difficulty, see `mining_proxy::share_rate`
+    max_shares_per_minute: Option<u32>,
+    /// Times that the difficulty of a flooding channel is raised before closing it, default to 3
+    max_difficulty_raises: Option<u32>,
+    /// If true the aggregated channels survive the reconnections with the upstream
+    resume_sessions: Option<bool>,
+    /// If set the channel requests received while no upstream is available are parked for this
+    /// many seconds, see `mining_proxy::parked_requests`
+    park_timeout_secs: Option<u64>,
+    /// Max number of parked channel requests, default to 1000
+    max_parked_requests: Option<usize>,
+    /// If true the proxy is behind a load balancer that send the PROXY protocol header
+    proxy_protocol: Option<bool>,
+    /// If set the downstream connections are served by this many worker threads (eg the number of
+    /// cores), see `mining_proxy::workers`
+    listener_workers: Option<usize>,
+    /// Worker of a downstream connection, `accept-order` (default) or `peer-ip`
+    listener_sharding: Option<String>,
+    /// Channel types accepted from every downstream: `standard`, `extended`, `group` or
+    /// `group-and-extended`, default to `group`. The header only downstreams always open standard
+    /// channels
+    downstream_channel_types: Option<String>,
+    /// If set the control API is served on 127.0.0.1 at this port, see `mining_proxy::admin`
+    admin_port: Option<u16>,
+    /// Operator labels of the listener (`name`, `region`, `tier`), see `mining_proxy::labels`
+    labels: Option<Labels>,
 }
 
-pub fn initialize_r_logic() -> RLogic {
-    let config_file = std::fs::read_to_string("proxy-config.toml").unwrap();
-    let config: Config = toml::from_str(&config_file).unwrap();
-    let upstreams = config.upstreams;
-    let job_ids = Arc::new(Mutex::new(Id::new()));
-    let upstream_mining_nodes: Vec<Arc<Mutex<UpstreamMiningNode>>> = upstreams
-        .iter()
-        .enumerate()
-        .map(|(index, upstream)| {
-            let socket =
-                SocketAddr::new(IpAddr::from_str(&upstream.address).unwrap(), upstream.port);
-            Arc::new(Mutex::new(UpstreamMiningNode::new(
-                index as u32,
-                socket,
-                upstream.pub_key,
-                job_ids.clone(),
-            )))
+impl Config {
+    fn load() -> Result<Self, String> {
+        let config_file = std::fs::read_to_string(CONFIG_PATH).map_err(|e| e.to_string())?;
+        toml::from_str(&config_file).map_err(|e| e.to_string())
+    }
+
+    /// Target policy of the downstream channels, None if no option is set so that the proxy relay
+    /// the upstream targets
+    fn target_policy(&self) -> Option<ChannelTargetPolicy> {
+        if self.initial_difficulty.is_none()
+            && self.shares_per_minute.is_none()
+            && self.min_difficulty.is_none()
+        {
+            return None;
+        }
+        Some(ChannelTargetPolicy {
+            initial_target: Target::from_difficulty(self.initial_difficulty.unwrap_or(0.0)),
+            shares_per_minute: self.shares_per_minute,
+            min_difficulty: self.min_difficulty.unwrap_or(0.0),
         })
-        .collect();
-    //crate::lib::upstream_mining::scan(upstream_mining_nodes.clone()).await;
-    let upstream_selector = GeneralMiningSelector::new(upstream_mining_nodes);
-    MiningProxyRoutingLogic {
-        upstream_selector,
-        downstream_id_generator: Id::new(),
-        downstream_to_upstream_map: std::collections::HashMap::new(),
     }
 }
 
@@ -156,15 +186,116 @@ pub fn initialize_r_logic() -> RLogic {
 ///    upstream_mining::UpstreamMiningNode begin
 #[async_std::main]
 async fn main() {
-    // Scan all the upstreams and map them
-    let config_file = std::fs::read_to_string("proxy-config.toml").unwrap();
-    let config: Config = toml::from_str(&config_file).unwrap();
-    initialize_upstreams().await;
-
-    // Wait for downstream connection
+    let config = Config::load().unwrap();
     let socket = SocketAddr::new(
         IpAddr::from_str(&config.listen_address).unwrap(),
         config.listen_mining_port,
     );
-    crate::lib::downstream_mining::listen_for_downstream_mining(socket).await
+    let mut builder = Proxy::builder()
+        .listen(socket)
+        .supported_versions(config.min_supported_version, config.max_supported_version);
+    if let Some(path) = &config.snapshot_path {
+        builder = builder.snapshot(path);
+    }
+    if let Some(policy) = config.target_policy() {
+        builder = builder.channel_target_policy(policy);
+    }
+    if let Some(hash_rate) = config.aggregated_hash_rate {
+        builder = builder.aggregate_standard_channels(hash_rate);
+    }
+    if let Some(window) = config.share_batch_window_ms {
+        builder = builder.batch_shares(Duration::from_millis(window));
+    }
+    if let Some(grace) = config.stale_share_grace_secs {
+        builder = builder.stale_share_grace_period(Duration::from_secs(grace));
+    }
+    if let Some(capacity) = config.job_history {
+        builder = builder.job_history(capacity);
+    }
+    if let Some(timeout) = config.request_timeout_secs {
+        builder = builder.request_timeout(Duration::from_secs(timeout));
+    }
+    if let Some(max_shares_per_minute) = config.max_shares_per_minute {
+        builder = builder.share_rate_limit(ShareRateLimit {
+            max_shares_per_minute,
+            max_escalations: config.max_difficulty_raises.unwrap_or(3),
+        });
+    }
+    if config.resume_sessions.unwrap_or(false) {
+        builder = builder.resume_sessions();
+    }
+    if let Some(timeout) = config.park_timeout_secs {
+        builder = builder.park_channel_requests(
+            config.max_parked_requests.unwrap_or(1000),
+            Duration::from_secs(timeout),
+        );
+    }
+    if config.proxy_protocol.unwrap_or(false) {
+        builder = builder.proxy_protocol();
+    }
+    if let Some(workers) = config.listener_workers {
+        let sharding = match &config.listener_sharding {
+            Some(sharding) => sharding.parse().unwrap(),
+            None => Sharding::AcceptOrder,
+        };
+        builder = builder.listener_workers(workers, sharding);
+    }
+    if let Some(types) = &config.downstream_channel_types {
+        let types: SupportedChannelTypes = types.parse().unwrap();
+        builder = builder.downstream_channel_types(types);
+    }
+    if let Some(port) = config.admin_port {
+        // Only the target policy can change without restarting the proxy
+        builder = builder
+            .admin(SocketAddr::new(IpAddr::from([127, 0, 0, 1]), port))
+            .on_reload(|control| {
+                control.set_target_policy(Config::load()?.target_policy());
+                Ok(())
+            });
+    }
+    for upstream in &config.upstreams {
+        let (address, hostname) = upstream.resolve().await;
+        builder = match &upstream.tls_server_name {
+            Some(server_name) => {
+                builder.upstream_tls(address, server_name.clone(), upstream.tls_config())
+            }
+            None => builder.upstream(address, upstream.pub_key.expect("Missing upstream pub_key")),
+        };
+        if let Some(labels) = &upstream.labels {
+            builder = builder.upstream_labels(labels.clone());
+        }
+        if let Some(hostname) = hostname {
+            builder = builder.upstream_hostname(hostname);
+        }
+        if let Some(bucket) = upstream.padding_bucket {
+            builder = builder.upstream_padding(PaddingPolicy::Bucket(bucket));
+        }
+        if let Some(policy) = &upstream.target_conflict {
+            builder = builder.upstream_target_conflict(policy.parse().unwrap());
+        }
+    }
+    if let Some(labels) = &config.labels {
+        builder = builder.labels(labels.clone());
+    }
+
+    // Shutdown the proxy on SIGINT/SIGTERM
+    let (shutdown_sender, shutdown_receiver) = async_channel::bounded(1);
+    ctrlc::set_handler(move || {
+        let _ = shutdown_sender.try_send(());
+    })
+    .unwrap();
+
+    // Scan all the upstreams and wait for downstream connections
+    let proxy = builder.spawn().await.unwrap();
+
+    let _ = shutdown_receiver.recv().await;
+    let stats = proxy.stats();
+    println!(
+        "Shutting down, closing {} downstream connections",
+        stats.downstreams
+    );
+    match proxy.shutdown().await {
+        Ok(()) => println!("Proxy stopped"),
+        Err(e) => println!("Proxy stopped, can not save the proxy state: {}", e),
+    }
 }
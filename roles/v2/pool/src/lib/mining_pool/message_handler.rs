@@ -1,9 +1,13 @@
-use crate::lib::mining_pool::{Downstream, VelideateTargetResult};
+use crate::lib::mining_pool::{u256_to_block_hash, Downstream, VelideateTargetResult};
 use binary_sv2::U256;
 use bitcoin::util::uint::Uint256;
 use roles_logic_sv2::{
+    conformance::ConformanceGuard,
+    error_codes::{open_mining_channel_error, share_error, submit_shares_error, MiningErrorCode},
     errors::Error,
+    events::ConnectionEvent,
     handlers::mining::{ParseDownstreamMiningMessages, SendTo, SupportedChannelTypes},
+    job_dispatcher::{ShareKey, StaleJob},
     mining_sv2::*,
     parsers::Mining,
     routing_logic::NoRouting,
@@ -17,13 +21,16 @@ use std::{convert::TryInto, sync::Arc};
 // Depending on server’s target setting policy, this value can be used for
 // setting a reasonable target for the channel. Proxy MUST send 0.0f when
 // there are no mining devices connected yet.
-pub fn hash_rate_to_target(_hs: f32) -> U256<'static> {
-    vec![
-        0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0,
-    ]
-    .try_into()
-    .unwrap()
+// The target is chosen by `crate::target_policy`.
+pub fn hash_rate_to_target(hs: f32) -> U256<'static> {
+    let target = crate::target_policy().target_for(hs);
+    // The pool (see `u256_to_uint_256`) and the test mining device read the target as big endian
+    target
+        .into_inner()
+        .to_be_bytes()
+        .to_vec()
+        .try_into()
+        .unwrap()
 }
 
 #[allow(clippy::many_single_char_names)]
@@ -36,6 +43,101 @@ pub fn u256_to_uint_256(v: U256<'static>) -> Uint256 {
     Uint256([d, c, b, a])
 }
 
+impl Downstream {
+    /// Validate a share, return true if the share is a block. A rejected share return the error
+    /// code for the downstream, None if the channel has no job. `share` identify the share in the
+    /// duplicate filter, it is recorded only if the share is accepted.
+    fn check_share_standard(
+        &mut self,
+        m: &SubmitSharesStandard,
+        share: ShareKey,
+    ) -> Result<bool, Option<MiningErrorCode>> {
+        if self.is_duplicate_share(m.channel_id, &share) {
+            return Err(Some(MiningErrorCode::DuplicateShare));
+        }
+        let result = self.validate_share_standard(m);
+        if result.is_ok() {
+            self.record_share(m.channel_id, share);
+        }
+        result
+    }
+
+    /// Validate the share, credit it and answer the downstream
+    fn on_submit_share(&mut self, m: SubmitSharesStandard, share: ShareKey) -> SendTo<()> {
+        let result = self.check_share_standard(&m, share);
+        let response = match result {
+            // Every accepted share count 1 in new_shares_sum
+            Ok(_) => match self
+                .share_accounting
+                .on_accepted(m.channel_id, m.sequence_number, 1)
+            {
+                Some(success) => SendTo::Respond(Mining::SubmitSharesSuccess(success)),
+                // Acknowledged by a next success
+                None => SendTo::None(None),
+            },
+            Err(Some(error_code)) => {
+                SendTo::Respond(Mining::SubmitSharesError(share_error(&m, error_code)))
+            }
+            Err(None) => SendTo::None(None),
+        };
+        let (error_code, block) = match result {
+            Ok(block) => (None, block),
+            Err(error_code) => (
+                Some(error_code.unwrap_or(MiningErrorCode::InvalidJobId)),
+                false,
+            ),
+        };
+        self.log_share(m.channel_id, m.sequence_number, error_code, block);
+        if block {
+            self.publish(ConnectionEvent::BlockFound {
+                connection_id: self.connection_id,
+                channel_id: m.channel_id,
+            });
+        }
+        response
+    }
+
+    fn validate_share_standard(
+        &mut self,
+        m: &SubmitSharesStandard,
+    ) -> Result<bool, Option<MiningErrorCode>> {
+        if self.job_state.active_job().is_none() {
+            return Err(Some(MiningErrorCode::InvalidJobId));
+        }
+        let active_job_id = self.job_state.active_job().map(|(job_id, _)| job_id);
+        if active_job_id != Some(m.job_id) {
+            match self.check_stale_target(m) {
+                // A block on the replaced prev hash is useless, the share is only credited
+                StaleJob::InGracePeriod(Ok(VelideateTargetResult::LessThanBitcoinTarget(..)))
+                | StaleJob::InGracePeriod(Ok(VelideateTargetResult::LessThanDownstreamTarget(
+                    ..,
+                ))) => return Ok(false),
+                StaleJob::InGracePeriod(Ok(VelideateTargetResult::Invalid(_))) => {
+                    return Err(Some(MiningErrorCode::DifficultyTooLow))
+                }
+                StaleJob::InGracePeriod(Ok(VelideateTargetResult::InvalidVersion)) => {
+                    return Err(Some(MiningErrorCode::InvalidVersion))
+                }
+                StaleJob::InGracePeriod(Err(())) => return Err(None),
+                StaleJob::Expired => return Err(Some(MiningErrorCode::StaleShare)),
+                // Not a stale share
+                StaleJob::Unknown => (),
+            }
+        }
+        match self.check_target(m) {
+            Ok(VelideateTargetResult::LessThanBitcoinTarget(_, solution)) => {
+                // That unwrap means lose a block!!! TODO
+                self.solution_sender.try_send(solution).unwrap();
+                Ok(true)
+            }
+            Ok(VelideateTargetResult::LessThanDownstreamTarget(_)) => Ok(false),
+            Ok(VelideateTargetResult::Invalid(_)) => Err(Some(MiningErrorCode::DifficultyTooLow)),
+            Ok(VelideateTargetResult::InvalidVersion) => Err(Some(MiningErrorCode::InvalidVersion)),
+            Err(()) => Err(None),
+        }
+    }
+}
+
 impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting> for Downstream {
     fn get_channel_type(&self) -> SupportedChannelTypes {
         SupportedChannelTypes::Group
@@ -45,12 +147,26 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
         false
     }
 
+    fn get_conformance_guard(&self) -> Option<Arc<Mutex<ConformanceGuard>>> {
+        Some(self.conformance.clone())
+    }
+
     fn handle_open_standard_mining_channel(
         &mut self,
         incoming: OpenStandardMiningChannel,
         _m: Option<Arc<Mutex<()>>>,
     ) -> Result<SendTo<()>, Error> {
         let request_id = incoming.get_request_id_as_u32();
+        let worker =
+            match crate::identity_rules().parse_bytes(incoming.user_identity.inner_as_ref()) {
+                Ok(worker) => worker,
+                Err(e) => {
+                    println!("Invalid user identity: {}", e);
+                    return Ok(SendTo::Respond(Mining::OpenMiningChannelError(
+                        open_mining_channel_error(request_id, MiningErrorCode::UnknownUser),
+                    )));
+                }
+            };
         let target = hash_rate_to_target(incoming.nominal_hash_rate);
         let extranonce_prefix = self
             .extranonces
@@ -58,36 +174,25 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
             .unwrap();
         let message = match (self.downstream_data.header_only, self.id) {
             (false, group_channel_id) => {
-                let channel_id = self.channel_ids.next();
+                let channel_id = self
+                    .channel_ids
+                    .allocate()
+                    .ok_or(Error::ChannelIdsExhausted)?;
                 let mut partial_job = crate::lib::mining_pool::StandardJob::new(
                     u256_to_uint_256(target.clone()),
                     extranonce_prefix.clone().to_vec(),
                 );
-                match (
-                    &self.last_valid_extended_job,
-                    &self.last_prev_hash,
-                    &self.last_nbits,
-                ) {
-                    (Some(job), Some(p_hash), Some(n_bits)) => {
-                        partial_job.update_job(&job.0, *n_bits, *p_hash, job.1);
-                        self.jobs.insert(channel_id, partial_job);
-                    }
-                    (None, Some(_), Some(_)) => {
-                        self.jobs.insert(channel_id, partial_job);
-                    }
-                    (None, None, None) => {
-                        self.jobs.insert(channel_id, partial_job);
-                    }
-                    (Some(_), None, None) => {
-                        self.jobs.insert(channel_id, partial_job);
-                    }
-                    (_, Some(_), None) => {
-                        panic!("impossible state")
-                    }
-                    (_, None, Some(_)) => {
-                        panic!("impossible state")
-                    }
-                };
+                if let (Some((_, job)), Some(prev_hash)) =
+                    (self.job_state.active_job(), self.job_state.prev_hash())
+                {
+                    partial_job.update_job(
+                        &job.0,
+                        prev_hash.nbits,
+                        u256_to_block_hash(prev_hash.prev_hash.clone()),
+                        job.1,
+                    );
+                }
+                self.jobs.insert(channel_id, partial_job);
 
                 OpenStandardMiningChannelSuccess {
                     request_id: request_id.into(),
@@ -102,31 +207,17 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
                     u256_to_uint_256(target.clone()),
                     extranonce_prefix.clone().to_vec(),
                 );
-                match (
-                    &self.last_valid_extended_job,
-                    &self.last_prev_hash,
-                    &self.last_nbits,
-                ) {
-                    (Some(job), Some(p_hash), Some(n_bits)) => {
-                        partial_job.update_job(&job.0, *n_bits, *p_hash, job.1);
-                        self.jobs.insert(channel_id, partial_job);
-                    }
-                    (None, Some(_), Some(_)) => {
-                        self.jobs.insert(channel_id, partial_job);
-                    }
-                    (None, None, None) => {
-                        self.jobs.insert(channel_id, partial_job);
-                    }
-                    (Some(_), None, None) => {
-                        self.jobs.insert(channel_id, partial_job);
-                    }
-                    (_, Some(_), None) => {
-                        panic!("impossible state")
-                    }
-                    (_, None, Some(_)) => {
-                        panic!("impossible state")
-                    }
-                };
+                if let (Some((_, job)), Some(prev_hash)) =
+                    (self.job_state.active_job(), self.job_state.prev_hash())
+                {
+                    partial_job.update_job(
+                        &job.0,
+                        prev_hash.nbits,
+                        u256_to_block_hash(prev_hash.prev_hash.clone()),
+                        job.1,
+                    );
+                }
+                self.jobs.insert(channel_id, partial_job);
 
                 OpenStandardMiningChannelSuccess {
                     request_id: request_id.into(),
@@ -137,6 +228,11 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
                 }
             }
         };
+        self.workers.insert(message.channel_id, worker);
+        self.publish(ConnectionEvent::ChannelOpened {
+            connection_id: self.connection_id,
+            channel_id: message.channel_id,
+        });
         Ok(SendTo::RelayNewMessage(
             Arc::new(Mutex::new(())),
             Mining::OpenStandardMiningChannelSuccess(message),
@@ -158,43 +254,40 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
         &mut self,
         m: SubmitSharesStandard,
     ) -> Result<SendTo<()>, Error> {
-        match self.check_target(&m) {
-            Ok(VelideateTargetResult::LessThanBitcoinTarget(_, new_shares_sum, solution)) => {
-                // That unwrap means lose a block!!! TODO
-                self.solution_sender.try_send(solution).unwrap();
-                Ok(SendTo::Respond(Mining::SubmitSharesSuccess(
-                    SubmitSharesSuccess {
-                        channel_id: m.channel_id,
-                        last_sequence_number: m.sequence_number,
-                        new_submits_accepted_count: 1,
-                        new_shares_sum,
-                    },
-                )))
-            }
-            Ok(VelideateTargetResult::LessThanDownstreamTarget(_, new_shares_sum)) => Ok(
-                SendTo::Respond(Mining::SubmitSharesSuccess(SubmitSharesSuccess {
-                    channel_id: m.channel_id,
-                    last_sequence_number: m.sequence_number,
-                    new_submits_accepted_count: 1,
-                    new_shares_sum,
-                })),
-            ),
-            Ok(VelideateTargetResult::Invalid(_)) => Ok(SendTo::Respond(
-                Mining::SubmitSharesError(SubmitSharesError {
-                    channel_id: m.channel_id,
-                    sequence_number: m.sequence_number,
-                    error_code: "difficulty-too-low".to_string().try_into().unwrap(),
-                }),
-            )),
-            Err(()) => Ok(SendTo::None(None)),
-        }
+        let share = ShareKey::from(&m);
+        Ok(self.on_submit_share(m, share))
     }
 
     fn handle_submit_shares_extended(
         &mut self,
-        _: SubmitSharesExtended,
+        m: SubmitSharesExtended,
     ) -> Result<SendTo<()>, Error> {
-        todo!()
+        // The pool open only standard channels, their extranonce is fixed so an extended share is
+        // valid only without extranonce and then is validated as a standard share
+        if !m.extranonce.inner_as_ref().is_empty() || !self.jobs.contains_key(&m.channel_id) {
+            let error = submit_shares_error(
+                m.channel_id,
+                m.sequence_number,
+                MiningErrorCode::InvalidChannelId,
+            );
+            self.log_share(
+                m.channel_id,
+                m.sequence_number,
+                Some(MiningErrorCode::InvalidChannelId),
+                false,
+            );
+            return Ok(SendTo::Respond(Mining::SubmitSharesError(error)));
+        }
+        let share = ShareKey::from(&m);
+        let standard = SubmitSharesStandard {
+            channel_id: m.channel_id,
+            sequence_number: m.sequence_number,
+            job_id: m.job_id,
+            nonce: m.nonce,
+            ntime: m.ntime,
+            version: m.version,
+        };
+        Ok(self.on_submit_share(standard, share))
     }
 
     fn handle_set_custom_mining_job(&mut self, _: SetCustomMiningJob) -> Result<SendTo<()>, Error> {
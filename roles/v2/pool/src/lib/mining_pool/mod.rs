@@ -15,19 +15,36 @@ use bitcoin::{
 };
 use codec_sv2::Frame;
 use roles_logic_sv2::{
+    channel_state::ChannelState,
+    coinbase_outputs::CoinbaseOutputs,
     common_properties::{CommonDownstreamData, IsDownstream, IsMiningDownstream},
+    conformance::ConformanceGuard,
+    error_codes::MiningErrorCode,
     errors::Error,
+    events::ConnectionEvent,
     handlers::mining::{ParseDownstreamMiningMessages, SendTo},
-    job_creator::JobsCreators,
+    job_creator::{empty_block_template_id, mining_prev_hash, JobsCreators},
+    job_dispatcher::{DuplicateShareFilter, ShareKey, StaleJob, StaleJobs},
     mining_sv2::{
         Extranonce, NewExtendedMiningJob, SetNewPrevHash as NewPrevHash, SubmitSharesStandard,
     },
     parsers::{Mining, PoolMessages},
     routing_logic::MiningRoutingLogic,
+    share_accounting::ShareAccounting,
     template_distribution_sv2::{NewTemplate, SetNewPrevHash, SubmitSolution},
-    utils::{merkle_root_from_path, Id, Mutex},
+    user_identity::UserIdentity,
+    utils::{
+        build_coinbase, is_valid_rolled_version, merkle_root_from_path, version_rolling_mask,
+        ChannelIdFactory, Id, Mutex,
+    },
+};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fmt,
+    net::SocketAddr,
+    time::{Duration, Instant},
 };
-use std::{collections::HashMap, convert::TryInto};
 
 pub fn u256_to_block_hash(v: U256<'static>) -> BlockHash {
     let hash: [u8; 32] = v.to_vec().try_into().unwrap();
@@ -69,13 +86,14 @@ impl PartialStandardJob {
             target: self.target,
             nbits,
             prev_hash,
-            new_shares_sum: 0,
             coinbase_tx_prefix: new_ext_job.coinbase_tx_prefix.to_vec(),
             coinbase_tx_suffix: new_ext_job.coinbase_tx_suffix.to_vec(),
             merkle_path: new_ext_job.merkle_path.to_vec(),
             extranonce: self.extranonce.clone(),
             merkle_root,
             template_id,
+            version: new_ext_job.version,
+            version_rolling_mask: version_rolling_mask(new_ext_job.version_rolling_allowed),
         }
     }
 }
@@ -86,29 +104,35 @@ struct CompleteStandardJob {
     target: Uint256,
     nbits: u32,
     prev_hash: BlockHash,
-    new_shares_sum: u64,
     coinbase_tx_suffix: Vec<u8>,
     coinbase_tx_prefix: Vec<u8>,
     extranonce: Vec<u8>,
     #[allow(dead_code)]
     merkle_path: Vec<Vec<u8>>,
     merkle_root: TxMerkleNode,
+    /// Version of the job, the shares can change only the bits in `version_rolling_mask`
+    version: u32,
+    version_rolling_mask: u32,
 }
 
 #[derive(Debug)]
 pub enum VelideateTargetResult {
-    LessThanBitcoinTarget(BlockHash, u64, SubmitSolution<'static>),
-    LessThanDownstreamTarget(BlockHash, u64),
+    LessThanBitcoinTarget(BlockHash, SubmitSolution<'static>),
+    LessThanDownstreamTarget(BlockHash),
     Invalid(BlockHash),
+    /// The share change bits of the version outside the version rolling mask of the job
+    InvalidVersion,
 }
 
 impl CompleteStandardJob {
     pub fn get_coinbase(&self) -> B064K<'static> {
-        let mut coinbase = Vec::new();
-        coinbase.extend(self.coinbase_tx_prefix.clone());
-        coinbase.extend(self.extranonce.clone());
-        coinbase.extend(self.coinbase_tx_suffix.clone());
-        coinbase.try_into().unwrap()
+        build_coinbase(
+            &self.coinbase_tx_prefix,
+            &self.extranonce,
+            &self.coinbase_tx_suffix,
+        )
+        .try_into()
+        .unwrap()
     }
     pub fn validate_target(
         &mut self,
@@ -116,6 +140,9 @@ impl CompleteStandardJob {
         version: u32,
         ntime: u32,
     ) -> VelideateTargetResult {
+        if !is_valid_rolled_version(self.version, version, self.version_rolling_mask) {
+            return VelideateTargetResult::InvalidVersion;
+        }
         // TODO  how should version be transoformed from u32 into i32???
         let version = version as i32;
         let header = BlockHeader {
@@ -134,7 +161,6 @@ impl CompleteStandardJob {
         hash.reverse();
         let hash = Uint256::from_be_bytes(hash);
         if hash <= bitcoin_target {
-            self.new_shares_sum += 1;
             let solution = SubmitSolution {
                 template_id: self.template_id,
                 version: version as u32,
@@ -142,10 +168,9 @@ impl CompleteStandardJob {
                 header_nonce: nonce,
                 coinbase_tx: self.get_coinbase(),
             };
-            VelideateTargetResult::LessThanBitcoinTarget(hash_, self.new_shares_sum, solution)
+            VelideateTargetResult::LessThanBitcoinTarget(hash_, solution)
         } else if hash <= self.target {
-            self.new_shares_sum += 1;
-            VelideateTargetResult::LessThanDownstreamTarget(hash_, self.new_shares_sum)
+            VelideateTargetResult::LessThanDownstreamTarget(hash_)
         } else {
             VelideateTargetResult::Invalid(hash_)
         }
@@ -173,13 +198,14 @@ impl CompleteStandardJob {
             target: self.target,
             nbits,
             prev_hash,
-            new_shares_sum: 0,
             coinbase_tx_prefix: new_ext_job.coinbase_tx_prefix.to_vec(),
             coinbase_tx_suffix: new_ext_job.coinbase_tx_suffix.to_vec(),
             merkle_path: new_ext_job.merkle_path.to_vec(),
             extranonce: self.extranonce.clone(),
             merkle_root,
             template_id,
+            version: new_ext_job.version,
+            version_rolling_mask: version_rolling_mask(new_ext_job.version_rolling_allowed),
         }
     }
 }
@@ -237,6 +263,39 @@ pub struct ExtendedJob {
     nbits: u32,
 }
 
+/// Entry of the share log, one for every share submitted to the pool
+#[derive(Debug, Clone)]
+pub struct ShareLogEntry {
+    pub connection_id: u32,
+    pub channel_id: u32,
+    /// None if the channel is unknown
+    pub worker: Option<UserIdentity>,
+    pub sequence_number: u32,
+    /// Error code of a rejected share, None if the share is accepted
+    pub error_code: Option<MiningErrorCode>,
+    /// The share is a valid block
+    pub block: bool,
+}
+
+impl fmt::Display for ShareLogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let worker = match &self.worker {
+            Some(worker) => worker.to_string(),
+            None => "-".to_string(),
+        };
+        write!(
+            f,
+            "share connection={} channel={} worker={} sequence={} ",
+            self.connection_id, self.channel_id, worker, self.sequence_number
+        )?;
+        match (&self.error_code, self.block) {
+            (Some(error_code), _) => write!(f, "rejected={}", error_code),
+            (None, true) => write!(f, "accepted block"),
+            (None, false) => write!(f, "accepted"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Downstream {
     // Either group or channel id
@@ -244,17 +303,38 @@ pub struct Downstream {
     receiver: Receiver<EitherFrame>,
     sender: Sender<EitherFrame>,
     downstream_data: CommonDownstreamData,
-    channel_ids: Id,
+    channel_ids: ChannelIdFactory,
     extranonces: Arc<Mutex<Extranonce>>,
     // channel_id -> StandardJob
     jobs: HashMap<u32, StandardJob>,
-    // extended_job_id -> (FutureJob,template_id)
-    future_jobs: HashMap<u32, (NewExtendedMiningJob<'static>, u64)>,
-    last_prev_hash: Option<BlockHash>,
-    last_nbits: Option<u32>,
-    // (job,template_id)
-    last_valid_extended_job: Option<(NewExtendedMiningJob<'static>, u64)>,
+    // Jobs of the group channel, (job,template_id)
+    job_state: ChannelState<(NewExtendedMiningJob<'static>, u64)>,
     solution_sender: Sender<SubmitSolution<'static>>,
+    duplicate_shares: DuplicateShareFilter,
+    // job_id -> channel_id -> job, for the job replaced by the last prev hash
+    stale_jobs: StaleJobs<HashMap<u32, StandardJob>>,
+    share_accounting: ShareAccounting,
+    /// Identify the connection in the published `ConnectionEvent`s
+    connection_id: u32,
+    events: Sender<ConnectionEvent>,
+    /// channel_id -> user_identity the channel was opened with
+    workers: HashMap<u32, UserIdentity>,
+    /// Check the ordering of the messages of the connection, see `roles_logic_sv2::conformance`
+    conformance: Arc<Mutex<ConformanceGuard>>,
+    share_log: Sender<ShareLogEntry>,
+}
+
+/// Identity of the pool on a listening address: the downstreams connected to `address` check the
+/// certificates of `authority_public_k` and mine on coinbases that pay `coinbase_outputs`. One
+/// process can serve several identities, that share the templates, the channel ids and the share
+/// accounting.
+#[derive(Debug, Clone)]
+pub struct PoolIdentity {
+    pub address: SocketAddr,
+    pub authority_public_k: [u8; 32],
+    pub authority_private_k: [u8; 32],
+    pub cert_validity: Duration,
+    pub coinbase_outputs: CoinbaseOutputs,
 }
 
 /// Accept downstream connection
@@ -265,25 +345,67 @@ pub struct Pool {
     hom_downstreams: HashMap<u32, Arc<Mutex<Downstream>>>,
     hom_ids: Arc<Mutex<Id>>,
     group_ids: Arc<Mutex<Id>>,
-    job_creators: Arc<Mutex<JobsCreators>>,
+    /// One for each `PoolIdentity`, the group channels of a downstream are in the job creators of
+    /// the identity it connected to
+    job_creators: Vec<Arc<Mutex<JobsCreators>>>,
     last_new_prev_hash: Option<SetNewPrevHash<'static>>,
     extranonces: Arc<Mutex<Extranonce>>,
     solution_sender: Sender<SubmitSolution<'static>>,
     new_template_processed: bool,
+    /// Last template on the current prev hash not yet turned in jobs, see
+    /// `crate::JOB_REFRESH_INTERVAL_SECS`
+    pending_template: Option<NewTemplate<'static>>,
+    connection_ids: Id,
+    /// Lifecycle events of the downstream connections, see `roles_logic_sv2::events`
+    events: Sender<ConnectionEvent>,
+    share_log: Sender<ShareLogEntry>,
 }
 
 impl Downstream {
+    /// Publish `event`, the event is dropped if the observer is not fast enough
+    pub fn publish(&self, event: ConnectionEvent) {
+        let _ = self.events.try_send(event);
+    }
+
+    /// Add a share to the share log, the entry is dropped if the log is not fast enough
+    pub fn log_share(
+        &self,
+        channel_id: u32,
+        sequence_number: u32,
+        error_code: Option<MiningErrorCode>,
+        block: bool,
+    ) {
+        let _ = self.share_log.try_send(ShareLogEntry {
+            connection_id: self.connection_id,
+            channel_id,
+            worker: self.workers.get(&channel_id).cloned(),
+            sequence_number,
+            error_code,
+            block,
+        });
+    }
+
+    pub fn is_duplicate_share(&self, channel_id: u32, share: &ShareKey) -> bool {
+        self.duplicate_shares.is_duplicate(channel_id, share)
+    }
+
+    /// Remember an accepted share so that it is rejected if submitted again
+    pub fn record_share(&mut self, channel_id: u32, share: ShareKey) {
+        self.duplicate_shares.insert(channel_id, share);
+    }
+
     pub fn check_target(&mut self, m: &SubmitSharesStandard) -> Result<VelideateTargetResult, ()> {
         let id = m.channel_id;
         match self.jobs.get_mut(&id) {
             Some(StandardJob::Complete(job)) => {
                 let res = job.validate_target(m.nonce, m.version, m.ntime);
                 match res {
-                    VelideateTargetResult::LessThanBitcoinTarget(_, _, _) => {
+                    VelideateTargetResult::LessThanBitcoinTarget(_, _) => {
                         self.jobs.get_mut(&id).as_mut().unwrap().make_partial();
                     }
-                    VelideateTargetResult::LessThanDownstreamTarget(_, _) => (),
+                    VelideateTargetResult::LessThanDownstreamTarget(_) => (),
                     VelideateTargetResult::Invalid(_) => (),
+                    VelideateTargetResult::InvalidVersion => (),
                 };
                 Ok(res)
             }
@@ -292,6 +414,23 @@ impl Downstream {
         }
     }
 
+    /// Like `check_target` for a share of the job replaced by the last prev hash
+    pub fn check_stale_target(
+        &mut self,
+        m: &SubmitSharesStandard,
+    ) -> StaleJob<Result<VelideateTargetResult, ()>> {
+        match self.stale_jobs.get_mut(m.job_id, Instant::now()) {
+            StaleJob::InGracePeriod(jobs) => match jobs.get_mut(&m.channel_id) {
+                Some(StandardJob::Complete(job)) => {
+                    StaleJob::InGracePeriod(Ok(job.validate_target(m.nonce, m.version, m.ntime)))
+                }
+                _ => StaleJob::InGracePeriod(Err(())),
+            },
+            StaleJob::Expired => StaleJob::Expired,
+            StaleJob::Unknown => StaleJob::Unknown,
+        }
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub async fn new(
         mut receiver: Receiver<EitherFrame>,
@@ -302,12 +441,15 @@ impl Downstream {
         extranonces: Arc<Mutex<Extranonce>>,
         last_new_prev_hash: Option<SetNewPrevHash<'static>>,
         solution_sender: Sender<SubmitSolution<'static>>,
-    ) -> Arc<Mutex<Self>> {
-        let setup_connection = Arc::new(Mutex::new(SetupConnectionHandler::new()));
+        connection_id: u32,
+        events: Sender<ConnectionEvent>,
+        share_log: Sender<ShareLogEntry>,
+    ) -> Result<Arc<Mutex<Self>>, ()> {
+        let conformance = Arc::new(Mutex::new(ConformanceGuard::new()));
+        let setup_connection =
+            Arc::new(Mutex::new(SetupConnectionHandler::new(conformance.clone())));
         let downstream_data =
-            SetupConnectionHandler::setup(setup_connection, &mut receiver, &mut sender)
-                .await
-                .unwrap();
+            SetupConnectionHandler::setup(setup_connection, &mut receiver, &mut sender).await?;
         let id = match downstream_data.header_only {
             false => group_ids.safe_lock(|id| id.next()).unwrap(),
             true => {
@@ -315,6 +457,8 @@ impl Downstream {
                 panic!("Downstream standard channel not supported");
             }
         };
+        // The jobs of the group are sent before that the first channel of the group is opened
+        conformance.safe_lock(|g| g.open_group(id)).unwrap();
         let extended_jobs = job_creators
             .safe_lock(|j| {
                 j.new_group_channel(id, downstream_data.version_rolling)
@@ -322,44 +466,41 @@ impl Downstream {
             })
             .unwrap();
 
-        let mut future_jobs = HashMap::new();
-        let mut last_valid_extended_job = None;
-
+        // Jobs sent before the prev hash wait for it, the last prev hash (sent below) activate the
+        // job built on it
+        let mut job_state = ChannelState::new();
         for job in &extended_jobs {
-            if job.0.future_job {
-                future_jobs.insert(job.0.job_id, (job.0.clone(), job.1));
-            } else {
-                last_valid_extended_job = Some((job.0.clone(), job.1));
-            }
-        }
-
-        if last_valid_extended_job.is_none() && last_new_prev_hash.is_some() {
-            let template_id = last_new_prev_hash.as_ref().unwrap().template_id;
-            let job_id = job_creators
-                .safe_lock(|jc| jc.job_id_from_template(template_id, id))
+            job_state
+                .on_new_job(job.0.job_id, true, (job.0.clone(), job.1))
                 .unwrap();
-            for job in &extended_jobs {
-                if job.0.job_id == job_id.unwrap() {
-                    last_valid_extended_job = Some((job.0.clone(), template_id));
-                    break;
-                }
-            }
         }
 
+        // The standard channels are opened in the group channel `id`, their ids must differ
+        let mut channel_ids = ChannelIdFactory::new();
+        channel_ids.reserve(id);
+
         let self_ = Arc::new(Mutex::new(Downstream {
             id,
             receiver,
             sender,
             downstream_data,
-            channel_ids: Id::new(),
+            channel_ids,
             extranonces,
             jobs: HashMap::new(),
-            future_jobs,
-            last_prev_hash: None,
-            last_nbits: None,
-            last_valid_extended_job,
+            job_state,
             solution_sender,
+            duplicate_shares: DuplicateShareFilter::default(),
+            stale_jobs: StaleJobs::new(crate::stale_share_grace_period()),
+            share_accounting: ShareAccounting::new(crate::SHARES_PER_SUCCESS),
+            connection_id,
+            events,
+            workers: HashMap::new(),
+            share_log,
+            conformance,
         }));
+        self_
+            .safe_lock(|d| d.publish(ConnectionEvent::Paired { connection_id }))
+            .unwrap();
 
         for job in extended_jobs {
             Self::send(
@@ -374,13 +515,7 @@ impl Downstream {
             let job_id = job_creators
                 .safe_lock(|j| j.job_id_from_template(new_prev_hash.template_id, id))
                 .unwrap();
-            let message = NewPrevHash {
-                channel_id: id,
-                job_id: job_id.unwrap(),
-                prev_hash: new_prev_hash.prev_hash.clone(),
-                min_ntime: 0,
-                nbits: new_prev_hash.n_bits,
-            };
+            let message = mining_prev_hash(&new_prev_hash, id, job_id.unwrap());
             self_
                 .safe_lock(|d| d.on_new_prev_hash_sync(message.clone()))
                 .unwrap()
@@ -395,19 +530,30 @@ impl Downstream {
         task::spawn(async move {
             loop {
                 let receiver = cloned.safe_lock(|d| d.receiver.clone()).unwrap();
-                let incoming: StdFrame = receiver.recv().await.unwrap().try_into().unwrap();
+                let incoming: StdFrame = match receiver.recv().await {
+                    Ok(frame) => frame.try_into().unwrap(),
+                    // Connection has been closed
+                    Err(_) => {
+                        cloned
+                            .safe_lock(|d| {
+                                d.publish(ConnectionEvent::Disconnected { connection_id })
+                            })
+                            .unwrap();
+                        break;
+                    }
+                };
                 Downstream::next(cloned.clone(), incoming).await
             }
         });
-        self_
+        Ok(self_)
     }
 
     pub async fn next(self_mutex: Arc<Mutex<Self>>, mut incoming: StdFrame) {
-        let message_type = incoming.get_header().unwrap().msg_type();
+        let header = incoming.get_header().unwrap();
         let payload = incoming.payload();
         let next_message_to_send = ParseDownstreamMiningMessages::handle_message_mining(
             self_mutex.clone(),
-            message_type,
+            header,
             payload,
             MiningRoutingLogic::None,
         );
@@ -415,11 +561,16 @@ impl Downstream {
             Ok(SendTo::RelayNewMessage(_, message)) => {
                 Self::send(self_mutex, message).await.unwrap();
             }
+            // Already checked by the handler
             Ok(SendTo::Respond(message)) => {
-                Self::send(self_mutex, message).await.unwrap();
+                Self::write(self_mutex, message).await.unwrap();
             }
             Ok(SendTo::None(_)) => (),
             Ok(_) => panic!(),
+            // The message is dropped, the downstream can still submit the next shares
+            Err(Error::ProtocolViolation(violation)) => {
+                println!("Downstream error: {}, message dropped", violation)
+            }
             Err(Error::UnexpectedMessage) => todo!(),
             Err(_) => todo!(),
         }
@@ -428,6 +579,15 @@ impl Downstream {
     pub async fn send(
         self_mutex: Arc<Mutex<Self>>,
         message: roles_logic_sv2::parsers::Mining<'static>,
+    ) -> Result<(), ()> {
+        let conformance = self_mutex.safe_lock(|s| s.conformance.clone()).unwrap();
+        conformance.safe_lock(|g| g.observe_sent(&message)).unwrap();
+        Self::write(self_mutex, message).await
+    }
+
+    async fn write(
+        self_mutex: Arc<Mutex<Self>>,
+        message: roles_logic_sv2::parsers::Mining<'static>,
     ) -> Result<(), ()> {
         let sv2_frame: StdFrame = PoolMessages::Mining(message).try_into().unwrap();
         let sender = self_mutex.safe_lock(|self_| self_.sender.clone()).unwrap();
@@ -438,20 +598,31 @@ impl Downstream {
     pub fn on_new_prev_hash_sync(&mut self, message: NewPrevHash<'static>) -> Result<StdFrame, ()> {
         let prev_hash = message.prev_hash.clone();
 
-        if let Some(future_job) = self.future_jobs.remove(&message.job_id) {
-            for job in self.jobs.values_mut() {
-                job.update_job(
-                    &future_job.0,
-                    message.nbits,
-                    u256_to_block_hash(prev_hash.clone()),
-                    future_job.1,
-                );
-            }
+        let replaced = self.job_state.active_job().map(|(job_id, _)| job_id);
+        let replaced_jobs = self.jobs.clone();
+        if let Err(e) = self.job_state.on_set_new_prev_hash(message.clone()) {
+            println!("Invalid prev hash: {}", e);
+            return Err(());
+        }
+        self.stale_jobs.on_new_prev_hash(
+            replaced.map(|job_id| (job_id, replaced_jobs)),
+            Instant::now(),
+        );
+        // Is fine to unwrap, the prev hash has just activated a job
+        let (_, active_job) = self.job_state.active_job().unwrap();
+        for job in self.jobs.values_mut() {
+            job.update_job(
+                &active_job.0,
+                message.nbits,
+                u256_to_block_hash(prev_hash.clone()),
+                active_job.1,
+            );
+        }
+        // Job ids are never reused so the filter is cleared only to free memory, that can not be
+        // done while the shares for the stale job are still accepted
+        if self.stale_jobs.grace_period() == Duration::from_secs(0) {
+            self.duplicate_shares.clear();
         }
-
-        self.last_nbits = Some(message.nbits);
-        self.last_prev_hash = Some(u256_to_block_hash(prev_hash));
-        self.future_jobs = HashMap::new();
 
         let sv2_frame: StdFrame = PoolMessages::Mining(Mining::SetNewPrevHash(message))
             .try_into()
@@ -464,7 +635,14 @@ impl Downstream {
         message: NewPrevHash<'static>,
     ) -> Result<(), ()> {
         let sv2_frame = self_
-            .safe_lock(|s| s.on_new_prev_hash_sync(message))
+            .safe_lock(|s| {
+                let prev_hash = Mining::SetNewPrevHash(message.clone());
+                let sv2_frame = s.on_new_prev_hash_sync(message)?;
+                s.conformance
+                    .safe_lock(|g| g.observe_sent(&prev_hash))
+                    .unwrap();
+                Ok(sv2_frame)
+            })
             .unwrap()?;
         let sender = self_.safe_lock(|self_| self_.sender.clone()).unwrap();
 
@@ -479,27 +657,28 @@ impl Downstream {
         _merkle_path: Vec<Vec<u8>>,
         template_id: u64,
     ) -> Result<(), ()> {
-        if !message.future_job {
-            self_
-                .safe_lock(|s| {
+        self_
+            .safe_lock(|s| {
+                if let Err(e) = s.job_state.on_new_job(
+                    message.job_id,
+                    message.future_job,
+                    (message.clone(), template_id),
+                ) {
+                    println!("Invalid job: {}", e);
+                    return Err(());
+                }
+                let job = Mining::NewExtendedMiningJob(message.clone());
+                s.conformance.safe_lock(|g| g.observe_sent(&job)).unwrap();
+                if let Some(prev_hash) = s.job_state.prev_hash().filter(|_| !message.future_job) {
+                    let nbits = prev_hash.nbits;
+                    let prev_hash = u256_to_block_hash(prev_hash.prev_hash.clone());
                     for job in s.jobs.values_mut() {
-                        job.update_job(
-                            &message,
-                            s.last_nbits.unwrap(),
-                            *s.last_prev_hash.as_ref().unwrap(),
-                            template_id,
-                        );
+                        job.update_job(&message, nbits, prev_hash, template_id);
                     }
-                })
-                .unwrap();
-        } else {
-            self_
-                .safe_lock(|s| {
-                    s.future_jobs
-                        .insert(message.job_id, (message.clone(), template_id))
-                })
-                .unwrap();
-        }
+                }
+                Ok(())
+            })
+            .unwrap()?;
 
         let sv2_frame: StdFrame = PoolMessages::Mining(Mining::NewExtendedMiningJob(message))
             .try_into()
@@ -514,23 +693,40 @@ impl Downstream {
 
 impl IsDownstream for Downstream {
     fn get_downstream_mining_data(&self) -> CommonDownstreamData {
-        self.downstream_data
+        self.downstream_data.clone()
     }
 }
 
 impl IsMiningDownstream for Downstream {}
 
 impl Pool {
-    async fn accept_incoming_connection(self_: Arc<Mutex<Pool>>) {
-        let listner = TcpListener::bind(crate::ADDR).await.unwrap();
+    async fn accept_incoming_connection(
+        self_: Arc<Mutex<Pool>>,
+        identity: PoolIdentity,
+        job_creators: Arc<Mutex<JobsCreators>>,
+    ) {
+        let listner = TcpListener::bind(identity.address).await.unwrap();
         let mut incoming = listner.incoming();
         while let Some(stream) = incoming.next().await {
             let solution_sender = self_.safe_lock(|p| p.solution_sender.clone()).unwrap();
             let stream = stream.unwrap();
+            let (connection_id, events, share_log) = self_
+                .safe_lock(|p| {
+                    (
+                        p.connection_ids.next(),
+                        p.events.clone(),
+                        p.share_log.clone(),
+                    )
+                })
+                .unwrap();
+            let _ = events.try_send(ConnectionEvent::Accepted {
+                connection_id,
+                remote_address: stream.peer_addr().ok(),
+            });
             let responder = Responder::from_authority_kp(
-                &crate::AUTHORITY_PUBLIC_K[..],
-                &crate::AUTHORITY_PRIVATE_K[..],
-                crate::CERT_VALIDITY,
+                &identity.authority_public_k[..],
+                &identity.authority_private_k[..],
+                identity.cert_validity,
             )
             .unwrap();
             let last_new_prev_hash = self_.safe_lock(|x| x.last_new_prev_hash.clone()).unwrap();
@@ -538,7 +734,7 @@ impl Pool {
                 Connection::new(stream, HandshakeRole::Responder(responder), 10).await;
             let group_ids = self_.safe_lock(|s| s.group_ids.clone()).unwrap();
             let hom_ids = self_.safe_lock(|s| s.hom_ids.clone()).unwrap();
-            let job_creators = self_.safe_lock(|s| s.job_creators.clone()).unwrap();
+            let job_creators = job_creators.clone();
             let extranonces = self_.safe_lock(|s| s.extranonces.clone()).unwrap();
             let downstream = Downstream::new(
                 receiver,
@@ -549,8 +745,19 @@ impl Pool {
                 extranonces,
                 last_new_prev_hash,
                 solution_sender,
+                connection_id,
+                events.clone(),
+                share_log,
             )
             .await;
+            let downstream = match downstream {
+                Ok(downstream) => downstream,
+                // Setup connection failed, the connection is dropped
+                Err(_) => {
+                    let _ = events.try_send(ConnectionEvent::HandshakeFailed { connection_id });
+                    continue;
+                }
+            };
 
             let (is_header_only, channel_id) = downstream
                 .safe_lock(|d| (d.downstream_data.header_only, d.id))
@@ -576,16 +783,31 @@ impl Pool {
             self_
                 .safe_lock(|s| s.new_template_processed = false)
                 .unwrap();
+            let job_creators = self_.safe_lock(|s| s.job_creators.clone()).unwrap();
+            for job_creators in &job_creators {
+                job_creators
+                    .safe_lock(|jc| jc.on_new_prev_hash(&new_prev_hash))
+                    .unwrap();
+            }
             self_
                 .safe_lock(|s| {
-                    s.job_creators
-                        .safe_lock(|jc| jc.on_new_prev_hash(&new_prev_hash))
-                        .unwrap()
+                    s.last_new_prev_hash = Some(new_prev_hash.clone());
+                    // Built on the old prev hash
+                    s.pending_template = None;
                 })
                 .unwrap();
-            self_
-                .safe_lock(|s| s.last_new_prev_hash = Some(new_prev_hash.clone()))
-                .unwrap();
+            let mut empty_jobs = HashMap::new();
+            let mut full_jobs = HashMap::new();
+            for job_creators in job_creators.iter().filter(|_| crate::EMPTY_BLOCK_JOBS) {
+                job_creators
+                    .safe_lock(|j| {
+                        // Is fine to unwrap, the template has already been used for the jobs that
+                        // these replace
+                        empty_jobs.extend(j.empty_block_jobs(new_prev_hash.template_id).unwrap());
+                        full_jobs.extend(j.full_block_jobs(new_prev_hash.template_id).unwrap());
+                    })
+                    .unwrap();
+            }
             let hom_downstreams: Vec<Arc<Mutex<Downstream>>> = self_
                 .safe_lock(|s| s.hom_downstreams.iter().map(|d| d.1.clone()).collect())
                 .unwrap();
@@ -594,22 +816,22 @@ impl Pool {
                 .unwrap();
             for downstream in [&hom_downstreams[..], &group_downstreams[..]].concat() {
                 let channel_id = downstream.safe_lock(|d| d.id).unwrap();
-                let job_id = self_
-                    .safe_lock(|s| {
-                        s.job_creators
-                            .safe_lock(|j| {
-                                j.job_id_from_template(new_prev_hash.template_id, channel_id)
-                            })
-                            .unwrap()
-                    })
-                    .unwrap();
-                let message = NewPrevHash {
-                    channel_id,
-                    job_id: job_id.unwrap(),
-                    prev_hash: new_prev_hash.prev_hash.clone(),
-                    min_ntime: 0,
-                    nbits: new_prev_hash.n_bits,
-                };
+                if let (Some(empty_job), Some(full_job)) = (
+                    empty_jobs.remove(&channel_id),
+                    full_jobs.remove(&channel_id),
+                ) {
+                    Self::send_empty_block_job(downstream, &new_prev_hash, empty_job, full_job)
+                        .await;
+                    continue;
+                }
+                let job_id = job_creators.iter().find_map(|job_creators| {
+                    job_creators
+                        .safe_lock(|j| {
+                            j.job_id_from_template(new_prev_hash.template_id, channel_id)
+                        })
+                        .unwrap()
+                });
+                let message = mining_prev_hash(&new_prev_hash, channel_id, job_id.unwrap());
                 Downstream::on_new_prev_hash(downstream.clone(), message)
                     .await
                     .unwrap();
@@ -617,66 +839,141 @@ impl Pool {
         }
     }
 
-    async fn on_new_template(self_: Arc<Mutex<Self>>, rx: Receiver<NewTemplate<'_>>) {
-        while let Ok(mut new_template) = rx.recv().await {
-            let job_creators = self_.safe_lock(|s| s.job_creators.clone()).unwrap();
-            let mut new_jobs = job_creators
-                .safe_lock(|j| j.on_new_template(&mut new_template).unwrap())
-                .unwrap();
-            let group_downstreams: Vec<Arc<Mutex<Downstream>>> = self_
-                .safe_lock(|s| s.group_downstreams.iter().map(|d| d.1.clone()).collect())
-                .unwrap();
-            // TODO add standard channel downstream
-            for downstream in group_downstreams {
-                let channel_id = downstream.safe_lock(|x| x.id).unwrap();
-                let extended_job = new_jobs.remove(&channel_id).unwrap();
-                Downstream::on_new_extended_job(
-                    downstream,
-                    extended_job,
-                    new_template.merkle_path.to_vec(),
-                    new_template.template_id,
-                )
-                .await
+    /// The prev hash activate `empty_job`, that is replaced by `full_job` as soon as it is sent
+    async fn send_empty_block_job(
+        downstream: Arc<Mutex<Downstream>>,
+        new_prev_hash: &SetNewPrevHash<'static>,
+        empty_job: NewExtendedMiningJob<'static>,
+        full_job: NewExtendedMiningJob<'static>,
+    ) {
+        let template_id = new_prev_hash.template_id;
+        let message = mining_prev_hash(new_prev_hash, empty_job.channel_id, empty_job.job_id);
+        Downstream::on_new_extended_job(
+            downstream.clone(),
+            empty_job,
+            vec![],
+            empty_block_template_id(template_id),
+        )
+        .await
+        .unwrap();
+        Downstream::on_new_prev_hash(downstream.clone(), message)
+            .await
+            .unwrap();
+        let merkle_path = full_job.merkle_path.to_vec();
+        Downstream::on_new_extended_job(downstream, full_job, merkle_path, template_id)
+            .await
+            .unwrap();
+    }
+
+    async fn send_new_jobs(self_: Arc<Mutex<Self>>, mut new_template: NewTemplate<'static>) {
+        let job_creators = self_.safe_lock(|s| s.job_creators.clone()).unwrap();
+        let mut new_jobs = HashMap::new();
+        for job_creators in job_creators {
+            job_creators
+                .safe_lock(|j| new_jobs.extend(j.on_new_template(&mut new_template).unwrap()))
                 .unwrap();
+        }
+        let group_downstreams: Vec<Arc<Mutex<Downstream>>> = self_
+            .safe_lock(|s| s.group_downstreams.iter().map(|d| d.1.clone()).collect())
+            .unwrap();
+        // TODO add standard channel downstream
+        for downstream in group_downstreams {
+            let channel_id = downstream.safe_lock(|x| x.id).unwrap();
+            let extended_job = new_jobs.remove(&channel_id).unwrap();
+            Downstream::on_new_extended_job(
+                downstream,
+                extended_job,
+                new_template.merkle_path.to_vec(),
+                new_template.template_id,
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    async fn on_new_template(self_: Arc<Mutex<Self>>, rx: Receiver<NewTemplate<'static>>) {
+        while let Ok(new_template) = rx.recv().await {
+            if !new_template.future_template && crate::job_refresh_interval().is_some() {
+                // Sent by refresh_jobs
+                self_
+                    .safe_lock(|s| s.pending_template = Some(new_template))
+                    .unwrap();
+                continue;
             }
+            Self::send_new_jobs(self_.clone(), new_template).await;
             self_
                 .safe_lock(|s| s.new_template_processed = true)
                 .unwrap();
         }
     }
 
+    /// Every `interval` turn in jobs the last template received in the interval, if any
+    async fn refresh_jobs(self_: Arc<Mutex<Self>>, interval: Duration) {
+        loop {
+            task::sleep(interval).await;
+            let pending = self_.safe_lock(|s| s.pending_template.take()).unwrap();
+            if let Some(new_template) = pending {
+                Self::send_new_jobs(self_.clone(), new_template).await;
+            }
+        }
+    }
+
+    /// Serve every identity of `identities` on its own address
     pub async fn start(
+        identities: Vec<PoolIdentity>,
         new_template_rx: Receiver<NewTemplate<'static>>,
         new_prev_hash_rx: Receiver<SetNewPrevHash<'static>>,
         solution_sender: Sender<SubmitSolution<'static>>,
+        events: Sender<ConnectionEvent>,
+        share_log: Sender<ShareLogEntry>,
     ) {
         //let group_id_generator = Arc::new(Mutex::new(Id::new()));
+        let job_creators: Vec<Arc<Mutex<JobsCreators>>> = identities
+            .iter()
+            .map(|identity| {
+                Arc::new(Mutex::new(JobsCreators::with_outputs(
+                    crate::BLOCK_REWARD,
+                    identity.coinbase_outputs.clone(),
+                )))
+            })
+            .collect();
         let pool = Arc::new(Mutex::new(Pool {
             group_downstreams: HashMap::new(),
             hom_downstreams: HashMap::new(),
             hom_ids: Arc::new(Mutex::new(Id::new())),
             group_ids: Arc::new(Mutex::new(Id::new())),
-            job_creators: Arc::new(Mutex::new(
-                JobsCreators::new(crate::BLOCK_REWARD, crate::new_pub_key()).unwrap(),
-            )),
+            job_creators: job_creators.clone(),
             last_new_prev_hash: None,
             extranonces: Arc::new(Mutex::new(Extranonce::new())),
             solution_sender,
             new_template_processed: false,
+            pending_template: None,
+            connection_ids: Id::new(),
+            events,
+            share_log,
         }));
 
-        let cloned = pool.clone();
         let cloned2 = pool.clone();
         let cloned3 = pool.clone();
 
-        task::spawn(async move {
-            Self::accept_incoming_connection(cloned).await;
-        });
+        for (identity, job_creators) in identities.into_iter().zip(job_creators) {
+            let cloned = pool.clone();
+            task::spawn(async move {
+                Self::accept_incoming_connection(cloned, identity, job_creators).await;
+            });
+        }
 
         task::spawn(async {
             Self::on_new_prev_hash(cloned2, new_prev_hash_rx).await;
         });
 
+        if let Some(interval) = crate::job_refresh_interval() {
+            let cloned4 = pool.clone();
+            task::spawn(async move {
+                Self::refresh_jobs(cloned4, interval).await;
+            });
+        }
+
         task::spawn(async move {
             Self::on_new_template(cloned3, new_template_rx).await;
         })
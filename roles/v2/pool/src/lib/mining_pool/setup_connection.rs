@@ -7,6 +7,7 @@ use roles_logic_sv2::{
         SetupConnectionSuccess,
     },
     common_properties::CommonDownstreamData,
+    conformance::ConformanceGuard,
     errors::Error,
     handlers::common::ParseDownstreamCommonMessages,
     parsers::{CommonMessages, PoolMessages},
@@ -16,12 +17,20 @@ use roles_logic_sv2::{
 use std::{convert::TryInto, sync::Arc};
 
 pub struct SetupConnectionHandler {
-    header_only: Option<bool>,
+    /// Built from the SetupConnection of the downstream
+    downstream_data: Option<CommonDownstreamData>,
+    version: Option<u16>,
+    /// Guard of the connection, observe the SetupConnectionSuccess sent to the downstream
+    conformance: Arc<Mutex<ConformanceGuard>>,
 }
 
 impl SetupConnectionHandler {
-    pub fn new() -> Self {
-        Self { header_only: None }
+    pub fn new(conformance: Arc<Mutex<ConformanceGuard>>) -> Self {
+        Self {
+            downstream_data: None,
+            version: None,
+            conformance,
+        }
     }
     pub async fn setup(
         self_: Arc<Mutex<Self>>,
@@ -29,29 +38,39 @@ impl SetupConnectionHandler {
         sender: &mut Sender<EitherFrame>,
     ) -> Result<CommonDownstreamData, ()> {
         let mut incoming: StdFrame = receiver.recv().await.unwrap().try_into().unwrap();
-        let message_type = incoming.get_header().unwrap().msg_type();
+        let header = incoming.get_header().unwrap();
         let payload = incoming.payload();
         let response = ParseDownstreamCommonMessages::handle_message_common(
             self_.clone(),
-            message_type,
+            header,
             payload,
             CommonRoutingLogic::None,
         )
         .unwrap();
 
         let message = response.into_message().unwrap();
+        let conformance = self_.safe_lock(|s| s.conformance.clone()).unwrap();
+        conformance.safe_lock(|g| g.observe_sent(&message)).unwrap();
 
         let sv2_frame: StdFrame = PoolMessages::Common(message.clone()).try_into().unwrap();
         let sv2_frame = sv2_frame.into();
         sender.send(sv2_frame).await.unwrap();
-        self_.safe_lock(|s| s.header_only.unwrap()).unwrap();
 
         match message {
-            CommonMessages::SetupConnectionSuccess(m) => Ok(CommonDownstreamData {
-                header_only: has_requires_std_job(m.flags),
-                work_selection: has_work_selection(m.flags),
-                version_rolling: has_version_rolling(m.flags),
-            }),
+            // Downstream requested an unsupported protocol version, the error has been sent and
+            // the connection can be dropped
+            CommonMessages::SetupConnectionError(_) => Err(()),
+            CommonMessages::SetupConnectionSuccess(m) => {
+                let data = self_
+                    .safe_lock(|s| s.downstream_data.take().unwrap())
+                    .unwrap();
+                Ok(CommonDownstreamData {
+                    header_only: has_requires_std_job(m.flags),
+                    work_selection: has_work_selection(m.flags),
+                    version_rolling: has_version_rolling(m.flags),
+                    ..data
+                })
+            }
             _ => panic!(),
         }
     }
@@ -64,14 +83,18 @@ impl ParseDownstreamCommonMessages<NoRouting> for SetupConnectionHandler {
         _: Option<Result<(CommonDownstreamData, SetupConnectionSuccess), Error>>,
     ) -> Result<roles_logic_sv2::handlers::common::SendTo, Error> {
         use roles_logic_sv2::handlers::common::SendTo;
-        let header_only = incoming.requires_standard_job();
-        self.header_only = Some(header_only);
+        self.downstream_data = Some(CommonDownstreamData::from_setup_connection(&incoming, None));
         Ok(SendTo::RelayNewMessage(
             Arc::new(Mutex::new(())),
             CommonMessages::SetupConnectionSuccess(SetupConnectionSuccess {
                 flags: 0,
-                used_version: 2,
+                // Is set by on_version_negotiated before that this function is called
+                used_version: self.version.unwrap(),
             }),
         ))
     }
+
+    fn on_version_negotiated(&mut self, version: u16) {
+        self.version = Some(version);
+    }
 }
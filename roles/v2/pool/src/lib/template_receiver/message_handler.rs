@@ -31,6 +31,7 @@ impl ParseServerTemplateDistributionMessages for TemplateRx {
     }
 
     fn handle_set_new_prev_hash(&mut self, m: SetNewPrevHash) -> Result<SendTo, Error> {
+        self.block_assembler.on_new_prev_hash(&m);
         let new_prev_hash = SetNewPrevHash {
             template_id: m.template_id,
             prev_hash: m.prev_hash.into_static(),
@@ -47,15 +48,23 @@ impl ParseServerTemplateDistributionMessages for TemplateRx {
 
     fn handle_request_tx_data_success(
         &mut self,
-        _m: RequestTransactionDataSuccess,
+        m: RequestTransactionDataSuccess,
     ) -> Result<SendTo, Error> {
-        todo!()
+        if let Err(e) = self.block_assembler.on_transaction_data(&m) {
+            println!("{}", e);
+        }
+        Ok(SendTo::None(None))
     }
 
     fn handle_request_tx_data_error(
         &mut self,
-        _m: RequestTransactionDataError,
+        m: RequestTransactionDataError,
     ) -> Result<SendTo, Error> {
-        todo!()
+        println!(
+            "No transactions for template {}: {}",
+            m.template_id,
+            String::from_utf8_lossy(m.error_code.inner_as_ref())
+        );
+        Ok(SendTo::None(None))
     }
 }
@@ -2,12 +2,17 @@ use crate::{EitherFrame, StdFrame};
 use async_channel::{Receiver, Sender};
 //use std::sync::mpsc::Sender as SSender;
 use async_std::{net::TcpStream, task};
+use bitcoin::consensus::encode::serialize_hex;
 use codec_sv2::Frame;
 use network_helpers::PlainConnection;
 use roles_logic_sv2::{
+    block_assembler::BlockAssembler,
     handlers::template_distribution::ParseServerTemplateDistributionMessages,
+    job_creator::is_empty_block_template,
     parsers::{PoolMessages, TemplateDistribution},
-    template_distribution_sv2::{NewTemplate, SetNewPrevHash, SubmitSolution},
+    template_distribution_sv2::{
+        CoinbaseOutputDataSize, NewTemplate, RequestTransactionData, SetNewPrevHash, SubmitSolution,
+    },
     utils::Mutex,
 };
 use std::{convert::TryInto, net::SocketAddr, sync::Arc};
@@ -22,6 +27,8 @@ pub struct TemplateRx {
     sender: Sender<EitherFrame>,
     new_template_sender: Sender<NewTemplate<'static>>,
     new_prev_hash_sender: Sender<SetNewPrevHash<'static>>,
+    /// Transactions of the templates, used to rebuild the blocks of the solutions
+    block_assembler: BlockAssembler,
 }
 
 impl TemplateRx {
@@ -30,6 +37,7 @@ impl TemplateRx {
         templ_sender: Sender<NewTemplate<'static>>,
         prev_h_sender: Sender<SetNewPrevHash<'static>>,
         solution_receiver: Receiver<SubmitSolution<'static>>,
+        coinbase_output_max_additional_size: u32,
     ) {
         let stream = TcpStream::connect(address).await.unwrap();
 
@@ -40,11 +48,22 @@ impl TemplateRx {
             .await
             .unwrap();
 
+        // The templates must leave room for the coinbase outputs of the pool
+        let size = CoinbaseOutputDataSize {
+            coinbase_output_max_additional_size,
+        };
+        let frame: StdFrame =
+            PoolMessages::TemplateDistribution(TemplateDistribution::CoinbaseOutputDataSize(size))
+                .try_into()
+                .unwrap();
+        sender.send(frame.into()).await.unwrap();
+
         let self_ = Arc::new(Mutex::new(Self {
             receiver,
             sender,
             new_template_sender: templ_sender,
             new_prev_hash_sender: prev_h_sender,
+            block_assembler: BlockAssembler::new(),
         }));
         let cloned = self_.clone();
 
@@ -65,11 +84,11 @@ impl TemplateRx {
         loop {
             let message_from_tp = receiver.recv().await.unwrap();
             let mut message_from_tp: StdFrame = message_from_tp.try_into().unwrap();
-            let message_type = message_from_tp.get_header().unwrap().msg_type();
+            let header = message_from_tp.get_header().unwrap();
             let payload = message_from_tp.payload();
             match ParseServerTemplateDistributionMessages::handle_message_template_distribution(
                 self_.clone(),
-                message_type,
+                header,
                 payload,
             )
             .unwrap()
@@ -77,7 +96,9 @@ impl TemplateRx {
                 roles_logic_sv2::handlers::SendTo_::RelayNewMessage(_, m) => match m {
                     TemplateDistribution::CoinbaseOutputDataSize(_) => todo!(),
                     TemplateDistribution::NewTemplate(m) => {
-                        new_template_sender.send(m).await.unwrap()
+                        let template_id = m.template_id;
+                        new_template_sender.send(m).await.unwrap();
+                        Self::request_transaction_data(self_.clone(), template_id).await;
                     }
                     TemplateDistribution::RequestTransactionData(_) => todo!(),
                     TemplateDistribution::RequestTransactionDataError(_) => todo!(),
@@ -87,6 +108,7 @@ impl TemplateRx {
                     }
                     TemplateDistribution::SubmitSolution(_) => todo!(),
                 },
+                roles_logic_sv2::handlers::SendTo_::None(_) => (),
                 _ => todo!(),
             }
         }
@@ -103,8 +125,45 @@ impl TemplateRx {
         }
     }
 
+    /// Ask the transactions of a template, they are kept by the `BlockAssembler`
+    async fn request_transaction_data(self_: Arc<Mutex<Self>>, template_id: u64) {
+        let request = RequestTransactionData { template_id };
+        let sv2_frame: StdFrame = PoolMessages::TemplateDistribution(
+            TemplateDistribution::RequestTransactionData(request),
+        )
+        .try_into()
+        .unwrap();
+        Self::send(self_, sv2_frame).await.unwrap();
+    }
+
     async fn on_new_solution(self_: Arc<Mutex<Self>>, rx: Receiver<SubmitSolution<'static>>) {
         while let Ok(solution) = rx.recv().await {
+            // The Template Provider publish the block, the pool only check that it can rebuild it
+            let block = self_
+                .safe_lock(|s| s.block_assembler.assemble_solution(&solution))
+                .unwrap();
+            match block {
+                Ok(block) if is_empty_block_template(solution.template_id) => println!(
+                    "Empty block {} found: {}",
+                    block.block_hash(),
+                    serialize_hex(&block)
+                ),
+                Ok(block) => println!(
+                    "Block {} found, {} transactions",
+                    block.block_hash(),
+                    block.txdata.len()
+                ),
+                Err(e) => println!(
+                    "Can not rebuild the block of template {}: {}",
+                    solution.template_id, e
+                ),
+            }
+            // The Template Provider would add the transactions of the template to the block of an
+            // empty block job, that block is only printed above and can be published with
+            // `bitcoin-cli submitblock`
+            if is_empty_block_template(solution.template_id) {
+                continue;
+            }
             let sv2_frame: StdFrame =
                 PoolMessages::TemplateDistribution(TemplateDistribution::SubmitSolution(solution))
                     .try_into()
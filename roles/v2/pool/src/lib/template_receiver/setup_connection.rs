@@ -47,11 +47,11 @@ impl SetupConnectionHandler {
         sender.send(sv2_frame).await.map_err(|_| ())?;
 
         let mut incoming: StdFrame = receiver.recv().await.unwrap().try_into().unwrap();
-        let message_type = incoming.get_header().unwrap().msg_type();
+        let header = incoming.get_header().unwrap();
         let payload = incoming.payload();
         ParseUpstreamCommonMessages::handle_message_common(
             Arc::new(Mutex::new(SetupConnectionHandler {})),
-            message_type,
+            header,
             payload,
             CommonRoutingLogic::None,
         )
@@ -1,13 +1,19 @@
 use async_channel::bounded;
 use codec_sv2::{StandardEitherFrame, StandardSv2Frame};
 use roles_logic_sv2::{
-    bitcoin::{secp256k1::Secp256k1, Network, PrivateKey, PublicKey},
+    bitcoin::{secp256k1::Secp256k1, util::uint::Uint256, Network, PrivateKey, PublicKey, Script},
+    coinbase_outputs::{CoinbaseOutputs, CoinbaseOutputsBuilder},
     parsers::PoolMessages,
+    user_identity::IdentityRules,
+    utils::{ChannelTargetPolicy, Target},
 };
 
 mod lib;
 
-use lib::{mining_pool::Pool, template_receiver::TemplateRx};
+use lib::{
+    mining_pool::{Pool, PoolIdentity},
+    template_receiver::TemplateRx,
+};
 
 pub type Message = PoolMessages<'static>;
 pub type StdFrame = StandardSv2Frame<Message>;
@@ -22,6 +28,45 @@ const NETWORK: Network = Network::Testnet;
 
 const BLOCK_REWARD: u64 = 625_000_000_000;
 
+/// Room that the Template Provider leave in the blocks for the coinbase outputs of the pool,
+/// declared with CoinbaseOutputDataSize
+const COINBASE_OUTPUT_MAX_ADDITIONAL_SIZE: u32 = 200;
+/// If Some, the coinbase outputs (see `roles_logic_sv2::coinbase_outputs` for the syntax), else
+/// the whole reward is paid to the key PRIVATE_KEY_BTC
+const COINBASE_OUTPUTS: Option<&str> = None;
+
+/// Target of the channels that do not declare their hash rate (2^240)
+const INITIAL_TARGET: Uint256 = Uint256([0, 0, 0, 1 << 48]);
+/// If Some, channels that declare their nominal hash rate get a target that make them submit this
+/// many shares per minute
+const SHARES_PER_MINUTE: Option<f32> = None;
+/// No channel get a target easier than this difficulty
+const MIN_DIFFICULTY: f64 = 0.0;
+
+/// Shares for the job replaced by a prev hash are accepted for this many seconds after the prev
+/// hash
+const STALE_SHARE_GRACE_SECS: u64 = 5;
+
+/// If Some, the templates updated by the Template Provider on the same prev hash (refreshed
+/// mempool) are turned in new jobs every this many seconds, only the last one received in the
+/// interval. If None every template is sent as soon as received. Future templates are always sent
+/// immediately.
+const JOB_REFRESH_INTERVAL_SECS: Option<u64> = None;
+
+/// If true on a new prev hash the downstreams get an empty block job (coinbase only, paying the
+/// subsidy) followed by the full job of the template. The blocks found on an empty block job can
+/// not be published by the Template Provider, they are printed by the pool.
+const EMPTY_BLOCK_JOBS: bool = false;
+
+/// A SubmitSharesSuccess is sent every this many accepted shares of a channel, the shares of an
+/// incomplete batch are acknowledged when the batch is complete
+const SHARES_PER_SUCCESS: u32 = 1;
+
+/// Separate the account from the worker name in the user_identity of the channels
+const WORKER_SEPARATOR: char = '.';
+/// If true channels opened without a worker name are rejected
+const REQUIRE_WORKER_NAME: bool = false;
+
 const AUTHORITY_PUBLIC_K: [u8; 32] = [
     215, 11, 47, 78, 34, 232, 25, 192, 195, 168, 170, 209, 95, 181, 40, 114, 154, 226, 176, 190,
     90, 169, 238, 89, 191, 183, 97, 63, 194, 119, 11, 31,
@@ -34,12 +79,93 @@ const AUTHORITY_PRIVATE_K: [u8; 32] = [
 
 const CERT_VALIDITY: std::time::Duration = std::time::Duration::from_secs(3600);
 
+/// Other pool identity served by the process, on its own address
+struct Tenant {
+    address: &'static str,
+    authority_public_k: [u8; 32],
+    authority_private_k: [u8; 32],
+    /// See `roles_logic_sv2::coinbase_outputs` for the syntax
+    coinbase_outputs: &'static str,
+}
+
+/// Identities served together with the one of ADDR, eg for white label operators. Every tenant has
+/// its own authority keys and coinbase outputs, the Template Provider, the channel ids and the
+/// share accounting are shared.
+const TENANTS: &[Tenant] = &[];
+
+fn target_policy() -> ChannelTargetPolicy {
+    ChannelTargetPolicy {
+        initial_target: Target::new(INITIAL_TARGET),
+        shares_per_minute: SHARES_PER_MINUTE,
+        min_difficulty: MIN_DIFFICULTY,
+    }
+}
+
+fn stale_share_grace_period() -> std::time::Duration {
+    std::time::Duration::from_secs(STALE_SHARE_GRACE_SECS)
+}
+
+fn job_refresh_interval() -> Option<std::time::Duration> {
+    JOB_REFRESH_INTERVAL_SECS.map(std::time::Duration::from_secs)
+}
+
+/// Channels with a user_identity that do not follow the rules are rejected with `unknown-user`
+fn identity_rules() -> IdentityRules {
+    IdentityRules {
+        separator: WORKER_SEPARATOR,
+        require_worker: REQUIRE_WORKER_NAME,
+        ..IdentityRules::default()
+    }
+}
+
+fn identities() -> Vec<PoolIdentity> {
+    let mut identities = vec![PoolIdentity {
+        address: ADDR.parse().unwrap(),
+        authority_public_k: AUTHORITY_PUBLIC_K,
+        authority_private_k: AUTHORITY_PRIVATE_K,
+        cert_validity: CERT_VALIDITY,
+        coinbase_outputs: coinbase_outputs(),
+    }];
+    for tenant in TENANTS {
+        identities.push(PoolIdentity {
+            address: tenant.address.parse().expect("Invalid tenant address"),
+            authority_public_k: tenant.authority_public_k,
+            authority_private_k: tenant.authority_private_k,
+            cert_validity: CERT_VALIDITY,
+            coinbase_outputs: CoinbaseOutputs::parse(
+                tenant.coinbase_outputs,
+                NETWORK,
+                COINBASE_OUTPUT_MAX_ADDITIONAL_SIZE,
+            )
+            .expect("Invalid tenant coinbase outputs"),
+        });
+    }
+    identities
+}
+
 fn new_pub_key() -> PublicKey {
     let priv_k = PrivateKey::from_slice(&PRIVATE_KEY_BTC, NETWORK).unwrap();
     let secp = Secp256k1::default();
     PublicKey::from_private_key(&secp, &priv_k)
 }
 
+fn coinbase_outputs() -> CoinbaseOutputs {
+    match COINBASE_OUTPUTS {
+        Some(config) => {
+            CoinbaseOutputs::parse(config, NETWORK, COINBASE_OUTPUT_MAX_ADDITIONAL_SIZE)
+                .expect("Invalid coinbase outputs")
+        }
+        None => {
+            // The key is compressed
+            let script = Script::new_v0_wpkh(&new_pub_key().wpubkey_hash().unwrap());
+            CoinbaseOutputsBuilder::new()
+                .pool(script)
+                .build(COINBASE_OUTPUT_MAX_ADDITIONAL_SIZE)
+                .unwrap()
+        }
+    }
+}
+
 #[async_std::main]
 async fn main() {
     //let test: bool = std::env::var("TEST").unwrap().parse().unwrap();
@@ -51,7 +177,34 @@ async fn main() {
         crate::lib::template_receiver::test_template::TestTemplateRx::start(s_new_t, s_prev_hash)
             .await;
     } else {
-        TemplateRx::connect(TP_ADDR.parse().unwrap(), s_new_t, s_prev_hash, r_solution).await;
+        TemplateRx::connect(
+            TP_ADDR.parse().unwrap(),
+            s_new_t,
+            s_prev_hash,
+            r_solution,
+            COINBASE_OUTPUT_MAX_ADDITIONAL_SIZE,
+        )
+        .await;
     }
-    Pool::start(r_new_t, r_prev_hash, s_solution).await;
+    let (s_events, r_events) = bounded(100);
+    async_std::task::spawn(async move {
+        while let Ok(event) = r_events.recv().await {
+            println!("{:?}", event);
+        }
+    });
+    let (s_share_log, r_share_log) = bounded(100);
+    async_std::task::spawn(async move {
+        while let Ok(entry) = r_share_log.recv().await {
+            println!("{}", entry);
+        }
+    });
+    Pool::start(
+        identities(),
+        r_new_t,
+        r_prev_hash,
+        s_solution,
+        s_events,
+        s_share_log,
+    )
+    .await;
 }
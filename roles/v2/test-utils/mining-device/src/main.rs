@@ -32,7 +32,7 @@ use async_channel::{Receiver, Sender};
 use binary_sv2::u256_from_int;
 use codec_sv2::{Frame, StandardEitherFrame, StandardSv2Frame};
 use roles_logic_sv2::{
-    common_messages_sv2::{Protocol, SetupConnection, SetupConnectionSuccess},
+    common_messages_sv2::{MiningFlags, Protocol, SetupConnection, SetupConnectionSuccess},
     common_properties::{IsMiningUpstream, IsUpstream},
     errors::Error,
     handlers::{
@@ -40,7 +40,7 @@ use roles_logic_sv2::{
         mining::{ParseUpstreamMiningMessages, SendTo, SupportedChannelTypes},
     },
     mining_sv2::*,
-    parsers::{Mining, MiningDeviceMessages},
+    parsers::{CommonMessageTypes, Mining, MiningDeviceMessages},
     routing_logic::{CommonRoutingLogic, MiningRoutingLogic, NoRouting},
     selectors::NullDownstreamMiningSelector,
     utils::Mutex,
@@ -51,7 +51,7 @@ pub type StdFrame = StandardSv2Frame<Message>;
 pub type EitherFrame = StandardEitherFrame<Message>;
 
 struct SetupConnectionHandler {}
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 
 impl SetupConnectionHandler {
     pub fn new() -> Self {
@@ -67,7 +67,7 @@ impl SetupConnectionHandler {
             protocol: Protocol::MiningProtocol,
             min_version: 2,
             max_version: 2,
-            flags: 0b1000_0000_0000_0000_0000_0000_0000_0000,
+            flags: MiningFlags::REQUIRES_STANDARD_JOBS.bits(),
             endpoint_host,
             endpoint_port: address.port(),
             vendor,
@@ -91,11 +91,11 @@ impl SetupConnectionHandler {
         sender.send(sv2_frame).await.unwrap();
 
         let mut incoming: StdFrame = receiver.recv().await.unwrap().try_into().unwrap();
-        let message_type = incoming.get_header().unwrap().msg_type();
+        let header = incoming.get_header().unwrap();
         let payload = incoming.payload();
         ParseUpstreamCommonMessages::handle_message_common(
             self_,
-            message_type,
+            header,
             payload,
             CommonRoutingLogic::None,
         )
@@ -132,7 +132,6 @@ pub struct Device {
     #[allow(dead_code)]
     receiver: Receiver<EitherFrame>,
     sender: Sender<EitherFrame>,
-    #[allow(dead_code)]
     channel_opened: bool,
     channel_id: Option<u32>,
     miner: Arc<Mutex<Miner>>,
@@ -210,11 +209,30 @@ impl Device {
 
         loop {
             let mut incoming: StdFrame = receiver.recv().await.unwrap().try_into().unwrap();
-            let message_type = incoming.get_header().unwrap().msg_type();
+            let header = incoming.get_header().unwrap();
+            let message_type = header.msg_type();
             let payload = incoming.payload();
+            if CommonMessageTypes::try_from(message_type).is_ok() {
+                ParseUpstreamCommonMessages::handle_message_common(
+                    self_mutex.clone(),
+                    header,
+                    payload,
+                    CommonRoutingLogic::None,
+                )
+                .unwrap();
+                // Channel has been reset by ChannelEndpointChanged open a new one
+                if !self_mutex.safe_lock(|s| s.channel_opened).unwrap() {
+                    let open_channel = MiningDeviceMessages::Mining(
+                        Mining::OpenStandardMiningChannel(self::open_channel()),
+                    );
+                    let frame: StdFrame = open_channel.try_into().unwrap();
+                    sender.send(frame.into()).await.unwrap();
+                }
+                continue;
+            }
             let next = Device::handle_message_mining(
                 self_mutex.clone(),
-                message_type,
+                header,
                 payload,
                 MiningRoutingLogic::None,
             )
@@ -238,9 +256,14 @@ impl Device {
         version: u32,
         ntime: u32,
     ) {
+        // Channel could have been reset by ChannelEndpointChanged
+        let channel_id = match self_mutex.safe_lock(|s| s.channel_id).unwrap() {
+            Some(channel_id) => channel_id,
+            None => return,
+        };
         let share =
             MiningDeviceMessages::Mining(Mining::SubmitSharesStandard(SubmitSharesStandard {
-                channel_id: self_mutex.safe_lock(|s| s.channel_id.unwrap()).unwrap(),
+                channel_id,
                 sequence_number: self_mutex.safe_lock(|s| s.sequence_numbers.next()).unwrap(),
                 job_id,
                 nonce,
@@ -253,6 +276,41 @@ impl Device {
     }
 }
 
+impl ParseUpstreamCommonMessages<NoRouting> for Device {
+    fn handle_setup_connection_success(
+        &mut self,
+        _: SetupConnectionSuccess,
+    ) -> Result<roles_logic_sv2::handlers::common::SendTo, Error> {
+        Err(Error::UnexpectedMessage)
+    }
+
+    fn handle_setup_connection_error(
+        &mut self,
+        _: roles_logic_sv2::common_messages_sv2::SetupConnectionError,
+    ) -> Result<roles_logic_sv2::handlers::common::SendTo, Error> {
+        Err(Error::UnexpectedMessage)
+    }
+
+    /// The channel has been remapped to another upstream: drop the channel state, a new channel
+    /// is opened by the caller
+    fn handle_channel_endpoint_changed(
+        &mut self,
+        m: roles_logic_sv2::common_messages_sv2::ChannelEndpointChanged,
+    ) -> Result<roles_logic_sv2::handlers::common::SendTo, Error> {
+        use roles_logic_sv2::handlers::common::SendTo;
+        if self.channel_id == Some(m.channel_id) {
+            println!("MINING DEVICE: channel {} endpoint changed", m.channel_id);
+            self.channel_opened = false;
+            self.channel_id = None;
+            self.jobs = Vec::new();
+            self.prev_hash = None;
+            self.sequence_numbers = Id::new();
+            self.miner.safe_lock(|miner| miner.reset()).unwrap();
+        }
+        Ok(SendTo::None(None))
+    }
+}
+
 impl IsUpstream<(), NullDownstreamMiningSelector> for Device {
     fn get_version(&self) -> u16 {
         todo!()
@@ -455,6 +513,14 @@ impl Miner {
         }
     }
 
+    /// Stop mining until a new channel is opened
+    fn reset(&mut self) {
+        self.header = None;
+        self.target = None;
+        self.job_id = None;
+        self.version = None;
+    }
+
     fn new_target(&mut self, target: Vec<u8>) {
         self.target = Some(Uint256::from_be_bytes(target.try_into().unwrap()));
     }
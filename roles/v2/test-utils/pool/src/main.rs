@@ -102,11 +102,15 @@ impl Default for Id {
 
 struct SetupConnectionHandler {
     header_only: Option<bool>,
+    version: Option<u16>,
 }
 
 impl SetupConnectionHandler {
     pub fn new() -> Self {
-        Self { header_only: None }
+        Self {
+            header_only: None,
+            version: None,
+        }
     }
     pub async fn setup(
         self_: Arc<Mutex<Self>>,
@@ -114,11 +118,11 @@ impl SetupConnectionHandler {
         sender: &mut Sender<EitherFrame>,
     ) -> bool {
         let mut incoming: StdFrame = receiver.recv().await.unwrap().try_into().unwrap();
-        let message_type = incoming.get_header().unwrap().msg_type();
+        let header = incoming.get_header().unwrap();
         let payload = incoming.payload();
         let response = ParseDownstreamCommonMessages::handle_message_common(
             self_.clone(),
-            message_type,
+            header,
             payload,
             CommonRoutingLogic::None,
         )
@@ -147,10 +151,14 @@ impl ParseDownstreamCommonMessages<NoRouting> for SetupConnectionHandler {
             Arc::new(Mutex::new(())),
             CommonMessages::SetupConnectionSuccess(SetupConnectionSuccess {
                 flags: 0,
-                used_version: 2,
+                used_version: self.version.unwrap(),
             }),
         ))
     }
+
+    fn on_version_negotiated(&mut self, version: u16) {
+        self.version = Some(version);
+    }
 }
 
 #[derive(Debug)]
@@ -201,11 +209,11 @@ impl Downstream {
     }
 
     pub async fn next(self_mutex: Arc<Mutex<Self>>, mut incoming: StdFrame) {
-        let message_type = incoming.get_header().unwrap().msg_type();
+        let header = incoming.get_header().unwrap();
         let payload = incoming.payload();
         let next_message_to_send = ParseDownstreamMiningMessages::handle_message_mining(
             self_mutex.clone(),
-            message_type,
+            header,
             payload,
             MiningRoutingLogic::None,
         );
@@ -241,6 +249,9 @@ impl IsDownstream for Downstream {
             header_only: false,
             work_selection: false,
             version_rolling: false,
+            flags: 0,
+            device: Default::default(),
+            remote_address: None,
         }
     }
 }
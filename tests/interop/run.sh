@@ -0,0 +1,24 @@
+#! /bin/sh
+#
+# Run the interop tests against a containerized third party Responder.
+#
+# usage: ./run.sh <docker image> <container port> <responder authority public key as 64 hex chars>
+#
+# The image must start an Sv2 upstream (pool or proxy) listening on <container port> that accept
+# standard channels.
+
+set -e
+
+IMAGE=$1
+PORT=$2
+AUTHORITY=$3
+
+CONTAINER=$(docker run -d -p "$PORT:$PORT" "$IMAGE")
+trap 'docker rm -f "$CONTAINER" > /dev/null' EXIT
+
+# Give the responder the time to start
+sleep 5
+
+SV2_INTEROP_RESPONDER="127.0.0.1:$PORT" \
+SV2_INTEROP_RESPONDER_AUTHORITY="$AUTHORITY" \
+cargo test -p interop_tests --test third_party initiator_against_third_party_responder -- --ignored --nocapture
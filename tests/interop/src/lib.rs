@@ -0,0 +1,548 @@
+//! Interoperability test harness.
+//!
+//! The harness drive one side of an Sv2 connection, either as Initiator (downstream) or as
+//! Responder (upstream), and check every step of the exchange:
+//! 1. noise handshake, when the Initiator is driven by the harness the certificate of the
+//!    Responder is verified against the authority public key
+//! 2. SetupConnection -> SetupConnectionSuccess
+//! 3. OpenStandardMiningChannel -> OpenStandardMiningChannelSuccess
+//! 4. NewMiningJob and SetNewPrevHash
+//! 5. SubmitSharesStandard -> SubmitSharesSuccess or SubmitSharesError
+//!
+//! The other side can be this crate (the harness is run against itself so that it is always
+//! tested) or a third party implementation, see the README.
+//!
+//! The handshake and the codec are driven directly (not with `network_helpers`) so that every
+//! failure is reported as an `InteropError` that say at which step the two implementations
+//! diverged instead of panicking in a background task.
+//!
+//! `Sv2Stream` work on any stream, `sim` provide in-memory streams with injectable faults to
+//! test the error paths without a real network. The roles that accept plain connections (eg the
+//! downstream side of the mining proxy) are driven with `Sv2Stream::plain`.
+use async_std::{
+    io::{timeout, Read, Write},
+    net::TcpStream,
+    prelude::*,
+};
+use binary_sv2::{u256_from_int, U256};
+use codec_sv2::{
+    Encoder, Frame, HandShakeFrame, HandshakeRole, Initiator, NoiseEncoder, Responder,
+    StandardDecoder, StandardEitherFrame, StandardNoiseDecoder, StandardSv2Frame, State,
+};
+use roles_logic_sv2::{
+    common_messages_sv2::{MiningFlags, Protocol, SetupConnection, SetupConnectionSuccess},
+    mining_sv2::{
+        NewMiningJob, OpenStandardMiningChannel, OpenStandardMiningChannelSuccess, SetNewPrevHash,
+        SubmitSharesStandard, SubmitSharesSuccess,
+    },
+    parsers::{CommonMessages, Mining, PoolMessages},
+};
+use std::{convert::TryInto, net::SocketAddr, time::Duration};
+
+pub mod sim;
+
+pub type Message = PoolMessages<'static>;
+pub type StdFrame = StandardSv2Frame<Message>;
+pub type EitherFrame = StandardEitherFrame<Message>;
+
+/// Max time that the harness wait for a frame from the other side, see `Sv2Stream::set_recv_timeout`
+pub const RECV_TIMEOUT: Duration = Duration::from_secs(10);
+/// Max number of unexpected messages (eg SetTarget) skipped while waiting for a message
+const MAX_SKIPPED_MESSAGES: usize = 16;
+
+#[derive(Debug)]
+pub enum InteropError {
+    Io(std::io::Error),
+    /// The noise handshake failed, when the harness is the Initiator this include an invalid
+    /// certificate
+    Handshake,
+    /// A frame could not be encoded or decoded
+    Codec(String),
+    /// A well formed message was received but it is not the expected one
+    UnexpectedMessage {
+        expected: &'static str,
+        received: u8,
+    },
+    /// The message was received but its content is wrong
+    InvalidMessage(&'static str),
+}
+
+impl From<std::io::Error> for InteropError {
+    fn from(e: std::io::Error) -> Self {
+        InteropError::Io(e)
+    }
+}
+
+/// A noise connection driven step by step, over a `TcpStream` or a `sim::SimStream`
+pub struct Sv2Stream<S = TcpStream> {
+    stream: S,
+    state: State,
+    decoder: StandardNoiseDecoder<Message>,
+    encoder: NoiseEncoder<Message>,
+    /// Set if the connection is not encrypted
+    plain: Option<PlainCodec>,
+    recv_timeout: Duration,
+}
+
+struct PlainCodec {
+    decoder: StandardDecoder<Message>,
+    encoder: Encoder<Message>,
+}
+
+impl Sv2Stream<TcpStream> {
+    /// Connect to `address` and do the handshake as Initiator, the Responder certificate must be
+    /// signed by `authority_public_key`
+    pub async fn connect(
+        address: SocketAddr,
+        authority_public_key: [u8; 32],
+    ) -> Result<Self, InteropError> {
+        let stream = TcpStream::connect(address).await?;
+        Self::initiate(stream, authority_public_key).await
+    }
+
+    /// Connect to `address` without noise
+    pub async fn connect_plain(address: SocketAddr) -> Result<Self, InteropError> {
+        let stream = TcpStream::connect(address).await?;
+        Ok(Self::plain(stream))
+    }
+}
+
+impl<S: Read + Write + Unpin> Sv2Stream<S> {
+    /// Do the handshake as Initiator on a connected stream, the Responder certificate must be
+    /// signed by `authority_public_key`
+    pub async fn initiate(stream: S, authority_public_key: [u8; 32]) -> Result<Self, InteropError> {
+        let initiator =
+            Initiator::from_raw_k(authority_public_key).map_err(|_| InteropError::Handshake)?;
+        let mut self_ = Self::new(
+            stream,
+            State::initialize(HandshakeRole::Initiator(initiator)),
+        );
+
+        let first_message = self_
+            .state
+            .step(None)
+            .map_err(|_| InteropError::Handshake)?;
+        self_.send_frame(first_message.into()).await?;
+        let second_message = self_.recv_handshake_frame().await?;
+        self_
+            .state
+            .step(Some(second_message))
+            .map_err(|_| InteropError::Handshake)?;
+        self_.into_transport_mode()
+    }
+
+    /// Use a connected stream without noise, the frames are sent and received in clear
+    pub fn plain(stream: S) -> Self {
+        let mut self_ = Self::new(stream, State::new());
+        self_.plain = Some(PlainCodec {
+            decoder: StandardDecoder::<Message>::new(),
+            encoder: Encoder::<Message>::new(),
+        });
+        self_
+    }
+
+    /// Do the handshake as Responder on an accepted connection
+    pub async fn accept(stream: S, responder: Responder) -> Result<Self, InteropError> {
+        let mut self_ = Self::new(
+            stream,
+            State::initialize(HandshakeRole::Responder(responder)),
+        );
+
+        let first_message = self_.recv_handshake_frame().await?;
+        let second_message = self_
+            .state
+            .step(Some(first_message))
+            .map_err(|_| InteropError::Handshake)?;
+        self_.send_frame(second_message.into()).await?;
+        self_.into_transport_mode()
+    }
+
+    fn new(stream: S, state: State) -> Self {
+        Self {
+            stream,
+            state,
+            decoder: StandardNoiseDecoder::<Message>::new(),
+            encoder: NoiseEncoder::<Message>::new(),
+            plain: None,
+            recv_timeout: RECV_TIMEOUT,
+        }
+    }
+
+    /// Max time that `recv` wait for a frame, default to `RECV_TIMEOUT`
+    pub fn set_recv_timeout(&mut self, recv_timeout: Duration) {
+        self.recv_timeout = recv_timeout;
+    }
+
+    fn into_transport_mode(mut self) -> Result<Self, InteropError> {
+        let state = std::mem::replace(&mut self.state, State::new());
+        self.state = state
+            .into_transport_mode()
+            .map_err(|_| InteropError::Handshake)?;
+        Ok(self)
+    }
+
+    async fn send_frame(&mut self, frame: EitherFrame) -> Result<(), InteropError> {
+        let bytes = self
+            .encoder
+            .encode(frame, &mut self.state)
+            .map_err(|e| InteropError::Codec(format!("{:?}", e)))?;
+        self.stream.write_all(bytes).await?;
+        Ok(())
+    }
+
+    async fn recv_frame(&mut self) -> Result<EitherFrame, InteropError> {
+        loop {
+            let writable = self.decoder.writable();
+            timeout(self.recv_timeout, self.stream.read_exact(writable)).await?;
+            match self.decoder.next_frame(&mut self.state) {
+                Ok(frame) => return Ok(frame),
+                Err(codec_sv2::Error::MissingBytes(_)) => (),
+                Err(e) => return Err(InteropError::Codec(format!("{:?}", e))),
+            }
+        }
+    }
+
+    async fn recv_handshake_frame(&mut self) -> Result<Vec<u8>, InteropError> {
+        let mut frame: HandShakeFrame = self
+            .recv_frame()
+            .await?
+            .try_into()
+            .map_err(|_| InteropError::Codec("Expected an handshake frame".to_string()))?;
+        Ok(frame.payload().to_vec())
+    }
+
+    /// Write `bytes` as they are, to send a malformed frame on a plain connection
+    pub async fn send_raw(&mut self, bytes: &[u8]) -> Result<(), InteropError> {
+        self.stream.write_all(bytes).await?;
+        Ok(())
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<(), InteropError> {
+        let frame: StdFrame = message
+            .try_into()
+            .map_err(|e| InteropError::Codec(format!("{:?}", e)))?;
+        if let Some(plain) = &mut self.plain {
+            let bytes = plain
+                .encoder
+                .encode(frame)
+                .map_err(|e| InteropError::Codec(format!("{:?}", e)))?;
+            self.stream.write_all(bytes).await?;
+            return Ok(());
+        }
+        self.send_frame(frame.into()).await
+    }
+
+    /// Receive the next Sv2 frame, use `decode` to get the message
+    pub async fn recv(&mut self) -> Result<StdFrame, InteropError> {
+        if let Some(plain) = &mut self.plain {
+            loop {
+                let writable = plain.decoder.writable();
+                timeout(self.recv_timeout, self.stream.read_exact(writable)).await?;
+                match plain.decoder.next_frame() {
+                    Ok(frame) => return Ok(frame),
+                    Err(codec_sv2::Error::MissingBytes(_)) => (),
+                    Err(e) => return Err(InteropError::Codec(format!("{:?}", e))),
+                }
+            }
+        }
+        self.recv_frame()
+            .await?
+            .try_into()
+            .map_err(|_| InteropError::Codec("Expected an Sv2 frame".to_string()))
+    }
+}
+
+/// Decode the message carried by `frame`
+pub fn decode(frame: &mut StdFrame) -> Result<PoolMessages<'_>, InteropError> {
+    let header = frame
+        .get_header()
+        .ok_or_else(|| InteropError::Codec("Frame without header".to_string()))?;
+    (header.msg_type(), frame.payload())
+        .try_into()
+        .map_err(|e| InteropError::Codec(format!("{:?}", e)))
+}
+
+fn message_type(frame: &StdFrame) -> u8 {
+    // Frames received from the decoder always have an header
+    frame.get_header().unwrap().msg_type()
+}
+
+/// What the downstream side of the exchange received
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownstreamReport {
+    pub used_version: u16,
+    pub channel_id: u32,
+    pub job_id: u32,
+    /// True if the share has been accepted, the share is not valid so most upstreams reject it
+    pub share_accepted: bool,
+}
+
+pub fn setup_connection(address: SocketAddr) -> SetupConnection<'static> {
+    SetupConnection {
+        protocol: Protocol::MiningProtocol,
+        min_version: 2,
+        max_version: 2,
+        // The harness open only standard channels
+        flags: MiningFlags::REQUIRES_STANDARD_JOBS.bits(),
+        endpoint_host: address.ip().to_string().into_bytes().try_into().unwrap(),
+        endpoint_port: address.port(),
+        vendor: "interop-tests".to_string().try_into().unwrap(),
+        hardware_version: String::new().try_into().unwrap(),
+        firmware: String::new().try_into().unwrap(),
+        device_id: String::new().try_into().unwrap(),
+    }
+}
+
+/// Drive the downstream side of the exchange
+pub async fn run_downstream<S: Read + Write + Unpin>(
+    connection: &mut Sv2Stream<S>,
+    address: SocketAddr,
+) -> Result<DownstreamReport, InteropError> {
+    connection.send(setup_connection(address).into()).await?;
+    let mut frame = connection.recv().await?;
+    let used_version = match decode(&mut frame)? {
+        PoolMessages::Common(CommonMessages::SetupConnectionSuccess(m)) => m.used_version,
+        _ => {
+            return Err(InteropError::UnexpectedMessage {
+                expected: "SetupConnectionSuccess",
+                received: message_type(&frame),
+            })
+        }
+    };
+
+    let request_id: u32 = 1;
+    let open_channel = OpenStandardMiningChannel {
+        request_id: request_id.into(),
+        user_identity: "interop-tests".to_string().try_into().unwrap(),
+        nominal_hash_rate: 1.0,
+        max_target: u256_from_int(u64::MAX),
+    };
+    connection
+        .send(PoolMessages::Mining(Mining::OpenStandardMiningChannel(
+            open_channel,
+        )))
+        .await?;
+    let mut frame = connection.recv().await?;
+    let channel_id = match decode(&mut frame)? {
+        PoolMessages::Mining(Mining::OpenStandardMiningChannelSuccess(m)) => {
+            if m.get_request_id_as_u32() != request_id {
+                return Err(InteropError::InvalidMessage(
+                    "OpenStandardMiningChannelSuccess with wrong request id",
+                ));
+            }
+            m.channel_id
+        }
+        _ => {
+            return Err(InteropError::UnexpectedMessage {
+                expected: "OpenStandardMiningChannelSuccess",
+                received: message_type(&frame),
+            })
+        }
+    };
+
+    // Wait for a job and a prev hash, other messages (eg SetTarget) are skipped
+    let mut job = None;
+    let mut prev_hash = None;
+    let mut skipped = 0;
+    while job.is_none() || prev_hash.is_none() {
+        let mut frame = connection.recv().await?;
+        match decode(&mut frame)? {
+            PoolMessages::Mining(Mining::NewMiningJob(m)) if m.channel_id == channel_id => {
+                job = Some((m.job_id, m.version));
+            }
+            PoolMessages::Mining(Mining::SetNewPrevHash(m)) => {
+                prev_hash = Some(m.min_ntime);
+            }
+            _ if skipped < MAX_SKIPPED_MESSAGES => skipped += 1,
+            _ => {
+                return Err(InteropError::UnexpectedMessage {
+                    expected: "NewMiningJob or SetNewPrevHash",
+                    received: message_type(&frame),
+                })
+            }
+        }
+    }
+    let (job_id, version) = job.unwrap();
+    let ntime = prev_hash.unwrap();
+
+    let share = SubmitSharesStandard {
+        channel_id,
+        sequence_number: 0,
+        job_id,
+        nonce: 0,
+        ntime,
+        version,
+    };
+    connection
+        .send(PoolMessages::Mining(Mining::SubmitSharesStandard(share)))
+        .await?;
+    let mut skipped = 0;
+    let share_accepted = loop {
+        let mut frame = connection.recv().await?;
+        match decode(&mut frame)? {
+            PoolMessages::Mining(Mining::SubmitSharesSuccess(m)) if m.channel_id == channel_id => {
+                break true
+            }
+            PoolMessages::Mining(Mining::SubmitSharesError(m)) if m.channel_id == channel_id => {
+                break false
+            }
+            _ if skipped < MAX_SKIPPED_MESSAGES => skipped += 1,
+            _ => {
+                return Err(InteropError::UnexpectedMessage {
+                    expected: "SubmitSharesSuccess or SubmitSharesError",
+                    received: message_type(&frame),
+                })
+            }
+        }
+    };
+
+    Ok(DownstreamReport {
+        used_version,
+        channel_id,
+        job_id,
+        share_accepted,
+    })
+}
+
+/// Drive the upstream side of the exchange, every share is accepted
+pub async fn run_upstream<S: Read + Write + Unpin>(
+    connection: &mut Sv2Stream<S>,
+) -> Result<(), InteropError> {
+    let mut frame = connection.recv().await?;
+    let flags = match decode(&mut frame)? {
+        PoolMessages::Common(CommonMessages::SetupConnection(m)) => {
+            if m.protocol != Protocol::MiningProtocol {
+                return Err(InteropError::InvalidMessage(
+                    "SetupConnection for a protocol other than mining",
+                ));
+            }
+            if m.min_version > 2 || m.max_version < 2 {
+                return Err(InteropError::InvalidMessage(
+                    "SetupConnection do not support version 2",
+                ));
+            }
+            m.flags
+        }
+        _ => {
+            return Err(InteropError::UnexpectedMessage {
+                expected: "SetupConnection",
+                received: message_type(&frame),
+            })
+        }
+    };
+    let success = SetupConnectionSuccess {
+        used_version: 2,
+        flags,
+    };
+    connection.send(success.into()).await?;
+
+    let mut frame = connection.recv().await?;
+    let request_id = match decode(&mut frame)? {
+        PoolMessages::Mining(Mining::OpenStandardMiningChannel(m)) => m.get_request_id_as_u32(),
+        _ => {
+            return Err(InteropError::UnexpectedMessage {
+                expected: "OpenStandardMiningChannel",
+                received: message_type(&frame),
+            })
+        }
+    };
+    let channel_id = 1;
+    let target: U256 = [0xff; 32].to_vec().try_into().unwrap();
+    let success = OpenStandardMiningChannelSuccess {
+        request_id: request_id.into(),
+        channel_id,
+        target,
+        extranonce_prefix: vec![0; 16].try_into().unwrap(),
+        group_channel_id: 0,
+    };
+    connection
+        .send(PoolMessages::Mining(
+            Mining::OpenStandardMiningChannelSuccess(success),
+        ))
+        .await?;
+
+    let job_id = 1;
+    let job = NewMiningJob {
+        channel_id,
+        job_id,
+        future_job: true,
+        version: 0x2000_0000,
+        merkle_root: vec![0; 32].try_into().unwrap(),
+    };
+    connection
+        .send(PoolMessages::Mining(Mining::NewMiningJob(job)))
+        .await?;
+    let prev_hash = SetNewPrevHash {
+        channel_id,
+        job_id,
+        prev_hash: vec![0; 32].try_into().unwrap(),
+        min_ntime: 0,
+        nbits: 0x1d00_ffff,
+    };
+    connection
+        .send(PoolMessages::Mining(Mining::SetNewPrevHash(prev_hash)))
+        .await?;
+
+    let mut frame = connection.recv().await?;
+    let sequence_number = match decode(&mut frame)? {
+        PoolMessages::Mining(Mining::SubmitSharesStandard(m)) => {
+            if m.channel_id != channel_id || m.job_id != job_id {
+                return Err(InteropError::InvalidMessage(
+                    "SubmitSharesStandard for an unknown channel or job",
+                ));
+            }
+            m.sequence_number
+        }
+        _ => {
+            return Err(InteropError::UnexpectedMessage {
+                expected: "SubmitSharesStandard",
+                received: message_type(&frame),
+            })
+        }
+    };
+    let success = SubmitSharesSuccess {
+        channel_id,
+        last_sequence_number: sequence_number,
+        new_submits_accepted_count: 1,
+        new_shares_sum: 1,
+    };
+    connection
+        .send(PoolMessages::Mining(Mining::SubmitSharesSuccess(success)))
+        .await
+}
+
+/// Free local address, eg for the listener of a role under test
+pub fn free_address() -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// Prefix and suffix of a coinbase with one input whose script_sig is the extranonce, the proxies
+/// compute the merkle root of the standard jobs from it
+pub fn coinbase(extranonce_len: usize) -> (Vec<u8>, Vec<u8>) {
+    // version, inputs count, null outpoint, script_sig len
+    let mut prefix = vec![1, 0, 0, 0, 1];
+    prefix.extend_from_slice(&[0; 32]);
+    prefix.extend_from_slice(&[0xff; 4]);
+    prefix.push(extranonce_len as u8);
+    // sequence, outputs count, value, script_pubkey len, lock time
+    let mut suffix = vec![0xff; 4];
+    suffix.push(1);
+    suffix.extend_from_slice(&[0; 8]);
+    suffix.push(0);
+    suffix.extend_from_slice(&[0; 4]);
+    (prefix, suffix)
+}
+
+/// Parse an authority public key given as 64 hex chars
+pub fn parse_public_key(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
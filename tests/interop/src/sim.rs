@@ -0,0 +1,295 @@
+//! Simulated network: in-memory connections with injectable faults.
+//!
+//! `duplex` return the two ends of a connection that can be used in place of a `TcpStream` (eg
+//! with `Sv2Stream::initiate` and `Sv2Stream::accept`). Every write on one end is a packet that is
+//! delivered to the other end through a link, the `Faults` of the link can delay, drop, truncate
+//! or reorder the packets. The faults hit every nth packet, so the tests are reproducible.
+//!
+//! `relay` put the same links between a TCP client and a TCP server, so that the roles that only
+//! connect to or listen on an address can be tested behind a faulty network. On TCP a packet is
+//! what a single read returned, that is usually but not always a whole frame.
+use async_channel::{unbounded, Receiver, Sender};
+use async_std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    prelude::*,
+    task,
+};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Faults of one direction of a connection, the default is a perfect link
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Faults {
+    /// Every packet is delivered after this delay
+    pub delay: Duration,
+    /// Drop every nth packet
+    pub drop_every: Option<usize>,
+    /// Deliver only the first half of every nth packet
+    pub truncate_every: Option<usize>,
+    /// Deliver every nth packet after the packet that follow it
+    pub reorder_every: Option<usize>,
+    /// Close the link after this number of packets
+    pub close_after: Option<usize>,
+}
+
+impl Faults {
+    pub fn delay(delay: Duration) -> Self {
+        Self {
+            delay,
+            ..Default::default()
+        }
+    }
+
+    pub fn drop_every(n: usize) -> Self {
+        Self {
+            drop_every: Some(n),
+            ..Default::default()
+        }
+    }
+
+    pub fn truncate_every(n: usize) -> Self {
+        Self {
+            truncate_every: Some(n),
+            ..Default::default()
+        }
+    }
+
+    pub fn reorder_every(n: usize) -> Self {
+        Self {
+            reorder_every: Some(n),
+            ..Default::default()
+        }
+    }
+
+    pub fn close_after(n: usize) -> Self {
+        Self {
+            close_after: Some(n),
+            ..Default::default()
+        }
+    }
+}
+
+fn hits(every: Option<usize>, packet_number: usize) -> bool {
+    matches!(every, Some(n) if packet_number.checked_rem(n) == Some(0))
+}
+
+/// State of a link, apply the faults to the packets in the order in which they are sent
+#[derive(Debug)]
+pub struct FaultyLink {
+    faults: Faults,
+    sent: usize,
+    // Packet that wait for the next one in order to be reordered
+    held: Option<Vec<u8>>,
+}
+
+impl FaultyLink {
+    pub fn new(faults: Faults) -> Self {
+        Self {
+            faults,
+            sent: 0,
+            held: None,
+        }
+    }
+
+    /// Packets to deliver after that `packet` has been sent, None if the link is closed
+    pub fn on_packet(&mut self, mut packet: Vec<u8>) -> Option<Vec<Vec<u8>>> {
+        if matches!(self.faults.close_after, Some(n) if self.sent >= n) {
+            return None;
+        }
+        self.sent += 1;
+        if hits(self.faults.drop_every, self.sent) {
+            return Some(vec![]);
+        }
+        if hits(self.faults.truncate_every, self.sent) {
+            packet.truncate(packet.len() / 2);
+        }
+        if let Some(held) = self.held.take() {
+            return Some(vec![packet, held]);
+        }
+        if hits(self.faults.reorder_every, self.sent) {
+            self.held = Some(packet);
+            return Some(vec![]);
+        }
+        Some(vec![packet])
+    }
+
+    /// The sender closed the connection, return the packet held for reordering if any
+    pub fn on_close(&mut self) -> Option<Vec<u8>> {
+        self.held.take()
+    }
+}
+
+async fn run_link(faults: Faults, receiver: Receiver<Vec<u8>>, sender: Sender<Vec<u8>>) {
+    let delay = faults.delay;
+    let mut link = FaultyLink::new(faults);
+    while let Ok(packet) = receiver.recv().await {
+        if !delay.is_zero() {
+            task::sleep(delay).await;
+        }
+        match link.on_packet(packet) {
+            Some(packets) => {
+                for packet in packets {
+                    if sender.send(packet).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            None => return,
+        }
+    }
+    if let Some(packet) = link.on_close() {
+        let _ = sender.send(packet).await;
+    }
+}
+
+/// Spawn a link with `faults`, return the sender of the packets and the receiver of the
+/// delivered packets. The link is closed when the sender or the receiver is dropped.
+fn link(faults: Faults) -> (Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+    let (sender, link_receiver) = unbounded();
+    let (link_sender, receiver) = unbounded();
+    task::spawn(run_link(faults, link_receiver, link_sender));
+    (sender, receiver)
+}
+
+/// One end of an in-memory connection, a read return EOF when the other end is dropped or
+/// closed
+#[derive(Debug)]
+pub struct SimStream {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+    // Part of the last delivered packet not yet read
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+/// Connect two in-memory streams, the packets written on the first stream go through
+/// `first_to_second` and the packets written on the second through `second_to_first`
+pub fn duplex(first_to_second: Faults, second_to_first: Faults) -> (SimStream, SimStream) {
+    let (first_sender, second_receiver) = link(first_to_second);
+    let (second_sender, first_receiver) = link(second_to_first);
+    (
+        SimStream::new(first_sender, first_receiver),
+        SimStream::new(second_sender, second_receiver),
+    )
+}
+
+impl SimStream {
+    fn new(sender: Sender<Vec<u8>>, receiver: Receiver<Vec<u8>>) -> Self {
+        Self {
+            sender,
+            receiver,
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for SimStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.position == self.buffer.len() {
+            match Pin::new(&mut self.receiver).poll_next(cx) {
+                Poll::Ready(Some(packet)) => {
+                    self.buffer = packet;
+                    self.position = 0;
+                }
+                // The other end has been closed
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = buf.len().min(self.buffer.len() - self.position);
+        let position = self.position;
+        buf[..len].copy_from_slice(&self.buffer[position..position + len]);
+        self.position += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl Write for SimStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.sender.try_send(buf.to_vec()) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.sender.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+async fn read_packets(mut stream: TcpStream, sender: Sender<Vec<u8>>) {
+    let mut buffer = vec![0; 4096];
+    while let Ok(len) = stream.read(&mut buffer).await {
+        if len == 0 || sender.send(buffer[..len].to_vec()).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn write_packets(mut stream: TcpStream, receiver: Receiver<Vec<u8>>) {
+    while let Ok(packet) = receiver.recv().await {
+        if stream.write_all(&packet).await.is_err() {
+            break;
+        }
+    }
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+}
+
+async fn relay_connection(
+    client: TcpStream,
+    target: SocketAddr,
+    to_server: Faults,
+    to_client: Faults,
+) -> io::Result<()> {
+    let server = TcpStream::connect(target).await?;
+    let (client_sender, server_receiver) = link(to_server);
+    let (server_sender, client_receiver) = link(to_client);
+    task::spawn(read_packets(client.clone(), client_sender));
+    task::spawn(write_packets(server.clone(), server_receiver));
+    task::spawn(read_packets(server, server_sender));
+    task::spawn(write_packets(client, client_receiver));
+    Ok(())
+}
+
+/// Listen on a random local port and relay every connection to `target`, the packets sent by the
+/// client go through `to_server` and the packets sent by the server through `to_client`. Return
+/// the address on which the relay listen.
+pub async fn relay(
+    target: SocketAddr,
+    to_server: Faults,
+    to_client: Faults,
+) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    task::spawn(async move {
+        let mut incoming = listener.incoming();
+        while let Some(Ok(client)) = incoming.next().await {
+            if let Err(e) =
+                relay_connection(client, target, to_server.clone(), to_client.clone()).await
+            {
+                println!("Relay can not connect to {}: {}", target, e);
+            }
+        }
+    });
+    Ok(address)
+}
@@ -0,0 +1,304 @@
+//! Run the harness against the mining proxy, the proxy is between a downstream driven by the
+//! harness and a scripted upstream
+use async_std::{net::TcpListener, task};
+use binary_sv2::Seq0255;
+use codec_sv2::{noise_sv2::random_keypair, Responder};
+use interop_tests::{coinbase, decode, free_address, setup_connection, InteropError, Sv2Stream};
+use mining_proxy::{Proxy, ProxyHandle};
+use roles_logic_sv2::{
+    common_messages_sv2::SetupConnectionSuccess,
+    mining_sv2::{
+        NewExtendedMiningJob, OpenExtendedMiningChannelSuccess, OpenStandardMiningChannel,
+        SetNewPrevHash, SubmitSharesStandard, SubmitSharesSuccess,
+    },
+    parsers::{CommonMessages, Mining, PoolMessages},
+};
+use std::{
+    convert::TryInto,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+const EXTENDED_CHANNEL_ID: u32 = 1;
+const EXTRANONCE_PREFIX_LEN: usize = 4;
+const SHARES_PER_SUCCESS: u32 = 2;
+
+/// Upstream that open the extended channel requested by an aggregating proxy and send a future
+/// job and the prev hash for it, then accept the shares: every `SHARES_PER_SUCCESS` shares are
+/// acknowledged with a single SubmitSharesSuccess. The instant every share is received is sent to
+/// `shares`
+async fn aggregating_upstream(
+    listener: TcpListener,
+    authority_keypair: ([u8; 32], [u8; 32]),
+    ready: async_channel::Sender<()>,
+    shares: async_channel::Sender<Instant>,
+) -> Result<(), InteropError> {
+    let (stream, _) = listener.accept().await?;
+    let (public_key, secret_key) = authority_keypair;
+    let responder =
+        Responder::from_authority_kp(&public_key, &secret_key, Duration::from_secs(3600))
+            .map_err(|_| InteropError::Handshake)?;
+    let mut connection = Sv2Stream::accept(stream, responder).await?;
+
+    let mut frame = connection.recv().await?;
+    let flags = match decode(&mut frame)? {
+        PoolMessages::Common(CommonMessages::SetupConnection(m)) => m.flags,
+        _ => return Err(InteropError::InvalidMessage("Expected SetupConnection")),
+    };
+    let success = SetupConnectionSuccess {
+        used_version: 2,
+        flags,
+    };
+    connection.send(success.into()).await?;
+
+    let mut frame = connection.recv().await?;
+    let (request_id, extranonce_size) = match decode(&mut frame)? {
+        PoolMessages::Mining(Mining::OpenExtendedMiningChannel(m)) => {
+            (m.request_id, m.min_extranonce_size)
+        }
+        _ => {
+            return Err(InteropError::InvalidMessage(
+                "Expected OpenExtendedMiningChannel",
+            ))
+        }
+    };
+    let success = OpenExtendedMiningChannelSuccess {
+        request_id,
+        channel_id: EXTENDED_CHANNEL_ID,
+        target: [0xff; 32].to_vec().try_into().unwrap(),
+        extranonce_size,
+        extranonce_prefix: vec![0; EXTRANONCE_PREFIX_LEN].try_into().unwrap(),
+    };
+    connection
+        .send(PoolMessages::Mining(
+            Mining::OpenExtendedMiningChannelSuccess(success),
+        ))
+        .await?;
+    let (coinbase_tx_prefix, coinbase_tx_suffix) =
+        coinbase(EXTRANONCE_PREFIX_LEN + extranonce_size as usize);
+    let job = NewExtendedMiningJob {
+        channel_id: EXTENDED_CHANNEL_ID,
+        job_id: 1,
+        future_job: true,
+        version: 0x2000_0000,
+        version_rolling_allowed: true,
+        merkle_path: Seq0255::new(Vec::new()).unwrap(),
+        coinbase_tx_prefix: coinbase_tx_prefix.try_into().unwrap(),
+        coinbase_tx_suffix: coinbase_tx_suffix.try_into().unwrap(),
+    };
+    connection
+        .send(PoolMessages::Mining(Mining::NewExtendedMiningJob(job)))
+        .await?;
+    let prev_hash = SetNewPrevHash {
+        channel_id: EXTENDED_CHANNEL_ID,
+        job_id: 1,
+        prev_hash: vec![0; 32].try_into().unwrap(),
+        min_ntime: 0,
+        nbits: 0x1d00_ffff,
+    };
+    connection
+        .send(PoolMessages::Mining(Mining::SetNewPrevHash(prev_hash)))
+        .await?;
+    let _ = ready.send(()).await;
+
+    connection.set_recv_timeout(Duration::from_secs(3600));
+    let mut not_acknowledged = 0;
+    loop {
+        let mut frame = connection.recv().await?;
+        if let PoolMessages::Mining(Mining::SubmitSharesExtended(m)) = decode(&mut frame)? {
+            let _ = shares.try_send(Instant::now());
+            not_acknowledged += 1;
+            if not_acknowledged == SHARES_PER_SUCCESS {
+                let success = SubmitSharesSuccess {
+                    channel_id: EXTENDED_CHANNEL_ID,
+                    last_sequence_number: m.sequence_number,
+                    new_submits_accepted_count: not_acknowledged,
+                    new_shares_sum: not_acknowledged as u64,
+                };
+                connection
+                    .send(PoolMessages::Mining(Mining::SubmitSharesSuccess(success)))
+                    .await?;
+                not_acknowledged = 0;
+            }
+        }
+    }
+}
+
+/// Spawn an aggregating proxy in front of `aggregating_upstream`, return when the upstream has
+/// sent the job and the prev hash. If `batch_window` is Some the proxy batch the shares. The
+/// returned receiver get the instant every share reach the upstream.
+async fn spawn_aggregating_proxy(
+    batch_window: Option<Duration>,
+) -> (ProxyHandle, SocketAddr, async_channel::Receiver<Instant>) {
+    let authority_keypair = random_keypair();
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = upstream_listener.local_addr().unwrap();
+    let (ready_sender, ready) = async_channel::bounded(1);
+    let (shares_sender, shares) = async_channel::unbounded();
+    task::spawn(aggregating_upstream(
+        upstream_listener,
+        authority_keypair,
+        ready_sender,
+        shares_sender,
+    ));
+
+    let proxy_address = free_address();
+    let mut builder = Proxy::builder()
+        .listen(proxy_address)
+        .upstream(upstream_address, authority_keypair.0)
+        .aggregate_standard_channels(1000.0);
+    if let Some(window) = batch_window {
+        builder = builder.batch_shares(window);
+    }
+    let proxy = builder.spawn().await.unwrap();
+    ready.recv().await.unwrap();
+    // Give the proxy the time to relay the job and the prev hash to the aggregator
+    task::sleep(Duration::from_millis(200)).await;
+    (proxy, proxy_address, shares)
+}
+
+/// Connect to the proxy and open a standard channel, return the connection, the channel id and
+/// the id of the job
+async fn open_aggregated_channel(
+    proxy_address: SocketAddr,
+    request_id: u32,
+) -> (Sv2Stream, u32, u32) {
+    let mut connection = Sv2Stream::connect_plain(proxy_address).await.unwrap();
+    connection
+        .send(setup_connection(proxy_address).into())
+        .await
+        .unwrap();
+    let mut frame = connection.recv().await.unwrap();
+    assert!(matches!(
+        decode(&mut frame).unwrap(),
+        PoolMessages::Common(CommonMessages::SetupConnectionSuccess(_))
+    ));
+
+    let open_channel = OpenStandardMiningChannel {
+        request_id: request_id.into(),
+        user_identity: "interop-tests".to_string().try_into().unwrap(),
+        nominal_hash_rate: 1.0,
+        max_target: [0xff; 32].to_vec().try_into().unwrap(),
+    };
+    connection
+        .send(PoolMessages::Mining(Mining::OpenStandardMiningChannel(
+            open_channel,
+        )))
+        .await
+        .unwrap();
+    // The success, the job and the prev hash come back from the aggregated extended channel
+    // without a round trip to the upstream
+    let mut frame = connection.recv().await.unwrap();
+    let channel_id = match decode(&mut frame).unwrap() {
+        PoolMessages::Mining(Mining::OpenStandardMiningChannelSuccess(m)) => {
+            assert_eq!(m.get_request_id_as_u32(), request_id);
+            assert_eq!(m.group_channel_id, EXTENDED_CHANNEL_ID);
+            m.channel_id
+        }
+        m => panic!("Expected OpenStandardMiningChannelSuccess, got {:?}", m),
+    };
+    let mut frame = connection.recv().await.unwrap();
+    let job_id = match decode(&mut frame).unwrap() {
+        PoolMessages::Mining(Mining::NewMiningJob(m)) => {
+            assert_eq!(m.channel_id, channel_id);
+            assert!(m.future_job);
+            m.job_id
+        }
+        m => panic!("Expected NewMiningJob, got {:?}", m),
+    };
+    let mut frame = connection.recv().await.unwrap();
+    match decode(&mut frame).unwrap() {
+        PoolMessages::Mining(Mining::SetNewPrevHash(m)) => {
+            assert_eq!(m.channel_id, channel_id);
+            assert_eq!(m.job_id, job_id);
+        }
+        m => panic!("Expected SetNewPrevHash, got {:?}", m),
+    };
+    (connection, channel_id, job_id)
+}
+
+/// Submit the share `sequence_number` of the standard channel `channel_id`
+async fn submit_share(
+    connection: &mut Sv2Stream,
+    channel_id: u32,
+    job_id: u32,
+    sequence_number: u32,
+) {
+    let share = SubmitSharesStandard {
+        channel_id,
+        sequence_number,
+        job_id,
+        nonce: 0,
+        ntime: 0,
+        version: 0x2000_0000,
+    };
+    connection
+        .send(PoolMessages::Mining(Mining::SubmitSharesStandard(share)))
+        .await
+        .unwrap();
+}
+
+/// Receive the SubmitSharesSuccess that acknowledge the share `sequence_number` of `channel_id`
+async fn expect_success(connection: &mut Sv2Stream, channel_id: u32, sequence_number: u32) {
+    let mut frame = connection.recv().await.unwrap();
+    match decode(&mut frame).unwrap() {
+        PoolMessages::Mining(Mining::SubmitSharesSuccess(m)) => {
+            assert_eq!(m.channel_id, channel_id);
+            assert_eq!(m.last_sequence_number, sequence_number);
+            assert_eq!(m.new_submits_accepted_count, 1);
+        }
+        m => panic!("Expected SubmitSharesSuccess, got {:?}", m),
+    }
+}
+
+#[async_std::test]
+async fn aggregated_standard_channel_get_job_and_prev_hash() {
+    let (proxy, proxy_address, _) = spawn_aggregating_proxy(None).await;
+    open_aggregated_channel(proxy_address, 7).await;
+    proxy.shutdown().await.unwrap();
+}
+
+#[async_std::test]
+async fn range_success_is_relayed_to_every_channel() {
+    let (proxy, proxy_address, _) = spawn_aggregating_proxy(None).await;
+    let (mut first, first_channel_id, first_job_id) =
+        open_aggregated_channel(proxy_address, 1).await;
+    let (mut second, second_channel_id, second_job_id) =
+        open_aggregated_channel(proxy_address, 2).await;
+
+    submit_share(&mut first, first_channel_id, first_job_id, 10).await;
+    submit_share(&mut second, second_channel_id, second_job_id, 20).await;
+    // The upstream acknowledge the two shares of the extended channel with one success, every
+    // downstream get the success for its own channel and sequence number
+    expect_success(&mut first, first_channel_id, 10).await;
+    expect_success(&mut second, second_channel_id, 20).await;
+    proxy.shutdown().await.unwrap();
+}
+
+#[async_std::test]
+async fn batched_shares_are_sent_together_and_acknowledged_by_range() {
+    let window = Duration::from_millis(400);
+    let (proxy, proxy_address, shares) = spawn_aggregating_proxy(Some(window)).await;
+    let (mut first, first_channel_id, first_job_id) =
+        open_aggregated_channel(proxy_address, 1).await;
+    let (mut second, second_channel_id, second_job_id) =
+        open_aggregated_channel(proxy_address, 2).await;
+
+    // Both the shares are for the aggregated extended channel, the first open the window
+    let submitted = Instant::now();
+    submit_share(&mut first, first_channel_id, first_job_id, 10).await;
+    task::sleep(Duration::from_millis(100)).await;
+    submit_share(&mut second, second_channel_id, second_job_id, 20).await;
+
+    let first_received = shares.recv().await.unwrap();
+    let second_received = shares.recv().await.unwrap();
+    // Nothing is sent before the window expire
+    assert!(first_received.duration_since(submitted) >= window);
+    // The batch is written at once, the second share is not 100ms behind the first
+    assert!(second_received.duration_since(first_received) < Duration::from_millis(50));
+
+    // One success for the last sequence number of the batch acknowledge both the shares
+    expect_success(&mut first, first_channel_id, 10).await;
+    expect_success(&mut second, second_channel_id, 20).await;
+    proxy.shutdown().await.unwrap();
+}
@@ -0,0 +1,49 @@
+//! Run the harness against itself, the downstream side against the upstream side
+use async_std::{net::TcpListener, task};
+use codec_sv2::{noise_sv2::random_keypair, Responder};
+use interop_tests::{run_downstream, run_upstream, InteropError, Sv2Stream};
+use std::time::Duration;
+
+async fn spawn_upstream(
+    authority_keypair: ([u8; 32], [u8; 32]),
+) -> (
+    std::net::SocketAddr,
+    task::JoinHandle<Result<(), InteropError>>,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let upstream = task::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let (public_key, secret_key) = authority_keypair;
+        let responder =
+            Responder::from_authority_kp(&public_key, &secret_key, Duration::from_secs(3600))
+                .map_err(|_| InteropError::Handshake)?;
+        let mut connection = Sv2Stream::accept(stream, responder).await?;
+        run_upstream(&mut connection).await
+    });
+    (address, upstream)
+}
+
+#[async_std::test]
+async fn downstream_and_upstream() {
+    let authority_keypair = random_keypair();
+    let (address, upstream) = spawn_upstream(authority_keypair).await;
+
+    let mut connection = Sv2Stream::connect(address, authority_keypair.0)
+        .await
+        .unwrap();
+    let report = run_downstream(&mut connection, address).await.unwrap();
+    assert_eq!(report.used_version, 2);
+    assert_eq!(report.channel_id, 1);
+    assert_eq!(report.job_id, 1);
+    assert!(report.share_accepted);
+    upstream.await.unwrap();
+}
+
+#[async_std::test]
+async fn certificate_signed_by_another_authority() {
+    let (address, _upstream) = spawn_upstream(random_keypair()).await;
+    let (other_authority, _) = random_keypair();
+    let res = Sv2Stream::connect(address, other_authority).await;
+    assert!(matches!(res, Err(InteropError::Handshake)));
+}
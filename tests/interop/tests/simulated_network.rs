@@ -0,0 +1,481 @@
+//! Run the harness against itself over a simulated network with injected faults, every fault must
+//! end the exchange with an error, never with a panic or a hang.
+//!
+//! The same faults are injected between the mining proxy of this repo and its downstream or its
+//! upstream, after every fault the proxy must still serve a new downstream.
+use async_std::{
+    net::{TcpListener, TcpStream},
+    task,
+};
+use binary_sv2::Seq0255;
+use codec_sv2::{noise_sv2::random_keypair, Responder};
+use interop_tests::{
+    coinbase, decode, free_address, run_downstream, run_upstream, setup_connection,
+    sim::{duplex, relay, Faults, FaultyLink},
+    DownstreamReport, InteropError, Sv2Stream,
+};
+use mining_proxy::{Proxy, ProxyHandle};
+use roles_logic_sv2::{
+    common_messages_sv2::SetupConnectionSuccess,
+    events::ConnectionEvent,
+    mining_sv2::{
+        NewExtendedMiningJob, OpenStandardMiningChannel, OpenStandardMiningChannelSuccess,
+        SetNewPrevHash, SubmitSharesSuccess,
+    },
+    parsers::{CommonMessages, Mining, PoolMessages},
+};
+use std::{convert::TryInto, net::SocketAddr, time::Duration};
+
+const SHORT_TIMEOUT: Duration = Duration::from_millis(500);
+const GROUP_CHANNEL_ID: u32 = 1;
+const EXTRANONCE_LEN: usize = 32;
+
+/// Run the downstream side against the upstream side, the packets sent by the downstream go
+/// through `to_upstream` and the packets sent by the upstream through `to_downstream`
+async fn run(
+    to_upstream: Faults,
+    to_downstream: Faults,
+) -> (
+    Result<DownstreamReport, InteropError>,
+    Result<(), InteropError>,
+) {
+    let (public_key, secret_key) = random_keypair();
+    let (downstream_stream, upstream_stream) = duplex(to_upstream, to_downstream);
+    let upstream = task::spawn(async move {
+        let responder =
+            Responder::from_authority_kp(&public_key, &secret_key, Duration::from_secs(3600))
+                .map_err(|_| InteropError::Handshake)?;
+        let mut connection = Sv2Stream::accept(upstream_stream, responder).await?;
+        connection.set_recv_timeout(SHORT_TIMEOUT);
+        run_upstream(&mut connection).await
+        // The connection is dropped here so the downstream see the other end closed
+    });
+    let downstream = async {
+        let mut connection = Sv2Stream::initiate(downstream_stream, public_key).await?;
+        connection.set_recv_timeout(SHORT_TIMEOUT);
+        run_downstream(&mut connection, "127.0.0.1:34254".parse().unwrap()).await
+    };
+    let downstream = downstream.await;
+    (downstream, upstream.await)
+}
+
+#[test]
+fn faulty_link_applies_faults_in_order() {
+    let mut link = FaultyLink::new(Faults {
+        drop_every: Some(3),
+        truncate_every: Some(2),
+        reorder_every: Some(4),
+        close_after: Some(6),
+        ..Default::default()
+    });
+    assert_eq!(link.on_packet(vec![1; 4]), Some(vec![vec![1; 4]]));
+    assert_eq!(link.on_packet(vec![2; 4]), Some(vec![vec![2; 2]]));
+    assert_eq!(link.on_packet(vec![3; 4]), Some(vec![]));
+    // Truncated and held for reordering
+    assert_eq!(link.on_packet(vec![4; 4]), Some(vec![]));
+    assert_eq!(
+        link.on_packet(vec![5; 4]),
+        Some(vec![vec![5; 4], vec![4; 2]])
+    );
+    assert_eq!(link.on_packet(vec![6; 4]), Some(vec![]));
+    assert_eq!(link.on_packet(vec![7; 4]), None);
+    assert_eq!(link.on_close(), None);
+}
+
+#[async_std::test]
+async fn perfect_network() {
+    let (downstream, upstream) = run(Faults::default(), Faults::default()).await;
+    let report = downstream.unwrap();
+    assert_eq!(report.channel_id, 1);
+    assert!(report.share_accepted);
+    upstream.unwrap();
+}
+
+#[async_std::test]
+async fn delay_shorter_than_the_timeout() {
+    let delay = Faults::delay(Duration::from_millis(50));
+    let (downstream, upstream) = run(delay.clone(), delay).await;
+    assert!(downstream.unwrap().share_accepted);
+    upstream.unwrap();
+}
+
+#[async_std::test]
+async fn delay_longer_than_the_timeout() {
+    let (downstream, _) = run(Faults::default(), Faults::delay(SHORT_TIMEOUT * 2)).await;
+    assert!(matches!(downstream, Err(InteropError::Io(_))));
+}
+
+#[async_std::test]
+async fn dropped_frame() {
+    // The third packet of the downstream is OpenStandardMiningChannel, the upstream time out
+    // waiting for it and close the connection while the downstream wait for the response
+    let (downstream, upstream) = run(Faults::drop_every(3), Faults::default()).await;
+    match (downstream, upstream) {
+        (Err(InteropError::Io(d)), Err(InteropError::Io(u))) => {
+            assert_eq!(u.kind(), std::io::ErrorKind::TimedOut);
+            assert!(matches!(
+                d.kind(),
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::UnexpectedEof
+            ));
+        }
+        res => panic!("Expected two I/O errors, got {:?}", res),
+    }
+}
+
+#[async_std::test]
+async fn truncated_frame() {
+    // The second packet of the upstream is SetupConnectionSuccess
+    let (downstream, _) = run(Faults::default(), Faults::truncate_every(2)).await;
+    assert!(matches!(
+        downstream,
+        Err(InteropError::Io(_)) | Err(InteropError::Codec(_))
+    ));
+}
+
+#[async_std::test]
+async fn truncated_handshake() {
+    let (downstream, _) = run(Faults::default(), Faults::truncate_every(1)).await;
+    assert!(matches!(
+        downstream,
+        Err(InteropError::Io(_)) | Err(InteropError::Handshake)
+    ));
+}
+
+#[async_std::test]
+async fn reordered_frames() {
+    // NewMiningJob (4th packet of the upstream) is delivered after SetNewPrevHash, the noise
+    // nonces do not match anymore
+    let (downstream, _) = run(Faults::default(), Faults::reorder_every(4)).await;
+    assert!(matches!(downstream, Err(InteropError::Codec(_))));
+}
+
+#[async_std::test]
+async fn closed_connection() {
+    let (downstream, _) = run(Faults::default(), Faults::close_after(2)).await;
+    match downstream {
+        Err(InteropError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+        res => panic!("Expected an unexpected EOF, got {:?}", res),
+    }
+}
+
+/// Upstream of the proxy: open every standard channel in the same group and send an extended job
+/// and a prev hash for the group, accept every share
+async fn proxy_upstream(stream: TcpStream, authority_keypair: ([u8; 32], [u8; 32])) {
+    let (public_key, secret_key) = authority_keypair;
+    let responder =
+        Responder::from_authority_kp(&public_key, &secret_key, Duration::from_secs(3600)).unwrap();
+    let mut connection = match Sv2Stream::accept(stream, responder).await {
+        Ok(connection) => connection,
+        Err(_) => return,
+    };
+    connection.set_recv_timeout(Duration::from_secs(3600));
+    let mut next_channel_id = GROUP_CHANNEL_ID + 1;
+    let mut next_job_id = 1;
+    while let Ok(mut frame) = connection.recv().await {
+        let responses: Vec<PoolMessages> = match decode(&mut frame) {
+            Ok(PoolMessages::Common(CommonMessages::SetupConnection(m))) => {
+                let success = SetupConnectionSuccess {
+                    used_version: 2,
+                    flags: m.flags,
+                };
+                vec![success.into()]
+            }
+            Ok(PoolMessages::Mining(Mining::OpenStandardMiningChannel(m))) => {
+                let success = OpenStandardMiningChannelSuccess {
+                    request_id: m.get_request_id_as_u32().into(),
+                    channel_id: next_channel_id,
+                    target: [0xff; 32].to_vec().try_into().unwrap(),
+                    extranonce_prefix: vec![0; EXTRANONCE_LEN].try_into().unwrap(),
+                    group_channel_id: GROUP_CHANNEL_ID,
+                };
+                next_channel_id += 1;
+                // Every channel get a new job, the proxy relay it to the whole group
+                let (coinbase_tx_prefix, coinbase_tx_suffix) = coinbase(EXTRANONCE_LEN);
+                let job = NewExtendedMiningJob {
+                    channel_id: GROUP_CHANNEL_ID,
+                    job_id: next_job_id,
+                    future_job: true,
+                    version: 0x2000_0000,
+                    version_rolling_allowed: true,
+                    merkle_path: Seq0255::new(Vec::new()).unwrap(),
+                    coinbase_tx_prefix: coinbase_tx_prefix.try_into().unwrap(),
+                    coinbase_tx_suffix: coinbase_tx_suffix.try_into().unwrap(),
+                };
+                let prev_hash = SetNewPrevHash {
+                    channel_id: GROUP_CHANNEL_ID,
+                    job_id: next_job_id,
+                    prev_hash: vec![0; 32].try_into().unwrap(),
+                    min_ntime: 0,
+                    nbits: 0x1d00_ffff,
+                };
+                next_job_id += 1;
+                vec![
+                    PoolMessages::Mining(Mining::OpenStandardMiningChannelSuccess(success)),
+                    PoolMessages::Mining(Mining::NewExtendedMiningJob(job)),
+                    PoolMessages::Mining(Mining::SetNewPrevHash(prev_hash)),
+                ]
+            }
+            Ok(PoolMessages::Mining(Mining::SubmitSharesStandard(m))) => {
+                let success = SubmitSharesSuccess {
+                    channel_id: m.channel_id,
+                    last_sequence_number: m.sequence_number,
+                    new_submits_accepted_count: 1,
+                    new_shares_sum: 1,
+                };
+                vec![PoolMessages::Mining(Mining::SubmitSharesSuccess(success))]
+            }
+            _ => vec![],
+        };
+        for response in responses {
+            if connection.send(response).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Proxy under test, the packets sent by the proxy to its upstream go through `to_upstream` and
+/// the packets sent by the upstream through `to_proxy`
+async fn spawn_proxy(
+    to_upstream: Faults,
+    to_proxy: Faults,
+) -> (
+    ProxyHandle,
+    SocketAddr,
+    async_channel::Receiver<ConnectionEvent>,
+) {
+    let authority_keypair = random_keypair();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap();
+    task::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            task::spawn(proxy_upstream(stream, authority_keypair));
+        }
+    });
+    let upstream_address = relay(upstream_address, to_upstream, to_proxy)
+        .await
+        .unwrap();
+    let proxy_address = free_address();
+    let (events, events_receiver) = async_channel::bounded(64);
+    let proxy = Proxy::builder()
+        .listen(proxy_address)
+        .upstream(upstream_address, authority_keypair.0)
+        .request_timeout(SHORT_TIMEOUT)
+        .events(events)
+        .spawn()
+        .await
+        .unwrap();
+    (proxy, proxy_address, events_receiver)
+}
+
+/// Run the downstream side against the proxy, the packets sent by the downstream go through
+/// `to_proxy` and the packets sent by the proxy through `to_downstream`
+async fn run_through_proxy(
+    proxy_address: SocketAddr,
+    to_proxy: Faults,
+    to_downstream: Faults,
+) -> Result<DownstreamReport, InteropError> {
+    let address = relay(proxy_address, to_proxy, to_downstream).await?;
+    let mut connection = Sv2Stream::connect_plain(address).await?;
+    connection.set_recv_timeout(SHORT_TIMEOUT);
+    run_downstream(&mut connection, proxy_address).await
+}
+
+/// A new downstream get its share accepted, the proxy has survived the previous faults
+async fn assert_proxy_serves(proxy_address: SocketAddr) {
+    let report = run_through_proxy(proxy_address, Faults::default(), Faults::default())
+        .await
+        .unwrap();
+    assert!(report.share_accepted);
+}
+
+/// Wait until the proxy publish an event that match `expected`
+async fn wait_event(
+    events: &async_channel::Receiver<ConnectionEvent>,
+    expected: impl Fn(&ConnectionEvent) -> bool,
+) {
+    let wait = async {
+        while let Ok(event) = events.recv().await {
+            if expected(&event) {
+                return;
+            }
+        }
+        panic!("Events closed");
+    };
+    async_std::future::timeout(SHORT_TIMEOUT * 4, wait)
+        .await
+        .expect("Event not published");
+}
+
+#[async_std::test]
+async fn proxy_perfect_network() {
+    let (proxy, proxy_address, _) = spawn_proxy(Faults::default(), Faults::default()).await;
+    assert_proxy_serves(proxy_address).await;
+    proxy.shutdown().await.unwrap();
+}
+
+#[async_std::test]
+async fn proxy_dropped_frame() {
+    let (proxy, proxy_address, _) = spawn_proxy(Faults::default(), Faults::default()).await;
+    // OpenStandardMiningChannel is dropped, the downstream time out waiting for the response
+    let downstream =
+        run_through_proxy(proxy_address, Faults::drop_every(2), Faults::default()).await;
+    match downstream {
+        Err(InteropError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::TimedOut),
+        res => panic!("Expected a timeout, got {:?}", res),
+    }
+    assert_proxy_serves(proxy_address).await;
+    proxy.shutdown().await.unwrap();
+}
+
+#[async_std::test]
+async fn proxy_truncated_frame() {
+    let (proxy, proxy_address, events) = spawn_proxy(Faults::default(), Faults::default()).await;
+    // The proxy never get the whole SetupConnection, when the downstream give up the connection
+    // is dropped before that it is paired
+    let downstream =
+        run_through_proxy(proxy_address, Faults::truncate_every(1), Faults::default()).await;
+    assert!(matches!(downstream, Err(InteropError::Io(_))));
+    wait_event(&events, |e| {
+        matches!(e, ConnectionEvent::HandshakeFailed { .. })
+    })
+    .await;
+    assert_proxy_serves(proxy_address).await;
+    proxy.shutdown().await.unwrap();
+}
+
+#[async_std::test]
+async fn proxy_reordered_frames() {
+    let (proxy, proxy_address, _) = spawn_proxy(Faults::default(), Faults::default()).await;
+    // The response to OpenStandardMiningChannel is held after the next packet of the proxy, the
+    // plain frames are not broken so the downstream get an unexpected message or time out
+    let downstream =
+        run_through_proxy(proxy_address, Faults::default(), Faults::reorder_every(2)).await;
+    assert!(matches!(
+        downstream,
+        Err(InteropError::Io(_)) | Err(InteropError::UnexpectedMessage { .. })
+    ));
+    assert_proxy_serves(proxy_address).await;
+    proxy.shutdown().await.unwrap();
+}
+
+#[async_std::test]
+async fn proxy_closed_connection() {
+    let (proxy, proxy_address, events) = spawn_proxy(Faults::default(), Faults::default()).await;
+    // The link is closed before the share, the proxy see the downstream disconnected
+    let downstream =
+        run_through_proxy(proxy_address, Faults::close_after(2), Faults::default()).await;
+    assert!(matches!(downstream, Err(InteropError::Io(_))));
+    wait_event(&events, |e| {
+        matches!(e, ConnectionEvent::Disconnected { .. })
+    })
+    .await;
+    assert_proxy_serves(proxy_address).await;
+    proxy.shutdown().await.unwrap();
+}
+
+#[async_std::test]
+async fn proxy_drop_unexpected_message() {
+    let (proxy, proxy_address, _) = spawn_proxy(Faults::default(), Faults::default()).await;
+    let mut connection = Sv2Stream::connect_plain(proxy_address).await.unwrap();
+    connection.set_recv_timeout(SHORT_TIMEOUT);
+    connection
+        .send(setup_connection(proxy_address).into())
+        .await
+        .unwrap();
+    let mut frame = connection.recv().await.unwrap();
+    assert!(matches!(
+        decode(&mut frame).unwrap(),
+        PoolMessages::Common(CommonMessages::SetupConnectionSuccess(_))
+    ));
+    // Only the upstreams send SetNewPrevHash, the proxy drop it and keep the connection
+    let prev_hash = SetNewPrevHash {
+        channel_id: 1,
+        job_id: 1,
+        prev_hash: vec![0; 32].try_into().unwrap(),
+        min_ntime: 0,
+        nbits: 0x1d00_ffff,
+    };
+    connection
+        .send(PoolMessages::Mining(Mining::SetNewPrevHash(prev_hash)))
+        .await
+        .unwrap();
+    let open_channel = OpenStandardMiningChannel {
+        request_id: 1.into(),
+        user_identity: "interop-tests".to_string().try_into().unwrap(),
+        nominal_hash_rate: 1.0,
+        max_target: [0xff; 32].to_vec().try_into().unwrap(),
+    };
+    connection
+        .send(PoolMessages::Mining(Mining::OpenStandardMiningChannel(
+            open_channel,
+        )))
+        .await
+        .unwrap();
+    let mut frame = connection.recv().await.unwrap();
+    assert!(matches!(
+        decode(&mut frame).unwrap(),
+        PoolMessages::Mining(Mining::OpenStandardMiningChannelSuccess(_))
+    ));
+    proxy.shutdown().await.unwrap();
+}
+
+#[async_std::test]
+async fn proxy_close_on_malformed_message() {
+    let (proxy, proxy_address, events) = spawn_proxy(Faults::default(), Faults::default()).await;
+    let mut connection = Sv2Stream::connect_plain(proxy_address).await.unwrap();
+    connection.set_recv_timeout(SHORT_TIMEOUT);
+    connection
+        .send(setup_connection(proxy_address).into())
+        .await
+        .unwrap();
+    connection.recv().await.unwrap();
+    // Channel message SubmitSharesStandard with a 4 bytes payload, the message is 24 bytes
+    let header = [1, 0, 0x1a, 4, 0, 0];
+    connection
+        .send_raw(&[&header[..], &[0; 4]].concat())
+        .await
+        .unwrap();
+    match connection.recv().await {
+        Err(InteropError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+        res => panic!("Expected the connection closed, got {:?}", res),
+    }
+    wait_event(&events, |e| {
+        matches!(e, ConnectionEvent::Disconnected { .. })
+    })
+    .await;
+    assert_proxy_serves(proxy_address).await;
+    proxy.shutdown().await.unwrap();
+}
+
+/// Downstream of a proxy whose upstream failed the setup
+async fn assert_setup_refused(proxy_address: SocketAddr) {
+    let mut connection = Sv2Stream::connect_plain(proxy_address).await.unwrap();
+    connection.set_recv_timeout(SHORT_TIMEOUT);
+    connection
+        .send(setup_connection(proxy_address).into())
+        .await
+        .unwrap();
+    let mut frame = connection.recv().await.unwrap();
+    assert!(matches!(
+        decode(&mut frame).unwrap(),
+        PoolMessages::Common(CommonMessages::SetupConnectionError(_))
+    ));
+}
+
+#[async_std::test]
+async fn proxy_upstream_dropped_setup_connection() {
+    // The second packet of the proxy is SetupConnection, the proxy stop waiting the response
+    // after the request timeout
+    let (proxy, proxy_address, _) = spawn_proxy(Faults::drop_every(2), Faults::default()).await;
+    assert_setup_refused(proxy_address).await;
+    proxy.shutdown().await.unwrap();
+}
+
+#[async_std::test]
+async fn proxy_upstream_closed_during_setup() {
+    // The upstream close the connection instead of answering SetupConnection
+    let (proxy, proxy_address, _) = spawn_proxy(Faults::default(), Faults::close_after(1)).await;
+    assert_setup_refused(proxy_address).await;
+    proxy.shutdown().await.unwrap();
+}
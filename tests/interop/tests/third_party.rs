@@ -0,0 +1,48 @@
+//! Run the harness against a third party implementation, see the README
+//!
+//! These tests are ignored by default, run them with `cargo test -p interop_tests -- --ignored`
+//! after setting the environment variables below.
+use async_std::net::TcpListener;
+use codec_sv2::Responder;
+use interop_tests::{parse_public_key, run_downstream, run_upstream, Sv2Stream};
+use std::{net::SocketAddr, time::Duration};
+
+fn env(name: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| panic!("{} is not set", name))
+}
+
+/// Initiator of this crate against a third party Responder
+///
+/// SV2_INTEROP_RESPONDER: address of the responder (eg 127.0.0.1:34254)
+/// SV2_INTEROP_RESPONDER_AUTHORITY: authority public key of the responder as 64 hex chars
+#[async_std::test]
+#[ignore]
+async fn initiator_against_third_party_responder() {
+    let address: SocketAddr = env("SV2_INTEROP_RESPONDER").parse().unwrap();
+    let authority_public_key = parse_public_key(&env("SV2_INTEROP_RESPONDER_AUTHORITY")).unwrap();
+    let mut connection = Sv2Stream::connect(address, authority_public_key)
+        .await
+        .unwrap();
+    let report = run_downstream(&mut connection, address).await.unwrap();
+    println!("{:?}", report);
+}
+
+/// Third party Initiator against the Responder of this crate, the test wait for one connection
+///
+/// SV2_INTEROP_LISTEN: address where the responder listen (eg 0.0.0.0:34255)
+/// SV2_INTEROP_AUTHORITY_PUBLIC_KEY and SV2_INTEROP_AUTHORITY_SECRET_KEY: authority keypair used
+/// to sign the responder certificate as 64 hex chars, the third party Initiator must be configured
+/// with the public key
+#[async_std::test]
+#[ignore]
+async fn responder_against_third_party_initiator() {
+    let address: SocketAddr = env("SV2_INTEROP_LISTEN").parse().unwrap();
+    let public_key = parse_public_key(&env("SV2_INTEROP_AUTHORITY_PUBLIC_KEY")).unwrap();
+    let secret_key = parse_public_key(&env("SV2_INTEROP_AUTHORITY_SECRET_KEY")).unwrap();
+    let listener = TcpListener::bind(address).await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let responder =
+        Responder::from_authority_kp(&public_key, &secret_key, Duration::from_secs(3600)).unwrap();
+    let mut connection = Sv2Stream::accept(stream, responder).await.unwrap();
+    run_upstream(&mut connection).await.unwrap();
+}
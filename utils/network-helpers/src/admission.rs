@@ -0,0 +1,310 @@
+//! Admission of the accepted connections. The policy is evaluated on the address of the peer
+//! before the handshake, so a rejected peer do not cost a noise handshake. The deny list is
+//! checked first, then the allow list (an empty allow list allow everything) and at last the
+//! optional hook, that let operators plug custom logic (GeoIP, abuse feeds, ...).
+//!
+//! The policy see the address of the tcp peer. Behind a load balancer that send the address of
+//! the downstream in a PROXY protocol header (eg the mining proxy with
+//! `ProxyBuilder::proxy_protocol`) the peer is always the load balancer, so the policy can not be
+//! used there: filter the downstreams on the load balancer instead.
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+
+/// The ipv4 address of an ipv4 mapped ipv6 address (`::ffff:a.b.c.d`), like
+/// `Ipv6Addr::to_ipv4_mapped` that is not available in our msrv
+fn to_ipv4_mapped(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.octets() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, a, b, c, d] => Some(Ipv4Addr::new(a, b, c, d)),
+        _ => None,
+    }
+}
+
+/// An ip network in CIDR notation, eg `10.0.0.0/8` or `2001:db8::/32`. An address without prefix
+/// is a network with a single address. The host bits of the address are cleared (`10.0.0.1/8` is
+/// `10.0.0.0/8`) and an ipv4 mapped network is an ipv4 network (`::ffff:10.0.0.0/104` is
+/// `10.0.0.0/8`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn new(address: IpAddr, prefix_len: u8) -> Result<Self, InvalidCidr> {
+        let max_len = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return Err(InvalidCidr);
+        }
+        let (address, prefix_len) = match address {
+            IpAddr::V6(v6) if prefix_len >= 96 => match to_ipv4_mapped(v6) {
+                Some(v4) => (IpAddr::V4(v4), prefix_len - 96),
+                None => (address, prefix_len),
+            },
+            address => (address, prefix_len),
+        };
+        let address = match address {
+            IpAddr::V4(net) => IpAddr::V4((u32::from(net) & Self::v4_mask(prefix_len)).into()),
+            IpAddr::V6(net) => IpAddr::V6((u128::from(net) & Self::v6_mask(prefix_len)).into()),
+        };
+        Ok(Self {
+            address,
+            prefix_len,
+        })
+    }
+
+    fn v4_mask(prefix_len: u8) -> u32 {
+        u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+    }
+
+    fn v6_mask(prefix_len: u8) -> u128 {
+        u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0)
+    }
+
+    /// True if `ip` is in the network, an ipv4 address is never in an ipv6 network and the
+    /// opposite, ipv4 mapped ipv6 addresses are compared as ipv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => to_ipv4_mapped(v6).map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.address, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                u32::from(net) == u32::from(ip) & Self::v4_mask(self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                u128::from(net) == u128::from(ip) & Self::v6_mask(self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidCidr;
+
+impl fmt::Display for InvalidCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid CIDR")
+    }
+}
+
+impl std::error::Error for InvalidCidr {}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (
+                address.parse().map_err(|_| InvalidCidr)?,
+                Some(prefix_len.parse().map_err(|_| InvalidCidr)?),
+            ),
+            None => (s.parse().map_err(|_| InvalidCidr)?, None),
+        };
+        let prefix_len = prefix_len.unwrap_or(match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        });
+        Self::new(address, prefix_len)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+/// Custom admission logic, return false to drop the connection
+pub type AdmissionHook = Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>;
+
+/// Which peers can open a connection, the default policy admit everyone
+#[derive(Clone, Default)]
+pub struct AdmissionPolicy {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    hook: Option<AdmissionHook>,
+}
+
+impl AdmissionPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Admit only the peers in `allow`, can be called more times
+    pub fn allow(mut self, allow: impl IntoIterator<Item = Cidr>) -> Self {
+        self.allow.extend(allow);
+        self
+    }
+
+    /// Reject the peers in `deny` even if they are in the allow list, can be called more times
+    pub fn deny(mut self, deny: impl IntoIterator<Item = Cidr>) -> Self {
+        self.deny.extend(deny);
+        self
+    }
+
+    /// Called for the peers that passed the lists
+    pub fn hook(mut self, hook: impl Fn(SocketAddr) -> bool + Send + Sync + 'static) -> Self {
+        self.hook = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn admit(&self, peer: SocketAddr) -> bool {
+        let ip = peer.ip();
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        match &self.hook {
+            Some(hook) => hook(peer),
+            None => true,
+        }
+    }
+}
+
+impl fmt::Debug for AdmissionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdmissionPolicy")
+            .field("allow", &self.allow)
+            .field("deny", &self.deny)
+            .field("hook", &self.hook.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn peer(s: &str) -> SocketAddr {
+        SocketAddr::new(ip(s), 34255)
+    }
+
+    #[test]
+    fn parse_the_prefix_lengths() {
+        assert_eq!(cidr("10.0.0.1"), cidr("10.0.0.1/32"));
+        assert_eq!(cidr("2001:db8::1"), cidr("2001:db8::1/128"));
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        assert_eq!(cidr("::/0").to_string(), "::/0");
+        for invalid in [
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0.0/-1",
+            "10.0.0.0/",
+            "10.0.0.0/8/8",
+            "10.0.0/8",
+            "",
+        ] {
+            assert_eq!(invalid.parse::<Cidr>(), Err(InvalidCidr), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn contains_with_the_shortest_and_longest_prefix() {
+        assert!(cidr("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(cidr("0.0.0.0/0").contains(ip("0.0.0.0")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("10.0.0.1/32").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1/32").contains(ip("10.0.0.2")));
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+    }
+
+    #[test]
+    fn the_host_bits_of_the_network_are_cleared() {
+        assert_eq!(cidr("10.1.2.3/8"), cidr("10.0.0.0/8"));
+        assert_eq!(cidr("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert!(cidr("10.1.2.3/8").contains(ip("10.200.0.1")));
+        assert_eq!(cidr("2001:db8::1/32").to_string(), "2001:db8::/32");
+        assert!(cidr("2001:db8::1/32").contains(ip("2001:db8:ffff::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_and_networks_are_ipv4() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+        // Only the mapped addresses, not the other ipv6 addresses that end with an ipv4
+        assert!(!cidr("10.0.0.0/8").contains(ip("::10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("64:ff9b::10.1.2.3")));
+
+        assert_eq!(cidr("::ffff:10.0.0.0/104"), cidr("10.0.0.0/8"));
+        assert!(cidr("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+        assert!(cidr("::ffff:10.1.2.3").contains(ip("10.1.2.3")));
+        assert!(!cidr("::ffff:0.0.0.0/95").contains(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn everyone_is_admitted_by_default() {
+        let policy = AdmissionPolicy::new();
+        assert!(policy.admit(peer("10.0.0.1")));
+        assert!(policy.admit(peer("2001:db8::1")));
+    }
+
+    #[test]
+    fn only_the_allowed_peers_are_admitted() {
+        let policy = AdmissionPolicy::new()
+            .allow(vec![cidr("10.0.0.0/8")])
+            .allow(vec![cidr("2001:db8::/32")]);
+        assert!(policy.admit(peer("10.0.0.1")));
+        assert!(policy.admit(peer("::ffff:10.0.0.1")));
+        assert!(policy.admit(peer("2001:db8::1")));
+        assert!(!policy.admit(peer("192.168.0.1")));
+        assert!(!policy.admit(peer("2001:db9::1")));
+    }
+
+    #[test]
+    fn deny_take_precedence_over_allow() {
+        let policy = AdmissionPolicy::new()
+            .allow(vec![cidr("10.0.0.0/8")])
+            .deny(vec![cidr("10.1.0.0/16")]);
+        assert!(policy.admit(peer("10.0.0.1")));
+        assert!(!policy.admit(peer("10.1.0.1")));
+        assert!(!policy.admit(peer("::ffff:10.1.0.1")));
+
+        let policy = AdmissionPolicy::new()
+            .allow(vec![cidr("10.0.0.1/32")])
+            .deny(vec![cidr("10.0.0.1/32")]);
+        assert!(!policy.admit(peer("10.0.0.1")));
+    }
+
+    #[test]
+    fn the_hook_is_called_only_for_the_peers_that_passed_the_lists() {
+        let called = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let called_ = called.clone();
+        let policy = AdmissionPolicy::new()
+            .allow(vec![cidr("10.0.0.0/8")])
+            .deny(vec![cidr("10.1.0.0/16")])
+            .hook(move |peer| {
+                called_.lock().unwrap().push(peer);
+                peer.port() != 1
+            });
+        assert!(policy.admit(peer("10.0.0.1")));
+        assert!(!policy.admit(SocketAddr::new(ip("10.0.0.1"), 1)));
+        assert!(!policy.admit(peer("10.1.0.1")));
+        assert!(!policy.admit(peer("192.168.0.1")));
+        assert_eq!(
+            *called.lock().unwrap(),
+            vec![peer("10.0.0.1"), SocketAddr::new(ip("10.0.0.1"), 1)]
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use async_channel::Sender;
+use async_std::{
+    net::TcpStream,
+    task::{self, JoinHandle},
+};
+use futures::future::{abortable, AbortHandle};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+/// How long `ConnectionHandle::shutdown` wait for the writer task to flush the queued frames, a
+/// remote that do not read anymore can not make the shutdown hang
+pub const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Clear the running flag of a task when the task exit or is aborted
+#[derive(Debug)]
+struct RunningGuard(Arc<AtomicBool>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Reader or writer task of a connection
+#[derive(Debug)]
+pub(crate) struct ConnectionTask {
+    join: JoinHandle<()>,
+    abort: AbortHandle,
+    running: Arc<AtomicBool>,
+}
+
+/// Spawn the reader or the writer task of a connection, the task can be aborted with the
+/// `AbortToken` of the `ConnectionHandle`
+pub(crate) fn spawn_task<F: Future<Output = ()> + Send + 'static>(future: F) -> ConnectionTask {
+    let running = Arc::new(AtomicBool::new(true));
+    let guard = RunningGuard(running.clone());
+    let (future, abort) = abortable(future);
+    let join = task::spawn(async move {
+        let _guard = guard;
+        let _ = future.await;
+    });
+    ConnectionTask {
+        join,
+        abort,
+        running,
+    }
+}
+
+/// Event that make the connection close because the remote or the stream can not be trusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecurityEvent {
+    /// A noise frame failed the authentication (the AEAD tag does not match): the stream has been
+    /// tampered or the remote is out of sync
+    TagMismatch,
+}
+
+/// Where the reader task record the security event that closed the connection
+pub(crate) type SecurityEventSlot = Arc<Mutex<Option<SecurityEvent>>>;
+
+/// Abort the reader and writer tasks of a connection, it can be cloned and used without owning
+/// the `ConnectionHandle`
+#[derive(Debug, Clone)]
+pub struct AbortToken {
+    stream: TcpStream,
+    reader: AbortHandle,
+    writer: AbortHandle,
+}
+
+impl AbortToken {
+    /// Stop both tasks at their next await and shut down the tcp stream, the queued frames are
+    /// not written
+    pub fn abort(&self) {
+        self.reader.abort();
+        self.writer.abort();
+        let _ = self.stream.shutdown(async_std::net::Shutdown::Both);
+    }
+}
+
+/// Handle to the reader and writer tasks of a connection, returned by
+/// `Connection::new_with_handle` and `PlainConnection::new_with_handle`.
+///
+/// The tasks do not need the handle: dropping every sender returned with the handle make the
+/// writer task exit and shut down the tcp stream, that make the reader task exit. The reader task
+/// also exit when the stream is closed by the remote or when a frame is received and the receiver
+/// has been dropped, in the last case the sender is closed too so that the writer task exit.
+/// Dropping the handle detach the tasks.
+#[derive(Debug)]
+pub struct ConnectionHandle {
+    stream: TcpStream,
+    reader: ConnectionTask,
+    writer: ConnectionTask,
+    remote_certificate_expiry: Option<SystemTime>,
+    channel_binding: Option<Vec<u8>>,
+    security_event: SecurityEventSlot,
+}
+
+impl ConnectionHandle {
+    pub(crate) fn new(stream: TcpStream, reader: ConnectionTask, writer: ConnectionTask) -> Self {
+        Self {
+            stream,
+            reader,
+            writer,
+            remote_certificate_expiry: None,
+            channel_binding: None,
+            security_event: SecurityEventSlot::default(),
+        }
+    }
+
+    pub(crate) fn with_remote_certificate_expiry(mut self, expiry: Option<SystemTime>) -> Self {
+        self.remote_certificate_expiry = expiry;
+        self
+    }
+
+    pub(crate) fn with_channel_binding(mut self, channel_binding: Option<Vec<u8>>) -> Self {
+        self.channel_binding = channel_binding;
+        self
+    }
+
+    pub(crate) fn with_security_event(mut self, security_event: SecurityEventSlot) -> Self {
+        self.security_event = security_event;
+        self
+    }
+
+    /// When the certificate of the remote expires, only for the noise connections opened as
+    /// initiator
+    pub fn remote_certificate_expiry(&self) -> Option<SystemTime> {
+        self.remote_certificate_expiry
+    }
+
+    /// Hash of the noise handshake, only for the noise connections. The remote has the same value,
+    /// see `codec_sv2::TransportMode::handshake_hash`
+    pub fn channel_binding(&self) -> Option<&[u8]> {
+        self.channel_binding.as_deref()
+    }
+
+    /// Security event that closed the connection, if any. Only the noise connections detect them
+    pub fn security_event(&self) -> Option<SecurityEvent> {
+        // Is fine to unwrap, the lock is never held while panicking
+        *self.security_event.lock().unwrap()
+    }
+
+    pub fn is_reader_running(&self) -> bool {
+        self.reader.running.load(Ordering::Acquire)
+    }
+
+    pub fn is_writer_running(&self) -> bool {
+        self.writer.running.load(Ordering::Acquire)
+    }
+
+    pub fn abort_token(&self) -> AbortToken {
+        AbortToken {
+            stream: self.stream.clone(),
+            reader: self.reader.abort.clone(),
+            writer: self.writer.abort.clone(),
+        }
+    }
+
+    /// Abort both tasks without flushing the queued frames and wait for them to exit
+    pub async fn abort(self) {
+        self.abort_token().abort();
+        self.join().await;
+    }
+
+    /// Wait for both tasks to exit
+    pub async fn join(self) {
+        self.reader.join.await;
+        self.writer.join.await;
+    }
+
+    /// Join handles of the reader and of the writer task
+    pub fn into_join_handles(self) -> (JoinHandle<()>, JoinHandle<()>) {
+        (self.reader.join, self.writer.join)
+    }
+
+    /// Gracefully close the connection. `sender` is the sender returned together with the handle:
+    /// it is closed so that the writer task flush the frames already queued and exit, then the tcp
+    /// stream is shut down and the reader task is joined. If the writer task do not exit within
+    /// `SHUTDOWN_FLUSH_TIMEOUT` (eg the remote do not read and the stream is full) the frames still
+    /// queued are dropped and both tasks are aborted.
+    pub async fn shutdown<T>(self, sender: &Sender<T>) {
+        sender.close();
+        let abort_token = self.abort_token();
+        let Self {
+            stream,
+            reader,
+            mut writer,
+            ..
+        } = self;
+        match async_std::future::timeout(SHUTDOWN_FLUSH_TIMEOUT, &mut writer.join).await {
+            Ok(()) => {
+                let _ = stream.shutdown(async_std::net::Shutdown::Both);
+            }
+            Err(_) => {
+                abort_token.abort();
+                writer.join.await;
+            }
+        }
+        reader.join.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plain_connection_async_std::PlainConnection;
+    use async_std::{
+        future::timeout,
+        io::{ReadExt, WriteExt},
+        net::TcpListener,
+    };
+
+    /// Serialized sv2 frame with a `u32` payload
+    fn raw_frame(payload: u32) -> Vec<u8> {
+        let mut frame = vec![0, 0, 1, 4, 0, 0];
+        frame.extend_from_slice(&payload.to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn dropping_the_receiver_close_the_connection_and_end_both_tasks() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let (receiver, sender, handle) =
+                PlainConnection::new_with_handle::<u32>(stream, 8).await;
+            assert!(handle.is_reader_running() && handle.is_writer_running());
+
+            // The reader notice the dropped receiver when it receive the next frame
+            drop(receiver);
+            peer.write_all(&raw_frame(7)).await.unwrap();
+
+            let mut buffer = [0; 16];
+            let read = timeout(Duration::from_secs(1), peer.read(&mut buffer)).await;
+            assert_eq!(read.unwrap().unwrap(), 0);
+
+            let stopped = timeout(Duration::from_secs(1), async {
+                while handle.is_reader_running() || handle.is_writer_running() {
+                    task::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await;
+            assert!(stopped.is_ok());
+            // The sender is still alive but the connection can not be used anymore
+            assert!(sender.is_closed());
+            timeout(Duration::from_secs(1), handle.join())
+                .await
+                .unwrap();
+        });
+    }
+}
@@ -0,0 +1,150 @@
+//! Threads where the CPU heavy part of the noise handshakes is done.
+//!
+//! A handshake cost some DH operations, the verification of the certificate of the Responder and,
+//! for the connections accepted by `listen`, the creation and the signature of a new certificate.
+//! That is a lot more than the steady state traffic of a connection, so a reconnect storm (eg
+//! every device of a farm reconnecting after an upstream restart) would starve the executor that
+//! serve the connections. The handshake steps of `Connection` are run on a small pool of dedicated
+//! threads instead, the queue of the pool is bounded so that the handshakes wait (asynchronously)
+//! when the pool is saturated.
+//!
+//! The pool is created on the first handshake with `DEFAULT_THREADS` and `DEFAULT_QUEUE`, call
+//! `init_handshake_workers` before the first connection to choose other values.
+use async_channel::{bounded, Sender};
+use once_cell::sync::OnceCell;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+pub const DEFAULT_THREADS: usize = 4;
+/// Max number of handshake steps waiting for a thread
+pub const DEFAULT_QUEUE: usize = 1024;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+static WORKERS: OnceCell<HandshakeWorkers> = OnceCell::new();
+
+#[derive(Debug, Clone)]
+pub struct HandshakeWorkers {
+    jobs: Sender<Job>,
+}
+
+impl HandshakeWorkers {
+    /// Start `threads` threads, `queue` jobs can wait for a thread
+    pub fn new(threads: usize, queue: usize) -> Self {
+        let (jobs, receiver) = bounded::<Job>(queue.max(1));
+        for index in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("handshake-worker-{}", index))
+                .spawn(move || {
+                    while let Ok(job) = async_std::task::block_on(receiver.recv()) {
+                        // A job that panic only lose its result, the thread keep serving the
+                        // other jobs
+                        let _ = catch_unwind(AssertUnwindSafe(job));
+                    }
+                })
+                .expect("Can not spawn an handshake worker");
+        }
+        Self { jobs }
+    }
+
+    /// Run `job` on a worker, None if the job panicked
+    pub async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Option<T> {
+        let (sender, receiver) = bounded(1);
+        let job: Job = Box::new(move || {
+            let _ = sender.try_send(job());
+        });
+        // The workers never stop, so the channel is never closed
+        self.jobs.send(job).await.ok()?;
+        receiver.recv().await.ok()
+    }
+}
+
+/// Size the pool used by the handshakes, return false if the pool has already been created
+pub fn init_handshake_workers(threads: usize, queue: usize) -> bool {
+    WORKERS.set(HandshakeWorkers::new(threads, queue)).is_ok()
+}
+
+/// Pool used by the handshakes of `Connection`
+pub fn handshake_workers() -> &'static HandshakeWorkers {
+    WORKERS.get_or_init(|| HandshakeWorkers::new(DEFAULT_THREADS, DEFAULT_QUEUE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{future::timeout, task};
+    use std::{
+        sync::{Arc, Barrier},
+        time::Duration,
+    };
+
+    #[test]
+    fn jobs_run_concurrently() {
+        let workers = HandshakeWorkers::new(2, 4);
+        // Each job wait for the other, they complete only if they run on two threads
+        let barrier = Arc::new(Barrier::new(2));
+        let (first, second) = (barrier.clone(), barrier);
+        let first = task::spawn({
+            let workers = workers.clone();
+            async move { workers.run(move || first.wait().is_leader()).await }
+        });
+        let second =
+            task::spawn(async move { workers.run(move || second.wait().is_leader()).await });
+        task::block_on(async {
+            let first = timeout(Duration::from_secs(5), first).await.unwrap();
+            let second = timeout(Duration::from_secs(5), second).await.unwrap();
+            // Only one of the threads is the leader
+            assert!(first.unwrap() ^ second.unwrap());
+        });
+    }
+
+    #[test]
+    fn full_queue_make_the_jobs_wait() {
+        let workers = HandshakeWorkers::new(1, 1);
+        let (started_sender, started) = bounded(1);
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        task::block_on(async {
+            // The only thread is busy
+            let busy = task::spawn({
+                let workers = workers.clone();
+                async move {
+                    workers
+                        .run(move || {
+                            let _ = started_sender.try_send(());
+                            released.recv().unwrap();
+                        })
+                        .await
+                }
+            });
+            started.recv().await.unwrap();
+            // The only place in the queue is taken
+            let queued = task::spawn({
+                let workers = workers.clone();
+                async move { workers.run(|| 1).await }
+            });
+            while !workers.jobs.is_full() {
+                task::yield_now().await;
+            }
+            assert!(timeout(Duration::from_millis(100), workers.run(|| 2))
+                .await
+                .is_err());
+
+            release.send(()).unwrap();
+            assert_eq!(busy.await, Some(()));
+            assert_eq!(queued.await, Some(1));
+            assert_eq!(workers.run(|| 3).await, Some(3));
+        });
+    }
+
+    #[test]
+    fn panicking_job_does_not_stop_the_worker() {
+        let workers = HandshakeWorkers::new(1, 1);
+        task::block_on(async {
+            assert_eq!(workers.run(|| -> u8 { panic!("malformed") }).await, None);
+            assert_eq!(workers.run(|| 1).await, Some(1));
+        });
+    }
+}
@@ -0,0 +1,220 @@
+//! Dual stack dialing of the upstreams (happy eyeballs, RFC 8305).
+//!
+//! An hostname of a pool often resolve to both IPv6 and IPv4 addresses. Dialing only the first
+//! resolved address on a network with a broken IPv6 route block until the tcp connect time out
+//! (minutes on some systems). `dial` order the resolved addresses alternating the families, IPv6
+//! first, and start a new attempt every `CONNECTION_ATTEMPT_DELAY` (or as soon as the previous
+//! attempt fail) without cancelling the attempts already started. The first connected stream is
+//! returned and the other attempts are dropped.
+use async_std::{
+    future::timeout,
+    net::{TcpStream, ToSocketAddrs},
+};
+use futures::{
+    stream::{FuturesUnordered, StreamExt},
+    Future,
+};
+use std::{collections::VecDeque, io, net::SocketAddr, time::Duration};
+
+/// Wait before starting the attempt with the next address, see RFC 8305 section 5
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolved addresses alternating the families, starting from IPv6. The order of the addresses
+/// of the same family is kept.
+fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (VecDeque<_>, VecDeque<_>) =
+        addresses.into_iter().partition(|a| a.is_ipv6());
+    let mut sorted = Vec::with_capacity(v6.len() + v4.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        sorted.extend(v6.pop_front());
+        sorted.extend(v4.pop_front());
+    }
+    sorted
+}
+
+/// Open a tcp connection with `address` (an hostname or an ip with the port), racing the
+/// resolved addresses. Return the error of the last failed attempt if no attempt succeed.
+pub async fn dial(address: &str) -> io::Result<TcpStream> {
+    let addresses: Vec<SocketAddr> = address.to_socket_addrs().await?.collect();
+    dial_addresses(interleave(addresses), CONNECTION_ATTEMPT_DELAY).await
+}
+
+async fn dial_addresses(addresses: Vec<SocketAddr>, delay: Duration) -> io::Result<TcpStream> {
+    race(addresses, delay, TcpStream::connect).await
+}
+
+/// Race the attempts made with `connect`, kept apart from the IO so that it can be tested
+async fn race<S, F, A>(addresses: Vec<SocketAddr>, delay: Duration, connect: F) -> io::Result<S>
+where
+    F: Fn(SocketAddr) -> A,
+    A: Future<Output = io::Result<S>>,
+{
+    let mut last_error = io::Error::new(io::ErrorKind::AddrNotAvailable, "No address resolved");
+    let mut addresses = addresses.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    match addresses.next() {
+        Some(address) => attempts.push(connect(address)),
+        None => return Err(last_error),
+    }
+    loop {
+        // Wait for an attempt to complete, at most `delay` if there is another address to try
+        let completed = if addresses.len() > 0 {
+            timeout(delay, attempts.next()).await.ok()
+        } else {
+            Some(attempts.next().await)
+        };
+        match completed {
+            Some(Some(Ok(stream))) => return Ok(stream),
+            Some(Some(Err(e))) => {
+                last_error = e;
+                match addresses.next() {
+                    Some(address) => attempts.push(connect(address)),
+                    None if attempts.is_empty() => return Err(last_error),
+                    None => (),
+                }
+            }
+            Some(None) => return Err(last_error),
+            // The delay elapsed, the next address is tried in parallel
+            None => {
+                if let Some(address) = addresses.next() {
+                    attempts.push(connect(address));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{net::TcpListener, task};
+    use futures::future::{pending, BoxFuture, FutureExt};
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Instant,
+    };
+
+    /// Set `dropped` when the attempt is dropped
+    struct DropGuard(Arc<AtomicBool>);
+
+    impl Drop for DropGuard {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Connect to every address but `black_hole`, for which the attempt never complete like a
+    /// connect to an address that drop the SYNs
+    fn connect(
+        black_hole: SocketAddr,
+        dropped: Arc<AtomicBool>,
+    ) -> impl Fn(SocketAddr) -> BoxFuture<'static, io::Result<TcpStream>> {
+        move |address| {
+            if address == black_hole {
+                let guard = DropGuard(dropped.clone());
+                async move {
+                    let _guard = guard;
+                    pending::<io::Result<TcpStream>>().await
+                }
+                .boxed()
+            } else {
+                TcpStream::connect(address).boxed()
+            }
+        }
+    }
+
+    #[test]
+    fn interleave_the_families_starting_from_ipv6() {
+        let addresses: Vec<SocketAddr> = vec![
+            "10.0.0.1:1".parse().unwrap(),
+            "10.0.0.2:1".parse().unwrap(),
+            "10.0.0.3:1".parse().unwrap(),
+            "[2001:db8::1]:1".parse().unwrap(),
+        ];
+        assert_eq!(
+            interleave(addresses.clone()),
+            vec![addresses[3], addresses[0], addresses[1], addresses[2]]
+        );
+    }
+
+    #[test]
+    fn a_black_holed_address_do_not_block_the_next_one() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let black_hole: SocketAddr = "[2001:db8::1]:34255".parse().unwrap();
+            let dropped = Arc::new(AtomicBool::new(false));
+
+            let start = Instant::now();
+            let stream = race(
+                vec![black_hole, address],
+                CONNECTION_ATTEMPT_DELAY,
+                connect(black_hole, dropped.clone()),
+            )
+            .await
+            .unwrap();
+            let elapsed = start.elapsed();
+
+            assert_eq!(stream.peer_addr().unwrap(), address);
+            // The second attempt start after the delay and connect right away
+            assert!(elapsed >= CONNECTION_ATTEMPT_DELAY);
+            assert!(elapsed < CONNECTION_ATTEMPT_DELAY * 2, "{:?}", elapsed);
+            // The losing attempt is cancelled
+            assert!(dropped.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn the_next_address_is_not_tried_if_the_first_connect() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let black_hole: SocketAddr = "[2001:db8::1]:34255".parse().unwrap();
+            let dropped = Arc::new(AtomicBool::new(false));
+
+            let start = Instant::now();
+            let stream = race(
+                vec![address, black_hole],
+                CONNECTION_ATTEMPT_DELAY,
+                connect(black_hole, dropped.clone()),
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(stream.peer_addr().unwrap(), address);
+            assert!(start.elapsed() < CONNECTION_ATTEMPT_DELAY);
+            assert!(!dropped.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn the_next_address_is_tried_as_soon_as_the_previous_fail() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            // Nothing listen on the address of a dropped listener
+            let refused = TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let black_hole: SocketAddr = "[2001:db8::1]:34255".parse().unwrap();
+            let dropped = Arc::new(AtomicBool::new(false));
+
+            let start = Instant::now();
+            let stream = race(
+                vec![refused, address],
+                CONNECTION_ATTEMPT_DELAY,
+                connect(black_hole, dropped),
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(stream.peer_addr().unwrap(), address);
+            assert!(start.elapsed() < CONNECTION_ATTEMPT_DELAY);
+        });
+    }
+}
@@ -1,8 +1,48 @@
+mod admission;
+#[cfg(feature = "async_std")]
+mod connection_handle;
+#[cfg(feature = "async_std")]
+mod handshake_workers;
+#[cfg(feature = "async_std")]
+mod happy_eyeballs;
 #[cfg(feature = "async_std")]
 mod noise_connection_async_std;
 #[cfg(feature = "async_std")]
+mod outbound_queue;
+#[cfg(feature = "async_std")]
 mod plain_connection_async_std;
+#[cfg(feature = "quic")]
+mod quic_connection_async_std;
+#[cfg(feature = "async_std")]
+mod retry;
+#[cfg(feature = "tls")]
+mod tls_connection_async_std;
+#[cfg(feature = "ws")]
+mod ws_connection_async_std;
+pub use admission::{AdmissionHook, AdmissionPolicy, Cidr, InvalidCidr};
+#[cfg(feature = "async_std")]
+pub use connection_handle::{AbortToken, ConnectionHandle, SecurityEvent, SHUTDOWN_FLUSH_TIMEOUT};
+#[cfg(feature = "async_std")]
+pub use handshake_workers::{
+    handshake_workers, init_handshake_workers, HandshakeWorkers, DEFAULT_QUEUE, DEFAULT_THREADS,
+};
+#[cfg(feature = "async_std")]
+pub use happy_eyeballs::{dial, CONNECTION_ATTEMPT_DELAY};
+#[cfg(feature = "initiator_auth")]
+pub use noise_connection_async_std::listen_with_allowed_initiators;
+#[cfg(feature = "async_std")]
+pub use noise_connection_async_std::{connect, listen, listen_with_policy, Connection};
+#[cfg(feature = "async_std")]
+pub use outbound_queue::CONTROL_MESSAGE_TYPES;
 #[cfg(feature = "async_std")]
-pub use noise_connection_async_std::{connect, listen, Connection};
+pub use plain_connection_async_std::{
+    plain_connect, plain_listen, plain_listen_with_policy, PlainConnection,
+};
+#[cfg(feature = "quic")]
+pub use quic_connection_async_std::{quic_connect, quic_listen, QuicConnection};
 #[cfg(feature = "async_std")]
-pub use plain_connection_async_std::{plain_connect, plain_listen, PlainConnection};
+pub use retry::{connect_with_retry, ConnectAttempt, ConnectError, RetryPolicy};
+#[cfg(feature = "tls")]
+pub use tls_connection_async_std::{rustls, tls_client_config, TlsConnection};
+#[cfg(feature = "ws")]
+pub use ws_connection_async_std::WsConnection;
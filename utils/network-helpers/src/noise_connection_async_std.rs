@@ -1,17 +1,33 @@
+use crate::{
+    admission::AdmissionPolicy,
+    connection_handle::{spawn_task, SecurityEvent, SecurityEventSlot},
+    handshake_workers::handshake_workers,
+    happy_eyeballs::dial,
+    outbound_queue::OutboundQueue,
+    retry::ConnectError,
+    ConnectionHandle,
+};
 use async_channel::{bounded, Receiver, Sender};
 use async_std::{
     net::{TcpListener, TcpStream},
     prelude::*,
     sync::{Arc, Mutex},
-    task,
 };
 use binary_sv2::{Deserialize, Serialize};
+use const_sv2::SV2_FRAME_MAX_LEN;
 use core::convert::TryInto;
+use futures::{
+    future::{select, Either},
+    pin_mut,
+};
 use std::time::Duration;
 
+#[cfg(feature = "initiator_auth")]
+use codec_sv2::noise_sv2::{AllowedInitiators, Authority};
+
 use binary_sv2::GetSize;
 use codec_sv2::{
-    Frame, HandShakeFrame, HandshakeRole, Initiator, Responder, StandardEitherFrame,
+    Frame, HandShakeFrame, HandshakeRole, Initiator, PaddingPolicy, Responder, StandardEitherFrame,
     StandardNoiseDecoder,
 };
 
@@ -30,6 +46,140 @@ impl Connection {
         Receiver<StandardEitherFrame<Message>>,
         Sender<StandardEitherFrame<Message>>,
     ) {
+        let (receiver, sender, _) = Self::new_with_handle(stream, role, capacity).await;
+        (receiver, sender)
+    }
+
+    /// Like `Connection::new` but also return a `ConnectionHandle` that can be used to gracefully
+    /// shutdown the connection
+    pub async fn new_with_handle<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        stream: TcpStream,
+        role: HandshakeRole,
+        capacity: usize,
+    ) -> (
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        ConnectionHandle,
+    ) {
+        let (receiver, sender, _, handle) = Self::new_with_priority(stream, role, capacity).await;
+        (receiver, sender, handle)
+    }
+
+    /// Like `Connection::new_with_handle` but also return a priority sender: the frames sent with
+    /// it are written before the frames already queued in the normal sender (eg a block solution
+    /// that can not wait behind a batch of shares). Once the priority sender is dropped the
+    /// connection only serve the normal one.
+    pub async fn new_with_priority<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        stream: TcpStream,
+        role: HandshakeRole,
+        capacity: usize,
+    ) -> (
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        ConnectionHandle,
+    ) {
+        Self::new_with_padding(stream, role, capacity, PaddingPolicy::None).await
+    }
+
+    /// Like `Connection::new_with_priority` but the frames sent once the handshake is completed are
+    /// padded following `padding`, see `codec_sv2::PaddingPolicy`. The remote must support the
+    /// padding frames
+    pub async fn new_with_padding<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        stream: TcpStream,
+        role: HandshakeRole,
+        capacity: usize,
+        padding: PaddingPolicy,
+    ) -> (
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        ConnectionHandle,
+    ) {
+        Self::new_with_max_frame_len(stream, role, capacity, padding, SV2_FRAME_MAX_LEN).await
+    }
+
+    /// Like `Connection::new_with_padding` but the incoming frames longer than `max_frame_len` (at
+    /// most `SV2_FRAME_MAX_LEN`) are rejected and the connection is closed, so that a peer can not
+    /// make the connection buffer 16MB frames
+    pub async fn new_with_max_frame_len<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        stream: TcpStream,
+        role: HandshakeRole,
+        capacity: usize,
+        padding: PaddingPolicy,
+        max_frame_len: usize,
+    ) -> (
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        ConnectionHandle,
+    ) {
+        Self::try_new_with_priority(stream, role, capacity, padding, max_frame_len)
+            .await
+            .unwrap()
+    }
+
+    /// Like `Connection::new_with_handle` but a failed handshake as initiator (eg the upstream
+    /// closed the connection or its certificate is not valid) is returned instead of panicking,
+    /// the connection is closed
+    #[allow(clippy::type_complexity)]
+    pub async fn try_new_with_handle<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        stream: TcpStream,
+        role: HandshakeRole,
+        capacity: usize,
+    ) -> Result<
+        (
+            Receiver<StandardEitherFrame<Message>>,
+            Sender<StandardEitherFrame<Message>>,
+            ConnectionHandle,
+        ),
+        ConnectError,
+    > {
+        let (receiver, sender, _, handle) = Self::try_new_with_priority(
+            stream,
+            role,
+            capacity,
+            PaddingPolicy::None,
+            SV2_FRAME_MAX_LEN,
+        )
+        .await?;
+        Ok((receiver, sender, handle))
+    }
+
+    #[allow(clippy::type_complexity)]
+    async fn try_new_with_priority<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        stream: TcpStream,
+        role: HandshakeRole,
+        capacity: usize,
+        padding: PaddingPolicy,
+        max_frame_len: usize,
+    ) -> Result<
+        (
+            Receiver<StandardEitherFrame<Message>>,
+            Sender<StandardEitherFrame<Message>>,
+            Sender<StandardEitherFrame<Message>>,
+            ConnectionHandle,
+        ),
+        ConnectError,
+    > {
         let (mut reader, writer) = (stream.clone(), stream.clone());
 
         let (sender_incoming, receiver_incoming): (
@@ -40,6 +190,10 @@ impl Connection {
             Sender<StandardEitherFrame<Message>>,
             Receiver<StandardEitherFrame<Message>>,
         ) = bounded(capacity);
+        let (sender_priority, receiver_priority): (
+            Sender<StandardEitherFrame<Message>>,
+            Receiver<StandardEitherFrame<Message>>,
+        ) = bounded(capacity);
 
         let state = codec_sv2::State::new();
 
@@ -47,10 +201,16 @@ impl Connection {
 
         let cloned1 = connection.clone();
         let cloned2 = connection.clone();
+        let security_event = SecurityEventSlot::default();
+        let security_event_cloned = security_event.clone();
+
+        // Closed by the reader when the receiver is dropped, so that the writer exit too
+        let outgoing = receiver_outgoing.clone();
 
         // RECEIVE AND PARSE INCOMING MESSAGES FROM TCP STREAM
-        task::spawn(async move {
+        let reader_task = spawn_task(async move {
             let mut decoder = StandardNoiseDecoder::<Message>::new();
+            decoder.set_max_frame_len(max_frame_len);
 
             loop {
                 let writable = decoder.writable();
@@ -58,8 +218,35 @@ impl Connection {
                     Ok(_) => {
                         let mut connection = cloned1.lock().await;
 
-                        if let Ok(x) = decoder.next_frame(&mut connection.state) {
-                            sender_incoming.send(x).await.unwrap();
+                        match decoder.next_frame(&mut connection.state) {
+                            Ok(x) => {
+                                // Receiver has been dropped the connection is not used anymore
+                                if sender_incoming.send(x).await.is_err() {
+                                    outgoing.close();
+                                    let _ = reader.shutdown(async_std::net::Shutdown::Both);
+                                    break;
+                                }
+                            }
+                            // The stream can not be decoded anymore
+                            Err(e @ codec_sv2::Error::FrameTooLarge { .. })
+                            | Err(e @ codec_sv2::Error::InvalidFragment) => {
+                                println!("{:?}", e);
+                                let _ = reader.shutdown(async_std::net::Shutdown::Both);
+                                break;
+                            }
+                            // The session can not be trusted anymore
+                            Err(codec_sv2::Error::TagMismatch) => {
+                                let event = SecurityEvent::TagMismatch;
+                                println!(
+                                    "Security event {:?} from {:?}, closing the connection",
+                                    event,
+                                    reader.peer_addr().ok()
+                                );
+                                *security_event_cloned.lock().unwrap() = Some(event);
+                                let _ = reader.shutdown(async_std::net::Shutdown::Both);
+                                break;
+                            }
+                            Err(_) => (),
                         }
                     }
                     Err(e) => {
@@ -74,20 +261,53 @@ impl Connection {
         let receiver_outgoing_cloned = receiver_outgoing.clone();
 
         // ENCODE AND SEND INCOMING MESSAGES TO TCP STREAM
-        task::spawn(async move {
+        let writer_task = spawn_task(async move {
             let mut encoder = codec_sv2::NoiseEncoder::<Message>::new();
+            encoder.set_padding(padding);
+            let mut queue = OutboundQueue::new(capacity);
 
             loop {
-                let received = receiver_outgoing.recv().await;
+                let received = match receiver_priority.try_recv() {
+                    Ok(frame) => Ok(frame),
+                    Err(_) if receiver_priority.is_closed() => queue.recv(&receiver_outgoing).await,
+                    Err(_) => {
+                        // Dropping the pending recv does not lose any frame
+                        let priority = receiver_priority.recv();
+                        let normal = queue.recv(&receiver_outgoing);
+                        pin_mut!(priority, normal);
+                        match select(priority, normal).await {
+                            Either::Left((Ok(frame), _)) => Ok(frame),
+                            Either::Left((Err(_), normal)) => normal.await,
+                            Either::Right((received, _)) => received,
+                        }
+                    }
+                };
                 match received {
                     Ok(frame) => {
                         let mut connection = cloned2.lock().await;
-                        let b = encoder.encode(frame, &mut connection.state).unwrap();
+                        let mut b = encoder
+                            .encode(frame, &mut connection.state)
+                            .unwrap()
+                            .to_vec();
+                        // The frames already queued are written together with this one
+                        for _ in 1..capacity {
+                            let frame = match receiver_priority.try_recv() {
+                                Ok(frame) => frame,
+                                Err(_) => match queue.try_recv(&receiver_outgoing) {
+                                    Some(frame) => frame,
+                                    None => break,
+                                },
+                            };
+                            b.extend_from_slice(
+                                encoder.encode(frame, &mut connection.state).unwrap(),
+                            );
+                        }
 
-                        match (&writer).write_all(b).await {
+                        match (&writer).write_all(&b).await {
                             Ok(_) => (),
                             Err(_) => {
                                 let _ = writer.shutdown(async_std::net::Shutdown::Both);
+                                break;
                             }
                         }
                     }
@@ -119,13 +339,33 @@ impl Connection {
                 .await
             }
         };
+        let transport_mode = match transport_mode {
+            Ok(transport_mode) => transport_mode,
+            // The reader task exit when the stream is closed and the writer task when the senders
+            // are dropped
+            Err(e) => {
+                let _ = stream.shutdown(async_std::net::Shutdown::Both);
+                return Err(e);
+            }
+        };
 
+        let remote_certificate_expiry = transport_mode
+            .transport_mode()
+            .and_then(|transport_mode| transport_mode.remote_certificate_expiry());
+        let channel_binding = transport_mode
+            .transport_mode()
+            .and_then(|transport_mode| transport_mode.handshake_hash())
+            .map(|hash| hash.to_vec());
         Self::set_state(connection.clone(), transport_mode).await;
 
-        (receiver_incoming, sender_outgoing)
+        let handle = ConnectionHandle::new(stream, reader_task, writer_task)
+            .with_remote_certificate_expiry(remote_certificate_expiry)
+            .with_channel_binding(channel_binding)
+            .with_security_event(security_event);
+        Ok((receiver_incoming, sender_outgoing, sender_priority, handle))
     }
 
-    async fn set_state(self_: Arc<Mutex<Self>>, state: codec_sv2::State) {
+    pub(crate) async fn set_state(self_: Arc<Mutex<Self>>, state: codec_sv2::State) {
         loop {
             if let Some(mut connection) = self_.try_lock() {
                 connection.state = state;
@@ -134,49 +374,100 @@ impl Connection {
         }
     }
 
-    async fn initialize_as_downstream<'a, Message: Serialize + Deserialize<'a> + GetSize>(
+    pub(crate) async fn initialize_as_downstream<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize,
+    >(
         role: HandshakeRole,
         sender_outgoing: Sender<StandardEitherFrame<Message>>,
         receiver_incoming: Receiver<StandardEitherFrame<Message>>,
-    ) -> codec_sv2::State {
-        let mut state = codec_sv2::State::initialize(role);
+    ) -> Result<codec_sv2::State, ConnectError> {
+        let workers = handshake_workers();
+        let (state, first_message) = workers
+            .run(move || {
+                let mut state = codec_sv2::State::initialize(role);
+                let first_message = state.step(None);
+                (state, first_message)
+            })
+            .await
+            .ok_or(ConnectError::Handshake("can not build first message"))?;
+        let first_message =
+            first_message.map_err(|_| ConnectError::Handshake("can not build first message"))?;
+        sender_outgoing
+            .send(first_message.into())
+            .await
+            .map_err(|_| ConnectError::ConnectionClosed)?;
 
-        let first_message = state.step(None).unwrap();
-        sender_outgoing.send(first_message.into()).await.unwrap();
-
-        let second_message = receiver_incoming.recv().await.unwrap();
-        let mut second_message: HandShakeFrame = second_message.try_into().unwrap();
+        let second_message = receiver_incoming
+            .recv()
+            .await
+            .map_err(|_| ConnectError::ConnectionClosed)?;
+        let mut second_message: HandShakeFrame = second_message
+            .try_into()
+            .map_err(|_| ConnectError::Handshake("expected an handshake frame"))?;
         let second_message = second_message.payload().to_vec();
 
-        state.step(Some(second_message)).unwrap();
-
-        state.into_transport_mode().unwrap()
+        // Verify the certificate of the upstream and complete the DH off the executor
+        workers
+            .run(move || {
+                let mut state = state;
+                state
+                    .step(Some(second_message))
+                    .map_err(|_| ConnectError::Handshake("invalid certificate"))?;
+                state
+                    .into_transport_mode()
+                    .map_err(|_| ConnectError::Handshake("can not enter transport mode"))
+            })
+            .await
+            // A malformed second message can make the handshake step panic on the worker
+            .unwrap_or(Err(ConnectError::Handshake("invalid certificate")))
     }
 
-    async fn initialize_as_upstream<'a, Message: Serialize + Deserialize<'a> + GetSize>(
+    pub(crate) async fn initialize_as_upstream<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize,
+    >(
         role: HandshakeRole,
         sender_outgoing: Sender<StandardEitherFrame<Message>>,
         sender_incoming: Receiver<StandardEitherFrame<Message>>,
         receiver_incoming: Receiver<StandardEitherFrame<Message>>,
-    ) -> codec_sv2::State {
-        let mut state = codec_sv2::State::initialize(role);
-
-        let mut first_message: HandShakeFrame =
-            receiver_incoming.recv().await.unwrap().try_into().unwrap();
+    ) -> Result<codec_sv2::State, ConnectError> {
+        let first_message = receiver_incoming
+            .recv()
+            .await
+            .map_err(|_| ConnectError::ConnectionClosed)?;
+        let mut first_message: HandShakeFrame = first_message
+            .try_into()
+            .map_err(|_| ConnectError::Handshake("expected an handshake frame"))?;
         let first_message = first_message.payload().to_vec();
 
-        let second_message = state.step(Some(first_message)).unwrap();
+        let (state, second_message) = handshake_workers()
+            .run(move || {
+                let mut state = codec_sv2::State::initialize(role);
+                let second_message = state.step(Some(first_message));
+                (state, second_message)
+            })
+            .await
+            // A malformed first message can make the handshake step panic on the worker
+            .ok_or(ConnectError::Handshake("Initiator rejected"))?;
+        // The first message can not be read or the Initiator is not allowed
+        let second_message =
+            second_message.map_err(|_| ConnectError::Handshake("Initiator rejected"))?;
 
-        sender_outgoing.send(second_message.into()).await.unwrap();
+        sender_outgoing
+            .send(second_message.into())
+            .await
+            .map_err(|_| ConnectError::ConnectionClosed)?;
 
-        // CHECK IF SECOND_MESSAGE HAS BEEN SENT
-        loop {
-            if sender_incoming.is_empty() {
-                break;
-            }
+        // CHECK IF SECOND_MESSAGE HAS BEEN SENT, yielding so that the writer task can run on this
+        // thread
+        while !sender_incoming.is_empty() {
+            async_std::task::yield_now().await;
         }
 
-        state.into_transport_mode().unwrap()
+        state
+            .into_transport_mode()
+            .map_err(|_| ConnectError::Handshake("can not enter transport mode"))
     }
 }
 
@@ -187,25 +478,104 @@ pub async fn listen(
     cert_validity: Duration,
     sender: Sender<(TcpStream, HandshakeRole)>,
 ) {
-    let listner = TcpListener::bind(address).await.unwrap();
-    let mut incoming = listner.incoming();
-    while let Some(stream) = incoming.next().await {
-        let stream = stream.unwrap();
-        let responder = Responder::from_authority_kp(
+    listen_with_policy(
+        address,
+        authority_public_key,
+        authority_private_key,
+        cert_validity,
+        AdmissionPolicy::default(),
+        sender,
+    )
+    .await
+}
+
+/// Like `listen` but the connections of the peers rejected by `policy` are dropped before the
+/// handshake
+pub async fn listen_with_policy(
+    address: &str,
+    authority_public_key: [u8; 32],
+    authority_private_key: [u8; 32],
+    cert_validity: Duration,
+    policy: AdmissionPolicy,
+    sender: Sender<(TcpStream, HandshakeRole)>,
+) {
+    let new_responder = move || {
+        Responder::from_authority_kp(
             &authority_public_key[..],
             &authority_private_key[..],
             cert_validity,
         )
-        .unwrap();
-        let role = HandshakeRole::Responder(responder);
-        let _ = sender.send((stream, role)).await;
+        .unwrap()
+    };
+    listen_with_responder(address, policy, new_responder, sender).await
+}
+
+/// Like `listen_with_policy` but the Responders accept only the Initiators with a static key in
+/// `allowed_initiators` (built with `Initiator::with_static_keypair`). The handshake of the other
+/// Initiators fail and the connection is closed
+#[cfg(feature = "initiator_auth")]
+pub async fn listen_with_allowed_initiators(
+    address: &str,
+    authority_public_key: [u8; 32],
+    authority_private_key: [u8; 32],
+    cert_validity: Duration,
+    policy: AdmissionPolicy,
+    allowed_initiators: AllowedInitiators,
+    sender: Sender<(TcpStream, HandshakeRole)>,
+) {
+    let new_responder = move || {
+        let authority = Authority::from_raw_k(&authority_public_key, &authority_private_key)
+            .expect("Invalid authority keys");
+        Responder::from_authority_with_allowed_initiators(
+            &authority,
+            cert_validity,
+            allowed_initiators.clone(),
+        )
+        .unwrap()
+    };
+    listen_with_responder(address, policy, new_responder, sender).await
+}
+
+async fn listen_with_responder(
+    address: &str,
+    policy: AdmissionPolicy,
+    new_responder: impl Fn() -> Responder + Clone + Send + 'static,
+    sender: Sender<(TcpStream, HandshakeRole)>,
+) {
+    let listner = TcpListener::bind(address).await.unwrap();
+    let mut incoming = listner.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = stream.unwrap();
+        match stream.peer_addr() {
+            Ok(peer) if policy.admit(peer) => (),
+            _ => continue,
+        }
+        // A new static key and certificate for every connection, generated by the handshake
+        // workers in the task of the connection so that the listener keep accepting while the
+        // workers are busy
+        let new_responder = new_responder.clone();
+        let sender = sender.clone();
+        async_std::task::spawn(async move {
+            match handshake_workers().run(new_responder).await {
+                Some(responder) => {
+                    let role = HandshakeRole::Responder(responder);
+                    let _ = sender.send((stream, role)).await;
+                }
+                None => {
+                    println!("Can not build the Responder for {:?}", stream.peer_addr());
+                    let _ = stream.shutdown(async_std::net::Shutdown::Both);
+                }
+            }
+        });
     }
 }
+/// Dial `address` with `crate::dial`, so an hostname with IPv6 and IPv4 addresses connect on the
+/// first family that work
 pub async fn connect(
     address: &str,
     authority_public_key: [u8; 32],
 ) -> Result<(TcpStream, HandshakeRole), ()> {
-    let stream = TcpStream::connect(address).await.map_err(|_| ())?;
+    let stream = dial(address).await.map_err(|_| ())?;
     let initiator = Initiator::from_raw_k(authority_public_key).unwrap();
     let role = HandshakeRole::Initiator(initiator);
     Ok((stream, role))
@@ -0,0 +1,289 @@
+//! Two tier queue of the frames written by the connections.
+//!
+//! When a new block is found the frames queued for the old block (jobs, shares, share responses)
+//! are worth very little, but a busy connection can have a backlog of them in its outgoing
+//! channel. The writer task of every connection move the frames already queued in the channel in
+//! an `OutboundQueue` and write first the control frames (SetNewPrevHash, SetTarget and
+//! SubmitSolution), so that a prev hash update is never stuck behind the backlog. Frames of the
+//! same tier keep their order.
+//!
+//! A SetNewPrevHash can activate a future job (a future template in the template distribution
+//! protocol) sent before it, so the jobs queued before a SetNewPrevHash are moved in the control
+//! tier together with it.
+//!
+//! The frames that open a connection or a channel (SetupConnectionSuccess and the
+//! Open*MiningChannelSuccess) are barriers: a frame queued after a barrier, control frames
+//! included, is never written before it. The peer can not use a SetTarget or a SetNewPrevHash for
+//! a channel that it do not know yet.
+use async_channel::{Receiver, RecvError};
+use codec_sv2::StandardEitherFrame;
+use const_sv2::{
+    MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH, MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB,
+    MESSAGE_TYPE_NEW_MINING_JOB, MESSAGE_TYPE_NEW_TEMPLATE,
+    MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL_SUCCES,
+    MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS, MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS,
+    MESSAGE_TYPE_SET_NEW_PREV_HASH, MESSAGE_TYPE_SET_TARGET, MESSAGE_TYPE_SUBMIT_SOLUTION,
+};
+use std::collections::VecDeque;
+
+/// Message types written ahead of the other frames
+pub const CONTROL_MESSAGE_TYPES: [u8; 4] = [
+    MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH,
+    MESSAGE_TYPE_SET_TARGET,
+    MESSAGE_TYPE_SET_NEW_PREV_HASH,
+    MESSAGE_TYPE_SUBMIT_SOLUTION,
+];
+
+/// Message types that no frame queued after them can overtake
+const BARRIER_MESSAGE_TYPES: [u8; 3] = [
+    MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS,
+    MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS,
+    MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL_SUCCES,
+];
+
+/// Message types that a control frame of type `message_type` can reference
+fn dependencies(message_type: u8) -> &'static [u8] {
+    match message_type {
+        MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH => &[
+            MESSAGE_TYPE_NEW_MINING_JOB,
+            MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB,
+        ],
+        MESSAGE_TYPE_SET_NEW_PREV_HASH => &[MESSAGE_TYPE_NEW_TEMPLATE],
+        _ => &[],
+    }
+}
+
+/// Message type of a frame of the core protocols, None for the handshake and extension frames
+fn message_type<Message>(frame: &StandardEitherFrame<Message>) -> Option<u8>
+where
+    Message: binary_sv2::Serialize + binary_sv2::GetSize,
+{
+    frame
+        .get_header()
+        .filter(|header| header.ext_type_without_channel_msg() == 0)
+        .map(|header| header.msg_type())
+}
+
+#[derive(Debug)]
+pub(crate) struct OutboundQueue<Message> {
+    /// Frames queued up to the last barrier, written before any other frame in this order
+    sealed: VecDeque<StandardEitherFrame<Message>>,
+    control: VecDeque<StandardEitherFrame<Message>>,
+    bulk: VecDeque<(Option<u8>, StandardEitherFrame<Message>)>,
+    /// Frames moved from the channel at most, so that the channel still apply back pressure
+    capacity: usize,
+}
+
+impl<Message: binary_sv2::Serialize + binary_sv2::GetSize> OutboundQueue<Message> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sealed: VecDeque::new(),
+            control: VecDeque::new(),
+            bulk: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.sealed.len() + self.control.len() + self.bulk.len()
+    }
+
+    fn push(&mut self, frame: StandardEitherFrame<Message>) {
+        let message_type = message_type(&frame);
+        match message_type {
+            Some(t) if BARRIER_MESSAGE_TYPES.contains(&t) => {
+                // The frames before the barrier keep the order of the tiers
+                self.sealed.extend(self.control.drain(..));
+                self.sealed
+                    .extend(self.bulk.drain(..).map(|(_, frame)| frame));
+                self.sealed.push_back(frame);
+            }
+            Some(t) if CONTROL_MESSAGE_TYPES.contains(&t) => {
+                let dependencies = dependencies(t);
+                if !dependencies.is_empty() {
+                    let (moved, bulk) = self
+                        .bulk
+                        .drain(..)
+                        .partition(|(t, _)| matches!(t, Some(t) if dependencies.contains(t)));
+                    self.bulk = bulk;
+                    self.control
+                        .extend(moved.into_iter().map(|(_, frame)| frame));
+                }
+                self.control.push_back(frame);
+            }
+            _ => self.bulk.push_back((message_type, frame)),
+        }
+    }
+
+    fn pop(&mut self) -> Option<StandardEitherFrame<Message>> {
+        self.sealed
+            .pop_front()
+            .or_else(|| self.control.pop_front())
+            .or_else(|| self.bulk.pop_front().map(|(_, frame)| frame))
+    }
+
+    /// Next frame to write, the control frames already queued in `receiver` first. Err when the
+    /// receiver is closed and every frame has been returned.
+    ///
+    /// Dropping the returned future does not lose any frame.
+    pub async fn recv(
+        &mut self,
+        receiver: &Receiver<StandardEitherFrame<Message>>,
+    ) -> Result<StandardEitherFrame<Message>, RecvError> {
+        match self.try_recv(receiver) {
+            Some(frame) => Ok(frame),
+            None => receiver.recv().await,
+        }
+    }
+
+    /// Like `recv` but do not wait, None if no frame is queued. The writers use it to write in a
+    /// single write the frames queued together, eg a batch of shares.
+    pub fn try_recv(
+        &mut self,
+        receiver: &Receiver<StandardEitherFrame<Message>>,
+    ) -> Option<StandardEitherFrame<Message>> {
+        while self.len() < self.capacity {
+            match receiver.try_recv() {
+                Ok(frame) => self.push(frame),
+                Err(_) => break,
+            }
+        }
+        self.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec_sv2::{Frame, StandardSv2Frame};
+    use const_sv2::{MESSAGE_TYPE_SUBMIT_SHARES_STANDARD, MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS};
+
+    fn frame(message_type: u8) -> StandardEitherFrame<u32> {
+        StandardSv2Frame::from_message(message_type as u32, message_type, 0, false)
+            .unwrap()
+            .into()
+    }
+
+    /// Push the frames of `message_types` in the queue and return the order they are written
+    fn written(message_types: &[u8]) -> Vec<u8> {
+        let mut queue = OutboundQueue::new(message_types.len());
+        for message_type in message_types {
+            queue.push(frame(*message_type));
+        }
+        std::iter::from_fn(|| queue.pop())
+            .map(|frame| message_type(&frame).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn control_frames_are_written_first() {
+        let written = written(&[
+            MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS,
+            MESSAGE_TYPE_SET_TARGET,
+            MESSAGE_TYPE_SUBMIT_SHARES_STANDARD,
+            MESSAGE_TYPE_SUBMIT_SOLUTION,
+        ]);
+        assert_eq!(
+            written,
+            vec![
+                MESSAGE_TYPE_SET_TARGET,
+                MESSAGE_TYPE_SUBMIT_SOLUTION,
+                MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS,
+                MESSAGE_TYPE_SUBMIT_SHARES_STANDARD,
+            ]
+        );
+    }
+
+    #[test]
+    fn jobs_move_with_the_prev_hash() {
+        let written = written(&[
+            MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS,
+            MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB,
+            MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH,
+        ]);
+        assert_eq!(
+            written,
+            vec![
+                MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB,
+                MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH,
+                MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS,
+            ]
+        );
+    }
+
+    #[test]
+    fn control_frames_do_not_overtake_a_barrier() {
+        for barrier in BARRIER_MESSAGE_TYPES {
+            for control in CONTROL_MESSAGE_TYPES {
+                let written = written(&[barrier, control]);
+                assert_eq!(written, vec![barrier, control]);
+            }
+        }
+    }
+
+    #[test]
+    fn jobs_do_not_overtake_a_barrier() {
+        // The job is queued before the success, the prev hash after: the prev hash can not move
+        // the job after the barrier
+        let written = written(&[
+            MESSAGE_TYPE_NEW_MINING_JOB,
+            MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS,
+            MESSAGE_TYPE_NEW_MINING_JOB,
+            MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS,
+            MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH,
+        ]);
+        assert_eq!(
+            written,
+            vec![
+                MESSAGE_TYPE_NEW_MINING_JOB,
+                MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS,
+                MESSAGE_TYPE_NEW_MINING_JOB,
+                MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH,
+                MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS,
+            ]
+        );
+    }
+
+    #[test]
+    fn frames_before_a_barrier_keep_the_tiers() {
+        let written = written(&[
+            MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS,
+            MESSAGE_TYPE_SET_TARGET,
+            MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS,
+            MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS,
+            MESSAGE_TYPE_SET_TARGET,
+        ]);
+        assert_eq!(
+            written,
+            vec![
+                MESSAGE_TYPE_SET_TARGET,
+                MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS,
+                MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS,
+                MESSAGE_TYPE_SET_TARGET,
+                MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS,
+            ]
+        );
+    }
+
+    #[test]
+    fn recv_write_the_queued_control_frames_first() {
+        async_std::task::block_on(async {
+            let (sender, receiver) = async_channel::bounded(4);
+            sender
+                .send(frame(MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS))
+                .await
+                .unwrap();
+            sender.send(frame(MESSAGE_TYPE_SET_TARGET)).await.unwrap();
+            drop(sender);
+            let mut queue = OutboundQueue::new(4);
+            let first = queue.recv(&receiver).await.unwrap();
+            let second = queue.recv(&receiver).await.unwrap();
+            assert_eq!(message_type(&first), Some(MESSAGE_TYPE_SET_TARGET));
+            assert_eq!(
+                message_type(&second),
+                Some(MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS)
+            );
+            assert!(queue.recv(&receiver).await.is_err());
+        });
+    }
+}
@@ -1,8 +1,11 @@
+use crate::{
+    admission::AdmissionPolicy, connection_handle::spawn_task, happy_eyeballs::dial,
+    outbound_queue::OutboundQueue, ConnectionHandle,
+};
 use async_channel::{bounded, Receiver, Sender};
 use async_std::{
     net::{TcpListener, TcpStream},
     prelude::*,
-    task,
 };
 use binary_sv2::{Deserialize, Serialize};
 use core::convert::TryInto;
@@ -22,7 +25,24 @@ impl PlainConnection {
         Receiver<StandardEitherFrame<Message>>,
         Sender<StandardEitherFrame<Message>>,
     ) {
-        let (mut reader, writer) = (stream.clone(), stream);
+        let (receiver, sender, _) = Self::new_with_handle(stream, capacity).await;
+        (receiver, sender)
+    }
+
+    /// Like `PlainConnection::new` but also return a `ConnectionHandle` that can be used to
+    /// gracefully shutdown the connection
+    pub async fn new_with_handle<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        stream: TcpStream,
+        capacity: usize,
+    ) -> (
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        ConnectionHandle,
+    ) {
+        let (mut reader, writer) = (stream.clone(), stream.clone());
 
         let (sender_incoming, receiver_incoming): (
             Sender<StandardEitherFrame<Message>>,
@@ -33,16 +53,34 @@ impl PlainConnection {
             Receiver<StandardEitherFrame<Message>>,
         ) = bounded(capacity);
 
+        // Closed by the reader when the receiver is dropped, so that the writer exit too
+        let outgoing = receiver_outgoing.clone();
+
         // RECEIVE AND PARSE INCOMING MESSAGES FROM TCP STREAM
-        task::spawn(async move {
+        let reader_task = spawn_task(async move {
             let mut decoder = StandardDecoder::<Message>::new();
 
             loop {
                 let writable = decoder.writable();
                 match reader.read_exact(writable).await {
                     Ok(_) => {
-                        if let Ok(x) = decoder.next_frame() {
-                            sender_incoming.send(x.into()).await.unwrap();
+                        match decoder.next_frame() {
+                            Ok(x) => {
+                                // Receiver has been dropped the connection is not used anymore
+                                if sender_incoming.send(x.into()).await.is_err() {
+                                    outgoing.close();
+                                    let _ = reader.shutdown(async_std::net::Shutdown::Both);
+                                    break;
+                                }
+                            }
+                            // The stream can not be decoded anymore
+                            Err(e @ codec_sv2::Error::FrameTooLarge { .. })
+                            | Err(e @ codec_sv2::Error::InvalidFragment) => {
+                                println!("{:?}", e);
+                                let _ = reader.shutdown(async_std::net::Shutdown::Both);
+                                break;
+                            }
+                            Err(_) => (),
                         }
                     }
                     Err(_) => {
@@ -54,11 +92,12 @@ impl PlainConnection {
         });
 
         // ENCODE AND SEND INCOMING MESSAGES TO TCP STREAM
-        task::spawn(async move {
+        let writer_task = spawn_task(async move {
             let mut encoder = codec_sv2::Encoder::<Message>::new();
+            let mut queue = OutboundQueue::new(capacity);
 
             loop {
-                let received = receiver_outgoing.recv().await;
+                let received = queue.recv(&receiver_outgoing).await;
                 match received {
                     Ok(frame) => {
                         let b = encoder.encode(frame.try_into().unwrap()).unwrap();
@@ -67,6 +106,7 @@ impl PlainConnection {
                             Ok(_) => (),
                             Err(_) => {
                                 let _ = writer.shutdown(async_std::net::Shutdown::Both);
+                                break;
                             }
                         }
                     }
@@ -78,19 +118,33 @@ impl PlainConnection {
             }
         });
 
-        (receiver_incoming, sender_outgoing)
+        let handle = ConnectionHandle::new(stream, reader_task, writer_task);
+        (receiver_incoming, sender_outgoing, handle)
     }
 }
 
 pub async fn plain_listen(address: &str, sender: Sender<TcpStream>) {
+    plain_listen_with_policy(address, AdmissionPolicy::default(), sender).await
+}
+
+/// Like `plain_listen` but the connections of the peers rejected by `policy` are dropped
+pub async fn plain_listen_with_policy(
+    address: &str,
+    policy: AdmissionPolicy,
+    sender: Sender<TcpStream>,
+) {
     let listner = TcpListener::bind(address).await.unwrap();
     let mut incoming = listner.incoming();
     while let Some(stream) = incoming.next().await {
         let stream = stream.unwrap();
+        match stream.peer_addr() {
+            Ok(peer) if policy.admit(peer) => (),
+            _ => continue,
+        }
         let _ = sender.send(stream).await;
     }
 }
 pub async fn plain_connect(address: &str) -> Result<TcpStream, ()> {
-    let stream = TcpStream::connect(address).await.map_err(|_| ())?;
+    let stream = dial(address).await.map_err(|_| ())?;
     Ok(stream)
 }
@@ -0,0 +1,272 @@
+//! Experimental QUIC transport. A QUIC connection survive the change of address of the client
+//! (connection migration) and carry many independent streams, so a role can open one stream per
+//! channel and a lost packet only stall the channel that it belong to. Every bidirectional stream
+//! carry plain sv2 frames (QUIC is already encrypted with TLS 1.3) and is exposed with the same
+//! `Receiver/Sender<StandardEitherFrame>` of the other connections.
+use crate::outbound_queue::OutboundQueue;
+use async_channel::{bounded, Receiver, Sender};
+use async_std::task;
+use binary_sv2::{Deserialize, GetSize, Serialize};
+use codec_sv2::{StandardDecoder, StandardEitherFrame};
+use core::convert::TryInto;
+use futures_rustls::rustls;
+use quinn::{ClientConfig, Endpoint, RecvStream, SendStream, ServerConfig, VarInt};
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+fn to_io_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> Error {
+    Error::new(ErrorKind::Other, e)
+}
+
+/// A QUIC connection, streams are opened with `QuicConnection::open_stream` and accepted with
+/// `QuicConnection::accept_stream`
+#[derive(Debug, Clone)]
+pub struct QuicConnection {
+    connection: quinn::Connection,
+}
+
+impl QuicConnection {
+    /// Open a bidirectional stream
+    pub async fn open_stream<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        &self,
+        capacity: usize,
+    ) -> Result<(
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+    )> {
+        let (send, recv) = self.connection.open_bi().await.map_err(to_io_error)?;
+        Ok(Self::spawn(send, recv, capacity))
+    }
+
+    /// Wait for the next bidirectional stream opened by the counter party. A stream is seen by
+    /// the counter party only when the first frame is sent on it.
+    pub async fn accept_stream<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        &self,
+        capacity: usize,
+    ) -> Result<(
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+    )> {
+        let (send, recv) = self.connection.accept_bi().await.map_err(to_io_error)?;
+        Ok(Self::spawn(send, recv, capacity))
+    }
+
+    /// Address of the counter party, it change if the client migrate
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+
+    /// Close the connection and every stream
+    pub fn close(&self) {
+        self.connection.close(VarInt::from_u32(0), b"");
+    }
+
+    fn spawn<'a, Message: Serialize + Deserialize<'a> + GetSize + Send + 'static>(
+        mut send: SendStream,
+        mut recv: RecvStream,
+        capacity: usize,
+    ) -> (
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+    ) {
+        let (sender_incoming, receiver_incoming): (
+            Sender<StandardEitherFrame<Message>>,
+            Receiver<StandardEitherFrame<Message>>,
+        ) = bounded(capacity);
+        let (sender_outgoing, receiver_outgoing): (
+            Sender<StandardEitherFrame<Message>>,
+            Receiver<StandardEitherFrame<Message>>,
+        ) = bounded(capacity);
+
+        // RECEIVE AND PARSE INCOMING MESSAGES FROM QUIC STREAM
+        task::spawn(async move {
+            let mut decoder = StandardDecoder::<Message>::new();
+
+            loop {
+                let writable = decoder.writable();
+                match recv.read_exact(writable).await {
+                    Ok(_) => {
+                        match decoder.next_frame() {
+                            Ok(x) => {
+                                // Receiver has been dropped the stream is not used anymore
+                                if sender_incoming.send(x.into()).await.is_err() {
+                                    let _ = recv.stop(VarInt::from_u32(0));
+                                    break;
+                                }
+                            }
+                            // The stream can not be decoded anymore
+                            Err(e @ codec_sv2::Error::FrameTooLarge { .. })
+                            | Err(e @ codec_sv2::Error::InvalidFragment) => {
+                                println!("{:?}", e);
+                                let _ = recv.stop(VarInt::from_u32(0));
+                                break;
+                            }
+                            Err(_) => (),
+                        }
+                    }
+                    Err(_) => {
+                        sender_incoming.close();
+                        break;
+                    }
+                }
+            }
+        });
+
+        // ENCODE AND SEND INCOMING MESSAGES TO QUIC STREAM
+        task::spawn(async move {
+            let mut encoder = codec_sv2::Encoder::<Message>::new();
+            let mut queue = OutboundQueue::new(capacity);
+
+            loop {
+                let received = queue.recv(&receiver_outgoing).await;
+                match received {
+                    Ok(frame) => {
+                        let b = encoder.encode(frame.try_into().unwrap()).unwrap();
+
+                        if send.write_all(b).await.is_err() {
+                            receiver_outgoing.close();
+                            break;
+                        }
+                    }
+                    Err(_) => {
+                        let _ = send.finish().await;
+                        break;
+                    }
+                };
+            }
+        });
+
+        (receiver_incoming, sender_outgoing)
+    }
+}
+
+/// Connect to `address`, the certificate of the server must be valid for `server_name` according
+/// to `config`, see `tls_client_config`
+pub async fn quic_connect(
+    address: SocketAddr,
+    server_name: &str,
+    config: Arc<rustls::ClientConfig>,
+) -> Result<QuicConnection> {
+    let bind_address = match address {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let mut endpoint = Endpoint::client(bind_address)?;
+    endpoint.set_default_client_config(ClientConfig::new(config));
+    let connection = endpoint
+        .connect(address, server_name)
+        .map_err(to_io_error)?
+        .await
+        .map_err(to_io_error)?;
+    Ok(QuicConnection { connection })
+}
+
+/// Accept QUIC connections on `address` and send them to `sender`
+pub async fn quic_listen(
+    address: SocketAddr,
+    config: Arc<rustls::ServerConfig>,
+    sender: Sender<QuicConnection>,
+) -> Result<()> {
+    let endpoint = Endpoint::server(ServerConfig::with_crypto(config), address)?;
+    while let Some(connecting) = endpoint.accept().await {
+        // A failed handshake only affect that connection
+        if let Ok(connection) = connecting.await {
+            if sender.send(QuicConnection { connection }).await.is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{future::timeout, net::UdpSocket};
+    use codec_sv2::{Frame, StandardSv2Frame};
+    use rustls::{Certificate, PrivateKey, RootCertStore};
+    use std::time::Duration;
+
+    fn frame(payload: u32) -> StandardEitherFrame<u32> {
+        StandardSv2Frame::from_message(payload, 1, 0, false)
+            .unwrap()
+            .into()
+    }
+
+    async fn recv_payload(receiver: &Receiver<StandardEitherFrame<u32>>) -> Vec<u8> {
+        let received = timeout(Duration::from_secs(5), receiver.recv()).await;
+        let mut frame: StandardSv2Frame<u32> = received.unwrap().unwrap().try_into().unwrap();
+        frame.payload().to_vec()
+    }
+
+    /// Server and client config for a self signed certificate of `localhost`
+    fn configs() -> (Arc<rustls::ServerConfig>, Arc<rustls::ClientConfig>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = Certificate(cert.serialize_der().unwrap());
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert_der.clone()],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(&cert_der).unwrap();
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (Arc::new(server_config), Arc::new(client_config))
+    }
+
+    #[test]
+    fn frames_go_both_ways_and_closing_a_side_end_the_other() {
+        task::block_on(async {
+            let (server_config, client_config) = configs();
+            // A free udp port for the listener
+            let address = UdpSocket::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let (sender, connections) = bounded(1);
+            task::spawn(quic_listen(address, server_config, sender));
+
+            let client = quic_connect(address, "localhost", client_config)
+                .await
+                .unwrap();
+            let server = timeout(Duration::from_secs(5), connections.recv())
+                .await
+                .unwrap()
+                .unwrap();
+
+            // The server see the stream with the first frame
+            let (client_receiver, client_sender) = client.open_stream::<u32>(8).await.unwrap();
+            client_sender.send(frame(7)).await.unwrap();
+            let (server_receiver, server_sender) = server.accept_stream::<u32>(8).await.unwrap();
+            assert_eq!(recv_payload(&server_receiver).await, 7_u32.to_le_bytes());
+            server_sender.send(frame(8)).await.unwrap();
+            assert_eq!(recv_payload(&client_receiver).await, 8_u32.to_le_bytes());
+
+            // Dropping the sender finish the stream
+            drop(client_sender);
+            let closed = timeout(Duration::from_secs(5), server_receiver.recv()).await;
+            assert!(closed.unwrap().is_err());
+
+            // Closing the connection end every stream
+            server.close();
+            let closed = timeout(Duration::from_secs(5), client_receiver.recv()).await;
+            assert!(closed.unwrap().is_err());
+        });
+    }
+}
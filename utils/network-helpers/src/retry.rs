@@ -0,0 +1,261 @@
+//! Noise connections to an upstream that retry the failed dials and handshakes.
+//!
+//! The pool endpoints are often unreachable for a few seconds (restarts, load balancer changes),
+//! `connect_with_retry` dial the upstream and do the noise handshake, if one of them fail it wait
+//! and try again. The waits grow exponentially from `RetryPolicy::initial_backoff` up to
+//! `RetryPolicy::max_backoff` and are randomized by `RetryPolicy::jitter`, so that the downstreams
+//! of a restarted upstream do not reconnect all at the same time. Every failed attempt is passed to
+//! a callback, the caller can log it or count it.
+use crate::{happy_eyeballs::dial, noise_connection_async_std::Connection, ConnectionHandle};
+use async_channel::{Receiver, Sender};
+use async_std::{future::timeout, task};
+use binary_sv2::{Deserialize, GetSize, Serialize};
+use codec_sv2::{HandshakeRole, Initiator, StandardEitherFrame};
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The tcp connection can not be opened
+    Dial(std::io::Error),
+    /// The connection has been closed during the handshake
+    ConnectionClosed,
+    /// The handshake failed at the described step
+    Handshake(&'static str),
+    /// The handshake took longer than `RetryPolicy::handshake_timeout`
+    HandshakeTimeout,
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::Dial(e) => write!(f, "Dial failed: {}", e),
+            ConnectError::ConnectionClosed => write!(f, "Connection closed during the handshake"),
+            ConnectError::Handshake(step) => write!(f, "Handshake failed: {}", step),
+            ConnectError::HandshakeTimeout => write!(f, "Handshake timed out"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// Backoff of `connect_with_retry`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts made at most, None retry forever
+    pub max_attempts: Option<u32>,
+    /// Wait after the first failed attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// The wait is multiplied by this after every failed attempt
+    pub multiplier: f64,
+    /// Part of the wait that is randomized, between 0 and 1: with 0.5 the actual wait is between
+    /// half and the whole computed wait
+    pub jitter: f64,
+    /// An handshake that take longer fail the attempt
+    pub handshake_timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: Some(10),
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait after the failed attempt `attempt` (1 for the first attempt), `random` is between 0
+    /// and 1
+    pub fn backoff(&self, attempt: u32, random: f64) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let backoff = (self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_backoff.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0) * random.clamp(0.0, 1.0);
+        Duration::from_secs_f64(backoff * (1.0 - jitter))
+    }
+}
+
+/// A failed attempt of `connect_with_retry`
+#[derive(Debug)]
+pub struct ConnectAttempt<'a> {
+    /// 1 for the first attempt
+    pub attempt: u32,
+    pub error: &'a ConnectError,
+    /// Wait before the next attempt, None if this was the last one
+    pub retry_in: Option<Duration>,
+}
+
+/// Random number between 0 and 1, the keys of `RandomState` are random
+fn random() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+async fn try_connect<'a, Message: Serialize + Deserialize<'a> + GetSize + Send + 'static>(
+    address: &str,
+    authority_public_key: [u8; 32],
+    capacity: usize,
+    handshake_timeout: Duration,
+) -> Result<
+    (
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        ConnectionHandle,
+    ),
+    ConnectError,
+> {
+    let stream = dial(address).await.map_err(ConnectError::Dial)?;
+    let initiator = Initiator::from_raw_k(authority_public_key)
+        .map_err(|_| ConnectError::Handshake("invalid authority public key"))?;
+    let role = HandshakeRole::Initiator(initiator);
+    let handshake = Connection::try_new_with_handle(stream.clone(), role, capacity);
+    match timeout(handshake_timeout, handshake).await {
+        Ok(connection) => connection,
+        Err(_) => {
+            let _ = stream.shutdown(async_std::net::Shutdown::Both);
+            Err(ConnectError::HandshakeTimeout)
+        }
+    }
+}
+
+/// Open a noise connection with `address` as initiator like `connect` and `Connection::new`, the
+/// failed dials and handshakes are retried following `policy`. `on_error` is called after every
+/// failed attempt. Return the error of the last attempt if every attempt failed, an invalid
+/// `authority_public_key` is not retried.
+#[allow(clippy::type_complexity)]
+pub async fn connect_with_retry<
+    'a,
+    Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+>(
+    address: &str,
+    authority_public_key: [u8; 32],
+    capacity: usize,
+    policy: RetryPolicy,
+    mut on_error: impl FnMut(&ConnectAttempt),
+) -> Result<
+    (
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        ConnectionHandle,
+    ),
+    ConnectError,
+> {
+    // A wrong key fail every attempt
+    Initiator::from_raw_k(authority_public_key)
+        .map_err(|_| ConnectError::Handshake("invalid authority public key"))?;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let error = match try_connect(
+            address,
+            authority_public_key,
+            capacity,
+            policy.handshake_timeout,
+        )
+        .await
+        {
+            Ok(connection) => return Ok(connection),
+            Err(error) => error,
+        };
+        let retry_in = match policy.max_attempts {
+            Some(max_attempts) if attempt >= max_attempts => None,
+            _ => Some(policy.backoff(attempt, random())),
+        };
+        on_error(&ConnectAttempt {
+            attempt,
+            error: &error,
+            retry_in,
+        });
+        match retry_in {
+            Some(retry_in) => task::sleep(retry_in).await,
+            None => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use codec_sv2::noise_sv2::random_keypair;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: Some(2),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.0,
+            handshake_timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn backoff_grow_up_to_max_backoff() {
+        let policy = policy();
+        let backoffs: Vec<u64> = (1..=7)
+            .map(|attempt| policy.backoff(attempt, 0.5).as_millis() as u64)
+            .collect();
+        assert_eq!(backoffs, vec![100, 200, 400, 800, 1000, 1000, 1000]);
+        // No overflow after many attempts
+        assert_eq!(policy.backoff(u32::MAX, 0.5), policy.max_backoff);
+    }
+
+    #[test]
+    fn jitter_stay_in_bounds() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..policy()
+        };
+        assert_eq!(policy.backoff(3, 0.0), Duration::from_millis(400));
+        assert_eq!(policy.backoff(3, 0.5), Duration::from_millis(300));
+        assert_eq!(policy.backoff(3, 1.0), Duration::from_millis(200));
+        // The random number and the jitter are clamped between 0 and 1
+        assert_eq!(policy.backoff(3, 2.0), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3, -1.0), Duration::from_millis(400));
+        let policy = RetryPolicy {
+            jitter: 2.0,
+            ..policy
+        };
+        assert_eq!(policy.backoff(3, 1.0), Duration::from_secs(0));
+        for _ in 0..100 {
+            let random = random();
+            assert!((0.0..1.0).contains(&random));
+        }
+    }
+
+    #[test]
+    fn on_error_see_every_failed_attempt() {
+        task::block_on(async {
+            // Nothing listen on the address once the listener is dropped
+            let address = TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .to_string();
+            let mut attempts = Vec::new();
+            let result =
+                connect_with_retry::<u32>(&address, random_keypair().0, 10, policy(), |a| {
+                    let dial_failed = matches!(a.error, ConnectError::Dial(_));
+                    attempts.push((a.attempt, a.retry_in, dial_failed))
+                })
+                .await;
+            assert!(matches!(result, Err(ConnectError::Dial(_))));
+            assert_eq!(
+                attempts,
+                vec![(1, Some(Duration::from_millis(100)), true), (2, None, true)]
+            );
+        });
+    }
+}
@@ -0,0 +1,268 @@
+//! Sv2 frames inside a TLS connection, for networks that only allow TLS egress. The frames are
+//! not noise encrypted, TLS provide the encryption and the authentication of the server. The
+//! returned channels are the same as `PlainConnection` and `Connection` so the roles do not care
+//! about the transport.
+use crate::{connection_handle::spawn_task, outbound_queue::OutboundQueue, ConnectionHandle};
+use async_channel::{bounded, Receiver, Sender};
+use async_std::net::TcpStream;
+use binary_sv2::{Deserialize, GetSize, Serialize};
+use codec_sv2::{StandardDecoder, StandardEitherFrame};
+use core::convert::{TryFrom, TryInto};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures_rustls::{
+    rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+use std::{
+    io::{Error, ErrorKind, Result},
+    sync::Arc,
+};
+
+pub use futures_rustls::rustls;
+
+/// Client TLS config that trust the certificates in `ca_certs_pem` (PEM encoded), or the webpki
+/// roots if None
+pub fn tls_client_config(ca_certs_pem: Option<&[u8]>) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca_certs_pem {
+        Some(mut pem) => {
+            for cert in rustls_pemfile::certs(&mut pem)? {
+                roots
+                    .add(&Certificate(cert))
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            }
+        }
+        None => roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })),
+    }
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+#[derive(Debug)]
+pub struct TlsConnection {}
+
+impl TlsConnection {
+    /// Do the TLS handshake as client with `server_name` on `stream` and start relaying frames
+    pub async fn connect<'a, Message: Serialize + Deserialize<'a> + GetSize + Send + 'static>(
+        stream: TcpStream,
+        server_name: &str,
+        config: Arc<ClientConfig>,
+        capacity: usize,
+    ) -> Result<(
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        ConnectionHandle,
+    )> {
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let tls_stream = TlsConnector::from(config)
+            .connect(server_name, stream.clone())
+            .await?;
+        Ok(Self::spawn(tls_stream, stream, capacity))
+    }
+
+    /// Do the TLS handshake as server on an accepted `stream` and start relaying frames
+    pub async fn accept<'a, Message: Serialize + Deserialize<'a> + GetSize + Send + 'static>(
+        stream: TcpStream,
+        config: Arc<rustls::ServerConfig>,
+        capacity: usize,
+    ) -> Result<(
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        ConnectionHandle,
+    )> {
+        let tls_stream = futures_rustls::TlsAcceptor::from(config)
+            .accept(stream.clone())
+            .await?;
+        Ok(Self::spawn(tls_stream, stream, capacity))
+    }
+
+    /// Start the reader and writer tasks, `stream` is the tcp stream under `tls_stream` and is
+    /// used to shutdown the connection
+    fn spawn<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    >(
+        tls_stream: S,
+        stream: TcpStream,
+        capacity: usize,
+    ) -> (
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        ConnectionHandle,
+    ) {
+        let (mut reader, mut writer) = tls_stream.split();
+
+        let (sender_incoming, receiver_incoming): (
+            Sender<StandardEitherFrame<Message>>,
+            Receiver<StandardEitherFrame<Message>>,
+        ) = bounded(capacity);
+        let (sender_outgoing, receiver_outgoing): (
+            Sender<StandardEitherFrame<Message>>,
+            Receiver<StandardEitherFrame<Message>>,
+        ) = bounded(capacity);
+
+        let reader_stream = stream.clone();
+        let writer_stream = stream.clone();
+
+        // Closed by the reader when the receiver is dropped, so that the writer exit too
+        let outgoing = receiver_outgoing.clone();
+
+        // RECEIVE AND PARSE INCOMING MESSAGES FROM TLS STREAM
+        let reader_task = spawn_task(async move {
+            let mut decoder = StandardDecoder::<Message>::new();
+
+            loop {
+                let writable = decoder.writable();
+                match reader.read_exact(writable).await {
+                    Ok(_) => {
+                        match decoder.next_frame() {
+                            Ok(x) => {
+                                // Receiver has been dropped the connection is not used anymore
+                                if sender_incoming.send(x.into()).await.is_err() {
+                                    outgoing.close();
+                                    let _ = reader_stream.shutdown(async_std::net::Shutdown::Both);
+                                    break;
+                                }
+                            }
+                            // The stream can not be decoded anymore
+                            Err(e @ codec_sv2::Error::FrameTooLarge { .. })
+                            | Err(e @ codec_sv2::Error::InvalidFragment) => {
+                                println!("{:?}", e);
+                                let _ = reader_stream.shutdown(async_std::net::Shutdown::Both);
+                                break;
+                            }
+                            Err(_) => (),
+                        }
+                    }
+                    Err(_) => {
+                        let _ = reader_stream.shutdown(async_std::net::Shutdown::Both);
+                        break;
+                    }
+                }
+            }
+        });
+
+        // ENCODE AND SEND INCOMING MESSAGES TO TLS STREAM
+        let writer_task = spawn_task(async move {
+            let mut encoder = codec_sv2::Encoder::<Message>::new();
+            let mut queue = OutboundQueue::new(capacity);
+
+            loop {
+                let received = queue.recv(&receiver_outgoing).await;
+                match received {
+                    Ok(frame) => {
+                        let mut b = encoder.encode(frame.try_into().unwrap()).unwrap().to_vec();
+                        // The frames already queued are written together with this one
+                        for _ in 1..capacity {
+                            match queue.try_recv(&receiver_outgoing) {
+                                Some(frame) => b.extend_from_slice(
+                                    encoder.encode(frame.try_into().unwrap()).unwrap(),
+                                ),
+                                None => break,
+                            }
+                        }
+
+                        // The TLS stream buffer the records, the frames are sent only on flush
+                        let sent = match writer.write_all(&b).await {
+                            Ok(_) => writer.flush().await,
+                            Err(e) => Err(e),
+                        };
+                        if sent.is_err() {
+                            let _ = writer_stream.shutdown(async_std::net::Shutdown::Both);
+                            break;
+                        }
+                    }
+                    Err(_) => {
+                        let _ = writer.close().await;
+                        let _ = writer_stream.shutdown(async_std::net::Shutdown::Both);
+                        break;
+                    }
+                };
+            }
+        });
+
+        let handle = ConnectionHandle::new(stream, reader_task, writer_task);
+        (receiver_incoming, sender_outgoing, handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{future::timeout, net::TcpListener, task};
+    use codec_sv2::{Frame, StandardSv2Frame};
+    use futures_rustls::rustls::{PrivateKey, ServerConfig};
+    use std::time::Duration;
+
+    fn frame(payload: u32) -> StandardEitherFrame<u32> {
+        StandardSv2Frame::from_message(payload, 1, 0, false)
+            .unwrap()
+            .into()
+    }
+
+    async fn recv_payload(receiver: &Receiver<StandardEitherFrame<u32>>) -> Vec<u8> {
+        let received = timeout(Duration::from_secs(5), receiver.recv()).await;
+        let mut frame: StandardSv2Frame<u32> = received.unwrap().unwrap().try_into().unwrap();
+        frame.payload().to_vec()
+    }
+
+    /// Server and client config for a self signed certificate of `localhost`
+    fn configs() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(cert.serialize_der().unwrap())],
+                PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let client_config = tls_client_config(Some(cert.serialize_pem().unwrap().as_bytes()));
+        (Arc::new(server_config), client_config.unwrap())
+    }
+
+    #[test]
+    fn frames_go_both_ways_and_closing_a_side_end_the_other() {
+        task::block_on(async {
+            let (server_config, client_config) = configs();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = task::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                TlsConnection::accept::<u32>(stream, server_config, 8)
+                    .await
+                    .unwrap()
+            });
+            let stream = TcpStream::connect(address).await.unwrap();
+            let (client_receiver, client_sender, client_handle) =
+                TlsConnection::connect::<u32>(stream, "localhost", client_config, 8)
+                    .await
+                    .unwrap();
+            let (server_receiver, server_sender, server_handle) = server.await;
+
+            client_sender.send(frame(7)).await.unwrap();
+            assert_eq!(recv_payload(&server_receiver).await, 7_u32.to_le_bytes());
+            server_sender.send(frame(8)).await.unwrap();
+            assert_eq!(recv_payload(&client_receiver).await, 8_u32.to_le_bytes());
+
+            client_handle.shutdown(&client_sender).await;
+            let closed = timeout(Duration::from_secs(5), server_receiver.recv()).await;
+            assert!(closed.unwrap().is_err());
+            server_sender.close();
+            timeout(Duration::from_secs(5), server_handle.join())
+                .await
+                .unwrap();
+        });
+    }
+}
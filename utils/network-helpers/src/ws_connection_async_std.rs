@@ -0,0 +1,264 @@
+//! Noise encrypted sv2 frames carried by WebSocket binary messages, for browser based dashboards
+//! and miners behind HTTP only proxies. The noise handshake and the frames are the same of
+//! `Connection`, a binary message can contain any number of bytes of the noise stream.
+use crate::{
+    connection_handle::spawn_task, noise_connection_async_std::Connection,
+    outbound_queue::OutboundQueue, ConnectionHandle,
+};
+use async_channel::{bounded, Receiver, Sender};
+use async_std::{
+    net::TcpStream,
+    sync::{Arc, Mutex},
+};
+use async_tungstenite::{tungstenite::Message as WsMessage, WebSocketStream};
+use binary_sv2::{Deserialize, GetSize, Serialize};
+use codec_sv2::{HandshakeRole, StandardEitherFrame, StandardNoiseDecoder};
+use futures::{SinkExt, StreamExt};
+use std::io::{Error, ErrorKind, Result};
+
+#[derive(Debug)]
+pub struct WsConnection {}
+
+impl WsConnection {
+    /// Do the WebSocket handshake as client requesting `url` on `stream`, then the noise
+    /// handshake with `role`
+    pub async fn connect<'a, Message: Serialize + Deserialize<'a> + GetSize + Send + 'static>(
+        stream: TcpStream,
+        url: &str,
+        role: HandshakeRole,
+        capacity: usize,
+    ) -> Result<(
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        ConnectionHandle,
+    )> {
+        let (ws_stream, _) = async_tungstenite::client_async(url, stream.clone())
+            .await
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        Self::spawn(ws_stream, stream, role, capacity).await
+    }
+
+    /// Do the WebSocket handshake as server on an accepted `stream`, then the noise handshake
+    /// with `role`
+    pub async fn accept<'a, Message: Serialize + Deserialize<'a> + GetSize + Send + 'static>(
+        stream: TcpStream,
+        role: HandshakeRole,
+        capacity: usize,
+    ) -> Result<(
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        ConnectionHandle,
+    )> {
+        let ws_stream = async_tungstenite::accept_async(stream.clone())
+            .await
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        Self::spawn(ws_stream, stream, role, capacity).await
+    }
+
+    /// Start the reader and writer tasks and do the noise handshake, `stream` is the tcp stream
+    /// under `ws_stream` and is used to shutdown the connection
+    async fn spawn<'a, Message: Serialize + Deserialize<'a> + GetSize + Send + 'static>(
+        ws_stream: WebSocketStream<TcpStream>,
+        stream: TcpStream,
+        role: HandshakeRole,
+        capacity: usize,
+    ) -> Result<(
+        Receiver<StandardEitherFrame<Message>>,
+        Sender<StandardEitherFrame<Message>>,
+        ConnectionHandle,
+    )> {
+        let (mut ws_writer, mut ws_reader) = ws_stream.split();
+
+        let (sender_incoming, receiver_incoming): (
+            Sender<StandardEitherFrame<Message>>,
+            Receiver<StandardEitherFrame<Message>>,
+        ) = bounded(capacity);
+        let (sender_outgoing, receiver_outgoing): (
+            Sender<StandardEitherFrame<Message>>,
+            Receiver<StandardEitherFrame<Message>>,
+        ) = bounded(capacity);
+
+        let connection = Arc::new(Mutex::new(Connection {
+            state: codec_sv2::State::new(),
+        }));
+
+        let cloned1 = connection.clone();
+        let cloned2 = connection.clone();
+        let reader_stream = stream.clone();
+        let writer_stream = stream.clone();
+
+        // Closed by the reader when the receiver is dropped, so that the writer exit too
+        let outgoing = receiver_outgoing.clone();
+
+        // RECEIVE AND PARSE INCOMING MESSAGES FROM WEBSOCKET
+        let reader_task = spawn_task(async move {
+            let mut decoder = StandardNoiseDecoder::<Message>::new();
+            // Last received binary message and how many bytes of it the decoder consumed
+            let mut pending: Vec<u8> = Vec::new();
+            let mut consumed = 0;
+
+            loop {
+                if consumed == pending.len() {
+                    match ws_reader.next().await {
+                        Some(Ok(WsMessage::Binary(bytes))) => {
+                            pending = bytes;
+                            consumed = 0;
+                        }
+                        // Ping, pong and text messages do not carry sv2 frames
+                        Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => (),
+                    }
+                    continue;
+                }
+                // A message can end in the middle of a frame or contain more frames
+                consumed += decoder.push_bytes(&pending[consumed..]);
+
+                let mut connection = cloned1.lock().await;
+                match decoder.next_frame(&mut connection.state) {
+                    Ok(x) => {
+                        // Receiver has been dropped the connection is not used anymore
+                        if sender_incoming.send(x).await.is_err() {
+                            outgoing.close();
+                            break;
+                        }
+                    }
+                    // The stream can not be decoded anymore
+                    Err(e @ codec_sv2::Error::FrameTooLarge { .. })
+                    | Err(e @ codec_sv2::Error::InvalidFragment) => {
+                        println!("{:?}", e);
+                        break;
+                    }
+                    Err(_) => (),
+                }
+            }
+            let _ = reader_stream.shutdown(async_std::net::Shutdown::Both);
+        });
+
+        let receiver_outgoing_cloned = receiver_outgoing.clone();
+
+        // ENCODE AND SEND INCOMING MESSAGES TO WEBSOCKET
+        let writer_task = spawn_task(async move {
+            let mut encoder = codec_sv2::NoiseEncoder::<Message>::new();
+            let mut queue = OutboundQueue::new(capacity);
+
+            loop {
+                let received = queue.recv(&receiver_outgoing).await;
+                match received {
+                    Ok(frame) => {
+                        let mut connection = cloned2.lock().await;
+                        let b = encoder.encode(frame, &mut connection.state).unwrap();
+
+                        if ws_writer.send(WsMessage::Binary(b.to_vec())).await.is_err() {
+                            let _ = writer_stream.shutdown(async_std::net::Shutdown::Both);
+                            break;
+                        }
+                    }
+                    Err(_) => {
+                        let _ = ws_writer.close().await;
+                        let _ = writer_stream.shutdown(async_std::net::Shutdown::Both);
+                        break;
+                    }
+                };
+            }
+        });
+
+        // DO THE NOISE HANDSHAKE
+        let transport_mode = match role {
+            HandshakeRole::Initiator(_) => {
+                Connection::initialize_as_downstream(
+                    role,
+                    sender_outgoing.clone(),
+                    receiver_incoming.clone(),
+                )
+                .await
+            }
+            HandshakeRole::Responder(_) => {
+                Connection::initialize_as_upstream(
+                    role,
+                    sender_outgoing.clone(),
+                    receiver_outgoing_cloned,
+                    receiver_incoming.clone(),
+                )
+                .await
+            }
+        };
+        let transport_mode = match transport_mode {
+            Ok(transport_mode) => transport_mode,
+            Err(e) => {
+                let _ = stream.shutdown(async_std::net::Shutdown::Both);
+                return Err(Error::new(ErrorKind::Other, e));
+            }
+        };
+
+        Connection::set_state(connection, transport_mode).await;
+
+        let handle = ConnectionHandle::new(stream, reader_task, writer_task);
+        Ok((receiver_incoming, sender_outgoing, handle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{future::timeout, net::TcpListener, task};
+    use codec_sv2::{noise_sv2::random_keypair, Frame, Initiator, Responder, StandardSv2Frame};
+    use core::convert::TryInto;
+    use std::time::Duration;
+
+    fn frame(payload: u32) -> StandardEitherFrame<u32> {
+        StandardSv2Frame::from_message(payload, 1, 0, false)
+            .unwrap()
+            .into()
+    }
+
+    async fn recv_payload(receiver: &Receiver<StandardEitherFrame<u32>>) -> Vec<u8> {
+        let received = timeout(Duration::from_secs(5), receiver.recv()).await;
+        let mut frame: StandardSv2Frame<u32> = received.unwrap().unwrap().try_into().unwrap();
+        frame.payload().to_vec()
+    }
+
+    #[test]
+    fn frames_go_both_ways_and_closing_a_side_end_the_other() {
+        task::block_on(async {
+            let (public_key, private_key) = random_keypair();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = task::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let responder = Responder::from_authority_kp(
+                    &public_key,
+                    &private_key,
+                    Duration::from_secs(3600),
+                )
+                .unwrap();
+                WsConnection::accept::<u32>(stream, HandshakeRole::Responder(responder), 8)
+                    .await
+                    .unwrap()
+            });
+            let stream = TcpStream::connect(address).await.unwrap();
+            let initiator = Initiator::from_raw_k(public_key).unwrap();
+            let (client_receiver, client_sender, client_handle) = WsConnection::connect::<u32>(
+                stream,
+                &format!("ws://{}/", address),
+                HandshakeRole::Initiator(initiator),
+                8,
+            )
+            .await
+            .unwrap();
+            let (server_receiver, server_sender, server_handle) = server.await;
+
+            client_sender.send(frame(7)).await.unwrap();
+            assert_eq!(recv_payload(&server_receiver).await, 7_u32.to_le_bytes());
+            server_sender.send(frame(8)).await.unwrap();
+            assert_eq!(recv_payload(&client_receiver).await, 8_u32.to_le_bytes());
+
+            client_handle.shutdown(&client_sender).await;
+            let closed = timeout(Duration::from_secs(5), server_receiver.recv()).await;
+            assert!(closed.unwrap().is_err());
+            server_sender.close();
+            timeout(Duration::from_secs(5), server_handle.join())
+                .await
+                .unwrap();
+        });
+    }
+}
@@ -0,0 +1,319 @@
+//! Certificate authority daemon.
+//!
+//! The pools prove their identity to the downstreams with a certificate of their noise static key
+//! signed by the authority key. Without the daemon every pool host need the authority secret key
+//! to sign its own certificate. With the daemon only one host hold the authority secret key, the
+//! pools request a certificate for their static key when they start and again before it expire,
+//! so the certificates can be short lived.
+//!
+//! A pool connect with a noise handshake (`Noise_IX`) in which it send its static key, the daemon
+//! accept only the keys in its `AllowedInitiators` and certify the key used in the handshake, so a
+//! pool can only get a certificate for a key that it own. The daemon is itself certified by the
+//! authority, the pools authenticate it with the authority public key that the downstreams
+//! already trust.
+//!
+//! After the handshake the pool send one request and the daemon reply with one response, both
+//! encrypted and prefixed by their length (u16 little endian):
+//! * request: validity of the certificate in seconds (u32 little endian)
+//! * response: `RESPONSE_OK` followed by the serialized `SignatureNoiseMessage`, or
+//!   `RESPONSE_REJECTED`
+pub mod renewal;
+
+use async_std::{
+    net::{TcpListener, TcpStream},
+    task,
+};
+use core::convert::TryInto;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use noise_sv2::{
+    handshake, AllowedInitiators, Authority, Initiator, Responder, StaticKeypair, TransportMode,
+};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+pub use renewal::RenewingResponder;
+
+pub const RESPONSE_OK: u8 = 0;
+pub const RESPONSE_REJECTED: u8 = 1;
+/// Default time that a pool has to complete the handshake and to send its request
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum Error {
+    /// A pool that do not complete the handshake or do not send its request in time fail with
+    /// `std::io::ErrorKind::TimedOut`
+    Io(std::io::Error),
+    /// The handshake failed (eg the key of the pool is not allowed) or a message can not be
+    /// decrypted
+    Noise,
+    /// Malformed request or response
+    InvalidMessage,
+    /// The daemon can not issue the certificate
+    Rejected,
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+pub struct CertificateAuthority {
+    authority: Authority,
+    max_validity: Duration,
+    allowed_pools: AllowedInitiators,
+    request_timeout: Duration,
+}
+
+impl CertificateAuthority {
+    /// Daemon that issue certificates signed by `authority` to `allowed_pools`, the certificates
+    /// are valid at most `max_validity`
+    pub fn new(
+        authority: Authority,
+        max_validity: Duration,
+        allowed_pools: AllowedInitiators,
+    ) -> Self {
+        Self {
+            authority,
+            max_validity,
+            allowed_pools,
+            request_timeout: REQUEST_TIMEOUT,
+        }
+    }
+
+    /// The connections of the pools that do not complete the handshake and send their request
+    /// within `request_timeout` are closed, by default `REQUEST_TIMEOUT`
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Serialized certificate of `static_key` valid for `validity`, at most `max_validity`
+    pub fn issue(&self, static_key: &[u8], validity: Duration) -> Result<Vec<u8>, Error> {
+        let validity = validity.min(self.max_validity);
+        let certificate = self
+            .authority
+            .new_cert_from_raw(static_key, validity)
+            .map_err(|_| Error::Rejected)?;
+        certificate
+            .serialize_to_bytes_mut()
+            .map(|certificate| certificate.to_vec())
+            .map_err(|_| Error::Rejected)
+    }
+
+    /// Handshake with a pool, answer its request and close the connection
+    pub async fn serve(&self, mut stream: TcpStream) -> Result<(), Error> {
+        let responder = Responder::from_authority_with_allowed_initiators(
+            &self.authority,
+            self.max_validity,
+            self.allowed_pools.clone(),
+        )
+        .map_err(|_| Error::Noise)?;
+        // A pool that do not send the handshake messages or the request would hold the connection
+        // forever
+        let handshake = handshake::run_responder(&mut stream, responder);
+        let mut transport = with_timeout(self.request_timeout, handshake)
+            .await?
+            .map_err(|_| Error::Noise)?;
+        // The Responder accept only the Initiators that send an allowed static key
+        let static_key = transport.remote_static_key().ok_or(Error::Noise)?.to_vec();
+        let request = read_frame(&mut stream, &mut transport);
+        let request = with_timeout(self.request_timeout, request).await??;
+        let validity: [u8; 4] = request
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::InvalidMessage)?;
+        let validity = Duration::from_secs(u32::from_le_bytes(validity) as u64);
+        let issued = self.issue(&static_key, validity);
+        let response = match &issued {
+            Ok(certificate) => [&[RESPONSE_OK][..], certificate].concat(),
+            Err(_) => vec![RESPONSE_REJECTED],
+        };
+        write_frame(&mut stream, &mut transport, &response).await?;
+        issued.map(|_| ())
+    }
+}
+
+/// Serve the pools that connect to `listener`, every connection is handled in its own task
+pub async fn serve_pools(
+    ca: Arc<CertificateAuthority>,
+    listener: TcpListener,
+) -> Result<(), Error> {
+    loop {
+        let (stream, address) = listener.accept().await?;
+        let ca = ca.clone();
+        task::spawn(async move {
+            match ca.serve(stream).await {
+                Ok(()) => println!("Certificate issued to {}", address),
+                Err(e) => println!("Request of {} failed: {:?}", address, e),
+            }
+        });
+    }
+}
+
+/// Request a certificate for `static_keypair` to the daemon at `address`, the daemon must be
+/// certified by `authority_public_key`. Return the serialized `SignatureNoiseMessage` to build the
+/// Responders of the pool with `Responder::new`
+pub async fn request_certificate(
+    address: SocketAddr,
+    authority_public_key: [u8; 32],
+    static_keypair: &StaticKeypair,
+    validity: Duration,
+) -> Result<Vec<u8>, Error> {
+    let authority_public_key = ed25519_dalek::PublicKey::from_bytes(&authority_public_key[..])
+        .map_err(|_| Error::InvalidMessage)?;
+    let initiator = Initiator::with_static_keypair(authority_public_key, static_keypair)
+        .map_err(|_| Error::Noise)?;
+    let mut stream = TcpStream::connect(address).await?;
+    let mut transport = handshake::run_initiator(&mut stream, initiator)
+        .await
+        .map_err(|_| Error::Noise)?;
+    let validity = validity.as_secs().min(u32::MAX as u64) as u32;
+    write_frame(&mut stream, &mut transport, &validity.to_le_bytes()).await?;
+    let response = read_frame(&mut stream, &mut transport).await?;
+    match response.split_first() {
+        Some((&RESPONSE_OK, certificate)) if !certificate.is_empty() => Ok(certificate.to_vec()),
+        Some((&RESPONSE_REJECTED, _)) => Err(Error::Rejected),
+        _ => Err(Error::InvalidMessage),
+    }
+}
+
+async fn with_timeout<T>(
+    duration: Duration,
+    future: impl std::future::Future<Output = T>,
+) -> Result<T, Error> {
+    async_std::future::timeout(duration, future)
+        .await
+        .map_err(|_| Error::Io(std::io::ErrorKind::TimedOut.into()))
+}
+
+async fn write_frame<IO: AsyncWrite + Unpin>(
+    io: &mut IO,
+    transport: &mut TransportMode,
+    message: &[u8],
+) -> Result<(), Error> {
+    let mut encrypted = vec![0_u8; TransportMode::size_hint_encrypt(message.len())];
+    transport
+        .write(message, &mut encrypted)
+        .map_err(|_| Error::Noise)?;
+    let len: u16 = encrypted
+        .len()
+        .try_into()
+        .map_err(|_| Error::InvalidMessage)?;
+    io.write_all(&len.to_le_bytes()).await?;
+    io.write_all(&encrypted).await?;
+    io.flush().await?;
+    Ok(())
+}
+
+async fn read_frame<IO: AsyncRead + Unpin>(
+    io: &mut IO,
+    transport: &mut TransportMode,
+) -> Result<Vec<u8>, Error> {
+    let mut header = [0_u8; 2];
+    io.read_exact(&mut header).await?;
+    let mut encrypted = vec![0_u8; u16::from_le_bytes(header) as usize];
+    io.read_exact(&mut encrypted).await?;
+    let len = TransportMode::size_hint_decrypt(encrypted.len()).ok_or(Error::InvalidMessage)?;
+    let mut message = vec![0_u8; len];
+    transport
+        .read(&encrypted, &mut message)
+        .map_err(|_| Error::Noise)?;
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noise_sv2::{handshake::Step, CertificateClock, FixedTime};
+    use std::time::SystemTime;
+
+    /// Run the handshake of a downstream of the pool that check the certificate at `now`
+    fn downstream_handshake(
+        authority_public_key: [u8; 32],
+        pool: &StaticKeypair,
+        certificate: &[u8],
+        now: SystemTime,
+    ) -> bool {
+        let clock = CertificateClock::new(Arc::new(FixedTime(now)), Duration::from_secs(0));
+        let mut initiator = Initiator::from_raw_k(authority_public_key)
+            .unwrap()
+            .with_clock(clock);
+        let mut responder = Responder::new(pool, certificate.to_vec().into()).unwrap();
+        let first_message = initiator.step(None).unwrap().inner();
+        let second_message = responder.step(Some(first_message)).unwrap().inner();
+        initiator.step(Some(second_message)).is_ok()
+    }
+
+    #[async_std::test]
+    async fn issue_certificates_only_to_the_allowed_pools() {
+        let (public_key, private_key) = noise_sv2::random_keypair();
+        let authority = Authority::from_raw_k(&public_key, &private_key).unwrap();
+        let pool = noise_sv2::generate_keypair().unwrap();
+        let unknown = noise_sv2::generate_keypair().unwrap();
+        let allowed_pools = AllowedInitiators::new(vec![pool.public[..].try_into().unwrap()]);
+        let ca = CertificateAuthority::new(authority, Duration::from_secs(3600), allowed_pools);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        task::spawn(serve_pools(Arc::new(ca), listener));
+
+        let certificate =
+            request_certificate(address, public_key, &pool, Duration::from_secs(86400))
+                .await
+                .unwrap();
+        // The downstreams of the pool accept the certificate, the validity is capped to the max
+        // validity
+        let now = SystemTime::now();
+        assert!(downstream_handshake(public_key, &pool, &certificate, now));
+        let later = now + Duration::from_secs(7200);
+        assert!(!downstream_handshake(
+            public_key,
+            &pool,
+            &certificate,
+            later
+        ));
+
+        let unknown_request =
+            request_certificate(address, public_key, &unknown, Duration::from_secs(60)).await;
+        assert!(matches!(unknown_request, Err(Error::Noise)));
+    }
+
+    #[async_std::test]
+    async fn close_the_connections_of_the_silent_pools() {
+        let (public_key, private_key) = noise_sv2::random_keypair();
+        let authority = Authority::from_raw_k(&public_key, &private_key).unwrap();
+        let pool = noise_sv2::generate_keypair().unwrap();
+        let allowed_pools = AllowedInitiators::new(vec![pool.public[..].try_into().unwrap()]);
+        let ca = CertificateAuthority::new(authority, Duration::from_secs(3600), allowed_pools)
+            .with_request_timeout(Duration::from_millis(100));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // The pool connect and send nothing
+        let _silent = TcpStream::connect(address).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let served = async_std::future::timeout(Duration::from_secs(5), ca.serve(stream))
+            .await
+            .unwrap();
+        assert!(matches!(
+            served,
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut
+        ));
+
+        // The pool complete the handshake and do not send the request
+        let authority_public_key = ed25519_dalek::PublicKey::from_bytes(&public_key[..]).unwrap();
+        let initiator = Initiator::with_static_keypair(authority_public_key, &pool).unwrap();
+        let mut silent = TcpStream::connect(address).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let served = task::spawn(async move { ca.serve(stream).await });
+        let _transport = handshake::run_initiator(&mut silent, initiator)
+            .await
+            .unwrap();
+        let served = async_std::future::timeout(Duration::from_secs(5), served)
+            .await
+            .unwrap();
+        assert!(matches!(
+            served,
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut
+        ));
+    }
+}
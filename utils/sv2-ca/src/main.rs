@@ -0,0 +1,49 @@
+//! Certificate authority daemon
+//!
+//! Hold the authority keypair and issue short lived certificates to the pools listed in
+//! `allowed_pools_file` (base58check encoded static public keys, one for line).
+//!
+//! The daemon is configured with ca-config.toml
+use async_std::net::TcpListener;
+use noise_sv2::{AllowedInitiators, Authority};
+use serde::Deserialize;
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use sv2_ca::{serve_pools, CertificateAuthority};
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    listen_address: String,
+    listen_port: u16,
+    authority_pub_key: [u8; 32],
+    authority_secret_key: [u8; 32],
+    max_cert_validity_sec: u64,
+    allowed_pools_file: String,
+}
+
+#[async_std::main]
+async fn main() {
+    let config_file = std::fs::read_to_string("ca-config.toml").unwrap();
+    let config: Config = toml::from_str(&config_file).unwrap();
+    let listen_address = SocketAddr::new(
+        IpAddr::from_str(&config.listen_address).unwrap(),
+        config.listen_port,
+    );
+    let authority =
+        Authority::from_raw_k(&config.authority_pub_key, &config.authority_secret_key).unwrap();
+    let allowed_pools = std::fs::read_to_string(&config.allowed_pools_file).unwrap();
+    let allowed_pools = AllowedInitiators::from_bs58_lines(&allowed_pools).unwrap();
+    let ca = CertificateAuthority::new(
+        authority,
+        Duration::from_secs(config.max_cert_validity_sec),
+        allowed_pools,
+    );
+
+    let listener = TcpListener::bind(listen_address).await.unwrap();
+    println!("Certificate authority listening on {}", listen_address);
+    serve_pools(Arc::new(ca), listener).await.unwrap();
+}
@@ -0,0 +1,223 @@
+//! Responder of a pool that renew its certificate with the certificate authority daemon.
+//!
+//! `RenewingResponder` request a certificate when it is built and `RenewingResponder::run` request
+//! a new one when `RENEWAL_FRACTION` of the lifetime of the current certificate is left, so that
+//! the certificate is replaced well before `not_valid_after` even if the daemon is down for a
+//! while (a failed renewal is retried every `RETRY_INTERVAL`). Two renewals are at least
+//! `RETRY_INTERVAL` apart, a daemon that issue very short certificates can not make the pool renew
+//! in a loop. The new certificate replace the current one in a single step: every Responder built
+//! after the renewal send the new certificate, the handshakes already started are not affected.
+use crate::{request_certificate, Error};
+use async_std::task;
+use core::convert::TryFrom;
+use noise_sv2::{Responder, SignatureNoiseMessage, StaticKeypair};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+/// The certificate is renewed when this fraction of its lifetime is left
+pub const RENEWAL_FRACTION: u32 = 3;
+/// Wait that much before retrying a failed renewal, and at least that much between two renewals
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+struct CurrentCertificate {
+    signature_noise_message: Vec<u8>,
+    issued_at: SystemTime,
+    not_valid_after: SystemTime,
+}
+
+pub struct RenewingResponder {
+    ca_address: SocketAddr,
+    authority_public_key: [u8; 32],
+    static_keypair: StaticKeypair,
+    validity: Duration,
+    certificate: RwLock<Arc<CurrentCertificate>>,
+}
+
+impl RenewingResponder {
+    /// Request a certificate for `static_keypair` valid `validity` to the daemon at `ca_address`,
+    /// the daemon can issue a certificate that expire earlier
+    pub async fn new(
+        ca_address: SocketAddr,
+        authority_public_key: [u8; 32],
+        static_keypair: StaticKeypair,
+        validity: Duration,
+    ) -> Result<Self, Error> {
+        let certificate =
+            fetch_certificate(ca_address, authority_public_key, &static_keypair, validity).await?;
+        Ok(Self {
+            ca_address,
+            authority_public_key,
+            static_keypair,
+            validity,
+            certificate: RwLock::new(Arc::new(certificate)),
+        })
+    }
+
+    fn current(&self) -> Arc<CurrentCertificate> {
+        // Is fine to unwrap, the lock is never held while panicking
+        self.certificate.read().unwrap().clone()
+    }
+
+    /// Responder for a new connection, with the current certificate
+    pub fn responder(&self) -> Result<Responder, Error> {
+        let certificate = self.current().signature_noise_message.clone();
+        Responder::new(&self.static_keypair, certificate.into()).map_err(|_| Error::Noise)
+    }
+
+    /// When the current certificate expires
+    pub fn not_valid_after(&self) -> SystemTime {
+        self.current().not_valid_after
+    }
+
+    /// Request a new certificate and use it for the next Responders
+    pub async fn renew(&self) -> Result<(), Error> {
+        let certificate = fetch_certificate(
+            self.ca_address,
+            self.authority_public_key,
+            &self.static_keypair,
+            self.validity,
+        )
+        .await?;
+        *self.certificate.write().unwrap() = Arc::new(certificate);
+        Ok(())
+    }
+
+    /// Renew the certificate before it expires, forever
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            let current = self.current();
+            let delay = renewal_delay(
+                SystemTime::now(),
+                current.issued_at,
+                current.not_valid_after,
+            );
+            task::sleep(delay).await;
+            while let Err(e) = self.renew().await {
+                println!("Certificate renewal failed: {:?}", e);
+                task::sleep(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// How long to wait before renewing a certificate received at `issued_at`, at least
+/// `RETRY_INTERVAL`
+fn renewal_delay(now: SystemTime, issued_at: SystemTime, not_valid_after: SystemTime) -> Duration {
+    let lifetime = not_valid_after
+        .duration_since(issued_at)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    let renew_at = not_valid_after - lifetime / RENEWAL_FRACTION;
+    renew_at
+        .duration_since(now)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+        .max(RETRY_INTERVAL)
+}
+
+async fn fetch_certificate(
+    ca_address: SocketAddr,
+    authority_public_key: [u8; 32],
+    static_keypair: &StaticKeypair,
+    validity: Duration,
+) -> Result<CurrentCertificate, Error> {
+    let issued_at = SystemTime::now();
+    let signature_noise_message =
+        request_certificate(ca_address, authority_public_key, static_keypair, validity).await?;
+    let not_valid_after = SignatureNoiseMessage::try_from(&signature_noise_message[..])
+        .map_err(|_| Error::InvalidMessage)?
+        .not_valid_after();
+    Ok(CurrentCertificate {
+        signature_noise_message,
+        issued_at,
+        not_valid_after,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serve_pools, CertificateAuthority};
+    use async_std::net::TcpListener;
+    use core::convert::TryInto;
+    use noise_sv2::{
+        handshake::Step, AllowedInitiators, Authority, CertificateClock, FixedTime, Initiator,
+    };
+
+    #[test]
+    fn renews_when_a_third_of_the_lifetime_is_left() {
+        let issued_at = SystemTime::now();
+        let not_valid_after = issued_at + Duration::from_secs(3000);
+        let delay = renewal_delay(issued_at, issued_at, not_valid_after);
+        assert_eq!(delay, Duration::from_secs(2000));
+        let now = issued_at + Duration::from_secs(1500);
+        let delay = renewal_delay(now, issued_at, not_valid_after);
+        assert_eq!(delay, Duration::from_secs(500));
+        // A certificate that should already be renewed is renewed after RETRY_INTERVAL, so that
+        // the renewals are never closer
+        let now = issued_at + Duration::from_secs(2500);
+        let delay = renewal_delay(now, issued_at, not_valid_after);
+        assert_eq!(delay, RETRY_INTERVAL);
+        let not_valid_after = issued_at + Duration::from_secs(3);
+        let delay = renewal_delay(issued_at, issued_at, not_valid_after);
+        assert_eq!(delay, RETRY_INTERVAL);
+    }
+
+    /// Run the handshake of a downstream that check the certificate of the pool at `now`
+    fn downstream_handshake(
+        authority_public_key: [u8; 32],
+        mut pool: Responder,
+        now: SystemTime,
+    ) -> bool {
+        let clock = CertificateClock::new(Arc::new(FixedTime(now)), Duration::from_secs(0));
+        let mut initiator = Initiator::from_raw_k(authority_public_key)
+            .unwrap()
+            .with_clock(clock);
+        let first_message = initiator.step(None).unwrap().inner();
+        let second_message = pool.step(Some(first_message)).unwrap().inner();
+        initiator.step(Some(second_message)).is_ok()
+    }
+
+    #[async_std::test]
+    async fn switch_the_new_handshakes_to_the_renewed_certificate() {
+        let (public_key, private_key) = noise_sv2::random_keypair();
+        let authority = Authority::from_raw_k(&public_key, &private_key).unwrap();
+        let pool = noise_sv2::generate_keypair().unwrap();
+        let allowed_pools = AllowedInitiators::new(vec![pool.public[..].try_into().unwrap()]);
+        let ca = CertificateAuthority::new(authority, Duration::from_secs(3600), allowed_pools);
+        let ca = Arc::new(ca);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        task::spawn(serve_pools(ca.clone(), listener));
+
+        let pool_public_key = pool.public.clone();
+        let responder = RenewingResponder::new(address, public_key, pool, Duration::from_secs(60))
+            .await
+            .unwrap();
+        // Replace the certificate with one that expire now
+        let issued_at = SystemTime::now();
+        let expiring = ca.issue(&pool_public_key, Duration::from_secs(0)).unwrap();
+        *responder.certificate.write().unwrap() = Arc::new(CurrentCertificate {
+            signature_noise_message: expiring,
+            issued_at,
+            not_valid_after: issued_at,
+        });
+        let later = issued_at + Duration::from_secs(10);
+        assert!(!downstream_handshake(
+            public_key,
+            responder.responder().unwrap(),
+            later
+        ));
+
+        // The downstreams accept the renewed certificate
+        responder.renew().await.unwrap();
+        assert!(responder.not_valid_after() > later);
+        assert!(downstream_handshake(
+            public_key,
+            responder.responder().unwrap(),
+            later
+        ));
+    }
+}
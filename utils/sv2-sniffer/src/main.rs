@@ -0,0 +1,194 @@
+//! Sv2 sniffer
+//!
+//! Debug proxy that sit between a downstream and an upstream Sv2 node. For every downstream that
+//! connect to the sniffer a new connection with the upstream is opened, the sniffer perform the
+//! noise handshake with both of them (as responder with the downstream and as initiator with the
+//! upstream) and relay every frame in both directions.
+//!
+//! Every relayed frame is decoded into `PoolMessages` and logged on stdout as a json line so that
+//! the exchanged messages can be inspected when debugging a role or testing the interoperability
+//! with other implementations.
+//!
+//! The sniffer is configured with sniffer-config.toml
+use async_channel::{Receiver, Sender};
+use async_std::{
+    net::{TcpListener, TcpStream},
+    prelude::*,
+    task,
+};
+use codec_sv2::{
+    Frame, HandshakeRole, Initiator, Responder, StandardEitherFrame, StandardSv2Frame,
+};
+use network_helpers::Connection;
+use roles_logic_sv2::parsers::PoolMessages;
+use serde::Deserialize;
+use std::{
+    convert::TryInto,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+pub type Message = PoolMessages<'static>;
+pub type StdFrame = StandardSv2Frame<Message>;
+pub type EitherFrame = StandardEitherFrame<Message>;
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    listen_address: String,
+    listen_port: u16,
+    upstream_address: String,
+    upstream_port: u16,
+    upstream_authority_pub_key: [u8; 32],
+    authority_pub_key: [u8; 32],
+    authority_secret_key: [u8; 32],
+    cert_validity_sec: u64,
+}
+
+/// Where the relayed frame is going
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    ToUpstream,
+    ToDownstream,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::ToUpstream => "downstream->upstream",
+            Self::ToDownstream => "upstream->downstream",
+        }
+    }
+}
+
+/// Decode the frame and log it as a json line
+fn log_frame(connection_id: u32, direction: Direction, frame: &mut StdFrame) {
+    // Sv2Frame always has an header
+    let header = frame.get_header().unwrap();
+    let message: Result<PoolMessages, _> = (header.msg_type(), frame.payload()).try_into();
+    let message = match message.map(|m| serde_json::to_value(&m)) {
+        Ok(Ok(message)) => message,
+        Ok(Err(e)) => serde_json::json!(format!("Can not encode message: {}", e)),
+        Err(e) => serde_json::json!(format!("Can not decode message: {}", e)),
+    };
+    let line = serde_json::json!({
+        "connection_id": connection_id,
+        "direction": direction.as_str(),
+        "extension_type": header.extension_type(),
+        "msg_type": header.msg_type(),
+        "channel_msg": header.channel_msg(),
+        "length": header.len(),
+        "message": message,
+    });
+    println!("{}", line);
+}
+
+/// Relay every frame received from `receiver` to `sender` logging it. When one of the two
+/// connections is closed the other one is closed too.
+async fn relay(
+    connection_id: u32,
+    direction: Direction,
+    receiver: Receiver<EitherFrame>,
+    sender: Sender<EitherFrame>,
+) {
+    while let Ok(frame) = receiver.recv().await {
+        let mut frame: StdFrame = match frame.try_into() {
+            Ok(frame) => frame,
+            Err(_) => {
+                eprintln!(
+                    "Connection {}: received a non sv2 frame {}",
+                    connection_id,
+                    direction.as_str()
+                );
+                break;
+            }
+        };
+        log_frame(connection_id, direction, &mut frame);
+        if sender.send(frame.into()).await.is_err() {
+            break;
+        }
+    }
+    receiver.close();
+    sender.close();
+}
+
+async fn sniff(
+    connection_id: u32,
+    downstream: TcpStream,
+    responder: Responder,
+    upstream_address: SocketAddr,
+    upstream_authority_pub_key: [u8; 32],
+) {
+    let upstream = match TcpStream::connect(upstream_address).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            eprintln!(
+                "Connection {}: can not connect to upstream {}: {}",
+                connection_id, upstream_address, e
+            );
+            return;
+        }
+    };
+    let initiator = Initiator::from_raw_k(upstream_authority_pub_key).unwrap();
+
+    let (downstream_receiver, downstream_sender): (Receiver<EitherFrame>, Sender<EitherFrame>) =
+        Connection::new(downstream, HandshakeRole::Responder(responder), 10).await;
+    let (upstream_receiver, upstream_sender): (Receiver<EitherFrame>, Sender<EitherFrame>) =
+        Connection::new(upstream, HandshakeRole::Initiator(initiator), 10).await;
+    eprintln!("Connection {}: opened", connection_id);
+
+    let to_upstream = task::spawn(relay(
+        connection_id,
+        Direction::ToUpstream,
+        downstream_receiver,
+        upstream_sender,
+    ));
+    relay(
+        connection_id,
+        Direction::ToDownstream,
+        upstream_receiver,
+        downstream_sender,
+    )
+    .await;
+    to_upstream.await;
+    eprintln!("Connection {}: closed", connection_id);
+}
+
+#[async_std::main]
+async fn main() {
+    let config_file = std::fs::read_to_string("sniffer-config.toml").unwrap();
+    let config: Config = toml::from_str(&config_file).unwrap();
+    let listen_address = SocketAddr::new(
+        IpAddr::from_str(&config.listen_address).unwrap(),
+        config.listen_port,
+    );
+    let upstream_address = SocketAddr::new(
+        IpAddr::from_str(&config.upstream_address).unwrap(),
+        config.upstream_port,
+    );
+    let cert_validity = Duration::from_secs(config.cert_validity_sec);
+
+    let listner = TcpListener::bind(listen_address).await.unwrap();
+    let mut incoming = listner.incoming();
+    let mut connection_id: u32 = 0;
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        connection_id = connection_id.wrapping_add(1);
+        let responder = Responder::from_authority_kp(
+            &config.authority_pub_key[..],
+            &config.authority_secret_key[..],
+            cert_validity,
+        )
+        .unwrap();
+        task::spawn(sniff(
+            connection_id,
+            stream,
+            responder,
+            upstream_address,
+            config.upstream_authority_pub_key,
+        ));
+    }
+}